@@ -0,0 +1,698 @@
+//! `shim-run shim-doctor`: why the library won't load here.
+//!
+//! ```text
+//! shim-run shim-doctor [--lib PATH] [--json]
+//! ```
+//!
+//! A prebuilt library that doesn't fit the machine (an arm64-only dylib on
+//! an Intel Mac, one built against a newer macOS than the one running) is
+//! refused by dyld without a word, and the plugin just never hears from
+//! the shim. So this reads the library's headers itself (`otool -hl`, in
+//! short): each Mach-O slice's architecture, its `LC_BUILD_VERSION` (or
+//! `LC_VERSION_MIN_MACOSX`) minimum OS and its `LC_ID_DYLIB`, or an ELF
+//! header's machine and type. It compares them with this process and OS,
+//! loads the library in a child with the shim's environment cleared, asks
+//! it for `nvim_claude_shim_version`, and tries connecting to the socket
+//! the shim would. Each check passes, fails, warns or is skipped, and each
+//! failure says what to do about it. `--json` prints the report as one
+//! object for `:checkhealth`:
+//!
+//! ```json
+//! { "ok": false, "lib": "/p/libnvimclaude_shim.dylib",
+//!   "image": { "format": "mach-o", "slices": [{ "arch": "arm64", "minos": "14.0",
+//!              "id": "@rpath/libnvimclaude_shim.dylib" }] },
+//!   "checks": [{ "name": "arch", "status": "fail", "detail": "...", "fix": "..." }] }
+//! ```
+//!
+//! The exit status is 1 when any check failed.
+
+use std::ffi::{CStr, CString, OsStr};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+use crate::sockpath;
+
+pub(crate) const USAGE: &str = "usage: shim-run shim-doctor [--lib PATH] [--json]\n";
+
+const MH_MAGIC_64: u32 = 0xfeed_facf;
+const FAT_MAGIC: u32 = 0xcafe_babe;
+const FAT_MAGIC_64: u32 = 0xcafe_babf;
+const MH_DYLIB: u32 = 6;
+const LC_ID_DYLIB: u32 = 0xd;
+const LC_VERSION_MIN_MACOSX: u32 = 0x24;
+const LC_BUILD_VERSION: u32 = 0x32;
+const PLATFORM_MACOS: u32 = 1;
+const CPU_TYPE_X86_64: u32 = 0x0100_0007;
+const CPU_TYPE_ARM64: u32 = 0x0100_000c;
+const CPU_SUBTYPE_ARM64E: u32 = 2;
+
+const EM_X86_64: u16 = 62;
+const EM_AARCH64: u16 = 183;
+const ET_DYN: u16 = 3;
+
+/// One architecture's worth of a Mach-O.
+struct Slice {
+    arch: String,
+    dylib: bool,
+    minos: Option<Version>,
+    id: Option<String>,
+}
+
+enum Image {
+    MachO(Vec<Slice>),
+    Elf { arch: String, dylib: bool },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Version(u32, u32, u32);
+
+impl Version {
+    /// Mach-O's `xxxx.yy.zz` nibble packing.
+    fn unpack(v: u32) -> Version {
+        Version(v >> 16, (v >> 8) & 0xff, v & 0xff)
+    }
+
+    #[cfg(target_os = "macos")]
+    fn parse(s: &str) -> Option<Version> {
+        let mut parts = s.trim().split('.').map(|p| p.parse::<u32>());
+        let major = parts.next()?.ok()?;
+        let minor = parts.next().transpose().ok()?.unwrap_or(0);
+        let patch = parts.next().transpose().ok()?.unwrap_or(0);
+        Some(Version(major, minor, patch))
+    }
+}
+
+impl std::fmt::Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Version(major, minor, 0) => write!(f, "{major}.{minor}"),
+            Version(major, minor, patch) => write!(f, "{major}.{minor}.{patch}"),
+        }
+    }
+}
+
+fn u32_at(b: &[u8], at: usize, big: bool) -> Option<u32> {
+    let bytes: [u8; 4] = b.get(at..at + 4)?.try_into().ok()?;
+    Some(if big {
+        u32::from_be_bytes(bytes)
+    } else {
+        u32::from_le_bytes(bytes)
+    })
+}
+
+fn u64_at(b: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(b.get(at..at + 8)?.try_into().ok()?))
+}
+
+fn u16_at(b: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(b.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn parse(b: &[u8]) -> Result<Image, String> {
+    if b.starts_with(b"\x7fELF") {
+        return parse_elf(b);
+    }
+    let truncated = || "truncated Mach-O header".to_string();
+    match u32_at(b, 0, true).ok_or("empty file")? {
+        magic @ (FAT_MAGIC | FAT_MAGIC_64) => {
+            let wide = magic == FAT_MAGIC_64;
+            let n = u32_at(b, 4, true).ok_or_else(truncated)? as usize;
+            (0..n)
+                .map(|i| {
+                    let entry = 8 + i * if wide { 32 } else { 20 };
+                    let offset = if wide {
+                        u64_at(b, entry + 8).map(|o| o as usize)
+                    } else {
+                        u32_at(b, entry + 8, true).map(|o| o as usize)
+                    };
+                    let slice = b
+                        .get(offset.ok_or_else(truncated)?..)
+                        .ok_or_else(truncated)?;
+                    parse_thin(slice)
+                })
+                .collect::<Result<_, _>>()
+                .map(Image::MachO)
+        }
+        _ => parse_thin(b).map(|s| Image::MachO(vec![s])),
+    }
+}
+
+fn parse_thin(b: &[u8]) -> Result<Slice, String> {
+    let truncated = || "truncated Mach-O slice".to_string();
+    if u32_at(b, 0, false) != Some(MH_MAGIC_64) {
+        return Err("neither a Mach-O nor an ELF file".into());
+    }
+    let field = |at| u32_at(b, at, false).ok_or_else(truncated);
+    let (cputype, subtype) = (field(4)?, field(8)?);
+    let arch = match cputype {
+        CPU_TYPE_ARM64 if subtype & 0xff == CPU_SUBTYPE_ARM64E => "arm64e".to_string(),
+        CPU_TYPE_ARM64 => "arm64".to_string(),
+        CPU_TYPE_X86_64 => "x86_64".to_string(),
+        other => format!("cputype {other:#x}"),
+    };
+    let mut slice = Slice {
+        arch,
+        dylib: field(12)? == MH_DYLIB,
+        minos: None,
+        id: None,
+    };
+    let mut at = 32;
+    for _ in 0..field(16)? {
+        let (cmd, size) = (field(at)?, field(at + 4)? as usize);
+        match cmd {
+            LC_BUILD_VERSION if field(at + 8)? == PLATFORM_MACOS => {
+                slice.minos = Some(Version::unpack(field(at + 12)?));
+            }
+            LC_VERSION_MIN_MACOSX => slice.minos = Some(Version::unpack(field(at + 8)?)),
+            LC_ID_DYLIB => {
+                let name = b
+                    .get(at + field(at + 8)? as usize..at + size)
+                    .ok_or_else(truncated)?;
+                let name = name.split(|&c| c == 0).next().unwrap_or_default();
+                slice.id = Some(String::from_utf8_lossy(name).into_owned());
+            }
+            _ => {}
+        }
+        if size == 0 {
+            return Err(truncated());
+        }
+        at += size;
+    }
+    Ok(slice)
+}
+
+fn parse_elf(b: &[u8]) -> Result<Image, String> {
+    // 64-bit, little-endian: what the shim builds for.
+    if b.get(4..6) != Some(&[2, 1]) {
+        return Err("not a 64-bit little-endian ELF file".into());
+    }
+    let truncated = || "truncated ELF header".to_string();
+    let arch = match u16_at(b, 18).ok_or_else(truncated)? {
+        EM_X86_64 => "x86_64".to_string(),
+        EM_AARCH64 => "aarch64".to_string(),
+        other => format!("e_machine {other}"),
+    };
+    let dylib = u16_at(b, 16).ok_or_else(truncated)? == ET_DYN;
+    Ok(Image::Elf { arch, dylib })
+}
+
+impl Image {
+    fn to_json(&self) -> Value {
+        match self {
+            Image::MachO(slices) => json!({
+                "format": "mach-o",
+                "slices": slices
+                    .iter()
+                    .map(|s| json!({
+                        "arch": s.arch,
+                        "dylib": s.dylib,
+                        "minos": s.minos.map(|v| v.to_string()),
+                        "id": s.id,
+                    }))
+                    .collect::<Vec<_>>(),
+            }),
+            Image::Elf { arch, dylib } => json!({ "format": "elf", "arch": arch, "dylib": dylib }),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Pass,
+    Warn,
+    Fail,
+    Skip,
+}
+
+impl Status {
+    fn name(self) -> &'static str {
+        match self {
+            Status::Pass => "pass",
+            Status::Warn => "warn",
+            Status::Fail => "fail",
+            Status::Skip => "skip",
+        }
+    }
+}
+
+struct Check {
+    name: &'static str,
+    status: Status,
+    detail: String,
+    fix: Option<String>,
+}
+
+#[derive(Default)]
+struct Report {
+    checks: Vec<Check>,
+}
+
+impl Report {
+    fn add(&mut self, name: &'static str, status: Status, detail: impl Into<String>) {
+        self.checks.push(Check {
+            name,
+            status,
+            detail: detail.into(),
+            fix: None,
+        });
+    }
+
+    fn fail(&mut self, name: &'static str, detail: impl Into<String>, fix: impl Into<String>) {
+        self.checks.push(Check {
+            name,
+            status: Status::Fail,
+            detail: detail.into(),
+            fix: Some(fix.into()),
+        });
+    }
+
+    fn warn(&mut self, name: &'static str, detail: impl Into<String>, fix: impl Into<String>) {
+        self.fail(name, detail, fix);
+        if let Some(c) = self.checks.last_mut() {
+            c.status = Status::Warn;
+        }
+    }
+
+    fn ok(&self) -> bool {
+        self.checks.iter().all(|c| c.status != Status::Fail)
+    }
+}
+
+/// This process's architecture, named as the library's headers name it.
+fn host_arch() -> &'static str {
+    match std::env::consts::ARCH {
+        "aarch64" if cfg!(target_os = "macos") => "arm64",
+        arch => arch,
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn sysctl_string(name: &CStr) -> Option<String> {
+    let mut buf = [0u8; 64];
+    let mut len = buf.len();
+    let rc = unsafe {
+        libc::sysctlbyname(
+            name.as_ptr(),
+            buf.as_mut_ptr().cast(),
+            &mut len,
+            std::ptr::null_mut(),
+            0,
+        )
+    };
+    (rc == 0).then(|| {
+        let s = buf[..len].split(|&c| c == 0).next().unwrap_or_default();
+        String::from_utf8_lossy(s).into_owned()
+    })
+}
+
+#[cfg(target_os = "macos")]
+fn sysctl_flag(name: &CStr) -> bool {
+    let mut v: libc::c_int = 0;
+    let mut len = std::mem::size_of_val(&v);
+    let rc = unsafe {
+        libc::sysctlbyname(
+            name.as_ptr(),
+            (&mut v as *mut libc::c_int).cast(),
+            &mut len,
+            std::ptr::null_mut(),
+            0,
+        )
+    };
+    rc == 0 && v == 1
+}
+
+fn check_image(report: &mut Report, image: &Image) {
+    let arch = host_arch();
+    let rebuild = "rebuild it here with `cd shim && cargo xtask dist`";
+    match image {
+        Image::Elf { arch: lib, dylib } => {
+            if cfg!(target_os = "macos") {
+                report.fail(
+                    "format",
+                    "an ELF library, which macOS can't load",
+                    format!("use the .dylib build; {rebuild}"),
+                );
+                return;
+            }
+            if !dylib {
+                report.fail(
+                    "format",
+                    "an ELF file, but not a shared library",
+                    format!("point --lib at libnvimclaude_shim.so; {rebuild}"),
+                );
+            } else {
+                report.add("format", Status::Pass, "ELF shared library");
+            }
+            if lib == arch {
+                report.add("arch", Status::Pass, format!("built for {lib}"));
+            } else {
+                report.fail(
+                    "arch",
+                    format!("built for {lib}, but this machine runs {arch}"),
+                    format!("install the {arch} build, or {rebuild}"),
+                );
+            }
+            report.add(
+                "min_os",
+                Status::Skip,
+                "Linux libraries carry no minimum OS",
+            );
+        }
+        Image::MachO(slices) => {
+            if cfg!(target_os = "linux") {
+                report.fail(
+                    "format",
+                    "a Mach-O library, which Linux can't load",
+                    format!("use the .so build; {rebuild}"),
+                );
+                return;
+            }
+            let archs: Vec<_> = slices.iter().map(|s| s.arch.as_str()).collect();
+            if slices.iter().all(|s| s.dylib) {
+                report.add(
+                    "format",
+                    Status::Pass,
+                    format!("Mach-O dylib ({})", archs.join(", ")),
+                );
+            } else {
+                report.fail(
+                    "format",
+                    "a Mach-O file, but not a dylib",
+                    format!("point --lib at libnvimclaude_shim.dylib; {rebuild}"),
+                );
+            }
+            let Some(slice) = slices.iter().find(|s| s.arch == arch) else {
+                report.fail(
+                    "arch",
+                    format!(
+                        "only {} slices, but this process is {arch}",
+                        archs.join(", ")
+                    ),
+                    format!("install the universal build (arm64 and x86_64), or {rebuild}"),
+                );
+                return;
+            };
+            report.add("arch", Status::Pass, format!("has a {arch} slice"));
+            #[cfg(target_os = "macos")]
+            {
+                // On Apple silicon, Rosetta processes load the x86_64 slice.
+                let other = if arch == "arm64" { "x86_64" } else { "arm64" };
+                let both = sysctl_flag(c"hw.optional.arm64");
+                if both && !archs.contains(&other) {
+                    report.warn(
+                        "arch_other",
+                        format!("no {other} slice, so {other} processes here go untracked"),
+                        format!("install the universal build, or {rebuild}"),
+                    );
+                }
+            }
+            check_id(report, slice);
+            check_min_os(report, slice);
+        }
+    }
+}
+
+fn check_id(report: &mut Report, slice: &Slice) {
+    match &slice.id {
+        Some(id) => report.add("install_name", Status::Pass, id.clone()),
+        None => report.fail(
+            "install_name",
+            "no LC_ID_DYLIB: this isn't a dylib dyld will insert",
+            "rebuild with `cargo xtask dist`, which links it as a dylib",
+        ),
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn check_min_os(report: &mut Report, slice: &Slice) {
+    let os = sysctl_string(c"kern.osproductversion").and_then(|v| Version::parse(&v));
+    match (slice.minos, os) {
+        (Some(min), Some(os)) if min > os => report.fail(
+            "min_os",
+            format!("built for macOS {min} or later, but this is macOS {os}"),
+            format!("rebuild with MACOSX_DEPLOYMENT_TARGET={os} cargo xtask dist"),
+        ),
+        (Some(min), Some(os)) => {
+            report.add(
+                "min_os",
+                Status::Pass,
+                format!("needs macOS {min}; this is {os}"),
+            );
+        }
+        (None, _) => report.add("min_os", Status::Skip, "no minimum OS recorded"),
+        (_, None) => report.add("min_os", Status::Skip, "couldn't read the macOS version"),
+    }
+}
+
+#[cfg(not(target_os = "macos"))]
+fn check_min_os(report: &mut Report, _slice: &Slice) {
+    report.add("min_os", Status::Skip, "not on macOS");
+}
+
+/// Load `lib` in a child, where its constructor can't touch this process,
+/// and ask it which build it is.
+fn check_load(report: &mut Report, lib: &Path) {
+    let mut cmd = match std::env::current_exe() {
+        Ok(exe) => Command::new(exe),
+        Err(e) => return report.add("load", Status::Skip, format!("current_exe: {e}")),
+    };
+    for (key, _) in std::env::vars_os() {
+        let k = key.to_string_lossy();
+        if k.starts_with("NVIM_CLAUDE_SHIM") || k.starts_with("FS_SHIM") {
+            cmd.env_remove(&key);
+        }
+    }
+    cmd.env_remove("DYLD_INSERT_LIBRARIES")
+        .env_remove("LD_PRELOAD")
+        // Nowhere to report to: loading it mustn't find a socket either.
+        .env("NVIM_CLAUDE_SHIM_NO_DISCOVERY", "1")
+        .args(["shim-doctor", "--load"])
+        .arg(lib);
+    let out = match cmd.output() {
+        Ok(out) => out,
+        Err(e) => return report.add("load", Status::Skip, format!("spawn: {e}")),
+    };
+    let said = |s: &[u8]| String::from_utf8_lossy(s).trim().to_string();
+    match out.status.code() {
+        Some(0) => {
+            report.add("load", Status::Pass, "dlopen succeeded");
+            let version = said(&out.stdout);
+            let ours = env!("CARGO_PKG_VERSION");
+            if version.split('+').next() == Some(ours) {
+                report.add("version", Status::Pass, version);
+            } else {
+                report.warn(
+                    "version",
+                    format!("the library is {version}, but this shim-run is {ours}"),
+                    "install shim-run and the library from the same release",
+                );
+            }
+        }
+        Some(3) => {
+            report.add("load", Status::Pass, "dlopen succeeded");
+            report.fail(
+                "version",
+                "nvim_claude_shim_version is not exported",
+                "this is not the nvim-claude shim, or a very old one; reinstall it",
+            );
+        }
+        _ => report.fail(
+            "load",
+            format!("dlopen failed: {}", said(&out.stderr)),
+            "fix what dyld or ld.so names above; a missing dependency or a bad signature \
+             (`codesign -s - -f <lib>`) are the usual causes",
+        ),
+    }
+}
+
+/// The child side of `check_load`: 0 with the version on stdout, 2 when
+/// `dlopen` fails, 3 without the version symbol.
+fn load(lib: &Path) -> i32 {
+    let Ok(c_lib) = CString::new(OsStr::as_bytes(lib.as_os_str())) else {
+        return 2;
+    };
+    unsafe {
+        let handle = libc::dlopen(c_lib.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL);
+        if handle.is_null() {
+            eprintln!("{}", CStr::from_ptr(libc::dlerror()).to_string_lossy());
+            return 2;
+        }
+        let sym = libc::dlsym(handle, c"nvim_claude_shim_version".as_ptr());
+        if sym.is_null() {
+            return 3;
+        }
+        let version: extern "C" fn() -> *const libc::c_char = std::mem::transmute(sym);
+        println!("{}", CStr::from_ptr(version()).to_string_lossy());
+    }
+    0
+}
+
+/// Connect where the shim would: `NVIM_CLAUDE_SHIM_SOCK`, then
+/// `NVIM_CLAUDE_SHIM_TCP`, then the conventional sockets.
+fn check_socket(report: &mut Report) {
+    let vars = sockpath::from_process();
+    let fix = "start Neovim with nvim-claude in this project, or set NVIM_CLAUDE_SHIM_SOCK \
+               to the socket it listens on";
+    if let Ok(v) = std::env::var("NVIM_CLAUDE_SHIM_SOCK") {
+        let paths = sockpath::candidates(&v, &vars, |_| {});
+        match paths.iter().find(|p| UnixStream::connect(p).is_ok()) {
+            Some(p) => report.add(
+                "socket",
+                Status::Pass,
+                format!("connected to {}", p.display()),
+            ),
+            None => report.fail(
+                "socket",
+                format!("nothing accepts on NVIM_CLAUDE_SHIM_SOCK={v}"),
+                fix,
+            ),
+        }
+        return;
+    }
+    if let Ok(addr) = std::env::var("NVIM_CLAUDE_SHIM_TCP") {
+        let up = std::net::ToSocketAddrs::to_socket_addrs(&addr)
+            .ok()
+            .and_then(|mut a| a.next())
+            .is_some_and(|a| {
+                std::net::TcpStream::connect_timeout(&a, Duration::from_secs(1)).is_ok()
+            });
+        if up {
+            report.add("socket", Status::Pass, format!("connected to {addr}"));
+        } else {
+            report.fail("socket", format!("nothing accepts on {addr}"), fix);
+        }
+        return;
+    }
+    let off = std::env::var("NVIM_CLAUDE_SHIM_NO_DISCOVERY")
+        .is_ok_and(|v| v == "1" || v.eq_ignore_ascii_case("true"));
+    if off {
+        return report.add(
+            "socket",
+            Status::Skip,
+            "no socket set, and discovery is off",
+        );
+    }
+    match sockpath::discover(&sockpath::conventional(&vars)) {
+        Some(p) => report.add("socket", Status::Pass, format!("found {}", p.display())),
+        None => report.fail(
+            "socket",
+            "no socket set and none found where the shim looks",
+            fix,
+        ),
+    }
+}
+
+fn diagnose(lib: &Path) -> (Report, Option<Image>) {
+    let mut report = Report::default();
+    let bytes = match std::fs::read(lib) {
+        Ok(b) => b,
+        Err(e) => {
+            report.fail(
+                "file",
+                format!("{}: {e}", lib.display()),
+                "pass --lib, set NVIM_CLAUDE_SHIM_LIB, or install the library next to shim-run",
+            );
+            check_socket(&mut report);
+            return (report, None);
+        }
+    };
+    report.add("file", Status::Pass, lib.display().to_string());
+    let image = match parse(&bytes) {
+        Ok(image) => image,
+        Err(e) => {
+            report.fail("format", e, "reinstall the library; this file isn't one");
+            check_socket(&mut report);
+            return (report, None);
+        }
+    };
+    check_image(&mut report, &image);
+    if report.ok() {
+        check_load(&mut report, lib);
+    } else {
+        report.add("load", Status::Skip, "the checks above would fail it");
+    }
+    check_socket(&mut report);
+    (report, Some(image))
+}
+
+fn print(report: &Report, lib: &Path, image: Option<&Image>, as_json: bool) {
+    if as_json {
+        let checks: Vec<_> = report
+            .checks
+            .iter()
+            .map(|c| {
+                let mut v =
+                    json!({ "name": c.name, "status": c.status.name(), "detail": c.detail });
+                if let Some(fix) = &c.fix {
+                    v["fix"] = json!(fix);
+                }
+                v
+            })
+            .collect();
+        let out = json!({
+            "ok": report.ok(),
+            "lib": lib.to_string_lossy(),
+            "image": image.map(Image::to_json),
+            "checks": checks,
+        });
+        println!("{out}");
+        return;
+    }
+    for c in &report.checks {
+        println!("{:<4}  {:<12}  {}", c.status.name(), c.name, c.detail);
+        if let Some(fix) = &c.fix {
+            println!("{:<4}  {:<12}  fix: {fix}", "", "");
+        }
+    }
+}
+
+/// `args` are what follows `shim-doctor`; returns the exit status.
+pub(crate) fn run(
+    args: &[std::ffi::OsString],
+    default_lib: impl FnOnce() -> Option<PathBuf>,
+) -> i32 {
+    let mut lib = None;
+    let mut as_json = false;
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        match arg.to_str() {
+            Some("--lib") | Some("--load") => {
+                let Some(path) = it.next() else {
+                    eprint!("shim-run: {} needs a path\n{USAGE}", arg.to_string_lossy());
+                    return 2;
+                };
+                if arg == "--load" {
+                    return load(Path::new(path));
+                }
+                lib = Some(PathBuf::from(path));
+            }
+            Some("--json") => as_json = true,
+            Some("-h") | Some("--help") => {
+                print!("{USAGE}");
+                return 0;
+            }
+            _ => {
+                eprint!("shim-run: unknown argument {arg:?}\n{USAGE}");
+                return 2;
+            }
+        }
+    }
+    // Nothing found: report on where it should have been.
+    let lib = lib.or_else(default_lib).unwrap_or_else(|| {
+        std::env::current_exe()
+            .map(|exe| exe.with_file_name(crate::LIB_NAME))
+            .unwrap_or_else(|_| PathBuf::from(crate::LIB_NAME))
+    });
+    let (report, image) = diagnose(&lib);
+    print(&report, &lib, image.as_ref(), as_json);
+    if report.ok() {
+        0
+    } else {
+        1
+    }
+}
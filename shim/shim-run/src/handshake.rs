@@ -0,0 +1,244 @@
+//! Noticing that the library never loaded.
+//!
+//! macOS strips `DYLD_INSERT_LIBRARIES` for platform binaries and for
+//! hardened-runtime ones, the loader ignores `LD_PRELOAD` for set-user-ID
+//! programs, and a shell profile can scrub either; to the server that is
+//! indistinguishable from a quiet command. So before the command is run,
+//! a pipe is made and a watcher forked (twice, so that it isn't the
+//! command's child to reap), and the write end goes to the command as
+//! `NVIM_CLAUDE_SHIM_HANDSHAKE_FD`. The library's constructor writes one
+//! byte to it. If none arrives within `NVIM_CLAUDE_SHIM_HANDSHAKE_MS`
+//! (default 2000; 0 turns this off), or every copy of the write end closes
+//! first, the watcher says so on stderr and sends the destination the
+//! shim would have used a notification:
+//!
+//! ```json
+//! { "method": "shim/not_loaded",
+//!   "params": { "pid": 4242, "exe": "/usr/bin/make", "reason": "timeout",
+//!               "waited_ms": 2000, "platform_binary": true,
+//!               "hardened_runtime": false, "setid": false } }
+//! ```
+//!
+//! `reason` is `"timeout"`, or `"closed"` when the command exited (or
+//! closed its fds) first. `platform_binary` and `hardened_runtime` come
+//! from the running process's code signature while it lives, and from
+//! where the executable (or a script's interpreter) is installed once it
+//! is gone; both are false on Linux. The command keeps the launcher's pid,
+//! so `exec` is unchanged.
+
+use std::ffi::OsStr;
+use std::os::raw::c_int;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::prelude::RawFd;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+use serde_json::json;
+
+use crate::rpc;
+
+const ENV: &str = "NVIM_CLAUDE_SHIM_HANDSHAKE_FD";
+const DEFAULT_MS: u64 = 2000;
+
+/// The launcher's copy of the write end.
+pub(crate) struct Handshake {
+    write: RawFd,
+}
+
+impl Handshake {
+    /// Fork the watcher and hand `cmd` the pipe. `None` when turned off,
+    /// or when there is no pipe or process to be had.
+    pub(crate) fn start(cmd: &mut Command, program: &OsStr) -> Option<Handshake> {
+        let ms = std::env::var("NVIM_CLAUDE_SHIM_HANDSHAKE_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_MS);
+        if ms == 0 {
+            return None;
+        }
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            return None;
+        }
+        let (read, write) = (fds[0], fds[1]);
+        let launcher = unsafe { libc::getpid() };
+        let exe = resolve(program);
+        match unsafe { libc::fork() } {
+            -1 => {
+                unsafe {
+                    libc::close(read);
+                    libc::close(write);
+                }
+                return None;
+            }
+            0 => unsafe {
+                if libc::fork() == 0 {
+                    libc::close(write);
+                    watch(read, launcher, &exe, Duration::from_millis(ms));
+                }
+                libc::_exit(0);
+            },
+            pid => unsafe {
+                libc::waitpid(pid, std::ptr::null_mut(), 0);
+                libc::close(read);
+            },
+        }
+        cmd.env(ENV, write.to_string());
+        Some(Handshake { write })
+    }
+
+    /// The command never started: tell the watcher there is nothing to
+    /// wait for.
+    pub(crate) fn abandon(self) {
+        unsafe {
+            libc::write(self.write, b"x".as_ptr().cast(), 1);
+            libc::close(self.write);
+        }
+    }
+}
+
+/// `program` as `exec` will find it.
+fn resolve(program: &OsStr) -> PathBuf {
+    let program = Path::new(program);
+    if program.components().count() > 1 {
+        return program.to_path_buf();
+    }
+    std::env::var_os("PATH")
+        .and_then(|path| {
+            std::env::split_paths(&path)
+                .map(|dir| dir.join(program))
+                .find(|p| std::fs::metadata(p).is_ok_and(|m| m.is_file()))
+        })
+        .unwrap_or_else(|| program.to_path_buf())
+}
+
+fn watch(read: RawFd, pid: libc::pid_t, exe: &Path, wait: Duration) {
+    unsafe {
+        // Off the command's stdin and stdout; stderr is where we complain.
+        let null = libc::open(c"/dev/null".as_ptr(), libc::O_RDWR);
+        if null >= 0 {
+            libc::dup2(null, 0);
+            libc::dup2(null, 1);
+        }
+    }
+    let started = Instant::now();
+    let reason = loop {
+        let left = wait.saturating_sub(started.elapsed());
+        let mut pfd = libc::pollfd {
+            fd: read,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let n = unsafe { libc::poll(&mut pfd, 1, left.as_millis() as c_int) };
+        if n < 0 && std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted {
+            continue;
+        }
+        if n <= 0 {
+            break "timeout";
+        }
+        let mut byte = 0u8;
+        match unsafe { libc::read(read, (&mut byte as *mut u8).cast(), 1) } {
+            1 => return,
+            _ => break "closed",
+        }
+    };
+    let target = Target::of(pid, exe, reason == "timeout");
+    let waited_ms = started.elapsed().as_millis() as u64;
+    eprintln!(
+        "shim-run: the shim never loaded into {} ({}); nothing it does is tracked",
+        exe.display(),
+        target.why(reason, waited_ms)
+    );
+    rpc::Client::from_env().notify(
+        "shim/not_loaded",
+        json!({
+            "pid": pid,
+            "exe": exe.to_string_lossy(),
+            "reason": reason,
+            "waited_ms": waited_ms,
+            "platform_binary": target.platform_binary,
+            "hardened_runtime": target.hardened_runtime,
+            "setid": target.setid,
+        }),
+    );
+}
+
+/// What about the command keeps a preloaded library out.
+struct Target {
+    platform_binary: bool,
+    hardened_runtime: bool,
+    setid: bool,
+}
+
+impl Target {
+    /// `running` says `pid` is still the command, so its signature can
+    /// be asked for.
+    fn of(pid: libc::pid_t, exe: &Path, running: bool) -> Target {
+        // S_ISUID | S_ISGID, whose type differs between platforms.
+        let setid = std::fs::metadata(exe).is_ok_and(|m| m.permissions().mode() & 0o6000 != 0);
+        let (platform_binary, hardened_runtime) = signature(pid, exe, running);
+        Target {
+            platform_binary,
+            hardened_runtime,
+            setid,
+        }
+    }
+
+    fn why(&self, reason: &str, waited_ms: u64) -> String {
+        let preload = crate::PRELOAD_ENV;
+        let cause = if self.platform_binary {
+            format!("a platform binary, so macOS strips {preload}; run a copy from outside /usr and /System")
+        } else if self.hardened_runtime {
+            format!("hardened runtime without the allow-dyld-environment-variables entitlement, so {preload} is ignored")
+        } else if self.setid {
+            format!("set-user-ID or set-group-ID, so the loader ignores {preload}")
+        } else {
+            format!(
+                "{preload} was dropped on the way (a shell profile, a wrapper) or the library \
+                 failed to load; `shim-run shim-doctor` checks the library"
+            )
+        };
+        match reason {
+            "timeout" => format!("no handshake in {waited_ms} ms: {cause}"),
+            _ => format!("it exited without a handshake: {cause}"),
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+extern "C" {
+    fn csops(pid: libc::pid_t, ops: u32, useraddr: *mut libc::c_void, usersize: usize) -> c_int;
+}
+
+/// `(platform_binary, hardened_runtime)`.
+#[cfg(target_os = "macos")]
+fn signature(pid: libc::pid_t, exe: &Path, running: bool) -> (bool, bool) {
+    const CS_OPS_STATUS: u32 = 0;
+    const CS_RUNTIME: u32 = 0x0001_0000;
+    const CS_PLATFORM_BINARY: u32 = 0x0400_0000;
+    let mut flags: u32 = 0;
+    let size = std::mem::size_of_val(&flags);
+    if running && unsafe { csops(pid, CS_OPS_STATUS, (&mut flags as *mut u32).cast(), size) } == 0 {
+        return (flags & CS_PLATFORM_BINARY != 0, flags & CS_RUNTIME != 0);
+    }
+    // Gone: judge by where it, or the interpreter it names, lives.
+    let interpreter = std::fs::read(exe).ok().and_then(|b| {
+        let line = b.strip_prefix(b"#!")?.split(|&c| c == b'\n').next()?;
+        let path = line
+            .split(|c| c.is_ascii_whitespace())
+            .find(|w| !w.is_empty())?;
+        Some(PathBuf::from(String::from_utf8_lossy(path).into_owned()))
+    });
+    let image = interpreter.as_deref().unwrap_or(exe);
+    let protected = ["/System/", "/bin/", "/sbin/", "/usr/"]
+        .iter()
+        .any(|p| image.starts_with(p))
+        && !image.starts_with("/usr/local/");
+    (protected, false)
+}
+
+#[cfg(not(target_os = "macos"))]
+fn signature(_pid: libc::pid_t, _exe: &Path, _running: bool) -> (bool, bool) {
+    (false, false)
+}
@@ -0,0 +1,168 @@
+//! `shim-run`: launch a command with the fs shim preloaded.
+//!
+//! ```text
+//! shim-run [--lib PATH] [--supervise] [--no-preload] [--] CMD [ARGS...]
+//! shim-run shim-doctor [--lib PATH] [--json]
+//! ```
+//!
+//! By default this only sets `DYLD_INSERT_LIBRARIES` / `LD_PRELOAD` and execs
+//! the command. On Linux, `--supervise` additionally runs the command under a
+//! seccomp user-notification filter so raw-syscall writers (Go binaries,
+//! io_uring-free static tools) still get `pre_*` preflights. `shim-doctor`
+//! checks that the library can load here at all (see `doctor`).
+
+use std::ffi::OsString;
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{exit, Command};
+
+mod doctor;
+mod handshake;
+mod rpc;
+// Same socket path templates as the dylib.
+#[path = "../../src/sockpath.rs"]
+mod sockpath;
+#[cfg(target_os = "linux")]
+mod supervise;
+
+const USAGE: &str = "usage: shim-run [--lib PATH] [--supervise] [--no-preload] [--] CMD [ARGS...]
+       shim-run shim-doctor [--lib PATH] [--json]\n";
+
+#[cfg(target_os = "macos")]
+const PRELOAD_ENV: &str = "DYLD_INSERT_LIBRARIES";
+#[cfg(target_os = "linux")]
+const PRELOAD_ENV: &str = "LD_PRELOAD";
+
+#[cfg(target_os = "macos")]
+const LIB_NAME: &str = "libnvimclaude_shim.dylib";
+#[cfg(target_os = "linux")]
+const LIB_NAME: &str = "libnvimclaude_shim.so";
+
+struct Args {
+    lib: Option<PathBuf>,
+    supervise: bool,
+    preload: bool,
+    command: Vec<OsString>,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args {
+        lib: None,
+        supervise: false,
+        preload: true,
+        command: Vec::new(),
+    };
+    let mut it = std::env::args_os().skip(1);
+    while let Some(arg) = it.next() {
+        match arg.to_str() {
+            Some("--lib") => {
+                args.lib = Some(it.next().ok_or("--lib needs a path")?.into());
+            }
+            Some("--supervise") => args.supervise = true,
+            Some("--no-preload") => args.preload = false,
+            Some("-h") | Some("--help") => {
+                print!("{USAGE}");
+                exit(0);
+            }
+            Some("--") => {
+                args.command.extend(it.by_ref());
+            }
+            Some(flag) if flag.starts_with("--") => {
+                return Err(format!("unknown flag: {flag}"));
+            }
+            _ => {
+                args.command.push(arg);
+                args.command.extend(it.by_ref());
+            }
+        }
+    }
+    if args.command.is_empty() {
+        return Err("missing command".into());
+    }
+    Ok(args)
+}
+
+/// `--lib`, then `NVIM_CLAUDE_SHIM_LIB`, then the library next to this binary.
+fn resolve_lib(explicit: Option<PathBuf>) -> Result<PathBuf, String> {
+    if let Some(lib) =
+        explicit.or_else(|| std::env::var_os("NVIM_CLAUDE_SHIM_LIB").map(PathBuf::from))
+    {
+        return Ok(lib);
+    }
+    let exe = std::env::current_exe().map_err(|e| format!("current_exe: {e}"))?;
+    let sibling = exe.with_file_name(LIB_NAME);
+    if sibling.exists() {
+        Ok(sibling)
+    } else {
+        Err(format!(
+            "no shim library at {} (pass --lib)",
+            sibling.display()
+        ))
+    }
+}
+
+fn build_command(args: &Args) -> Result<Command, String> {
+    let mut cmd = Command::new(&args.command[0]);
+    cmd.args(&args.command[1..]);
+    if args.preload {
+        let lib = resolve_lib(args.lib.clone())?;
+        let mut value = OsString::from(lib);
+        if let Some(existing) = std::env::var_os(PRELOAD_ENV).filter(|v| !v.is_empty()) {
+            value.push(":");
+            value.push(existing);
+        }
+        cmd.env(PRELOAD_ENV, value);
+    }
+    if args.supervise {
+        // The supervisor answers delete/rename/truncate preflights itself; the
+        // dylib keeps first-write preflights and post events.
+        cmd.env("NVIM_CLAUDE_SHIM_SUPERVISED", "1");
+    }
+    Ok(cmd)
+}
+
+fn main() {
+    let argv: Vec<OsString> = std::env::args_os().skip(1).collect();
+    if argv.first().is_some_and(|a| a == "shim-doctor") {
+        exit(doctor::run(&argv[1..], || resolve_lib(None).ok()));
+    }
+    let args = match parse_args() {
+        Ok(a) => a,
+        Err(e) => {
+            eprint!("shim-run: {e}\n{USAGE}");
+            exit(2);
+        }
+    };
+    let mut cmd = match build_command(&args) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("shim-run: {e}");
+            exit(2);
+        }
+    };
+    let handshake = args
+        .preload
+        .then(|| handshake::Handshake::start(&mut cmd, &args.command[0]))
+        .flatten();
+
+    if args.supervise {
+        #[cfg(target_os = "linux")]
+        exit(supervise::run(cmd));
+        #[cfg(not(target_os = "linux"))]
+        {
+            eprintln!("shim-run: --supervise is only supported on Linux");
+            exit(2);
+        }
+    }
+
+    let err = cmd.exec();
+    if let Some(h) = handshake {
+        h.abandon();
+    }
+    eprintln!("shim-run: exec {:?}: {err}", args.command[0]);
+    exit(if err.kind() == std::io::ErrorKind::NotFound {
+        127
+    } else {
+        126
+    });
+}
@@ -0,0 +1,138 @@
+//! Minimal newline-delimited JSON-RPC client speaking the same preflight
+//! protocol as the dylib (`pre_*` requests answered with `{"allow": bool}`).
+//!
+//! The launcher runs as its own process, so plain std sockets are fine here;
+//! none of the dylib's unhooked-I/O care is needed.
+
+use crate::sockpath;
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+enum Stream {
+    Unix(BufReader<UnixStream>),
+    Tcp(BufReader<std::net::TcpStream>),
+}
+
+pub struct Client {
+    stream: Option<Stream>,
+    next_id: u64,
+    fail_closed: bool,
+}
+
+fn env_flag(name: &str) -> bool {
+    std::env::var(name)
+        .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+        .unwrap_or(false)
+}
+
+impl Client {
+    /// Connect using the same env as the dylib (`NVIM_CLAUDE_SHIM_SOCK`, then
+    /// `NVIM_CLAUDE_SHIM_TCP`, then the conventional socket locations). A
+    /// missing destination allows everything.
+    pub fn from_env() -> Client {
+        let timeout = Duration::from_millis(
+            std::env::var("FS_SHIM_PRE_TIMEOUT_MS")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(1500),
+        );
+        let stream = if let Ok(v) = std::env::var("NVIM_CLAUDE_SHIM_SOCK") {
+            let paths = sockpath::candidates(&v, &sockpath::from_process(), |e| {
+                eprintln!("shim-run: skipping socket candidate: {e}")
+            });
+            paths
+                .iter()
+                .find_map(|p| UnixStream::connect(p).ok())
+                .map(|s| {
+                    s.set_read_timeout(Some(timeout)).ok();
+                    Stream::Unix(BufReader::new(s))
+                })
+        } else if let Ok(addr) = std::env::var("NVIM_CLAUDE_SHIM_TCP") {
+            std::net::TcpStream::connect(addr).ok().map(|s| {
+                s.set_read_timeout(Some(timeout)).ok();
+                Stream::Tcp(BufReader::new(s))
+            })
+        } else if !env_flag("NVIM_CLAUDE_SHIM_NO_DISCOVERY") {
+            sockpath::discover(&sockpath::conventional(&sockpath::from_process()))
+                .and_then(|p| UnixStream::connect(p).ok())
+                .map(|s| {
+                    s.set_read_timeout(Some(timeout)).ok();
+                    Stream::Unix(BufReader::new(s))
+                })
+        } else {
+            None
+        };
+        Client {
+            stream,
+            next_id: 1,
+            fail_closed: env_flag("FS_SHIM_FAIL_CLOSED"),
+        }
+    }
+
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    pub fn is_connected(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    /// What to answer when nothing can be asked: allow, unless failing
+    /// closed (`FS_SHIM_FAIL_CLOSED`).
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    pub fn fallback(&self) -> bool {
+        !self.fail_closed
+    }
+
+    /// Blocking preflight; returns true to allow.
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    pub fn preflight(&mut self, method: &str, params: Value) -> bool {
+        let fallback = self.fallback();
+        let id = self.next_id;
+        self.next_id += 1;
+        let mut line =
+            json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }).to_string();
+        line.push('\n');
+
+        let reply = match self.stream.as_mut() {
+            Some(Stream::Unix(r)) => exchange(r, line.as_bytes()),
+            Some(Stream::Tcp(r)) => exchange(r, line.as_bytes()),
+            None => return fallback,
+        };
+        match reply {
+            Some(v) => v
+                .get("result")
+                .and_then(|r| r.get("allow"))
+                .and_then(Value::as_bool)
+                .unwrap_or(fallback),
+            None => {
+                // A timed-out or broken stream can't be trusted to stay in
+                // request/response lockstep; drop it.
+                self.stream = None;
+                fallback
+            }
+        }
+    }
+}
+
+impl Client {
+    /// Send a notification; nothing comes back, and a missing or broken
+    /// destination drops it.
+    pub fn notify(&mut self, method: &str, params: Value) {
+        let mut line = json!({ "jsonrpc": "2.0", "method": method, "params": params }).to_string();
+        line.push('\n');
+        let _ = match self.stream.as_mut() {
+            Some(Stream::Unix(r)) => r.get_mut().write_all(line.as_bytes()),
+            Some(Stream::Tcp(r)) => r.get_mut().write_all(line.as_bytes()),
+            None => Ok(()),
+        };
+    }
+}
+
+fn exchange<S: std::io::Read + Write>(r: &mut BufReader<S>, line: &[u8]) -> Option<Value> {
+    r.get_mut().write_all(line).ok()?;
+    let mut buf = String::new();
+    if r.read_line(&mut buf).ok()? == 0 {
+        return None;
+    }
+    serde_json::from_str(&buf).ok()
+}
@@ -0,0 +1,555 @@
+//! `--supervise`: seccomp user-notification supervisor (Linux).
+//!
+//! `LD_PRELOAD` never sees raw-syscall writers (Go, statically linked tools),
+//! so the child installs a seccomp filter that routes unlink/rename/truncate
+//! and `open*(O_TRUNC)` to us via a notification fd. For each notification we
+//! read the path out of the child's memory, run the same `pre_*` preflight the
+//! dylib would, and either let the kernel continue the syscall or fail it
+//! with EPERM.
+//!
+//! `SECCOMP_USER_NOTIF_FLAG_CONTINUE` re-reads the arguments after we answer,
+//! so a hostile multi-threaded child could swap the path under us. This mode
+//! is about visibility for cooperative tools, not sandboxing.
+
+use serde_json::json;
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::os::raw::{c_int, c_uint};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::Command;
+
+use crate::rpc::Client;
+
+// <linux/seccomp.h> ioctls (not exported by the libc crate).
+const SECCOMP_IOCTL_NOTIF_RECV: libc::c_ulong = 0xc050_2100;
+const SECCOMP_IOCTL_NOTIF_SEND: libc::c_ulong = 0xc018_2101;
+const SECCOMP_IOCTL_NOTIF_ID_VALID: libc::c_ulong = 0x4008_2102;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+// seccomp_data offsets.
+const OFF_NR: u32 = 0;
+const OFF_ARCH: u32 = 4;
+const fn off_arg(i: u32) -> u32 {
+    16 + 8 * i
+}
+
+/// How to turn a trapped syscall into a preflight.
+#[derive(Clone, Copy)]
+enum Kind {
+    /// `path` in arg `path`, relative to dirfd arg `dirfd` (if any).
+    Path {
+        method: &'static str,
+        dirfd: Option<usize>,
+        path: usize,
+    },
+    /// Path of the fd in arg 0.
+    Fd { method: &'static str },
+    /// `open*` with O_TRUNC: only existing files are worth asking about.
+    TruncOpen { dirfd: Option<usize>, path: usize },
+}
+
+fn classify(nr: i64) -> Option<Kind> {
+    let k = match nr {
+        libc::SYS_unlinkat => Kind::Path {
+            method: "pre_delete",
+            dirfd: Some(0),
+            path: 1,
+        },
+        libc::SYS_renameat2 => Kind::Path {
+            method: "pre_rename",
+            dirfd: Some(2),
+            path: 3,
+        },
+        libc::SYS_truncate => Kind::Path {
+            method: "pre_truncate",
+            dirfd: None,
+            path: 0,
+        },
+        libc::SYS_ftruncate => Kind::Fd {
+            method: "pre_truncate",
+        },
+        libc::SYS_openat => Kind::TruncOpen {
+            dirfd: Some(0),
+            path: 1,
+        },
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_unlink => Kind::Path {
+            method: "pre_delete",
+            dirfd: None,
+            path: 0,
+        },
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_rename => Kind::Path {
+            method: "pre_rename",
+            dirfd: None,
+            path: 1,
+        },
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_renameat => Kind::Path {
+            method: "pre_rename",
+            dirfd: Some(2),
+            path: 3,
+        },
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_open => Kind::TruncOpen {
+            dirfd: None,
+            path: 0,
+        },
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_creat => Kind::TruncOpen {
+            dirfd: None,
+            path: 0,
+        },
+        _ => return None,
+    };
+    Some(k)
+}
+
+//
+// -------- BPF filter --------
+//
+
+fn stmt(code: u32, k: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    }
+}
+
+/// Notify on every path-mutating syscall we resolve in `classify`, and on
+/// `open`/`openat` only when O_TRUNC is set (flags live in the low word).
+fn build_filter() -> Vec<libc::sock_filter> {
+    use libc::{BPF_ABS, BPF_JEQ, BPF_JMP, BPF_JSET, BPF_K, BPF_LD, BPF_RET, BPF_W};
+
+    let ld = BPF_LD | BPF_W | BPF_ABS;
+    let ret_allow = stmt(BPF_RET | BPF_K, libc::SECCOMP_RET_ALLOW);
+    let ret_notify = stmt(BPF_RET | BPF_K, libc::SECCOMP_RET_USER_NOTIF);
+
+    let mut plain: Vec<i64> = vec![
+        libc::SYS_unlinkat,
+        libc::SYS_renameat2,
+        libc::SYS_truncate,
+        libc::SYS_ftruncate,
+    ];
+    #[cfg(target_arch = "x86_64")]
+    plain.extend([
+        libc::SYS_unlink,
+        libc::SYS_rename,
+        libc::SYS_renameat,
+        libc::SYS_creat,
+    ]);
+    // (syscall, arg index holding the open flags)
+    let mut trunc_opens: Vec<(i64, u32)> = vec![(libc::SYS_openat, 2)];
+    #[cfg(target_arch = "x86_64")]
+    trunc_opens.push((libc::SYS_open, 1));
+
+    let mut prog = vec![
+        stmt(ld, OFF_ARCH),
+        jump(BPF_JMP | BPF_JEQ | BPF_K, AUDIT_ARCH, 1, 0),
+        ret_allow,
+        stmt(ld, OFF_NR),
+    ];
+    // Layout after the nr load:
+    //   plain checks (jt -> NOTIFY)
+    //   open checks  (jt -> their flag block)
+    //   ALLOW, NOTIFY
+    //   one 4-insn flag block per open syscall
+    let n_plain = plain.len();
+    let n_open = trunc_opens.len();
+    for (i, nr) in plain.iter().enumerate() {
+        let to_notify = (n_plain - i - 1) + n_open + 1;
+        prog.push(jump(
+            BPF_JMP | BPF_JEQ | BPF_K,
+            *nr as u32,
+            to_notify as u8,
+            0,
+        ));
+    }
+    for (i, (nr, _)) in trunc_opens.iter().enumerate() {
+        let to_block = (n_open - i - 1) + 2 + 4 * i;
+        prog.push(jump(
+            BPF_JMP | BPF_JEQ | BPF_K,
+            *nr as u32,
+            to_block as u8,
+            0,
+        ));
+    }
+    prog.push(ret_allow);
+    prog.push(ret_notify);
+    for (_, flags_arg) in &trunc_opens {
+        prog.push(stmt(ld, off_arg(*flags_arg)));
+        prog.push(jump(BPF_JMP | BPF_JSET | BPF_K, libc::O_TRUNC as u32, 0, 1));
+        prog.push(ret_notify);
+        prog.push(ret_allow);
+    }
+    prog
+}
+
+//
+// -------- Child side --------
+//
+
+/// Runs in the forked child right before exec: install the filter and hand
+/// the listener fd to the parent over `sock`. Only async-signal-safe calls.
+unsafe fn child_install_filter(sock: c_int, filter: &[libc::sock_filter]) -> c_int {
+    let prog = libc::sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_ptr() as *mut libc::sock_filter,
+    };
+    unsafe {
+        if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+            return -1;
+        }
+        let listener = libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER as libc::c_long,
+            libc::SECCOMP_FILTER_FLAG_NEW_LISTENER as libc::c_long,
+            &prog as *const libc::sock_fprog,
+        ) as c_int;
+        if listener < 0 {
+            return -1;
+        }
+        if send_fd(sock, listener) != 0 {
+            return -1;
+        }
+        libc::close(listener);
+        libc::close(sock);
+    }
+    0
+}
+
+unsafe fn send_fd(sock: c_int, fd: c_int) -> c_int {
+    unsafe {
+        let mut byte = [0u8; 1];
+        let mut iov = libc::iovec {
+            iov_base: byte.as_mut_ptr() as *mut libc::c_void,
+            iov_len: 1,
+        };
+        let space = libc::CMSG_SPACE(std::mem::size_of::<c_int>() as c_uint) as usize;
+        let mut cbuf = [0u8; 64];
+        let mut msg: libc::msghdr = std::mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = cbuf.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = space as _;
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<c_int>() as c_uint) as _;
+        std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut c_int, fd);
+        if libc::sendmsg(sock, &msg, 0) == 1 {
+            0
+        } else {
+            -1
+        }
+    }
+}
+
+fn recv_fd(sock: c_int) -> Option<c_int> {
+    unsafe {
+        let mut byte = [0u8; 1];
+        let mut iov = libc::iovec {
+            iov_base: byte.as_mut_ptr() as *mut libc::c_void,
+            iov_len: 1,
+        };
+        let mut cbuf = [0u8; 64];
+        let mut msg: libc::msghdr = std::mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = cbuf.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = cbuf.len() as _;
+        if libc::recvmsg(sock, &mut msg, libc::MSG_CMSG_CLOEXEC) != 1 {
+            return None;
+        }
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        if cmsg.is_null() || (*cmsg).cmsg_type != libc::SCM_RIGHTS {
+            return None;
+        }
+        Some(std::ptr::read_unaligned(
+            libc::CMSG_DATA(cmsg) as *const c_int
+        ))
+    }
+}
+
+//
+// -------- Supervisor side --------
+//
+
+/// Run `cmd` under the supervisor and return the exit code to propagate.
+pub fn run(mut cmd: Command) -> i32 {
+    let mut pair = [0 as c_int; 2];
+    if unsafe {
+        libc::socketpair(
+            libc::AF_UNIX,
+            libc::SOCK_STREAM | libc::SOCK_CLOEXEC,
+            0,
+            pair.as_mut_ptr(),
+        )
+    } != 0
+    {
+        eprintln!("shim-run: socketpair: {}", std::io::Error::last_os_error());
+        return 125;
+    }
+    let (parent_sock, child_sock) = (pair[0], pair[1]);
+    let filter = build_filter();
+
+    // `pre_exec` runs after fork in the child, which is exactly where the
+    // filter must be installed so it is inherited across the exec.
+    unsafe {
+        cmd.pre_exec(move || {
+            libc::close(parent_sock);
+            if child_install_filter(child_sock, &filter) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut child = match cmd.spawn() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("shim-run: seccomp unavailable or spawn failed: {e}");
+            return if e.kind() == std::io::ErrorKind::NotFound {
+                127
+            } else {
+                125
+            };
+        }
+    };
+    unsafe { libc::close(child_sock) };
+
+    let listener = recv_fd(parent_sock);
+    unsafe { libc::close(parent_sock) };
+    let Some(listener) = listener else {
+        eprintln!("shim-run: child did not hand over the seccomp listener");
+        let _ = child.kill();
+        return 125;
+    };
+
+    let mut client = Client::from_env();
+    if !client.is_connected() {
+        eprintln!("shim-run: no shim destination reachable; supervisor applies the fail policy");
+    }
+    let status = serve(listener, &mut client, &mut child);
+    unsafe { libc::close(listener) };
+
+    match status.map_or_else(|| child.wait(), Ok) {
+        Ok(status) => status.code().unwrap_or_else(|| {
+            128 + std::os::unix::process::ExitStatusExt::signal(&status).unwrap_or(0)
+        }),
+        Err(_) => 125,
+    }
+}
+
+/// Each path's last `path_seq`, which numbers the supervisor's events for
+/// it as the dylib numbers a process's (see the shim's `path_seq`): at
+/// most `Seqs::CAP` paths, after which every path starts again above the
+/// highest number handed out.
+#[derive(Default)]
+struct Seqs {
+    by_path: HashMap<String, u64>,
+    floor: u64,
+}
+
+impl Seqs {
+    const CAP: usize = 4096;
+
+    fn next(&mut self, path: &str) -> u64 {
+        if self.by_path.len() >= Self::CAP && !self.by_path.contains_key(path) {
+            self.floor = self
+                .by_path
+                .values()
+                .copied()
+                .max()
+                .unwrap_or(0)
+                .max(self.floor);
+            self.by_path.clear();
+        }
+        let seq = self.by_path.entry(path.to_string()).or_insert(self.floor);
+        *seq += 1;
+        *seq
+    }
+}
+
+/// Answer notifications until the listener hangs up, which it does once
+/// the last task carrying the filter is gone: the child, and whatever it
+/// left running (a daemon, a backgrounded job), whose trapped calls would
+/// fail with `ENOSYS` if nobody answered. The child is reaped on the way
+/// (its filter outlives it until then), and its status returned.
+fn serve(
+    listener: c_int,
+    client: &mut Client,
+    child: &mut std::process::Child,
+) -> Option<std::process::ExitStatus> {
+    let mut seqs = Seqs::default();
+    let mut status = None;
+    loop {
+        let mut pfd = libc::pollfd {
+            fd: listener,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let rc = unsafe { libc::poll(&mut pfd, 1, 100) };
+        if rc < 0 {
+            if std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+            return status;
+        }
+        if rc > 0 && pfd.revents & libc::POLLIN != 0 {
+            handle_one(listener, client, &mut seqs);
+            continue;
+        }
+        if pfd.revents & (libc::POLLHUP | libc::POLLERR | libc::POLLNVAL) != 0 {
+            return status;
+        }
+        if status.is_none() {
+            status = child.try_wait().ok().flatten();
+        }
+    }
+}
+
+fn handle_one(listener: c_int, client: &mut Client, seqs: &mut Seqs) {
+    let mut req: libc::seccomp_notif = unsafe { std::mem::zeroed() };
+    if unsafe { libc::ioctl(listener, SECCOMP_IOCTL_NOTIF_RECV, &mut req) } != 0 {
+        // ENOENT: the target died before we picked it up.
+        return;
+    }
+    let allow = decide(listener, &req, client, seqs);
+    let resp = libc::seccomp_notif_resp {
+        id: req.id,
+        val: 0,
+        error: if allow { 0 } else { -libc::EPERM },
+        flags: if allow {
+            libc::SECCOMP_USER_NOTIF_FLAG_CONTINUE as u32
+        } else {
+            0
+        },
+    };
+    unsafe {
+        let _ = libc::ioctl(listener, SECCOMP_IOCTL_NOTIF_SEND, &resp);
+    }
+}
+
+fn decide(
+    listener: c_int,
+    req: &libc::seccomp_notif,
+    client: &mut Client,
+    seqs: &mut Seqs,
+) -> bool {
+    let Some(kind) = classify(req.data.nr as i64) else {
+        return true;
+    };
+    let pid = req.pid;
+    let args = req.data.args;
+    // A path we can't read (a bad pointer, a task racing to exit) can't be
+    // asked about, so it gets what an unreachable server would.
+    let unreadable = client.fallback();
+    let (method, path) = match kind {
+        Kind::Path {
+            method,
+            dirfd,
+            path,
+        } => {
+            let Some(p) = read_path_arg(pid, dirfd.map(|i| args[i] as c_int), args[path]) else {
+                return unreadable;
+            };
+            (method, p)
+        }
+        Kind::Fd { method } => {
+            match std::fs::read_link(format!("/proc/{pid}/fd/{}", args[0] as c_int)) {
+                Ok(p) if p.is_absolute() => (method, p),
+                // A pipe or socket: no file to ask about.
+                Ok(_) => return true,
+                Err(_) => return unreadable,
+            }
+        }
+        Kind::TruncOpen { dirfd, path } => {
+            let Some(p) = read_path_arg(pid, dirfd.map(|i| args[i] as c_int), args[path]) else {
+                return unreadable;
+            };
+            // Creating a new file truncates nothing; the dylib's first-write
+            // preflight covers it.
+            if std::fs::symlink_metadata(&p).is_err() {
+                return true;
+            }
+            ("pre_truncate", p)
+        }
+    };
+    // The pid in the notification may have died and been reused while we
+    // were reading its memory; only trust what we read if it's still live.
+    if unsafe { libc::ioctl(listener, SECCOMP_IOCTL_NOTIF_ID_VALID, &req.id) } != 0 {
+        return true;
+    }
+    let path = path.to_string_lossy();
+    let seq = seqs.next(&path);
+    client.preflight(
+        method,
+        json!({ "pid": pid, "path": path, "path_seq": seq, "via": "seccomp" }),
+    )
+}
+
+fn proc_link(pid: u32, rel: &str) -> Option<PathBuf> {
+    std::fs::read_link(format!("/proc/{pid}/{rel}"))
+        .ok()
+        .filter(|p| p.is_absolute())
+}
+
+/// Read a NUL-terminated path from the target's memory, resolving it against
+/// the dirfd (or cwd) the kernel would use.
+fn read_path_arg(pid: u32, dirfd: Option<c_int>, addr: u64) -> Option<PathBuf> {
+    let raw = read_cstring(pid, addr)?;
+    let p = PathBuf::from(std::ffi::OsStr::from_bytes(raw.as_bytes()));
+    if p.is_absolute() {
+        return Some(p);
+    }
+    let base = match dirfd {
+        Some(fd) if fd != libc::AT_FDCWD => proc_link(pid, &format!("fd/{fd}"))?,
+        _ => proc_link(pid, "cwd")?,
+    };
+    Some(base.join(p))
+}
+
+fn read_cstring(pid: u32, addr: u64) -> Option<CString> {
+    use std::os::unix::fs::FileExt;
+
+    if addr == 0 {
+        return None;
+    }
+    let mem = std::fs::File::open(format!("/proc/{pid}/mem")).ok()?;
+    let mut out = Vec::with_capacity(128);
+    let mut chunk = [0u8; 256];
+    let mut off = addr;
+    while out.len() < libc::PATH_MAX as usize {
+        // Never read across a page boundary in one go: the string may end
+        // right before an unmapped page.
+        let room = (4096 - (off % 4096) as usize).min(chunk.len());
+        let n = mem.read_at(&mut chunk[..room], off).ok()?;
+        if n == 0 {
+            return None;
+        }
+        if let Some(pos) = chunk[..n].iter().position(|&b| b == 0) {
+            out.extend_from_slice(&chunk[..pos]);
+            return CString::new(out).ok();
+        }
+        out.extend_from_slice(&chunk[..n]);
+        off += n as u64;
+    }
+    None
+}
@@ -0,0 +1,88 @@
+#![cfg(target_os = "linux")]
+
+#[path = "../../tests/common/mod.rs"]
+mod common;
+
+use common::{MockServer, TempDir};
+use serde_json::Value;
+use std::path::Path;
+use std::process::Command;
+
+fn doctor(lib: &Path, sock: &Path) -> (Value, i32) {
+    let out = Command::new(env!("CARGO_BIN_EXE_shim-run"))
+        .args(["shim-doctor", "--json", "--lib"])
+        .arg(lib)
+        .env("NVIM_CLAUDE_SHIM_SOCK", sock)
+        .output()
+        .expect("spawn shim-run");
+    let report = serde_json::from_slice(&out.stdout).expect("one JSON report");
+    (report, out.status.code().unwrap_or(-1))
+}
+
+fn check<'a>(report: &'a Value, name: &str) -> &'a Value {
+    let checks = report["checks"].as_array().unwrap();
+    checks.iter().find(|c| c["name"] == name).unwrap()
+}
+
+#[test]
+fn the_built_library_passes_every_check() {
+    let server = MockServer::start();
+    let (report, code) = doctor(&common::shim_library(), &server.sock);
+    assert_eq!(code, 0, "{report}");
+    assert_eq!(report["ok"], true);
+    assert_eq!(report["image"]["format"], "elf");
+    for name in ["format", "arch", "load", "version", "socket"] {
+        assert_eq!(check(&report, name)["status"], "pass", "{report}");
+    }
+    // The library was loaded with the socket variables cleared.
+    assert_eq!(server.events(), Vec::<Value>::new());
+}
+
+/// An arm64-only dylib as a universal build would wrap it, built for
+/// macOS 14.
+fn mach_o() -> Vec<u8> {
+    let id = b"@rpath/libnvimclaude_shim.dylib\0\0\0\0\0\0\0\0\0";
+    let le = |v: u32| v.to_le_bytes();
+    let mut thin = Vec::new();
+    let build_version = [le(0x32), le(24), le(1), le(14 << 16), le(14 << 16), le(0)];
+    let dylib_id = [
+        le(0xd),
+        le(24 + id.len() as u32),
+        le(24),
+        le(0),
+        le(0),
+        le(0),
+    ];
+    let sizeofcmds = 24 + 24 + id.len() as u32;
+    for word in [0xfeed_facf, 0x0100_000c, 0, 6, 2, sizeofcmds, 0, 0] {
+        thin.extend(le(word));
+    }
+    thin.extend(build_version.concat());
+    thin.extend(dylib_id.concat());
+    thin.extend(id);
+    let mut fat = Vec::new();
+    for word in [0xcafe_babe, 1, 0x0100_000c, 0, 28, thin.len() as u32, 0] {
+        fat.extend(u32::to_be_bytes(word));
+    }
+    fat.extend(thin);
+    fat
+}
+
+#[test]
+fn a_mach_o_library_is_read_and_refused_with_a_fix() {
+    let dir = TempDir::new("doctor");
+    let lib = dir.join("libnvimclaude_shim.dylib");
+    std::fs::write(&lib, mach_o()).unwrap();
+    let (report, code) = doctor(&lib, &dir.join("none.sock"));
+    assert_eq!(code, 1);
+    assert_eq!(report["ok"], false);
+    let slices = &report["image"]["slices"];
+    assert_eq!(slices[0]["arch"], "arm64");
+    assert_eq!(slices[0]["minos"], "14.0");
+    assert_eq!(slices[0]["id"], "@rpath/libnvimclaude_shim.dylib");
+    let format = check(&report, "format");
+    assert_eq!(format["status"], "fail");
+    assert!(format["fix"].as_str().unwrap().contains("xtask dist"));
+    assert_eq!(check(&report, "load")["status"], "skip");
+    assert_eq!(check(&report, "socket")["status"], "fail");
+}
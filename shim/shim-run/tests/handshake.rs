@@ -0,0 +1,68 @@
+#![cfg(target_os = "linux")]
+
+#[path = "../../tests/common/mod.rs"]
+mod common;
+
+use common::MockServer;
+use serde_json::Value;
+use std::path::Path;
+use std::process::{Command, Output};
+use std::time::{Duration, Instant};
+
+fn launch(server: &MockServer, lib: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_shim-run"))
+        .arg("--lib")
+        .arg(lib)
+        .arg("--")
+        .args(args)
+        .env("NVIM_CLAUDE_SHIM_SOCK", &server.sock)
+        .env("NVIM_CLAUDE_SHIM_HANDSHAKE_MS", "500")
+        .output()
+        .expect("spawn shim-run")
+}
+
+/// The watcher's report, which may land just after the command's output
+/// is collected.
+fn not_loaded(server: &MockServer) -> Vec<Value> {
+    let deadline = Instant::now() + Duration::from_secs(2);
+    loop {
+        let found = server.params("shim/not_loaded");
+        if !found.is_empty() || Instant::now() > deadline {
+            return found;
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn a_library_that_never_loads_is_reported() {
+    let server = MockServer::start();
+    let out = launch(
+        &server,
+        Path::new("/nonexistent/libnvimclaude_shim.so"),
+        &["true"],
+    );
+    assert!(out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("never loaded into"), "{stderr}");
+    let reports = not_loaded(&server);
+    assert_eq!(reports.len(), 1, "{reports:?}");
+    assert_eq!(reports[0]["reason"], "closed");
+    assert!(reports[0]["exe"].as_str().unwrap().ends_with("/true"));
+    assert_eq!(reports[0]["setid"], false);
+
+    let out = launch(&server, Path::new("/nonexistent.so"), &["sleep", "1"]);
+    assert!(String::from_utf8_lossy(&out.stderr).contains("no handshake in"));
+    let reports = not_loaded(&server);
+    assert_eq!(reports.last().unwrap()["reason"], "timeout");
+}
+
+#[test]
+fn a_loaded_library_answers_the_handshake() {
+    let server = MockServer::start();
+    let out = launch(&server, &common::shim_library(), &["sh", "-c", "sleep 0.7"]);
+    assert!(out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(!stderr.contains("never loaded"), "{stderr}");
+    assert_eq!(not_loaded(&server), Vec::<Value>::new());
+}
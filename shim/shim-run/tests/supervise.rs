@@ -0,0 +1,134 @@
+#![cfg(target_os = "linux")]
+
+#[path = "../../tests/common/mod.rs"]
+mod common;
+
+use common::MockServer;
+use std::process::{Command, Output};
+
+#[test]
+fn fixture() {
+    common::fixture_entry();
+}
+
+fn supervised(server: &MockServer, args: &[&str]) -> Option<Output> {
+    supervised_with_env(server, args, &[])
+}
+
+fn supervised_with_env(server: &MockServer, args: &[&str], env: &[(&str, &str)]) -> Option<Output> {
+    let out = Command::new(env!("CARGO_BIN_EXE_shim-run"))
+        .args(["--supervise", "--no-preload", "--"])
+        .args(args)
+        .env("NVIM_CLAUDE_SHIM_SOCK", &server.sock)
+        .envs(env.iter().copied())
+        .output()
+        .expect("spawn shim-run");
+    if String::from_utf8_lossy(&out.stderr).contains("seccomp unavailable") {
+        eprintln!("skipping: seccomp user notification not available here");
+        return None;
+    }
+    Some(out)
+}
+
+#[test]
+fn raw_unlink_is_preflighted() {
+    let server = MockServer::start();
+    let file = server.dir.join("gone.txt");
+    std::fs::write(&file, "x").unwrap();
+    let Some(out) = supervised(&server, &["rm", file.to_str().unwrap()]) else {
+        return;
+    };
+    assert!(
+        out.status.success(),
+        "{}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+    assert!(!file.exists());
+    assert_eq!(
+        server.ops(),
+        [("pre_delete".to_string(), file.to_string_lossy().to_string())]
+    );
+}
+
+#[test]
+fn denied_unlink_fails_in_the_child() {
+    let server = MockServer::with_denied(&["pre_delete"]);
+    let file = server.dir.join("kept.txt");
+    std::fs::write(&file, "x").unwrap();
+    let Some(out) = supervised(&server, &["rm", "-f", file.to_str().unwrap()]) else {
+        return;
+    };
+    assert!(!out.status.success());
+    assert!(file.exists());
+}
+
+#[test]
+fn truncating_open_of_existing_file_is_preflighted() {
+    let server = MockServer::with_denied(&["pre_truncate"]);
+    let file = server.dir.join("t.txt");
+    std::fs::write(&file, "keep me").unwrap();
+    let script = format!("echo clobber > '{}'", file.display());
+    let Some(out) = supervised(&server, &["sh", "-c", &script]) else {
+        return;
+    };
+    assert!(!out.status.success());
+    assert_eq!(std::fs::read_to_string(&file).unwrap(), "keep me");
+
+    // New files aren't truncations and go straight through.
+    let fresh = server.dir.join("fresh.txt");
+    let script = format!("echo hi > '{}'", fresh.display());
+    let out = supervised(&server, &["sh", "-c", &script]).unwrap();
+    assert!(out.status.success());
+    assert_eq!(std::fs::read_to_string(&fresh).unwrap(), "hi\n");
+}
+
+#[test]
+fn a_job_left_running_is_still_supervised() {
+    let server = MockServer::start();
+    let file = server.dir.join("late.txt");
+    std::fs::write(&file, "x").unwrap();
+    // The shell exits at once; the job unlinks after it's gone.
+    let script = format!("(sleep 0.3; rm '{}') & exit 0", file.display());
+    let Some(out) = supervised(&server, &["sh", "-c", &script]) else {
+        return;
+    };
+    assert!(out.status.success());
+    assert!(!file.exists(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(
+        server.ops(),
+        [("pre_delete".to_string(), file.to_string_lossy().to_string())]
+    );
+}
+
+#[test]
+fn a_path_the_supervisor_cannot_read_takes_the_fail_policy() {
+    let server = MockServer::start();
+    let me = std::env::current_exe().unwrap();
+    let fixture = [
+        me.to_str().unwrap(),
+        "fixture",
+        "--exact",
+        "--nocapture",
+        "--test-threads=1",
+    ];
+    // Address 1: never mapped, so neither we nor the kernel can read it.
+    let errno = |closed: &str| {
+        let env = [
+            ("SHIM_FIXTURE_OPS", "rawunlink\t1"),
+            ("FS_SHIM_FAIL_CLOSED", closed),
+        ];
+        let out = supervised_with_env(&server, &fixture, &env)?;
+        let stdout = String::from_utf8_lossy(&out.stdout).into_owned();
+        let result = stdout
+            .lines()
+            .find_map(|l| l.rsplit_once("fixture: ").map(|(_, r)| r.to_string()));
+        Some(result.unwrap_or(stdout))
+    };
+    let Some(closed) = errno("1") else {
+        return;
+    };
+    assert_eq!(closed, format!("err {}", libc::EPERM));
+    // Failing open, the kernel gets the call and finds the pointer bad.
+    assert_eq!(errno("0").unwrap(), format!("err {}", libc::EFAULT));
+    assert_eq!(server.ops(), []);
+}
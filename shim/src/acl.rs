@@ -0,0 +1,77 @@
+//! ACL changes.
+//!
+//! An ACL decides who may modify a file as much as its mode does, and a
+//! script that grants or takes away access (`setfacl`, `chmod +a` on
+//! macOS) should at least be seen. So `acl_set_file`, `acl_set_fd` and,
+//! on macOS, `acl_set_link_np` are hooked, and each success is reported
+//! as
+//!
+//! ```json
+//! { "method": "post_acl",
+//!   "params": { "path": "/p/a.rs", "acl": "user::rw-\ngroup::r--\nother::r--\n" } }
+//! ```
+//!
+//! `acl` is the ACL as `acl_to_text` renders it, cut to `MAX_TEXT` bytes
+//! (then with `"acl_truncated": true`), or `null` when it can't be
+//! rendered. A file `acl_set_fd` can't find a path for is named as in
+//! `inodes`. `acl_mode = "block"` asks first, with a `pre_acl`
+//! preflight; `"off"` reports nothing. On Linux the calls live in
+//! libacl, not libc, so the originals (and `acl_to_text`) are those of
+//! whichever libacl the host loaded.
+
+use std::os::raw::c_void;
+
+use serde_json::{json, Value};
+
+use crate::platform;
+
+const MAX_TEXT: usize = 4096;
+
+/// The `post_acl` params for setting `acl` on the file `params` names.
+pub(crate) fn params(mut params: Value, acl: *mut c_void) -> Value {
+    let text = if acl.is_null() {
+        None
+    } else {
+        unsafe { platform::acl_text(acl) }
+    };
+    let Some(text) = text else {
+        params["acl"] = Value::Null;
+        return params;
+    };
+    let (text, truncated) = cut(&text);
+    params["acl"] = json!(text);
+    if truncated {
+        params["acl_truncated"] = json!(true);
+    }
+    params
+}
+
+/// `text` cut to `MAX_TEXT` bytes on a char boundary, and whether it was.
+fn cut(text: &str) -> (&str, bool) {
+    if text.len() <= MAX_TEXT {
+        return (text, false);
+    }
+    let mut end = MAX_TEXT;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    (&text[..end], true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_acls_are_cut_on_a_char_boundary() {
+        assert_eq!(cut("user::rw-\n"), ("user::rw-\n", false));
+        let long = format!("{}é", "a".repeat(MAX_TEXT - 1));
+        let (text, truncated) = cut(&long);
+        assert!(truncated);
+        assert_eq!(text.len(), MAX_TEXT - 1);
+        assert_eq!(
+            params(json!({ "path": "/p/a.rs" }), std::ptr::null_mut())["acl"],
+            Value::Null
+        );
+    }
+}
@@ -0,0 +1,120 @@
+//! What happens to a path soon after something on it was denied.
+//!
+//! Refused an unlink, a tool may try something else to the same effect:
+//! truncate the file to nothing, rename it into a trash directory, or
+//! overwrite it with an empty one. Those can fall under other policies, and
+//! the server would see each as unrelated. So every denial is remembered
+//! for `WINDOW`, and any preflight or post on the same path meanwhile
+//! (as `path`, or as `old_path` for the source of a rename) carries
+//!
+//! ```json
+//! "after_denied": { "op": "pre_delete", "op_id": 42, "ms_ago": 180 }
+//! ```
+//!
+//! `op_id` is the denied preflight's `path_seq`, absent when it never went
+//! out (over the blocking budget). At most `CAP` denials are kept, the
+//! oldest dropped first; a path's newest denial replaces its older one.
+
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+
+use crate::{config, paths};
+
+const WINDOW: Duration = Duration::from_secs(30);
+const CAP: usize = 256;
+
+struct Denied {
+    key: PathBuf,
+    op: String,
+    op_id: Option<u64>,
+    at: Instant,
+}
+
+static RECENT: Mutex<VecDeque<Denied>> = parking_lot::const_mutex(VecDeque::new());
+/// Whether anything may still be in the window, so the common case takes
+/// no lock.
+static ANY: AtomicBool = AtomicBool::new(false);
+
+fn key(path: &Path) -> PathBuf {
+    let cfg = config::get();
+    let path = paths::for_matching(path, cfg.normalize_unicode);
+    if cfg.case_insensitive {
+        PathBuf::from(path.to_string_lossy().to_lowercase())
+    } else {
+        path.into_owned()
+    }
+}
+
+/// `op` on `path` was just denied; `op_id` is its `path_seq`.
+pub(crate) fn record(op: &str, path: &Path, op_id: Option<u64>) {
+    let key = key(path);
+    let mut recent = RECENT.lock();
+    recent.retain(|d| d.key != key && d.at.elapsed() < WINDOW);
+    if recent.len() >= CAP {
+        recent.pop_front();
+    }
+    recent.push_back(Denied {
+        key,
+        op: op.to_string(),
+        op_id,
+        at: Instant::now(),
+    });
+    ANY.store(true, Ordering::Relaxed);
+}
+
+/// Add `after_denied` to an event's `params` if its path was denied
+/// something within the window.
+pub(crate) fn annotate(params: &mut Value) {
+    if !ANY.load(Ordering::Relaxed) {
+        return;
+    }
+    let keys: Vec<PathBuf> = ["path", "old_path"]
+        .iter()
+        .filter_map(|k| params[k].as_str())
+        .map(|p| key(Path::new(p)))
+        .collect();
+    let mut recent = RECENT.lock();
+    while recent.front().is_some_and(|d| d.at.elapsed() >= WINDOW) {
+        recent.pop_front();
+    }
+    ANY.store(!recent.is_empty(), Ordering::Relaxed);
+    let Some(d) = recent.iter().rev().find(|d| keys.contains(&d.key)) else {
+        return;
+    };
+    let mut after = json!({ "op": d.op, "ms_ago": d.at.elapsed().as_millis() as u64 });
+    if let Some(id) = d.op_id {
+        after["op_id"] = json!(id);
+    }
+    params["after_denied"] = after;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn later_events_on_a_denied_path_say_so() {
+        record("pre_delete", Path::new("/w/keep.rs"), Some(42));
+        let mut trash = json!({ "path": "/w/.Trash/keep.rs", "old_path": "/w/keep.rs" });
+        annotate(&mut trash);
+        assert_eq!(trash["after_denied"]["op"], "pre_delete");
+        assert_eq!(trash["after_denied"]["op_id"], 42);
+        assert!(trash["after_denied"]["ms_ago"].as_u64().unwrap() < 1000);
+
+        let mut other = json!({ "path": "/w/other.rs" });
+        annotate(&mut other);
+        assert!(other.get("after_denied").is_none());
+
+        // The newest denial of a path stands for it.
+        record("pre_truncate", Path::new("/w/keep.rs"), None);
+        let mut again = json!({ "path": "/w/keep.rs" });
+        annotate(&mut again);
+        assert_eq!(again["after_denied"]["op"], "pre_truncate");
+        assert!(again["after_denied"].get("op_id").is_none());
+    }
+}
@@ -0,0 +1,120 @@
+//! Recently granted preflights, so a burst of the same operation on one
+//! path asks the server once. Only allows are kept, each for
+//! `allow_cache_ms` (0, the default, turns the cache off). The server can
+//! drop everything with `shim/invalidate_cache`, or some paths with
+//! `shim/invalidate`. Entries count against the `heap` cap; refused room,
+//! the cache empties and starts again. A new process may start with
+//! entries the server hands it, each for its own TTL; see `session_cache`.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+
+use crate::{config, heap};
+
+/// Entries kept before expired ones are swept (and, if that isn't enough,
+/// everything is dropped).
+const CAP: usize = 4096;
+
+type Key = (String, PathBuf);
+
+/// Each allow with the instant it expires.
+static CACHE: Lazy<Mutex<HashMap<Key, Instant>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// What an entry is charged against the heap cap.
+fn cost((op, path): &Key) -> usize {
+    op.len() + path.as_os_str().len() + heap::ENTRY
+}
+
+/// `HashMap::retain`, giving back what the dropped entries were charged.
+fn retain_charged(cache: &mut HashMap<Key, Instant>, mut keep: impl FnMut(&Key, &Instant) -> bool) {
+    cache.retain(|k, until| {
+        let kept = keep(k, until);
+        if !kept {
+            heap::release(cost(k));
+        }
+        kept
+    });
+}
+
+fn ttl() -> Option<Duration> {
+    Some(config::get().allow_cache_ms)
+        .filter(|&ms| ms > 0)
+        .map(Duration::from_millis)
+}
+
+/// Whether the cache is on at all.
+pub(crate) fn enabled() -> bool {
+    ttl().is_some()
+}
+
+/// Whether `op` on `path` was allowed within its TTL.
+pub(crate) fn hit(op: &str, path: &Path) -> bool {
+    if !enabled() {
+        return false;
+    }
+    CACHE
+        .lock()
+        .get(&(op.to_string(), path.to_path_buf()))
+        .is_some_and(|&until| Instant::now() < until)
+}
+
+/// Remember that the server allowed `op` on `path`.
+pub(crate) fn insert(op: &str, path: &Path) {
+    if let Some(ttl) = ttl() {
+        insert_for(op, path, ttl);
+    }
+}
+
+/// Remember an allow of `op` on `path` the server handed out ahead of the
+/// preflight, for `ttl`.
+pub(crate) fn seed(op: &str, path: &Path, ttl: Duration) {
+    if enabled() {
+        insert_for(op, path, ttl);
+    }
+}
+
+fn insert_for(op: &str, path: &Path, ttl: Duration) {
+    let key = (op.to_string(), path.to_path_buf());
+    let now = Instant::now();
+    let Some(until) = now.checked_add(ttl) else {
+        return;
+    };
+    let mut cache = CACHE.lock();
+    if cache.len() >= CAP {
+        retain_charged(&mut cache, |_, &until| now < until);
+        if cache.len() >= CAP {
+            retain_charged(&mut cache, |_, _| false);
+        }
+    }
+    if let Some(at) = cache.get_mut(&key) {
+        *at = until;
+        return;
+    }
+    if !heap::charge(cost(&key)) {
+        retain_charged(&mut cache, |_, _| false);
+        if !heap::charge(cost(&key)) {
+            return;
+        }
+    }
+    cache.insert(key, until);
+}
+
+/// Forget the allows for paths `select` picks; returns how many.
+pub(crate) fn evict(mut select: impl FnMut(&Path) -> bool) -> usize {
+    let mut cache = CACHE.lock();
+    let before = cache.len();
+    retain_charged(&mut cache, |(_, path), _| !select(path));
+    before - cache.len()
+}
+
+/// Forget every allow; returns how many there were.
+pub(crate) fn clear() -> usize {
+    let mut cache = CACHE.lock();
+    let n = cache.len();
+    retain_charged(&mut cache, |_, _| false);
+    n
+}
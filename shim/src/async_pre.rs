@@ -0,0 +1,118 @@
+//! Preflights that don't wait.
+//!
+//! A write on an `O_NONBLOCK` fd is usually made from an event loop that
+//! must not stall for a preflight timeout. For those fds the question is
+//! sent without waiting, and the write that raised it is allowed at once
+//! (`"allowed_by": "optimistic"`). The answer is read whenever that thread's
+//! connection is next used, and before each later write on the fd. A
+//! denial makes those writes fail with `EPERM`; an allowal is cached like
+//! a blocking one. Failing closed, writes get `EAGAIN` until the answer
+//! comes, and `EPERM` once the preflight timeout passes without one.
+//! Answers for fds that have since been closed are dropped.
+//!
+//! `nonblocking_preflight = "block"` keeps such fds on the blocking path.
+//!
+//! On a reactor thread (see `thread_info`) every preflight is sent this
+//! way. For one that isn't about an fd's write, nothing later can honor a
+//! denial: the call is allowed (denied, failing closed) and only an
+//! allowal is kept, in the cache.
+
+use std::collections::HashMap;
+use std::os::unix::prelude::RawFd;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde_json::Value;
+
+/// Questions outstanding at once; past this the oldest answer is ignored.
+const CAP: usize = 256;
+
+struct Pending {
+    fd: Option<RawFd>,
+    op: String,
+    path: PathBuf,
+    seq: Option<u64>,
+}
+
+static PENDING: Lazy<Mutex<HashMap<u32, Pending>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+/// `PENDING.len()`, readable without the lock on every write.
+static OUTSTANDING: AtomicUsize = AtomicUsize::new(0);
+
+/// Request `id` asked about `op` on `path`, for `fd`'s first write if
+/// it names one.
+pub(crate) fn asked(id: u32, fd: Option<RawFd>, op: &str, path: PathBuf, seq: Option<u64>) {
+    let mut p = PENDING.lock();
+    if p.len() >= CAP {
+        if let Some(&oldest) = p.keys().min() {
+            p.remove(&oldest);
+        }
+    }
+    p.insert(
+        id,
+        Pending {
+            fd,
+            op: op.to_string(),
+            path,
+            seq,
+        },
+    );
+    OUTSTANDING.store(p.len(), Ordering::Relaxed);
+}
+
+/// Whether any answer is still due, so writes know to look for it.
+pub(crate) fn outstanding() -> bool {
+    OUTSTANDING.load(Ordering::Relaxed) > 0
+}
+
+/// What an answer to one of our questions said, for the caller to apply.
+pub(crate) struct Answer {
+    pub id: u32,
+    pub fd: Option<RawFd>,
+    pub op: String,
+    pub path: PathBuf,
+    /// The request's `path_seq`.
+    pub seq: Option<u64>,
+    /// `None` for an error or an answer that isn't a verdict, which get
+    /// the fail policy.
+    pub allow: Option<bool>,
+}
+
+/// `id`'s answer, if it was one of ours.
+pub(crate) fn answered(id: &Value, result: Result<Value, Value>) -> Option<Answer> {
+    let id = u32::try_from(id.as_u64()?).ok()?;
+    let pending = {
+        let mut p = PENDING.lock();
+        let pending = p.remove(&id)?;
+        OUTSTANDING.store(p.len(), Ordering::Relaxed);
+        pending
+    };
+    let allow = result
+        .ok()
+        .and_then(|r| r.as_bool().or_else(|| r.get("allow")?.as_bool()));
+    Some(Answer {
+        id,
+        fd: pending.fd,
+        op: pending.op,
+        path: pending.path,
+        seq: pending.seq,
+        allow,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn only_our_ids_are_answered_once() {
+        asked(900_001, Some(5), "pre_modify", PathBuf::from("/a"), None);
+        assert!(outstanding());
+        assert!(answered(&json!(900_002), Ok(json!(true))).is_none());
+        let a = answered(&json!(900_001), Ok(json!({ "allow": false }))).unwrap();
+        assert_eq!((a.fd, a.allow), (Some(5), Some(false)));
+        assert!(answered(&json!(900_001), Ok(json!(true))).is_none());
+    }
+}
@@ -0,0 +1,68 @@
+//! Changes summed into one report per window.
+//!
+//! `watch_dirs` and `touches` both turn a run of posts into one
+//! synthesized frame: the first change opens a window of some debounce,
+//! and the first one after it has passed (or a flush, or exit) takes what
+//! was summed. There is no timer thread. A batch keeps at most `FILES`
+//! distinct paths, within the `heap` cap; past that, each change counts
+//! as a path of its own.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::heap;
+
+const FILES: usize = 1024;
+
+#[derive(Default)]
+pub(crate) struct Batch {
+    files: HashSet<PathBuf>,
+    /// Changes counted past `FILES` or the heap cap.
+    more: u64,
+    /// When the first change not yet reported was.
+    since: Option<Instant>,
+}
+
+fn cost(path: &Path) -> usize {
+    path.as_os_str().len() + heap::ENTRY
+}
+
+impl Batch {
+    /// Count a change to `path` at `now`. True once `debounce` has passed
+    /// since the first change not yet taken.
+    pub(crate) fn add(&mut self, path: &Path, debounce: Duration, now: Instant) -> bool {
+        if !self.files.contains(path) {
+            if self.files.len() < FILES && heap::charge(cost(path)) {
+                self.files.insert(path.to_path_buf());
+            } else {
+                self.more += 1;
+            }
+        }
+        let since = *self.since.get_or_insert(now);
+        now.saturating_duration_since(since) >= debounce
+    }
+
+    /// Whether anything has been counted since the last `take`.
+    pub(crate) fn pending(&self) -> bool {
+        self.since.is_some()
+    }
+
+    /// How many paths changed, and those kept, sorted; the batch starts
+    /// afresh.
+    pub(crate) fn take(&mut self) -> (u64, Vec<PathBuf>) {
+        let count = self.files.len() as u64 + self.more;
+        let mut files: Vec<PathBuf> = self.files.drain().collect();
+        files.iter().for_each(|f| heap::release(cost(f)));
+        files.sort();
+        self.more = 0;
+        self.since = None;
+        (count, files)
+    }
+}
+
+impl Drop for Batch {
+    fn drop(&mut self) {
+        self.files.iter().for_each(|f| heap::release(cost(f)));
+    }
+}
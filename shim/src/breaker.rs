@@ -0,0 +1,151 @@
+//! Destinations that stopped reading.
+//!
+//! Control sockets block, so a server that stops reading fills the socket
+//! buffer, and the next post would block the traced process's `close`
+//! for as long as the server stays wedged. Every control socket gets a
+//! send timeout (`SO_SNDTIMEO`) of `send_timeout_ms`, or its
+//! `[[destination]]`'s own (0: none), and a send that runs into it is a
+//! transport error: the frame is lost (on a reliable connection, replayed
+//! with the rest) and the connection replaced before its next use.
+//!
+//! A fresh connection has an empty buffer again, so timeouts come one per
+//! buffer's worth. `STRIKES` of them within `WINDOW` trip the
+//! destination's breaker, and for `COOL_DOWN` its posts are counted and
+//! dropped without touching the socket; the first to go out after that
+//! is preceded by `shim/dropped` with the count. Preflights are sent
+//! regardless, with their own deadline. `shim/stats` reports the send
+//! timeouts and the posts dropped as `"send"`.
+
+use std::collections::VecDeque;
+use std::os::unix::prelude::RawFd;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+
+const STRIKES: usize = 3;
+const WINDOW: Duration = Duration::from_secs(60);
+const COOL_DOWN: Duration = Duration::from_secs(30);
+
+static TIMEOUTS: AtomicU64 = AtomicU64::new(0);
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Default)]
+struct State {
+    /// When the recent send timeouts were, oldest first.
+    strikes: VecDeque<Instant>,
+    open_until: Option<Instant>,
+    /// Posts refused since one was last let through.
+    dropped: u64,
+}
+
+/// One destination's send timeout and breaker.
+pub(crate) struct Breaker {
+    timeout: Option<Duration>,
+    state: Mutex<State>,
+}
+
+impl Breaker {
+    pub(crate) fn new(timeout: Option<Duration>) -> Breaker {
+        Breaker {
+            timeout,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Give a connection's socket this destination's send timeout.
+    pub(crate) fn arm(&self, fd: RawFd) {
+        let Some(t) = self.timeout else {
+            return;
+        };
+        let tv = libc::timeval {
+            tv_sec: t.as_secs() as libc::time_t,
+            tv_usec: t.subsec_micros().max(1) as libc::suseconds_t,
+        };
+        unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_SNDTIMEO,
+                &tv as *const libc::timeval as *const libc::c_void,
+                std::mem::size_of::<libc::timeval>() as libc::socklen_t,
+            );
+        }
+    }
+
+    /// Whether a post may be sent at `now`. A refused one is counted.
+    pub(crate) fn admits(&self, now: Instant) -> bool {
+        let mut st = self.state.lock();
+        match st.open_until {
+            Some(t) if now < t => {
+                st.dropped += 1;
+                DROPPED.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+            Some(_) => {
+                st.open_until = None;
+                true
+            }
+            None => true,
+        }
+    }
+
+    /// How many posts were refused since the last call, for the
+    /// `shim/dropped` that goes out ahead of the next one.
+    pub(crate) fn take_dropped(&self) -> u64 {
+        std::mem::take(&mut self.state.lock().dropped)
+    }
+
+    /// A send through this destination ran into its timeout at `now`.
+    /// True when that trips the breaker.
+    pub(crate) fn timed_out(&self, now: Instant) -> bool {
+        TIMEOUTS.fetch_add(1, Ordering::Relaxed);
+        let mut st = self.state.lock();
+        while st
+            .strikes
+            .front()
+            .is_some_and(|&t| now.saturating_duration_since(t) > WINDOW)
+        {
+            st.strikes.pop_front();
+        }
+        st.strikes.push_back(now);
+        if st.strikes.len() < STRIKES {
+            return false;
+        }
+        st.strikes.clear();
+        st.open_until = Some(now + COOL_DOWN);
+        true
+    }
+}
+
+/// For `shim/stats`.
+pub(crate) fn snapshot() -> Value {
+    json!({
+        "timeouts": TIMEOUTS.load(Ordering::Relaxed),
+        "dropped": DROPPED.load(Ordering::Relaxed),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeated_send_timeouts_trip_the_breaker_for_a_while() {
+        let b = Breaker::new(Some(Duration::from_millis(100)));
+        let t0 = Instant::now();
+        assert!(!b.timed_out(t0));
+        // Too long ago to count with the next two.
+        let t1 = t0 + WINDOW + Duration::from_secs(1);
+        assert!(!b.timed_out(t1));
+        assert!(!b.timed_out(t1));
+        assert!(b.admits(t1));
+        assert!(b.timed_out(t1 + Duration::from_secs(1)));
+        assert!(!b.admits(t1 + Duration::from_secs(2)));
+        assert!(!b.admits(t1 + Duration::from_secs(3)));
+        assert_eq!(b.take_dropped(), 2);
+        assert!(b.admits(t1 + COOL_DOWN + Duration::from_secs(2)));
+        assert_eq!(b.take_dropped(), 0);
+    }
+}
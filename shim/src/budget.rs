@@ -0,0 +1,147 @@
+//! A cap on how long one process spends blocked in preflights.
+//!
+//! A server that stops answering costs every preflight the full timeout,
+//! and with many threads writing that adds up to minutes of a stalled
+//! build. Time spent waiting on preflight answers is summed, across
+//! threads, over a sliding `WINDOW`. Once it passes `block_budget_ms`,
+//! preflights are no longer sent: each gets the fail policy at once and is
+//! counted as skipped, and `shim/budget_exceeded` goes out. They are sent
+//! again once enough of the window has drained. `shim/stats` reports the
+//! accounting.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+
+use crate::config;
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Blocked time per whole second since `epoch`, oldest first.
+struct Budget {
+    epoch: Instant,
+    slots: VecDeque<(u64, Duration)>,
+    exceeded: bool,
+    skipped: u64,
+}
+
+static BUDGET: Lazy<Mutex<Budget>> = Lazy::new(|| Mutex::new(Budget::new(Instant::now())));
+
+impl Budget {
+    fn new(epoch: Instant) -> Budget {
+        Budget {
+            epoch,
+            slots: VecDeque::new(),
+            exceeded: false,
+            skipped: 0,
+        }
+    }
+
+    fn second(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.epoch).as_secs()
+    }
+
+    /// Blocked time within the window ending at `now`.
+    fn spent(&mut self, now: Instant) -> Duration {
+        let oldest = self.second(now).saturating_sub(WINDOW.as_secs() - 1);
+        while self.slots.front().is_some_and(|&(s, _)| s < oldest) {
+            self.slots.pop_front();
+        }
+        self.slots.iter().map(|&(_, d)| d).sum()
+    }
+
+    /// Whether a preflight may be sent now. A refused one is counted.
+    fn admits(&mut self, now: Instant, limit: Duration) -> bool {
+        if self.exceeded && self.spent(now) <= limit {
+            self.exceeded = false;
+        }
+        if self.exceeded {
+            self.skipped += 1;
+        }
+        !self.exceeded
+    }
+
+    /// Count `blocked`, ending at `now`. True when this took the window
+    /// over `limit`.
+    fn record(&mut self, now: Instant, blocked: Duration, limit: Duration) -> bool {
+        let sec = self.second(now);
+        match self.slots.back_mut() {
+            Some((s, d)) if *s == sec => *d += blocked,
+            _ => self.slots.push_back((sec, blocked)),
+        }
+        let crossed = !self.exceeded && self.spent(now) > limit;
+        self.exceeded |= crossed;
+        crossed
+    }
+}
+
+/// The configured budget; `None` when it's off.
+fn limit() -> Option<Duration> {
+    match config::get().block_budget_ms {
+        0 => None,
+        ms => Some(Duration::from_millis(ms)),
+    }
+}
+
+/// Whether the next preflight may be sent, or should take the fail policy
+/// without asking.
+pub(crate) fn admits() -> bool {
+    limit().is_none_or(|limit| BUDGET.lock().admits(Instant::now(), limit))
+}
+
+/// Count time spent waiting on one preflight. True when the budget just
+/// ran out, for the caller to announce.
+pub(crate) fn record(blocked: Duration) -> bool {
+    let limit = limit().unwrap_or(Duration::MAX);
+    BUDGET.lock().record(Instant::now(), blocked, limit)
+}
+
+/// `shim/budget_exceeded` params.
+pub(crate) fn exceeded_params() -> Value {
+    let mut params = snapshot();
+    params["pid"] = json!(unsafe { libc::getpid() });
+    params
+}
+
+/// The accounting, for `shim/stats`.
+pub(crate) fn snapshot() -> Value {
+    let mut b = BUDGET.lock();
+    let spent = b.spent(Instant::now());
+    json!({
+        "blocked_ms": spent.as_millis() as u64,
+        "budget_ms": config::get().block_budget_ms,
+        "window_ms": WINDOW.as_millis() as u64,
+        "exceeded": b.exceeded,
+        "skipped": b.skipped,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn preflights_stop_until_the_window_drains() {
+        let t0 = Instant::now();
+        let at = |s: u64| t0 + Duration::from_secs(s);
+        let limit = Duration::from_secs(3);
+        let mut b = Budget::new(t0);
+
+        assert!(!b.record(at(0), Duration::from_secs(2), limit));
+        assert!(b.admits(at(1), limit));
+        assert!(b.record(at(1), Duration::from_secs(2), limit));
+        // Announced once, however long it stays exceeded.
+        assert!(!b.record(at(2), Duration::from_secs(1), limit));
+        assert!(!b.admits(at(2), limit));
+        assert!(!b.admits(at(59), limit));
+        assert_eq!(b.skipped, 2);
+
+        // The first two seconds have left the window: 1 s remains.
+        assert!(b.admits(at(61), limit));
+        assert_eq!(b.spent(at(61)), Duration::from_secs(1));
+        assert_eq!(b.spent(at(62)), Duration::ZERO);
+    }
+}
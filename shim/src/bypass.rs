@@ -0,0 +1,44 @@
+//! Processes the shim leaves entirely alone.
+//!
+//! Some programs are both harmless and very hot: the linker, `git`'s
+//! helpers, Spotlight's `mdworker` when it inherits the environment.
+//! Ignore globs would still cost them a table lookup and a path check on
+//! every write. `bypass_processes` names them instead, as globs on the
+//! executable: one without a `/` matches its file name, one with a `/`
+//! the whole path. That path is the one the kernel reports
+//! (`/proc/self/exe`, `_NSGetExecutablePath`), so on Linux symlinks are
+//! resolved and an `ld` linked to `ld.bfd` needs `ld*`.
+//!
+//! It is decided once, in the library constructor. A bypassed process
+//! skips the rest of init, and each hook goes straight to the original
+//! after one relaxed load: no context, no fd table, no destination.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{config, glob};
+
+static ON: AtomicBool = AtomicBool::new(false);
+
+/// Whether this process is bypassed.
+#[inline]
+pub(crate) fn on() -> bool {
+    ON.load(Ordering::Relaxed)
+}
+
+/// Decide, from the library constructor, whether this process is
+/// bypassed. Returns the answer.
+pub(crate) fn decide() -> bool {
+    let cfg = config::get();
+    if cfg.bypass_processes.is_empty() {
+        return false;
+    }
+    let Ok(exe) = std::env::current_exe() else {
+        return false;
+    };
+    let hit = cfg
+        .bypass_processes
+        .iter()
+        .any(|g| glob::matches(g, &exe, cfg.case_insensitive));
+    ON.store(hit, Ordering::Relaxed);
+    hit
+}
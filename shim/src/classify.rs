@@ -0,0 +1,275 @@
+//! What the shim makes of a path, decided without doing anything.
+//!
+//! Every preflight goes through the same steps before anything is asked:
+//! the roots, `ignore` globs, the server's own paths and a class that is
+//! `off` keep a path out; a supervisor asks about deletes, renames and
+//! truncates itself; a `notify` class or a path under `watch_dirs` is
+//! only reported; a cached allow answers (unless the path is open in a
+//! modified buffer or its class is `block`); and what's left is asked
+//! about. `decide` is those steps, given the config and the caches, and
+//! `preflight_params` acts on its verdict.
+//!
+//! The server can ask for the verdict itself, to warn about a glob that
+//! keeps back more than it should before an edit is attempted:
+//!
+//! ```json
+//! { "method": "shim/classify", "params": { "path": "/p/build/out.log" } }
+//! ```
+//!
+//! is answered with the path as preflights would name it, its class and
+//! the class's policy, and the outcome, with what decided it:
+//!
+//! ```json
+//! { "path": "/p/build/out.log", "op": "pre_modify", "class": "normal",
+//!   "class_policy": "default", "outcome": "ignore",
+//!   "ignored_by": { "glob": "build/**" } }
+//! ```
+//!
+//! `outcome` is `ignore` (with `ignored_by`, as `shim/ignored` has it),
+//! `notify` (with `watched`, the `watch_dirs` entry, when that is why),
+//! `allow` (with `allowed_by`: `supervised`, `cache` or `dir_cache`) or
+//! `ask`. `op` defaults to `pre_modify`. A relative path is taken from
+//! the process's working directory, and one that a symlink takes out of
+//! the project says so as `symlink_escape` (see `symlink_escape`).
+//! Nothing is created, opened for writing or sent.
+
+use std::path::Path;
+
+use serde_json::{json, Value};
+
+use crate::config::{ClassPolicy, IgnoredBy, ShimConfig, SymlinkEscape};
+use crate::path_class::{self, PathClass};
+use crate::{
+    allow_cache, dir_cache, ignore_stats, paths, session_cache, symlink_escape, AllowedBy,
+};
+
+/// The allows `decide` may find.
+pub(crate) trait Caches {
+    fn allowed(&self, op: &str, path: &Path) -> bool;
+    fn dir_allowed(&self, op: &str, path: &Path) -> bool;
+}
+
+/// The process's own allow and directory caches.
+pub(crate) struct Live;
+
+impl Caches for Live {
+    fn allowed(&self, op: &str, path: &Path) -> bool {
+        if session_cache::pending() {
+            // Connect now: the hello may bring this very allow.
+            let _ = crate::with_stream_for(path, |_| ());
+        }
+        allow_cache::hit(op, path)
+    }
+
+    fn dir_allowed(&self, op: &str, path: &Path) -> bool {
+        dir_cache::hit(op, path)
+    }
+}
+
+/// No allows at all, for when there is no server to have given any.
+pub(crate) struct Uncached;
+
+impl Caches for Uncached {
+    fn allowed(&self, _op: &str, _path: &Path) -> bool {
+        false
+    }
+
+    fn dir_allowed(&self, _op: &str, _path: &Path) -> bool {
+        false
+    }
+}
+
+/// What becomes of `op` on a path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Verdict {
+    /// Kept from the server altogether.
+    Ignored(IgnoredBy),
+    /// Asked about by the supervisor instead.
+    Supervised,
+    /// Of a `notify` class: reported, never asked about.
+    Notify,
+    /// Under the `watch_dirs` entry at this index: reported only.
+    Watched(usize),
+    /// Allowed by an earlier answer.
+    Cached(AllowedBy),
+    /// To be asked about.
+    Ask(PathClass),
+}
+
+/// The verdict on `op` on `path` under `cfg`; `supervised` when a
+/// supervisor preflights syscalls itself, `conflict` when the path is
+/// open in a modified buffer.
+pub(crate) fn decide(
+    op: &str,
+    path: &Path,
+    cfg: &ShimConfig,
+    supervised: bool,
+    conflict: bool,
+    caches: &dyn Caches,
+) -> Verdict {
+    if let Some(by) = cfg.ignored_by(path) {
+        return Verdict::Ignored(by);
+    }
+    if supervised && matches!(op, "pre_delete" | "pre_rename" | "pre_truncate") {
+        return Verdict::Supervised;
+    }
+    let (class, policy) = path_class::policy_with(cfg, path);
+    if policy == ClassPolicy::Notify {
+        return Verdict::Notify;
+    }
+    if let Some(i) = path_class::watched_with(cfg, path) {
+        return Verdict::Watched(i);
+    }
+    if !conflict && policy != ClassPolicy::Block {
+        if caches.allowed(op, path) {
+            return Verdict::Cached(AllowedBy::Cache);
+        }
+        if caches.dir_allowed(op, path) {
+            return Verdict::Cached(AllowedBy::DirCache);
+        }
+    }
+    Verdict::Ask(class)
+}
+
+/// The answer to a `shim/classify` for `op` on `path`.
+pub(crate) fn describe(op: &str, path: &Path, cfg: &ShimConfig, supervised: bool) -> Value {
+    let conflict = crate::conflicts::contains(path);
+    let verdict = decide(op, path, cfg, supervised, conflict, &Live);
+    let (class, policy) = path_class::policy_with(cfg, path);
+    let reported = paths::for_matching(path, cfg.normalize_unicode);
+    let mut out = json!({
+        "path": reported.to_string_lossy(),
+        "op": op,
+        "class": class.name(),
+        "class_policy": policy.name(),
+    });
+    if reported != path {
+        out["raw_path"] = json!(path.to_string_lossy());
+    }
+    match verdict {
+        Verdict::Ignored(by) => {
+            out["outcome"] = json!("ignore");
+            out["ignored_by"] = ignore_stats::reason(by);
+        }
+        Verdict::Notify => out["outcome"] = json!("notify"),
+        Verdict::Watched(i) => {
+            out["outcome"] = json!("notify");
+            out["watched"] = json!(cfg.watch_dirs.get(i).map(|w| w.to_string_lossy()));
+        }
+        Verdict::Supervised => {
+            out["outcome"] = json!("allow");
+            out["allowed_by"] = json!("supervised");
+        }
+        Verdict::Cached(by) => {
+            out["outcome"] = json!("allow");
+            out["allowed_by"] = json!(by.name());
+        }
+        Verdict::Ask(_) => out["outcome"] = json!("ask"),
+    }
+    let landed = std::fs::canonicalize(path).ok();
+    let escape = landed
+        .as_deref()
+        .and_then(|to| Some((symlink_escape::check(path, to)?, to)));
+    if let Some((from, to)) = escape.filter(|_| cfg.symlink_escape != SymlinkEscape::Off) {
+        out["symlink_escape"] = symlink_escape::params(&from, to, cfg.symlink_escape);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Allows for one path, from the allow cache or a directory's grant.
+    struct Fake {
+        path: &'static str,
+        dir: bool,
+    }
+
+    impl Caches for Fake {
+        fn allowed(&self, _op: &str, path: &Path) -> bool {
+            !self.dir && path == Path::new(self.path)
+        }
+
+        fn dir_allowed(&self, _op: &str, path: &Path) -> bool {
+            self.dir && path == Path::new(self.path)
+        }
+    }
+
+    #[test]
+    fn each_step_decides_in_turn() {
+        let cfg: ShimConfig = toml::from_str(
+            r#"
+            roots = ["/t/classify"]
+            ignore = ["*.log"]
+            watch_dirs = ["/t/classify/gen"]
+            class_vcs_internal = "notify"
+            class_vcs_hooks = "block"
+            "#,
+        )
+        .unwrap();
+        let a = Fake {
+            path: "/t/classify/a.rs",
+            dir: false,
+        };
+        let verdict = |op: &str, p: &str| decide(op, Path::new(p), &cfg, true, false, &a);
+        assert_eq!(
+            verdict("pre_modify", "/t/classify/out.log"),
+            Verdict::Ignored(IgnoredBy::Glob(0))
+        );
+        assert_eq!(
+            verdict("pre_modify", "/elsewhere/a.rs"),
+            Verdict::Ignored(IgnoredBy::OutsideRoots)
+        );
+        assert_eq!(
+            verdict("pre_delete", "/t/classify/b.rs"),
+            Verdict::Supervised
+        );
+        assert_eq!(
+            verdict("pre_modify", "/t/classify/.git/index"),
+            Verdict::Notify
+        );
+        assert_eq!(
+            verdict("pre_modify", "/t/classify/gen/x.rs"),
+            Verdict::Watched(0)
+        );
+        assert_eq!(
+            verdict("pre_modify", "/t/classify/a.rs"),
+            Verdict::Cached(AllowedBy::Cache)
+        );
+        assert_eq!(
+            verdict("pre_modify", "/t/classify/b.rs"),
+            Verdict::Ask(PathClass::Normal)
+        );
+
+        // A conflict, or a class always asked about, skips the caches.
+        let conflicted = decide("pre_modify", Path::new(a.path), &cfg, false, true, &a);
+        assert_eq!(conflicted, Verdict::Ask(PathClass::Normal));
+        let hook = Fake {
+            path: "/t/classify/.git/hooks/pre-commit",
+            dir: true,
+        };
+        let strict = decide(
+            "pre_modify",
+            Path::new(hook.path),
+            &cfg,
+            false,
+            false,
+            &hook,
+        );
+        assert_eq!(strict, Verdict::Ask(PathClass::VcsHooks));
+        let granted = Fake {
+            path: "/t/classify/c.rs",
+            dir: true,
+        };
+        let by_dir = decide(
+            "pre_create",
+            Path::new(granted.path),
+            &cfg,
+            false,
+            false,
+            &granted,
+        );
+        assert_eq!(by_dir, Verdict::Cached(AllowedBy::DirCache));
+    }
+}
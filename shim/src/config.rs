@@ -0,0 +1,1271 @@
+//! Policy configuration: built-in defaults, then the project's
+//! `.nvim-claude/shim.toml` (see `project`), then the TOML file named by
+//! `NVIM_CLAUDE_SHIM_CONFIG`, then `FS_SHIM_*` environment overrides.
+//!
+//! ```toml
+//! append_mode = "notify"        # notify | block | off
+//! append_debounce_ms = 0
+//! max_dirty_age_ms = 0          # 0: dirty fds are reported at close only
+//! ignore = ["*.log", "**/node_modules/**"]
+//! max_file_size = 104857600     # bytes; larger files get sizes only
+//! content_features_max_bytes = 4194304 # see `content_tier`; 0: no limit
+//! ignore_file_size = 1073741824 # bytes; larger files are ignored
+//! track_filesystems = ["apfs", "hfs"]
+//! other_filesystems = "notify"  # track | notify | ignore
+//! roots = ["/Users/me/project"] # empty: everything
+//! watch_dirs = ["/Users/me/project/src"] # posts only; see `watch_dirs`
+//! watch_debounce_ms = 1000
+//! touch_debounce_ms = 5000      # see `touches`
+//! touch_verbose = ["**/Cargo.lock"] # touches posted one by one
+//! sensitive = ["**/.env", "**/.ssh/**"] # see `probes`
+//! probe_debounce_ms = 60000
+//! normalize_unicode = true
+//! case_insensitive = true       # default on macOS only
+//! allow_cache_ms = 60000        # 0: no cache
+//! cache_token_file = "/tmp/shim-session.token" # see `session_cache`
+//! trust_self_created = true     # see `self_created`
+//! hello_env = ["PWD", "VIRTUAL_ENV", "CARGO_MANIFEST_DIR"]
+//! block_budget_ms = 30000       # per minute; 0: no budget
+//! preflight_rate = 0.0          # per path per second; 0: no limit
+//! preflight_burst = 10
+//! max_heap_bytes = 16777216     # held between calls; 0: no cap
+//! path_history = false          # per-path counts and times; see `summary`
+//! send_timeout_ms = 2000        # per control send; see `breaker`
+//! tcp_dns_ttl_ms = 60000        # see `tcp_addr`; 0: resolve once
+//! nonblocking_preflight = "async" # async | block
+//! reactor_threads = ["tokio-runtime-w*", "com.apple.NSURLSession*"]
+//! bypass_processes = ["ld", "clang*", "mdworker*"]
+//! disable_hooks = ["ftruncate", "renameatx_np"] # see `contain`
+//! capture_backtrace = ["**/.env", "/etc/hosts"]
+//! denial_log = "/tmp/shim-denials.log" # "": none
+//! truncate_clear = "block"      # block | notify | off, per kind
+//! truncate_shrink = "block"
+//! truncate_extend = "notify"
+//! acl_mode = "notify"           # notify | block | off
+//! symlink_escape = "block"      # block | ask | off; see `symlink_escape`
+//! no_server_writes = "allow"    # allow | deny; FS_SHIM_FAIL_CLOSED only
+//! class_vcs_internal = "off"    # off | notify | block | default, per class
+//! class_vcs_hooks = "block"
+//! class_dotfile = "block"
+//! class_normal = "default"
+//!
+//! [[destination]]               # extra receivers; see `fanout`
+//! kind = "unix"                 # unix | tcp
+//! address = "/tmp/audit.sock"
+//! role = "sink"                 # sink | authoritative
+//! name = "a"                    # for `[[route]]`
+//! send_timeout_ms = 500         # else `send_timeout_ms`
+//!
+//! [[route]]                     # see `routes`
+//! glob = "packages/a/**"
+//! destination = "a"
+//!
+//! [[classify]]                  # see `path_class`
+//! glob = "/Users/me/.cache/**"
+//! class = "normal"
+//!
+//! [[force]]                     # for testing; see `force`
+//! glob = "**/locked.rs"
+//! outcome = "deny"              # allow | deny | timeout
+//! ```
+//!
+//! Loaded once, from the library constructor (for `bypass_processes`)
+//! or else the first handler that needs it; the file read goes through
+//! the hooks, which pass it through as re-entrant or not yet ready.
+//! `shim/reload_config` loads it again (see `reload`).
+
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::time::Duration;
+
+use crate::force::Forced;
+use crate::path_class::PathClass;
+use crate::{glob, paths, project, self_paths};
+
+/// How writes through `O_APPEND` fds are treated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum AppendMode {
+    /// No blocking preflight; `post_modify` (with `"append": true`) on close.
+    #[default]
+    Notify,
+    /// Same as any other write: preflight on the first write.
+    Block,
+    /// Neither preflight nor post events.
+    Off,
+}
+
+impl FromStr for AppendMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "notify" => Ok(AppendMode::Notify),
+            "block" => Ok(AppendMode::Block),
+            "off" => Ok(AppendMode::Off),
+            other => Err(format!("unknown append_mode {other:?}")),
+        }
+    }
+}
+
+/// What happens on filesystems not listed in `track_filesystems`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum OtherFilesystems {
+    /// Track them like any other file.
+    Track,
+    /// No preflights; post events only.
+    #[default]
+    Notify,
+    /// Neither preflights nor events.
+    Ignore,
+}
+
+impl FromStr for OtherFilesystems {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "track" => Ok(OtherFilesystems::Track),
+            "notify" => Ok(OtherFilesystems::Notify),
+            "ignore" => Ok(OtherFilesystems::Ignore),
+            other => Err(format!("unknown other_filesystems {other:?}")),
+        }
+    }
+}
+
+/// How the first write through an `O_NONBLOCK` fd is preflighted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum NonblockingPreflight {
+    /// Ask without waiting; see `async_pre`.
+    #[default]
+    Async,
+    /// Wait for the answer, like any other write.
+    Block,
+}
+
+impl FromStr for NonblockingPreflight {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "async" => Ok(NonblockingPreflight::Async),
+            "block" => Ok(NonblockingPreflight::Block),
+            other => Err(format!("unknown nonblocking_preflight {other:?}")),
+        }
+    }
+}
+
+/// What a truncate does, by its length against the size before.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TruncateKind {
+    /// To zero: everything in the file is gone.
+    Clear,
+    /// To less than the size, but not zero.
+    Shrink,
+    /// To the size or more; nothing is lost.
+    Extend,
+}
+
+impl TruncateKind {
+    pub(crate) fn of(length: u64, size: u64) -> TruncateKind {
+        if length == 0 {
+            TruncateKind::Clear
+        } else if length < size {
+            TruncateKind::Shrink
+        } else {
+            TruncateKind::Extend
+        }
+    }
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            TruncateKind::Clear => "clear",
+            TruncateKind::Shrink => "shrink",
+            TruncateKind::Extend => "extend",
+        }
+    }
+}
+
+/// How truncates of one kind are treated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum TruncatePolicy {
+    /// `pre_truncate`, then `post_modify`.
+    #[default]
+    Block,
+    /// No preflight; `post_modify` only.
+    Notify,
+    /// Neither preflight nor post events.
+    Off,
+}
+
+impl FromStr for TruncatePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "block" => Ok(TruncatePolicy::Block),
+            "notify" => Ok(TruncatePolicy::Notify),
+            "off" => Ok(TruncatePolicy::Off),
+            other => Err(format!("unknown truncate policy {other:?}")),
+        }
+    }
+}
+
+/// How ACL changes are treated; see `acl`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum AclMode {
+    /// No preflight; `post_acl` only.
+    #[default]
+    Notify,
+    /// `pre_acl`, then `post_acl`.
+    Block,
+    /// Neither preflight nor post events.
+    Off,
+}
+
+impl FromStr for AclMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "notify" => Ok(AclMode::Notify),
+            "block" => Ok(AclMode::Block),
+            "off" => Ok(AclMode::Off),
+            other => Err(format!("unknown acl_mode {other:?}")),
+        }
+    }
+}
+
+/// What becomes of a write a symlink takes out of the project; see
+/// `symlink_escape`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum SymlinkEscape {
+    /// Refused without asking, and reported in a post.
+    #[default]
+    Block,
+    /// Asked about, with the escape named.
+    Ask,
+    /// Not looked for.
+    Off,
+}
+
+impl SymlinkEscape {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            SymlinkEscape::Block => "block",
+            SymlinkEscape::Ask => "ask",
+            SymlinkEscape::Off => "off",
+        }
+    }
+}
+
+impl FromStr for SymlinkEscape {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "block" => Ok(SymlinkEscape::Block),
+            "ask" => Ok(SymlinkEscape::Ask),
+            "off" => Ok(SymlinkEscape::Off),
+            other => Err(format!("unknown symlink_escape {other:?}")),
+        }
+    }
+}
+
+/// What `FS_SHIM_FAIL_CLOSED=1` does to writes with no server at all.
+/// Deletes, renames over a file and truncates to zero are denied either
+/// way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum NoServerWrites {
+    #[default]
+    Allow,
+    Deny,
+}
+
+impl FromStr for NoServerWrites {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "allow" => Ok(NoServerWrites::Allow),
+            "deny" => Ok(NoServerWrites::Deny),
+            other => Err(format!("unknown no_server_writes {other:?}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum DestinationKind {
+    /// `address` is a socket path, with the same placeholders and `:`
+    /// fallbacks as `NVIM_CLAUDE_SHIM_SOCK`.
+    Unix,
+    /// `address` is `host:port`.
+    Tcp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Role {
+    /// May answer preflights; the first one is used when no destination
+    /// comes from the environment.
+    Authoritative,
+    /// Notifications only, through a bounded queue.
+    #[default]
+    Sink,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub(crate) struct DestinationConfig {
+    pub kind: DestinationKind,
+    pub address: String,
+    #[serde(default)]
+    pub role: Role,
+    /// What a `[[route]]` calls it. A routed destination is neither the
+    /// primary nor a sink.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Overrides `send_timeout_ms` for this one.
+    #[serde(default)]
+    pub send_timeout_ms: Option<u64>,
+}
+
+/// How paths of one class are treated; see `path_class`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ClassPolicy {
+    /// Neither preflight nor post events, as if ignored.
+    Off,
+    /// No preflight; post events only.
+    Notify,
+    /// A preflight every time, whatever would have skipped it.
+    Block,
+    /// As the rest of the config has it.
+    Default,
+}
+
+impl ClassPolicy {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            ClassPolicy::Off => "off",
+            ClassPolicy::Notify => "notify",
+            ClassPolicy::Block => "block",
+            ClassPolicy::Default => "default",
+        }
+    }
+}
+
+impl FromStr for ClassPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "off" => Ok(ClassPolicy::Off),
+            "notify" => Ok(ClassPolicy::Notify),
+            "block" => Ok(ClassPolicy::Block),
+            "default" => Ok(ClassPolicy::Default),
+            other => Err(format!("unknown class policy {other:?}")),
+        }
+    }
+}
+
+/// Paths matching `glob` are of `class`, whatever they look like.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub(crate) struct ClassifyConfig {
+    pub glob: String,
+    pub class: PathClass,
+}
+
+/// Blocking preflights for paths matching `glob` get `outcome` unasked.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub(crate) struct ForceConfig {
+    pub glob: String,
+    pub outcome: Forced,
+}
+
+/// Preflights and posts for paths under `glob` go to the destination
+/// `name`d `destination`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub(crate) struct RouteConfig {
+    pub glob: String,
+    pub destination: String,
+}
+
+/// Local disk filesystems; network mounts, FUSE and removable media
+/// (`msdos`/`exfat`) are left out so they default to notify-only.
+fn default_track_filesystems() -> Vec<String> {
+    let names: &[&str] = if cfg!(target_os = "macos") {
+        &["apfs", "hfs"]
+    } else {
+        &["ext4", "xfs", "btrfs", "zfs", "f2fs", "tmpfs", "overlay"]
+    };
+    names.iter().map(|s| s.to_string()).collect()
+}
+
+/// Next to the plugin's own logs, in Neovim's `stdpath('data')`.
+fn default_denial_log() -> PathBuf {
+    let data = std::env::var_os("XDG_DATA_HOME")
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| {
+            let home = std::env::var_os("HOME").filter(|h| !h.is_empty())?;
+            Some(Path::new(&home).join(".local/share"))
+        });
+    data.map_or_else(PathBuf::new, |d| {
+        d.join("nvim/nvim-claude/logs/shim-denials.log")
+    })
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub(crate) struct ShimConfig {
+    pub append_mode: AppendMode,
+    /// Suppress repeat append `post_modify`s for a path within this window.
+    pub append_debounce_ms: u64,
+    /// Paths matching any of these globs get neither preflights nor events.
+    /// See [`glob::matches`] for the syntax.
+    pub ignore: Vec<String>,
+    /// Bytes. Larger files (before or after the write) are reported with
+    /// sizes only and `"large_file": true`, so the server skips pre-images.
+    pub max_file_size: Option<u64>,
+    /// Bytes. Files already larger than this when first written are ignored.
+    pub ignore_file_size: Option<u64>,
+    /// Bytes. An fd whose first write would leave its file larger gets
+    /// its writes counted and nothing more; see `content_tier`. 0 is no
+    /// limit.
+    pub content_features_max_bytes: u64,
+    /// Filesystem type names that are fully tracked.
+    pub track_filesystems: Vec<String>,
+    pub other_filesystems: OtherFilesystems,
+    /// When non-empty, only paths under one of these are tracked.
+    pub roots: Vec<PathBuf>,
+    /// Paths under these get posts but no blocking preflights, and the
+    /// nearest one above them a `post_dir_changed`; see `watch_dirs`.
+    pub watch_dirs: Vec<PathBuf>,
+    /// Sum a watched directory's changes into one `post_dir_changed` for
+    /// this long after the last.
+    pub watch_debounce_ms: u64,
+    /// Sum a directory's timestamp changes into one `post_touch` for this
+    /// long after the first; see `touches`.
+    pub touch_debounce_ms: u64,
+    /// Globs for paths whose timestamp changes each get a `post_utimes`.
+    pub touch_verbose: Vec<String>,
+    /// Globs for paths whose failed exclusive creates are reported as
+    /// `shim/probe`; see `probes`.
+    pub sensitive: Vec<String>,
+    /// Send a path's `shim/probe` at most this often after the first.
+    pub probe_debounce_ms: u64,
+    /// Compose paths to NFC before matching and reporting (the original is
+    /// sent alongside as `raw_path` when it differs).
+    pub normalize_unicode: bool,
+    /// Case-insensitive glob and root matching; defaults on for macOS,
+    /// whose volumes usually are.
+    pub case_insensitive: bool,
+    /// Remember allowed preflights for this long; 0 disables the cache.
+    pub allow_cache_ms: u64,
+    /// Where the server's `cache_token` is kept for the session's later
+    /// processes; empty for nowhere. See `session_cache`.
+    pub cache_token_file: PathBuf,
+    /// Don't ask about an fd's first write to a file this process created;
+    /// see `self_created`.
+    pub trust_self_created: bool,
+    /// Report an fd dirty for this long as if flushed; see `dirty_age`.
+    /// 0 waits for the close.
+    pub max_dirty_age_ms: u64,
+    /// Bytes. Control frames larger than this are neither sent nor read.
+    pub max_frame_bytes: usize,
+    /// How long a send may block on a destination that stopped reading;
+    /// 0 is forever. See `breaker`.
+    pub send_timeout_ms: u64,
+    /// How long a TCP destination's looked-up addresses are kept before
+    /// they are looked up again, off the hooks; see `tcp_addr`.
+    pub tcp_dns_ttl_ms: u64,
+    /// Bytes the shim's queues and caches may hold together; see `heap`.
+    pub max_heap_bytes: usize,
+    /// Keep how often and when each path was modified, for
+    /// `shim/query_path` and the summary.
+    pub path_history: bool,
+    /// Environment variables whose values `shim/hello` carries.
+    pub hello_env: Vec<String>,
+    /// Most time preflights may spend blocked per minute, summed across
+    /// threads, before the rest take the fail policy unasked; 0 is no cap.
+    pub block_budget_ms: u64,
+    /// Blocking preflights a second one path may have on average; 0 is no
+    /// limit. See `rate_limit`.
+    pub preflight_rate: f64,
+    /// How many of them may come at once.
+    pub preflight_burst: u32,
+    pub nonblocking_preflight: NonblockingPreflight,
+    /// Globs on thread names whose preflights never wait; see `thread_info`.
+    pub reactor_threads: Vec<String>,
+    /// Globs on the executable of processes the hooks leave alone; see
+    /// `bypass`.
+    pub bypass_processes: Vec<String>,
+    /// Hooks that only ever call the original; see `contain`.
+    pub disable_hooks: Vec<String>,
+    /// Paths matching any of these globs get the writer's native frames
+    /// in their first `pre_modify`; see `backtrace`.
+    pub capture_backtrace: Vec<String>,
+    /// Where denials made for want of an answer are appended; empty for
+    /// nowhere. See `denials`.
+    pub denial_log: PathBuf,
+    /// Truncates to zero, to a smaller size, and to the same or a larger one.
+    pub truncate_clear: TruncatePolicy,
+    pub truncate_shrink: TruncatePolicy,
+    pub truncate_extend: TruncatePolicy,
+    /// Whether `acl_set_file` and friends are asked about, reported, or
+    /// left alone.
+    pub acl_mode: AclMode,
+    /// Writes a symlink takes out of the project root.
+    pub symlink_escape: SymlinkEscape,
+    /// Whether writes that can be undone go ahead when fail-closed with
+    /// no destination to ask.
+    pub no_server_writes: NoServerWrites,
+    /// VCS metadata, VCS hooks, home dotfiles, and everything else.
+    pub class_vcs_internal: ClassPolicy,
+    pub class_vcs_hooks: ClassPolicy,
+    pub class_dotfile: ClassPolicy,
+    pub class_normal: ClassPolicy,
+    /// `[[classify]]` tables; the first match wins.
+    pub classify: Vec<ClassifyConfig>,
+    /// `[[force]]` tables; the first match wins.
+    pub force: Vec<ForceConfig>,
+    /// `[[destination]]` tables, in file order.
+    #[serde(rename = "destination")]
+    pub destinations: Vec<DestinationConfig>,
+    /// `[[route]]` tables; the first match wins.
+    #[serde(rename = "route")]
+    pub routes: Vec<RouteConfig>,
+}
+
+impl Default for ShimConfig {
+    fn default() -> ShimConfig {
+        ShimConfig {
+            append_mode: AppendMode::default(),
+            append_debounce_ms: 0,
+            ignore: Vec::new(),
+            max_file_size: None,
+            ignore_file_size: None,
+            content_features_max_bytes: 4 << 20,
+            track_filesystems: default_track_filesystems(),
+            other_filesystems: OtherFilesystems::default(),
+            roots: Vec::new(),
+            watch_dirs: Vec::new(),
+            watch_debounce_ms: 1000,
+            touch_debounce_ms: 5000,
+            touch_verbose: Vec::new(),
+            sensitive: Vec::new(),
+            probe_debounce_ms: 60000,
+            normalize_unicode: true,
+            case_insensitive: cfg!(target_os = "macos"),
+            allow_cache_ms: 0,
+            cache_token_file: PathBuf::new(),
+            trust_self_created: true,
+            max_dirty_age_ms: 0,
+            max_frame_bytes: 16 << 20,
+            send_timeout_ms: 2000,
+            tcp_dns_ttl_ms: 60000,
+            max_heap_bytes: 16 << 20,
+            path_history: false,
+            hello_env: ["PWD", "VIRTUAL_ENV", "CARGO_MANIFEST_DIR"]
+                .map(String::from)
+                .to_vec(),
+            capture_backtrace: Vec::new(),
+            denial_log: default_denial_log(),
+            truncate_clear: TruncatePolicy::default(),
+            truncate_shrink: TruncatePolicy::default(),
+            truncate_extend: TruncatePolicy::default(),
+            acl_mode: AclMode::default(),
+            symlink_escape: SymlinkEscape::default(),
+            no_server_writes: NoServerWrites::default(),
+            block_budget_ms: 30_000,
+            preflight_rate: 0.0,
+            preflight_burst: 10,
+            nonblocking_preflight: NonblockingPreflight::default(),
+            reactor_threads: ["tokio-runtime-w*", "com.apple.NSURLSession*"]
+                .map(String::from)
+                .to_vec(),
+            bypass_processes: Vec::new(),
+            disable_hooks: Vec::new(),
+            destinations: Vec::new(),
+            routes: Vec::new(),
+            class_vcs_internal: ClassPolicy::Off,
+            class_vcs_hooks: ClassPolicy::Block,
+            class_dotfile: ClassPolicy::Block,
+            class_normal: ClassPolicy::Default,
+            classify: Vec::new(),
+            force: Vec::new(),
+        }
+    }
+}
+
+impl ShimConfig {
+    fn load() -> ShimConfig {
+        let mut table = project::file()
+            .and_then(|f| {
+                let root = f.parent()?.parent()?.to_path_buf();
+                Some(project::rebase(project::read(&f)?, &root))
+            })
+            .unwrap_or_default();
+        if let Some(user) =
+            std::env::var_os("NVIM_CLAUDE_SHIM_CONFIG").and_then(|p| project::read(Path::new(&p)))
+        {
+            table.extend(user);
+        }
+        let mut cfg = toml::Value::Table(table)
+            .try_into()
+            .unwrap_or_else(|e: toml::de::Error| {
+                crate::log_debug(&format!("[shim] config: {e}\n"));
+                ShimConfig::default()
+            });
+        cfg.apply_env(|k| std::env::var(k).ok());
+        cfg.normalize();
+        cfg
+    }
+
+    /// Bring globs and roots into the same form paths are matched in.
+    fn normalize(&mut self) {
+        if !self.normalize_unicode {
+            return;
+        }
+        for g in &mut self.ignore {
+            if let Some(s) = paths::nfc(Path::new(g.as_str())).to_str() {
+                *g = s.to_string();
+            }
+        }
+        for r in self.roots.iter_mut().chain(&mut self.watch_dirs) {
+            *r = paths::nfc(r).into_owned();
+        }
+        for r in &mut self.routes {
+            if let Some(s) = paths::nfc(Path::new(r.glob.as_str())).to_str() {
+                r.glob = s.to_string();
+            }
+        }
+        for r in &mut self.classify {
+            if let Some(s) = paths::nfc(Path::new(r.glob.as_str())).to_str() {
+                r.glob = s.to_string();
+            }
+        }
+        for r in &mut self.force {
+            if let Some(s) = paths::nfc(Path::new(r.glob.as_str())).to_str() {
+                r.glob = s.to_string();
+            }
+        }
+    }
+
+    /// Env wins over the file. Unparseable values are logged and ignored.
+    fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) {
+        if let Some(v) = var("FS_SHIM_APPEND_MODE") {
+            match v.parse() {
+                Ok(mode) => self.append_mode = mode,
+                Err(e) => crate::log_debug(&format!("[shim] FS_SHIM_APPEND_MODE: {e}\n")),
+            }
+        }
+        if let Some(ms) = var("FS_SHIM_APPEND_DEBOUNCE_MS").and_then(|v| v.parse().ok()) {
+            self.append_debounce_ms = ms;
+        }
+        if let Some(n) = var("FS_SHIM_MAX_FILE_SIZE").and_then(|v| v.parse().ok()) {
+            self.max_file_size = Some(n);
+        }
+        if let Some(n) = var("FS_SHIM_IGNORE_FILE_SIZE").and_then(|v| v.parse().ok()) {
+            self.ignore_file_size = Some(n);
+        }
+        // ','-separated; replaces the list.
+        if let Some(v) = var("FS_SHIM_TRACK_FILESYSTEMS") {
+            self.track_filesystems = v
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect();
+        }
+        if let Some(ms) = var("FS_SHIM_BLOCK_BUDGET_MS").and_then(|v| v.parse().ok()) {
+            self.block_budget_ms = ms;
+        }
+        if let Some(r) = var("FS_SHIM_PREFLIGHT_RATE").and_then(|v| v.parse().ok()) {
+            self.preflight_rate = r;
+        }
+        if let Some(n) = var("FS_SHIM_PREFLIGHT_BURST").and_then(|v| v.parse().ok()) {
+            self.preflight_burst = n;
+        }
+        if let Some(v) = var("FS_SHIM_NONBLOCKING_PREFLIGHT") {
+            match v.parse() {
+                Ok(mode) => self.nonblocking_preflight = mode,
+                Err(e) => crate::log_debug(&format!("[shim] FS_SHIM_NONBLOCKING_PREFLIGHT: {e}\n")),
+            }
+        }
+        if let Some(v) = var("FS_SHIM_REACTOR_THREADS") {
+            self.reactor_threads = v
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect();
+        }
+        if let Some(v) = var("FS_SHIM_BYPASS_PROCESSES") {
+            self.bypass_processes = v
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect();
+        }
+        // Added to the file's list: a hook turned off stays off.
+        if let Some(v) = var("FS_SHIM_DISABLE_HOOKS") {
+            self.disable_hooks.extend(
+                v.split(',')
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty()),
+            );
+        }
+        // `glob=name` pairs, ','-separated; replaces the list.
+        if let Some(v) = var("FS_SHIM_ROUTES") {
+            self.routes = v
+                .split(',')
+                .filter_map(|r| {
+                    let (glob, name) = r.trim().rsplit_once('=')?;
+                    Some(RouteConfig {
+                        glob: glob.to_string(),
+                        destination: name.to_string(),
+                    })
+                })
+                .collect();
+        }
+        if let Some(v) = var("FS_SHIM_DENIAL_LOG") {
+            self.denial_log = PathBuf::from(v);
+        }
+        for (key, policy) in [
+            ("FS_SHIM_TRUNCATE_CLEAR", &mut self.truncate_clear),
+            ("FS_SHIM_TRUNCATE_SHRINK", &mut self.truncate_shrink),
+            ("FS_SHIM_TRUNCATE_EXTEND", &mut self.truncate_extend),
+        ] {
+            if let Some(v) = var(key) {
+                match v.parse() {
+                    Ok(p) => *policy = p,
+                    Err(e) => crate::log_debug(&format!("[shim] {key}: {e}\n")),
+                }
+            }
+        }
+        for (key, policy) in [
+            ("FS_SHIM_CLASS_VCS_INTERNAL", &mut self.class_vcs_internal),
+            ("FS_SHIM_CLASS_VCS_HOOKS", &mut self.class_vcs_hooks),
+            ("FS_SHIM_CLASS_DOTFILE", &mut self.class_dotfile),
+            ("FS_SHIM_CLASS_NORMAL", &mut self.class_normal),
+        ] {
+            if let Some(v) = var(key) {
+                match v.parse() {
+                    Ok(p) => *policy = p,
+                    Err(e) => crate::log_debug(&format!("[shim] {key}: {e}\n")),
+                }
+            }
+        }
+        // `glob=class` pairs, ','-separated; replaces the list.
+        if let Some(v) = var("FS_SHIM_CLASSIFY") {
+            self.classify = v
+                .split(',')
+                .filter_map(|r| {
+                    let (glob, class) = r.trim().rsplit_once('=')?;
+                    Some(ClassifyConfig {
+                        glob: glob.to_string(),
+                        class: class.parse().ok()?,
+                    })
+                })
+                .collect();
+        }
+        // `outcome:glob` pairs, ','-separated; replaces the list.
+        if let Some(v) = var("FS_SHIM_FORCE") {
+            self.force = v
+                .split(',')
+                .filter_map(|r| {
+                    let (outcome, glob) = r.trim().split_once(':')?;
+                    Some(ForceConfig {
+                        glob: glob.to_string(),
+                        outcome: outcome.parse().ok()?,
+                    })
+                })
+                .collect();
+        }
+        if let Some(v) = var("FS_SHIM_ACL_MODE") {
+            match v.parse() {
+                Ok(mode) => self.acl_mode = mode,
+                Err(e) => crate::log_debug(&format!("[shim] FS_SHIM_ACL_MODE: {e}\n")),
+            }
+        }
+        if let Some(v) = var("FS_SHIM_SYMLINK_ESCAPE") {
+            match v.parse() {
+                Ok(p) => self.symlink_escape = p,
+                Err(e) => crate::log_debug(&format!("[shim] FS_SHIM_SYMLINK_ESCAPE: {e}\n")),
+            }
+        }
+        if let Some(v) = var("FS_SHIM_NO_SERVER_WRITES") {
+            match v.parse() {
+                Ok(w) => self.no_server_writes = w,
+                Err(e) => crate::log_debug(&format!("[shim] FS_SHIM_NO_SERVER_WRITES: {e}\n")),
+            }
+        }
+        if let Some(v) = var("FS_SHIM_HELLO_ENV") {
+            self.hello_env = v
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect();
+        }
+        if let Some(v) = var("FS_SHIM_OTHER_FILESYSTEMS") {
+            match v.parse() {
+                Ok(o) => self.other_filesystems = o,
+                Err(e) => crate::log_debug(&format!("[shim] FS_SHIM_OTHER_FILESYSTEMS: {e}\n")),
+            }
+        }
+        if let Some(v) = var("FS_SHIM_NORMALIZE_UNICODE") {
+            self.normalize_unicode = v == "1" || v.eq_ignore_ascii_case("true");
+        }
+        if let Some(v) = var("FS_SHIM_CASE_INSENSITIVE") {
+            self.case_insensitive = v == "1" || v.eq_ignore_ascii_case("true");
+        }
+        if let Some(ms) = var("FS_SHIM_ALLOW_CACHE_MS").and_then(|v| v.parse().ok()) {
+            self.allow_cache_ms = ms;
+        }
+        if let Some(v) = var("FS_SHIM_CACHE_TOKEN_FILE") {
+            self.cache_token_file = PathBuf::from(v);
+        }
+        if let Some(n) = var("FS_SHIM_CONTENT_FEATURES_MAX_BYTES").and_then(|v| v.parse().ok()) {
+            self.content_features_max_bytes = n;
+        }
+        if let Some(ms) = var("FS_SHIM_MAX_DIRTY_AGE_MS").and_then(|v| v.parse().ok()) {
+            self.max_dirty_age_ms = ms;
+        }
+        if let Some(n) = var("FS_SHIM_MAX_FRAME_BYTES").and_then(|v| v.parse().ok()) {
+            self.max_frame_bytes = n;
+        }
+        if let Some(ms) = var("FS_SHIM_SEND_TIMEOUT_MS").and_then(|v| v.parse().ok()) {
+            self.send_timeout_ms = ms;
+        }
+        if let Some(ms) = var("FS_SHIM_TCP_DNS_TTL_MS").and_then(|v| v.parse().ok()) {
+            self.tcp_dns_ttl_ms = ms;
+        }
+        if let Some(n) = var("FS_SHIM_MAX_HEAP_BYTES").and_then(|v| v.parse().ok()) {
+            self.max_heap_bytes = n;
+        }
+        if let Some(v) = var("FS_SHIM_TRUST_SELF_CREATED") {
+            self.trust_self_created = v == "1" || v.eq_ignore_ascii_case("true");
+        }
+        if let Some(v) = var("FS_SHIM_PATH_HISTORY") {
+            self.path_history = v == "1" || v.eq_ignore_ascii_case("true");
+        }
+        if let Some(v) = var("FS_SHIM_ROOTS") {
+            self.roots = v
+                .split(':')
+                .filter(|r| !r.is_empty())
+                .map(PathBuf::from)
+                .collect();
+        }
+        if let Some(v) = var("FS_SHIM_WATCH_DIRS") {
+            self.watch_dirs = v
+                .split(':')
+                .filter(|r| !r.is_empty())
+                .map(PathBuf::from)
+                .collect();
+        }
+        if let Some(ms) = var("FS_SHIM_WATCH_DEBOUNCE_MS").and_then(|v| v.parse().ok()) {
+            self.watch_debounce_ms = ms;
+        }
+        if let Some(ms) = var("FS_SHIM_TOUCH_DEBOUNCE_MS").and_then(|v| v.parse().ok()) {
+            self.touch_debounce_ms = ms;
+        }
+        if let Some(ms) = var("FS_SHIM_PROBE_DEBOUNCE_MS").and_then(|v| v.parse().ok()) {
+            self.probe_debounce_ms = ms;
+        }
+        // ':'-separated, like PATH; added to the file's list.
+        if let Some(globs) = var("FS_SHIM_IGNORE") {
+            self.ignore
+                .extend(globs.split(':').filter(|g| !g.is_empty()).map(String::from));
+        }
+        if let Some(globs) = var("FS_SHIM_CAPTURE_BACKTRACE") {
+            self.capture_backtrace
+                .extend(globs.split(':').filter(|g| !g.is_empty()).map(String::from));
+        }
+        if let Some(globs) = var("FS_SHIM_TOUCH_VERBOSE") {
+            self.touch_verbose
+                .extend(globs.split(':').filter(|g| !g.is_empty()).map(String::from));
+        }
+        if let Some(globs) = var("FS_SHIM_SENSITIVE") {
+            self.sensitive
+                .extend(globs.split(':').filter(|g| !g.is_empty()).map(String::from));
+        }
+    }
+
+    /// The destination that answers preflights when the environment names
+    /// none.
+    pub fn first_authoritative(&self) -> Option<&DestinationConfig> {
+        self.destinations
+            .iter()
+            .find(|d| d.role == Role::Authoritative && !self.is_routed(d))
+    }
+
+    /// The send timeout for `d`, or for a destination not from the config.
+    pub fn send_timeout(&self, d: Option<&DestinationConfig>) -> Option<Duration> {
+        let ms = d
+            .and_then(|d| d.send_timeout_ms)
+            .unwrap_or(self.send_timeout_ms);
+        (ms > 0).then(|| Duration::from_millis(ms))
+    }
+
+    /// Whether a `[[route]]` names `d`.
+    pub fn is_routed(&self, d: &DestinationConfig) -> bool {
+        d.name
+            .as_ref()
+            .is_some_and(|n| self.routes.iter().any(|r| r.destination == *n))
+    }
+
+    /// Policy for a filesystem type name (`None` when `fstatfs` failed,
+    /// which is treated as untracked).
+    pub fn filesystem_policy(&self, fs_type: Option<&str>) -> OtherFilesystems {
+        match fs_type {
+            Some(t)
+                if self
+                    .track_filesystems
+                    .iter()
+                    .any(|f| f.eq_ignore_ascii_case(t)) =>
+            {
+                OtherFilesystems::Track
+            }
+            _ => self.other_filesystems,
+        }
+    }
+
+    /// Why `path` is ignored (outside every root, matched by an ignore
+    /// glob, registered by the server as its own, or of a class that is
+    /// `off`), if it is. Roots are checked first and classes last; among
+    /// globs the first match wins.
+    pub fn ignored_by(&self, path: &Path) -> Option<IgnoredBy> {
+        let path = paths::for_matching(path, self.normalize_unicode);
+        let fold = self.case_insensitive;
+        if !self.roots.is_empty() && !self.roots.iter().any(|r| glob::has_prefix(&path, r, fold)) {
+            return Some(IgnoredBy::OutsideRoots);
+        }
+        self.ignore
+            .iter()
+            .position(|g| glob::matches(g, &path, fold))
+            .map(IgnoredBy::Glob)
+            .or_else(|| self_paths::matches(&path, fold).then_some(IgnoredBy::SelfPath))
+            .or_else(|| {
+                let (class, policy) = crate::path_class::policy_with(self, &path);
+                (policy == ClassPolicy::Off).then_some(IgnoredBy::Class(class))
+            })
+    }
+
+    /// Whether `path`'s timestamp changes each get a `post_utimes`.
+    pub fn touch_verbose(&self, path: &Path) -> bool {
+        if self.touch_verbose.is_empty() {
+            return false;
+        }
+        let path = paths::for_matching(path, self.normalize_unicode);
+        let fold = self.case_insensitive;
+        self.touch_verbose
+            .iter()
+            .any(|g| glob::matches(g, &path, fold))
+    }
+
+    /// Whether a failed exclusive create of `path` is a `shim/probe`.
+    pub fn sensitive(&self, path: &Path) -> bool {
+        if self.sensitive.is_empty() {
+            return false;
+        }
+        let path = paths::for_matching(path, self.normalize_unicode);
+        let fold = self.case_insensitive;
+        self.sensitive.iter().any(|g| glob::matches(g, &path, fold))
+    }
+
+    pub fn captures_backtrace(&self, path: &Path) -> bool {
+        if self.capture_backtrace.is_empty() {
+            return false;
+        }
+        let path = paths::for_matching(path, self.normalize_unicode);
+        let fold = self.case_insensitive;
+        self.capture_backtrace
+            .iter()
+            .any(|g| glob::matches(g, &path, fold))
+    }
+
+    pub fn class_policy(&self, class: PathClass) -> ClassPolicy {
+        match class {
+            PathClass::VcsInternal => self.class_vcs_internal,
+            PathClass::VcsHooks => self.class_vcs_hooks,
+            PathClass::Dotfile => self.class_dotfile,
+            PathClass::Normal => self.class_normal,
+        }
+    }
+
+    pub fn truncate_policy(&self, kind: TruncateKind) -> TruncatePolicy {
+        match kind {
+            TruncateKind::Clear => self.truncate_clear,
+            TruncateKind::Shrink => self.truncate_shrink,
+            TruncateKind::Extend => self.truncate_extend,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IgnoredBy {
+    OutsideRoots,
+    /// Index into `ignore`.
+    Glob(usize),
+    /// See `self_paths`.
+    SelfPath,
+    /// A class whose policy is `off`; see `path_class`.
+    Class(PathClass),
+}
+
+static CONFIG: Lazy<ShimConfig> = Lazy::new(ShimConfig::load);
+
+/// The last `reload`'s, once there has been one.
+static RELOADED: AtomicPtr<ShimConfig> = AtomicPtr::new(std::ptr::null_mut());
+
+pub(crate) fn get() -> &'static ShimConfig {
+    let cfg = RELOADED.load(Ordering::Acquire);
+    if cfg.is_null() {
+        &CONFIG
+    } else {
+        unsafe { &*cfg }
+    }
+}
+
+/// Read the files and environment again. What callers of `get` read
+/// from then on changes; what was set up from the first load
+/// (destinations, routes, disabled hooks, `bypass_processes`) doesn't.
+/// The old config stays allocated, since anyone may still hold it.
+pub(crate) fn reload() {
+    let cfg = Box::leak(Box::new(ShimConfig::load()));
+    RELOADED.store(cfg, Ordering::Release);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_file_schema() {
+        let cfg: ShimConfig = toml::from_str(
+            r#"
+            append_mode = "block"
+            append_debounce_ms = 250
+            ignore = ["*.log"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(cfg.append_mode, AppendMode::Block);
+        assert_eq!(cfg.append_debounce_ms, 250);
+        assert!(cfg.ignored_by(Path::new("/p/build.log")).is_some());
+        assert!(cfg.ignored_by(Path::new("/p/main.rs")).is_none());
+    }
+
+    #[test]
+    fn parses_destination_tables() {
+        let cfg: ShimConfig = toml::from_str(
+            r#"
+            [[destination]]
+            kind = "unix"
+            address = "/run/nvim.sock"
+            role = "authoritative"
+
+            [[destination]]
+            kind = "tcp"
+            address = "127.0.0.1:7777"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(cfg.destinations.len(), 2);
+        assert_eq!(cfg.destinations[0].role, Role::Authoritative);
+        assert_eq!(cfg.destinations[1].kind, DestinationKind::Tcp);
+        assert_eq!(cfg.destinations[1].role, Role::Sink);
+    }
+
+    #[test]
+    fn routed_destinations_are_not_the_primary() {
+        let mut cfg: ShimConfig = toml::from_str(
+            r#"
+            [[destination]]
+            kind = "unix"
+            address = "/run/a.sock"
+            role = "authoritative"
+            name = "a"
+
+            [[destination]]
+            kind = "unix"
+            address = "/run/root.sock"
+            role = "authoritative"
+
+            [[route]]
+            glob = "packages/a/**"
+            destination = "a"
+            "#,
+        )
+        .unwrap();
+        assert!(cfg.is_routed(&cfg.destinations[0]));
+        assert_eq!(cfg.first_authoritative().unwrap().address, "/run/root.sock");
+        cfg.apply_env(|k| (k == "FS_SHIM_ROUTES").then(|| "web/**=b, api/**=a".into()));
+        assert_eq!(
+            cfg.routes
+                .iter()
+                .map(|r| (r.glob.as_str(), r.destination.as_str()))
+                .collect::<Vec<_>>(),
+            [("web/**", "b"), ("api/**", "a")]
+        );
+    }
+
+    #[test]
+    fn forced_outcomes_come_from_tables_or_env() {
+        let mut cfg: ShimConfig = toml::from_str(
+            r#"
+            [[force]]
+            glob = "**/locked.rs"
+            outcome = "deny"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(cfg.force[0].outcome, Forced::Deny);
+        cfg.apply_env(|k| {
+            (k == "FS_SHIM_FORCE").then(|| "timeout:**/slow/**, allow:*.rs,bogus:x".into())
+        });
+        assert_eq!(
+            cfg.force
+                .iter()
+                .map(|r| (r.outcome, r.glob.as_str()))
+                .collect::<Vec<_>>(),
+            [(Forced::Timeout, "**/slow/**"), (Forced::Allow, "*.rs")]
+        );
+    }
+
+    #[test]
+    fn env_overrides_file() {
+        let mut cfg: ShimConfig = toml::from_str("append_mode = \"block\"").unwrap();
+        cfg.apply_env(|k| match k {
+            "FS_SHIM_APPEND_MODE" => Some("off".into()),
+            "FS_SHIM_IGNORE" => Some("*.tmp::target/**".into()),
+            _ => None,
+        });
+        assert_eq!(cfg.append_mode, AppendMode::Off);
+        assert_eq!(cfg.ignore, ["*.tmp", "target/**"]);
+    }
+
+    #[test]
+    fn untracked_filesystems_follow_other_filesystems() {
+        let mut cfg: ShimConfig = toml::from_str("track_filesystems = [\"apfs\"]").unwrap();
+        assert_eq!(cfg.filesystem_policy(Some("APFS")), OtherFilesystems::Track);
+        assert_eq!(
+            cfg.filesystem_policy(Some("smbfs")),
+            OtherFilesystems::Notify
+        );
+        assert_eq!(cfg.filesystem_policy(None), OtherFilesystems::Notify);
+        cfg.apply_env(|k| (k == "FS_SHIM_OTHER_FILESYSTEMS").then(|| "ignore".into()));
+        assert_eq!(cfg.filesystem_policy(Some("nfs")), OtherFilesystems::Ignore);
+    }
+
+    #[test]
+    fn roots_and_globs_match_either_unicode_form() {
+        // TOML `\u00e9` escapes: the config side is written NFC.
+        let mut cfg: ShimConfig = toml::from_str(
+            r#"
+            roots = ["/w/dat\u00e9"]
+            ignore = ["caf\u00e9*"]
+            "#,
+        )
+        .unwrap();
+        cfg.normalize();
+        cfg.case_insensitive = false;
+        assert!(cfg
+            .ignored_by(Path::new("/w/date\u{301}/main.rs"))
+            .is_none());
+        assert!(cfg.ignored_by(Path::new("/w/other/main.rs")).is_some());
+        assert!(cfg
+            .ignored_by(Path::new("/w/dat\u{e9}/cafe\u{301}.txt"))
+            .is_some());
+
+        cfg.normalize_unicode = false;
+        assert!(cfg
+            .ignored_by(Path::new("/w/date\u{301}/main.rs"))
+            .is_some());
+    }
+
+    #[test]
+    fn case_insensitive_applies_to_roots_and_globs() {
+        let mut cfg: ShimConfig = toml::from_str(
+            r#"
+            roots = ["/Users/Me/Project"]
+            ignore = ["*.LOG"]
+            case_insensitive = false
+            "#,
+        )
+        .unwrap();
+        assert!(cfg
+            .ignored_by(Path::new("/users/me/project/main.rs"))
+            .is_some());
+        assert!(cfg
+            .ignored_by(Path::new("/Users/Me/Project/build.log"))
+            .is_none());
+        cfg.apply_env(|k| (k == "FS_SHIM_CASE_INSENSITIVE").then(|| "1".into()));
+        assert!(cfg
+            .ignored_by(Path::new("/users/me/project/main.rs"))
+            .is_none());
+        assert!(cfg
+            .ignored_by(Path::new("/users/me/project/build.log"))
+            .is_some());
+    }
+
+    #[test]
+    fn truncates_are_told_apart_by_what_they_lose() {
+        assert_eq!(TruncateKind::of(0, 10), TruncateKind::Clear);
+        assert_eq!(TruncateKind::of(0, 0), TruncateKind::Clear);
+        assert_eq!(TruncateKind::of(4, 10), TruncateKind::Shrink);
+        assert_eq!(TruncateKind::of(10, 10), TruncateKind::Extend);
+        assert_eq!(TruncateKind::of(20, 10), TruncateKind::Extend);
+        let mut cfg: ShimConfig = toml::from_str("truncate_extend = \"notify\"").unwrap();
+        cfg.apply_env(|k| (k == "FS_SHIM_TRUNCATE_CLEAR").then(|| "off".into()));
+        assert_eq!(
+            [
+                TruncateKind::Clear,
+                TruncateKind::Shrink,
+                TruncateKind::Extend
+            ]
+            .map(|k| cfg.truncate_policy(k)),
+            [
+                TruncatePolicy::Off,
+                TruncatePolicy::Block,
+                TruncatePolicy::Notify
+            ]
+        );
+    }
+
+    #[test]
+    fn ignored_by_names_the_first_matching_glob() {
+        let cfg: ShimConfig = toml::from_str(
+            r#"
+            roots = ["/p"]
+            ignore = ["*.tmp", "target/**", "*.o"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            cfg.ignored_by(Path::new("/q/a.rs")),
+            Some(IgnoredBy::OutsideRoots)
+        );
+        assert_eq!(
+            cfg.ignored_by(Path::new("/p/target/x.o")),
+            Some(IgnoredBy::Glob(1))
+        );
+        assert_eq!(cfg.ignored_by(Path::new("/p/src/a.rs")), None);
+    }
+
+    #[test]
+    fn bad_env_value_keeps_previous() {
+        let mut cfg = ShimConfig::default();
+        cfg.apply_env(|k| (k == "FS_SHIM_APPEND_MODE").then(|| "sometimes".into()));
+        assert_eq!(cfg.append_mode, AppendMode::Notify);
+    }
+}
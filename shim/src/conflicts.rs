@@ -0,0 +1,70 @@
+//! Files open in Neovim with unsaved changes.
+//!
+//! The agent rewriting a file under a modified buffer is the worst
+//! conflict there is, so the server keeps the shim told which paths those
+//! are, with `shim/conflict_paths` (`{"paths": [...]}`). Each push replaces
+//! the whole set at once. The first write to one of them always gets a
+//! blocking preflight, whatever the allow cache, `append_mode` or a
+//! reactor thread would otherwise do, with `"conflict": true` in it.
+//!
+//! Paths are kept in matching form (NFC, and folded to lower case where
+//! matching is case-insensitive), so membership is one hash lookup.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use serde_json::Value;
+
+use crate::{config, paths};
+
+static SET: Lazy<RwLock<HashSet<PathBuf>>> = Lazy::new(|| RwLock::new(HashSet::new()));
+/// Whether the set is non-empty, so the common case takes no lock.
+static ANY: AtomicBool = AtomicBool::new(false);
+
+fn key(path: &Path) -> PathBuf {
+    let cfg = config::get();
+    let path = paths::for_matching(path, cfg.normalize_unicode);
+    if cfg.case_insensitive {
+        PathBuf::from(path.to_string_lossy().to_lowercase())
+    } else {
+        path.into_owned()
+    }
+}
+
+/// Replace the set with `paths`; returns how many it now holds.
+pub(crate) fn replace(paths: &[Value]) -> usize {
+    let set: HashSet<PathBuf> = paths
+        .iter()
+        .filter_map(Value::as_str)
+        .map(|p| key(Path::new(p)))
+        .collect();
+    let n = set.len();
+    *SET.write() = set;
+    ANY.store(n > 0, Ordering::Relaxed);
+    n
+}
+
+/// Whether `path` is open in a modified buffer.
+pub(crate) fn contains(path: &Path) -> bool {
+    ANY.load(Ordering::Relaxed) && SET.read().contains(&key(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn each_push_replaces_the_set() {
+        assert_eq!(replace(&[json!("/w/a.rs"), json!("/w/b.rs"), json!(3)]), 2);
+        assert!(contains(Path::new("/w/a.rs")));
+        assert_eq!(replace(&[json!("/w/b.rs")]), 1);
+        assert!(!contains(Path::new("/w/a.rs")));
+        assert!(contains(Path::new("/w/b.rs")));
+        replace(&[]);
+        assert!(!contains(Path::new("/w/b.rs")));
+    }
+}
@@ -0,0 +1,736 @@
+//! The control protocol as a checkable contract.
+//!
+//! There is no protocol crate: the shim's serializers define what goes on
+//! the wire. This module writes that definition down once, as `METHODS`,
+//! and `validate_frame` checks one JSON payload (unframed, as
+//! `framing::split` returns it) against it. The vectors in
+//! `testdata/protocol/` come from `conformance_vectors`. `valid/` holds
+//! one frame per message type, built with the shim's own serializers, and
+//! `invalid/` holds frames that must be refused. Other implementations of
+//! either end test against them. `PROTOCOL_FIXTURES=write cargo test`
+//! regenerates them after a deliberate change; otherwise the tests fail
+//! when the shim's output drifts from what is checked in.
+//!
+//! The integration tests' mock server compiles this file as it is and
+//! runs every frame the shim sends through `validate_frame`, so the table
+//! is held to the shim's real output too. It must need nothing but `std`
+//! and `serde_json`.
+//!
+//! `schemas` writes the same table out as JSON Schema, one document per
+//! method plus the envelope, checked in under `schema/v<PROTOCOL_VERSION>/`
+//! next to the vectors (and regenerated with them) for consumers that
+//! would rather not read Rust.
+//!
+//! Only the JSON format is covered; msgpack-RPC carries the same
+//! methods and params.
+
+use std::fmt;
+
+use serde_json::{json, Map, Value};
+
+/// Who may send a method, and whether it takes an `id`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    /// Always has an `id`, and is always answered.
+    Request,
+    /// Never has an `id`.
+    Notification,
+    /// Either; the server's requests can also be sent as notifications.
+    Either,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Ty {
+    Int,
+    Str,
+    Bool,
+    Array,
+    Object,
+    /// A string, or `null` for a file known only by inode (`inodes`).
+    Path,
+    /// A string, or `null` when there is none to give.
+    OptStr,
+}
+
+impl Ty {
+    fn name(self) -> &'static str {
+        match self {
+            Ty::Int => "integer",
+            Ty::Str => "string",
+            Ty::Bool => "boolean",
+            Ty::Array => "array",
+            Ty::Object => "object",
+            Ty::Path | Ty::OptStr => "string or null",
+        }
+    }
+
+    fn schema(self) -> Value {
+        match self {
+            Ty::Path | Ty::OptStr => json!({ "type": ["string", "null"] }),
+            ty => json!({ "type": ty.name() }),
+        }
+    }
+
+    fn admits(self, v: &Value) -> bool {
+        match self {
+            Ty::Int => v.is_u64() || v.is_i64(),
+            Ty::Str => v.is_string(),
+            Ty::Bool => v.is_boolean(),
+            Ty::Array => v.is_array(),
+            Ty::Object => v.is_object(),
+            Ty::Path | Ty::OptStr => v.is_string() || v.is_null(),
+        }
+    }
+}
+
+/// Params a method requires, by name and type.
+type Params = &'static [(&'static str, Ty)];
+
+const PATH_EVENT: Params = &[("path", Ty::Str), ("path_seq", Ty::Int)];
+const PREFLIGHT: Params = &[("pid", Ty::Int), ("path", Ty::Str), ("path_seq", Ty::Int)];
+
+/// What the shim adds to a preflight or post when it has it: the
+/// writer's process chain (`lineage`) and thread (`thread_info`), the
+/// path as the call gave it, a recent denial on the same path
+/// (`after_denied`), and the path's class (`path_class`).
+const TAGS: Params = &[
+    ("pid", Ty::Int),
+    ("root_pid", Ty::Int),
+    ("root_argv0", Ty::Str),
+    ("tid", Ty::Int),
+    ("thread_name", Ty::Str),
+    ("conn", Ty::Int),
+    ("raw_path", Ty::Str),
+    ("after_denied", Ty::Object),
+    ("class", Ty::Str),
+];
+const PREFLIGHT_EXTRA: Params = &[
+    ("source", Ty::Str),
+    ("phase", Ty::Str),
+    ("euid", Ty::Int),
+    ("egid", Ty::Int),
+];
+/// What names a file reached through an fd instead, when `path` is
+/// `null`. See `inodes`.
+const FD_TARGET: Params = &[
+    ("path_seq", Ty::Int),
+    ("dev", Ty::Int),
+    ("ino", Ty::Int),
+    ("last_path", Ty::Str),
+];
+const POST_EXTRA: Params = &[
+    ("blocked_ms", Ty::Int),
+    ("allowed_by", Ty::OptStr),
+    ("seq", Ty::Int),
+    ("coalesced_waiters", Ty::Int),
+    ("rate_limited", Ty::Bool),
+];
+
+/// What a post for an fd says about how its writes were looked at.
+const CONTENT: Params = &[("content_features_skipped", Ty::Bool)];
+
+/// Which file a post for an fd wrote, and who else wrote it then.
+const WRITER: Params = &[
+    ("dev", Ty::Int),
+    ("ino", Ty::Int),
+    ("opened_ms", Ty::Int),
+    ("op_id", Ty::Int),
+    ("concurrent_writers", Ty::Bool),
+    ("concurrent_op_id", Ty::Int),
+];
+
+/// Why a post for a still-open fd went out before its close.
+const FLUSHED: Params = &[("reason", Ty::Str)];
+
+/// Whether the rename a post reports replaced a file at its path.
+const RENAMED: Params = &[("clobbered", Ty::Bool)];
+
+/// Every method either end sends, with the params it can't do without
+/// and the optional ones it names. Anything else in `params` is allowed,
+/// so fields can be added freely; a named one must have its type.
+const METHODS: &[(&str, Kind, Params, &[Params])] = &[
+    (
+        "pre_modify",
+        Kind::Request,
+        PREFLIGHT,
+        &[TAGS, PREFLIGHT_EXTRA],
+    ),
+    (
+        "pre_create",
+        Kind::Request,
+        PREFLIGHT,
+        &[TAGS, PREFLIGHT_EXTRA],
+    ),
+    (
+        "pre_delete",
+        Kind::Request,
+        PREFLIGHT,
+        &[TAGS, PREFLIGHT_EXTRA],
+    ),
+    (
+        "pre_rename",
+        Kind::Request,
+        PREFLIGHT,
+        &[
+            TAGS,
+            PREFLIGHT_EXTRA,
+            &[
+                ("old_path", Ty::Str),
+                ("destination_exists", Ty::Bool),
+                ("destination_size", Ty::Int),
+                ("destination_mtime", Ty::Int),
+            ],
+        ],
+    ),
+    (
+        "pre_truncate",
+        Kind::Request,
+        PREFLIGHT,
+        &[TAGS, PREFLIGHT_EXTRA],
+    ),
+    (
+        "pre_acl",
+        Kind::Request,
+        PREFLIGHT,
+        &[TAGS, PREFLIGHT_EXTRA],
+    ),
+    (
+        "post_modify",
+        Kind::Notification,
+        &[("path", Ty::Str), ("path_seq", Ty::Int), ("bytes", Ty::Int)],
+        &[TAGS, POST_EXTRA, FLUSHED, RENAMED, CONTENT, WRITER],
+    ),
+    (
+        "post_create",
+        Kind::Notification,
+        PATH_EVENT,
+        &[TAGS, POST_EXTRA, FLUSHED, RENAMED, CONTENT, WRITER],
+    ),
+    (
+        "post_delete",
+        Kind::Notification,
+        PATH_EVENT,
+        &[TAGS, POST_EXTRA],
+    ),
+    (
+        "post_rename",
+        Kind::Notification,
+        PATH_EVENT,
+        &[TAGS, POST_EXTRA, &[("old_path", Ty::Str), ("via", Ty::Str)]],
+    ),
+    (
+        "post_lock",
+        Kind::Notification,
+        &[
+            ("path", Ty::Str),
+            ("path_seq", Ty::Int),
+            ("lock", Ty::Str),
+            ("via", Ty::Str),
+            ("blocking", Ty::Bool),
+        ],
+        &[TAGS],
+    ),
+    (
+        "post_unlock",
+        Kind::Notification,
+        &[
+            ("path", Ty::Str),
+            ("path_seq", Ty::Int),
+            ("via", Ty::Str),
+            ("reason", Ty::Str),
+        ],
+        &[TAGS],
+    ),
+    (
+        "post_dir_changed",
+        Kind::Notification,
+        &[
+            ("path", Ty::Str),
+            ("path_seq", Ty::Int),
+            ("files", Ty::Int),
+            ("synthesized", Ty::Bool),
+        ],
+        &[TAGS],
+    ),
+    (
+        "post_touch",
+        Kind::Notification,
+        &[
+            ("path", Ty::Str),
+            ("path_seq", Ty::Int),
+            ("touches", Ty::Int),
+            ("files", Ty::Int),
+            ("sample", Ty::Array),
+            ("synthesized", Ty::Bool),
+        ],
+        &[TAGS],
+    ),
+    (
+        "post_refactor",
+        Kind::Notification,
+        &[
+            ("path", Ty::Str),
+            ("path_seq", Ty::Int),
+            ("kind", Ty::Str),
+            ("synthesized", Ty::Bool),
+        ],
+        &[
+            TAGS,
+            &[
+                ("old_path", Ty::Str),
+                ("via", Ty::Array),
+                ("backup", Ty::Str),
+            ],
+        ],
+    ),
+    (
+        "post_acl",
+        Kind::Notification,
+        &[("path", Ty::Path)],
+        &[TAGS, POST_EXTRA, FD_TARGET],
+    ),
+    (
+        "post_chmod",
+        Kind::Notification,
+        &[("path", Ty::Path), ("mode", Ty::Str)],
+        &[TAGS, POST_EXTRA, FD_TARGET],
+    ),
+    (
+        "post_utimes",
+        Kind::Notification,
+        &[("path", Ty::Path)],
+        &[TAGS, POST_EXTRA, FD_TARGET],
+    ),
+    (
+        "post_chflags",
+        Kind::Notification,
+        &[("path", Ty::Path), ("flags", Ty::Int)],
+        &[TAGS, POST_EXTRA, FD_TARGET],
+    ),
+    (
+        "post_create_special",
+        Kind::Notification,
+        &[("path", Ty::Str), ("path_seq", Ty::Int), ("node", Ty::Str)],
+        &[TAGS, POST_EXTRA, &[("mode", Ty::Str)]],
+    ),
+    (
+        "shim/hello",
+        Kind::Request,
+        &[
+            ("pid", Ty::Int),
+            ("version", Ty::Str),
+            ("framing", Ty::Array),
+            ("max_frame_bytes", Ty::Int),
+        ],
+        &[&[
+            ("protocol", Ty::Int),
+            ("reliable", Ty::Bool),
+            ("threads", Ty::Bool),
+            ("cwd", Ty::Str),
+            ("env", Ty::Object),
+            ("hooks", Ty::Object),
+            ("cache_token", Ty::Str),
+        ]],
+    ),
+    (
+        "shim/budget_exceeded",
+        Kind::Notification,
+        &[
+            ("pid", Ty::Int),
+            ("blocked_ms", Ty::Int),
+            ("budget_ms", Ty::Int),
+        ],
+        &[&[("window_ms", Ty::Int)]],
+    ),
+    (
+        "shim/overflow",
+        Kind::Notification,
+        &[("pid", Ty::Int), ("count", Ty::Int)],
+        &[],
+    ),
+    (
+        "shim/dropped",
+        Kind::Notification,
+        &[("pid", Ty::Int), ("count", Ty::Int)],
+        &[],
+    ),
+    (
+        "shim/error",
+        Kind::Notification,
+        &[("pid", Ty::Int), ("error", Ty::Str), ("detail", Ty::Str)],
+        &[],
+    ),
+    (
+        "shim/cwd_changed",
+        Kind::Notification,
+        &[("pid", Ty::Int), ("cwd", Ty::Str)],
+        &[],
+    ),
+    (
+        "shim/probe",
+        Kind::Notification,
+        &[("pid", Ty::Int), ("path", Ty::Str), ("count", Ty::Int)],
+        &[TAGS, &[("path_seq", Ty::Int)]],
+    ),
+    (
+        "shim/credentials_changed",
+        Kind::Notification,
+        &[
+            ("pid", Ty::Int),
+            ("call", Ty::Str),
+            ("old", Ty::Object),
+            ("new", Ty::Object),
+        ],
+        &[],
+    ),
+    (
+        "shim/coverage_warning",
+        Kind::Notification,
+        &[("pid", Ty::Int), ("route", Ty::Str), ("missed", Ty::Array)],
+        &[],
+    ),
+    (
+        "shim/not_loaded",
+        Kind::Notification,
+        &[
+            ("pid", Ty::Int),
+            ("exe", Ty::Str),
+            ("reason", Ty::Str),
+            ("waited_ms", Ty::Int),
+            ("platform_binary", Ty::Bool),
+            ("hardened_runtime", Ty::Bool),
+            ("setid", Ty::Bool),
+        ],
+        &[],
+    ),
+    (
+        "shim/config_error",
+        Kind::Notification,
+        &[("pid", Ty::Int), ("message", Ty::Str)],
+        &[&[("path", Ty::Str), ("line", Ty::Int), ("variable", Ty::Str)]],
+    ),
+    (
+        "shim/ignored",
+        Kind::Notification,
+        &[("path", Ty::Str)],
+        &[&[("method", Ty::Str)]],
+    ),
+    (
+        "shim/forced",
+        Kind::Notification,
+        &[
+            ("op", Ty::Str),
+            ("path", Ty::Str),
+            ("forced", Ty::Str),
+            ("glob", Ty::Str),
+            ("allowed", Ty::Bool),
+        ],
+        &[TAGS],
+    ),
+    (
+        "shim/invalidated",
+        Kind::Notification,
+        &[("evicted", Ty::Int), ("rearmed", Ty::Int)],
+        &[],
+    ),
+    (
+        "shim/summary",
+        Kind::Notification,
+        &[
+            ("pid", Ty::Int),
+            ("overflow", Ty::Int),
+            ("paths", Ty::Array),
+        ],
+        &[],
+    ),
+    ("shim/ack", Kind::Either, &[("upto", Ty::Int)], &[]),
+    (
+        "shim/classify",
+        Kind::Either,
+        &[("path", Ty::Str)],
+        &[&[("op", Ty::Str)]],
+    ),
+    (
+        "shim/conflict_paths",
+        Kind::Either,
+        &[("paths", Ty::Array)],
+        &[],
+    ),
+    ("shim/flush", Kind::Either, &[], &[]),
+    (
+        "shim/ignore_audit",
+        Kind::Either,
+        &[("enabled", Ty::Bool)],
+        &[],
+    ),
+    ("shim/invalidate", Kind::Either, &[], &[]),
+    ("shim/invalidate_cache", Kind::Either, &[], &[]),
+    ("shim/query_path", Kind::Either, &[("path", Ty::Str)], &[]),
+    ("shim/reload_config", Kind::Either, &[], &[]),
+    ("shim/self_paths", Kind::Either, &[], &[]),
+    ("shim/stats", Kind::Either, &[], &[]),
+];
+
+/// Debug builds' per-call events, named `shim/<call>_call`; their params
+/// are for people, not for the contract.
+fn is_debug_event(method: &str) -> bool {
+    method.starts_with("shim/") && method.ends_with("_call")
+}
+
+/// Why a frame isn't one the protocol allows.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConformanceError {
+    NotJson,
+    NotObject,
+    /// `jsonrpc` is missing or isn't `"2.0"`.
+    Version,
+    /// Neither `method` nor `id`.
+    NotRpc,
+    UnknownMethod(String),
+    /// A request without an `id`, or a notification with one.
+    Kind {
+        method: String,
+        want_id: bool,
+    },
+    ParamsNotObject(String),
+    MissingParam {
+        method: String,
+        param: &'static str,
+    },
+    WrongType {
+        method: String,
+        param: &'static str,
+        want: &'static str,
+    },
+    /// A response needs exactly one of `result` and `error`.
+    ResultAndError,
+    /// `error` needs an integer `code` and a string `message`.
+    BadError,
+}
+
+impl fmt::Display for ConformanceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConformanceError::NotJson => write!(f, "not JSON"),
+            ConformanceError::NotObject => write!(f, "not a JSON object"),
+            ConformanceError::Version => write!(f, "jsonrpc is not \"2.0\""),
+            ConformanceError::NotRpc => write!(f, "neither method nor id"),
+            ConformanceError::UnknownMethod(m) => write!(f, "unknown method {m:?}"),
+            ConformanceError::Kind { method, want_id } => {
+                let (what, id) = if *want_id {
+                    ("request", "without")
+                } else {
+                    ("notification", "with")
+                };
+                write!(f, "{method} is a {what}, sent {id} an id")
+            }
+            ConformanceError::ParamsNotObject(m) => write!(f, "{m}: params is not an object"),
+            ConformanceError::MissingParam { method, param } => {
+                write!(f, "{method}: missing param {param}")
+            }
+            ConformanceError::WrongType {
+                method,
+                param,
+                want,
+            } => write!(f, "{method}: param {param} is not a {want}"),
+            ConformanceError::ResultAndError => {
+                write!(f, "response needs exactly one of result and error")
+            }
+            ConformanceError::BadError => {
+                write!(f, "error needs an integer code and a string message")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConformanceError {}
+
+/// Check one JSON-RPC payload against the protocol.
+pub fn validate_frame(frame: &[u8]) -> Result<(), ConformanceError> {
+    let v: Value = serde_json::from_slice(frame).map_err(|_| ConformanceError::NotJson)?;
+    let msg = v.as_object().ok_or(ConformanceError::NotObject)?;
+    if msg.get("jsonrpc").and_then(Value::as_str) != Some("2.0") {
+        return Err(ConformanceError::Version);
+    }
+    let id = msg.get("id").filter(|id| !id.is_null());
+    match (msg.get("method"), id) {
+        (Some(method), _) => {
+            let method = method
+                .as_str()
+                .ok_or_else(|| ConformanceError::UnknownMethod(method.to_string()))?;
+            call(method, id.is_some(), msg.get("params"))
+        }
+        (None, Some(_)) => response(msg),
+        (None, None) => Err(ConformanceError::NotRpc),
+    }
+}
+
+fn call(method: &str, has_id: bool, params: Option<&Value>) -> Result<(), ConformanceError> {
+    let empty = Map::new();
+    let params = match params {
+        None | Some(Value::Null) => &empty,
+        Some(Value::Object(p)) => p,
+        Some(_) => return Err(ConformanceError::ParamsNotObject(method.to_string())),
+    };
+    if is_debug_event(method) && !has_id {
+        return Ok(());
+    }
+    let &(_, kind, required, optional) = METHODS
+        .iter()
+        .find(|(m, ..)| *m == method)
+        .ok_or_else(|| ConformanceError::UnknownMethod(method.to_string()))?;
+    let want_id = match kind {
+        // A preflight once the process is exiting is only told; see `shutdown`.
+        Kind::Request if params.get("phase").and_then(Value::as_str) == Some("shutdown") => None,
+        Kind::Request => Some(true),
+        Kind::Notification => Some(false),
+        Kind::Either => None,
+    };
+    if let Some(want_id) = want_id.filter(|&w| w != has_id) {
+        return Err(ConformanceError::Kind {
+            method: method.to_string(),
+            want_id,
+        });
+    }
+    for &(param, _) in required {
+        if !params.contains_key(param) {
+            return Err(ConformanceError::MissingParam {
+                method: method.to_string(),
+                param,
+            });
+        }
+    }
+    for &(param, ty) in required.iter().chain(optional.iter().copied().flatten()) {
+        let Some(v) = params.get(param) else {
+            continue;
+        };
+        if !ty.admits(v) {
+            return Err(ConformanceError::WrongType {
+                method: method.to_string(),
+                param,
+                want: ty.name(),
+            });
+        }
+    }
+    Ok(())
+}
+
+fn response(msg: &Map<String, Value>) -> Result<(), ConformanceError> {
+    match (msg.get("result"), msg.get("error")) {
+        (Some(_), None) => Ok(()),
+        (None, Some(e)) => {
+            let ok = e.get("code").is_some_and(|c| c.is_i64()) && e["message"].is_string();
+            ok.then_some(()).ok_or(ConformanceError::BadError)
+        }
+        _ => Err(ConformanceError::ResultAndError),
+    }
+}
+
+/// The protocol version `METHODS` describes, which `shim/hello` names as
+/// `protocol`. It goes up when a method is removed or a param becomes
+/// required or changes type; new methods and optional params don't move it.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+fn schema_id(name: &str) -> String {
+    format!("urn:nvim-claude-shim:protocol:{PROTOCOL_VERSION}:{name}")
+}
+
+fn params_schema(required: Params, optional: &[Params]) -> Value {
+    let mut props = Map::new();
+    for &(param, ty) in optional.iter().copied().flatten().chain(required) {
+        props.insert(param.to_string(), ty.schema());
+    }
+    let names: Vec<&str> = required.iter().map(|&(p, _)| p).collect();
+    json!({ "type": "object", "required": names, "properties": props })
+}
+
+/// One method's frames, as a JSON Schema (2020-12) document.
+fn method_schema(method: &str, kind: Kind, required: Params, optional: &[Params]) -> Value {
+    let mut params = params_schema(required, optional);
+    let mut frame_required = vec!["jsonrpc", "method"];
+    if !required.is_empty() {
+        frame_required.push("params");
+    } else {
+        params["type"] = json!(["object", "null"]);
+    }
+    let (id, description) = match kind {
+        Kind::Request => (
+            json!({ "type": ["integer", "string"] }),
+            "A request: always has an `id`, and is always answered.",
+        ),
+        Kind::Notification => (
+            json!({ "type": "null" }),
+            "A notification: never has an `id`.",
+        ),
+        Kind::Either => (
+            json!({ "type": ["integer", "string", "null"] }),
+            "A server request, or the same sent as a notification.",
+        ),
+    };
+    let mut schema = json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "$id": schema_id(&method.replace('/', "_")),
+        "title": method,
+        "description": description,
+        "type": "object",
+        "required": frame_required,
+        "properties": {
+            "jsonrpc": { "const": "2.0" },
+            "method": { "const": method },
+            "id": id,
+            "params": params,
+        },
+    });
+    if kind == Kind::Request {
+        // Only told once the process is exiting; see `shutdown`.
+        let told = json!({
+            "required": ["params"],
+            "properties": {
+                "params": { "required": ["phase"], "properties": { "phase": { "const": "shutdown" } } },
+            },
+        });
+        schema["anyOf"] = json!([{ "required": ["id"] }, told]);
+    }
+    schema
+}
+
+/// What every frame fits: a call to a known method, or a response.
+fn envelope_schema() -> Value {
+    let methods: Vec<&str> = METHODS.iter().map(|&(m, ..)| m).collect();
+    let error = json!({
+        "type": "object",
+        "required": ["code", "message"],
+        "properties": { "code": { "type": "integer" }, "message": { "type": "string" } },
+    });
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "$id": schema_id("envelope"),
+        "title": "frame",
+        "description": "One JSON-RPC payload; a call's params are in its method's schema.",
+        "type": "object",
+        "required": ["jsonrpc"],
+        "properties": { "jsonrpc": { "const": "2.0" } },
+        "anyOf": [
+            { "required": ["method"], "properties": { "method": { "enum": methods } } },
+            { "required": ["id", "result"], "not": { "required": ["error"] } },
+            {
+                "required": ["id", "error"],
+                "not": { "required": ["result"] },
+                "properties": { "error": error },
+            },
+        ],
+    })
+}
+
+/// The JSON Schema documents for `PROTOCOL_VERSION`, by file name: the
+/// envelope, then one per method, named as the vectors are (`shim/hello`
+/// is `shim_hello`). Debug builds' `shim/<call>_call` events aren't
+/// covered.
+pub fn schemas() -> Vec<(String, Value)> {
+    let mut out = vec![("envelope".to_string(), envelope_schema())];
+    for &(method, kind, required, optional) in METHODS {
+        let schema = method_schema(method, kind, required, optional);
+        out.push((method.replace('/', "_"), schema));
+    }
+    out
+}
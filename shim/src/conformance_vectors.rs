@@ -0,0 +1,777 @@
+//! The protocol vectors under `testdata/protocol/`, made with the shim's
+//! own serializers and checked against `conformance`'s table. Kept out of
+//! `conformance.rs`, which the integration tests' mock server compiles as
+//! it is (it needs nothing but `serde_json`) to check every frame it reads.
+
+use serde_json::{json, Value};
+
+use crate::config::ShimConfig;
+use crate::conformance::{schemas, validate_frame, ConformanceError, PROTOCOL_VERSION};
+use crate::demux::Incoming;
+use crate::fdtable::{FdStat, FdState};
+use crate::handlers::{modify_params, rename_extra, rename_post};
+use crate::{encode_notification, encode_response, AllowedBy, Decision, RpcCall};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+fn request(id: u64, method: &str, params: Value) -> Vec<u8> {
+    serde_json::to_vec(&RpcCall {
+        jsonrpc: "2.0",
+        id: Some(id),
+        method,
+        params: Some(params),
+    })
+    .unwrap()
+}
+
+fn notification(method: &str, params: Value) -> Vec<u8> {
+    encode_notification(method, params).unwrap()
+}
+
+/// One frame per message type, as the shim (or, for the server's
+/// side, its own request builder) serializes it.
+fn valid() -> Vec<(&'static str, Vec<u8>)> {
+    let pre = |op| {
+        json!({
+            "pid": 4243, "root_pid": 4242, "root_argv0": "bash",
+            "path": "/p/a.rs", "path_seq": 1, "source": op, "class": "normal",
+        })
+    };
+    let merged = |mut params: Value, extra: Value| {
+        if let (Some(p), Value::Object(extra)) = (params.as_object_mut(), extra) {
+            p.extend(extra);
+        }
+        params
+    };
+    // What `post_params` adds to every post on its way out.
+    let tagged = |params, path_seq: u64| {
+        let tags = json!({
+            "pid": 4243, "root_pid": 4242, "root_argv0": "bash",
+            "path_seq": path_seq, "class": "normal",
+        });
+        merged(params, tags)
+    };
+    let a_rs = Path::new("/p/a.rs");
+    let asked = |blocked_ms| Decision {
+        blocked: Duration::from_millis(blocked_ms),
+        by: Some(AllowedBy::Server),
+        ..Decision::default()
+    };
+    let wrote = |decision, bytes| FdState {
+        path: Some(a_rs.to_path_buf()),
+        bytes,
+        requested: bytes,
+        decision,
+        ..FdState::default()
+    };
+    let cfg = ShimConfig::default();
+    let modified = |s: &FdState| modify_params(s, a_rs, &cfg, None);
+    let post = tagged(modified(&wrote(asked(3), 12)), 2);
+    let renamed = |clobbers| rename_post(asked(3), a_rs, clobbers, clobbers).1;
+    vec![
+        ("pre_modify", request(1, "pre_modify", pre("open"))),
+        ("pre_create", request(2, "pre_create", pre("open"))),
+        ("pre_delete", request(3, "pre_delete", pre("unlink"))),
+        (
+            "pre_rename",
+            request(
+                4,
+                "pre_rename",
+                merged(pre("rename"), rename_extra(Some(Path::new("/p/a.rs.tmp")), None)),
+            ),
+        ),
+        (
+            "pre_rename_clobber",
+            request(4, "pre_rename", {
+                let dest = FdStat {
+                    dev: 1,
+                    ino: 7,
+                    size: 2048,
+                    perm: 0o644,
+                    uid: 501,
+                    regular: true,
+                    mtime: 1_760_000_000,
+                };
+                merged(pre("rename"), rename_extra(Some(Path::new("/p/b.rs")), Some(dest)))
+            }),
+        ),
+        ("pre_truncate", request(5, "pre_truncate", pre("truncate"))),
+        ("pre_acl", request(14, "pre_acl", pre("acl_set_file"))),
+        (
+            "pre_modify_privileged",
+            request(1, "pre_modify", {
+                let mut params = pre("open");
+                params["euid"] = json!(0);
+                params["egid"] = json!(0);
+                params
+            }),
+        ),
+        (
+            "shutdown_preflight",
+            notification(
+                "pre_create",
+                json!({
+                    "pid": 4243, "root_pid": 4242, "root_argv0": "bash",
+                    "path": "/p/.cache", "path_seq": 1, "source": "open", "phase": "shutdown",
+                }),
+            ),
+        ),
+        ("post_modify", notification("post_modify", post.clone())),
+        (
+            "post_create",
+            notification("post_create", tagged(renamed(false), 2)),
+        ),
+        (
+            "post_modify_coalesced",
+            notification(
+                "post_modify",
+                tagged(
+                    modified(&wrote(
+                        Decision {
+                            coalesced: 7,
+                            ..asked(40)
+                        },
+                        12,
+                    )),
+                    2,
+                ),
+            ),
+        ),
+        (
+            "post_modify_content_features_skipped",
+            notification(
+                "post_modify",
+                json!({
+                    "path": "/p/movie.mp4", "path_seq": 2, "bytes": 1_073_741_824,
+                    "content_features_skipped": true,
+                }),
+            ),
+        ),
+        (
+            "post_modify_rate_limited",
+            notification(
+                "post_modify",
+                tagged(
+                    modified(&wrote(
+                        Decision {
+                            rate_limited: true,
+                            ..asked(0)
+                        },
+                        12,
+                    )),
+                    2,
+                ),
+            ),
+        ),
+        (
+            "post_modify_clobbered",
+            notification(
+                "post_modify",
+                tagged(renamed(true), 2),
+            ),
+        ),
+        (
+            "post_modify_age_flush",
+            notification(
+                "post_modify",
+                json!({ "path": "/p/app.db-wal", "path_seq": 2, "bytes": 4120, "reason": "age_flush" }),
+            ),
+        ),
+        (
+            "post_modify_concurrent",
+            notification(
+                "post_modify",
+                json!({
+                    "path": "/p/out.log", "path_seq": 4, "bytes": 512,
+                    "dev": 2049, "ino": 1311, "opened_ms": 1760400000000u64, "op_id": 3,
+                    "concurrent_writers": true, "concurrent_op_id": 2,
+                }),
+            ),
+        ),
+        (
+            "post_delete",
+            notification(
+                "post_delete",
+                Decision::default().annotate(json!({ "path": "/p/a.rs", "path_seq": 3 })),
+            ),
+        ),
+        (
+            "post_acl",
+            notification(
+                "post_acl",
+                json!({ "path": "/p/a.rs", "path_seq": 4, "acl": "user::rw-\ngroup::r--\nother::r--\n" }),
+            ),
+        ),
+        (
+            "post_chmod",
+            notification(
+                "post_chmod",
+                json!({ "path": "/p/a.rs", "path_seq": 5, "mode": "0755" }),
+            ),
+        ),
+        (
+            "post_chmod_unlinked",
+            notification(
+                "post_chmod",
+                json!({ "path": null, "dev": 64769, "ino": 1311, "mode": "0600", "last_path": "/p/a.rs" }),
+            ),
+        ),
+        (
+            "post_utimes",
+            notification(
+                "post_utimes",
+                json!({ "path": "/p/a.rs", "path_seq": 6 }),
+            ),
+        ),
+        (
+            "post_chflags",
+            notification(
+                "post_chflags",
+                json!({ "path": "/p/a.rs", "path_seq": 7, "flags": 2 }),
+            ),
+        ),
+        (
+            "post_create_special",
+            notification(
+                "post_create_special",
+                json!({ "path": "/p/build.fifo", "path_seq": 1, "node": "fifo", "mode": "0644" }),
+            ),
+        ),
+        (
+            "post_rename",
+            notification(
+                "post_rename",
+                json!({ "path": "/q/a.rs", "path_seq": 1, "old_path": "/p/a.rs", "via": "copy" }),
+            ),
+        ),
+        (
+            "post_lock",
+            notification(
+                "post_lock",
+                json!({
+                    "path": "/p/app.db", "path_seq": 2, "lock": "exclusive",
+                    "via": "fcntl", "blocking": true,
+                }),
+            ),
+        ),
+        (
+            "post_unlock",
+            notification(
+                "post_unlock",
+                json!({ "path": "/p/app.db", "path_seq": 3, "via": "fcntl", "reason": "close" }),
+            ),
+        ),
+        (
+            "post_dir_changed",
+            notification(
+                "post_dir_changed",
+                json!({ "path": "/p/src", "path_seq": 4, "files": 12, "synthesized": true }),
+            ),
+        ),
+        (
+            "post_touch",
+            notification(
+                "post_touch",
+                json!({
+                    "path": "/p/out", "path_seq": 5, "touches": 250, "files": 250,
+                    "sample": ["a.o", "b.o"], "synthesized": true,
+                }),
+            ),
+        ),
+        (
+            "post_refactor_rewrite",
+            notification(
+                "post_refactor",
+                json!({
+                    "path": "/p/a.rs", "path_seq": 3, "kind": "rewrite",
+                    "backup": "/p/a.rs.bak", "synthesized": true,
+                }),
+            ),
+        ),
+        (
+            "post_refactor_move",
+            notification(
+                "post_refactor",
+                json!({
+                    "path": "/p/c.rs", "path_seq": 2, "kind": "move", "old_path": "/p/a.rs",
+                    "via": ["/p/b.rs"], "synthesized": true,
+                }),
+            ),
+        ),
+        (
+            "reliable_post",
+            notification(
+                "post_modify",
+                json!({ "path": "/p/a.rs", "path_seq": 4, "bytes": 1, "seq": 7 }),
+            ),
+        ),
+        (
+            "shim_hello",
+            request(
+                6,
+                "shim/hello",
+                json!({
+                    "pid": 4242, "version": "0.1.0", "protocol": PROTOCOL_VERSION,
+                    "framing": crate::framing::OFFERED,
+                    "max_frame_bytes": 16 << 20, "reliable": true, "threads": true,
+                    "cwd": "/p", "env": { "PWD": "/p" },
+                    "hooks": { "active": ["open", "write", "close"], "disabled": ["ftruncate"] },
+                }),
+            ),
+        ),
+        (
+            "shim_hello_result",
+            encode_response(
+                &json!(6),
+                Ok(json!({ "framing": "length-prefixed", "reliable": true })),
+            )
+            .unwrap(),
+        ),
+        (
+            "shim_hello_seeding_result",
+            encode_response(
+                &json!(6),
+                Ok(json!({ "allow": [{ "op": "pre_modify", "path": "/p/a.rs", "ttl_ms": 30000 }] })),
+            )
+            .unwrap(),
+        ),
+        (
+            "preflight_result",
+            encode_response(&json!(1), Ok(json!({ "allow": false }))).unwrap(),
+        ),
+        (
+            "preflight_cache_token_result",
+            encode_response(&json!(1), Ok(json!({ "allow": true, "cache_token": "s-81f2" })))
+                .unwrap(),
+        ),
+        (
+            "preflight_bare_result",
+            encode_response(&json!(1), Ok(json!(true))).unwrap(),
+        ),
+        (
+            "method_not_found",
+            encode_response(
+                &json!(9),
+                Err(json!({ "code": -32601, "message": "method not found: x" })),
+            )
+            .unwrap(),
+        ),
+        (
+            "shim_budget_exceeded",
+            notification(
+                "shim/budget_exceeded",
+                json!({ "pid": 4242, "blocked_ms": 30200, "budget_ms": 30000, "window_ms": 60000 }),
+            ),
+        ),
+        (
+            "shim_overflow",
+            notification("shim/overflow", json!({ "pid": 4242, "count": 3 })),
+        ),
+        (
+            "shim_dropped",
+            notification("shim/dropped", json!({ "pid": 4242, "count": 3 })),
+        ),
+        (
+            "shim_error",
+            notification(
+                "shim/error",
+                json!({ "pid": 4242, "error": "dlsym_missing", "detail": "write" }),
+            ),
+        ),
+        (
+            "shim_coverage_warning",
+            notification(
+                "shim/coverage_warning",
+                json!({ "pid": 4242, "route": "dlsym", "missed": ["write", "close"] }),
+            ),
+        ),
+        (
+            "shim_cwd_changed",
+            notification("shim/cwd_changed", json!({ "pid": 4242, "cwd": "/p" })),
+        ),
+        (
+            "shim_probe",
+            notification(
+                "shim/probe",
+                json!({ "pid": 4242, "path": "/p/.env", "path_seq": 3, "count": 7 }),
+            ),
+        ),
+        (
+            "shim_credentials_changed",
+            notification(
+                "shim/credentials_changed",
+                json!({
+                    "pid": 4242, "call": "seteuid",
+                    "old": { "uid": 0, "euid": 0, "gid": 0, "egid": 0 },
+                    "new": { "uid": 0, "euid": 1000, "gid": 0, "egid": 0 },
+                }),
+            ),
+        ),
+        (
+            "shim_not_loaded",
+            notification(
+                "shim/not_loaded",
+                json!({
+                    "pid": 4242,
+                    "exe": "/usr/bin/make",
+                    "reason": "timeout",
+                    "waited_ms": 2000,
+                    "platform_binary": true,
+                    "hardened_runtime": false,
+                    "setid": false,
+                }),
+            ),
+        ),
+        (
+            "shim_config_error",
+            notification(
+                "shim/config_error",
+                json!({
+                    "pid": 4242,
+                    "path": "/p/.nvim-claude/shim.toml",
+                    "line": 3,
+                    "message": "unknown variant `blok`",
+                }),
+            ),
+        ),
+        (
+            "shim_config_error_env",
+            notification(
+                "shim/config_error",
+                json!({
+                    "pid": 4242,
+                    "variable": "NVIM_CLAUDE_SHIM_TCP",
+                    "message": "\"localhost\": no port (host:port)",
+                }),
+            ),
+        ),
+        (
+            "shim_ignored",
+            notification(
+                "shim/ignored",
+                json!({ "path": "/p/x.log", "method": "post_modify" }),
+            ),
+        ),
+        (
+            "shim_forced",
+            notification(
+                "shim/forced",
+                json!({
+                    "op": "pre_modify", "path": "/p/a.rs", "forced": "timeout",
+                    "glob": "**/a.rs", "allowed": false, "pid": 4242, "tid": 7,
+                }),
+            ),
+        ),
+        (
+            "shim_invalidated",
+            notification("shim/invalidated", json!({ "evicted": 1, "rearmed": 0 })),
+        ),
+        (
+            "shim_summary",
+            notification(
+                "shim/summary",
+                json!({ "pid": 4242, "overflow": 0, "paths": [
+                    { "path": "/p/a.rs", "ops": ["create", "modify"], "bytes": 120 },
+                    { "path": "/p/b.rs", "ops": [], "bytes": 0, "denied": ["delete"] },
+                ] }),
+            ),
+        ),
+        ("shim_ack", notification("shim/ack", json!({ "upto": 7 }))),
+        (
+            "shim_classify",
+            request(
+                17,
+                "shim/classify",
+                json!({ "path": "/p/build/out.log", "op": "pre_modify" }),
+            ),
+        ),
+        (
+            "shim_conflict_paths",
+            notification("shim/conflict_paths", json!({ "paths": ["/p/a.rs"] })),
+        ),
+        ("shim_flush", request(10, "shim/flush", json!({}))),
+        (
+            "shim_ignore_audit",
+            request(11, "shim/ignore_audit", json!({ "enabled": true })),
+        ),
+        (
+            "shim_invalidate",
+            notification("shim/invalidate", json!({ "glob": "*.rs" })),
+        ),
+        (
+            "shim_invalidate_cache",
+            request(12, "shim/invalidate_cache", json!({})),
+        ),
+        (
+            "shim_query_path",
+            request(16, "shim/query_path", json!({ "path": "/p/dist/bundle.js" })),
+        ),
+        (
+            "shim_reload_config",
+            request(15, "shim/reload_config", json!({})),
+        ),
+        (
+            "shim_self_paths",
+            request(
+                14,
+                "shim/self_paths",
+                json!({ "paths": ["/p/.nvim-claude/baseline.json"], "globs": ["**/.nvim-claude/logs/**"] }),
+            ),
+        ),
+        (
+            "self_paths_in_result",
+            encode_response(
+                &json!(1),
+                Ok(json!({ "allow": true, "self_paths": { "paths": ["/p/.nvim-claude/state"] } })),
+            )
+            .unwrap(),
+        ),
+        ("shim_stats", request(13, "shim/stats", json!({}))),
+        (
+            "debug_event",
+            notification("shim/write_call", json!({ "fd": 3, "res": 12 })),
+        ),
+    ]
+}
+
+/// Frames either end must refuse, and why.
+fn invalid() -> Vec<(&'static str, Vec<u8>, ConformanceError)> {
+    let bytes = |v: Value| serde_json::to_vec(&v).unwrap();
+    vec![
+        (
+            "not_json",
+            b"{\"jsonrpc\":".to_vec(),
+            ConformanceError::NotJson,
+        ),
+        (
+            "not_object",
+            bytes(json!([1, 2])),
+            ConformanceError::NotObject,
+        ),
+        (
+            "wrong_version",
+            bytes(json!({ "jsonrpc": "1.0", "method": "shim/flush" })),
+            ConformanceError::Version,
+        ),
+        (
+            "not_rpc",
+            bytes(json!({ "jsonrpc": "2.0" })),
+            ConformanceError::NotRpc,
+        ),
+        (
+            "unknown_method",
+            notification("post_chown", json!({ "path": "/p/a.rs" })),
+            ConformanceError::UnknownMethod("post_chown".into()),
+        ),
+        (
+            "preflight_without_id",
+            notification(
+                "pre_modify",
+                json!({ "pid": 1, "path": "/p/a.rs", "path_seq": 1 }),
+            ),
+            ConformanceError::Kind {
+                method: "pre_modify".into(),
+                want_id: true,
+            },
+        ),
+        (
+            "post_with_id",
+            request(
+                1,
+                "post_delete",
+                json!({ "path": "/p/a.rs", "path_seq": 1 }),
+            ),
+            ConformanceError::Kind {
+                method: "post_delete".into(),
+                want_id: false,
+            },
+        ),
+        (
+            "params_not_object",
+            request(1, "shim/stats", json!([])),
+            ConformanceError::ParamsNotObject("shim/stats".into()),
+        ),
+        (
+            "preflight_without_path",
+            request(1, "pre_modify", json!({ "pid": 1, "path_seq": 1 })),
+            ConformanceError::MissingParam {
+                method: "pre_modify".into(),
+                param: "path",
+            },
+        ),
+        (
+            "path_seq_as_string",
+            notification("post_create", json!({ "path": "/p/a.rs", "path_seq": "1" })),
+            ConformanceError::WrongType {
+                method: "post_create".into(),
+                param: "path_seq",
+                want: "integer",
+            },
+        ),
+        (
+            "result_and_error",
+            bytes(
+                json!({ "jsonrpc": "2.0", "id": 1, "result": true, "error": { "code": 1, "message": "x" } }),
+            ),
+            ConformanceError::ResultAndError,
+        ),
+        (
+            "error_without_code",
+            bytes(json!({ "jsonrpc": "2.0", "id": 1, "error": { "message": "x" } })),
+            ConformanceError::BadError,
+        ),
+    ]
+}
+
+fn dir(which: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("testdata/protocol")
+        .join(which)
+}
+
+/// What's checked in under `which`, or (with `PROTOCOL_FIXTURES=write`)
+/// `frames` written there first.
+fn check_in(which: &str, frames: &[(&str, &[u8])]) {
+    let dir = dir(which);
+    if std::env::var("PROTOCOL_FIXTURES").as_deref() == Ok("write") {
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, frame) in frames {
+            let mut text = frame.to_vec();
+            text.push(b'\n');
+            std::fs::write(dir.join(format!("{name}.json")), text).unwrap();
+        }
+    }
+    for (name, frame) in frames {
+        let path = dir.join(format!("{name}.json"));
+        let on_disk = std::fs::read(&path).unwrap_or_else(|e| {
+            panic!(
+                "{}: {e}; regenerate with PROTOCOL_FIXTURES=write",
+                path.display()
+            )
+        });
+        assert_eq!(
+            on_disk.strip_suffix(b"\n").unwrap_or(&on_disk),
+            *frame,
+            "{} is stale; regenerate with PROTOCOL_FIXTURES=write",
+            path.display()
+        );
+    }
+    let checked_in = std::fs::read_dir(&dir).unwrap().count();
+    assert_eq!(
+        checked_in,
+        frames.len(),
+        "{}: fixtures nobody generates",
+        dir.display()
+    );
+}
+
+#[test]
+fn valid_vectors_pass_and_round_trip() {
+    let frames = valid();
+    let named: Vec<(&str, &[u8])> = frames.iter().map(|(n, f)| (*n, &f[..])).collect();
+    check_in("valid", &named);
+    for (name, frame) in &frames {
+        validate_frame(frame).unwrap_or_else(|e| panic!("{name}: {e}"));
+        // What the shim reads back, it writes out the same way.
+        let original: Value = serde_json::from_slice(frame).unwrap();
+        let again = match Incoming::from_json(original.clone()).unwrap() {
+            Incoming::Request { id, method, params } => serde_json::to_vec(&RpcCall {
+                jsonrpc: "2.0",
+                id: id.as_u64(),
+                method: &method,
+                params: Some(params),
+            })
+            .unwrap(),
+            Incoming::Notification { method, params } => notification(&method, params),
+            Incoming::Response { id, result } => encode_response(&id, result).unwrap(),
+        };
+        assert_eq!(
+            serde_json::from_slice::<Value>(&again).unwrap(),
+            original,
+            "{name}"
+        );
+    }
+}
+
+/// Whether `v` fits `schema`, for the keywords `schemas` uses.
+fn fits(schema: &Value, v: &Value) -> bool {
+    let kw = |k: &str| schema.get(k);
+    let is = |ty: &str| match ty {
+        "integer" => v.is_i64() || v.is_u64(),
+        "string" => v.is_string(),
+        "boolean" => v.is_boolean(),
+        "array" => v.is_array(),
+        "object" => v.is_object(),
+        "null" => v.is_null(),
+        _ => false,
+    };
+    let typed = match kw("type") {
+        None => true,
+        Some(Value::String(ty)) => is(ty),
+        Some(Value::Array(tys)) => tys.iter().filter_map(Value::as_str).any(is),
+        Some(_) => false,
+    };
+    let obj = v.as_object();
+    typed
+        && kw("const").is_none_or(|c| c == v)
+        && kw("enum").is_none_or(|e| e.as_array().is_some_and(|e| e.contains(v)))
+        && match (kw("required").and_then(Value::as_array), obj) {
+            (Some(req), Some(o)) => req
+                .iter()
+                .filter_map(Value::as_str)
+                .all(|k| o.contains_key(k)),
+            _ => true,
+        }
+        && match (kw("properties").and_then(Value::as_object), obj) {
+            (Some(props), Some(o)) => props
+                .iter()
+                .all(|(k, s)| o.get(k).is_none_or(|v| fits(s, v))),
+            _ => true,
+        }
+        && kw("anyOf")
+            .and_then(Value::as_array)
+            .is_none_or(|any| any.iter().any(|s| fits(s, v)))
+        && kw("not").is_none_or(|not| !fits(not, v))
+}
+
+#[test]
+fn schemas_are_checked_in_and_agree_with_the_vectors() {
+    let docs = schemas();
+    let texts: Vec<(&str, Vec<u8>)> = docs
+        .iter()
+        .map(|(n, d)| (n.as_str(), serde_json::to_vec_pretty(d).unwrap()))
+        .collect();
+    let named: Vec<(&str, &[u8])> = texts.iter().map(|(n, t)| (*n, &t[..])).collect();
+    check_in(&format!("schema/v{PROTOCOL_VERSION}"), &named);
+
+    let conforms = |frame: &[u8]| {
+        let Ok(v) = serde_json::from_slice::<Value>(frame) else {
+            return false;
+        };
+        let method = match v.get("method").and_then(Value::as_str) {
+            Some(m) => docs
+                .iter()
+                .find(|(n, _)| *n == m.replace('/', "_"))
+                .is_some_and(|(_, schema)| fits(schema, &v)),
+            None => true,
+        };
+        fits(&docs[0].1, &v) && method
+    };
+    // Debug events are outside the schemas.
+    for (name, frame) in valid().into_iter().filter(|(n, _)| *n != "debug_event") {
+        assert!(conforms(&frame), "{name}");
+    }
+    for (name, frame, _) in invalid() {
+        assert!(!conforms(&frame), "{name}");
+    }
+}
+
+#[test]
+fn invalid_vectors_are_refused_for_their_reason() {
+    let frames = invalid();
+    let named: Vec<(&str, &[u8])> = frames.iter().map(|(n, f, _)| (*n, &f[..])).collect();
+    check_in("invalid", &named);
+    for (name, frame, why) in &frames {
+        assert_eq!(validate_frame(frame).as_ref(), Err(why), "{name}");
+    }
+}
@@ -0,0 +1,416 @@
+//! Panics stop at the hook.
+//!
+//! A panic unwinding out of an `extern "C"` hook aborts the host process,
+//! the worst thing an injected library can do. So every hook runs its
+//! handler under `catch_unwind`. A caught panic is counted, reported on
+//! stderr in one line, and the call is completed untracked. If the
+//! handler had already made the real call (`ran`), its result stands;
+//! otherwise the original is called. After `DISABLE_AFTER` panics a hook
+//! stops tracking for the life of the process and only passes calls
+//! through. The counts are in `shim/stats`.
+//!
+//! A hook can also be turned off up front, without a rebuild, when it is
+//! known to break some program: `disable_hooks` names them (a hook's name
+//! as `shim/stats` gives it, or any symbol it covers, like `openat` or
+//! `renameatx_np`). The names are read once, in the library constructor,
+//! into a bitset, and a disabled hook calls the original after one
+//! relaxed load. `shim/hello` lists the hooks this platform has as
+//! `"hooks": {"active": [...], "disabled": [...]}`, so a server can tell
+//! when coverage is down, whether by config or by panics.
+//!
+//! A hook entered beneath another on the same thread, or in a critical
+//! section, passes its call through before any of this (see `critical`).
+//!
+//! `NVIM_CLAUDE_SHIM_PANIC_IN=<hook>` makes that hook panic right after
+//! its real call, for testing all of this.
+
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::os::raw::c_int;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use once_cell::sync::Lazy;
+use serde_json::{json, Map, Value};
+
+use crate::{bypass, config, critical, platform, report_error, verify};
+
+const DISABLE_AFTER: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Hook {
+    Open,
+    Write,
+    Pwrite,
+    Writev,
+    Pwritev,
+    Close,
+    Unlink,
+    Unlinkat,
+    Rename,
+    Renameat,
+    Truncate,
+    Ftruncate,
+    Chdir,
+    Fchdir,
+    Fflush,
+    Copyfile,
+    Removefile,
+    Renamex,
+    Mkfifo,
+    Mkfifoat,
+    Mknod,
+    Exit,
+    UExit,
+    AclSetFile,
+    AclSetLink,
+    AclSetFd,
+    Fchmod,
+    Futimens,
+    Futimes,
+    Utimensat,
+    Utimes,
+    Fchflags,
+    ShmOpen,
+    ShmUnlink,
+    Flock,
+    Fcntl,
+    Lockf,
+    Setuid,
+    Seteuid,
+    Setgid,
+    Setegid,
+    Exec,
+    Spawn,
+}
+
+const HOOKS: [Hook; 43] = [
+    Hook::Open,
+    Hook::Write,
+    Hook::Pwrite,
+    Hook::Writev,
+    Hook::Pwritev,
+    Hook::Close,
+    Hook::Unlink,
+    Hook::Unlinkat,
+    Hook::Rename,
+    Hook::Renameat,
+    Hook::Truncate,
+    Hook::Ftruncate,
+    Hook::Chdir,
+    Hook::Fchdir,
+    Hook::Fflush,
+    Hook::Copyfile,
+    Hook::Removefile,
+    Hook::Renamex,
+    Hook::Mkfifo,
+    Hook::Mkfifoat,
+    Hook::Mknod,
+    Hook::Exit,
+    Hook::UExit,
+    Hook::AclSetFile,
+    Hook::AclSetLink,
+    Hook::AclSetFd,
+    Hook::Fchmod,
+    Hook::Futimens,
+    Hook::Futimes,
+    Hook::Utimensat,
+    Hook::Utimes,
+    Hook::Fchflags,
+    Hook::ShmOpen,
+    Hook::ShmUnlink,
+    Hook::Flock,
+    Hook::Fcntl,
+    Hook::Lockf,
+    Hook::Setuid,
+    Hook::Seteuid,
+    Hook::Setgid,
+    Hook::Setegid,
+    Hook::Exec,
+    Hook::Spawn,
+];
+
+impl Hook {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Hook::Open => "open",
+            Hook::Write => "write",
+            Hook::Pwrite => "pwrite",
+            Hook::Writev => "writev",
+            Hook::Pwritev => "pwritev",
+            Hook::Close => "close",
+            Hook::Unlink => "unlink",
+            Hook::Unlinkat => "unlinkat",
+            Hook::Rename => "rename",
+            Hook::Renameat => "renameat",
+            Hook::Truncate => "truncate",
+            Hook::Ftruncate => "ftruncate",
+            Hook::Chdir => "chdir",
+            Hook::Fchdir => "fchdir",
+            Hook::Fflush => "fflush",
+            Hook::Copyfile => "copyfile",
+            Hook::Removefile => "removefile",
+            Hook::Renamex => "renamex",
+            Hook::Mkfifo => "mkfifo",
+            Hook::Mkfifoat => "mkfifoat",
+            Hook::Mknod => "mknod",
+            Hook::Exit => "exit",
+            Hook::UExit => "_exit",
+            Hook::AclSetFile => "acl_set_file",
+            Hook::AclSetLink => "acl_set_link_np",
+            Hook::AclSetFd => "acl_set_fd",
+            Hook::Fchmod => "fchmod",
+            Hook::Futimens => "futimens",
+            Hook::Futimes => "futimes",
+            Hook::Utimensat => "utimensat",
+            Hook::Utimes => "utimes",
+            Hook::Fchflags => "fchflags",
+            Hook::ShmOpen => "shm_open",
+            Hook::ShmUnlink => "shm_unlink",
+            Hook::Flock => "flock",
+            Hook::Fcntl => "fcntl",
+            Hook::Lockf => "lockf",
+            Hook::Setuid => "setuid",
+            Hook::Seteuid => "seteuid",
+            Hook::Setgid => "setgid",
+            Hook::Setegid => "setegid",
+            Hook::Exec => "execve",
+            Hook::Spawn => "posix_spawn",
+        }
+    }
+
+    /// The hook `name` picks out: its own name, or a symbol it covers.
+    fn named(name: &str) -> Option<Hook> {
+        let hook = match name {
+            "open64" | "openat" | "openat64" => Hook::Open,
+            "pwrite64" => Hook::Pwrite,
+            "pwritev64" => Hook::Pwritev,
+            "renameat2" => Hook::Renameat,
+            "truncate64" => Hook::Truncate,
+            "ftruncate64" => Hook::Ftruncate,
+            "fcntl64" => Hook::Fcntl,
+            "lockf64" => Hook::Lockf,
+            "renamex_np" | "renameatx_np" => Hook::Renamex,
+            "execv" | "execvp" => Hook::Exec,
+            "posix_spawnp" => Hook::Spawn,
+            name => return HOOKS.into_iter().find(|h| h.name() == name),
+        };
+        Some(hook)
+    }
+
+    /// Whether this platform has the hook at all.
+    fn here(self) -> bool {
+        match self {
+            Hook::Pwritev | Hook::Unlinkat | Hook::Renameat | Hook::Lockf => {
+                cfg!(target_os = "linux")
+            }
+            Hook::Copyfile
+            | Hook::Removefile
+            | Hook::Renamex
+            | Hook::AclSetLink
+            | Hook::Fchflags => cfg!(target_os = "macos"),
+            _ => true,
+        }
+    }
+}
+
+static PANICS: [AtomicU32; HOOKS.len()] = [const { AtomicU32::new(0) }; HOOKS.len()];
+
+/// Bit `hook as usize` for each hook `disable_hooks` turned off.
+static DISABLED: AtomicU64 = AtomicU64::new(0);
+
+static INJECT: Lazy<Option<Hook>> = Lazy::new(|| {
+    let name = std::env::var("NVIM_CLAUDE_SHIM_PANIC_IN").ok()?;
+    HOOKS.into_iter().find(|h| h.name() == name)
+});
+
+/// A hook's return value: an `int` or an `ssize_t`.
+pub(crate) trait Rc: Copy {
+    fn widen(self) -> i64;
+    fn narrow(v: i64) -> Self;
+}
+
+impl Rc for c_int {
+    fn widen(self) -> i64 {
+        self.into()
+    }
+    fn narrow(v: i64) -> Self {
+        v as c_int
+    }
+}
+
+impl Rc for isize {
+    fn widen(self) -> i64 {
+        self as i64
+    }
+    fn narrow(v: i64) -> Self {
+        v as isize
+    }
+}
+
+/// What the innermost running hook knows about itself.
+#[derive(Clone, Copy)]
+struct Frame {
+    hook: Hook,
+    /// The real call's result and errno, once made.
+    ran: Option<(i64, c_int)>,
+}
+
+thread_local! {
+    static FRAME: Cell<Option<Frame>> = const { Cell::new(None) };
+    /// Where the last caught panic happened, from the panic hook.
+    static WHERE: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Run `handler` for `hook`, falling back on `original` if it panics (or
+/// if the hook was disabled, by config or by earlier panics).
+pub(crate) fn hook<T: Rc>(
+    hook: Hook,
+    handler: impl FnOnce() -> T,
+    original: impl FnOnce() -> T,
+) -> T {
+    // Before anything that could lock or allocate; see `critical`.
+    if critical::active() || FRAME.try_with(|f| f.get().is_some()).unwrap_or(false) {
+        critical::passed();
+        return original();
+    }
+    if bypass::on() || verify::canary(hook) {
+        return original();
+    }
+    if DISABLED.load(Ordering::Relaxed) & 1 << hook as usize != 0 {
+        return original();
+    }
+    let count = &PANICS[hook as usize];
+    if count.load(Ordering::Relaxed) >= DISABLE_AFTER {
+        return original();
+    }
+    let outer = FRAME.replace(Some(Frame { hook, ran: None }));
+    let res = panic::catch_unwind(AssertUnwindSafe(handler));
+    let frame = FRAME.replace(outer);
+    let payload = match res {
+        Ok(v) => return v,
+        Err(payload) => payload,
+    };
+    let n = count.fetch_add(1, Ordering::Relaxed) + 1;
+    report(hook, n, &*payload);
+    match frame.and_then(|f| f.ran) {
+        Some((rc, errno)) => {
+            platform::set_errno(errno);
+            T::narrow(rc)
+        }
+        None => original(),
+    }
+}
+
+/// Note the real call's result, so a later panic doesn't repeat it.
+pub(crate) fn ran<T: Rc>(rc: T) -> T {
+    let errno = std::io::Error::last_os_error().raw_os_error().unwrap_or(0);
+    if let Some(mut f) = FRAME.get() {
+        f.ran = Some((rc.widen(), errno));
+        FRAME.set(Some(f));
+        if *INJECT == Some(f.hook) {
+            panic!("injected by NVIM_CLAUDE_SHIM_PANIC_IN");
+        }
+    }
+    rc
+}
+
+/// Keep the host's panic hook for its own panics; ours are only noted for
+/// the report. Installed once, at library load.
+pub(crate) fn install_panic_hook() {
+    let theirs = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        if FRAME.get().is_none() {
+            return theirs(info);
+        }
+        let at = info
+            .location()
+            .map(|l| format!("{}:{}", l.file(), l.line()));
+        WHERE.with(|w| *w.borrow_mut() = at);
+    }));
+}
+
+fn report(hook: Hook, n: u32, payload: &(dyn Any + Send)) {
+    let msg = payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("non-string payload");
+    let at = WHERE.with(|w| w.borrow_mut().take()).unwrap_or_default();
+    let then = if n >= DISABLE_AFTER {
+        "hook disabled"
+    } else {
+        "call passed through"
+    };
+    report_error(
+        "handler_panic",
+        &format!("{} hook, panic {n}: {msg} at {at}; {then}", hook.name()),
+        None,
+    );
+}
+
+/// Turn off the hooks `disable_hooks` names; from the library constructor.
+pub(crate) fn disable_configured() {
+    let mut bits = 0u64;
+    for name in &config::get().disable_hooks {
+        match Hook::named(name.trim()) {
+            Some(h) => bits |= 1 << h as usize,
+            None => report_error("config", &format!("disable_hooks: no hook {name:?}"), None),
+        }
+    }
+    DISABLED.store(bits, Ordering::Relaxed);
+}
+
+/// This platform's hooks for `shim/hello`, split by whether they track.
+pub(crate) fn hello_params() -> Value {
+    let off = DISABLED.load(Ordering::Relaxed);
+    let (disabled, active): (Vec<Hook>, Vec<Hook>) =
+        HOOKS.into_iter().filter(|h| h.here()).partition(|&h| {
+            off & 1 << h as usize != 0
+                || PANICS[h as usize].load(Ordering::Relaxed) >= DISABLE_AFTER
+        });
+    let names = |hooks: Vec<Hook>| hooks.into_iter().map(Hook::name).collect::<Vec<_>>();
+    json!({ "active": names(active), "disabled": names(disabled) })
+}
+
+/// Panics per hook, for `shim/stats`; hooks that never panicked are left
+/// out.
+pub(crate) fn snapshot() -> Value {
+    let counts: Map<String, Value> = HOOKS
+        .iter()
+        .filter_map(|&h| {
+            let n = PANICS[h as usize].load(Ordering::Relaxed);
+            (n > 0).then(|| (h.name().to_string(), Value::from(n)))
+        })
+        .collect();
+    Value::Object(counts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_panic_after_the_real_call_keeps_its_result() {
+        let calls = Cell::new(0);
+        let original = || {
+            calls.set(calls.get() + 1);
+            -1
+        };
+        // Before the call: the original stands in.
+        assert_eq!(hook(Hook::Unlinkat, || panic!("before"), original), -1);
+        assert_eq!(calls.get(), 1);
+        // After: its result is returned and nothing is called twice.
+        let after = || {
+            ran(7);
+            panic!("after")
+        };
+        assert_eq!(hook(Hook::Unlinkat, after, original), 7);
+        assert_eq!(calls.get(), 1);
+        // Third strike: the handler isn't run again.
+        assert_eq!(hook(Hook::Unlinkat, || panic!("third"), original), -1);
+        assert_eq!(hook(Hook::Unlinkat, || 0, original), -1);
+        assert_eq!(calls.get(), 3);
+        assert_eq!(snapshot()["unlinkat"], 3);
+    }
+}
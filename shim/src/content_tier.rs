@@ -0,0 +1,53 @@
+//! Content features off for huge writes.
+//!
+//! Writing a media file or an archive takes a few calls of many megabytes
+//! each, and anything that reads or copies the payload (a pre-image, a
+//! hash, the `lseek` samples behind `write_shape`) could cost more than
+//! the write. So an fd is put in a tier once, by its first write: when
+//! that write's count plus the file's size as first `fstat`ed is over
+//! `content_features_max_bytes`, the fd is `Huge` until it closes. A huge
+//! fd's writes are counted and nothing more, and its post says
+//! `"content_features_skipped": true`. Whatever would look at what an fd
+//! writes asks `content_features` first.
+
+use crate::config;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Tier {
+    Full,
+    Huge,
+}
+
+impl Tier {
+    /// The tier for an fd whose first write asks for `count` bytes of a
+    /// file `size` bytes long (if known).
+    pub(crate) fn of(count: u64, size: Option<u64>) -> Tier {
+        Tier::under(config::get().content_features_max_bytes, count, size)
+    }
+
+    fn under(max: u64, count: u64, size: Option<u64>) -> Tier {
+        if max > 0 && count.saturating_add(size.unwrap_or(0)) > max {
+            Tier::Huge
+        } else {
+            Tier::Full
+        }
+    }
+
+    /// Whether the fd's content may be looked at.
+    pub(crate) fn content_features(self) -> bool {
+        self == Tier::Full
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_first_write_leaving_the_file_large_makes_the_fd_huge() {
+        assert_eq!(Tier::under(100, 10, Some(50)), Tier::Full);
+        assert_eq!(Tier::under(100, 101, None), Tier::Huge);
+        assert_eq!(Tier::under(100, 60, Some(50)), Tier::Huge);
+        assert_eq!(Tier::under(0, u64::MAX, None), Tier::Full);
+    }
+}
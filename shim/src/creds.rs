@@ -0,0 +1,87 @@
+//! Processes changing their own ids.
+//!
+//! An installer or a test harness that drops or takes privileges changes
+//! what its writes mean, so a successful `setuid`, `seteuid`, `setgid` or
+//! `setegid` that changed any of the real or effective ids is reported as
+//!
+//! ```json
+//! { "method": "shim/credentials_changed",
+//!   "params": { "pid": 4242, "call": "seteuid",
+//!               "old": { "uid": 0, "euid": 0, "gid": 0, "egid": 0 },
+//!               "new": { "uid": 0, "euid": 1000, "gid": 0, "egid": 0 } } }
+//! ```
+//!
+//! and from then on every preflight says `euid` and `egid`, read at the
+//! time, so the server can be stricter with privileged writes. The call
+//! itself is made as it was asked for, with its result and `errno` as it
+//! left them; before the library constructor has run, or under another
+//! hook, it is only passed through. Ids changed some other way
+//! (`setresuid`, `setreuid`, a raw syscall) aren't seen.
+
+use std::os::raw::c_int;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use serde_json::{json, Value};
+
+use crate::op_context::OpContext;
+use crate::{platform, post_notify};
+
+/// Whether this process has changed its ids since the shim was loaded.
+static CHANGED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Ids {
+    uid: libc::uid_t,
+    euid: libc::uid_t,
+    gid: libc::gid_t,
+    egid: libc::gid_t,
+}
+
+impl Ids {
+    fn now() -> Ids {
+        unsafe {
+            Ids {
+                uid: libc::getuid(),
+                euid: libc::geteuid(),
+                gid: libc::getgid(),
+                egid: libc::getegid(),
+            }
+        }
+    }
+
+    fn params(self) -> Value {
+        json!({ "uid": self.uid, "euid": self.euid, "gid": self.gid, "egid": self.egid })
+    }
+}
+
+/// Make `set`, the `call` named, and report what it changed.
+pub(crate) fn change(ctx: &OpContext, call: &str, set: impl FnOnce() -> c_int) -> c_int {
+    let old = Ids::now();
+    let rc = set();
+    let errno = std::io::Error::last_os_error().raw_os_error().unwrap_or(0);
+    let new = Ids::now();
+    if rc == 0 && new != old {
+        CHANGED.store(true, Ordering::Relaxed);
+        post_notify(
+            ctx,
+            "shim/credentials_changed",
+            json!({
+                "pid": unsafe { libc::getpid() },
+                "call": call,
+                "old": old.params(),
+                "new": new.params(),
+            }),
+        );
+    }
+    platform::set_errno(errno);
+    rc
+}
+
+/// Add the effective ids to a preflight, once they have been changed.
+pub(crate) fn tag(params: &mut Value) {
+    if CHANGED.load(Ordering::Relaxed) {
+        let ids = Ids::now();
+        params["euid"] = json!(ids.euid);
+        params["egid"] = json!(ids.egid);
+    }
+}
@@ -0,0 +1,79 @@
+//! Calls made where the shim must not lock or allocate.
+//!
+//! Some hosts write from inside their own `malloc`, or from a signal
+//! handler. A hook that takes a lock the interrupted code holds, or that
+//! allocates, deadlocks such a host. So on a thread between
+//! `nvim_claude_shim_enter_critical()` and
+//! `nvim_claude_shim_exit_critical()` (the pair nests), every hook
+//! calls the original straight away, after one relaxed counter bump: no
+//! lock, no allocation, no panic, nothing reported.
+//!
+//! The same happens without the host's help when a hook is entered on a
+//! thread already inside one: a signal handler that interrupted a hook,
+//! or a `malloc` replacement the hook's own allocation called into. A
+//! hook the shim's own I/O enters has always passed through anyway. A
+//! signal handler that interrupts the host's code instead, outside any
+//! hook, can't be told apart from the host itself, and needs the pair if
+//! the code it interrupts may hold the host's own locks.
+//!
+//! The flag is a thread-local. On macOS a thread's first use of one may
+//! allocate, so a thread that needs this should enter and exit once
+//! early on, outside any handler. `shim/stats` reports the calls let
+//! through as `"critical"`.
+
+use std::cell::Cell;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static PASSED: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    static DEPTH: Cell<u32> = const { Cell::new(0) };
+}
+
+/// Whether this thread asked for pass-through.
+#[inline]
+pub(crate) fn active() -> bool {
+    DEPTH.try_with(|d| d.get() > 0).unwrap_or(false)
+}
+
+/// A hook passed its call through untouched.
+#[inline]
+pub(crate) fn passed() {
+    PASSED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// For `shim/stats`.
+pub(crate) fn count() -> u64 {
+    PASSED.load(Ordering::Relaxed)
+}
+
+/// From here until the matching `nvim_claude_shim_exit_critical`, this
+/// thread's calls pass through the shim untouched. Async-signal-safe.
+#[no_mangle]
+pub extern "C" fn nvim_claude_shim_enter_critical() {
+    let _ = DEPTH.try_with(|d| d.set(d.get().saturating_add(1)));
+}
+
+/// Ends the innermost `nvim_claude_shim_enter_critical`; an unmatched
+/// one does nothing.
+#[no_mangle]
+pub extern "C" fn nvim_claude_shim_exit_critical() {
+    let _ = DEPTH.try_with(|d| d.set(d.get().saturating_sub(1)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn critical_sections_nest_and_tolerate_extra_exits() {
+        assert!(!active());
+        nvim_claude_shim_enter_critical();
+        nvim_claude_shim_enter_critical();
+        nvim_claude_shim_exit_critical();
+        assert!(active());
+        nvim_claude_shim_exit_critical();
+        nvim_claude_shim_exit_critical();
+        assert!(!active());
+    }
+}
@@ -0,0 +1,141 @@
+//! Debug events for runs of writes, sent as one frame.
+//!
+//! With `NVIM_CLAUDE_SHIM_DEBUG` on, every hooked call sends a
+//! `shim/*_call` frame, and a program making a million 4 KB writes would
+//! send a million of them. So a thread's successive successful write
+//! calls (`write`, `pwrite`, `writev`, `pwritev`) with the same method, fd and
+//! tracked path are held back and sent as one frame: the first call's
+//! params, its `count` and `res` replaced by the run's totals, and
+//! `"repeat"` saying how many calls it stands for.
+//!
+//! A run ends, and is sent, when that thread's next debug event is
+//! anything else (the fd's own `shim/close_call` included), or is the
+//! same call more than `WINDOW` after the run began. With no timer, a
+//! thread that goes quiet mid-run holds it until its next event. Preflights
+//! and posts never come through here.
+
+use std::cell::RefCell;
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+const WINDOW: Duration = Duration::from_millis(100);
+
+const COALESCED: [&str; 4] = [
+    "shim/write_call",
+    "shim/pwrite_call",
+    "shim/writev_call",
+    "shim/pwritev_call",
+];
+
+struct Run {
+    method: &'static str,
+    params: Value,
+    repeat: u64,
+    count: u64,
+    res: i64,
+    since: Instant,
+}
+
+impl Run {
+    fn same(&self, method: &str, params: &Value) -> bool {
+        self.method == method
+            && self.params["fd"] == params["fd"]
+            && self.params["tracked_path"] == params["tracked_path"]
+            && self.since.elapsed() < WINDOW
+    }
+
+    fn add(&mut self, params: &Value) {
+        self.repeat += 1;
+        self.count += params["count"].as_u64().unwrap_or(0);
+        self.res += params["res"].as_i64().unwrap_or(0);
+    }
+
+    fn into_event(mut self) -> (&'static str, Value) {
+        self.params["repeat"] = json!(self.repeat);
+        if self.params.get("count").is_some() {
+            self.params["count"] = json!(self.count);
+        }
+        self.params["res"] = json!(self.res);
+        (self.method, self.params)
+    }
+}
+
+thread_local! {
+    static RUN: RefCell<Option<Run>> = const { RefCell::new(None) };
+}
+
+/// What to send now that `method` happened, in order: the run it ended,
+/// if any, then the event itself unless it was held back. Sent as is
+/// while this thread's run is out of reach (in use, or torn down at
+/// thread exit).
+pub(crate) fn offer(method: &str, params: Value) -> Vec<(&str, Value)> {
+    let held = COALESCED
+        .into_iter()
+        .find(|&m| m == method)
+        .filter(|_| params["res"].as_i64().is_some_and(|r| r >= 0));
+    let mut params = Some(params);
+    let sent = RUN.try_with(|cell| {
+        let mut run = cell.try_borrow_mut().ok()?;
+        let params = params.take()?;
+        if let (Some(r), Some(_)) = (run.as_mut(), held) {
+            if r.same(method, &params) {
+                r.add(&params);
+                return Some(Vec::new());
+            }
+        }
+        let mut out: Vec<(&str, Value)> = run.take().map(Run::into_event).into_iter().collect();
+        match held {
+            Some(method) => {
+                let mut r = Run {
+                    method,
+                    params,
+                    repeat: 0,
+                    count: 0,
+                    res: 0,
+                    since: Instant::now(),
+                };
+                let first = r.params.clone();
+                r.add(&first);
+                *run = Some(r);
+            }
+            None => out.push((method, params)),
+        }
+        Some(out)
+    });
+    sent.ok()
+        .flatten()
+        .unwrap_or_else(|| params.map(|p| (method, p)).into_iter().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(fd: i32, res: i64) -> Value {
+        json!({ "fd": fd, "count": 4096, "res": res, "tracked_path": "/p/a" })
+    }
+
+    #[test]
+    fn runs_of_writes_collapse_into_one_frame() {
+        for _ in 0..3 {
+            assert!(offer("shim/write_call", write(3, 4096)).is_empty());
+        }
+        // Another fd ends the run; a failed write is never held.
+        let sent = offer("shim/write_call", write(4, 100));
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].1["repeat"], 3);
+        assert_eq!(sent[0].1["count"], 3 * 4096);
+        assert_eq!(sent[0].1["res"], 3 * 4096);
+        let sent = offer("shim/write_call", write(4, -1));
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0].1["repeat"], 1);
+        assert_eq!(sent[1].1["res"], -1);
+        assert!(sent[1].1.get("repeat").is_none());
+
+        assert!(offer("shim/write_call", write(5, 1)).is_empty());
+        let sent = offer("shim/close_call", json!({ "fd": 5, "rc": 0 }));
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[1].0, "shim/close_call");
+    }
+}
@@ -0,0 +1,311 @@
+//! Everything the server sends on a control connection besides answers.
+//!
+//! The connection is read only while the shim is using it anyway: while
+//! a call waits for its response, and (without blocking) before anything
+//! else is sent. Frames are sorted the JSON-RPC way:
+//!
+//! - `id` and `method`: a server request, answered inline;
+//! - `method` only: a server notification, dispatched and not answered;
+//! - `id` only: a response, handed to the call waiting for it, or to
+//!   `async_pre` for preflights nobody waits for. Late answers to calls
+//!   that already timed out are dropped.
+//!
+//! Requests for methods not in `HANDLERS` get JSON-RPC's "method not
+//! found"; unknown notifications (Neovim has plenty of its own) are ignored.
+
+use std::path::{Path, PathBuf};
+
+use serde_json::{json, Value};
+
+use crate::{
+    absolute, allow_cache, async_pre, breaker, budget, classify, config, conflicts, contain,
+    critical, denials, dir_cache, encode_response, flush_now, glob, heap, ignore_stats, log_debug,
+    own_fds, paths, project, rate_limit, rearm_preflights, reliable, self_paths, settle_async,
+    summary, Conn, SUPERVISED,
+};
+
+#[derive(Debug)]
+pub(crate) enum Incoming {
+    Request {
+        id: Value,
+        method: String,
+        params: Value,
+    },
+    Notification {
+        method: String,
+        params: Value,
+    },
+    Response {
+        id: Value,
+        result: Result<Value, Value>,
+    },
+}
+
+impl Incoming {
+    /// Sort a JSON-RPC frame; one with neither `id` nor `method` is not RPC.
+    pub(crate) fn from_json(mut v: Value) -> Option<Incoming> {
+        let mut take = |k: &str| v.get_mut(k).map(Value::take).filter(|x| !x.is_null());
+        let id = take("id");
+        let method = match take("method") {
+            Some(Value::String(m)) => Some(m),
+            Some(_) => return None,
+            None => None,
+        };
+        let params = take("params").unwrap_or(Value::Null);
+        Some(match (id, method) {
+            (Some(id), Some(method)) => Incoming::Request { id, method, params },
+            (None, Some(method)) => Incoming::Notification { method, params },
+            (Some(id), None) => Incoming::Response {
+                id,
+                result: match take("error") {
+                    Some(e) => Err(e),
+                    None => Ok(take("result").unwrap_or(Value::Null)),
+                },
+            },
+            (None, None) => return None,
+        })
+    }
+}
+
+type Handler = fn(&mut Conn, &Value) -> Result<Value, Value>;
+
+/// What the server can ask for, by request or notification alike.
+const HANDLERS: &[(&str, Handler)] = &[
+    ("shim/ack", ack),
+    ("shim/classify", classify),
+    ("shim/conflict_paths", conflict_paths),
+    ("shim/flush", flush),
+    ("shim/ignore_audit", ignore_audit),
+    ("shim/invalidate", invalidate),
+    ("shim/invalidate_cache", invalidate_cache),
+    ("shim/query_path", query_path),
+    ("shim/reload_config", reload_config),
+    ("shim/self_paths", register_self_paths),
+    ("shim/stats", stats),
+];
+
+/// Run the handler for a server request or notification; a request's
+/// answer goes back on `conn`. Responses nobody is waiting for end here.
+pub(crate) fn dispatch(conn: &mut Conn, frame: Incoming) {
+    let (id, method, params) = match frame {
+        Incoming::Request { id, method, params } => (Some(id), method, params),
+        Incoming::Notification { method, params } => (None, method, params),
+        Incoming::Response { id, result } => {
+            if let Ok(r) = &result {
+                self_paths::from_result(r);
+            }
+            if let Some(answer) = async_pre::answered(&id, result) {
+                settle_async(answer);
+            }
+            return;
+        }
+    };
+    let handler = HANDLERS.iter().find(|(m, _)| *m == method).map(|&(_, h)| h);
+    log_debug(&format!(
+        "shim: server {} {method}{}\n",
+        if id.is_some() {
+            "request"
+        } else {
+            "notification"
+        },
+        if handler.is_some() {
+            ""
+        } else {
+            " (unhandled)"
+        },
+    ));
+    let result = match handler {
+        Some(h) => h(conn, &params),
+        None => Err(json!({ "code": -32601, "message": format!("method not found: {method}") })),
+    };
+    if let Some(payload) = id.and_then(|id| encode_response(&id, result)) {
+        let _ = conn.send(&payload);
+    }
+}
+
+fn flush(conn: &mut Conn, _params: &Value) -> Result<Value, Value> {
+    let flushed = flush_now(conn);
+    if let Some(params) = summary::params() {
+        conn.notify("shim/summary", params);
+    }
+    Ok(json!({ "flushed": flushed }))
+}
+
+fn invalidate_cache(_conn: &mut Conn, _params: &Value) -> Result<Value, Value> {
+    Ok(json!({ "dropped": allow_cache::clear() + dir_cache::clear() }))
+}
+
+/// `{"path": ...}`: that path's summary entry so far, or `null`.
+fn query_path(_conn: &mut Conn, params: &Value) -> Result<Value, Value> {
+    let Some(path) = params["path"].as_str() else {
+        return Err(json!({ "code": -32602, "message": "shim/query_path wants `path`" }));
+    };
+    Ok(summary::query(path).unwrap_or(Value::Null))
+}
+
+/// `{"path": ..., "op": ...}`: what a preflight for `op` on `path` would
+/// come to, decided the way one would be; see `classify`.
+fn classify(_conn: &mut Conn, params: &Value) -> Result<Value, Value> {
+    let Some(path) = params["path"].as_str() else {
+        return Err(json!({ "code": -32602, "message": "shim/classify wants `path`" }));
+    };
+    let op = params["op"].as_str().unwrap_or("pre_modify");
+    let path = absolute(PathBuf::from(path));
+    Ok(classify::describe(op, &path, config::get(), *SUPERVISED))
+}
+
+/// Read the config files and environment again, for a project file
+/// that changed under a long-lived process. Parse errors go out as
+/// `shim/config_error` first.
+fn reload_config(conn: &mut Conn, _params: &Value) -> Result<Value, Value> {
+    config::reload();
+    let errors = project::take_errors();
+    let count = errors.len();
+    for params in errors {
+        conn.notify("shim/config_error", params);
+    }
+    Ok(json!({
+        "project": project::file().map(|f| f.to_string_lossy().into_owned()),
+        "errors": count,
+    }))
+}
+
+/// Revoke earlier allows for some paths: cached ones are dropped, and
+/// open fds on those paths preflight again at their next write. The
+/// counts also go out as a `shim/invalidated` notification, since
+/// `shim/invalidate` is usually sent as one.
+fn invalidate(conn: &mut Conn, params: &Value) -> Result<Value, Value> {
+    let Some(select) = Selector::from_params(params) else {
+        return Err(json!({
+            "code": -32602,
+            "message": "shim/invalidate wants `paths`, `glob` or `all: true`",
+        }));
+    };
+    let counts = json!({
+        "evicted": allow_cache::evict(|p| select.matches(p))
+            + dir_cache::evict(|p| select.matches(p)),
+        "rearmed": rearm_preflights(|p| select.matches(p)),
+    });
+    conn.notify("shim/invalidated", counts.clone());
+    Ok(counts)
+}
+
+/// `{"paths": [...]}`: the files open in modified buffers, replacing the
+/// last set.
+fn conflict_paths(_conn: &mut Conn, params: &Value) -> Result<Value, Value> {
+    let Some(list) = params["paths"].as_array() else {
+        return Err(json!({ "code": -32602, "message": "shim/conflict_paths wants `paths`" }));
+    };
+    Ok(json!({ "paths": conflicts::replace(list) }))
+}
+
+/// `{"paths": [...], "globs": [...], "replace": bool}`: the server's own
+/// files, to be kept out of every event.
+fn register_self_paths(_conn: &mut Conn, params: &Value) -> Result<Value, Value> {
+    Ok(self_paths::apply(params))
+}
+
+fn stats(_conn: &mut Conn, _params: &Value) -> Result<Value, Value> {
+    Ok(json!({
+        "ignored": ignore_stats::snapshot(),
+        "blocking": budget::snapshot(),
+        "panics": contain::snapshot(),
+        "fallback_denials": denials::snapshot(),
+        "rate_limited": rate_limit::snapshot(),
+        "heap": heap::snapshot(),
+        "send": breaker::snapshot(),
+        "critical": critical::count(),
+        "fds": own_fds::snapshot(),
+    }))
+}
+
+/// `{"enabled": bool}`: report ignored paths as `shim/ignored` or stop.
+fn ignore_audit(_conn: &mut Conn, params: &Value) -> Result<Value, Value> {
+    let Some(on) = params["enabled"].as_bool() else {
+        return Err(json!({ "code": -32602, "message": "shim/ignore_audit wants `enabled`" }));
+    };
+    ignore_stats::set_audit(on);
+    Ok(json!({ "audit": on }))
+}
+
+/// The server has every notification numbered `upto` or lower.
+fn ack(_conn: &mut Conn, params: &Value) -> Result<Value, Value> {
+    let Some(upto) = params["upto"].as_u64() else {
+        return Err(json!({ "code": -32602, "message": "shim/ack wants `upto`" }));
+    };
+    Ok(json!({ "unacked": reliable::ack(upto) }))
+}
+
+/// Which paths a `shim/invalidate` covers, compared the way ignore globs
+/// are (normalized, and case-folded if configured).
+enum Selector {
+    All,
+    Paths(Vec<PathBuf>),
+    Glob(String),
+}
+
+impl Selector {
+    fn from_params(params: &Value) -> Option<Selector> {
+        let normalize = config::get().normalize_unicode;
+        if params["all"] == true {
+            Some(Selector::All)
+        } else if let Some(list) = params["paths"].as_array() {
+            let paths = list.iter().filter_map(Value::as_str);
+            Some(Selector::Paths(
+                paths
+                    .map(|p| paths::for_matching(Path::new(p), normalize).into_owned())
+                    .collect(),
+            ))
+        } else {
+            params["glob"]
+                .as_str()
+                .map(|g| Selector::Glob(g.to_string()))
+        }
+    }
+
+    fn matches(&self, path: &Path) -> bool {
+        let cfg = config::get();
+        let path = paths::for_matching(path, cfg.normalize_unicode);
+        let fold = cfg.case_insensitive;
+        match self {
+            Selector::All => true,
+            Selector::Paths(list) => list.iter().any(|p| glob::same_path(&path, p, fold)),
+            Selector::Glob(g) => glob::matches(g, &path, fold),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_are_sorted_by_id_and_method() {
+        let req = Incoming::from_json(json!({ "id": 9, "method": "shim/flush" }));
+        assert!(matches!(req, Some(Incoming::Request { id, .. }) if id == 9));
+        let note = Incoming::from_json(json!({ "method": "shim/flush", "id": null }));
+        assert!(matches!(note, Some(Incoming::Notification { .. })));
+        let ok = Incoming::from_json(json!({ "id": 3, "result": { "allow": true } }));
+        assert!(matches!(ok, Some(Incoming::Response { result: Ok(r), .. }) if r["allow"] == true));
+        let err = Incoming::from_json(json!({ "id": 3, "error": { "code": 1 } }));
+        assert!(matches!(
+            err,
+            Some(Incoming::Response { result: Err(_), .. })
+        ));
+        assert!(Incoming::from_json(json!({ "jsonrpc": "2.0" })).is_none());
+        assert!(Incoming::from_json(json!({ "id": 1, "method": 5 })).is_none());
+    }
+
+    #[test]
+    fn invalidate_selects_by_paths_glob_or_all() {
+        let a = Path::new("/p/a.rs");
+        let paths = Selector::from_params(&json!({ "paths": ["/p/a.rs"] })).unwrap();
+        assert!(paths.matches(a) && !paths.matches(Path::new("/p/b.rs")));
+        let glob = Selector::from_params(&json!({ "glob": "*.rs" })).unwrap();
+        assert!(glob.matches(a) && !glob.matches(Path::new("/p/a.txt")));
+        assert!(Selector::from_params(&json!({ "all": true }))
+            .unwrap()
+            .matches(a));
+        assert!(Selector::from_params(&json!({ "all": false })).is_none());
+    }
+}
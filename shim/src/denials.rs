@@ -0,0 +1,162 @@
+//! A trail for denials nobody decided.
+//!
+//! Failing closed, an operation is denied whenever no answer comes: the
+//! server is unreachable, it timed out, the blocking budget is spent, the
+//! path is over its `preflight_rate` with no earlier answer, or there is
+//! no server configured at all (`no_server`).
+//! From inside the traced program that is just `EPERM` with no cause. So
+//! each such denial is appended as one line to `denial_log`:
+//!
+//! ```text
+//! 2026-10-14T09:30:12Z pid=4242 op=pre_modify path=/p/a.rs reason=transport_down
+//! ```
+//!
+//! The last `KEPT` are also held in memory for `shim/stats`. A process
+//! that had any prints a one-line summary on stderr at exit, naming the
+//! log. Denials the server answered are its own business and aren't
+//! recorded here.
+
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+
+use crate::{config, escape, internal_io, platform};
+
+const KEPT: usize = 50;
+
+/// Why nothing answered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Reason {
+    /// No connection, a send that failed, or no answer in time.
+    TransportDown,
+    /// Over `block_budget_ms`, so not asked.
+    BudgetExhausted,
+    /// Over the path's `preflight_rate` with no earlier answer to go by.
+    RateLimited,
+    /// No destination to ask at all.
+    NoServer,
+}
+
+impl Reason {
+    fn name(self) -> &'static str {
+        match self {
+            Reason::TransportDown => "transport_down",
+            Reason::BudgetExhausted => "budget_exhausted",
+            Reason::RateLimited => "rate_limited",
+            Reason::NoServer => "no_server",
+        }
+    }
+}
+
+struct Denial {
+    at: u64,
+    op: String,
+    path: String,
+    reason: Reason,
+}
+
+static RECENT: Mutex<VecDeque<Denial>> = parking_lot::const_mutex(VecDeque::new());
+static TOTAL: AtomicU64 = AtomicU64::new(0);
+
+/// `op` on `path` was just denied for `reason`.
+pub(crate) fn record(op: &str, path: &Path, reason: Reason) {
+    static AT_EXIT: std::sync::Once = std::sync::Once::new();
+    AT_EXIT.call_once(|| unsafe {
+        libc::atexit(summary_at_exit);
+    });
+    let at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let d = Denial {
+        at,
+        op: op.to_string(),
+        path: path.to_string_lossy().into_owned(),
+        reason,
+    };
+    TOTAL.fetch_add(1, Ordering::Relaxed);
+    let log = &config::get().denial_log;
+    if !log.as_os_str().is_empty() {
+        if let Some(dir) = log.parent() {
+            let _ = std::fs::create_dir_all(dir);
+        }
+        let _ = internal_io::append(log, line(&d).as_bytes());
+    }
+    let mut recent = RECENT.lock();
+    if recent.len() >= KEPT {
+        recent.pop_front();
+    }
+    recent.push_back(d);
+}
+
+fn line(d: &Denial) -> String {
+    format!(
+        "{} pid={} op={} path={} reason={}\n",
+        utc(d.at),
+        unsafe { libc::getpid() },
+        d.op,
+        escape::printable(&d.path),
+        d.reason.name()
+    )
+}
+
+/// `secs` since the epoch as `YYYY-MM-DDTHH:MM:SSZ`.
+fn utc(secs: u64) -> String {
+    let (days, rem) = (secs / 86_400, secs % 86_400);
+    // Howard Hinnant's `civil_from_days`, for days since 1970-01-01.
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60
+    )
+}
+
+/// The most recent denials, oldest first, for `shim/stats`.
+pub(crate) fn snapshot() -> Value {
+    let recent: Vec<Value> = RECENT
+        .lock()
+        .iter()
+        .map(|d| json!({ "at": d.at, "op": d.op, "path": d.path, "reason": d.reason.name() }))
+        .collect();
+    json!({ "total": TOTAL.load(Ordering::Relaxed), "recent": recent })
+}
+
+extern "C" fn summary_at_exit() {
+    let n = TOTAL.load(Ordering::Relaxed);
+    let s = if n == 1 { "" } else { "s" };
+    let log = &config::get().denial_log;
+    let see = if log.as_os_str().is_empty() {
+        String::new()
+    } else {
+        format!("; see {}", escape::printable(&log.to_string_lossy()))
+    };
+    platform::stderr_write(
+        format!("nvim-claude shim: denied {n} operation{s} with no answer from the server{see}\n")
+            .as_bytes(),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamps_are_utc_calendar_dates() {
+        assert_eq!(utc(0), "1970-01-01T00:00:00Z");
+        assert_eq!(utc(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(utc(1_791_970_212), "2026-10-14T09:30:12Z");
+    }
+}
@@ -0,0 +1,198 @@
+//! Allows granted for a whole directory.
+//!
+//! Scaffolding a feature creates a dozen files in one new directory, and
+//! asking about each one is a dozen prompts. So the server may answer a
+//! `pre_create` or `pre_modify` with
+//!
+//! ```json
+//! { "allow": true, "scope": "dir", "ttl_ms": 60000 }
+//! ```
+//!
+//! and for `ttl_ms` the same operation on any other file directly in that
+//! directory is allowed without asking; `"scope": "tree"` covers its
+//! subdirectories too. Posts for those say `"allowed_by": "dir_cache"`.
+//! Without `ttl_ms` the answer is a plain allow.
+//!
+//! Directories are keyed by their canonical path (case-folded under
+//! `case_insensitive`), so a symlink or `..` in the way doesn't miss. A
+//! grant is forgotten when this process deletes or renames the directory
+//! or one above it, and is only honoured while the path still names the
+//! directory it was granted for (same dev and inode), which catches
+//! other processes doing the same. `shim/invalidate_cache` drops every
+//! grant, and `shim/invalidate` those whose directory it selects. Only
+//! answers to blocking preflights grant anything.
+
+use std::collections::HashMap;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde_json::Value;
+
+use crate::config;
+
+/// Grants kept before expired ones are swept (and, if that isn't enough,
+/// everything is dropped).
+const CAP: usize = 1024;
+
+/// What an allow's `scope` and `ttl_ms` ask for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Scope {
+    tree: bool,
+    ttl: Duration,
+}
+
+impl Scope {
+    /// The scope an answer grants, if any.
+    pub(crate) fn of(result: &Value) -> Option<Scope> {
+        let tree = match result.get("scope")?.as_str()? {
+            "dir" => false,
+            "tree" => true,
+            _ => return None,
+        };
+        let ttl = Duration::from_millis(result.get("ttl_ms")?.as_u64()?);
+        Some(Scope { tree, ttl })
+    }
+}
+
+struct Grant {
+    tree: bool,
+    until: Instant,
+    dev: u64,
+    ino: u64,
+}
+
+static GRANTS: Lazy<Mutex<HashMap<(String, PathBuf), Grant>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+/// Whether any grant may be live, so the common case takes no lock and
+/// makes no syscall.
+static ANY: AtomicBool = AtomicBool::new(false);
+
+fn fold(path: PathBuf) -> PathBuf {
+    if config::get().case_insensitive {
+        PathBuf::from(path.to_string_lossy().to_lowercase())
+    } else {
+        path
+    }
+}
+
+/// The canonical directory `path` is in.
+fn dir_of(path: &Path) -> Option<PathBuf> {
+    std::fs::canonicalize(path.parent()?).ok().map(fold)
+}
+
+/// Whether a grant covers `op` on `path`.
+pub(crate) fn hit(op: &str, path: &Path) -> bool {
+    if !ANY.load(Ordering::Relaxed) {
+        return false;
+    }
+    let Some(dir) = dir_of(path) else {
+        return false;
+    };
+    let now = Instant::now();
+    let found = {
+        let grants = GRANTS.lock();
+        dir.ancestors().enumerate().find_map(|(depth, d)| {
+            let g = grants.get(&(op.to_string(), d.to_path_buf()))?;
+            ((depth == 0 || g.tree) && g.until > now).then(|| (d.to_path_buf(), g.dev, g.ino))
+        })
+    };
+    found.is_some_and(|(d, dev, ino)| {
+        std::fs::metadata(d).is_ok_and(|m| m.dev() == dev && m.ino() == ino)
+    })
+}
+
+/// Remember that the server allowed `op` for `path`'s whole directory.
+pub(crate) fn grant(op: &str, path: &Path, scope: Scope) {
+    if !matches!(op, "pre_create" | "pre_modify") {
+        return;
+    }
+    let Some(dir) = dir_of(path) else {
+        return;
+    };
+    let Ok(m) = std::fs::metadata(&dir) else {
+        return;
+    };
+    let now = Instant::now();
+    let mut grants = GRANTS.lock();
+    if grants.len() >= CAP {
+        grants.retain(|_, g| g.until > now);
+        if grants.len() >= CAP {
+            grants.clear();
+        }
+    }
+    let grant = Grant {
+        tree: scope.tree,
+        until: now + scope.ttl,
+        dev: m.dev(),
+        ino: m.ino(),
+    };
+    grants.insert((op.to_string(), dir), grant);
+    ANY.store(true, Ordering::Relaxed);
+}
+
+/// `path` was deleted or renamed away: forget grants for it and anything
+/// under it.
+pub(crate) fn forget(path: &Path) {
+    if !ANY.load(Ordering::Relaxed) {
+        return;
+    }
+    let (Some(dir), Some(name)) = (dir_of(path), path.file_name()) else {
+        return;
+    };
+    let gone = fold(dir.join(name));
+    evict(|d| d.starts_with(&gone));
+}
+
+/// Forget the grants for directories `select` picks; returns how many.
+pub(crate) fn evict(mut select: impl FnMut(&Path) -> bool) -> usize {
+    let mut grants = GRANTS.lock();
+    let before = grants.len();
+    grants.retain(|(_, d), _| !select(d));
+    ANY.store(!grants.is_empty(), Ordering::Relaxed);
+    before - grants.len()
+}
+
+/// Forget every grant; returns how many there were.
+pub(crate) fn clear() -> usize {
+    evict(|_| true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn a_dir_grant_covers_its_children_until_the_dir_goes() {
+        let root = std::env::temp_dir().join(format!("dir-cache-{}", std::process::id()));
+        let sub = root.join("sub");
+        std::fs::create_dir_all(&sub).unwrap();
+        let dir = Scope::of(&json!({ "allow": true, "scope": "dir", "ttl_ms": 60_000 })).unwrap();
+        assert_eq!(Scope::of(&json!({ "allow": true, "scope": "dir" })), None);
+
+        grant("pre_create", &root.join("a.rs"), dir);
+        assert!(hit("pre_create", &root.join("b.rs")));
+        assert!(hit("pre_create", &root.join("sub/../c.rs")));
+        assert!(!hit("pre_modify", &root.join("b.rs")));
+        assert!(!hit("pre_create", &sub.join("d.rs")));
+
+        let tree = Scope { tree: true, ..dir };
+        grant("pre_modify", &root.join("a.rs"), tree);
+        assert!(hit("pre_modify", &sub.join("d.rs")));
+
+        // Replaced behind our back: another directory at the same path.
+        grant("pre_create", &sub.join("e.rs"), dir);
+        std::fs::rename(&sub, root.join("old")).unwrap();
+        std::fs::create_dir(&sub).unwrap();
+        assert!(!hit("pre_create", &sub.join("f.rs")));
+
+        forget(&root);
+        assert!(!hit("pre_create", &root.join("b.rs")));
+        assert!(!hit("pre_modify", &sub.join("d.rs")));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
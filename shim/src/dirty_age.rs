@@ -0,0 +1,78 @@
+//! Posts for fds that never close.
+//!
+//! A daemon can hold a file open and dirty for the whole session (a
+//! SQLite WAL, a log it never syncs), and its changes were only reported
+//! at close, which is often exit. With `max_dirty_age_ms` set, an fd
+//! that has been dirty longer is reported as `shim/flush` would report
+//! it: its `post_modify` (or `post_create`) goes out with
+//! `"reason": "age_flush"`, its counts start afresh, and it stays tracked,
+//! so the next write doesn't preflight again.
+//!
+//! There is no timer thread. `DUE` holds the coarse monotonic time at
+//! which the oldest dirty fd comes of age, and the write, `open` and
+//! `close` hooks look at it before their call. While no fd is dirty (or
+//! the option is 0) that is one relaxed load; while some are, a read of
+//! the coarse clock too, which on Linux is the vDSO's and no syscall. A
+//! process that stops making calls is reported at exit, as before.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{config, platform};
+
+const NEVER: u64 = u64::MAX;
+
+/// When the oldest dirty fd comes of age, in `platform::coarse_ms`.
+/// Only ever early: a check that finds nothing of age sets it again.
+static DUE: AtomicU64 = AtomicU64::new(NEVER);
+
+fn max_age() -> u64 {
+    config::get().max_dirty_age_ms
+}
+
+/// An fd just went dirty: when it did, or `None` with no age limit.
+pub(crate) fn arm() -> Option<u64> {
+    let age = max_age();
+    if age == 0 {
+        return None;
+    }
+    let now = platform::coarse_ms();
+    DUE.fetch_min(now.saturating_add(age), Ordering::Relaxed);
+    Some(now)
+}
+
+/// The time now, if some fd may have come of age by it.
+#[inline]
+pub(crate) fn due() -> Option<u64> {
+    let due = DUE.load(Ordering::Relaxed);
+    if due == NEVER {
+        return None;
+    }
+    let now = platform::coarse_ms();
+    (now >= due).then_some(now)
+}
+
+/// Whether an fd dirty since `since` is of age at `now`.
+pub(crate) fn aged(since: Option<u64>, now: u64) -> bool {
+    since.is_some_and(|t| now.saturating_sub(t) >= max_age())
+}
+
+/// After a flush, with `oldest` when the longest-dirty fd left went dirty.
+pub(crate) fn rearm(oldest: Option<u64>) {
+    let due = oldest.map_or(NEVER, |t| t.saturating_add(max_age()));
+    DUE.store(due, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_flush_sets_the_next_check_by_the_oldest_fd_left() {
+        rearm(Some(0));
+        assert!(due().is_some());
+        rearm(Some(u64::MAX - 1));
+        assert_eq!(due(), None);
+        rearm(None);
+        assert_eq!(DUE.load(Ordering::Relaxed), NEVER);
+    }
+}
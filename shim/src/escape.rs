@@ -0,0 +1,62 @@
+//! Control characters in text the shim writes out for people.
+//!
+//! A file name can hold anything but `/` and NUL. On the wire that is
+//! harmless: serde_json escapes control characters in strings, msgpack
+//! and length-prefixed frames carry lengths, and `Framing::encode` refuses
+//! a newline-delimited payload with a newline in it. The denial log and
+//! stderr have no such protection, so a `\n` in a name would forge a log
+//! line and an ANSI escape would repaint the user's terminal. Both go
+//! through `printable` first.
+
+use std::borrow::Cow;
+use std::fmt::Write;
+
+/// `s` with C0 and C1 controls and DEL escaped (`\n`, `\r`, `\t`, `\x1b`,
+/// `\u{85}`), and backslashes doubled so an escape can't be forged.
+pub(crate) fn printable(s: &str) -> Cow<'_, str> {
+    if !s.chars().any(|c| c == '\\' || c.is_control()) {
+        return Cow::Borrowed(s);
+    }
+    let mut out = String::with_capacity(s.len() + 8);
+    for c in s.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x80 && c.is_control() => {
+                let _ = write!(out, "\\x{:02x}", c as u32);
+            }
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{{{:x}}}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    Cow::Owned(out)
+}
+
+/// A message for stderr: escaped throughout, but the newline that ends it
+/// is kept.
+pub(crate) fn line(msg: &str) -> String {
+    match msg.strip_suffix('\n') {
+        Some(body) => format!("{}\n", printable(body)),
+        None => printable(msg).into_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn controls_are_escaped_and_the_rest_left_alone() {
+        assert!(matches!(printable("/p/é.rs"), Cow::Borrowed(_)));
+        assert_eq!(
+            printable("a\nb\r\tc\x1b[2J\x7f\u{85}"),
+            "a\\nb\\r\\tc\\x1b[2J\\x7f\\u{85}"
+        );
+        assert_eq!(printable("x\\ny"), "x\\\\ny");
+        assert_eq!(line("shim: bad\nname\n"), "shim: bad\\nname\n");
+    }
+}
@@ -0,0 +1,438 @@
+//! Tracked fds a new image goes on writing.
+//!
+//! A process that `execve`s keeps its fds but not the shim's state for
+//! them, so the new image would ask again about a file the old one was
+//! allowed to write, and post only its own writes, while the old image's
+//! were never posted at all. So when the new image loads this library too
+//! (its environment preloads a library of this one's file name), the
+//! state of each tracked fd it will inherit (not `FD_CLOEXEC`) goes along
+//! in `ENV`: the fd's number, `dev` and `ino`, how its preflight went, its
+//! path, and the writes not yet posted. The new image's library
+//! constructor takes `ENV` out of the environment and starts each fd that
+//! `fstat` still finds on the same `dev` and `ino` where the old image
+//! left off: not asked about again, and one post at the close for both
+//! images' writes. Other entries are dropped, and those fds are tracked as
+//! any other unknown fd would be. Dirty fds whose state doesn't go along
+//! (closed on exec, or a new image without the shim) are posted just
+//! before the call, with `"reason": "exec"`.
+//!
+//! An fd with a truncate not yet posted, or a preflight not yet answered,
+//! stays behind. `posix_spawn` and `posix_spawnp` hand a child the same, without the
+//! writes: the parent still has its fds and posts its own. A spawn's file
+//! actions may move or close fds; the `fstat` check drops those.
+//!
+//! The value is `1`, `e` (an exec) or `s` (a spawn), and the pid that
+//! made it, then one `;`-separated entry per fd; an exec's is taken by
+//! that same pid, a spawn's by its children. Each path is stored as what
+//! it adds to the one before (most are neighbours), escaped. At most
+//! `FDS` entries and `MAX_BYTES` go along; the rest stay behind. Locks
+//! are only tried, and a table held elsewhere (a `vfork` child's parent)
+//! sends nothing.
+
+use std::ffi::{CStr, CString, OsStr};
+use std::os::raw::c_char;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::prelude::RawFd;
+use std::path::PathBuf;
+
+use once_cell::sync::Lazy;
+
+use crate::fdtable::{fd_stat, FdState, WriteShape, FD_TABLE};
+use crate::{inodes, AllowedBy, Decision};
+
+pub(crate) const ENV: &str = "NVIM_CLAUDE_SHIM_FDS";
+const FDS: usize = 64;
+const MAX_BYTES: usize = 16 << 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Mode {
+    Exec,
+    Spawn,
+}
+
+/// A new image's environment with `ENV` set, for as long as the call.
+pub(crate) struct Envp {
+    _var: CString,
+    ptrs: Vec<*const c_char>,
+    /// The fds whose state went along.
+    pub(crate) fds: Vec<RawFd>,
+}
+
+impl Envp {
+    pub(crate) fn as_ptr(&self) -> *const *const c_char {
+        self.ptrs.as_ptr()
+    }
+}
+
+/// The variables in `envp`, a null-terminated array.
+unsafe fn vars<'a>(envp: *const *const c_char) -> impl Iterator<Item = &'a CStr> {
+    let mut at = envp;
+    std::iter::from_fn(move || {
+        if at.is_null() || unsafe { (*at).is_null() } {
+            return None;
+        }
+        let var = unsafe { CStr::from_ptr(*at) };
+        at = unsafe { at.add(1) };
+        Some(var)
+    })
+}
+
+/// This library's file name, as the loader found it.
+static LIBRARY: Lazy<Option<Vec<u8>>> = Lazy::new(|| {
+    let mut info: libc::Dl_info = unsafe { std::mem::zeroed() };
+    let here = carry as *const libc::c_void;
+    if unsafe { libc::dladdr(here, &mut info) } == 0 || info.dli_fname.is_null() {
+        return None;
+    }
+    let path = unsafe { CStr::from_ptr(info.dli_fname) }.to_bytes();
+    Some(path.rsplit(|&b| b == b'/').next()?.to_vec())
+});
+
+/// Whether `envp` preloads this library.
+unsafe fn preloads_shim(envp: *const *const c_char) -> bool {
+    let Some(name) = LIBRARY.as_deref() else {
+        return false;
+    };
+    let var = if cfg!(target_os = "macos") {
+        &b"DYLD_INSERT_LIBRARIES="[..]
+    } else {
+        &b"LD_PRELOAD="[..]
+    };
+    unsafe { vars(envp) }.any(|v| {
+        v.to_bytes().strip_prefix(var).is_some_and(|libs| {
+            libs.split(|&b| b == b':' || b == b' ')
+                .any(|lib| lib.rsplit(|&b| b == b'/').next() == Some(name))
+        })
+    })
+}
+
+fn escape(bytes: &[u8], out: &mut String) {
+    for &b in bytes {
+        match b {
+            b'%' | b';' | b',' => out.push_str(&format!("%{b:02X}")),
+            0x21..=0x7e => out.push(b as char),
+            _ => out.push_str(&format!("%{b:02X}")),
+        }
+    }
+}
+
+fn unescape(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            out.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            out.push(b);
+        }
+    }
+    Some(out)
+}
+
+fn shape_code(shape: Option<WriteShape>) -> &'static str {
+    match shape {
+        Some(WriteShape::Append) => "a",
+        Some(WriteShape::Overwrite) => "o",
+        Some(WriteShape::Mixed) => "m",
+        None => "",
+    }
+}
+
+fn opt<T: ToString>(v: Option<T>) -> String {
+    v.map_or(String::new(), |v| v.to_string())
+}
+
+/// One fd's entry; `prev` is the path of the entry before it.
+fn entry(fd: RawFd, s: &FdState, path: &[u8], prev: &[u8], mode: Mode) -> String {
+    let shared = path.iter().zip(prev).take_while(|(a, b)| a == b).count();
+    let exec = mode == Mode::Exec;
+    let mut flags = String::from("a");
+    for (on, c) in [
+        (exec && s.dirty, 'd'),
+        (exec && s.created, 'c'),
+        (s.append, 'p'),
+        (s.notify_only, 'n'),
+        (s.self_created, 's'),
+    ] {
+        if on {
+            flags.push(c);
+        }
+    }
+    let (bytes, requested) = if exec { (s.bytes, s.requested) } else { (0, 0) };
+    let mut out = format!(
+        "{fd},{},{},{flags},{bytes},{requested},{},{},{},{},{},{},{},{},{shared},",
+        s.dev,
+        s.ino,
+        opt(s.size_before.filter(|_| exec)),
+        s.perm,
+        opt(s.open_flags),
+        opt(s.open_mode),
+        opt(s.opened_ms),
+        if exec { shape_code(s.shape) } else { "" },
+        s.decision.by.map_or("", AllowedBy::name),
+        s.decision.blocked.as_millis(),
+    );
+    escape(&path[shared..], &mut out);
+    out
+}
+
+/// `envp` with `ENV` naming the tracked fds a new image started from it
+/// inherits, when it will load the shim and there are any.
+pub(crate) unsafe fn carry(envp: *const *const c_char, mode: Mode) -> Option<Envp> {
+    let t = FD_TABLE.try_lock()?;
+    if t.is_empty() || !unsafe { preloads_shim(envp) } {
+        return None;
+    }
+    let mut value = format!(
+        "1{}{}",
+        if mode == Mode::Exec { 'e' } else { 's' },
+        unsafe { libc::getpid() }
+    );
+    let (mut fds, mut prev) = (Vec::new(), Vec::new());
+    for (&fd, s) in t.iter() {
+        let Some(path) = s.path.as_deref() else {
+            continue;
+        };
+        if !s.pre_sent || s.ignored || s.denied || s.asked.is_some() || s.truncated.is_some() {
+            continue;
+        }
+        let fl = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+        if fl < 0 || fl & libc::FD_CLOEXEC != 0 {
+            continue;
+        }
+        let path = path.as_os_str().as_bytes();
+        let e = entry(fd, s, path, &prev, mode);
+        if fds.len() == FDS || value.len() + e.len() + 1 > MAX_BYTES {
+            break;
+        }
+        value.push(';');
+        value.push_str(&e);
+        fds.push(fd);
+        prev = path.to_vec();
+    }
+    drop(t);
+    if fds.is_empty() {
+        return None;
+    }
+    let var = CString::new(format!("{ENV}={value}")).ok()?;
+    let prefix = format!("{ENV}=");
+    let mut ptrs: Vec<*const c_char> = unsafe { vars(envp) }
+        .filter(|v| !v.to_bytes().starts_with(prefix.as_bytes()))
+        .map(CStr::as_ptr)
+        .collect();
+    ptrs.push(var.as_ptr());
+    ptrs.push(std::ptr::null());
+    Some(Envp {
+        _var: var,
+        ptrs,
+        fds,
+    })
+}
+
+/// The entries in `value` meant for process `pid` (child of `ppid`).
+fn parse(value: &str, pid: i32, ppid: i32) -> Vec<(RawFd, FdState)> {
+    let mut entries = value.split(';');
+    let Some(head) = entries.next().and_then(|h| h.strip_prefix('1')) else {
+        return Vec::new();
+    };
+    let from = head.get(1..).and_then(|p| p.parse::<i32>().ok());
+    let ours = match head.as_bytes().first() {
+        Some(b'e') => from == Some(pid),
+        Some(b's') => from == Some(ppid),
+        _ => false,
+    };
+    if !ours {
+        return Vec::new();
+    }
+    let mut prev: Vec<u8> = Vec::new();
+    let mut out = Vec::new();
+    for e in entries.take(FDS) {
+        let Some((fd, s, path)) = parse_entry(e, &prev) else {
+            continue;
+        };
+        prev.clone_from(&path);
+        out.push((
+            fd,
+            FdState {
+                path: Some(PathBuf::from(OsStr::from_bytes(&path))),
+                ..s
+            },
+        ));
+    }
+    out
+}
+
+fn parse_entry(e: &str, prev: &[u8]) -> Option<(RawFd, FdState, Vec<u8>)> {
+    let mut f = e.splitn(16, ',');
+    let fd = f.next()?;
+    let dev = f.next()?;
+    let ino = f.next()?;
+    let flags = f.next()?;
+    let bytes = f.next()?;
+    let requested = f.next()?;
+    let size_before = f.next()?;
+    let perm = f.next()?;
+    let open_flags = f.next()?;
+    let open_mode = f.next()?;
+    let opened_ms = f.next()?;
+    let shape = f.next()?;
+    let by = f.next()?;
+    let blocked = f.next()?;
+    let shared = f.next()?;
+    let suffix = f.next()?;
+    let num = |s: &str| s.parse::<u64>().ok();
+    let maybe = |s: &str| {
+        if s.is_empty() {
+            Some(None)
+        } else {
+            num(s).map(Some)
+        }
+    };
+    let shared: usize = shared.parse().ok()?;
+    let mut path = prev.get(..shared)?.to_vec();
+    path.extend(unescape(suffix)?);
+    let has = |c| flags.contains(c);
+    let s = FdState {
+        dev: num(dev)?,
+        ino: num(ino)?,
+        pre_sent: has('a'),
+        dirty: has('d'),
+        created: has('c'),
+        append: has('p'),
+        notify_only: has('n'),
+        self_created: has('s'),
+        bytes: num(bytes)?,
+        requested: num(requested)?,
+        size_before: maybe(size_before)?,
+        perm: perm.parse().ok()?,
+        open_flags: maybe(open_flags)?.map(|f| f as i32),
+        open_mode: maybe(open_mode)?.map(|m| m as libc::mode_t),
+        opened_ms: maybe(opened_ms)?,
+        shape: match shape {
+            "a" => Some(WriteShape::Append),
+            "o" => Some(WriteShape::Overwrite),
+            "m" => Some(WriteShape::Mixed),
+            _ => None,
+        },
+        decision: Decision {
+            by: AllowedBy::named(by),
+            blocked: std::time::Duration::from_millis(num(blocked)?),
+            ..Decision::default()
+        },
+        ..FdState::default()
+    };
+    Some((fd.parse().ok()?, s, path))
+}
+
+/// Take the fds the image before this one handed over; from the library
+/// constructor, before the program has threads that could race
+/// `unsetenv`.
+pub(crate) fn adopt() {
+    let Some(value) = std::env::var_os(ENV) else {
+        return;
+    };
+    if let Ok(name) = CString::new(ENV) {
+        unsafe { libc::unsetenv(name.as_ptr()) };
+    }
+    let (pid, ppid) = unsafe { (libc::getpid(), libc::getppid()) };
+    let mut t = FD_TABLE.lock();
+    for (fd, mut s) in parse(&value.to_string_lossy(), pid, ppid) {
+        let Some(st) = fd_stat(fd).filter(|st| st.regular && (st.dev, st.ino) == (s.dev, s.ino))
+        else {
+            continue;
+        };
+        if s.size_before.is_none() {
+            s.size_before = Some(st.size);
+        }
+        s.uid = st.uid;
+        if let Some(p) = &s.path {
+            inodes::remember(s.dev, s.ino, p);
+        }
+        if s.dirty {
+            s.dirty_since = crate::dirty_age::arm();
+            s.writer.start();
+        }
+        t.insert(fd, s);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracked(path: &str, bytes: u64) -> FdState {
+        FdState {
+            path: Some(PathBuf::from(path)),
+            dev: 7,
+            ino: 42,
+            pre_sent: true,
+            dirty: bytes > 0,
+            bytes,
+            requested: bytes,
+            size_before: Some(3),
+            decision: Decision {
+                by: Some(AllowedBy::Server),
+                ..Decision::default()
+            },
+            ..FdState::default()
+        }
+    }
+
+    fn value(mode: Mode, pid: i32, fds: &[(RawFd, FdState)]) -> String {
+        let mut v = format!("1{}{pid}", if mode == Mode::Exec { 'e' } else { 's' });
+        let mut prev: &[u8] = b"";
+        for (fd, s) in fds {
+            let path = s.path.as_deref().unwrap().as_os_str().as_bytes();
+            v.push(';');
+            v.push_str(&entry(*fd, s, path, prev, mode));
+            prev = path;
+        }
+        v
+    }
+
+    #[test]
+    fn an_exec_hands_over_paths_and_unposted_writes() {
+        let fds = [
+            (5, tracked("/p/src/a;b,c.rs", 6)),
+            (6, tracked("/p/src/b.rs", 0)),
+        ];
+        let v = value(Mode::Exec, 4242, &fds);
+        assert!(
+            v.contains(",0,/p/src/a%3Bb%2Cc.rs;") && v.ends_with(",7,b.rs"),
+            "{v}"
+        );
+        let got = parse(&v, 4242, 1);
+        assert_eq!(got.len(), 2);
+        let (fd, s) = &got[0];
+        assert_eq!((*fd, s.path.as_deref()), (5, fds[0].1.path.as_deref()));
+        assert_eq!((s.dirty, s.bytes, s.size_before), (true, 6, Some(3)));
+        assert_eq!(s.decision.by, Some(AllowedBy::Server));
+        assert_eq!(got[1].1.path.as_deref(), fds[1].1.path.as_deref());
+        // Another process's, or a spawn's read by the spawner, is not ours.
+        assert!(parse(&v, 4243, 4242).is_empty());
+        assert!(parse(&value(Mode::Spawn, 4242, &fds), 4242, 1).is_empty());
+    }
+
+    #[test]
+    fn a_spawn_hands_over_approvals_only() {
+        let v = value(Mode::Spawn, 4242, &[(5, tracked("/p/a", 6))]);
+        let got = parse(&v, 4300, 4242);
+        let s = &got[0].1;
+        assert!(s.pre_sent && !s.dirty);
+        assert_eq!((s.bytes, s.size_before), (0, None));
+    }
+
+    #[test]
+    fn malformed_values_hand_over_nothing() {
+        for v in [
+            "",
+            "2e1",
+            "1x1;5",
+            "1e1;5,7,42",
+            "1e1;5,7,42,a,0,0,,0,,,,,,0,9,x",
+        ] {
+            assert!(parse(v, 1, 0).is_empty(), "{v}");
+        }
+    }
+}
@@ -0,0 +1,413 @@
+//! Notification fan-out to the extra `[[destination]]`s in the config file.
+//!
+//! Preflights only ever go to the primary destination (see `DESTINATION`),
+//! or to the one a `[[route]]` names (see `routes`). Every other
+//! configured destination is a sink: it gets a copy of each
+//! notification through a bounded queue served by its own thread, with its
+//! own connection, reconnect backoff and drop counter. The traced thread
+//! only ever pays for the queue push; a slow or dead sink loses events
+//! (counted, and reported to it as `shim/dropped` once it is back) instead
+//! of adding latency.
+//!
+//! A host can keep the shim from starting threads at all (a thread limit,
+//! a sandbox, a call from very early in the process), and a worker can
+//! die. Neither may cost events, so each sink's worker is supervised from
+//! the queue push. When the spawn fails, or the worker's heartbeat hasn't
+//! moved for `STALL` while lines wait, the sink is served inline: the
+//! traced thread sends each notification itself, after what the queue
+//! still held, as `post_notify` does for the primary. That is reported
+//! once, on stderr and to the sink as a `shim/error` of kind
+//! `sink_fallback`. Starting a worker is tried again every `RETRY`; the
+//! one it replaces, if still running, stops at its next turn.
+//!
+//! `NVIM_CLAUDE_SHIM_FAIL_SPAWN=<n>` fails the first `n` spawns, for
+//! testing this.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+use parking_lot::{Condvar, Mutex};
+use serde_json::json;
+
+use crate::breaker::Breaker;
+use crate::config::{self, Role};
+use crate::{
+    connect, encode_notification, env_names_destination, heap, log_debug, report_error, Conn,
+    ConnectError, Destination,
+};
+
+/// Lines queued per sink before new ones are dropped.
+const QUEUE_CAP: usize = 1024;
+const BACKOFF_MIN: Duration = Duration::from_millis(50);
+const BACKOFF_MAX: Duration = Duration::from_secs(5);
+/// How long a drain (at process exit, or on a flush) waits for connected
+/// sinks.
+const DRAIN_WAIT: Duration = Duration::from_millis(250);
+/// How often an idle worker beats.
+const HEARTBEAT: Duration = Duration::from_secs(1);
+/// How long a worker's heartbeat may stand still, with lines waiting,
+/// before it is taken for dead: past the longest backoff sleep.
+const STALL: Duration = Duration::from_secs(10);
+/// How long a sink is served inline before a worker is tried again.
+const RETRY: Duration = Duration::from_secs(1);
+
+struct Sink {
+    dest: Destination,
+    breaker: Breaker,
+    state: Mutex<SinkState>,
+    wake: Condvar,
+    /// Bumped by the worker on each turn of its loop.
+    beats: AtomicU64,
+    /// The connection inline sends use, and the pid that made it.
+    inline: Mutex<Option<(libc::pid_t, Conn)>>,
+}
+
+#[derive(Default)]
+struct SinkState {
+    lines: VecDeque<Vec<u8>>,
+    dropped: u64,
+    /// Pid that owns the worker thread; a forked child starts its own.
+    worker_pid: Option<libc::pid_t>,
+    /// Which worker is the current one; any other stops at its next turn.
+    generation: u64,
+    /// The heartbeat last seen, and when it was first seen.
+    seen: Option<(u64, Instant)>,
+    /// Served inline until then, when a worker is tried again.
+    inline_until: Option<Instant>,
+    /// No inline connect is tried before then.
+    inline_down_until: Option<Instant>,
+    /// The last connect attempt failed; the worker is backing off.
+    down: bool,
+    /// The peer failed the uid check; nothing more is queued.
+    dead: bool,
+}
+
+static SINKS: Lazy<Vec<Sink>> = Lazy::new(|| {
+    let cfg = config::get();
+    // The first authoritative entry is the primary unless the environment
+    // already named one.
+    let primary = (!env_names_destination())
+        .then(|| cfg.first_authoritative())
+        .flatten();
+    cfg.destinations
+        .iter()
+        .filter(|d| !primary.is_some_and(|p| std::ptr::eq(*d, p)) && !cfg.is_routed(d))
+        .inspect(|d| {
+            if d.role == Role::Authoritative {
+                log_debug(&format!(
+                    "shim: {} is not the first authoritative destination; notifications only\n",
+                    d.address
+                ));
+            }
+        })
+        .map(|d| Sink {
+            dest: Destination::from_config(d),
+            breaker: Breaker::new(cfg.send_timeout(Some(d))),
+            state: Mutex::new(SinkState::default()),
+            wake: Condvar::new(),
+            beats: AtomicU64::new(0),
+            inline: Mutex::new(None),
+        })
+        .collect()
+});
+
+pub(crate) fn is_empty() -> bool {
+    SINKS.is_empty()
+}
+
+/// Queue one notification payload for every sink, or send it inline to
+/// those without a worker.
+pub(crate) fn notify(line: &[u8]) {
+    let pid = unsafe { libc::getpid() };
+    let now = Instant::now();
+    for sink in SINKS.iter() {
+        let mut st = sink.state.lock();
+        if st.dead {
+            continue;
+        }
+        if let Serve::Inline { warn } = supervise(sink, &mut st, pid, now) {
+            drop(st);
+            send_inline(sink, line, warn, pid, now);
+            continue;
+        }
+        if st.lines.len() >= QUEUE_CAP || !heap::charge(line.len()) {
+            st.dropped += 1;
+            continue;
+        }
+        st.lines.push_back(line.to_vec());
+        sink.wake.notify_one();
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Serve {
+    Queue,
+    /// `warn`: why the sink just went inline, the first time.
+    Inline {
+        warn: Option<&'static str>,
+    },
+}
+
+fn supervise(sink: &'static Sink, st: &mut SinkState, pid: libc::pid_t, now: Instant) -> Serve {
+    if let Some(until) = st.inline_until {
+        if now < until || !spawn_worker(sink, st, pid) {
+            st.inline_until = Some(until.max(now + RETRY));
+            return Serve::Inline { warn: None };
+        }
+        st.inline_until = None;
+        return Serve::Queue;
+    }
+    let beats = sink.beats.load(Ordering::Relaxed);
+    let warn = if st.worker_pid != Some(pid) {
+        if spawn_worker(sink, st, pid) {
+            return Serve::Queue;
+        }
+        "could not start a sender thread; sending inline"
+    } else if stalled(&mut st.seen, beats, !st.lines.is_empty(), now) {
+        "the sender thread stopped; sending inline"
+    } else {
+        return Serve::Queue;
+    };
+    // Retire whatever worker there was.
+    st.generation += 1;
+    st.worker_pid = None;
+    st.inline_until = Some(now + RETRY);
+    Serve::Inline { warn: Some(warn) }
+}
+
+/// Whether a worker whose heartbeat is `beats` now has stood still for
+/// `STALL` while `busy`. `seen` keeps the last count and when it moved.
+fn stalled(seen: &mut Option<(u64, Instant)>, beats: u64, busy: bool, now: Instant) -> bool {
+    match *seen {
+        Some((last, at)) if last == beats => busy && now.saturating_duration_since(at) >= STALL,
+        _ => {
+            *seen = Some((beats, now));
+            false
+        }
+    }
+}
+
+static FAIL_SPAWN: Lazy<AtomicU32> = Lazy::new(|| {
+    let n = std::env::var("NVIM_CLAUDE_SHIM_FAIL_SPAWN").ok();
+    AtomicU32::new(n.and_then(|n| n.parse().ok()).unwrap_or(0))
+});
+
+fn spawn_worker(sink: &'static Sink, st: &mut SinkState, pid: libc::pid_t) -> bool {
+    static AT_EXIT: std::sync::Once = std::sync::Once::new();
+    AT_EXIT.call_once(|| unsafe {
+        libc::atexit(drain_at_exit);
+    });
+    let injected = FAIL_SPAWN
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+        .is_ok();
+    st.generation += 1;
+    let generation = st.generation;
+    let spawned = !injected
+        && std::thread::Builder::new()
+            .name("nvim-claude-sink".into())
+            .spawn(move || run_worker(sink, generation))
+            .is_ok();
+    if spawned {
+        st.worker_pid = Some(pid);
+        st.seen = None;
+    }
+    spawned
+}
+
+/// Send `line` to `sink` from the calling thread, after whatever its
+/// queue still holds. The calling hook's context covers the socket I/O.
+fn send_inline(
+    sink: &'static Sink,
+    line: &[u8],
+    warn: Option<&'static str>,
+    pid: libc::pid_t,
+    now: Instant,
+) {
+    if !sink.breaker.admits(now) {
+        sink.state.lock().dropped += 1;
+        return;
+    }
+    let mut slot = sink.inline.lock();
+    if slot.as_ref().is_some_and(|(owner, _)| *owner != pid) {
+        // The parent's; a forked child connects afresh.
+        *slot = None;
+    }
+    if slot.is_none() {
+        match connect_inline(sink, now) {
+            Some(ch) => *slot = Some((pid, ch)),
+            None => {
+                if let Some(detail) = warn {
+                    report_error("sink_fallback", detail, None);
+                }
+                return;
+            }
+        }
+    }
+    let Some((_, ch)) = slot.as_mut() else {
+        return;
+    };
+    if let Some(detail) = warn {
+        report_error("sink_fallback", detail, Some(ch));
+    }
+    let (left, dropped) = {
+        let mut st = sink.state.lock();
+        let left = std::mem::take(&mut st.lines);
+        heap::release(left.iter().map(Vec::len).sum());
+        (left, std::mem::take(&mut st.dropped))
+    };
+    let frames = (dropped > 0)
+        .then(|| dropped_frame(dropped))
+        .flatten()
+        .into_iter()
+        .chain(left)
+        .chain([line.to_vec()]);
+    let mut lost = 0;
+    for frame in frames {
+        if lost > 0 || ch.send(&frame).is_err() {
+            lost += 1;
+        }
+    }
+    if lost > 0 {
+        *slot = None;
+        sink.state.lock().dropped += lost;
+    }
+}
+
+/// A connection for inline sends, unless the last try failed within
+/// `RETRY`. `None` counts the line about to be sent as dropped.
+fn connect_inline(sink: &'static Sink, now: Instant) -> Option<Conn> {
+    {
+        let mut st = sink.state.lock();
+        if st.inline_down_until.is_some_and(|t| now < t) {
+            st.dropped += 1;
+            return None;
+        }
+    }
+    let err = match connect(&sink.dest, &sink.breaker) {
+        Ok(ch) => return Some(ch),
+        Err(e) => e,
+    };
+    let mut st = sink.state.lock();
+    st.dropped += 1;
+    if err == ConnectError::Untrusted {
+        st.dead = true;
+        heap::release(st.lines.iter().map(Vec::len).sum());
+        st.lines.clear();
+    } else {
+        st.inline_down_until = Some(now + RETRY);
+    }
+    None
+}
+
+fn run_worker(sink: &'static Sink, generation: u64) {
+    // Depth 1 for the whole thread: the socket I/O and closes it does pass
+    // straight through the hooks.
+    let _ctx = crate::op_context::OpContext::enter();
+    let mut channel: Option<Conn> = None;
+    let mut backoff = BACKOFF_MIN;
+    loop {
+        sink.beats.fetch_add(1, Ordering::Relaxed);
+        {
+            let mut st = sink.state.lock();
+            while st.lines.is_empty() && st.generation == generation {
+                sink.wake.wait_for(&mut st, HEARTBEAT);
+                sink.beats.fetch_add(1, Ordering::Relaxed);
+            }
+            if st.generation != generation {
+                return;
+            }
+        }
+        let ch = match channel.as_mut() {
+            Some(ch) => ch,
+            None => match connect(&sink.dest, &sink.breaker) {
+                Ok(mut ch) => {
+                    backoff = BACKOFF_MIN;
+                    let dropped = {
+                        let mut st = sink.state.lock();
+                        st.down = false;
+                        std::mem::take(&mut st.dropped)
+                    };
+                    if let Some(frame) = (dropped > 0).then(|| dropped_frame(dropped)).flatten() {
+                        let _ = ch.send(&frame);
+                    }
+                    channel.insert(ch)
+                }
+                Err(ConnectError::Untrusted) => {
+                    let mut st = sink.state.lock();
+                    st.dead = true;
+                    heap::release(st.lines.iter().map(Vec::len).sum());
+                    st.lines.clear();
+                    return;
+                }
+                Err(ConnectError::Unreachable) => {
+                    sink.state.lock().down = true;
+                    std::thread::sleep(backoff);
+                    backoff = (backoff * 2).min(BACKOFF_MAX);
+                    continue;
+                }
+            },
+        };
+        // Popped only once sent, so an empty queue means delivered.
+        let Some(line) = sink.state.lock().lines.front().cloned() else {
+            continue;
+        };
+        let sent = ch.send(&line).is_ok();
+        let mut st = sink.state.lock();
+        if st.generation != generation {
+            // Replaced while sending; the queue is the inline path's now.
+            return;
+        }
+        st.lines.pop_front();
+        heap::release(line.len());
+        if !sent {
+            st.dropped += 1;
+            channel = None;
+        }
+    }
+}
+
+fn dropped_frame(count: u64) -> Option<Vec<u8>> {
+    let pid = unsafe { libc::getpid() };
+    encode_notification("shim/dropped", json!({ "pid": pid, "count": count }))
+}
+
+extern "C" fn drain_at_exit() {
+    drain();
+}
+
+/// Give sinks a moment to send what the process just did; ones whose last
+/// connect failed are not waited for.
+pub(crate) fn drain() {
+    let deadline = Instant::now() + DRAIN_WAIT;
+    for sink in SINKS.iter() {
+        while Instant::now() < deadline {
+            let st = sink.state.lock();
+            if st.lines.is_empty() || st.down {
+                break;
+            }
+            drop(st);
+            std::thread::sleep(Duration::from_millis(2));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_worker_is_stalled_once_its_heartbeat_stands_still_with_lines_waiting() {
+        let t0 = Instant::now();
+        let mut seen = None;
+        assert!(!stalled(&mut seen, 3, true, t0));
+        let late = t0 + STALL;
+        // Idle: nothing is waiting on it.
+        assert!(!stalled(&mut seen, 3, false, late));
+        assert!(stalled(&mut seen, 3, true, late));
+        // It beat again: the wait starts over.
+        assert!(!stalled(&mut seen, 4, true, late));
+        assert!(!stalled(&mut seen, 4, true, late + HEARTBEAT));
+    }
+}
@@ -0,0 +1,530 @@
+//! What the shim knows about each fd a program writes through.
+//!
+//! An fd earns an entry when it is opened for writing a regular file, or
+//! at its first write when the shim didn't see it open (inherited, or
+//! made by `dup`), and loses it at its close. The entry holds the file's
+//! identity and size as first seen, how its first preflight was decided,
+//! and what its writes have done since, which is everything its post
+//! reports. The table is keyed by fd, so before a close the entry for a
+//! reused number is replaced, never inherited.
+//!
+//! Nothing here talks to the server; the hook bodies in `handlers` decide
+//! when to ask and post, and read and update entries under `FD_TABLE`'s
+//! lock.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::os::raw::{c_char, c_int};
+use std::os::unix::prelude::RawFd;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde_json::json;
+
+use crate::config::{self, NonblockingPreflight, OtherFilesystems, TruncateKind};
+use crate::{content_tier, dirty_age, inodes, internal_io, platform, special, writers, Decision};
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct FdState {
+    pub(crate) path: Option<PathBuf>,
+    pub(crate) dev: u64,
+    pub(crate) ino: u64,
+    pub(crate) dirty: bool,
+    pub(crate) bytes: u64, // total transferred by successful writes since open
+    pub(crate) requested: u64, // what those writes asked to transfer; more when some were short
+    pub(crate) pre_sent: bool, // did we already block on the first write/truncate for this FD?
+    pub(crate) open_flags: Option<c_int>, // None when the fd predates the shim or came from dup/fcntl
+    pub(crate) open_mode: Option<libc::mode_t>,
+    pub(crate) pre_open: Option<PreOpen>,
+    pub(crate) created: bool, // this fd's open made the file: pre_create/post_create
+    pub(crate) append: bool,  // O_APPEND, from open_flags or F_GETFL on first write
+    pub(crate) size_before: Option<u64>, // st_size when first fstat'ed; None until then
+    pub(crate) perm: u32,     // permission bits from that fstat
+    pub(crate) uid: u32,      // owner from that fstat
+    pub(crate) ignored: bool, // no preflight, no events (size or filesystem policy)
+    pub(crate) notify_only: bool, // no preflight, events only (filesystem policy)
+    pub(crate) self_created: bool, // no preflight, events only: see `self_created`
+    pub(crate) decision: Decision, // how the last preflight for this fd was allowed
+    pub(crate) nonblocking: bool, // O_NONBLOCK, read with append
+    pub(crate) pre_mode: Option<NonblockingPreflight>, // how a nonblocking fd's preflight went out
+    pub(crate) asked: Option<(u32, Instant)>, // an async preflight's request id, until answered
+    pub(crate) denied: bool,  // its answer was no: every later write fails
+    pub(crate) truncated: Option<Truncation>, // the last ftruncate through it
+    pub(crate) shape: Option<WriteShape>, // where the writes went, from the samples so far
+    pub(crate) unsampled: u32, // position writes since the last `lseek` sample
+    pub(crate) dirty_since: Option<u64>, // when it went dirty, with `max_dirty_age_ms`; see `dirty_age`
+    pub(crate) tier: Option<content_tier::Tier>, // set by the first write; see `content_tier`
+    pub(crate) opened_ms: Option<u64>,   // when its open returned; None when we didn't see it
+    pub(crate) escape: Option<PathBuf>, // the path opened, when a symlink took it out of the project
+    pub(crate) writer: writers::Writer, // see `writers`
+}
+
+/// Where a session's writes went against the end of the file, for
+/// `post_modify`'s `write_shape`. Plain writes carry no offset, so the
+/// fd's position is read back with `lseek` on the first one and every
+/// `SAMPLE_EVERY` after; explicit offsets are judged on every call. A
+/// write starting before the end (the size as for a truncate, so an upper
+/// bound) overwrites. `O_APPEND` fds only append and `O_TRUNC` opens only
+/// overwrite, sampled never; once `Mixed`, nothing is sampled again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum WriteShape {
+    Append,
+    Overwrite,
+    Mixed,
+}
+
+impl WriteShape {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            WriteShape::Append => "append",
+            WriteShape::Overwrite => "overwrite",
+            WriteShape::Mixed => "mixed",
+        }
+    }
+}
+
+const SAMPLE_EVERY: u32 = 64;
+
+/// Where a write was made: at an explicit offset (`pwrite`, `pwritev`)
+/// or at the fd's position.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum WriteAt {
+    Offset(u64),
+    Position,
+}
+
+/// One truncate: the `length` asked for and the `size` it found. Through
+/// an fd, `size` is the cached `fstat`'s plus what was written since, so
+/// an upper bound once there have been writes; a truncate is only called
+/// an extension when it surely is one.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Truncation {
+    pub(crate) length: u64,
+    pub(crate) size: u64,
+    pub(crate) bytes: u64, // the fd's `bytes` when it was made
+}
+
+impl Truncation {
+    pub(crate) fn kind(&self) -> TruncateKind {
+        TruncateKind::of(self.length, self.size)
+    }
+
+    pub(crate) fn params(&self) -> serde_json::Value {
+        json!({ "length": self.length, "size": self.size, "kind": self.kind().name() })
+    }
+}
+
+/// What a `stat` just before `open(O_CREAT | O_TRUNC)` saw, which the
+/// fd alone can no longer tell.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct PreOpen {
+    pub(crate) existed: bool,
+    pub(crate) size: u64,
+}
+
+/// Keyed by fd, so a process that raised its limit into the hundreds of
+/// thousands costs an entry per fd it writes, however high the numbers.
+pub(crate) static FD_TABLE: Lazy<Mutex<HashMap<RawFd, FdState>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Everything we want from one `fstat`: identity, type and size are all
+/// read together so first sight of an fd costs a single syscall.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct FdStat {
+    pub(crate) dev: u64,
+    pub(crate) ino: u64,
+    pub(crate) size: u64,
+    pub(crate) perm: u32,
+    pub(crate) uid: u32,
+    pub(crate) regular: bool,
+    /// Seconds since the epoch.
+    pub(crate) mtime: i64,
+}
+
+impl FdStat {
+    // `stat` field types differ between platforms.
+    #[allow(clippy::unnecessary_cast)]
+    pub(crate) fn of(st: &libc::stat) -> FdStat {
+        FdStat {
+            dev: st.st_dev as u64,
+            ino: st.st_ino as u64,
+            size: st.st_size as u64,
+            perm: st.st_mode as u32 & 0o7777,
+            uid: st.st_uid as u32,
+            regular: (st.st_mode & libc::S_IFMT) == libc::S_IFREG,
+            mtime: st.st_mtime as i64,
+        }
+    }
+
+    pub(crate) fn of_metadata(m: &std::fs::Metadata) -> FdStat {
+        use std::os::unix::fs::MetadataExt;
+
+        FdStat {
+            dev: m.dev(),
+            ino: m.ino(),
+            size: m.size(),
+            perm: m.mode() & 0o7777,
+            uid: m.uid(),
+            regular: m.file_type().is_file(),
+            mtime: m.mtime(),
+        }
+    }
+}
+
+pub(crate) fn fd_stat(fd: RawFd) -> Option<FdStat> {
+    unsafe {
+        let mut st: libc::stat = std::mem::zeroed();
+        if libc::fstat(fd, &mut st as *mut _) != 0 {
+            return None;
+        }
+        Some(FdStat::of(&st))
+    }
+}
+
+/// `fstatat` on what an `open`/`openat` is about to open; `None` when it
+/// doesn't exist (or can't be looked at).
+pub(crate) unsafe fn stat_at(dirfd: Option<c_int>, path: *const c_char) -> Option<FdStat> {
+    unsafe {
+        let mut st: libc::stat = std::mem::zeroed();
+        let dirfd = dirfd.unwrap_or(libc::AT_FDCWD);
+        if libc::fstatat(dirfd, path, &mut st as *mut _, 0) != 0 {
+            return None;
+        }
+        Some(FdStat::of(&st))
+    }
+}
+
+impl FdState {
+    pub(crate) fn apply_stat(&mut self, st: FdStat) {
+        self.dev = st.dev;
+        self.ino = st.ino;
+        self.size_before = Some(st.size);
+        self.perm = st.perm;
+        self.uid = st.uid;
+        if let Some(p) = &self.path {
+            inodes::remember(st.dev, st.ino, p);
+        }
+    }
+
+    /// The fd's first preflight is being decided: whether it gets one, and
+    /// posts, by its filesystem (`fs_type` names it, when its device isn't
+    /// cached) and size.
+    pub(crate) fn first_sight(
+        &mut self,
+        fs_type: impl FnOnce() -> Option<String>,
+        cfg: &config::ShimConfig,
+    ) {
+        self.pre_sent = true;
+        match filesystem_policy(self.dev, fs_type) {
+            OtherFilesystems::Track => {}
+            OtherFilesystems::Notify => self.notify_only = true,
+            OtherFilesystems::Ignore => self.ignored = true,
+        }
+        if cfg
+            .ignore_file_size
+            .is_some_and(|max| self.size_before.unwrap_or(0) > max)
+        {
+            self.ignored = true;
+        }
+    }
+
+    /// Whether its first preflight was asked and allowed, which covers a
+    /// later truncate through it too.
+    pub(crate) fn approved(&self) -> bool {
+        self.pre_sent
+            && self.decision.by.is_some()
+            && !self.denied
+            && self.asked.is_none()
+            && self.escape.is_none()
+    }
+
+    /// What its preflight and post are called.
+    pub(crate) fn events(&self) -> (&'static str, &'static str) {
+        if self.created {
+            ("pre_create", "post_create")
+        } else {
+            ("pre_modify", "post_modify")
+        }
+    }
+
+    /// The file's size by what went through the fd: the last truncate's
+    /// length, else the first `fstat`'s size, plus the bytes written since.
+    pub(crate) fn size_now(&self) -> Option<u64> {
+        let (from, at) = match self.truncated {
+            Some(t) => (t.length, t.bytes),
+            None => (self.size_before?, 0),
+        };
+        Some(from.saturating_add(self.bytes.saturating_sub(at)))
+    }
+
+    /// What a truncate to `length` now would find.
+    pub(crate) fn truncation(&self, length: u64) -> Option<Truncation> {
+        Some(Truncation {
+            length,
+            size: self.size_now()?,
+            bytes: self.bytes,
+        })
+    }
+
+    /// Fold a write of `written` bytes into `shape`, before it is counted
+    /// in `bytes`.
+    pub(crate) fn note_write(&mut self, fd: RawFd, at: WriteAt, written: u64) {
+        if self.shape == Some(WriteShape::Mixed) {
+            return;
+        }
+        let Some(size) = self.size_now() else {
+            return;
+        };
+        if self.append {
+            self.shape = Some(WriteShape::Append);
+            return;
+        }
+        if self.open_flags.is_some_and(|f| f & libc::O_TRUNC != 0) {
+            self.shape = Some(WriteShape::Overwrite);
+            return;
+        }
+        let start = match at {
+            WriteAt::Offset(off) => off,
+            WriteAt::Position => {
+                let due = self.unsampled == 0;
+                self.unsampled = (self.unsampled + 1) % SAMPLE_EVERY;
+                if !due {
+                    return;
+                }
+                let pos = unsafe { libc::lseek(fd, 0, libc::SEEK_CUR) };
+                if pos < 0 {
+                    return;
+                }
+                (pos as u64).saturating_sub(written)
+            }
+        };
+        let kind = if start >= size {
+            WriteShape::Append
+        } else {
+            WriteShape::Overwrite
+        };
+        self.shape = Some(match self.shape {
+            Some(s) if s != kind => WriteShape::Mixed,
+            _ => kind,
+        });
+    }
+
+    /// Above `max_file_size`, before or (by byte count) after the writes.
+    pub(crate) fn is_large(&self, cfg: &config::ShimConfig) -> bool {
+        let before = self.size_before.unwrap_or(0);
+        cfg.max_file_size
+            .is_some_and(|max| before > max || before.saturating_add(self.bytes) > max)
+    }
+}
+
+/// Filesystem policy per `st_dev`, looked up with `fstatfs` on first sight
+/// of each device. Mounts change rarely, so instead of watching them the
+/// whole map is dropped every few minutes in case a device number was reused.
+struct FsCache {
+    born: Instant,
+    by_dev: HashMap<u64, OtherFilesystems>,
+}
+
+const FS_CACHE_TTL: Duration = Duration::from_secs(300);
+
+static FS_CACHE: Lazy<Mutex<FsCache>> = Lazy::new(|| {
+    Mutex::new(FsCache {
+        born: Instant::now(),
+        by_dev: HashMap::new(),
+    })
+});
+
+fn filesystem_policy(dev: u64, fs_type: impl FnOnce() -> Option<String>) -> OtherFilesystems {
+    {
+        let mut cache = FS_CACHE.lock();
+        if cache.born.elapsed() > FS_CACHE_TTL {
+            cache.by_dev.clear();
+            cache.born = Instant::now();
+        }
+        if let Some(policy) = cache.by_dev.get(&dev) {
+            return *policy;
+        }
+    }
+    // Outside the lock: fstatfs on a sick network mount can take a while.
+    let policy = config::get().filesystem_policy(fs_type().as_deref());
+    FS_CACHE.lock().by_dev.insert(dev, policy);
+    policy
+}
+
+pub(crate) fn tracked_path(fd: RawFd) -> Option<String> {
+    FD_TABLE
+        .lock()
+        .get(&fd)
+        .and_then(|s| s.path.as_ref())
+        .map(|p| p.to_string_lossy().to_string())
+}
+
+/// Record a change through `fd`: `bytes` actually written, or 0 for a
+/// truncate. Callers only get here once something really changed, so a
+/// session of failed or zero-length writes never reaches `post_modify`.
+pub(crate) fn mark_fd_dirty(fd: RawFd, at: Option<WriteAt>, requested: u64, bytes: u64) {
+    let mut t = FD_TABLE.lock();
+    let e = match t.entry(fd) {
+        Entry::Occupied(e) => e.into_mut(),
+        // Not one we opened: only a regular file earns an entry (and the
+        // path lookup), so a host logging to a pipe or tty under memory
+        // pressure doesn't have the shim allocating on each line.
+        Entry::Vacant(_) if special::is_fd(fd) => return,
+        Entry::Vacant(v) => {
+            let Some(st) = fd_stat(fd).filter(|st| st.regular) else {
+                return;
+            };
+            let e = v.insert(FdState {
+                path: platform::fd_path(fd),
+                ..FdState::default()
+            });
+            e.apply_stat(st);
+            e
+        }
+    };
+    if e.path.is_none() {
+        e.path = platform::fd_path(fd);
+    }
+    if e.size_before.is_none() {
+        if let Some(st) = fd_stat(fd).filter(|st| st.regular) {
+            e.apply_stat(st);
+        }
+    }
+    if let Some(at) = at {
+        let tier = *e
+            .tier
+            .get_or_insert_with(|| content_tier::Tier::of(requested, e.size_before));
+        if tier.content_features() {
+            e.note_write(fd, at, bytes);
+        }
+    }
+    let starting = !e.dirty;
+    if starting {
+        e.dirty_since = dirty_age::arm();
+        e.writer.start();
+    }
+    e.dirty = true;
+    e.bytes += bytes;
+    e.requested += requested;
+    if starting && e.ino != 0 {
+        meet_writers(&mut t, fd);
+    }
+}
+
+/// `fd`'s writes just started: pair it with the other fds on its inode
+/// whose writes aren't posted yet (see `writers`).
+fn meet_writers(t: &mut HashMap<RawFd, FdState>, fd: RawFd) {
+    let Some((inode, mut writer)) = t.get(&fd).map(|e| ((e.dev, e.ino), e.writer)) else {
+        return;
+    };
+    let mut met = false;
+    for (_, s) in t
+        .iter_mut()
+        .filter(|&(&o, ref s)| o != fd && s.dirty && !s.ignored && (s.dev, s.ino) == inode)
+    {
+        met |= writer.meet(&mut s.writer);
+    }
+    if let (true, Some(e)) = (met, t.get_mut(&fd)) {
+        e.writer = writer;
+    }
+}
+
+/// The shim's own files are opened past the hooks (`internal_io`), so a
+/// handler seeing one means something went around that.
+#[inline]
+pub(crate) fn debug_assert_foreign(fd: RawFd) {
+    debug_assert!(
+        !internal_io::is_owned(fd),
+        "shim: handler saw its own fd {fd}"
+    );
+}
+
+pub(crate) fn take_fd(fd: RawFd) -> Option<FdState> {
+    special::forget_fd(fd);
+    FD_TABLE.lock().remove(&fd)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AllowedBy;
+
+    fn seen(size: u64) -> FdState {
+        FdState {
+            size_before: Some(size),
+            ..FdState::default()
+        }
+    }
+
+    /// A write as `mark_fd_dirty` counts it: shape first, then bytes.
+    fn write(st: &mut FdState, at: WriteAt, n: u64) {
+        st.note_write(-1, at, n);
+        st.bytes += n;
+    }
+
+    #[test]
+    fn size_follows_writes_and_the_last_truncate() {
+        let mut st = FdState::default();
+        assert_eq!(st.size_now(), None);
+        assert!(st.truncation(0).is_none());
+
+        st = seen(100);
+        st.bytes = 20;
+        assert_eq!(st.size_now(), Some(120));
+        let t = st.truncation(10).unwrap();
+        assert_eq!((t.length, t.size, t.bytes), (10, 120, 20));
+        assert_eq!(t.kind(), TruncateKind::Shrink);
+        st.truncated = Some(t);
+        st.bytes = 25;
+        assert_eq!(st.size_now(), Some(15));
+    }
+
+    #[test]
+    fn only_an_answered_allow_that_went_nowhere_else_is_approved() {
+        let mut st = seen(0);
+        assert_eq!(st.events(), ("pre_modify", "post_modify"));
+        st.created = true;
+        assert_eq!(st.events(), ("pre_create", "post_create"));
+
+        assert!(!st.approved());
+        st.pre_sent = true;
+        st.decision.by = Some(AllowedBy::Server);
+        assert!(st.approved());
+        st.escape = Some(PathBuf::from("/elsewhere"));
+        assert!(!st.approved());
+        st.escape = None;
+        st.asked = Some((1, Instant::now()));
+        assert!(!st.approved());
+        st.asked = None;
+        st.denied = true;
+        assert!(!st.approved());
+    }
+
+    #[test]
+    fn offsets_shape_the_writes() {
+        let mut st = seen(10);
+        write(&mut st, WriteAt::Offset(10), 5);
+        assert_eq!(st.shape, Some(WriteShape::Append));
+        write(&mut st, WriteAt::Offset(15), 5);
+        assert_eq!(st.shape, Some(WriteShape::Append));
+        write(&mut st, WriteAt::Offset(0), 5);
+        assert_eq!(st.shape, Some(WriteShape::Mixed));
+        write(&mut st, WriteAt::Offset(20), 5);
+        assert_eq!(st.shape, Some(WriteShape::Mixed));
+
+        let mut appending = FdState {
+            append: true,
+            ..seen(10)
+        };
+        write(&mut appending, WriteAt::Offset(0), 5);
+        assert_eq!(appending.shape, Some(WriteShape::Append));
+        let mut trunc = FdState {
+            open_flags: Some(libc::O_WRONLY | libc::O_TRUNC),
+            ..seen(0)
+        };
+        write(&mut trunc, WriteAt::Offset(0), 5);
+        assert_eq!(trunc.shape, Some(WriteShape::Overwrite));
+    }
+}
@@ -0,0 +1,72 @@
+//! Answers made up for testing.
+//!
+//! Seeing how a plugin copes with a denial, or with a server that never
+//! answers, used to take a policy written for the purpose. `[[force]]`
+//! tables, or `FS_SHIM_FORCE=deny:<glob>,allow:<glob>,timeout:<glob>`,
+//! settle each blocking preflight for a matching path without asking:
+//! `deny` denies it, `allow` allows it (`"allowed_by": "forced"`), and
+//! `timeout` waits out `FS_SHIM_PRE_TIMEOUT_MS` and then takes the fail
+//! policy, as if the server had gone quiet. What was made up goes out as
+//! a `shim/forced` notification:
+//!
+//! ```json
+//! { "method": "shim/forced",
+//!   "params": { "op": "pre_modify", "path": "/p/a.rs", "forced": "timeout",
+//!               "glob": "**/a.rs", "allowed": false } }
+//! ```
+//!
+//! The first matching rule wins, on the path as reported
+//! (`paths::for_matching`). Only blocking preflights are forced, and only
+//! once the checks that would have kept them from being asked (an
+//! ignored path, a cached allow) have let them through; one from the
+//! reactor thread, or for a nonblocking fd, is asked as usual.
+
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::{config, glob, paths};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Forced {
+    Allow,
+    Deny,
+    Timeout,
+}
+
+impl Forced {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Forced::Allow => "allow",
+            Forced::Deny => "deny",
+            Forced::Timeout => "timeout",
+        }
+    }
+}
+
+impl std::str::FromStr for Forced {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "allow" => Ok(Forced::Allow),
+            "deny" => Ok(Forced::Deny),
+            "timeout" => Ok(Forced::Timeout),
+            other => Err(format!("unknown forced outcome {other:?}")),
+        }
+    }
+}
+
+/// The outcome forced on `path`, and the glob that forced it.
+pub(crate) fn lookup(path: &Path) -> Option<(Forced, &'static str)> {
+    let cfg = config::get();
+    if cfg.force.is_empty() {
+        return None;
+    }
+    let path = paths::for_matching(path, cfg.normalize_unicode);
+    cfg.force
+        .iter()
+        .find(|r| glob::matches(&r.glob, &path, cfg.case_insensitive))
+        .map(|r| (r.outcome, r.glob.as_str()))
+}
@@ -0,0 +1,273 @@
+//! Frame boundaries on a JSON control connection.
+//!
+//! Every connection starts newline-delimited (v1), which is all older
+//! servers speak. Right after connecting the shim offers framing v2 in
+//! `shim/hello`; a server that answers with `"framing": "length-prefixed"`
+//! gets every later frame, in both directions, as a 4-byte little-endian
+//! length followed by the payload. Anything else keeps v1.
+//!
+//! msgpack-RPC is self-delimiting, so it skips the handshake; its values
+//! are taken off the same buffer with no framing around them.
+
+use std::io;
+use std::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Framing {
+    Newline,
+    LengthPrefixed,
+}
+
+/// What the shim offers in `shim/hello`, preferred first.
+pub(crate) const OFFERED: [&str; 2] = ["length-prefixed", "newline"];
+
+impl Framing {
+    /// The framing a `shim/hello` result selects. A missing result (a
+    /// server that predates the handshake, or no answer in time) and names
+    /// we don't know keep newlines.
+    pub(crate) fn from_hello(result: Option<&serde_json::Value>) -> Framing {
+        match result.and_then(|r| r.get("framing")?.as_str()) {
+            Some("length-prefixed") => Framing::LengthPrefixed,
+            _ => Framing::Newline,
+        }
+    }
+
+    /// One payload, framed. Payloads over `max` are refused rather than
+    /// sent for the peer to reject, and so is a newline-delimited one with
+    /// a newline of its own, which would end the frame early.
+    #[cfg(test)]
+    pub(crate) fn encode(self, payload: &[u8], max: usize) -> io::Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(payload.len() + 4);
+        self.encode_into(payload, max, &mut out)?;
+        Ok(out)
+    }
+
+    /// `encode`, appending to `out`.
+    pub(crate) fn encode_into(
+        self,
+        payload: &[u8],
+        max: usize,
+        out: &mut Vec<u8>,
+    ) -> io::Result<()> {
+        if payload.len() > max {
+            return Err(too_large(payload.len(), max));
+        }
+        if self == Framing::Newline && payload.contains(&b'\n') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "newline inside a newline-delimited frame",
+            ));
+        }
+        out.reserve(payload.len() + 4);
+        match self {
+            Framing::Newline => {
+                out.extend_from_slice(payload);
+                out.push(b'\n');
+            }
+            Framing::LengthPrefixed => {
+                out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+                out.extend_from_slice(payload);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn too_large(len: usize, max: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("frame of {len} bytes exceeds the {max} byte cap"),
+    )
+}
+
+/// Reads frames off one connection. Bytes that arrive past the end of a
+/// frame stay buffered for the next one.
+pub(crate) struct FrameReader {
+    buf: Vec<u8>,
+    max: usize,
+}
+
+impl FrameReader {
+    pub(crate) fn new(max: usize) -> FrameReader {
+        FrameReader {
+            buf: Vec::new(),
+            max,
+        }
+    }
+
+    /// A complete value from what is already buffered, without reading.
+    pub(crate) fn take_by<T>(
+        &mut self,
+        parse: impl FnOnce(&[u8]) -> io::Result<Option<(T, usize)>>,
+    ) -> io::Result<Option<T>> {
+        match parse(&self.buf)? {
+            Some((v, used)) => {
+                self.buf.drain(..used);
+                Ok(Some(v))
+            }
+            None if self.buf.len() > self.max => Err(too_large(self.buf.len(), self.max)),
+            None => Ok(None),
+        }
+    }
+
+    /// Read once more into the buffer.
+    pub(crate) fn fill(
+        &mut self,
+        deadline: Instant,
+        read: &mut impl FnMut(&mut [u8]) -> io::Result<usize>,
+    ) -> io::Result<()> {
+        let mut tmp = [0u8; 4096];
+        loop {
+            if Instant::now() >= deadline {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            match read(&mut tmp) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => {
+                    self.buf.extend_from_slice(&tmp[..n]);
+                    return Ok(());
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// The payload at the front of `buf` and the bytes it spans, framing
+/// included.
+pub(crate) fn split(
+    framing: Framing,
+    buf: &[u8],
+    max: usize,
+) -> io::Result<Option<(Vec<u8>, usize)>> {
+    match framing {
+        Framing::Newline => match buf.iter().position(|&b| b == b'\n') {
+            Some(pos) if pos > max => Err(too_large(pos, max)),
+            Some(pos) => Ok(Some((buf[..pos].to_vec(), pos + 1))),
+            None => Ok(None),
+        },
+        Framing::LengthPrefixed => {
+            let Some(head) = buf.get(..4) else {
+                return Ok(None);
+            };
+            let len = u32::from_le_bytes(head.try_into().unwrap()) as usize;
+            if len > max {
+                return Err(too_large(len, max));
+            }
+            Ok(buf.get(4..4 + len).map(|frame| (frame.to_vec(), 4 + len)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::time::Duration;
+
+    /// `read` over `data`, `chunk` bytes per call.
+    fn torn(data: &[u8], chunk: usize) -> impl FnMut(&mut [u8]) -> io::Result<usize> + '_ {
+        let mut pos = 0;
+        move |buf: &mut [u8]| {
+            let n = chunk.min(buf.len()).min(data.len() - pos);
+            buf[..n].copy_from_slice(&data[pos..pos + n]);
+            pos += n;
+            Ok(n)
+        }
+    }
+
+    fn later() -> Instant {
+        Instant::now() + Duration::from_secs(5)
+    }
+
+    impl FrameReader {
+        /// The next payload, reading until one is complete.
+        fn next(
+            &mut self,
+            framing: Framing,
+            deadline: Instant,
+            mut read: impl FnMut(&mut [u8]) -> io::Result<usize>,
+        ) -> io::Result<Vec<u8>> {
+            let max = self.max;
+            loop {
+                if let Some(frame) = self.take_by(|buf| split(framing, buf, max))? {
+                    return Ok(frame);
+                }
+                self.fill(deadline, &mut read)?;
+            }
+        }
+    }
+
+    #[test]
+    fn frames_survive_torn_reads() {
+        for framing in [Framing::Newline, Framing::LengthPrefixed] {
+            let mut wire = framing.encode(b"{\"a\":1}", 64).unwrap();
+            wire.extend(framing.encode(b"second", 64).unwrap());
+            for chunk in [1, 3, 4096] {
+                let mut read = torn(&wire, chunk);
+                let mut r = FrameReader::new(64);
+                assert_eq!(r.next(framing, later(), &mut read).unwrap(), b"{\"a\":1}");
+                assert_eq!(r.next(framing, later(), &mut read).unwrap(), b"second");
+                let eof = r.next(framing, later(), &mut read).unwrap_err();
+                assert_eq!(eof.kind(), io::ErrorKind::UnexpectedEof);
+            }
+        }
+    }
+
+    #[test]
+    fn length_prefixed_payloads_may_contain_newlines() {
+        let wire = Framing::LengthPrefixed
+            .encode(b"line 1\nline 2", 64)
+            .unwrap();
+        assert_eq!(&wire[..4], &13u32.to_le_bytes());
+        let mut r = FrameReader::new(64);
+        let got = r.next(Framing::LengthPrefixed, later(), torn(&wire, 2));
+        assert_eq!(got.unwrap(), b"line 1\nline 2");
+        assert!(Framing::Newline.encode(b"line 1\nline 2", 64).is_err());
+    }
+
+    #[test]
+    fn frames_over_the_cap_are_refused() {
+        assert!(Framing::LengthPrefixed.encode(&[0; 9], 8).is_err());
+        assert!(Framing::Newline.encode(&[0; 9], 8).is_err());
+
+        // The length alone is enough to refuse; the body never arrives.
+        let wire = 1000u32.to_le_bytes();
+        let err = FrameReader::new(8)
+            .next(Framing::LengthPrefixed, later(), torn(&wire, 4))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let wire = [b'x'; 32];
+        let err = FrameReader::new(8)
+            .next(Framing::Newline, later(), torn(&wire, 4))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn hello_answers_from_any_server_version() {
+        let v2 = json!({ "framing": "length-prefixed" });
+        assert_eq!(Framing::from_hello(Some(&v2)), Framing::LengthPrefixed);
+        // Predates the handshake: answers every request like a preflight.
+        let v1 = json!({ "allow": true });
+        assert_eq!(Framing::from_hello(Some(&v1)), Framing::Newline);
+        let newer = json!({ "framing": "v3" });
+        assert_eq!(Framing::from_hello(Some(&newer)), Framing::Newline);
+        assert_eq!(Framing::from_hello(None), Framing::Newline);
+    }
+
+    #[test]
+    fn buffered_frames_are_taken_without_reading() {
+        let mut wire = Framing::Newline.encode(b"one", 64).unwrap();
+        wire.extend(Framing::Newline.encode(b"two", 64).unwrap());
+        let mut r = FrameReader::new(64);
+        let split_nl = |buf: &[u8]| split(Framing::Newline, buf, 64);
+        assert_eq!(r.take_by(split_nl).unwrap(), None);
+        r.fill(later(), &mut torn(&wire, 4096)).unwrap();
+        assert_eq!(r.take_by(split_nl).unwrap().unwrap(), b"one");
+        assert_eq!(r.take_by(split_nl).unwrap().unwrap(), b"two");
+        assert_eq!(r.take_by(split_nl).unwrap(), None);
+    }
+}
@@ -0,0 +1,199 @@
+//! Small allocation-free glob matcher for ignore patterns.
+//!
+//! - `*` matches within one path component, `?` one non-`/` character, `**` any
+//!   run of bytes including `/` (`**/` also matches zero components).
+//! - A pattern without `/` is matched against the file name (`*.log`).
+//! - A pattern starting with `/` is matched against the whole path.
+//! - Any other pattern may match at any component boundary, as if it were
+//!   prefixed with `**/` (`target/**`, `.git/index`).
+//!
+//! With `fold`, literals compare case-insensitively. Folding happens per
+//! character during the comparison, so nothing is lowercased up front.
+
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+pub(crate) fn matches(pattern: &str, path: &Path, fold: bool) -> bool {
+    let p = pattern.as_bytes();
+    let s = path.as_os_str().as_bytes();
+    if !p.contains(&b'/') {
+        let name = path.file_name().map_or(&[][..], |n| n.as_bytes());
+        return match_bytes(p, name, fold);
+    }
+    if p[0] == b'/' {
+        return match_bytes(p, s, fold);
+    }
+    if match_bytes(p, s, fold) {
+        return true;
+    }
+    s.iter()
+        .enumerate()
+        .filter(|&(_, &b)| b == b'/')
+        .any(|(i, _)| match_bytes(p, &s[i + 1..], fold))
+}
+
+/// Component-wise `path.starts_with(prefix)`, optionally case-folded.
+pub(crate) fn has_prefix(path: &Path, prefix: &Path, fold: bool) -> bool {
+    if !fold {
+        return path.starts_with(prefix);
+    }
+    let mut comps = path.components();
+    prefix.components().all(|want| {
+        comps.next().is_some_and(|got| {
+            let (a, b) = (got.as_os_str().as_bytes(), want.as_os_str().as_bytes());
+            eq_fold(a, b)
+        })
+    })
+}
+
+/// Component-wise `a == b`, optionally case-folded.
+pub(crate) fn same_path(a: &Path, b: &Path, fold: bool) -> bool {
+    a.components().count() == b.components().count() && has_prefix(a, b, fold)
+}
+
+/// Length of the UTF-8 sequence starting with `b` (1 for stray bytes, so
+/// non-UTF-8 input still advances byte by byte).
+fn char_len(b: u8) -> usize {
+    match b {
+        0xF0..=0xF7 => 4,
+        0xE0..=0xEF => 3,
+        0xC0..=0xDF => 2,
+        _ => 1,
+    }
+}
+
+/// Split the first character off `s`: its bytes and, if valid UTF-8, the char.
+fn first_char(s: &[u8]) -> (&[u8], Option<char>) {
+    let n = char_len(s[0]).min(s.len());
+    let c = std::str::from_utf8(&s[..n])
+        .ok()
+        .and_then(|c| c.chars().next());
+    (&s[..n], c)
+}
+
+/// If `p` and `s` start with the same character (case-folded when `fold`),
+/// the byte lengths of that character in each.
+fn literal_step(p: &[u8], s: &[u8], fold: bool) -> Option<(usize, usize)> {
+    let (&a, &b) = (p.first()?, s.first()?);
+    if a.is_ascii() || b.is_ascii() {
+        let same = if fold {
+            a.eq_ignore_ascii_case(&b)
+        } else {
+            a == b
+        };
+        return same.then_some((1, 1));
+    }
+    let ((pa, ca), (sb, cb)) = (first_char(p), first_char(s));
+    let same = match (ca, cb) {
+        (Some(x), Some(y)) if fold => x == y || x.to_lowercase().eq(y.to_lowercase()),
+        _ => pa == sb,
+    };
+    same.then_some((pa.len(), sb.len()))
+}
+
+fn eq_fold(a: &[u8], b: &[u8]) -> bool {
+    let (mut a, mut b) = (a, b);
+    while !a.is_empty() && !b.is_empty() {
+        let Some((i, j)) = literal_step(a, b, true) else {
+            return false;
+        };
+        a = &a[i..];
+        b = &b[j..];
+    }
+    a.is_empty() && b.is_empty()
+}
+
+fn match_bytes(p: &[u8], s: &[u8], fold: bool) -> bool {
+    match p {
+        [] => s.is_empty(),
+        [b'*', b'*', rest @ ..] => {
+            if let [b'/', after @ ..] = rest {
+                if match_bytes(after, s, fold) {
+                    return true;
+                }
+            }
+            (0..=s.len()).any(|i| match_bytes(rest, &s[i..], fold))
+        }
+        [b'*', rest @ ..] => {
+            for i in 0..=s.len() {
+                if match_bytes(rest, &s[i..], fold) {
+                    return true;
+                }
+                if s.get(i) == Some(&b'/') {
+                    break;
+                }
+            }
+            false
+        }
+        [b'?', rest @ ..] => match s {
+            [] | [b'/', ..] => false,
+            _ => match_bytes(rest, &s[first_char(s).0.len()..], fold),
+        },
+        _ => literal_step(p, s, fold).is_some_and(|(i, j)| match_bytes(&p[i..], &s[j..], fold)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn m(p: &str, s: &str) -> bool {
+        matches(p, Path::new(s), false)
+    }
+
+    fn mi(p: &str, s: &str) -> bool {
+        matches(p, Path::new(s), true)
+    }
+
+    #[test]
+    fn bare_patterns_match_the_file_name() {
+        assert!(m("*.log", "/work/build.log"));
+        assert!(m("build.?og", "/work/build.log"));
+        assert!(!m("*.log", "/work/build.log.txt"));
+        assert!(!m("*.log", "/logs.log/file"));
+    }
+
+    #[test]
+    fn star_stays_within_a_component() {
+        assert!(m("/work/*.rs", "/work/main.rs"));
+        assert!(!m("/work/*.rs", "/work/src/main.rs"));
+        assert!(m("/work/**/*.rs", "/work/src/bin/main.rs"));
+        assert!(m("/work/**/*.rs", "/work/main.rs"));
+    }
+
+    #[test]
+    fn relative_patterns_float() {
+        assert!(m("target/**", "/work/target/debug/x"));
+        assert!(m("**/node_modules/**", "/a/node_modules/b/c.js"));
+        assert!(m(".git/index", "/work/.git/index"));
+        assert!(!m("target/**", "/work/mytarget/x"));
+        assert!(!m("/target/**", "/work/target/x"));
+    }
+
+    #[test]
+    fn question_mark_takes_a_whole_character() {
+        assert!(m("caf?.txt", "/w/caf\u{e9}.txt"));
+    }
+
+    #[test]
+    fn folding_is_opt_in() {
+        assert!(!m("*.LOG", "/w/build.log"));
+        assert!(mi("*.LOG", "/w/build.log"));
+        assert!(mi("/Users/Me/**", "/users/me/project/x"));
+        assert!(mi("CAF\u{c9}*", "/w/caf\u{e9}.txt"));
+        assert!(!mi("*.log", "/w/build.lag"));
+    }
+
+    #[test]
+    fn prefix_checks_fold_by_component() {
+        let (root, path) = (
+            Path::new("/Users/Me/Project"),
+            Path::new("/users/me/project/f"),
+        );
+        assert!(!has_prefix(path, root, false));
+        assert!(has_prefix(path, root, true));
+        assert!(!has_prefix(Path::new("/users/me/projectx/f"), root, true));
+        assert!(same_path(Path::new("/users/me/project"), root, true));
+        assert!(!same_path(path, root, true));
+    }
+}
@@ -0,0 +1,2828 @@
+//! The hook bodies: what each intercepted call does around the real one.
+//!
+//! A handler asks before a change it may be refused, makes the call, and
+//! reports what it did. It makes the call through `RawIo`, learns what a
+//! path argument or an fd names through `PathResolver`, and reaches the
+//! server through `Transport`; the entry points in `lib.rs` pass `Live`,
+//! which is the platform layer and the control connections, and the tests
+//! here a fake that runs on any OS without the library loaded.
+//!
+//! What the handlers keep between calls (the fd table, the caches,
+//! `special`'s nodes) is still the process's own, so a test picks fds
+//! and paths no other test uses.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::os::raw::{c_char, c_int, c_void};
+use std::os::unix::prelude::RawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde_json::json;
+
+use crate::config::{
+    self, AclMode, AppendMode, NonblockingPreflight, TruncateKind, TruncatePolicy,
+};
+use crate::fdtable::{
+    debug_assert_foreign, mark_fd_dirty, take_fd, tracked_path, FdStat, FdState, PreOpen,
+    Truncation, WriteAt, FD_TABLE,
+};
+use crate::op_context::OpContext;
+use crate::{
+    acl, allow_cache, async_pre, backtrace, c_path, conflicts, contain, creds, denials, dir_cache,
+    dirty_age, exec_fds, inodes, is_inherited_fd, locks, note_denied, path_class, platform, probes,
+    procinfo, rename_chain, self_created, shutdown, special, summary, symlink_escape, take_dirty,
+    thread_info, touches, truncated, writers, xdev, AllowedBy, Decision, FAIL_CLOSED,
+    PRE_TIMEOUT_MS,
+};
+
+/// The real calls a handler makes on the caller's behalf, and its errno.
+pub(crate) trait RawIo {
+    /// `openat` when there is a `dirfd`, else `open`.
+    unsafe fn open(
+        &self,
+        dirfd: Option<c_int>,
+        path: *const c_char,
+        flags: c_int,
+        mode: libc::mode_t,
+    ) -> c_int;
+    unsafe fn write(&self, fd: c_int, buf: *const c_void, count: libc::size_t) -> libc::ssize_t;
+    unsafe fn pwrite(
+        &self,
+        fd: c_int,
+        buf: *const c_void,
+        count: libc::size_t,
+        offset: libc::off_t,
+    ) -> libc::ssize_t;
+    /// `writev`, or on Linux `pwritev` when there is an `offset`.
+    unsafe fn writev(
+        &self,
+        fd: c_int,
+        iov: *const libc::iovec,
+        iovcnt: c_int,
+        offset: Option<libc::off_t>,
+    ) -> libc::ssize_t;
+    unsafe fn close(&self, fd: c_int) -> c_int;
+    unsafe fn unlink(&self, path: *const c_char) -> c_int;
+    unsafe fn rename(&self, old: *const c_char, new: *const c_char) -> c_int;
+    #[cfg(target_os = "linux")]
+    unsafe fn unlinkat(&self, dirfd: c_int, path: *const c_char, flags: c_int) -> c_int;
+    /// `renameat2` when there are `flags`, else `renameat`.
+    #[cfg(target_os = "linux")]
+    unsafe fn renameat(
+        &self,
+        olddirfd: c_int,
+        old: *const c_char,
+        newdirfd: c_int,
+        new: *const c_char,
+        flags: Option<libc::c_uint>,
+    ) -> c_int;
+    #[cfg(target_os = "macos")]
+    unsafe fn copyfile(
+        &self,
+        from: *const c_char,
+        to: *const c_char,
+        state: *mut c_void,
+        flags: libc::copyfile_flags_t,
+    ) -> c_int;
+    #[cfg(target_os = "macos")]
+    unsafe fn removefile(&self, path: *const c_char, state: *mut c_void, flags: u32) -> c_int;
+    #[cfg(target_os = "macos")]
+    unsafe fn renameatx(
+        &self,
+        olddirfd: c_int,
+        old: *const c_char,
+        newdirfd: c_int,
+        new: *const c_char,
+        flags: libc::c_uint,
+    ) -> c_int;
+    unsafe fn ftruncate(&self, fd: c_int, len: libc::off_t) -> c_int;
+    unsafe fn truncate(&self, path: *const c_char, len: libc::off_t) -> c_int;
+    unsafe fn fflush(&self, stream: *mut libc::FILE) -> c_int;
+    unsafe fn shm_open(&self, name: *const c_char, oflag: c_int, mode: libc::mode_t) -> c_int;
+    unsafe fn shm_unlink(&self, name: *const c_char) -> c_int;
+    unsafe fn chdir(&self, path: *const c_char) -> c_int;
+    unsafe fn fchdir(&self, fd: c_int) -> c_int;
+    /// `fcntl(fd, F_GETFL)`.
+    fn status_flags(&self, fd: c_int) -> c_int;
+    fn errno(&self) -> c_int;
+    fn set_errno(&self, errno: c_int);
+}
+
+/// What the files behind path arguments and fds are.
+pub(crate) trait PathResolver {
+    /// The path `fd` is open on; `None` for a pipe, a socket, or one
+    /// whose name is gone.
+    fn fd_path(&self, fd: RawFd) -> Option<PathBuf>;
+    /// A relative `path` joined onto the cwd.
+    fn absolute(&self, path: PathBuf) -> PathBuf;
+    fn fd_stat(&self, fd: RawFd) -> Option<FdStat>;
+    /// The name of the filesystem `fd` is on, as `track_filesystems`
+    /// lists them.
+    fn fs_type(&self, fd: RawFd) -> Option<String>;
+    /// `fstatat` on what `path` (relative to `dirfd`, or the cwd) names,
+    /// following a symlink.
+    unsafe fn stat_at(&self, dirfd: Option<c_int>, path: *const c_char) -> Option<FdStat>;
+    /// `lstat`: the name itself, a symlink included.
+    fn lstat(&self, path: &Path) -> Option<FdStat>;
+    /// Where the shared memory object `name` lives, where it has a path.
+    fn shm_path(&self, name: *const c_char) -> Option<PathBuf>;
+
+    /// A path argument, absolute.
+    fn path(&self, ptr: *const c_char) -> Option<PathBuf> {
+        crate::c_path(ptr).map(|p| self.absolute(p))
+    }
+
+    /// A `*at()` path argument: absolute paths pass through, `AT_FDCWD`
+    /// joins onto the cwd (like the plain calls), otherwise onto the
+    /// dirfd's path.
+    fn path_at(&self, dirfd: c_int, ptr: *const c_char) -> Option<PathBuf> {
+        let p = crate::c_path(ptr)?;
+        if p.is_absolute() || dirfd == libc::AT_FDCWD {
+            return Some(self.absolute(p));
+        }
+        self.fd_path(dirfd).map(|dir| dir.join(p))
+    }
+}
+
+/// What a handler says to the server.
+pub(crate) trait Transport {
+    /// Ask about `op` on `path`, with `extra` in the params, and wait;
+    /// `None` refuses it.
+    fn preflight(
+        &self,
+        ctx: &OpContext,
+        op: &str,
+        path: &Path,
+        extra: serde_json::Value,
+    ) -> Option<Decision>;
+    /// Ask without waiting; see `preflight_async`.
+    fn preflight_async(
+        &self,
+        ctx: &OpContext,
+        op: &str,
+        path: &Path,
+        extra: serde_json::Value,
+        fd: Option<RawFd>,
+    ) -> (Option<u32>, Option<Decision>);
+    /// Take in the answers already waiting on `path`'s connection.
+    fn take_answers(&self, path: &Path);
+    fn post(&self, ctx: &OpContext, method: &str, params: serde_json::Value);
+    /// Send posts `take_dirty` took.
+    fn send_posts(&self, pending: Vec<(&'static str, serde_json::Value)>);
+    /// The posts for the dirty fds (or just `only`), taken and sent; see
+    /// `flush_dirty`.
+    fn flush(&self, only: Option<RawFd>);
+    /// Everything pending, as `shim/flush` sends it; see `flush_now`.
+    fn flush_all(&self);
+    /// A `shim/*_call` event, sent only with `FS_SHIM_DEBUG`.
+    fn debug(&self, method: &str, params: serde_json::Value);
+}
+
+/// All three, which is what a handler is given.
+pub(crate) trait Host: RawIo + PathResolver + Transport {}
+
+impl<T: RawIo + PathResolver + Transport> Host for T {}
+
+/// The platform layer and the control connections.
+pub(crate) struct Live;
+
+impl RawIo for Live {
+    unsafe fn open(
+        &self,
+        dirfd: Option<c_int>,
+        path: *const c_char,
+        flags: c_int,
+        mode: libc::mode_t,
+    ) -> c_int {
+        unsafe {
+            match dirfd {
+                Some(dirfd) => platform::sys_openat(dirfd, path, flags, mode),
+                None => platform::sys_open(path, flags, mode),
+            }
+        }
+    }
+
+    unsafe fn write(&self, fd: c_int, buf: *const c_void, count: libc::size_t) -> libc::ssize_t {
+        unsafe { platform::sys_write(fd, buf, count) }
+    }
+
+    unsafe fn pwrite(
+        &self,
+        fd: c_int,
+        buf: *const c_void,
+        count: libc::size_t,
+        offset: libc::off_t,
+    ) -> libc::ssize_t {
+        unsafe { platform::sys_pwrite(fd, buf, count, offset) }
+    }
+
+    unsafe fn writev(
+        &self,
+        fd: c_int,
+        iov: *const libc::iovec,
+        iovcnt: c_int,
+        offset: Option<libc::off_t>,
+    ) -> libc::ssize_t {
+        unsafe {
+            match offset {
+                #[cfg(target_os = "linux")]
+                Some(offset) => platform::sys_pwritev(fd, iov, iovcnt, offset),
+                _ => platform::sys_writev(fd, iov, iovcnt),
+            }
+        }
+    }
+
+    unsafe fn close(&self, fd: c_int) -> c_int {
+        unsafe { platform::sys_close(fd) }
+    }
+
+    unsafe fn unlink(&self, path: *const c_char) -> c_int {
+        unsafe { platform::sys_unlink(path) }
+    }
+
+    unsafe fn rename(&self, old: *const c_char, new: *const c_char) -> c_int {
+        unsafe { platform::sys_rename(old, new) }
+    }
+
+    #[cfg(target_os = "linux")]
+    unsafe fn unlinkat(&self, dirfd: c_int, path: *const c_char, flags: c_int) -> c_int {
+        unsafe { platform::sys_unlinkat(dirfd, path, flags) }
+    }
+
+    #[cfg(target_os = "linux")]
+    unsafe fn renameat(
+        &self,
+        olddirfd: c_int,
+        old: *const c_char,
+        newdirfd: c_int,
+        new: *const c_char,
+        flags: Option<libc::c_uint>,
+    ) -> c_int {
+        unsafe {
+            match flags {
+                Some(f) => platform::sys_renameat2(olddirfd, old, newdirfd, new, f),
+                None => platform::sys_renameat(olddirfd, old, newdirfd, new),
+            }
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    unsafe fn copyfile(
+        &self,
+        from: *const c_char,
+        to: *const c_char,
+        state: *mut c_void,
+        flags: libc::copyfile_flags_t,
+    ) -> c_int {
+        unsafe { platform::sys_copyfile(from, to, state, flags) }
+    }
+
+    #[cfg(target_os = "macos")]
+    unsafe fn removefile(&self, path: *const c_char, state: *mut c_void, flags: u32) -> c_int {
+        unsafe { platform::sys_removefile(path, state, flags) }
+    }
+
+    #[cfg(target_os = "macos")]
+    unsafe fn renameatx(
+        &self,
+        olddirfd: c_int,
+        old: *const c_char,
+        newdirfd: c_int,
+        new: *const c_char,
+        flags: libc::c_uint,
+    ) -> c_int {
+        unsafe { platform::sys_renameatx(olddirfd, old, newdirfd, new, flags) }
+    }
+
+    unsafe fn ftruncate(&self, fd: c_int, len: libc::off_t) -> c_int {
+        unsafe { platform::sys_ftruncate(fd, len) }
+    }
+
+    unsafe fn truncate(&self, path: *const c_char, len: libc::off_t) -> c_int {
+        unsafe { platform::sys_truncate(path, len) }
+    }
+
+    unsafe fn fflush(&self, stream: *mut libc::FILE) -> c_int {
+        unsafe { platform::sys_fflush(stream) }
+    }
+
+    unsafe fn shm_open(&self, name: *const c_char, oflag: c_int, mode: libc::mode_t) -> c_int {
+        unsafe { platform::sys_shm_open(name, oflag, mode) }
+    }
+
+    unsafe fn shm_unlink(&self, name: *const c_char) -> c_int {
+        unsafe { platform::sys_shm_unlink(name) }
+    }
+
+    unsafe fn chdir(&self, path: *const c_char) -> c_int {
+        unsafe { platform::sys_chdir(path) }
+    }
+
+    unsafe fn fchdir(&self, fd: c_int) -> c_int {
+        unsafe { platform::sys_fchdir(fd) }
+    }
+
+    fn status_flags(&self, fd: c_int) -> c_int {
+        unsafe { libc::fcntl(fd, libc::F_GETFL) }
+    }
+
+    fn errno(&self) -> c_int {
+        std::io::Error::last_os_error().raw_os_error().unwrap_or(0)
+    }
+
+    fn set_errno(&self, errno: c_int) {
+        platform::set_errno(errno);
+    }
+}
+
+impl PathResolver for Live {
+    fn fd_path(&self, fd: RawFd) -> Option<PathBuf> {
+        platform::fd_path(fd)
+    }
+
+    fn absolute(&self, path: PathBuf) -> PathBuf {
+        crate::absolute(path)
+    }
+
+    fn fd_stat(&self, fd: RawFd) -> Option<FdStat> {
+        crate::fdtable::fd_stat(fd)
+    }
+
+    fn fs_type(&self, fd: RawFd) -> Option<String> {
+        platform::fd_fs_type(fd)
+    }
+
+    unsafe fn stat_at(&self, dirfd: Option<c_int>, path: *const c_char) -> Option<FdStat> {
+        unsafe { crate::fdtable::stat_at(dirfd, path) }
+    }
+
+    fn lstat(&self, path: &Path) -> Option<FdStat> {
+        path.symlink_metadata()
+            .ok()
+            .map(|m| FdStat::of_metadata(&m))
+    }
+
+    fn shm_path(&self, name: *const c_char) -> Option<PathBuf> {
+        platform::shm_path(name)
+    }
+}
+
+impl Transport for Live {
+    fn preflight(
+        &self,
+        ctx: &OpContext,
+        op: &str,
+        path: &Path,
+        extra: serde_json::Value,
+    ) -> Option<Decision> {
+        crate::preflight_with(ctx, op, path, extra)
+    }
+
+    fn preflight_async(
+        &self,
+        ctx: &OpContext,
+        op: &str,
+        path: &Path,
+        extra: serde_json::Value,
+        fd: Option<RawFd>,
+    ) -> (Option<u32>, Option<Decision>) {
+        crate::preflight_async(ctx, op, path, extra, fd)
+    }
+
+    fn take_answers(&self, path: &Path) {
+        let _ = crate::with_stream_for(path, |_| ());
+    }
+
+    fn post(&self, ctx: &OpContext, method: &str, params: serde_json::Value) {
+        crate::post_notify(ctx, method, params);
+    }
+
+    fn send_posts(&self, pending: Vec<(&'static str, serde_json::Value)>) {
+        let _ = crate::with_thread_stream(|conn| crate::send_posts(conn, pending));
+    }
+
+    fn flush(&self, only: Option<RawFd>) {
+        let _ = crate::with_thread_stream(|conn| crate::flush_dirty(conn, only));
+    }
+
+    fn flush_all(&self) {
+        let _ = crate::with_thread_stream(crate::flush_now);
+    }
+
+    fn debug(&self, method: &str, params: serde_json::Value) {
+        crate::debug_event(method, params);
+    }
+}
+
+/// Send the post for every fd dirty past `max_dirty_age_ms`, from a hook
+/// before its call; see `dirty_age`.
+fn flush_aged(net: &impl Transport) {
+    let Some(now) = dirty_age::due() else {
+        return;
+    };
+    let pending = take_dirty(
+        |_, s| dirty_age::aged(s.dirty_since, now),
+        Some("age_flush"),
+    );
+    if !pending.is_empty() {
+        net.send_posts(pending);
+    }
+}
+
+/// Preflight `fd`'s first write, and refuse later ones that an async
+/// preflight hasn't cleared. `Err` has the errno to fail with.
+fn maybe_pre_on_first_write(h: &impl Host, ctx: &OpContext, fd: c_int) -> Result<(), c_int> {
+    let cfg = config::get();
+    if async_pre::outstanding() {
+        let asked = FD_TABLE
+            .lock()
+            .get(&fd)
+            .filter(|e| e.asked.is_some())
+            .and_then(|e| e.path.clone());
+        if let Some(p) = asked {
+            // Its answer may be waiting on this thread's connection.
+            h.take_answers(&p);
+        }
+    }
+    let (path_opt, send_pre, method, mut extra, nonblocking) = {
+        let mut t = FD_TABLE.lock();
+        let e = match t.entry(fd) {
+            Entry::Occupied(e) if e.get().size_before.is_some() => e.into_mut(),
+            _ if special::is_fd(fd) => return Ok(()),
+            entry => {
+                // First sight: one fstat gives type, identity and size.
+                let Some(st) = h.fd_stat(fd).filter(|st| st.regular) else {
+                    return Ok(());
+                };
+                let e = entry.or_insert_with(|| FdState {
+                    path: h.fd_path(fd),
+                    ..FdState::default()
+                });
+                e.apply_stat(st);
+                e
+            }
+        };
+        if e.path.is_none() {
+            e.path = h.fd_path(fd);
+        }
+        let mut getfl = None;
+        if e.open_flags.is_none() {
+            // Opened before we were loaded, or through a path we don't hook.
+            let fl = h.status_flags(fd);
+            e.append = fl & libc::O_APPEND != 0;
+            e.nonblocking = fl & libc::O_NONBLOCK != 0;
+            getfl = Some(fl);
+        }
+        if let Some((_, at)) = e.asked {
+            if at.elapsed() >= Duration::from_millis(*PRE_TIMEOUT_MS) {
+                // Unanswered in time: the fail policy, and a late answer is dropped.
+                e.asked = None;
+                e.denied = *FAIL_CLOSED;
+                if e.denied {
+                    let (path, op) = (e.path.clone(), e.events().0);
+                    drop(t);
+                    if let Some(p) = path {
+                        denials::record(op, &p, denials::Reason::TransportDown);
+                    }
+                    return Err(libc::EPERM);
+                }
+            } else if *FAIL_CLOSED {
+                return Err(libc::EAGAIN);
+            }
+        }
+        if e.denied {
+            return Err(libc::EPERM);
+        }
+        if !e.pre_sent {
+            e.first_sight(|| h.fs_type(fd), cfg);
+            if let (Some(from), Some(to)) = (&e.escape, &e.path) {
+                if cfg.symlink_escape == config::SymlinkEscape::Block {
+                    // No answer could let it through, so nobody is asked; the
+                    // post still names the escape.
+                    e.denied = true;
+                    let (method, to) = (e.events().0, to.clone());
+                    let escape = symlink_escape::params(from, &to, cfg.symlink_escape);
+                    drop(t);
+                    note_denied(method, &to, None);
+                    let params = json!({
+                        "path": to.to_string_lossy(),
+                        "bytes": 0,
+                        "symlink_escape": escape,
+                    });
+                    h.post(ctx, "post_modify", Decision::default().annotate(params));
+                    return Err(libc::EPERM);
+                }
+            }
+            let conflict = e.path.as_deref().is_some_and(conflicts::contains);
+            let strict = e.path.as_deref().is_some_and(path_class::strict);
+            e.self_created = cfg.trust_self_created
+                && !e.created
+                && e.path
+                    .as_deref()
+                    .is_some_and(|p| self_created::holds(e.dev, e.ino, p, e.uid));
+            // Appends (logs, `>>`) only get post events unless configured
+            // to block, and so do files this process made.
+            let quiet = e.append && cfg.append_mode != AppendMode::Block || e.self_created;
+            let ask = !e.ignored && !e.notify_only && (!quiet || conflict || strict)
+                || e.escape.is_some();
+            // Writing back what an allowed path truncate cut; see `truncated`.
+            let covered = ask
+                && !conflict
+                && e.escape.is_none()
+                && e.path
+                    .as_deref()
+                    .is_some_and(|p| truncated::take(e.dev, e.ino, p));
+            if covered {
+                e.decision.by = Some(AllowedBy::Server);
+            }
+            let ask = ask && !covered;
+            let extra = if ask {
+                let mut extra = modify_preflight_extra(e, getfl, cfg);
+                if conflict {
+                    extra["conflict"] = json!(true);
+                }
+                if let (Some(from), Some(to)) = (&e.escape, &e.path) {
+                    extra["symlink_escape"] = symlink_escape::params(from, to, cfg.symlink_escape);
+                }
+                extra
+            } else {
+                serde_json::Value::Null
+            };
+            (e.path.clone(), ask, e.events().0, extra, e.nonblocking)
+        } else {
+            (e.path.clone(), false, "", serde_json::Value::Null, false)
+        }
+    };
+
+    if send_pre {
+        if let Some(ref p) = path_opt {
+            if cfg.captures_backtrace(p) {
+                extra["backtrace"] = backtrace::capture();
+            }
+            let escaped = extra["symlink_escape"].is_object();
+            let mode = if extra["conflict"] == true || escaped {
+                None
+            } else if thread_info::on_reactor() {
+                Some(NonblockingPreflight::Async)
+            } else {
+                nonblocking.then_some(cfg.nonblocking_preflight)
+            };
+            let (asked, decision) = match mode {
+                Some(NonblockingPreflight::Async) => {
+                    h.preflight_async(ctx, method, p, extra, Some(fd))
+                }
+                _ => (None, h.preflight(ctx, method, p, extra)),
+            };
+            if let Some(e) = FD_TABLE.lock().get_mut(&fd) {
+                e.pre_mode = mode;
+            }
+            let Some(decision) = decision else {
+                return Err(if asked.is_some() {
+                    libc::EAGAIN
+                } else {
+                    libc::EPERM
+                });
+            };
+            note_decision(fd, decision);
+            if method == "pre_create" && cfg.trust_self_created {
+                if let Some(e) = FD_TABLE.lock().get(&fd) {
+                    self_created::created(e.dev, e.ino, p, e.uid);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Apply the answer to a `preflight_async`. It is dropped for an fd that
+/// was closed meanwhile (or whose number now belongs to another open), or
+/// that gave up waiting; an allowal is cached all the same.
+pub(crate) fn settle_async(answer: async_pre::Answer) {
+    match answer.allow {
+        Some(true) => allow_cache::insert(&answer.op, &answer.path),
+        Some(false) => note_denied(&answer.op, &answer.path, answer.seq),
+        None if *FAIL_CLOSED => note_denied(&answer.op, &answer.path, answer.seq),
+        None => {}
+    }
+    let Some(fd) = answer.fd else {
+        return;
+    };
+    let mut t = FD_TABLE.lock();
+    let Some(e) = t
+        .get_mut(&fd)
+        .filter(|e| e.asked.is_some_and(|(id, _)| id == answer.id))
+    else {
+        return;
+    };
+    e.asked = None;
+    match answer.allow {
+        Some(true) => e.decision.by = Some(AllowedBy::Server),
+        Some(false) => e.denied = true,
+        None => e.denied = *FAIL_CLOSED,
+    }
+}
+
+/// Keep how `fd`'s preflight was allowed for the posts that follow.
+fn note_decision(fd: RawFd, decision: Decision) {
+    if let Some(e) = FD_TABLE.lock().get_mut(&fd) {
+        e.decision = decision;
+    }
+}
+
+/// How the file is being opened, for `pre_modify` (or `pre_create`). `"source": "open"`
+/// when the open hook saw the flags and mode; otherwise `"first_write"`,
+/// with the flags `F_GETFL` still reports (`getfl`) and the mode and size
+/// from `fstat`.
+fn modify_preflight_extra(
+    e: &FdState,
+    getfl: Option<c_int>,
+    cfg: &config::ShimConfig,
+) -> serde_json::Value {
+    let mut extra = match (e.open_flags, getfl) {
+        (Some(flags), _) => {
+            let existed = e.pre_open.is_none_or(|p| p.existed);
+            let mut extra = json!({
+                "source": "open",
+                "flags": open_flag_names(flags),
+                "existing": existed,
+            });
+            if let Some(mode) = e.open_mode {
+                extra["mode"] = json!(format!("{:04o}", mode & 0o7777));
+            }
+            if existed {
+                let size = e.pre_open.map(|p| p.size).or(e.size_before);
+                extra["size"] = json!(size.unwrap_or(0));
+            }
+            extra
+        }
+        (None, fl) => json!({
+            "source": "first_write",
+            "flags": fl.map(open_flag_names),
+            "mode": format!("{:04o}", e.perm),
+            "existing": true,
+            "size": e.size_before.unwrap_or(0),
+        }),
+    };
+    // Large files: sizes only, so the server skips pre-image capture.
+    if e.is_large(cfg) {
+        extra["large_file"] = json!(true);
+        extra["size"] = json!(e.size_before.unwrap_or(0));
+    }
+    extra
+}
+
+/// Symbolic names for the `open(2)` flags worth showing someone asked to
+/// approve a write, access mode first.
+fn open_flag_names(flags: c_int) -> Vec<&'static str> {
+    let access = match flags & libc::O_ACCMODE {
+        libc::O_RDONLY => "O_RDONLY",
+        libc::O_WRONLY => "O_WRONLY",
+        _ => "O_RDWR",
+    };
+    let named = [
+        (libc::O_CREAT, "O_CREAT"),
+        (libc::O_EXCL, "O_EXCL"),
+        (libc::O_TRUNC, "O_TRUNC"),
+        (libc::O_APPEND, "O_APPEND"),
+        (libc::O_NOFOLLOW, "O_NOFOLLOW"),
+        (libc::O_CLOEXEC, "O_CLOEXEC"),
+        (libc::O_NONBLOCK, "O_NONBLOCK"),
+        (libc::O_SYNC, "O_SYNC"),
+    ];
+    std::iter::once(access)
+        .chain(
+            named
+                .into_iter()
+                .filter(|&(bit, _)| flags & bit == bit)
+                .map(|(_, name)| name),
+        )
+        .collect()
+}
+
+/// Shared body of the `open`/`openat` hooks. `mode` is only `Some` when the
+/// flags make the caller pass one (`platform::open_needs_mode`). Writable
+/// regular-file fds get their open flags recorded; the `pre_modify` still
+/// waits for the first write.
+pub(crate) unsafe fn tracked_open(
+    h: &impl Host,
+    dirfd: Option<c_int>,
+    path: *const c_char,
+    flags: c_int,
+    mode: Option<libc::mode_t>,
+) -> c_int {
+    let ctx = OpContext::enter();
+    let raw_mode = mode.unwrap_or(0);
+    let writable = flags & libc::O_ACCMODE != libc::O_RDONLY;
+    if ctx.enabled() && ctx.is_outermost() {
+        flush_aged(h);
+    }
+
+    // Creating or truncating destroys what `pre_modify` wants to report, so
+    // look first.
+    let pre_open = (ctx.enabled()
+        && ctx.is_outermost()
+        && writable
+        && flags & (libc::O_CREAT | libc::O_TRUNC) != 0)
+        .then(|| {
+            let st = unsafe { h.stat_at(dirfd, path) };
+            PreOpen {
+                existed: st.is_some(),
+                size: st.map_or(0, |st| st.size),
+            }
+        });
+
+    let fd = contain::ran(unsafe { h.open(dirfd, path, flags, raw_mode) });
+
+    if !ctx.enabled() || !ctx.is_outermost() {
+        return fd;
+    }
+    if fd < 0 {
+        let excl = libc::O_CREAT | libc::O_EXCL;
+        let errno = h.errno();
+        if flags & excl == excl && errno == libc::EEXIST && !config::get().sensitive.is_empty() {
+            let path = match dirfd {
+                Some(dirfd) => h.path_at(dirfd, path),
+                None => h.path(path),
+            };
+            if let Some(params) = path.and_then(|p| probes::failed(&p)) {
+                h.post(&ctx, "shim/probe", params);
+            }
+            h.set_errno(errno);
+        }
+        return fd;
+    }
+
+    // A node `special` knows of needs no look to tell it isn't a file.
+    special::forget_fd(fd);
+    let known_node = writable
+        && special::any()
+        && match dirfd {
+            Some(dirfd) => h.path_at(dirfd, path),
+            None => h.path(path),
+        }
+        .is_some_and(|p| special::is_path(&p));
+    let st = if writable && !known_node {
+        h.fd_stat(fd)
+    } else {
+        None
+    };
+    if let Some(st) = st.filter(|st| st.regular) {
+        // The stat and the open aren't atomic. A file someone else created
+        // in between already has content; then this open made nothing.
+        let created = flags & libc::O_CREAT != 0
+            && pre_open.is_some_and(|p| !p.existed)
+            && (flags & libc::O_EXCL != 0 || st.size == 0);
+        let mut state = FdState {
+            path: h.fd_path(fd),
+            open_flags: Some(flags),
+            open_mode: mode,
+            append: flags & libc::O_APPEND != 0,
+            nonblocking: flags & libc::O_NONBLOCK != 0,
+            pre_open,
+            created,
+            opened_ms: Some(writers::now_ms()),
+            ..FdState::default()
+        };
+        state.apply_stat(st);
+        if config::get().symlink_escape != config::SymlinkEscape::Off && symlink_escape::checking()
+        {
+            let raw = match dirfd {
+                Some(dirfd) => h.path_at(dirfd, path),
+                None => h.path(path),
+            };
+            if let (Some(raw), Some(landed)) = (raw, state.path.as_deref()) {
+                state.escape = symlink_escape::check(&raw, landed);
+            }
+        }
+        FD_TABLE.lock().insert(fd, state);
+    } else {
+        // A reused fd number must not inherit a stale entry whose close we
+        // never saw.
+        FD_TABLE.lock().remove(&fd);
+        if known_node {
+            special::opened(fd);
+        }
+    }
+
+    h.debug(
+        "shim/open_call",
+        json!({
+            "fd": fd,
+            "flags": flags,
+            "mode": mode,
+            "path": c_path(path).map(|p| p.to_string_lossy().to_string()),
+        }),
+    );
+    fd
+}
+
+pub(crate) unsafe fn tracked_write(
+    h: &impl Host,
+    fd: c_int,
+    buf: *const c_void,
+    count: libc::size_t,
+) -> libc::ssize_t {
+    let ctx = OpContext::enter();
+
+    if !ctx.enabled() {
+        return unsafe { h.write(fd, buf, count) };
+    }
+    debug_assert_foreign(fd);
+
+    if ctx.is_outermost() {
+        flush_aged(h);
+    }
+    if ctx.is_outermost() && count > 0 {
+        if let Err(errno) = maybe_pre_on_first_write(h, &ctx, fd) {
+            h.set_errno(errno);
+            return -1;
+        }
+    }
+
+    let res = contain::ran(unsafe { h.write(fd, buf, count) });
+
+    if ctx.is_outermost() && res > 0 {
+        mark_fd_dirty(fd, Some(WriteAt::Position), count as u64, res as u64);
+        h.debug(
+            "shim/write_call",
+            json!({ "fd": fd, "count": count, "res": res, "tracked_path": tracked_path(fd)}),
+        );
+    }
+    res
+}
+
+pub(crate) unsafe fn tracked_pwrite(
+    h: &impl Host,
+    fd: c_int,
+    buf: *const c_void,
+    count: libc::size_t,
+    offset: libc::off_t,
+) -> libc::ssize_t {
+    let ctx = OpContext::enter();
+
+    if !ctx.enabled() {
+        return unsafe { h.pwrite(fd, buf, count, offset) };
+    }
+    debug_assert_foreign(fd);
+
+    if ctx.is_outermost() {
+        flush_aged(h);
+    }
+    if ctx.is_outermost() && count > 0 {
+        if let Err(errno) = maybe_pre_on_first_write(h, &ctx, fd) {
+            h.set_errno(errno);
+            return -1;
+        }
+    }
+
+    let res = contain::ran(unsafe { h.pwrite(fd, buf, count, offset) });
+
+    if ctx.is_outermost() && res > 0 {
+        mark_fd_dirty(
+            fd,
+            Some(WriteAt::Offset(offset as u64)),
+            count as u64,
+            res as u64,
+        );
+        h.debug(
+            "shim/pwrite_call",
+            json!({ "fd": fd, "count": count, "res": res, "tracked_path": tracked_path(fd)}),
+        );
+    }
+    res
+}
+
+/// Past this many iovecs the kernel refuses the call (`EINVAL`), so the
+/// array isn't read.
+const IOV_MAX: c_int = 1024;
+
+/// The bytes an iovec array asks to write. Nothing for a null array or a
+/// count outside `1..=IOV_MAX`, which the call itself will reject, and
+/// the sum saturates rather than overflowing.
+unsafe fn iov_total(iov: *const libc::iovec, iovcnt: c_int) -> u64 {
+    if iov.is_null() || !(1..=IOV_MAX).contains(&iovcnt) {
+        return 0;
+    }
+    let iovs = unsafe { std::slice::from_raw_parts(iov, iovcnt as usize) };
+    iovs.iter()
+        .fold(0u64, |n, v| n.saturating_add(v.iov_len as u64))
+}
+
+/// `writev`, and on Linux `pwritev` when there is an `offset`. Only an
+/// array that carries bytes is preflighted, and only a call that wrote
+/// some marks the fd dirty.
+pub(crate) unsafe fn tracked_writev(
+    h: &impl Host,
+    fd: c_int,
+    iov: *const libc::iovec,
+    iovcnt: c_int,
+    offset: Option<libc::off_t>,
+) -> libc::ssize_t {
+    let call = || unsafe { h.writev(fd, iov, iovcnt, offset) };
+    let ctx = OpContext::enter();
+
+    if !ctx.enabled() {
+        return call();
+    }
+    debug_assert_foreign(fd);
+
+    if ctx.is_outermost() {
+        flush_aged(h);
+    }
+    let requested = unsafe { iov_total(iov, iovcnt) };
+    if ctx.is_outermost() && requested > 0 {
+        if let Err(errno) = maybe_pre_on_first_write(h, &ctx, fd) {
+            h.set_errno(errno);
+            return -1;
+        }
+    }
+
+    let res = contain::ran(call());
+
+    if ctx.is_outermost() && res > 0 {
+        let at = offset.map_or(WriteAt::Position, |o| WriteAt::Offset(o as u64));
+        mark_fd_dirty(fd, Some(at), requested, res as u64);
+        let method = if offset.is_some() {
+            "shim/pwritev_call"
+        } else {
+            "shim/writev_call"
+        };
+        h.debug(
+            method,
+            json!({
+                "fd": fd,
+                "iovcnt": iovcnt,
+                "requested": requested,
+                "written": res,
+                "res": res,
+                "tracked_path": tracked_path(fd)
+            }),
+        );
+    }
+    res
+}
+
+static APPEND_POSTS: Lazy<Mutex<HashMap<PathBuf, Instant>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Whether an append session on `path` should be reported: never with
+/// `append_mode = "off"`, and at most once per `append_debounce_ms`.
+fn append_post_due(path: &Path) -> bool {
+    let cfg = config::get();
+    if cfg.append_mode == AppendMode::Off {
+        return false;
+    }
+    if cfg.append_debounce_ms == 0 {
+        return true;
+    }
+    let window = Duration::from_millis(cfg.append_debounce_ms);
+    let now = Instant::now();
+    let mut last = APPEND_POSTS.lock();
+    if last
+        .get(path)
+        .is_some_and(|t| now.duration_since(*t) < window)
+    {
+        return false;
+    }
+    if last.len() >= 256 {
+        last.retain(|_, t| now.duration_since(*t) < window);
+    }
+    last.insert(path.to_path_buf(), now);
+    true
+}
+
+/// The path `close` should send `post_modify` for, given the state it took.
+/// `EINTR` still releases the fd on both platforms, so it counts as closed.
+fn close_post_path(rc: c_int, errno: c_int, state: Option<&FdState>) -> Option<&Path> {
+    let closed = rc == 0 || errno == libc::EINTR;
+    state
+        .filter(|s| closed && s.dirty && !s.ignored)
+        .and_then(|s| s.path.as_deref())
+}
+
+/// Make live fds on paths `select` picks preflight again at their next
+/// write or truncate; returns how many. For `shim/invalidate`.
+pub(crate) fn rearm_preflights(mut select: impl FnMut(&Path) -> bool) -> usize {
+    let mut n = 0;
+    for s in FD_TABLE.lock().values_mut() {
+        if s.pre_sent && s.path.as_deref().is_some_and(&mut select) {
+            s.pre_sent = false;
+            n += 1;
+        }
+    }
+    n
+}
+
+/// `post_modify` (or `post_create`) params for what `s` saw happen to
+/// `path`. A creation also reports the mode it asked for, the umask, and
+/// the size and permissions `last` (an `fstat` before the close) found.
+pub(crate) fn modify_params(
+    s: &FdState,
+    path: &Path,
+    cfg: &config::ShimConfig,
+    last: Option<FdStat>,
+) -> serde_json::Value {
+    let mut params = s.decision.annotate(json!({
+        "path": path.to_string_lossy(),
+        "bytes": s.bytes,
+        "dev": s.dev,
+        "ino": s.ino,
+    }));
+    if let Some(at) = s.opened_ms {
+        params["opened_ms"] = json!(at);
+    }
+    s.writer.annotate(&mut params);
+    if s.created {
+        if let Some(mode) = s.open_mode {
+            params["mode"] = json!(format!("{:04o}", mode & 0o7777));
+        }
+        if let Some(umask) = procinfo::umask() {
+            params["umask"] = json!(format!("{umask:04o}"));
+        }
+        params["size"] = json!(last.map(|st| st.size));
+        if let Some(st) = last {
+            params["final_mode"] = json!(format!("{:04o}", st.perm));
+        }
+    }
+    if s.append {
+        params["append"] = json!(true);
+    }
+    if s.self_created {
+        params["self_created"] = json!(true);
+    }
+    if let Some(from) = &s.escape {
+        params["symlink_escape"] = symlink_escape::params(from, path, cfg.symlink_escape);
+    }
+    if s.requested != s.bytes {
+        params["requested"] = json!(s.requested);
+    }
+    if let (false, Some(shape)) = (s.created, s.shape) {
+        params["write_shape"] = json!(shape.name());
+    }
+    if s.tier.is_some_and(|t| !t.content_features()) {
+        params["content_features_skipped"] = json!(true);
+    }
+    match s.pre_mode {
+        Some(NonblockingPreflight::Async) => params["preflight_mode"] = json!("async"),
+        Some(NonblockingPreflight::Block) => params["preflight_mode"] = json!("block"),
+        None => {}
+    }
+    if s.is_large(cfg) {
+        params["large_file"] = json!(true);
+        params["size_before"] = json!(s.size_before);
+    }
+    if let Some(t) = s.truncated {
+        params["truncate"] = t.params();
+    }
+    params
+}
+
+pub(crate) unsafe fn tracked_close(h: &impl Host, fd: c_int) -> c_int {
+    // The inherited control fd belongs to the shim; programs that close every
+    // fd on startup must not cut the channel.
+    if is_inherited_fd(fd) {
+        return 0;
+    }
+    let ctx = OpContext::enter();
+
+    if !ctx.enabled() || !ctx.is_outermost() {
+        return unsafe { h.close(fd) };
+    }
+    debug_assert_foreign(fd);
+
+    // Take the state exactly once, before the fd number can be reused by a
+    // concurrent open; the post and debug events both report from it.
+    let state = take_fd(fd);
+    // After the take, so this fd's own post is the close's.
+    flush_aged(h);
+    let last = state
+        .as_ref()
+        .filter(|s| s.created && s.dirty)
+        .and_then(|_| h.fd_stat(fd));
+    let rc = contain::ran(unsafe { h.close(fd) });
+    let errno = if rc == 0 { 0 } else { h.errno() };
+
+    if let (Some(p), Some(s)) = (close_post_path(rc, errno, state.as_ref()), &state) {
+        if !s.append || append_post_due(p) {
+            h.post(&ctx, s.events().1, modify_params(s, p, config::get(), last));
+        }
+    }
+    if let Some(params) = locks::closed(fd) {
+        h.post(&ctx, "post_unlock", params);
+    }
+    h.debug(
+        "shim/close_call",
+        json!({
+            "fd": fd,
+            "rc": rc,
+            "errno": errno,
+            "tracked_path": state.as_ref().and_then(|s| s.path.as_ref()).map(|p| p.to_string_lossy()),
+            "dirty": state.as_ref().map(|s| s.dirty),
+            "bytes": state.as_ref().map(|s| s.bytes),
+            "open_flags": state.as_ref().and_then(|s| s.open_flags),
+            "open_mode": state.as_ref().and_then(|s| s.open_mode),
+        }),
+    );
+
+    if rc != 0 {
+        // The notifications above may have clobbered it.
+        h.set_errno(errno);
+    }
+    rc
+}
+
+pub(crate) unsafe fn tracked_unlink(h: &impl Host, path: *const c_char) -> c_int {
+    let ctx = OpContext::enter();
+
+    if !ctx.enabled() {
+        return unsafe { h.unlink(path) };
+    }
+
+    let pbuf = h.path(path);
+    let mut decision = Decision::default();
+    if ctx.is_outermost() {
+        if let Some(ref p) = pbuf {
+            let Some(d) = h.preflight(&ctx, "pre_delete", p, json!({})) else {
+                h.set_errno(libc::EPERM);
+                return -1;
+            };
+            decision = d;
+        }
+    }
+
+    let rc = contain::ran(unsafe { h.unlink(path) });
+
+    if ctx.is_outermost() && rc == 0 {
+        if let Some(p) = pbuf {
+            post_delete(h, &ctx, &p, decision);
+        }
+        h.debug(
+            "shim/unlink_call",
+            json!({ "rc": rc, "path": c_path(path).map(|p| p.to_string_lossy().to_string()) }),
+        );
+    }
+
+    rc
+}
+
+pub(crate) unsafe fn tracked_rename(
+    h: &impl Host,
+    old: *const c_char,
+    new: *const c_char,
+) -> c_int {
+    let ctx = OpContext::enter();
+
+    if !ctx.enabled() {
+        return unsafe { h.rename(old, new) };
+    }
+
+    let oldp = h.path(old);
+    let newp = h.path(new);
+
+    let mut replaces = true;
+    let mut decision = Decision::default();
+    if ctx.is_outermost() {
+        if let Some(ref to) = newp {
+            let dest = h.lstat(to);
+            let extra = rename_extra(oldp.as_deref(), dest);
+            let Some(d) = h.preflight(&ctx, "pre_rename", to, extra) else {
+                h.set_errno(libc::EPERM);
+                return -1;
+            };
+            decision = d;
+            replaces = dest.is_some();
+        }
+    }
+
+    let rc = contain::ran(unsafe { h.rename(old, new) });
+    if ctx.is_outermost() && rc != 0 {
+        note_cross_device(h, oldp.as_deref(), newp.as_deref());
+    }
+
+    if ctx.is_outermost() && rc == 0 {
+        renamed(oldp.as_deref(), newp.as_deref());
+        if let Some(ref to) = newp {
+            let (post, params) = rename_post(decision, to, replaces, replaces);
+            h.post(&ctx, post, params);
+            if let Some(old) = oldp.as_deref() {
+                post_refactor(h, &ctx, rename_chain::renamed(old, to));
+            }
+        }
+        h.debug(
+            "shim/rename_call",
+            json!({
+                "rc": rc,
+                "oldPath": oldp.as_ref().map(|p| p.to_string_lossy().to_string()),
+                "newPath": newp.as_ref().map(|p| p.to_string_lossy().to_string())
+            }),
+        );
+    }
+
+    rc
+}
+
+/// After a failed rename: remember an `EXDEV` one, which the caller will
+/// likely redo as copy and unlink. `errno` is left as the rename set it.
+fn note_cross_device(io: &impl RawIo, old: Option<&Path>, new: Option<&Path>) {
+    let err = io.errno();
+    if let (libc::EXDEV, Some(old), Some(new)) = (err, old, new) {
+        xdev::failed(old, new);
+        io.set_errno(err);
+    }
+}
+
+/// A successful unlink of `path`, which may finish a move across
+/// filesystems.
+fn post_delete(net: &impl Transport, ctx: &OpContext, path: &Path, decision: Decision) {
+    dir_cache::forget(path);
+    special::forget(path);
+    self_created::forget(path);
+    net.post(
+        ctx,
+        "post_delete",
+        decision.annotate(json!({ "path": path.to_string_lossy() })),
+    );
+    if let Some(to) = xdev::unlinked(path) {
+        let params = json!({
+            "path": to.to_string_lossy(),
+            "old_path": path.to_string_lossy(),
+            "via": "copy",
+        });
+        net.post(ctx, "post_rename", decision.annotate(params));
+    }
+    post_refactor(net, ctx, rename_chain::unlinked(path));
+}
+
+/// The summary of a chain of renames the last event completed, if it did;
+/// see `rename_chain`.
+fn post_refactor(net: &impl Transport, ctx: &OpContext, params: Option<serde_json::Value>) {
+    if let Some(params) = params {
+        net.post(ctx, "post_refactor", params);
+    }
+}
+
+/// A rename's preflight names where the file comes from, and whether it
+/// would replace a file at `dest` (looked at with `lstat` before asking),
+/// with that file's size and mtime, so a server can tell a move onto a
+/// free name from one that clobbers.
+pub(crate) fn rename_extra(old: Option<&Path>, dest: Option<FdStat>) -> serde_json::Value {
+    let mut extra = json!({ "destination_exists": dest.is_some() });
+    if let Some(o) = old {
+        extra["old_path"] = json!(o.to_string_lossy());
+    }
+    if let Some(st) = dest {
+        extra["destination_size"] = json!(st.size);
+        extra["destination_mtime"] = json!(st.mtime);
+    }
+    extra
+}
+
+/// After a successful rename: a directory moved away, or replaced, loses
+/// its directory-wide allows.
+fn renamed(old: Option<&Path>, new: Option<&Path>) {
+    for p in [old, new].into_iter().flatten() {
+        dir_cache::forget(p);
+        special::forget(p);
+        self_created::forget(p);
+    }
+}
+
+/// The post for a rename onto `to`: `post_modify` of no bytes when it
+/// replaced a file, else `post_create`. `clobbered` only when what it
+/// replaced is gone (not for a swap).
+pub(crate) fn rename_post(
+    decision: Decision,
+    to: &Path,
+    replaces: bool,
+    clobbered: bool,
+) -> (&'static str, serde_json::Value) {
+    let params = json!({ "path": to.to_string_lossy(), "clobbered": clobbered });
+    if replaces {
+        let mut params = decision.annotate(params);
+        params["bytes"] = json!(0);
+        ("post_modify", params)
+    } else {
+        ("post_create", decision.annotate(params))
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) unsafe fn tracked_unlinkat(
+    h: &impl Host,
+    dirfd: c_int,
+    path: *const c_char,
+    flags: c_int,
+) -> c_int {
+    let ctx = OpContext::enter();
+
+    if !ctx.enabled() {
+        return unsafe { h.unlinkat(dirfd, path, flags) };
+    }
+
+    let pbuf = h.path_at(dirfd, path);
+    let mut decision = Decision::default();
+    if ctx.is_outermost() {
+        if let Some(ref p) = pbuf {
+            let Some(d) = h.preflight(&ctx, "pre_delete", p, json!({})) else {
+                h.set_errno(libc::EPERM);
+                return -1;
+            };
+            decision = d;
+        }
+    }
+
+    let rc = contain::ran(unsafe { h.unlinkat(dirfd, path, flags) });
+
+    if ctx.is_outermost() && rc == 0 {
+        if let Some(ref p) = pbuf {
+            post_delete(h, &ctx, p, decision);
+        }
+        h.debug(
+            "shim/unlinkat_call",
+            json!({
+                "rc": rc,
+                "flags": flags,
+                "path": pbuf.as_ref().map(|p| p.to_string_lossy().to_string())
+            }),
+        );
+    }
+
+    rc
+}
+
+/// `renameat` and `renameat2`; `flags` is `None` for the former so we forward
+/// to the matching original rather than assuming renameat2 exists (see
+/// `RawIo::renameat`).
+#[cfg(target_os = "linux")]
+pub(crate) unsafe fn tracked_renameat(
+    h: &impl Host,
+    olddirfd: c_int,
+    old: *const c_char,
+    newdirfd: c_int,
+    new: *const c_char,
+    flags: Option<libc::c_uint>,
+) -> c_int {
+    let call = || unsafe { h.renameat(olddirfd, old, newdirfd, new, flags) };
+    let ctx = OpContext::enter();
+
+    if !ctx.enabled() {
+        return call();
+    }
+
+    let oldp = h.path_at(olddirfd, old);
+    let newp = h.path_at(newdirfd, new);
+
+    let mut replaces = true;
+    let mut decision = Decision::default();
+    if ctx.is_outermost() {
+        if let Some(ref to) = newp {
+            let dest = h.lstat(to);
+            let extra = rename_extra(oldp.as_deref(), dest);
+            let Some(d) = h.preflight(&ctx, "pre_rename", to, extra) else {
+                h.set_errno(libc::EPERM);
+                return -1;
+            };
+            decision = d;
+            replaces = dest.is_some();
+        }
+    }
+
+    let rc = contain::ran(call());
+    if ctx.is_outermost() && rc != 0 {
+        note_cross_device(h, oldp.as_deref(), newp.as_deref());
+    }
+
+    if ctx.is_outermost() && rc == 0 {
+        renamed(oldp.as_deref(), newp.as_deref());
+        if let Some(ref to) = newp {
+            let (post, params) = rename_post(decision, to, replaces, replaces);
+            h.post(&ctx, post, params);
+            if let Some(old) = oldp.as_deref() {
+                post_refactor(h, &ctx, rename_chain::renamed(old, to));
+            }
+        }
+        h.debug(
+            "shim/renameat_call",
+            json!({
+                "rc": rc,
+                "flags": flags.unwrap_or(0),
+                "oldPath": oldp.as_ref().map(|p| p.to_string_lossy().to_string()),
+                "newPath": newp.as_ref().map(|p| p.to_string_lossy().to_string())
+            }),
+        );
+    }
+
+    rc
+}
+
+/// `copyfile` as one operation on `to`, reported after the call; see
+/// `op_context`. `COPYFILE_MOVE` deletes `from` as well.
+#[cfg(target_os = "macos")]
+pub(crate) unsafe fn tracked_copyfile(
+    h: &impl Host,
+    from: *const c_char,
+    to: *const c_char,
+    state: *mut c_void,
+    flags: libc::copyfile_flags_t,
+) -> c_int {
+    let call = || unsafe { h.copyfile(from, to, state, flags) };
+    let ctx = OpContext::enter();
+    if !ctx.enabled() || !ctx.is_outermost() {
+        return call();
+    }
+    let (Some(src), Some(dst)) = (h.path(from), h.path(to)) else {
+        return call();
+    };
+
+    let existed = dst.symlink_metadata().is_ok();
+    let (pre, post) = if existed {
+        ("pre_modify", "post_modify")
+    } else {
+        ("pre_create", "post_create")
+    };
+    let extra = json!({
+        "source": "copyfile",
+        "from": src.to_string_lossy(),
+        "clone": flags & libc::COPYFILE_CLONE != 0,
+    });
+    let Some(decision) = h.preflight(&ctx, pre, &dst, extra) else {
+        h.set_errno(libc::EPERM);
+        return -1;
+    };
+    let moves = flags & libc::COPYFILE_MOVE != 0;
+    let mut deleted = Decision::default();
+    if moves {
+        let Some(d) = h.preflight(&ctx, "pre_delete", &src, json!({})) else {
+            h.set_errno(libc::EPERM);
+            return -1;
+        };
+        deleted = d;
+    }
+
+    // The copy's own opens and writes come in beneath this hook and pass
+    // straight through, so this context sends the only pair.
+    let rc = contain::ran(call());
+
+    if rc == 0 {
+        let params = json!({ "path": dst.to_string_lossy(), "from": src.to_string_lossy(), "via": "copyfile" });
+        h.post(&ctx, post, decision.annotate(params));
+        if moves {
+            post_delete(h, &ctx, &src, deleted);
+        }
+        h.debug(
+            "shim/copyfile_call",
+            json!({ "rc": rc, "flags": flags, "from": src.to_string_lossy(), "to": dst.to_string_lossy() }),
+        );
+    }
+    rc
+}
+
+/// `removefile` as one delete of `path`, however much is under it.
+#[cfg(target_os = "macos")]
+pub(crate) unsafe fn tracked_removefile(
+    h: &impl Host,
+    path: *const c_char,
+    state: *mut c_void,
+    flags: u32,
+) -> c_int {
+    let call = || unsafe { h.removefile(path, state, flags) };
+    let ctx = OpContext::enter();
+    if !ctx.enabled() || !ctx.is_outermost() {
+        return call();
+    }
+    let Some(p) = h.path(path) else {
+        return call();
+    };
+
+    let recursive = flags & platform::REMOVEFILE_RECURSIVE != 0;
+    let extra = json!({ "source": "removefile", "recursive": recursive });
+    let Some(decision) = h.preflight(&ctx, "pre_delete", &p, extra) else {
+        h.set_errno(libc::EPERM);
+        return -1;
+    };
+
+    let rc = contain::ran(call());
+
+    if rc == 0 {
+        post_delete(h, &ctx, &p, decision);
+        h.debug(
+            "shim/removefile_call",
+            json!({ "rc": rc, "flags": flags, "path": p.to_string_lossy() }),
+        );
+    }
+    rc
+}
+
+/// `renamex_np` and `renameatx_np`. With `RENAME_SWAP` both names are
+/// rewritten, so both are asked about and both get a `post_modify`.
+#[cfg(target_os = "macos")]
+pub(crate) unsafe fn tracked_renameatx(
+    h: &impl Host,
+    olddirfd: c_int,
+    old: *const c_char,
+    newdirfd: c_int,
+    new: *const c_char,
+    flags: libc::c_uint,
+) -> c_int {
+    let call = || unsafe { h.renameatx(olddirfd, old, newdirfd, new, flags) };
+    let ctx = OpContext::enter();
+    if !ctx.enabled() || !ctx.is_outermost() {
+        return call();
+    }
+    let (Some(oldp), Some(newp)) = (h.path_at(olddirfd, old), h.path_at(newdirfd, new)) else {
+        return call();
+    };
+
+    let swap = flags & libc::RENAME_SWAP != 0;
+    let dest = h.lstat(&newp);
+    let extra = rename_extra(Some(&oldp), dest);
+    let Some(decision) = h.preflight(&ctx, "pre_rename", &newp, extra) else {
+        h.set_errno(libc::EPERM);
+        return -1;
+    };
+    let mut swapped = Decision::default();
+    if swap {
+        let source = h.lstat(&oldp);
+        let extra = rename_extra(Some(&newp), source);
+        let Some(d) = h.preflight(&ctx, "pre_rename", &oldp, extra) else {
+            h.set_errno(libc::EPERM);
+            return -1;
+        };
+        swapped = d;
+    }
+    // A swap replaces both names but clobbers neither.
+    let clobbered = !swap && dest.is_some();
+    let replaces = swap || clobbered;
+
+    let rc = contain::ran(call());
+    if rc != 0 {
+        note_cross_device(h, Some(&oldp), Some(&newp));
+        return rc;
+    }
+
+    renamed(Some(&oldp), Some(&newp));
+    let (post, params) = rename_post(decision, &newp, replaces, clobbered);
+    h.post(&ctx, post, params);
+    if !swap {
+        post_refactor(h, &ctx, rename_chain::renamed(&oldp, &newp));
+    }
+    if swap {
+        h.post(
+            &ctx,
+            "post_modify",
+            swapped.annotate(json!({ "path": oldp.to_string_lossy(), "bytes": 0 })),
+        );
+    }
+    h.debug(
+        "shim/renameatx_call",
+        json!({
+            "rc": rc,
+            "flags": flags,
+            "oldPath": oldp.to_string_lossy(),
+            "newPath": newp.to_string_lossy()
+        }),
+    );
+    rc
+}
+
+/// `fflush` on a stream over a dirty fd (on every stream, for `NULL`)
+/// sends that fd's post then and there, as `shim/flush` would, since the
+/// close may come from stdio's exit-time cleanup, too late for a post to
+/// go out. The real flush is made before the context is opened: its writes
+/// are the program's, preflighted like any other. Streams over memory or
+/// cookies have no fd and are left alone.
+pub(crate) unsafe fn tracked_fflush(h: &impl Host, stream: *mut libc::FILE) -> c_int {
+    let rc = contain::ran(unsafe { h.fflush(stream) });
+    if rc != 0 {
+        return rc;
+    }
+    let ctx = OpContext::enter();
+    if !ctx.enabled() || !ctx.is_outermost() {
+        return rc;
+    }
+    let errno = h.errno();
+    let only = if stream.is_null() {
+        None
+    } else {
+        match unsafe { libc::fileno(stream) } {
+            -1 => {
+                h.set_errno(errno);
+                return rc;
+            }
+            fd => Some(fd),
+        }
+    };
+    let dirty = FD_TABLE
+        .lock()
+        .iter()
+        .any(|(&fd, s)| s.dirty && !s.ignored && only.is_none_or(|o| o == fd));
+    if dirty {
+        h.flush(only);
+    }
+    h.set_errno(errno);
+    rc
+}
+
+pub(crate) unsafe fn tracked_ftruncate(h: &impl Host, fd: c_int, len: libc::off_t) -> c_int {
+    let ctx = OpContext::enter();
+
+    if !ctx.enabled() {
+        return unsafe { h.ftruncate(fd, len) };
+    }
+    debug_assert_foreign(fd);
+
+    let mut decision = None;
+    let mut policy = TruncatePolicy::Block;
+    let mut truncation = None;
+    let mut asked = false;
+    if ctx.is_outermost() {
+        if let Some(p) = tracked_path(fd).map(PathBuf::from) {
+            truncation = fd_truncation(h, fd, len.max(0) as u64);
+            let kind = truncation
+                .as_ref()
+                .map_or(TruncateKind::Clear, Truncation::kind);
+            policy = config::get().truncate_policy(kind);
+            if path_class::strict(&p) {
+                policy = TruncatePolicy::Block;
+            }
+            // One approval per fd: its allowed first write covers this.
+            let approved = FD_TABLE
+                .lock()
+                .get(&fd)
+                .filter(|e| e.approved())
+                .map(|e| e.decision);
+            if approved.is_some() && policy == TruncatePolicy::Block {
+                policy = TruncatePolicy::Notify;
+            }
+            asked = policy == TruncatePolicy::Block;
+            decision = match policy {
+                TruncatePolicy::Block => {
+                    let extra = truncation.as_ref().map_or(json!({}), Truncation::params);
+                    h.preflight(&ctx, "pre_truncate", &p, extra)
+                }
+                TruncatePolicy::Notify | TruncatePolicy::Off => Some(approved.unwrap_or_default()),
+            };
+            if decision.is_none() {
+                h.set_errno(libc::EPERM);
+                return -1;
+            }
+        }
+    }
+
+    let rc = contain::ran(unsafe { h.ftruncate(fd, len) });
+
+    if ctx.is_outermost() && rc == 0 && policy != TruncatePolicy::Off {
+        mark_fd_dirty(fd, None, 0, 0);
+        let mut t = FD_TABLE.lock();
+        if let Some(e) = t.get_mut(&fd) {
+            if truncation.is_some() {
+                e.truncated = truncation;
+            }
+            if let Some(d) = decision {
+                e.decision = d;
+            }
+            // And the server's allowal of it covers the writes that follow.
+            let allowed = decision.is_some_and(|d| d.by == Some(AllowedBy::Server));
+            if asked && allowed && !e.pre_sent && e.escape.is_none() {
+                e.first_sight(|| h.fs_type(fd), config::get());
+            }
+        }
+        drop(t);
+        h.debug(
+            "shim/ftruncate_call",
+            json!({ "fd": fd, "len": len, "rc": rc, "tracked_path": tracked_path(fd)}),
+        );
+    }
+    rc
+}
+
+/// What truncating `fd` to `length` would be, from the size its table
+/// entry already knows. An entry without one gets the `fstat` that
+/// `mark_fd_dirty` would otherwise make afterwards.
+fn fd_truncation(paths: &impl PathResolver, fd: RawFd, length: u64) -> Option<Truncation> {
+    let mut t = FD_TABLE.lock();
+    let e = t.get_mut(&fd)?;
+    if e.size_before.is_none() && e.truncated.is_none() {
+        if let Some(st) = paths.fd_stat(fd).filter(|st| st.regular) {
+            e.apply_stat(st);
+        }
+    }
+    e.truncation(length)
+}
+
+pub(crate) unsafe fn tracked_truncate(
+    h: &impl Host,
+    path: *const c_char,
+    len: libc::off_t,
+) -> c_int {
+    let ctx = OpContext::enter();
+
+    if !ctx.enabled() {
+        return unsafe { h.truncate(path, len) };
+    }
+
+    let pbuf = h.path(path);
+    let mut decision = Decision::default();
+    let mut policy = TruncatePolicy::Block;
+    let mut truncation = json!({});
+    let mut stat = None;
+    if ctx.is_outermost() {
+        if let Some(ref p) = pbuf {
+            stat = unsafe { h.stat_at(None, path) };
+            let t = Truncation {
+                length: len.max(0) as u64,
+                size: stat.map_or(0, |st| st.size),
+                bytes: 0,
+            };
+            policy = config::get().truncate_policy(t.kind());
+            if path_class::strict(p) {
+                policy = TruncatePolicy::Block;
+            }
+            truncation = t.params();
+            if policy == TruncatePolicy::Block {
+                let Some(d) = h.preflight(&ctx, "pre_truncate", p, truncation.clone()) else {
+                    h.set_errno(libc::EPERM);
+                    return -1;
+                };
+                decision = d;
+            }
+        }
+    }
+
+    let rc = contain::ran(unsafe { h.truncate(path, len) });
+
+    if ctx.is_outermost() && rc == 0 {
+        if let (Some(p), Some(st)) = (&pbuf, stat) {
+            // So the open that writes back what it cut isn't asked again.
+            if policy == TruncatePolicy::Block && decision.by == Some(AllowedBy::Server) {
+                truncated::allowed(st.dev, st.ino, p);
+            }
+        }
+        if let Some(p) = pbuf.filter(|_| policy != TruncatePolicy::Off) {
+            // Nothing is written, but every `post_modify` carries `bytes`.
+            let mut params = json!({ "path": p.to_string_lossy(), "bytes": 0 });
+            if let (Some(o), serde_json::Value::Object(t)) = (params.as_object_mut(), truncation) {
+                o.extend(t);
+            }
+            h.post(&ctx, "post_modify", decision.annotate(params));
+        }
+        h.debug(
+            "shim/truncate_call",
+            json!({ "len": len, "rc": rc, "path": c_path(path).map(|p| p.to_string_lossy().to_string()) }),
+        );
+    }
+
+    rc
+}
+
+/// Shared body of the `mkfifo`/`mkfifoat`/`mknod` hooks; `mode` carries the
+/// node type, and `real` makes the node. See `special`.
+pub(crate) unsafe fn tracked_mknod(
+    h: &impl Host,
+    dirfd: Option<c_int>,
+    path: *const c_char,
+    mode: libc::mode_t,
+    real: impl FnOnce() -> c_int,
+) -> c_int {
+    let ctx = OpContext::enter();
+    let rc = contain::ran(real());
+    if !ctx.enabled() || !ctx.is_outermost() || rc != 0 {
+        return rc;
+    }
+    let Some(node) = special::kind(mode) else {
+        return rc;
+    };
+    let pbuf = match dirfd {
+        Some(dirfd) => h.path_at(dirfd, path),
+        None => h.path(path),
+    };
+    if let Some(p) = pbuf {
+        special::created(&p);
+        h.post(
+            &ctx,
+            "post_create_special",
+            json!({
+                "path": p.to_string_lossy(),
+                "node": node,
+                "mode": format!("{:04o}", mode & 0o7777),
+            }),
+        );
+    }
+    rc
+}
+
+/// Shared memory is never tracked: its fd, and its path where it has
+/// one, are known to `special` before anything can write. Not gated on
+/// `enabled`, which only costs a table entry.
+pub(crate) unsafe fn tracked_shm_open(
+    h: &impl Host,
+    name: *const c_char,
+    oflag: c_int,
+    mode: libc::mode_t,
+) -> c_int {
+    let _ctx = OpContext::enter();
+    let fd = contain::ran(unsafe { h.shm_open(name, oflag, mode) });
+    if fd >= 0 {
+        special::shm_opened(fd, h.shm_path(name).as_deref());
+    }
+    fd
+}
+
+/// Never a `pre_delete`: an `unlink` the C library makes for it runs
+/// beneath this hook, so passes through.
+pub(crate) unsafe fn tracked_shm_unlink(h: &impl Host, name: *const c_char) -> c_int {
+    let _ctx = OpContext::enter();
+    let rc = contain::ran(unsafe { h.shm_unlink(name) });
+    if rc == 0 {
+        if let Some(p) = h.shm_path(name) {
+            special::forget(&p);
+        }
+    }
+    rc
+}
+
+/// Shared body of the ACL hooks: `target` gives the params naming the
+/// file, and `real` sets `acl` on it. `source` is the call, for the
+/// preflight, which a file without a path goes without. See `acl`.
+pub(crate) unsafe fn tracked_acl(
+    h: &impl Host,
+    source: &str,
+    target: impl FnOnce() -> Option<serde_json::Value>,
+    acl: *mut c_void,
+    real: impl FnOnce() -> c_int,
+) -> c_int {
+    let ctx = OpContext::enter();
+    let mode = config::get().acl_mode;
+    if !ctx.enabled() || !ctx.is_outermost() || mode == AclMode::Off {
+        return contain::ran(real());
+    }
+    let target = target();
+    let mut decision = Decision::default();
+    let path = target.as_ref().and_then(|t| t["path"].as_str());
+    let strict = path.is_some_and(|p| path_class::strict(Path::new(p)));
+    if let (Some(p), AclMode::Block, _) | (Some(p), _, true) = (path, mode, strict) {
+        let Some(d) = h.preflight(&ctx, "pre_acl", Path::new(p), json!({ "source": source }))
+        else {
+            h.set_errno(libc::EPERM);
+            return -1;
+        };
+        decision = d;
+    }
+    let rc = contain::ran(real());
+    if let Some(target) = target.filter(|_| rc == 0) {
+        h.post(
+            &ctx,
+            "post_acl",
+            decision.annotate(acl::params(target, acl)),
+        );
+    }
+    rc
+}
+
+/// Shared body of the fd metadata hooks: once `real` succeeds, post
+/// `method` naming `fd`'s file, with `extra` added. See `inodes`.
+pub(crate) unsafe fn tracked_fd_meta(
+    h: &impl Host,
+    method: &str,
+    fd: c_int,
+    extra: serde_json::Value,
+    real: impl FnOnce() -> c_int,
+) -> c_int {
+    let ctx = OpContext::enter();
+    let rc = contain::ran(real());
+    if !ctx.enabled() || !ctx.is_outermost() || rc != 0 {
+        return rc;
+    }
+    if let Some(mut params) = inodes::params(fd, tracked_path(fd).map(PathBuf::from)) {
+        if let (Some(params), serde_json::Value::Object(extra)) = (params.as_object_mut(), extra) {
+            params.extend(extra);
+        }
+        h.post(&ctx, method, params);
+    }
+    rc
+}
+
+/// Shared body of the timestamp hooks: once `real` succeeds, count the
+/// change toward the directory of the file `target` names. One matching
+/// `touch_verbose`, or known only by inode, gets its own `post_utimes`.
+/// See `touches`.
+pub(crate) unsafe fn tracked_touch(
+    h: &impl Host,
+    target: impl FnOnce() -> Option<serde_json::Value>,
+    real: impl FnOnce() -> c_int,
+) -> c_int {
+    let ctx = OpContext::enter();
+    let rc = contain::ran(real());
+    if !ctx.enabled() || !ctx.is_outermost() || rc != 0 {
+        return rc;
+    }
+    let Some(params) = target() else {
+        return rc;
+    };
+    let cfg = config::get();
+    match params["path"].as_str().map(Path::new) {
+        Some(p) if cfg.ignored_by(p).is_none() && !cfg.touch_verbose(p) => {
+            for due in touches::touched(p) {
+                h.post(&ctx, "post_touch", due);
+            }
+        }
+        // An ignored one is counted as ignored there.
+        _ => h.post(&ctx, "post_utimes", params),
+    }
+    rc
+}
+
+/// A lock call through `via` asking for `req` (`None`: nothing `locks`
+/// reads). See `locks`.
+pub(crate) unsafe fn tracked_lock(
+    h: &impl Host,
+    fd: c_int,
+    via: &'static str,
+    req: Option<locks::Request>,
+    real: impl FnOnce() -> c_int,
+) -> c_int {
+    let ctx = OpContext::enter();
+    let rc = contain::ran(real());
+    if !ctx.enabled() || !ctx.is_outermost() || rc != 0 {
+        return rc;
+    }
+    let settled =
+        req.and_then(|req| locks::settled(fd, via, req, tracked_path(fd).map(PathBuf::from)));
+    if let Some((method, params)) = settled {
+        h.post(&ctx, method, params);
+    }
+    rc
+}
+
+/// `exit` or `_exit` (`immediate`) is about to end the process. See
+/// `shutdown`.
+pub(crate) fn tracked_exit(h: &impl Host, immediate: bool) -> c_int {
+    shutdown::begin();
+    let ctx = OpContext::enter();
+    if !ctx.enabled() || !ctx.is_outermost() || (immediate && !shutdown::loaded_here()) {
+        return 0;
+    }
+    h.flush_all();
+    if immediate {
+        summary::send();
+    }
+    0
+}
+
+/// Not gated on `enabled`: the cached cwd has to follow every change, or
+/// relative paths resolve against the wrong directory once tracking starts.
+pub(crate) unsafe fn tracked_chdir(h: &impl Host, path: *const c_char) -> c_int {
+    procinfo::change_dir(|| contain::ran(unsafe { h.chdir(path) }))
+}
+
+pub(crate) unsafe fn tracked_fchdir(h: &impl Host, fd: c_int) -> c_int {
+    procinfo::change_dir(|| contain::ran(unsafe { h.fchdir(fd) }))
+}
+
+/// One of the `set*id` calls, named `call`; see `creds`.
+pub(crate) fn tracked_setid(call: &str, set: impl FnOnce() -> c_int) -> c_int {
+    let ctx = OpContext::enter();
+    if !ctx.enabled() || !ctx.is_outermost() {
+        return set();
+    }
+    creds::change(&ctx, call, || contain::ran(set()))
+}
+
+/// An exec, with the tracked fds the new image inherits handed to it (see
+/// `exec_fds`) and the other dirty ones posted first. Only returns when
+/// the exec failed.
+pub(crate) unsafe fn tracked_exec(
+    h: &impl Host,
+    envp: *const *const c_char,
+    exec: impl FnOnce(*const *const c_char) -> c_int,
+) -> c_int {
+    let ctx = OpContext::enter();
+    if !ctx.enabled() || !ctx.is_outermost() {
+        return exec(envp);
+    }
+    let carried = unsafe { exec_fds::carry(envp, exec_fds::Mode::Exec) };
+    let kept = carried.as_ref().map_or(&[][..], |c| &c.fds[..]);
+    let pending = take_dirty(|fd, _| !kept.contains(&fd), Some("exec"));
+    if !pending.is_empty() {
+        h.send_posts(pending);
+    }
+    contain::ran(exec(carried.as_ref().map_or(envp, exec_fds::Envp::as_ptr)))
+}
+
+/// A spawn, with the child handed what it needs to write the tracked fds
+/// it inherits without asking again.
+pub(crate) unsafe fn tracked_spawn(
+    envp: *const *mut c_char,
+    spawn: impl FnOnce(*const *mut c_char) -> c_int,
+) -> c_int {
+    let ctx = OpContext::enter();
+    if !ctx.enabled() || !ctx.is_outermost() {
+        return spawn(envp);
+    }
+    let carried = unsafe { exec_fds::carry(envp.cast(), exec_fds::Mode::Spawn) };
+    contain::ran(spawn(carried.as_ref().map_or(envp, |c| c.as_ptr().cast())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SHIM_READY;
+    use std::cell::{Cell, RefCell};
+    use std::ffi::CString;
+    use std::sync::atomic::Ordering;
+
+    fn state(path: Option<&str>, dirty: bool) -> FdState {
+        FdState {
+            path: path.map(PathBuf::from),
+            dirty,
+            bytes: if dirty { 1 } else { 0 },
+            pre_sent: true,
+            ..FdState::default()
+        }
+    }
+
+    #[test]
+    fn open_flags_are_named_access_mode_first() {
+        let flags = libc::O_RDWR | libc::O_APPEND | libc::O_CREAT;
+        assert_eq!(open_flag_names(flags), ["O_RDWR", "O_CREAT", "O_APPEND"]);
+        assert_eq!(open_flag_names(libc::O_WRONLY), ["O_WRONLY"]);
+    }
+
+    #[test]
+    fn close_reports_dirty_fd() {
+        let s = state(Some("/tmp/a"), true);
+        assert_eq!(close_post_path(0, 0, Some(&s)), Some(Path::new("/tmp/a")));
+    }
+
+    #[test]
+    fn close_reports_dirty_fd_on_eintr() {
+        let s = state(Some("/tmp/a"), true);
+        assert_eq!(
+            close_post_path(-1, libc::EINTR, Some(&s)),
+            Some(Path::new("/tmp/a"))
+        );
+    }
+
+    #[test]
+    fn close_skips_other_failures_clean_and_untracked_fds() {
+        let dirty = state(Some("/tmp/a"), true);
+        assert_eq!(close_post_path(-1, libc::EBADF, Some(&dirty)), None);
+        assert_eq!(close_post_path(-1, libc::EIO, Some(&dirty)), None);
+        assert_eq!(
+            close_post_path(0, 0, Some(&state(Some("/tmp/a"), false))),
+            None
+        );
+        assert_eq!(close_post_path(0, 0, Some(&state(None, true))), None);
+        assert_eq!(close_post_path(0, 0, None), None);
+    }
+
+    #[test]
+    fn iovec_totals_guard_their_counts() {
+        let buf = [0u8; 8];
+        let iov = |len| libc::iovec {
+            iov_base: buf.as_ptr() as *mut c_void,
+            iov_len: len,
+        };
+        let v = [iov(3), iov(0), iov(5)];
+        let huge = [iov(usize::MAX), iov(usize::MAX)];
+        unsafe {
+            assert_eq!(iov_total(v.as_ptr(), 3), 8);
+            assert_eq!(iov_total(v[1..].as_ptr(), 1), 0);
+            assert_eq!(iov_total(v.as_ptr(), 0), 0);
+            assert_eq!(iov_total(v.as_ptr(), -1), 0);
+            assert_eq!(iov_total(std::ptr::null(), 3), 0);
+            assert_eq!(iov_total(huge.as_ptr(), 2), u64::MAX);
+        }
+    }
+
+    #[test]
+    fn short_writes_report_what_was_asked() {
+        let cfg = config::ShimConfig::default();
+        let mut s = state(Some("/tmp/a"), true);
+        // A writev of 8 bytes that wrote 5.
+        s.bytes = 5;
+        s.requested = 8;
+        let params = modify_params(&s, Path::new("/tmp/a"), &cfg, None);
+        assert_eq!(
+            (params["bytes"].clone(), params["requested"].clone()),
+            (json!(5), json!(8))
+        );
+        s.requested = 5;
+        let params = modify_params(&s, Path::new("/tmp/a"), &cfg, None);
+        assert!(params.get("requested").is_none());
+    }
+
+    /// A process's whole world, kept in memory: the files by path, the
+    /// fds open on them, and what was asked and posted. `refuse` lists the
+    /// preflights the server says no to, and `fall_open` those nothing
+    /// answers in time.
+    struct Fake {
+        files: RefCell<HashMap<PathBuf, FdStat>>,
+        fds: RefCell<HashMap<RawFd, PathBuf>>,
+        next_fd: Cell<RawFd>,
+        errno: Cell<c_int>,
+        refuse: Vec<&'static str>,
+        fall_open: Vec<&'static str>,
+        asked: RefCell<Vec<(String, PathBuf, serde_json::Value)>>,
+        posted: RefCell<Vec<(String, serde_json::Value)>>,
+    }
+
+    impl Fake {
+        /// Fds from `first_fd` up, which no other test uses: the fd table
+        /// is the process's.
+        fn new(first_fd: RawFd, files: &[(&str, u64)]) -> Fake {
+            SHIM_READY.store(true, Ordering::Relaxed);
+            let files = files
+                .iter()
+                .enumerate()
+                .map(|(i, &(p, size))| (PathBuf::from(p), file(first_fd, i as u64, size)))
+                .collect();
+            Fake {
+                files: RefCell::new(files),
+                fds: RefCell::default(),
+                next_fd: Cell::new(first_fd),
+                errno: Cell::new(0),
+                refuse: Vec::new(),
+                fall_open: Vec::new(),
+                asked: RefCell::default(),
+                posted: RefCell::default(),
+            }
+        }
+
+        fn refusing(mut self, op: &'static str) -> Fake {
+            self.refuse.push(op);
+            self
+        }
+
+        fn falling_open(mut self, op: &'static str) -> Fake {
+            self.fall_open.push(op);
+            self
+        }
+
+        fn fail(&self, errno: c_int) -> c_int {
+            self.errno.set(errno);
+            -1
+        }
+
+        fn asked(&self) -> Vec<(String, PathBuf)> {
+            let asked = self.asked.borrow();
+            asked
+                .iter()
+                .map(|(op, p, _)| (op.clone(), p.clone()))
+                .collect()
+        }
+
+        fn posted(&self) -> Vec<String> {
+            let posted = self.posted.borrow();
+            posted.iter().map(|(method, _)| method.clone()).collect()
+        }
+
+        fn sent(&self, i: usize) -> serde_json::Value {
+            self.posted.borrow()[i].1.clone()
+        }
+
+        fn size(&self, path: &str) -> Option<u64> {
+            self.files.borrow().get(Path::new(path)).map(|st| st.size)
+        }
+
+        fn grow(&self, fd: c_int, n: usize) -> libc::ssize_t {
+            let Some(p) = self.fds.borrow().get(&fd).cloned() else {
+                return self.fail(libc::EBADF) as libc::ssize_t;
+            };
+            if let Some(st) = self.files.borrow_mut().get_mut(&p) {
+                st.size += n as u64;
+            }
+            n as libc::ssize_t
+        }
+
+        fn resize(&self, path: &Path, len: libc::off_t) -> c_int {
+            match self.files.borrow_mut().get_mut(path) {
+                Some(_) if len < 0 => self.fail(libc::EINVAL),
+                Some(st) => {
+                    st.size = len as u64;
+                    0
+                }
+                None => self.fail(libc::ENOENT),
+            }
+        }
+
+        fn remove(&self, path: Option<PathBuf>) -> c_int {
+            match path.and_then(|p| self.files.borrow_mut().remove(&p)) {
+                Some(_) => 0,
+                None => self.fail(libc::ENOENT),
+            }
+        }
+
+        fn moved(&self, from: Option<PathBuf>, to: Option<PathBuf>) -> c_int {
+            let (Some(from), Some(to)) = (from, to) else {
+                return self.fail(libc::EFAULT);
+            };
+            let Some(st) = self.files.borrow_mut().remove(&from) else {
+                return self.fail(libc::ENOENT);
+            };
+            self.files.borrow_mut().insert(to, st);
+            0
+        }
+    }
+
+    /// A regular file on a device no real fd is on, so the filesystem
+    /// cache is this fake's too. Inodes are numbered by the test's fds.
+    fn file(fd: RawFd, n: u64, size: u64) -> FdStat {
+        FdStat {
+            dev: 0xfa4e,
+            ino: (fd as u64) << 8 | n,
+            size,
+            perm: 0o644,
+            uid: 501,
+            regular: true,
+            mtime: 1_760_000_000,
+        }
+    }
+
+    impl RawIo for Fake {
+        unsafe fn open(
+            &self,
+            dirfd: Option<c_int>,
+            path: *const c_char,
+            flags: c_int,
+            _mode: libc::mode_t,
+        ) -> c_int {
+            let Some(p) = dirfd.map_or_else(|| self.path(path), |d| self.path_at(d, path)) else {
+                return self.fail(libc::EFAULT);
+            };
+            let fd = self.next_fd.get();
+            {
+                let mut files = self.files.borrow_mut();
+                match files.get_mut(&p) {
+                    Some(st) if flags & libc::O_TRUNC != 0 => st.size = 0,
+                    Some(_) => {}
+                    None if flags & libc::O_CREAT != 0 => {
+                        files.insert(p.clone(), file(fd, 0xff, 0));
+                    }
+                    None => return self.fail(libc::ENOENT),
+                }
+            }
+            self.next_fd.set(fd + 1);
+            self.fds.borrow_mut().insert(fd, p);
+            fd
+        }
+
+        unsafe fn write(
+            &self,
+            fd: c_int,
+            _buf: *const c_void,
+            count: libc::size_t,
+        ) -> libc::ssize_t {
+            self.grow(fd, count)
+        }
+
+        unsafe fn pwrite(
+            &self,
+            fd: c_int,
+            _buf: *const c_void,
+            count: libc::size_t,
+            _offset: libc::off_t,
+        ) -> libc::ssize_t {
+            self.grow(fd, count)
+        }
+
+        unsafe fn writev(
+            &self,
+            fd: c_int,
+            iov: *const libc::iovec,
+            iovcnt: c_int,
+            _offset: Option<libc::off_t>,
+        ) -> libc::ssize_t {
+            self.grow(fd, unsafe { iov_total(iov, iovcnt) } as usize)
+        }
+
+        unsafe fn close(&self, fd: c_int) -> c_int {
+            match self.fds.borrow_mut().remove(&fd) {
+                Some(_) => 0,
+                None => self.fail(libc::EBADF),
+            }
+        }
+
+        unsafe fn unlink(&self, path: *const c_char) -> c_int {
+            self.remove(self.path(path))
+        }
+
+        unsafe fn rename(&self, old: *const c_char, new: *const c_char) -> c_int {
+            self.moved(self.path(old), self.path(new))
+        }
+
+        #[cfg(target_os = "linux")]
+        unsafe fn unlinkat(&self, dirfd: c_int, path: *const c_char, _flags: c_int) -> c_int {
+            self.remove(self.path_at(dirfd, path))
+        }
+
+        #[cfg(target_os = "linux")]
+        unsafe fn renameat(
+            &self,
+            olddirfd: c_int,
+            old: *const c_char,
+            newdirfd: c_int,
+            new: *const c_char,
+            _flags: Option<libc::c_uint>,
+        ) -> c_int {
+            self.moved(self.path_at(olddirfd, old), self.path_at(newdirfd, new))
+        }
+
+        #[cfg(target_os = "macos")]
+        unsafe fn copyfile(
+            &self,
+            from: *const c_char,
+            to: *const c_char,
+            _state: *mut c_void,
+            _flags: libc::copyfile_flags_t,
+        ) -> c_int {
+            let st = self.path(from).and_then(|p| self.lstat(&p));
+            match (st, self.path(to)) {
+                (Some(st), Some(to)) => {
+                    self.files.borrow_mut().insert(to, st);
+                    0
+                }
+                _ => self.fail(libc::ENOENT),
+            }
+        }
+
+        #[cfg(target_os = "macos")]
+        unsafe fn removefile(
+            &self,
+            path: *const c_char,
+            _state: *mut c_void,
+            _flags: u32,
+        ) -> c_int {
+            self.remove(self.path(path))
+        }
+
+        #[cfg(target_os = "macos")]
+        unsafe fn renameatx(
+            &self,
+            olddirfd: c_int,
+            old: *const c_char,
+            newdirfd: c_int,
+            new: *const c_char,
+            _flags: libc::c_uint,
+        ) -> c_int {
+            self.moved(self.path_at(olddirfd, old), self.path_at(newdirfd, new))
+        }
+
+        unsafe fn ftruncate(&self, fd: c_int, len: libc::off_t) -> c_int {
+            match self.fd_path(fd) {
+                Some(p) => self.resize(&p, len),
+                None => self.fail(libc::EBADF),
+            }
+        }
+
+        unsafe fn truncate(&self, path: *const c_char, len: libc::off_t) -> c_int {
+            match self.path(path) {
+                Some(p) => self.resize(&p, len),
+                None => self.fail(libc::EFAULT),
+            }
+        }
+
+        unsafe fn fflush(&self, _stream: *mut libc::FILE) -> c_int {
+            0
+        }
+
+        unsafe fn shm_open(
+            &self,
+            _name: *const c_char,
+            _oflag: c_int,
+            _mode: libc::mode_t,
+        ) -> c_int {
+            self.fail(libc::ENOSYS)
+        }
+
+        unsafe fn shm_unlink(&self, _name: *const c_char) -> c_int {
+            self.fail(libc::ENOSYS)
+        }
+
+        unsafe fn chdir(&self, _path: *const c_char) -> c_int {
+            0
+        }
+
+        unsafe fn fchdir(&self, _fd: c_int) -> c_int {
+            0
+        }
+
+        fn status_flags(&self, _fd: c_int) -> c_int {
+            libc::O_WRONLY
+        }
+
+        fn errno(&self) -> c_int {
+            self.errno.get()
+        }
+
+        fn set_errno(&self, errno: c_int) {
+            self.errno.set(errno);
+        }
+    }
+
+    impl PathResolver for Fake {
+        fn fd_path(&self, fd: RawFd) -> Option<PathBuf> {
+            self.fds.borrow().get(&fd).cloned()
+        }
+
+        fn absolute(&self, path: PathBuf) -> PathBuf {
+            Path::new("/proj").join(path)
+        }
+
+        fn fd_stat(&self, fd: RawFd) -> Option<FdStat> {
+            self.lstat(&self.fd_path(fd)?)
+        }
+
+        fn fs_type(&self, _fd: RawFd) -> Option<String> {
+            config::get().track_filesystems.first().cloned()
+        }
+
+        unsafe fn stat_at(&self, dirfd: Option<c_int>, path: *const c_char) -> Option<FdStat> {
+            let p = dirfd.map_or_else(|| self.path(path), |d| self.path_at(d, path))?;
+            self.lstat(&p)
+        }
+
+        fn lstat(&self, path: &Path) -> Option<FdStat> {
+            self.files.borrow().get(path).copied()
+        }
+
+        fn shm_path(&self, _name: *const c_char) -> Option<PathBuf> {
+            None
+        }
+    }
+
+    impl Transport for Fake {
+        fn preflight(
+            &self,
+            _ctx: &OpContext,
+            op: &str,
+            path: &Path,
+            extra: serde_json::Value,
+        ) -> Option<Decision> {
+            let asked = (op.to_string(), path.to_path_buf(), extra);
+            self.asked.borrow_mut().push(asked);
+            let by = if self.fall_open.contains(&op) {
+                AllowedBy::FallbackOpen
+            } else {
+                AllowedBy::Server
+            };
+            (!self.refuse.contains(&op)).then_some(Decision {
+                by: Some(by),
+                ..Decision::default()
+            })
+        }
+
+        fn preflight_async(
+            &self,
+            ctx: &OpContext,
+            op: &str,
+            path: &Path,
+            extra: serde_json::Value,
+            _fd: Option<RawFd>,
+        ) -> (Option<u32>, Option<Decision>) {
+            (None, self.preflight(ctx, op, path, extra))
+        }
+
+        fn take_answers(&self, _path: &Path) {}
+
+        fn post(&self, _ctx: &OpContext, method: &str, params: serde_json::Value) {
+            self.posted.borrow_mut().push((method.to_string(), params));
+        }
+
+        fn send_posts(&self, pending: Vec<(&'static str, serde_json::Value)>) {
+            let ctx = OpContext::enter();
+            for (method, params) in pending {
+                self.post(&ctx, method, params);
+            }
+        }
+
+        fn flush(&self, only: Option<RawFd>) {
+            self.send_posts(take_dirty(|fd, _| only.is_none_or(|o| o == fd), None));
+        }
+
+        fn flush_all(&self) {
+            self.flush(None);
+        }
+
+        fn debug(&self, _method: &str, _params: serde_json::Value) {}
+    }
+
+    fn c(path: &str) -> CString {
+        CString::new(path).unwrap()
+    }
+
+    /// Write `n` bytes to `fd` through the hook.
+    fn write(fake: &Fake, fd: c_int, n: usize) -> libc::ssize_t {
+        let buf = vec![b'x'; n];
+        unsafe { tracked_write(fake, fd, buf.as_ptr().cast(), n) }
+    }
+
+    #[test]
+    fn a_write_is_asked_about_once_and_posted_at_the_close() {
+        let fake = Fake::new(20_100, &[("/proj/w1/a.rs", 40)]);
+        let a = c("/proj/w1/a.rs");
+        let fd = unsafe {
+            tracked_open(
+                &fake,
+                None,
+                a.as_ptr(),
+                libc::O_WRONLY | libc::O_TRUNC,
+                None,
+            )
+        };
+        assert_eq!(
+            (fd, write(&fake, fd, 5), write(&fake, fd, 3)),
+            (20_100, 5, 3)
+        );
+        assert_eq!(unsafe { tracked_close(&fake, fd) }, 0);
+
+        assert_eq!(
+            fake.asked(),
+            [("pre_modify".into(), "/proj/w1/a.rs".into())]
+        );
+        let extra = &fake.asked.borrow()[0].2;
+        assert_eq!(
+            (&extra["source"], &extra["size"]),
+            (&json!("open"), &json!(40))
+        );
+        assert_eq!(fake.posted(), ["post_modify"]);
+        let post = fake.sent(0);
+        assert_eq!(
+            (&post["bytes"], &post["allowed_by"]),
+            (&json!(8), &json!("server"))
+        );
+        assert_eq!(fake.size("/proj/w1/a.rs"), Some(8));
+    }
+
+    #[test]
+    fn a_refused_first_write_fails_and_changes_nothing() {
+        let fake = Fake::new(20_200, &[("/proj/w2/a.rs", 40)]).refusing("pre_modify");
+        let a = c("/proj/w2/a.rs");
+        let fd = unsafe { tracked_open(&fake, None, a.as_ptr(), libc::O_WRONLY, None) };
+        assert_eq!((write(&fake, fd, 5), fake.errno()), (-1, libc::EPERM));
+        assert_eq!(unsafe { tracked_close(&fake, fd) }, 0);
+
+        assert_eq!(fake.asked().len(), 1);
+        assert_eq!(fake.size("/proj/w2/a.rs"), Some(40));
+        assert!(fake.posted().is_empty());
+    }
+
+    #[test]
+    fn a_new_file_is_created_not_modified() {
+        let fake = Fake::new(20_300, &[]);
+        let b = c("w3/b.rs");
+        let flags = libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL;
+        let fd = unsafe { tracked_open(&fake, None, b.as_ptr(), flags, Some(0o600)) };
+        write(&fake, fd, 2);
+        unsafe { tracked_close(&fake, fd) };
+
+        // A relative path is the resolver's to make absolute.
+        assert_eq!(
+            fake.asked(),
+            [("pre_create".into(), "/proj/w3/b.rs".into())]
+        );
+        assert_eq!(fake.posted(), ["post_create"]);
+        let post = fake.sent(0);
+        assert_eq!((&post["mode"], &post["size"]), (&json!("0600"), &json!(2)));
+    }
+
+    #[test]
+    fn appends_are_posted_without_asking() {
+        let fake = Fake::new(20_400, &[("/proj/w4/log", 100)]);
+        let log = c("/proj/w4/log");
+        let fd = unsafe {
+            tracked_open(
+                &fake,
+                None,
+                log.as_ptr(),
+                libc::O_WRONLY | libc::O_APPEND,
+                None,
+            )
+        };
+        write(&fake, fd, 10);
+        unsafe { tracked_close(&fake, fd) };
+
+        assert!(fake.asked().is_empty());
+        assert_eq!(fake.posted(), ["post_modify"]);
+        assert_eq!(fake.sent(0)["append"], true);
+    }
+
+    #[test]
+    fn an_allowed_write_covers_a_truncate_through_the_same_fd() {
+        let fake = Fake::new(20_500, &[("/proj/w5/a.rs", 40)]);
+        let a = c("/proj/w5/a.rs");
+        let fd = unsafe { tracked_open(&fake, None, a.as_ptr(), libc::O_RDWR, None) };
+        write(&fake, fd, 4);
+        assert_eq!(unsafe { tracked_ftruncate(&fake, fd, 0) }, 0);
+        unsafe { tracked_close(&fake, fd) };
+
+        assert_eq!(fake.asked().len(), 1);
+        assert_eq!(fake.posted(), ["post_modify"]);
+        assert_eq!(fake.sent(0)["truncate"]["length"], 0);
+        assert_eq!(fake.size("/proj/w5/a.rs"), Some(0));
+    }
+
+    #[test]
+    fn a_truncate_by_path_is_asked_about_first() {
+        let fake = Fake::new(20_600, &[("/proj/w6/a.rs", 40)]).refusing("pre_truncate");
+        let a = c("/proj/w6/a.rs");
+        assert_eq!(unsafe { tracked_truncate(&fake, a.as_ptr(), 0) }, -1);
+        assert_eq!(fake.errno(), libc::EPERM);
+
+        let extra = &fake.asked.borrow()[0].2;
+        assert_eq!(
+            (&extra["kind"], &extra["size"]),
+            (&json!("clear"), &json!(40))
+        );
+        assert_eq!(fake.size("/proj/w6/a.rs"), Some(40));
+        assert!(fake.posted().is_empty());
+    }
+
+    #[test]
+    fn an_allowed_path_truncate_covers_the_next_write_back() {
+        let fake = Fake::new(21_200, &[("/proj/w12/a.rs", 40)]);
+        let a = c("/proj/w12/a.rs");
+        let overwrite = |n| unsafe {
+            let fd = tracked_open(
+                &fake,
+                None,
+                a.as_ptr(),
+                libc::O_WRONLY | libc::O_TRUNC,
+                None,
+            );
+            write(&fake, fd, n);
+            tracked_close(&fake, fd)
+        };
+        assert_eq!(unsafe { tracked_truncate(&fake, a.as_ptr(), 0) }, 0);
+        assert_eq!((overwrite(3), overwrite(4)), (0, 0));
+
+        let asked: Vec<String> = fake.asked().into_iter().map(|(op, _)| op).collect();
+        assert_eq!(asked, ["pre_truncate", "pre_modify"]);
+        assert_eq!(fake.posted(), ["post_modify", "post_modify", "post_modify"]);
+        assert_eq!(fake.sent(1)["allowed_by"], "server");
+        assert_eq!(fake.size("/proj/w12/a.rs"), Some(4));
+    }
+
+    #[test]
+    fn only_a_truncate_the_server_allowed_and_that_ran_covers_a_write() {
+        let fake = Fake::new(21_300, &[("/proj/w13/a.rs", 40)]).falling_open("pre_truncate");
+        let (a, b) = (c("/proj/w13/a.rs"), c("/proj/w13/b.rs"));
+        assert_eq!(unsafe { tracked_truncate(&fake, a.as_ptr(), 0) }, 0);
+        let fake_b = Fake::new(21_350, &[("/proj/w13/b.rs", 40)]);
+        assert_eq!(unsafe { tracked_truncate(&fake_b, b.as_ptr(), -1) }, -1);
+        assert_eq!(fake_b.errno(), libc::EINVAL);
+
+        for (fake, path) in [(&fake, &a), (&fake_b, &b)] {
+            let fd = unsafe { tracked_open(fake, None, path.as_ptr(), libc::O_WRONLY, None) };
+            write(fake, fd, 1);
+            unsafe { tracked_close(fake, fd) };
+            let asked: Vec<String> = fake.asked().into_iter().map(|(op, _)| op).collect();
+            assert_eq!(asked, ["pre_truncate", "pre_modify"]);
+        }
+    }
+
+    #[test]
+    fn a_refused_unlink_leaves_the_file() {
+        let fake = Fake::new(20_700, &[("/proj/w7/a.rs", 40)]).refusing("pre_delete");
+        let a = c("/proj/w7/a.rs");
+        assert_eq!(unsafe { tracked_unlink(&fake, a.as_ptr()) }, -1);
+        assert_eq!(fake.errno(), libc::EPERM);
+        assert_eq!(fake.size("/proj/w7/a.rs"), Some(40));
+        assert!(fake.posted().is_empty());
+
+        let fake = Fake::new(20_710, &[("/proj/w7/b.rs", 40)]);
+        let b = c("/proj/w7/b.rs");
+        assert_eq!(unsafe { tracked_unlink(&fake, b.as_ptr()) }, 0);
+        assert_eq!(fake.size("/proj/w7/b.rs"), None);
+        assert_eq!(fake.posted(), ["post_delete"]);
+    }
+
+    #[test]
+    fn a_rename_says_what_it_would_replace() {
+        let files = [
+            ("/proj/w8/a.rs.tmp", 10),
+            ("/proj/w8/a.rs", 2048),
+            ("/proj/w8/b.tmp", 1),
+        ];
+        let fake = Fake::new(20_800, &files);
+        let (tmp, a, b) = (
+            c("/proj/w8/a.rs.tmp"),
+            c("/proj/w8/a.rs"),
+            c("/proj/w8/b.tmp"),
+        );
+        let free = c("/proj/w8/b.rs");
+        assert_eq!(
+            unsafe { tracked_rename(&fake, tmp.as_ptr(), a.as_ptr()) },
+            0
+        );
+        assert_eq!(
+            unsafe { tracked_rename(&fake, b.as_ptr(), free.as_ptr()) },
+            0
+        );
+
+        let asked = fake.asked.borrow();
+        assert_eq!(asked[0].2["old_path"], "/proj/w8/a.rs.tmp");
+        assert_eq!(
+            (
+                &asked[0].2["destination_exists"],
+                &asked[0].2["destination_size"]
+            ),
+            (&json!(true), &json!(2048))
+        );
+        assert_eq!(asked[1].2["destination_exists"], false);
+        assert_eq!(fake.posted(), ["post_modify", "post_create"]);
+        assert_eq!(
+            (&fake.sent(0)["clobbered"], &fake.sent(0)["bytes"]),
+            (&json!(true), &json!(0))
+        );
+        assert_eq!(fake.sent(1)["clobbered"], false);
+        assert_eq!(fake.size("/proj/w8/a.rs"), Some(10));
+    }
+
+    #[test]
+    fn a_refused_rename_moves_nothing() {
+        let fake = Fake::new(20_900, &[("/proj/w9/a.rs", 10)]).refusing("pre_rename");
+        let (a, b) = (c("/proj/w9/a.rs"), c("/proj/w9/b.rs"));
+        assert_eq!(unsafe { tracked_rename(&fake, a.as_ptr(), b.as_ptr()) }, -1);
+        assert_eq!(fake.errno(), libc::EPERM);
+        assert_eq!(
+            (fake.size("/proj/w9/a.rs"), fake.size("/proj/w9/b.rs")),
+            (Some(10), None)
+        );
+        assert!(fake.posted().is_empty());
+    }
+
+    #[test]
+    fn a_failed_call_posts_nothing_and_keeps_its_errno() {
+        let fake = Fake::new(21_000, &[]);
+        let gone = c("/proj/w10/gone.rs");
+        assert_eq!(unsafe { tracked_unlink(&fake, gone.as_ptr()) }, -1);
+        assert_eq!(fake.errno(), libc::ENOENT);
+        assert_eq!(unsafe { tracked_close(&fake, 21_099) }, -1);
+        assert_eq!(fake.errno(), libc::EBADF);
+        assert_eq!(fake.asked().len(), 1);
+        assert!(fake.posted().is_empty());
+    }
+
+    #[test]
+    fn what_the_handlers_send_conforms_to_the_protocol() {
+        let fake = Fake::new(21_100, &[("/proj/w11/a.rs", 40), ("/proj/w11/a.rs.tmp", 3)]);
+        let (a, tmp, b) = (
+            c("/proj/w11/a.rs"),
+            c("/proj/w11/a.rs.tmp"),
+            c("/proj/w11/b.rs"),
+        );
+        let fd = unsafe { tracked_open(&fake, None, a.as_ptr(), libc::O_WRONLY, None) };
+        write(&fake, fd, 5);
+        unsafe {
+            tracked_close(&fake, fd);
+            tracked_truncate(&fake, a.as_ptr(), 1);
+            tracked_rename(&fake, tmp.as_ptr(), a.as_ptr());
+            let fd = tracked_open(
+                &fake,
+                None,
+                b.as_ptr(),
+                libc::O_WRONLY | libc::O_CREAT,
+                Some(0o644),
+            );
+            write(&fake, fd, 1);
+            tracked_close(&fake, fd);
+            tracked_unlink(&fake, b.as_ptr());
+        }
+
+        // What `preflight_params` and `post_params` add, and a traced
+        // process's lineage with them.
+        let sent = |params: serde_json::Value, path: &Path| {
+            let mut sent = json!({
+                "pid": 4243, "root_pid": 4242, "root_argv0": "bash",
+                "path": path.to_string_lossy(), "path_seq": 1, "class": "normal",
+            });
+            if let (Some(s), serde_json::Value::Object(params)) = (sent.as_object_mut(), params) {
+                s.extend(params);
+            }
+            sent
+        };
+        let asked = fake.asked.borrow();
+        assert_eq!(asked.len(), 5);
+        for (id, (op, path, extra)) in asked.iter().enumerate() {
+            let frame = serde_json::to_vec(&crate::RpcCall {
+                jsonrpc: "2.0",
+                id: Some(id as u64 + 1),
+                method: op,
+                params: Some(sent(extra.clone(), path)),
+            })
+            .unwrap();
+            assert_eq!(crate::validate_frame(&frame), Ok(()), "{op}");
+        }
+        let posted = fake.posted.borrow();
+        assert_eq!(posted.len(), 5);
+        for (method, params) in posted.iter() {
+            let path = PathBuf::from(params["path"].as_str().unwrap());
+            let frame = crate::encode_notification(method, sent(params.clone(), &path)).unwrap();
+            assert_eq!(crate::validate_frame(&frame), Ok(()), "{method}");
+        }
+    }
+}
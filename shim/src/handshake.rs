@@ -0,0 +1,40 @@
+//! Telling `shim-run` the library loaded.
+//!
+//! When macOS strips `DYLD_INSERT_LIBRARIES` from a protected binary, or a
+//! shell profile scrubs the environment, the shim never runs and the
+//! server hears nothing at all. So `shim-run` passes the write end of a
+//! pipe as `NVIM_CLAUDE_SHIM_HANDSHAKE_FD`, and the first thing the
+//! library constructor does (bypassed or not: it did load) is write one
+//! byte to it and close it. The variable is removed before `main`, so the
+//! command's own children, which get a shim of their own, don't write to
+//! whatever has reused that fd number; an fd that isn't a pipe is left
+//! alone for the same reason.
+
+use std::os::unix::prelude::RawFd;
+
+use crate::platform;
+
+const ENV: &str = "NVIM_CLAUDE_SHIM_HANDSHAKE_FD";
+
+/// Run first from the platform's library init hook.
+pub(crate) fn announce() {
+    let Some(v) = std::env::var_os(ENV) else {
+        return;
+    };
+    // Still one thread: nothing else reads the environment yet.
+    std::env::remove_var(ENV);
+    let Some(fd) = v.to_str().and_then(|s| s.parse::<RawFd>().ok()) else {
+        return;
+    };
+    let mut st: libc::stat = unsafe { std::mem::zeroed() };
+    if fd < 0
+        || unsafe { libc::fstat(fd, &mut st) } != 0
+        || st.st_mode & libc::S_IFMT != libc::S_IFIFO
+    {
+        return;
+    }
+    unsafe {
+        platform::sys_write(fd, b"1".as_ptr().cast(), 1);
+        platform::sys_close(fd);
+    }
+}
@@ -0,0 +1,90 @@
+//! A cap on the memory the shim holds on to.
+//!
+//! The shim runs inside someone else's program, often one already short
+//! of memory, and what it keeps between calls should never be what tips
+//! it over. The holders that grow with the host's activity each count
+//! their bytes here, roughly (payload or key length plus a fixed
+//! overhead): the reliable window and the sink queues, the allow cache
+//! and the `path_seq` counters. Together they may hold `max_heap_bytes`.
+//! Past it, a queue drops what it was given (counted, as when it is full:
+//! `shim/overflow`, `shim/dropped`), and a cache empties itself and
+//! starts again, or forgets the entry. Nothing fails for want of room.
+//!
+//! Frames are also built in a per-thread buffer (`with_scratch`) rather
+//! than a fresh allocation each.
+
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use serde_json::{json, Value};
+
+use crate::config;
+
+/// Per-entry bookkeeping on top of a key's own bytes: the table slot,
+/// the allocation header, the value.
+pub(crate) const ENTRY: usize = 64;
+
+/// Scratch kept between frames; a larger one is given back after use.
+const SCRATCH_KEEP: usize = 64 << 10;
+
+static USED: AtomicUsize = AtomicUsize::new(0);
+static REFUSED: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    static SCRATCH: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+fn cap() -> usize {
+    config::get().max_heap_bytes
+}
+
+/// Take `n` bytes of the cap; `false`, with nothing taken, when they
+/// don't fit.
+pub(crate) fn charge(n: usize) -> bool {
+    let cap = cap();
+    let fits = USED
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+            let after = used.saturating_add(n);
+            (cap == 0 || after <= cap).then_some(after)
+        })
+        .is_ok();
+    if !fits {
+        REFUSED.fetch_add(1, Ordering::Relaxed);
+    }
+    fits
+}
+
+/// Give back `n` bytes taken with `charge`.
+pub(crate) fn release(n: usize) {
+    let _ = USED.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+        Some(used.saturating_sub(n))
+    });
+}
+
+/// Run `f` on this thread's empty scratch buffer, or on a fresh one while
+/// it is out of reach (in use further up the stack, or torn down).
+pub(crate) fn with_scratch<T>(f: impl FnOnce(&mut Vec<u8>) -> T) -> T {
+    let mut f = Some(f);
+    let pooled = SCRATCH.try_with(|cell| {
+        let mut buf = cell.try_borrow_mut().ok()?;
+        buf.clear();
+        let out = (f.take()?)(&mut buf);
+        if buf.capacity() > SCRATCH_KEEP {
+            *buf = Vec::new();
+        }
+        Some(out)
+    });
+    match pooled.ok().flatten() {
+        Some(out) => out,
+        None => (f.take().expect("scratch closure already run"))(&mut Vec::new()),
+    }
+}
+
+/// For `shim/stats`.
+pub(crate) fn snapshot() -> Value {
+    json!({
+        "used": USED.load(Ordering::Relaxed),
+        "cap": cap(),
+        "refused": REFUSED.load(Ordering::Relaxed),
+    })
+}
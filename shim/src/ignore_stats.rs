@@ -0,0 +1,134 @@
+//! What ignore globs and roots are keeping from the server, so a
+//! misconfigured `ignore` can be spotted.
+//!
+//! Every suppressed preflight or post is counted under the glob that
+//! matched it (or "outside roots", "self paths" for the server's own
+//! files, or the `path_class` whose policy is `off`), and the last `RECENT` are kept as
+//! samples. The server reads both with a `shim/stats` request. While it
+//! has ignore audit on (`shim/ignore_audit`), each ignored path is also
+//! reported as a `shim/ignored` notification, at most once per path every
+//! `AUDIT_DEBOUNCE`.
+
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+
+use crate::config::{self, IgnoredBy};
+
+const RECENT: usize = 20;
+const AUDIT_DEBOUNCE: Duration = Duration::from_secs(5);
+/// Paths remembered for debouncing before the memory is dropped.
+const AUDIT_PATHS: usize = 1024;
+
+#[derive(Default)]
+struct Stats {
+    /// Parallel to `ShimConfig::ignore`.
+    by_glob: Vec<u64>,
+    outside_roots: u64,
+    self_paths: u64,
+    by_class: HashMap<&'static str, u64>,
+    recent: VecDeque<(String, PathBuf)>,
+    last_audit: HashMap<PathBuf, Instant>,
+    audit_queue: Vec<Value>,
+}
+
+static STATS: Lazy<Mutex<Stats>> = Lazy::new(|| Mutex::new(Stats::default()));
+static AUDIT: AtomicBool = AtomicBool::new(false);
+
+/// Count `op` on `path` as kept back by `by`.
+pub(crate) fn record(op: &str, path: &Path, by: IgnoredBy) {
+    let mut st = STATS.lock();
+    match by {
+        IgnoredBy::OutsideRoots => st.outside_roots += 1,
+        IgnoredBy::SelfPath => st.self_paths += 1,
+        IgnoredBy::Class(class) => *st.by_class.entry(class.name()).or_default() += 1,
+        IgnoredBy::Glob(i) => {
+            if st.by_glob.len() <= i {
+                st.by_glob.resize(i + 1, 0);
+            }
+            st.by_glob[i] += 1;
+        }
+    }
+    if st.recent.len() == RECENT {
+        st.recent.pop_front();
+    }
+    st.recent.push_back((op.to_string(), path.to_path_buf()));
+
+    if !AUDIT.load(Ordering::Relaxed) {
+        return;
+    }
+    let now = Instant::now();
+    if st
+        .last_audit
+        .get(path)
+        .is_some_and(|at| now.duration_since(*at) < AUDIT_DEBOUNCE)
+    {
+        return;
+    }
+    if st.last_audit.len() >= AUDIT_PATHS {
+        st.last_audit.clear();
+    }
+    st.last_audit.insert(path.to_path_buf(), now);
+    st.audit_queue.push(json!({
+        "op": op,
+        "path": path.to_string_lossy(),
+        "by": reason(by),
+    }));
+}
+
+/// `by` as `shim/ignored` names it.
+pub(crate) fn reason(by: IgnoredBy) -> Value {
+    match by {
+        IgnoredBy::OutsideRoots => json!({ "outside_roots": true }),
+        IgnoredBy::SelfPath => json!({ "self_path": true }),
+        IgnoredBy::Class(class) => json!({ "class": class.name() }),
+        IgnoredBy::Glob(i) => json!({ "glob": config::get().ignore.get(i) }),
+    }
+}
+
+/// Turn ignore audit on or off; off also forgets the debounce state.
+pub(crate) fn set_audit(on: bool) {
+    AUDIT.store(on, Ordering::Relaxed);
+    if !on {
+        let mut st = STATS.lock();
+        st.last_audit.clear();
+        st.audit_queue.clear();
+    }
+}
+
+/// `shim/ignored` params waiting to be sent.
+pub(crate) fn take_audit() -> Vec<Value> {
+    if !AUDIT.load(Ordering::Relaxed) {
+        return Vec::new();
+    }
+    std::mem::take(&mut STATS.lock().audit_queue)
+}
+
+/// The `ignored` section of `shim/stats`.
+pub(crate) fn snapshot() -> Value {
+    let st = STATS.lock();
+    let globs: serde_json::Map<String, Value> = config::get()
+        .ignore
+        .iter()
+        .enumerate()
+        .map(|(i, g)| (g.clone(), json!(st.by_glob.get(i).copied().unwrap_or(0))))
+        .collect();
+    let recent: Vec<Value> = st
+        .recent
+        .iter()
+        .map(|(op, path)| json!({ "op": op, "path": path.to_string_lossy() }))
+        .collect();
+    json!({
+        "globs": globs,
+        "outside_roots": st.outside_roots,
+        "self_paths": st.self_paths,
+        "classes": st.by_class,
+        "recent": recent,
+        "audit": AUDIT.load(Ordering::Relaxed),
+    })
+}
@@ -0,0 +1,126 @@
+//! Files reached only through an fd.
+//!
+//! An fd can outlive its file's name: the file is unlinked while open
+//! (the usual way to make a temporary), or the fd came from somewhere the
+//! shim never saw a path for and the platform can't say either. A
+//! metadata change through such an fd used to be dropped for want of a
+//! path. It is now reported with the file's identity instead,
+//!
+//! ```json
+//! { "method": "post_chmod",
+//!   "params": { "path": null, "dev": 64769, "ino": 1311, "mode": "0600",
+//!               "last_path": "/p/a.rs" } }
+//! ```
+//!
+//! where `last_path` is the last path this process knew for that inode,
+//! when it knew one. Every fd resolved to a path (and every tracked fd
+//! `fstat`ed) remembers its (dev, ino) here, for that. At most `CAP`
+//! inodes are kept, within the `heap` cap; past that the table starts
+//! again. An fd whose file has no links left counts as unresolved even
+//! when a path is known for it, since the path now names nothing, or
+//! something else. Only regular files are reported that way; a pipe or
+//! socket has no file to report.
+
+use std::collections::HashMap;
+use std::os::unix::prelude::RawFd;
+use std::path::{Path, PathBuf};
+
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+
+use crate::{heap, platform};
+
+const CAP: usize = 1024;
+
+#[derive(Default)]
+struct Known {
+    pid: i32,
+    paths: HashMap<(u64, u64), PathBuf>,
+}
+
+static KNOWN: Mutex<Option<Known>> = parking_lot::const_mutex(None);
+
+fn cost(path: &Path) -> usize {
+    path.as_os_str().len() + heap::ENTRY
+}
+
+fn with<T>(f: impl FnOnce(&mut Known) -> T) -> T {
+    let pid = unsafe { libc::getpid() };
+    let mut known = KNOWN.lock();
+    let k = known.get_or_insert_with(Known::default);
+    if k.pid != pid {
+        clear(k);
+        k.pid = pid;
+    }
+    f(k)
+}
+
+fn clear(k: &mut Known) {
+    for path in k.paths.values() {
+        heap::release(cost(path));
+    }
+    k.paths.clear();
+}
+
+/// `path` names the file at (`dev`, `ino`).
+pub(crate) fn remember(dev: u64, ino: u64, path: &Path) {
+    with(|k| {
+        if k.paths.get(&(dev, ino)).is_some_and(|p| p == path) {
+            return;
+        }
+        if let Some(old) = k.paths.remove(&(dev, ino)) {
+            heap::release(cost(&old));
+        }
+        if k.paths.len() >= CAP {
+            clear(k);
+        }
+        if heap::charge(cost(path)) {
+            k.paths.insert((dev, ino), path.to_path_buf());
+        }
+    });
+}
+
+fn last_path(dev: u64, ino: u64) -> Option<PathBuf> {
+    with(|k| k.paths.get(&(dev, ino)).cloned())
+}
+
+/// The params naming the file `fd` is open on: `{"path": ...}`, or the
+/// identity described above. `known` is the path the shim already has
+/// for `fd`, if any. `None` when `fd` can't be looked at, or has no path
+/// and no file to report.
+pub(crate) fn params(fd: RawFd, known: Option<PathBuf>) -> Option<Value> {
+    let mut st: libc::stat = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstat(fd, &mut st) } != 0 {
+        return None;
+    }
+    // `stat` field types differ between platforms.
+    #[allow(clippy::unnecessary_cast)]
+    let (dev, ino) = (st.st_dev as u64, st.st_ino as u64);
+    if st.st_nlink > 0 {
+        if let Some(p) = known.or_else(|| platform::fd_path(fd)) {
+            remember(dev, ino, &p);
+            return Some(json!({ "path": p.to_string_lossy() }));
+        }
+    }
+    if st.st_mode & libc::S_IFMT != libc::S_IFREG {
+        return None;
+    }
+    let mut params = json!({ "path": null, "dev": dev, "ino": ino });
+    if let Some(p) = last_path(dev, ino) {
+        params["last_path"] = json!(p.to_string_lossy());
+    }
+    Some(params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_last_path_of_an_inode_is_kept() {
+        remember(7, 11, Path::new("/s/a.rs"));
+        remember(7, 11, Path::new("/s/b.rs"));
+        assert_eq!(last_path(7, 11).as_deref(), Some(Path::new("/s/b.rs")));
+        assert_eq!(last_path(7, 12), None);
+    }
+}
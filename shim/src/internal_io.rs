@@ -0,0 +1,165 @@
+//! File I/O the shim does for itself: the config file, the TLS CA bundle,
+//! the denial log, the session's cache token.
+//!
+//! Opens and closes go straight to the platform's raw calls rather than
+//! through our own hooks, so they never reach `FD_TABLE` or cost an
+//! `OpContext` round trip. Each fd is recorded in `OWNED` (and counted
+//! in `own_fds`) while it is open, and the write/close/ftruncate handlers
+//! assert (in debug builds) that they never see one. Reads aren't hooked
+//! and use `read(2)` directly.
+
+use std::collections::HashSet;
+use std::ffi::CString;
+use std::io;
+use std::os::raw::c_void;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::prelude::RawFd;
+use std::path::Path;
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+
+use crate::{own_fds, platform};
+
+static OWNED: Lazy<Mutex<HashSet<RawFd>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+/// An fd the shim opened for itself; closed on drop.
+struct ShimFile(RawFd, own_fds::Held);
+
+impl ShimFile {
+    fn open(path: &Path) -> io::Result<ShimFile> {
+        ShimFile::open_with(path, libc::O_RDONLY, 0)
+    }
+
+    fn open_with(path: &Path, flags: libc::c_int, mode: libc::mode_t) -> io::Result<ShimFile> {
+        let c = CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let fd = unsafe { platform::sys_open(c.as_ptr(), flags | libc::O_CLOEXEC, mode) };
+        if fd < 0 {
+            let e = io::Error::last_os_error();
+            own_fds::note(&e);
+            return Err(e);
+        }
+        OWNED.lock().insert(fd);
+        Ok(ShimFile(fd, own_fds::Held::new()))
+    }
+
+    fn read_to_end(&self) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        let mut buf = [0u8; 8192];
+        loop {
+            let n = unsafe { libc::read(self.0, buf.as_mut_ptr() as *mut c_void, buf.len()) };
+            match n {
+                0 => return Ok(out),
+                n if n > 0 => out.extend_from_slice(&buf[..n as usize]),
+                _ => {
+                    let e = io::Error::last_os_error();
+                    if e.kind() != io::ErrorKind::Interrupted {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Drop for ShimFile {
+    fn drop(&mut self) {
+        // Forget it first: once closed, the number can be reused by anyone.
+        OWNED.lock().remove(&self.0);
+        unsafe { platform::sys_close(self.0) };
+    }
+}
+
+/// The whole file at `path`.
+pub(crate) fn read(path: &Path) -> io::Result<Vec<u8>> {
+    ShimFile::open(path)?.read_to_end()
+}
+
+/// The whole file at `path`, which must be UTF-8.
+pub(crate) fn read_to_string(path: &Path) -> io::Result<String> {
+    String::from_utf8(read(path)?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Add `bytes` to the end of the file at `path`, creating it (owner-only)
+/// if need be. One `write`, so concurrent appenders' lines don't interleave.
+pub(crate) fn append(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let f = ShimFile::open_with(path, libc::O_WRONLY | libc::O_APPEND | libc::O_CREAT, 0o600)?;
+    let n = unsafe { platform::sys_write(f.0, bytes.as_ptr() as *const c_void, bytes.len()) };
+    match n {
+        n if n as usize == bytes.len() => Ok(()),
+        n if n >= 0 => Err(io::ErrorKind::WriteZero.into()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Make the file at `path` hold `bytes`, creating it (owner-only) if need
+/// be. Written to a name beside it and renamed over it, so a reader finds
+/// the old contents or the new ones.
+pub(crate) fn replace(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let mut beside = path.as_os_str().to_owned();
+    beside.push(format!(".{}.tmp", std::process::id()));
+    let beside = Path::new(&beside);
+    {
+        let f = ShimFile::open_with(
+            beside,
+            libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC,
+            0o600,
+        )?;
+        let n = unsafe { platform::sys_write(f.0, bytes.as_ptr() as *const c_void, bytes.len()) };
+        if n < 0 || n as usize != bytes.len() {
+            let e = io::Error::last_os_error();
+            let _ = std::fs::remove_file(beside);
+            return Err(if n < 0 {
+                e
+            } else {
+                io::ErrorKind::WriteZero.into()
+            });
+        }
+    }
+    let c = |p: &Path| {
+        CString::new(p.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    };
+    let (from, to) = (c(beside)?, c(path)?);
+    if unsafe { platform::sys_rename(from.as_ptr(), to.as_ptr()) } < 0 {
+        let e = io::Error::last_os_error();
+        let _ = std::fs::remove_file(beside);
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Whether `fd` is one of ours, for handlers' debug assertions.
+pub(crate) fn is_owned(fd: RawFd) -> bool {
+    OWNED.lock().contains(&fd)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_whole_files_and_releases_the_fd() {
+        let path = std::env::temp_dir().join(format!("shim-io-{}", std::process::id()));
+        let text = "x".repeat(20_000);
+        std::fs::write(&path, &text).unwrap();
+        let f = ShimFile::open(&path).unwrap();
+        assert!(is_owned(f.0));
+        assert_eq!(f.read_to_end().unwrap(), text.as_bytes());
+        let fd = f.0;
+        drop(f);
+        assert!(!is_owned(fd));
+        std::fs::remove_file(&path).unwrap();
+        assert!(read(&path).is_err());
+    }
+
+    #[test]
+    fn replaced_files_hold_only_the_new_contents() {
+        let path = std::env::temp_dir().join(format!("shim-io-replace-{}", std::process::id()));
+        std::fs::write(&path, "an older, longer token\n").unwrap();
+        replace(&path, b"new\n").unwrap();
+        assert_eq!(read_to_string(&path).unwrap(), "new\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,522 +1,1202 @@
 #![deny(unsafe_op_in_unsafe_fn)]
 #![allow(clippy::missing_safety_doc)]
-
-#[cfg(not(target_os = "macos"))]
-compile_error!("This shim currently targets macOS (dyld __interpose).");
+// Unit-test builds skip the interpose/export glue, so handlers look unused.
+// `notify-only` compiles out the preflight and read paths; what only they
+// used is left unreferenced for the linker to drop.
+#![cfg_attr(any(test, feature = "notify-only"), allow(dead_code))]
 
 use once_cell::sync::Lazy;
-use serde::{Deserialize, Serialize};
-use serde_json::json;
-use std::cell::{Cell, RefCell};
 use parking_lot::Mutex;
-use std::collections::HashMap;
+use serde::Serialize;
+use serde_json::json;
+use std::borrow::Cow;
 use std::ffi::{CStr, OsStr};
 use std::os::raw::{c_char, c_int, c_void};
 use std::os::unix::ffi::OsStrExt;
-use std::os::unix::net::UnixStream;
-use std::os::unix::prelude::{AsRawFd, RawFd};
+use std::os::unix::prelude::RawFd;
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU32, Ordering};
 use std::time::{Duration, Instant};
 
+mod acl;
+mod after_denied;
+mod allow_cache;
+mod async_pre;
+mod backtrace;
+mod batch;
+mod breaker;
+mod budget;
+mod bypass;
+mod classify;
+mod config;
+mod conflicts;
+mod conformance;
+#[cfg(test)]
+mod conformance_vectors;
+mod contain;
+mod content_tier;
+mod creds;
+mod critical;
+mod debug_coalesce;
+mod demux;
+mod denials;
+mod dir_cache;
+mod dirty_age;
+mod escape;
+mod exec_fds;
+mod fanout;
+mod fdtable;
+mod force;
+mod framing;
+mod glob;
+mod handlers;
+mod handshake;
+mod heap;
+mod ignore_stats;
+mod inodes;
+mod internal_io;
+mod lineage;
+mod locks;
+mod msgpack;
+mod op_context;
+mod own_fds;
+mod path_class;
+mod path_seq;
+mod paths;
+mod platform;
+mod probes;
+mod procinfo;
+mod project;
+mod rate_limit;
+mod reliable;
+mod rename_chain;
+mod routes;
+mod self_created;
+mod self_paths;
+mod session_cache;
+mod shutdown;
+mod single_flight;
+mod sockpath;
+mod special;
+mod summary;
+mod symlink_escape;
+mod tcp_addr;
+mod thread_info;
+#[cfg(feature = "tls")]
+mod tls;
+mod touches;
+mod transport;
+mod truncated;
+mod verify;
+mod watch_dirs;
+mod writers;
+mod xdev;
+
+use breaker::Breaker;
+use contain::Hook;
+use fdtable::{fd_stat, tracked_path, FdState, FD_TABLE};
+use handlers::{
+    modify_params, rearm_preflights, settle_async, tracked_acl, tracked_chdir, tracked_close,
+    tracked_exec, tracked_exit, tracked_fchdir, tracked_fd_meta, tracked_fflush, tracked_ftruncate,
+    tracked_lock, tracked_mknod, tracked_open, tracked_pwrite, tracked_rename, tracked_setid,
+    tracked_shm_open, tracked_shm_unlink, tracked_spawn, tracked_touch, tracked_truncate,
+    tracked_unlink, tracked_write, tracked_writev, Live,
+};
 #[cfg(target_os = "macos")]
-const F_GETPATH: c_int = libc::F_GETPATH;
+use handlers::{tracked_copyfile, tracked_removefile, tracked_renameatx};
+#[cfg(target_os = "linux")]
+use handlers::{tracked_renameat, tracked_unlinkat};
+use op_context::OpContext;
+pub(crate) use transport::{connect, on_slot, with_exit_stream, Conn, ConnectError};
+use transport::{with_stream_for, with_thread_stream, PlainChannel};
 
-#[cfg(target_os = "macos")]
-mod darwin_sys {
-    use libc::c_int;
+/// The control protocol's contract, for servers' conformance tests.
+pub use conformance::{schemas, validate_frame, ConformanceError, PROTOCOL_VERSION};
 
-    pub const SYS_WRITE: c_int = 4;
-    pub const SYS_PWRITE: c_int = 154;
-    pub const SYS_WRITEV: c_int = 121;
-    pub const SYS_CLOSE: c_int = 6;
-    pub const SYS_UNLINK: c_int = 10;
-    pub const SYS_RENAME: c_int = 128;
-    pub const SYS_TRUNCATE: c_int = 200;
-    pub const SYS_FTRUNCATE: c_int = 201;
+//
+// -------- Build identity --------
+//
+
+/// `<version>+<git hash>` when built by `cargo xtask dist`, which sets
+/// `NVIM_CLAUDE_SHIM_BUILD_ID` once for every slice of a universal build;
+/// plain `cargo build` falls back to the crate version.
+pub(crate) const BUILD_ID: &str = match option_env!("NVIM_CLAUDE_SHIM_BUILD_ID") {
+    Some(id) => id,
+    None => env!("CARGO_PKG_VERSION"),
+};
+
+static BUILD_ID_C: Lazy<std::ffi::CString> =
+    Lazy::new(|| std::ffi::CString::new(BUILD_ID).unwrap_or_default());
+
+/// Lets installers (and the xtask smoke test) ask a loaded library which
+/// build it is.
+#[no_mangle]
+pub extern "C" fn nvim_claude_shim_version() -> *const c_char {
+    BUILD_ID_C.as_ptr()
 }
 
 //
-// -------- Execution context / recursion guard --------
+// -------- Execution context --------
 //
 
-#[derive(Debug)]
-struct Guard {
-    primary: bool,
-    enabled: bool,
+pub(crate) static SHIM_READY: AtomicBool = AtomicBool::new(false);
+
+//
+// -------- Environment + destination --------
+//
+
+fn env_flag(name: &str) -> bool {
+    std::env::var(name)
+        .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+        .unwrap_or(false)
 }
 
-static SHIM_READY: AtomicBool = AtomicBool::new(false);
+#[derive(Debug)]
+pub(crate) enum Destination {
+    /// Expanded `NVIM_CLAUDE_SHIM_SOCK` candidates, tried in order, or the
+    /// one socket discovery found.
+    Unix(Vec<PathBuf>),
+    Tcp(tcp_addr::TcpAddr),
+    /// A pre-connected stream inherited via `NVIM_CLAUDE_SHIM_SOCK_FD`.
+    /// Threads share it, so each exchange holds the `INHERITED` lock.
+    Fd(RawFd),
+    Disabled,
+}
 
-unsafe extern "C" fn shim_library_init() {
-    SHIM_READY.store(true, Ordering::SeqCst);
+impl Destination {
+    fn from_config(d: &config::DestinationConfig) -> Destination {
+        match d.kind {
+            config::DestinationKind::Unix => Destination::Unix(unix_candidates(&d.address)),
+            config::DestinationKind::Tcp => match tcp_addr::parse(&d.address) {
+                Ok(addr) => Destination::Tcp(addr),
+                Err(e) => {
+                    let file = std::env::var_os("NVIM_CLAUDE_SHIM_CONFIG").unwrap_or_default();
+                    project::record(Path::new(&file), None, &format!("[[destination]] {e}"));
+                    report_error("config_error", &e, None);
+                    Destination::Disabled
+                }
+            },
+        }
+    }
 }
 
-#[cfg_attr(target_os = "macos", link_section = "__DATA,__mod_init_func")]
-#[used]
-static SHIM_INIT_HOOK: unsafe extern "C" fn() = shim_library_init;
+/// Whether the environment names the primary destination, leaving every
+/// `[[destination]]` for fan-out.
+fn env_names_destination() -> bool {
+    [
+        "NVIM_CLAUDE_SHIM_SOCK_FD",
+        "NVIM_CLAUDE_SHIM_SOCK",
+        "NVIM_CLAUDE_SHIM_TCP",
+    ]
+    .iter()
+    .any(|k| std::env::var_os(k).is_some())
+}
 
-thread_local! {
-    static IN_SHIM: Cell<u32> = Cell::new(0);
+fn unix_candidates(value: &str) -> Vec<PathBuf> {
+    let paths = sockpath::candidates(value, &sockpath::from_process(), |e| {
+        log_debug(&format!("shim: skipping socket candidate: {e}\n"))
+    });
+    paths.into_iter().filter(|p| socket_dir_ok(p)).collect()
 }
 
-impl Guard {
-    fn enter() -> Guard {
-        if !SHIM_READY.load(Ordering::Relaxed) {
-            return Guard {
-                primary: false,
-                enabled: false,
-            };
+// The inherited control fd, or -1. Kept outside `DESTINATION` so the close
+// hook can check it without forcing the Lazy (discovery closes its probe).
+static INHERITED_FD: AtomicI32 = AtomicI32::new(-1);
+/// The connection over the inherited fd, set up on first use.
+static INHERITED: Mutex<Option<Conn>> = parking_lot::const_mutex(None);
+
+/// Validate an inherited fd: it must be an open stream socket. It is made
+/// blocking and inheritable, so exec'd children that see the same
+/// `NVIM_CLAUDE_SHIM_SOCK_FD` find it open.
+fn adopt_inherited_fd(value: &str) -> Option<RawFd> {
+    let fd: RawFd = value.trim().parse().ok().filter(|&fd| fd >= 0)?;
+    let mut ty: c_int = 0;
+    let mut len = std::mem::size_of::<c_int>() as libc::socklen_t;
+    let rc = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TYPE,
+            &mut ty as *mut c_int as *mut c_void,
+            &mut len,
+        )
+    };
+    if rc != 0 || ty != libc::SOCK_STREAM {
+        return None;
+    }
+    unsafe {
+        let fl = libc::fcntl(fd, libc::F_GETFL);
+        if fl >= 0 && fl & libc::O_NONBLOCK != 0 {
+            libc::fcntl(fd, libc::F_SETFL, fl & !libc::O_NONBLOCK);
         }
-        let mut primary = false;
-        IN_SHIM.with(|cell| {
-            let depth = cell.get();
-            if depth == 0 {
-                primary = true;
-            }
-            cell.set(depth.saturating_add(1));
-        });
-        Guard {
-            primary,
-            enabled: true,
+        let fd_fl = libc::fcntl(fd, libc::F_GETFD);
+        if fd_fl >= 0 && fd_fl & libc::FD_CLOEXEC != 0 {
+            libc::fcntl(fd, libc::F_SETFD, fd_fl & !libc::FD_CLOEXEC);
         }
     }
-    fn is_primary(&self) -> bool {
-        self.primary
-    }
+    INHERITED_FD.store(fd, Ordering::Release);
+    Some(fd)
 }
-impl Drop for Guard {
-    fn drop(&mut self) {
-        if !self.enabled {
-            return;
+
+/// Run from the platform's library init hook, before the program's `main`
+/// can close the inherited fd.
+pub(crate) fn adopt_inherited_fd_from_env() {
+    if let Ok(v) = std::env::var("NVIM_CLAUDE_SHIM_SOCK_FD") {
+        if adopt_inherited_fd(&v).is_none() {
+            log_debug("shim: NVIM_CLAUDE_SHIM_SOCK_FD is not a stream socket\n");
         }
-        IN_SHIM.with(|cell| {
-            let depth = cell.get();
-            cell.set(depth.saturating_sub(1));
-        });
     }
 }
 
-#[inline]
-fn in_shim() -> bool {
-    if !SHIM_READY.load(Ordering::Relaxed) {
-        return false;
-    }
-    IN_SHIM.with(|cell| cell.get() > 0)
-}
+/// The primary's breaker, with its `[[destination]]`'s send timeout when
+/// that is where it came from.
+static PRIMARY_BREAKER: Lazy<Breaker> = Lazy::new(|| {
+    let cfg = config::get();
+    let from_config = (!env_names_destination())
+        .then(|| cfg.first_authoritative())
+        .flatten();
+    Breaker::new(cfg.send_timeout(from_config))
+});
 
-#[inline]
-unsafe fn syscall_write(fd: c_int, buf: *const c_void, count: libc::size_t) -> libc::ssize_t {
-    unsafe {
-        libc::syscall(
-        darwin_sys::SYS_WRITE,
-        fd as libc::intptr_t,
-        buf as libc::intptr_t,
-        count as libc::intptr_t,
-    ) as libc::ssize_t
-    }
+fn is_inherited_fd(fd: RawFd) -> bool {
+    fd >= 0 && INHERITED_FD.load(Ordering::Acquire) == fd
 }
+// Forced on first use rather than at load, so the placeholders in the socket
+// path see the environment and session the traced process actually runs in.
+// Discovery probes once here; its result is cached for the process.
+static DESTINATION: Lazy<Destination> = Lazy::new(|| {
+    let fd = INHERITED_FD.load(Ordering::Acquire);
+    if fd >= 0 {
+        // A TCP fd has no peer credentials; only Unix peers are checked.
+        if platform::peer_uid(fd).is_some() && !peer_trusted(fd) {
+            return Destination::Disabled;
+        }
+        return Destination::Fd(fd);
+    }
+    if let Ok(v) = std::env::var("NVIM_CLAUDE_SHIM_SOCK") {
+        return Destination::Unix(unix_candidates(&v));
+    }
+    if let Some(addr) = std::env::var_os("NVIM_CLAUDE_SHIM_TCP") {
+        return match tcp_addr::parse(&addr.to_string_lossy()) {
+            Ok(addr) => Destination::Tcp(addr),
+            Err(e) => {
+                project::record_env("NVIM_CLAUDE_SHIM_TCP", &e);
+                report_error("config_error", &format!("NVIM_CLAUDE_SHIM_TCP {e}"), None);
+                Destination::Disabled
+            }
+        };
+    }
+    if let Some(d) = config::get().first_authoritative() {
+        return Destination::from_config(d);
+    }
+    if env_flag("NVIM_CLAUDE_SHIM_NO_DISCOVERY") {
+        return Destination::Disabled;
+    }
+    let mut conventional = sockpath::conventional(&sockpath::from_process());
+    conventional.retain(|p| socket_dir_ok(p));
+    match sockpath::discover(&conventional) {
+        Some(p) => {
+            log_debug(&format!("shim: discovered socket {}\n", p.display()));
+            Destination::Unix(vec![p])
+        }
+        None => Destination::Disabled,
+    }
+});
 
-#[inline]
-unsafe fn syscall_pwrite(
-    fd: c_int,
-    buf: *const c_void,
-    count: libc::size_t,
-    offset: libc::off_t,
-) -> libc::ssize_t {
-    unsafe {
-        libc::syscall(
-            darwin_sys::SYS_PWRITE,
-            fd as libc::intptr_t,
-            buf as libc::intptr_t,
-            count as libc::intptr_t,
-            offset as libc::intptr_t,
-        ) as libc::ssize_t
+// Set once the primary destination's peer fails the uid check; from then on
+// it is treated as disabled for the whole process.
+static PEER_UNTRUSTED: AtomicBool = AtomicBool::new(false);
+
+static ALLOW_ROOT_PEER: Lazy<bool> = Lazy::new(|| env_flag("NVIM_CLAUDE_SHIM_ALLOW_ROOT_PEER"));
+static ALLOW_INSECURE_DIR: Lazy<bool> =
+    Lazy::new(|| env_flag("NVIM_CLAUDE_SHIM_ALLOW_INSECURE_DIR"));
+
+fn destination_disabled() -> bool {
+    match &*DESTINATION {
+        Destination::Disabled => true,
+        Destination::Fd(_) => PEER_UNTRUSTED.load(Ordering::Relaxed),
+        _ => PEER_UNTRUSTED.load(Ordering::Relaxed) || own_fds::exhausted(),
     }
 }
 
-#[inline]
-unsafe fn syscall_writev(
-    fd: c_int,
-    iov: *const libc::iovec,
-    iovcnt: c_int,
-) -> libc::ssize_t {
-    unsafe {
-        libc::syscall(
-            darwin_sys::SYS_WRITEV,
-            fd as libc::intptr_t,
-            iov as libc::intptr_t,
-            iovcnt as libc::intptr_t,
-        ) as libc::ssize_t
+/// Whoever answers on the socket decides what is allowed, so it must be us
+/// (or root, if allowed). A mismatch is reported on stderr regardless of
+/// `NVIM_CLAUDE_SHIM_DEBUG`, and to the peer as `shim/error`; the caller
+/// disables the destination.
+fn peer_trusted(fd: RawFd) -> bool {
+    let me = unsafe { libc::geteuid() };
+    let peer = platform::peer_uid(fd);
+    if peer == Some(me) || (peer == Some(0) && *ALLOW_ROOT_PEER) {
+        return true;
     }
+    let shown = peer.map_or_else(|| "unknown".to_string(), |u| u.to_string());
+    report_error(
+        "peer_uid_mismatch",
+        &format!("socket peer uid {shown} is not {me}; destination disabled"),
+        Some(&mut Conn::new(Box::new(PlainChannel(fd)), &PRIMARY_BREAKER)),
+    );
+    false
 }
 
-#[inline]
-unsafe fn syscall_close(fd: c_int) -> c_int {
-    unsafe { libc::syscall(darwin_sys::SYS_CLOSE, fd as libc::intptr_t) as c_int }
+/// Problems the user has to act on: always printed to stderr, and sent as
+/// `shim/error` when there is a channel to send it on.
+fn report_error(kind: &str, detail: &str, conn: Option<&mut Conn>) {
+    let shown = escape::line(&format!("nvim-claude shim: {kind}: {detail}\n"));
+    platform::stderr_write(shown.as_bytes());
+    let Some(conn) = conn else {
+        return;
+    };
+    let params = json!({ "error": kind, "detail": detail, "pid": unsafe { libc::getpid() } });
+    conn.notify("shim/error", params);
 }
 
-#[inline]
-unsafe fn syscall_unlink(path: *const c_char) -> c_int {
-    unsafe { libc::syscall(darwin_sys::SYS_UNLINK, path as libc::intptr_t) as c_int }
-}
+/// Refuse sockets in world-writable directories without the sticky bit,
+/// where anyone could have created (or can replace) the socket first.
+fn socket_dir_ok(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
 
-#[inline]
-unsafe fn syscall_rename(old: *const c_char, new: *const c_char) -> c_int {
-    unsafe {
-        libc::syscall(
-            darwin_sys::SYS_RENAME,
-            old as libc::intptr_t,
-            new as libc::intptr_t,
-        ) as c_int
+    if *ALLOW_INSECURE_DIR {
+        return true;
     }
-}
-
-#[inline]
-unsafe fn syscall_truncate_path(path: *const c_char, len: libc::off_t) -> c_int {
-    unsafe {
-        libc::syscall(
-            darwin_sys::SYS_TRUNCATE,
-            path as libc::intptr_t,
-            len as libc::intptr_t,
-        ) as c_int
+    let Some(mode) = path
+        .parent()
+        .and_then(|d| std::fs::metadata(d).ok())
+        .map(|m| m.permissions().mode())
+    else {
+        return true;
+    };
+    let ok = mode & 0o002 == 0 || mode & 0o1000 != 0;
+    if !ok {
+        log_debug(&format!(
+            "shim: refusing socket in world-writable dir: {}\n",
+            path.display()
+        ));
     }
+    ok
 }
 
-#[inline]
-unsafe fn syscall_ftruncate_fd(fd: c_int, len: libc::off_t) -> c_int {
-    unsafe {
-        libc::syscall(
-            darwin_sys::SYS_FTRUNCATE,
-            fd as libc::intptr_t,
-            len as libc::intptr_t,
-        ) as c_int
+// Set by `shim-run --supervise`: the seccomp supervisor already preflights
+// every delete/rename/truncate syscall, including the ones we forward.
+static SUPERVISED: Lazy<bool> = Lazy::new(|| env_flag("NVIM_CLAUDE_SHIM_SUPERVISED"));
+
+static DEBUG: Lazy<bool> = Lazy::new(|| env_flag("NVIM_CLAUDE_SHIM_DEBUG"));
+
+static FAIL_CLOSED: Lazy<bool> = Lazy::new(|| env_flag("FS_SHIM_FAIL_CLOSED"));
+
+static PRE_TIMEOUT_MS: Lazy<u64> = Lazy::new(|| {
+    std::env::var("FS_SHIM_PRE_TIMEOUT_MS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(1500)
+});
+
+fn log_debug(msg: &str) {
+    if !*DEBUG {
+        return;
     }
+    platform::stderr_write(escape::line(msg).as_bytes());
 }
 
 //
-// -------- File descriptor tracking --------
+// -------- Minimal JSON-RPC helpers --------
 //
 
-#[derive(Debug, Clone)]
-struct FdState {
-    path: Option<PathBuf>,
-    dev: u64,
-    ino: u64,
-    dirty: bool,
-    pre_sent: bool, // did we already block on the first write/truncate for this FD?
+#[derive(Serialize)]
+struct RpcCall<'a, T: Serialize> {
+    jsonrpc: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<u64>,
+    method: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    params: Option<T>,
 }
 
-static FD_TABLE: Lazy<Mutex<HashMap<RawFd, FdState>>> = Lazy::new(|| Mutex::new(HashMap::new()));
-
-fn fd_path(fd: RawFd) -> Option<PathBuf> {
-    unsafe {
-        let mut buf = [0u8; libc::PATH_MAX as usize];
-        let rc = libc::fcntl(fd, F_GETPATH, buf.as_mut_ptr() as *mut c_void);
-        if rc == -1 {
-            return None;
-        }
-        let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
-        let slice = &buf[..len];
-        Some(PathBuf::from(OsStr::from_bytes(slice)))
-    }
+/// Wire format on every control connection (`NVIM_CLAUDE_SHIM_FORMAT`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    /// Newline-delimited JSON-RPC 2.0, for the plugin's socket server.
+    Json,
+    /// Neovim's own msgpack-RPC, for talking to `v:servername` directly.
+    MsgpackRpc,
 }
 
-fn fd_dev_ino(fd: RawFd) -> Option<(u64, u64)> {
-    unsafe {
-        let mut st: libc::stat = std::mem::zeroed();
-        if libc::fstat(fd, &mut st as *mut _) != 0 {
-            return None;
+static FORMAT: Lazy<Format> =
+    Lazy::new(
+        || match std::env::var("NVIM_CLAUDE_SHIM_FORMAT").as_deref() {
+            Err(_) | Ok("") | Ok("json") => Format::Json,
+            Ok("msgpack-rpc") => Format::MsgpackRpc,
+            Ok(other) => {
+                report_error(
+                    "bad_format",
+                    &format!("unknown NVIM_CLAUDE_SHIM_FORMAT {other:?}; using json"),
+                    None,
+                );
+                Format::Json
+            }
+        },
+    );
+
+/// Request ids, process-wide so an inherited fd shared by several threads
+/// never sees two requests with the same id.
+static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+
+/// One notification payload in the configured format; `Conn::send` frames it.
+pub(crate) fn encode_notification(method: &str, params: serde_json::Value) -> Option<Vec<u8>> {
+    match *FORMAT {
+        Format::Json => {
+            let call = RpcCall {
+                jsonrpc: "2.0",
+                id: None,
+                method,
+                params: Some(params),
+            };
+            serde_json::to_vec(&call).ok()
         }
-        Some((st.st_dev as u64, st.st_ino as u64))
+        Format::MsgpackRpc => Some(msgpack::notification(method, &params)),
     }
 }
 
-fn is_regular_file(fd: RawFd) -> bool {
-    unsafe {
-        let mut st: libc::stat = std::mem::zeroed();
-        if libc::fstat(fd, &mut st as *mut _) != 0 {
-            return false;
+/// An answer to a server request, in the configured format.
+pub(crate) fn encode_response(
+    id: &serde_json::Value,
+    result: Result<serde_json::Value, serde_json::Value>,
+) -> Option<Vec<u8>> {
+    match *FORMAT {
+        Format::Json => {
+            let reply = match result {
+                Ok(r) => json!({ "jsonrpc": "2.0", "id": id, "result": r }),
+                Err(e) => json!({ "jsonrpc": "2.0", "id": id, "error": e }),
+            };
+            serde_json::to_vec(&reply).ok()
         }
-        (st.st_mode & libc::S_IFMT) == libc::S_IFREG
+        Format::MsgpackRpc => Some(msgpack::response(id, result)),
     }
 }
 
-fn tracked_path(fd: RawFd) -> Option<String> {
-    FD_TABLE
-        .lock()
-        .get(&fd)
-        .and_then(|s| s.path.as_ref())
-        .map(|p| p.to_string_lossy().to_string())
+/// Send one preflight and wait for the verdict; `None` when no usable
+/// answer arrived by `deadline`.
+#[cfg(not(feature = "notify-only"))]
+fn request_allow(
+    conn: &mut Conn,
+    method: &str,
+    params: serde_json::Value,
+    deadline: Instant,
+) -> Option<(bool, Option<dir_cache::Scope>)> {
+    let result = conn.call(method, params, deadline)?.ok()?;
+    // `{ "allow": bool }`; a Lua handler may return the bare boolean.
+    let allow = result
+        .as_bool()
+        .or_else(|| result.get("allow")?.as_bool())?;
+    if allow {
+        session_cache::offered(&result);
+    }
+    Some((allow, dir_cache::Scope::of(&result)))
 }
 
-fn mark_fd_dirty(fd: RawFd) {
-    let mut t = FD_TABLE.lock();
-    let e = t.entry(fd).or_insert_with(|| FdState {
-        path: fd_path(fd),
-        dev: 0,
-        ino: 0,
-        dirty: false,
-        pre_sent: false,
-    });
-    if e.path.is_none() {
-        e.path = fd_path(fd);
+fn debug_event(method: &str, params: serde_json::Value) {
+    if !*DEBUG || op_context::nested() {
+        return;
     }
-    if (e.dev, e.ino) == (0, 0) {
-        if let Some((d, i)) = fd_dev_ino(fd) {
-            e.dev = d;
-            e.ino = i;
+    for (method, params) in debug_coalesce::offer(method, params) {
+        if let Some(payload) = encode_notification(method, params) {
+            let _ = with_thread_stream(|conn| conn.send(&payload));
         }
     }
-    e.dirty = true;
 }
 
-fn take_fd(fd: RawFd) -> Option<FdState> {
-    FD_TABLE.lock().remove(&fd)
+/// How an allowed preflight was decided.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AllowedBy {
+    Server,
+    Cache,
+    /// A directory-wide allow; see `dir_cache`.
+    DirCache,
+    /// Nothing answered (unreachable, timed out, or over the blocking
+    /// budget) and the shim fails open.
+    FallbackOpen,
+    /// Asked without waiting, for a nonblocking fd; see `async_pre`.
+    Optimistic,
+    /// Told rather than asked, the process being on its way out; see
+    /// `shutdown`.
+    Shutdown,
+    /// The answer to another thread's preflight on the same path; see
+    /// `single_flight`.
+    Coalesced,
+    /// Made up for testing; see `force`.
+    Forced,
 }
 
-//
-// -------- Environment + destination --------
-//
-
-#[derive(Debug)]
-enum Destination {
-    Unix(PathBuf),
-    Tcp(String),
-    Disabled,
-}
-static DESTINATION: Lazy<Destination> = Lazy::new(|| {
-    if let Some(p) = std::env::var_os("NVIM_CLAUDE_SHIM_SOCK") {
-        return Destination::Unix(PathBuf::from(p));
-    }
-    if let Some(addr) = std::env::var_os("NVIM_CLAUDE_SHIM_TCP") {
-        return Destination::Tcp(addr.to_string_lossy().to_string());
+const ALLOWED_BY: [AllowedBy; 8] = [
+    AllowedBy::Server,
+    AllowedBy::Cache,
+    AllowedBy::DirCache,
+    AllowedBy::FallbackOpen,
+    AllowedBy::Optimistic,
+    AllowedBy::Shutdown,
+    AllowedBy::Coalesced,
+    AllowedBy::Forced,
+];
+
+impl AllowedBy {
+    /// As posts' `allowed_by` says it.
+    fn name(self) -> &'static str {
+        match self {
+            AllowedBy::Server => "server",
+            AllowedBy::Cache => "cache",
+            AllowedBy::DirCache => "dir_cache",
+            AllowedBy::FallbackOpen => "fallback_open",
+            AllowedBy::Optimistic => "optimistic",
+            AllowedBy::Shutdown => "shutdown",
+            AllowedBy::Coalesced => "coalesced",
+            AllowedBy::Forced => "forced",
+        }
     }
-    Destination::Disabled
-});
 
-static DEBUG: Lazy<bool> = Lazy::new(|| {
-    std::env::var("NVIM_CLAUDE_SHIM_DEBUG")
-        .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
-        .unwrap_or(false)
-});
+    fn named(name: &str) -> Option<AllowedBy> {
+        ALLOWED_BY.into_iter().find(|b| b.name() == name)
+    }
+}
 
-static FAIL_CLOSED: Lazy<bool> = Lazy::new(|| {
-    std::env::var("FS_SHIM_FAIL_CLOSED")
-        .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
-        .unwrap_or(false)
-});
+/// What the post after an allowed preflight says about it. The default is
+/// for operations nothing was asked about.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Decision {
+    blocked: Duration,
+    by: Option<AllowedBy>,
+    /// Threads that waited on this preflight and took its answer.
+    coalesced: usize,
+    /// Not asked, the path being over its rate; see `rate_limit`.
+    rate_limited: bool,
+}
 
-static PRE_TIMEOUT_MS: Lazy<u64> = Lazy::new(|| {
-    std::env::var("FS_SHIM_PRE_TIMEOUT_MS")
-        .ok()
-        .and_then(|s| s.parse().ok())
-        .unwrap_or(1500)
-});
+impl Decision {
+    /// `params` with `blocked_ms` and `allowed_by` added, `coalesced_waiters`
+    /// when any took the answer and `rate_limited` when it was held back.
+    fn annotate(self, mut params: serde_json::Value) -> serde_json::Value {
+        if self.coalesced > 0 {
+            params["coalesced_waiters"] = json!(self.coalesced);
+        }
+        if self.rate_limited {
+            params["rate_limited"] = json!(true);
+        }
+        params["blocked_ms"] = json!(self.blocked.as_millis() as u64);
+        params["allowed_by"] = json!(self.by.map(AllowedBy::name));
+        params
+    }
+}
 
-fn log_debug(msg: &str) {
-    if !*DEBUG {
-        return;
+/// The params for asking about `op` on `path`, or (`Err`) the outcome
+/// when nothing needs asking: the destination is off (failing closed,
+/// see `without_server`), `classify::decide` finds the path ignored,
+/// supervised, only reported or allowed already, or the process is
+/// exiting, when the params are sent as a notification instead. `extra`
+/// is merged in alongside pid/path, and a `"conflict": true` in it skips
+/// the caches.
+#[cfg(not(feature = "notify-only"))]
+fn preflight_params(
+    ctx: &OpContext,
+    op: &str,
+    path: &Path,
+    extra: serde_json::Value,
+) -> Result<serde_json::Value, Option<Decision>> {
+    use classify::Verdict;
+
+    let no_server = destination_disabled() && routes::target(path).is_none();
+    if no_server && !*FAIL_CLOSED {
+        return Err(Some(Decision::default()));
     }
-    unsafe {
-        let _ = libc::syscall(
-            darwin_sys::SYS_WRITE,
-            libc::STDERR_FILENO as libc::intptr_t,
-            msg.as_ptr() as libc::intptr_t,
-            msg.len() as libc::intptr_t,
-        );
+    let caches: &dyn classify::Caches = if no_server {
+        &classify::Uncached
+    } else {
+        &classify::Live
+    };
+    let conflict = extra["conflict"] == true;
+    let class = match classify::decide(op, path, config::get(), *SUPERVISED, conflict, caches) {
+        Verdict::Ignored(by) => {
+            ignore_stats::record(op, path, by);
+            send_ignore_audit();
+            return Err(Some(Decision::default()));
+        }
+        Verdict::Supervised | Verdict::Notify | Verdict::Watched(_) => {
+            return Err(Some(Decision::default()));
+        }
+        Verdict::Cached(by) => {
+            return Err(Some(Decision {
+                blocked: Duration::ZERO,
+                by: Some(by),
+                coalesced: 0,
+                rate_limited: false,
+            }));
+        }
+        Verdict::Ask(_) if no_server => return Err(without_server(op, path, &extra)),
+        Verdict::Ask(class) => class,
+    };
+    let reported = paths::for_matching(path, config::get().normalize_unicode);
+    let mut params = json!({ "path": reported.to_string_lossy(), "class": class.name() });
+    lineage::tag(&mut params);
+    creds::tag(&mut params);
+    if reported != path {
+        params["raw_path"] = json!(path.to_string_lossy());
     }
+    if let (Some(p), serde_json::Value::Object(extra)) = (params.as_object_mut(), extra) {
+        p.extend(extra);
+    }
+    after_denied::annotate(&mut params);
+    announce_cwd(ctx);
+    ctx.emitting(op, &params);
+    if shutdown::active() {
+        stamp_path_seq(&mut params);
+        params["phase"] = json!("shutdown");
+        let _ = with_stream_for(path, |conn| {
+            let params = conn.with_thread(params);
+            conn.notify(op, params)
+        });
+        return Err(Some(Decision {
+            blocked: Duration::ZERO,
+            by: Some(AllowedBy::Shutdown),
+            coalesced: 0,
+            rate_limited: false,
+        }));
+    }
+    Ok(params)
 }
 
-//
-// -------- Thread-local control connection --------
-//
+/// Failing closed with no destination to ask: what can't be undone (a
+/// delete, a rename over a file, a truncate to zero) is denied, and the
+/// rest as `no_server_writes` says. Said once per process on stderr,
+/// since otherwise nothing would show the shim is running unprotected.
+#[cfg(not(feature = "notify-only"))]
+fn without_server(op: &str, path: &Path, extra: &serde_json::Value) -> Option<Decision> {
+    static WARNED: std::sync::Once = std::sync::Once::new();
+    WARNED.call_once(|| {
+        report_error(
+            "no_server",
+            "FS_SHIM_FAIL_CLOSED=1 but no server is configured; denying deletes, \
+             overwriting renames and truncates to zero",
+            None,
+        );
+    });
+    let destructive = match op {
+        "pre_delete" => true,
+        "pre_rename" => extra["destination_exists"] == true,
+        "pre_truncate" => extra["kind"] == "clear",
+        _ => false,
+    };
+    if !destructive && config::get().no_server_writes == config::NoServerWrites::Allow {
+        return Some(Decision::default());
+    }
+    denials::record(op, path, denials::Reason::NoServer);
+    note_denied(op, path, None);
+    None
+}
 
-thread_local! {
-    static CTRL_UNIX: RefCell<Option<UnixStream>> = RefCell::new(None);
-    static CTRL_TCP: RefCell<Option<std::net::TcpStream>> = RefCell::new(None);
+/// `op` on `path` was denied; `seq` is its preflight's `path_seq`.
+fn note_denied(op: &str, path: &Path, seq: Option<u64>) {
+    after_denied::record(op, path, seq);
+    let reported = paths::for_matching(path, config::get().normalize_unicode);
+    summary::denied(op, &reported.to_string_lossy());
 }
 
-// Use the real write/read on socket fds so we never recurse.
-fn write_unhooked(fd: RawFd, mut buf: &[u8]) -> std::io::Result<()> {
-    unsafe {
-        let real = real_write();
-        while !buf.is_empty() {
-            let n = real(fd, buf.as_ptr() as *const c_void, buf.len());
-            if n < 0 {
-                return Err(std::io::Error::last_os_error());
-            }
-            if n == 0 {
-                break;
-            }
-            buf = &buf[n as usize..];
-        }
+/// Number the request about to go out for its path.
+#[cfg(not(feature = "notify-only"))]
+fn stamp_path_seq(params: &mut serde_json::Value) {
+    if let Some(p) = params["path"].as_str() {
+        params["path_seq"] = json!(path_seq::next(p));
     }
-    Ok(())
 }
 
-fn read_line_unhooked(fd: RawFd, deadline: Instant) -> std::io::Result<Vec<u8>> {
-    unsafe {
-        let real = real_read();
-        let mut out = Vec::with_capacity(256);
-        let mut tmp = [0u8; 512];
-        loop {
-            if Instant::now() >= deadline {
-                return Err(std::io::Error::from(std::io::ErrorKind::TimedOut));
-            }
-            let n = real(fd, tmp.as_mut_ptr() as *mut c_void, tmp.len());
-            if n < 0 {
-                let e = std::io::Error::last_os_error();
-                if e.kind() == std::io::ErrorKind::Interrupted {
-                    continue;
+// `extra` is merged into the request params alongside pid/path.
+#[cfg(not(feature = "notify-only"))]
+fn preflight_with(
+    ctx: &OpContext,
+    op: &str,
+    path: &Path,
+    extra: serde_json::Value,
+) -> Option<Decision> {
+    // A conflict (see `conflicts`) is waited for, even here.
+    if thread_info::on_reactor() && extra["conflict"] != true {
+        return preflight_async(ctx, op, path, extra, None).1;
+    }
+    let params = match preflight_params(ctx, op, path, extra) {
+        Ok(params) => params,
+        Err(decision) => return decision,
+    };
+    let deadline = Instant::now() + Duration::from_millis(*PRE_TIMEOUT_MS);
+
+    let fallback = (!*FAIL_CLOSED).then_some(Decision {
+        blocked: Duration::ZERO,
+        by: Some(AllowedBy::FallbackOpen),
+        coalesced: 0,
+        rate_limited: false,
+    });
+    if let Some((forced, glob)) = force::lookup(path) {
+        return settle_forced(op, path, (forced, glob), deadline, fallback);
+    }
+    if !budget::admits() {
+        if fallback.is_none() {
+            denials::record(op, path, denials::Reason::BudgetExhausted);
+            note_denied(op, path, None);
+        }
+        return fallback;
+    }
+    // A conflict is asked about on its own; see `conflicts`.
+    if params["conflict"] == true {
+        return ask(ctx, op, path, params, deadline, fallback);
+    }
+    if let Err(last) = rate_limit::take(path) {
+        let decision = match last {
+            Some(answer) => answer,
+            None => {
+                if fallback.is_none() {
+                    denials::record(op, path, denials::Reason::RateLimited);
                 }
-                return Err(e);
-            }
-            if n == 0 {
-                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof));
-            }
-            let n = n as usize;
-            if let Some(pos) = tmp[..n].iter().position(|&b| b == b'\n') {
-                out.extend_from_slice(&tmp[..pos]);
-                return Ok(out);
-            } else {
-                out.extend_from_slice(&tmp[..n]);
+                fallback
             }
+        };
+        if decision.is_none() {
+            note_denied(op, path, None);
         }
+        return decision.map(|d| Decision {
+            blocked: Duration::ZERO,
+            rate_limited: true,
+            ..d
+        });
     }
+    let joined = Instant::now();
+    let leader = match single_flight::join(op, path, deadline) {
+        single_flight::Turn::Lead(leader) => leader,
+        single_flight::Turn::Follow(answer) => {
+            return answer.map(|d| Decision {
+                blocked: joined.elapsed(),
+                by: d.by.map(|by| match by {
+                    AllowedBy::Server => AllowedBy::Coalesced,
+                    by => by,
+                }),
+                coalesced: 0,
+                rate_limited: false,
+            })
+        }
+        single_flight::Turn::TimedOut => {
+            if fallback.is_none() {
+                denials::record(op, path, denials::Reason::TransportDown);
+                note_denied(op, path, None);
+            }
+            return fallback.map(|d| Decision {
+                blocked: joined.elapsed(),
+                ..d
+            });
+        }
+    };
+    let decision = ask(ctx, op, path, params, deadline, fallback);
+    let coalesced = leader.land(decision);
+    decision.map(|d| Decision { coalesced, ..d })
 }
 
-fn with_thread_stream<T>(f: impl FnOnce(RawFd) -> T) -> Option<T> {
-    match &*DESTINATION {
-        Destination::Unix(path) => CTRL_UNIX.with(|cell| {
-            if cell.borrow().is_none() {
-                match UnixStream::connect(path) {
-                    Ok(stream) => {
-                        log_debug("shim: connected unix socket\n");
-                        stream.set_nonblocking(false).ok();
-                        *cell.borrow_mut() = Some(stream);
-                    }
-                    Err(_) => {
-                        log_debug("shim: unix connect failed\n");
-                    }
-                }
-            }
-            cell.borrow().as_ref().map(|s| f(s.as_raw_fd()))
+/// Settle a preflight whose answer `force` makes up, as `ask` would have
+/// for that answer, and say what was made up.
+#[cfg(not(feature = "notify-only"))]
+fn settle_forced(
+    op: &str,
+    path: &Path,
+    (forced, glob): (force::Forced, &str),
+    deadline: Instant,
+    fallback: Option<Decision>,
+) -> Option<Decision> {
+    let started = Instant::now();
+    let decision = match forced {
+        force::Forced::Allow => Some(Decision {
+            by: Some(AllowedBy::Forced),
+            ..Decision::default()
         }),
-        Destination::Tcp(addr) => CTRL_TCP.with(|cell| {
-            if cell.borrow().is_none() {
-                if let Ok(stream) = std::net::TcpStream::connect(addr) {
-                    stream.set_nonblocking(false).ok();
-                    *cell.borrow_mut() = Some(stream);
-                }
+        force::Forced::Deny => None,
+        force::Forced::Timeout => {
+            std::thread::sleep(deadline.saturating_duration_since(Instant::now()));
+            if fallback.is_none() {
+                denials::record(op, path, denials::Reason::TransportDown);
             }
-            cell.borrow().as_ref().map(|s| f(s.as_raw_fd()))
-        }),
-        Destination::Disabled => None,
+            fallback
+        }
+    };
+    if decision.is_none() {
+        note_denied(op, path, None);
     }
+    let reported = paths::for_matching(path, config::get().normalize_unicode);
+    let params = json!({
+        "op": op,
+        "path": reported.to_string_lossy(),
+        "forced": forced.name(),
+        "glob": glob,
+        "allowed": decision.is_some(),
+    });
+    let _ = with_stream_for(path, |conn| {
+        let params = conn.with_thread(params);
+        conn.notify("shim/forced", params)
+    });
+    decision.map(|d| Decision {
+        blocked: started.elapsed(),
+        ..d
+    })
 }
 
-//
-// -------- Minimal JSON-RPC helpers --------
-//
+/// Send the preflight `preflight_with` built and wait for its answer,
+/// or `fallback` when none comes.
+#[cfg(not(feature = "notify-only"))]
+fn ask(
+    ctx: &OpContext,
+    op: &str,
+    path: &Path,
+    mut params: serde_json::Value,
+    deadline: Instant,
+    fallback: Option<Decision>,
+) -> Option<Decision> {
+    stamp_path_seq(&mut params);
+    let seq = params["path_seq"].as_u64();
+    let (verdict, blocked, exhausted) = with_stream_for(path, |conn| {
+        let params = conn.with_thread(params);
+        let asked = Instant::now();
+        let verdict = request_allow(conn, op, params, deadline);
+        let blocked = asked.elapsed();
+        (verdict, blocked, budget::record(blocked))
+    })
+    .unwrap_or((None, Duration::ZERO, false));
+    if exhausted {
+        post_notify(ctx, "shim/budget_exceeded", budget::exceeded_params());
+    }
+    match verdict {
+        Some((true, scope)) => {
+            allow_cache::insert(op, path);
+            if let Some(scope) = scope {
+                dir_cache::grant(op, path, scope);
+            }
+            let decision = Decision {
+                blocked,
+                by: Some(AllowedBy::Server),
+                coalesced: 0,
+                rate_limited: false,
+            };
+            rate_limit::answered(path, Some(decision));
+            Some(decision)
+        }
+        Some((false, _)) => {
+            rate_limit::answered(path, None);
+            note_denied(op, path, seq);
+            None
+        }
+        None if fallback.is_none() => {
+            denials::record(op, path, denials::Reason::TransportDown);
+            note_denied(op, path, seq);
+            None
+        }
+        None => fallback.map(|d| Decision { blocked, ..d }),
+    }
+}
 
-#[derive(Serialize)]
-struct RpcCall<'a, T: Serialize> {
-    jsonrpc: &'a str,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    id: Option<u64>,
-    method: &'a str,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    params: Option<T>,
+/// Ask without waiting for the answer, which `settle_async` applies to
+/// `fd` when it comes, the question being about its first write. Returns
+/// the request id, `Some` while an answer is due, and the decision for
+/// this write: `None` when failing closed, which refuses it until then.
+/// Nothing waits, so the blocking budget doesn't apply.
+#[cfg(not(feature = "notify-only"))]
+fn preflight_async(
+    ctx: &OpContext,
+    op: &str,
+    path: &Path,
+    extra: serde_json::Value,
+    fd: Option<RawFd>,
+) -> (Option<u32>, Option<Decision>) {
+    let mut params = match preflight_params(ctx, op, path, extra) {
+        Ok(params) => params,
+        Err(decision) => return (None, decision),
+    };
+    stamp_path_seq(&mut params);
+    let seq = params["path_seq"].as_u64();
+    let id = with_stream_for(path, |conn| {
+        let params = conn.with_thread(params);
+        let id = conn.request(op, params)?;
+        // Noted before anyone can read the answer off a shared connection.
+        async_pre::asked(id, fd, op, path.to_path_buf(), seq);
+        if let Some(fd) = fd {
+            if let Some(e) = FD_TABLE.lock().get_mut(&fd) {
+                e.asked = Some((id, Instant::now()));
+            }
+        }
+        Some(id)
+    })
+    .flatten();
+    if *FAIL_CLOSED {
+        if id.is_none() {
+            denials::record(op, path, denials::Reason::TransportDown);
+        }
+        return (id, None);
+    }
+    let decision = Decision {
+        blocked: Duration::ZERO,
+        by: Some(AllowedBy::Optimistic),
+        coalesced: 0,
+        rate_limited: false,
+    };
+    (id, Some(decision))
 }
 
-#[derive(Deserialize)]
-struct RpcAck {
-    #[allow(dead_code)]
-    jsonrpc: Option<String>,
-    #[allow(dead_code)]
-    id: Option<u64>,
-    result: Option<AckRes>,
+/// `notify-only`: nothing is asked, so nothing waits and nothing is
+/// denied. Inlined so each handler's deny branch folds away.
+#[cfg(feature = "notify-only")]
+#[inline(always)]
+fn preflight_with(
+    _ctx: &OpContext,
+    _op: &str,
+    _path: &Path,
+    _extra: serde_json::Value,
+) -> Option<Decision> {
+    Some(Decision::default())
 }
-#[derive(Deserialize)]
-struct AckRes {
-    allow: bool,
+
+#[cfg(feature = "notify-only")]
+#[inline(always)]
+fn preflight_async(
+    _ctx: &OpContext,
+    _op: &str,
+    _path: &Path,
+    _extra: serde_json::Value,
+    _fd: Option<RawFd>,
+) -> (Option<u32>, Option<Decision>) {
+    (None, Some(Decision::default()))
 }
 
-fn debug_event(method: &str, params: serde_json::Value) {
-    if !*DEBUG || in_shim() {
+// Fire-and-forget notification. Every post carries the affected file as
+// `params.path`, so ignore globs and normalization are applied here once for
+// all of them.
+fn post_notify(ctx: &OpContext, method: &str, params: serde_json::Value) {
+    if op_context::nested() || (destination_disabled() && fanout::is_empty() && routes::is_empty())
+    {
         return;
     }
-    let call = RpcCall {
-        jsonrpc: "2.0",
-        id: None,
-        method,
-        params: Some(params),
+    let Some(params) = post_params(method, params) else {
+        send_ignore_audit();
+        return;
+    };
+    announce_cwd(ctx);
+    ctx.emitting(method, &params);
+    if destination_disabled() {
+        // Nobody else would hear of them.
+        for e in project::take_errors() {
+            fan_out("shim/config_error", &e);
+        }
+    }
+    fan_out(method, &params);
+    let path = params["path"].as_str().map(PathBuf::from);
+    let dir_changed = dir_changed(method, &params);
+    let breaker = match path.as_deref().and_then(routes::target) {
+        Some(i) => routes::breaker(i),
+        None => Some(&*PRIMARY_BREAKER),
     };
-    let mut line = match serde_json::to_vec(&call) {
-        Ok(v) => v,
-        Err(_) => return,
+    let admitted = breaker.is_none_or(|b| b.admits(Instant::now()));
+    let dropped = match breaker {
+        Some(b) if admitted => b.take_dropped(),
+        _ => 0,
     };
-    line.push(b'\n');
-    let _ = with_thread_stream(|fd| write_unhooked(fd, &line));
+    let send = |conn: &mut Conn| {
+        for params in project::take_errors() {
+            conn.notify("shim/config_error", params);
+        }
+        if dropped > 0 {
+            let params = json!({ "pid": unsafe { libc::getpid() }, "count": dropped });
+            conn.notify("shim/dropped", params);
+        }
+        let params = conn.with_thread(params);
+        conn.notify(method, params)
+    };
+    let _ = match path {
+        _ if !admitted => None,
+        Some(p) => with_stream_for(&p, send),
+        None if shutdown::active() => with_exit_stream(send),
+        None => with_thread_stream(send),
+    };
+    if let Some(params) = dir_changed {
+        post_notify(ctx, "post_dir_changed", params);
+    }
 }
 
-// Blocking pre-flight; returns true to allow, false to deny.
-fn preflight_block(op: &str, path: &Path) -> bool {
-    if matches!(&*DESTINATION, Destination::Disabled) {
-        return true;
+/// A post about to go out counts toward its watched directory's
+/// `post_dir_changed`; these params when one is due. See `watch_dirs`.
+fn dir_changed(method: &str, params: &serde_json::Value) -> Option<serde_json::Value> {
+    // A lock changes nothing.
+    if !method.starts_with("post_")
+        || matches!(method, "post_lock" | "post_unlock")
+        || params["synthesized"] == true
+    {
+        return None;
     }
-    // Serialize the request.
-    let call = RpcCall {
-        jsonrpc: "2.0",
-        id: Some(1), // per-thread stream is strictly request->response
-        method: op,
-        params: Some(json!({
-            "pid": unsafe { libc::getpid() },
-            "path": path.to_string_lossy()
-        })),
-    };
-    let mut line = match serde_json::to_vec(&call) {
-        Ok(v) => v,
-        Err(_) => return !*FAIL_CLOSED,
-    };
-    line.push(b'\n');
+    watch_dirs::changed(Path::new(params["path"].as_str()?))
+}
 
-    let deadline = Instant::now() + Duration::from_millis(*PRE_TIMEOUT_MS);
+/// Report freshly ignored paths while the server has ignore audit on.
+fn send_ignore_audit() {
+    let pending = ignore_stats::take_audit();
+    if pending.is_empty() {
+        return;
+    }
+    let _ = with_thread_stream(|conn| {
+        for params in pending {
+            conn.notify("shim/ignored", params);
+        }
+    });
+}
 
-    match with_thread_stream(|fd| {
-        let _ = write_unhooked(fd, &line);
-        read_line_unhooked(fd, deadline)
-    }) {
-        Some(Ok(bytes)) => {
-            if let Ok(ack) = serde_json::from_slice::<RpcAck>(&bytes) {
-                if let Some(res) = ack.result {
-                    return res.allow;
-                }
+/// Copy a post to every sink. Sinks get no sequence numbers; replay is
+/// only for the primary.
+fn fan_out(method: &str, params: &serde_json::Value) {
+    if fanout::is_empty() {
+        return;
+    }
+    if let Some(payload) = encode_notification(method, params.clone()) {
+        fanout::notify(&payload);
+    }
+}
+
+/// A post's params as sent; `None` when its path (or, for a file known
+/// only by inode, its `last_path`) is ignored.
+fn post_params(method: &str, mut params: serde_json::Value) -> Option<serde_json::Value> {
+    if let Some(p) = params.get("path").and_then(|p| p.as_str()) {
+        let cfg = config::get();
+        if let Some(by) = cfg.ignored_by(Path::new(p)) {
+            ignore_stats::record(method, Path::new(p), by);
+            return None;
+        }
+        if let Cow::Owned(nfc) = paths::for_matching(Path::new(p), cfg.normalize_unicode) {
+            params["raw_path"] = params["path"].take();
+            params["path"] = json!(nfc.to_string_lossy());
+        }
+        if let Some(p) = params["path"].as_str() {
+            let class = path_class::classify(Path::new(p));
+            params["path_seq"] = json!(path_seq::next(p));
+            params["class"] = json!(class.name());
+        }
+        after_denied::annotate(&mut params);
+        summary::posted(method, &params);
+    } else if let Some(p) = params.get("last_path").and_then(|p| p.as_str()) {
+        // Known only by inode; its old name still says whether it's ours.
+        if let Some(by) = config::get().ignored_by(Path::new(p)) {
+            ignore_stats::record(method, Path::new(p), by);
+            return None;
+        }
+    }
+    if method.starts_with("post_") {
+        lineage::tag(&mut params);
+    }
+    Some(params)
+}
+
+/// Send the post for every dirty fd (or just `only`) now, as its close
+/// would, and start counting afresh. For `shim/flush` and `fflush`; the
+/// posts go out on `conn`, for `shim/flush` the connection the request
+/// came in on, unless a `[[route]]` sends them elsewhere.
+pub(crate) fn flush_dirty(conn: &mut Conn, only: Option<RawFd>) -> usize {
+    let pending = take_dirty(|fd, _| only.is_none_or(|o| o == fd), None);
+    send_posts(conn, pending)
+}
+
+/// The posts for the dirty fds `select` picks, with `reason` if given,
+/// and each of them counted afresh.
+fn take_dirty(
+    mut select: impl FnMut(RawFd, &FdState) -> bool,
+    reason: Option<&str>,
+) -> Vec<(&'static str, serde_json::Value)> {
+    let cfg = config::get();
+    let mut t = FD_TABLE.lock();
+    let pending = t
+        .iter_mut()
+        .filter(|&(&fd, ref s)| s.dirty && !s.ignored && select(fd, s))
+        .filter_map(|(&fd, s)| {
+            let st = s.created.then(|| fd_stat(fd)).flatten();
+            let mut params = modify_params(s, s.path.as_deref()?, cfg, st);
+            if let Some(reason) = reason {
+                params["reason"] = json!(reason);
             }
-            !*FAIL_CLOSED
+            let post = (s.events().1, params);
+            // Reported as created once; later writes are modifications.
+            s.created = false;
+            s.dirty = false;
+            s.dirty_since = None;
+            s.bytes = 0;
+            s.requested = 0;
+            s.shape = None;
+            s.unsampled = 0;
+            s.writer.posted();
+            Some(post)
+        })
+        .collect();
+    dirty_age::rearm(
+        t.values()
+            .filter(|s| s.dirty && !s.ignored)
+            .filter_map(|s| s.dirty_since)
+            .min(),
+    );
+    pending
+}
+
+fn send_posts(conn: &mut Conn, pending: Vec<(&str, serde_json::Value)>) -> usize {
+    let mut sent = 0;
+    let mut dirs = Vec::new();
+    for (method, params) in pending {
+        let Some(params) = post_params(method, params) else {
+            continue;
+        };
+        fan_out(method, &params);
+        dirs.extend(dir_changed(method, &params).map(|p| ("post_dir_changed", p)));
+        let route = params["path"]
+            .as_str()
+            .and_then(|p| routes::target(Path::new(p)));
+        let send = |conn: &mut Conn| {
+            let params = conn.with_thread(params);
+            conn.notify(method, params)
+        };
+        if route == conn.route {
+            send(conn);
+        } else if let Some(i) = route {
+            routes::with_stream(i, send);
+        } else {
+            with_thread_stream(send);
         }
-        Some(Err(_)) => !*FAIL_CLOSED,
-        None => !*FAIL_CLOSED,
+        sent += 1;
     }
+    if !dirs.is_empty() {
+        send_posts(conn, dirs);
+    }
+    sent
 }
 
-fn post_notify(method: &str, params: serde_json::Value) {
-    if in_shim() {
-        return;
+/// `flush_dirty` and the `post_dir_changed`s and `post_touch`es still
+/// pending, then wait briefly for sinks to send what they have queued.
+/// Behind both `shim/flush` and `nvim_claude_shim_flush`.
+pub(crate) fn flush_now(conn: &mut Conn) -> usize {
+    let sent = flush_dirty(conn, None);
+    let dirs = watch_dirs::take_pending()
+        .into_iter()
+        .map(|p| ("post_dir_changed", p))
+        .chain(
+            touches::take_pending()
+                .into_iter()
+                .map(|p| ("post_touch", p)),
+        )
+        .collect();
+    send_posts(conn, dirs);
+    fanout::drain();
+    sent
+}
+
+/// Lets a cooperating host (the broker, test fixtures) have every pending
+/// post sent at a point of its choosing, e.g. right before it reports an
+/// edit as done. The fds stay open. Callable from any thread; returns how
+/// many posts went out, 0 when there is no server to send them to.
+#[no_mangle]
+pub extern "C" fn nvim_claude_shim_flush() -> c_int {
+    // Depth 1, as in a hook, so our own socket I/O passes through.
+    let ctx = OpContext::enter();
+    if !ctx.enabled() || !ctx.is_outermost() {
+        return 0;
     }
-    let call = RpcCall {
-        jsonrpc: "2.0",
-        id: None, // notification
-        method,
-        params: Some(params),
-    };
-    let mut line = match serde_json::to_vec(&call) {
-        Ok(v) => v,
-        Err(_) => return,
-    };
-    line.push(b'\n');
-    let _ = with_thread_stream(|fd| write_unhooked(fd, &line));
+    with_thread_stream(flush_now).unwrap_or(0) as c_int
 }
 
 //
@@ -537,176 +1217,196 @@ fn c_path(ptr: *const c_char) -> Option<PathBuf> {
     }
 }
 
-#[inline]
-fn set_errno(e: c_int) {
-    // macOS: __error() -> *mut c_int
-    unsafe {
-        *libc::__error() = e;
+/// Set when `absolute` finds the cwd moved, until `announce_cwd`.
+static CWD_MOVED: AtomicBool = AtomicBool::new(false);
+
+/// `path` joined onto the cwd when it's relative. A cwd that moved since
+/// we last looked is announced before the next event, so the server can
+/// tell which project later paths belong to.
+fn absolute(path: PathBuf) -> PathBuf {
+    if path.is_absolute() || op_context::nested() {
+        return path;
+    }
+    let Some((cwd, moved)) = procinfo::current_cwd() else {
+        return path;
+    };
+    if moved {
+        CWD_MOVED.store(true, Ordering::Relaxed);
+    }
+    cwd.join(path)
+}
+
+/// `shim/cwd_changed` ahead of an event, when `absolute` found the cwd
+/// moved. Helpers only note it, since they have no say in what is sent.
+fn announce_cwd(ctx: &OpContext) {
+    if !CWD_MOVED.swap(false, Ordering::Relaxed) {
+        return;
+    }
+    if let Some((cwd, _)) = procinfo::current_cwd() {
+        post_notify(
+            ctx,
+            "shim/cwd_changed",
+            json!({ "pid": unsafe { libc::getpid() }, "cwd": procinfo::cwd_value(&cwd) }),
+        );
+    }
+}
+
+/// Resolve a `*at()` path argument: absolute paths pass through, `AT_FDCWD`
+/// joins onto the cwd (like the plain calls), otherwise onto the dirfd's
+/// path.
+fn c_path_at(dirfd: c_int, ptr: *const c_char) -> Option<PathBuf> {
+    let p = c_path(ptr)?;
+    if p.is_absolute() || dirfd == libc::AT_FDCWD {
+        return Some(absolute(p));
     }
+    platform::fd_path(dirfd).map(|dir| dir.join(p))
 }
 
 //
 // -------- dlsym lookup for originals (RTLD_NEXT) --------
 //
 
-use std::sync::OnceLock;
+/// `$fn_name()` is the next definition of `$sym_name` after ours, looked
+/// up once. `None` when there is none, which callers cover with the raw
+/// syscall; that is reported on stderr the first time.
 macro_rules! declare_symbol {
     ($fn_name:ident, $sym_name:literal, $ty:ty) => {
-        fn $fn_name() -> $ty {
-            static SLOT: OnceLock<$ty> = OnceLock::new();
+        pub(crate) fn $fn_name() -> Option<$ty> {
+            static SLOT: std::sync::OnceLock<Option<$ty>> = std::sync::OnceLock::new();
             *SLOT.get_or_init(|| unsafe {
                 const NAME_BYTES: &[u8] = concat!($sym_name, "\0").as_bytes();
-                let cname = CStr::from_bytes_with_nul_unchecked(NAME_BYTES);
+                let cname = std::ffi::CStr::from_bytes_with_nul_unchecked(NAME_BYTES);
                 let sym = libc::dlsym(libc::RTLD_NEXT, cname.as_ptr());
                 if sym.is_null() {
-                    panic!("shim: dlsym failed for {}", $sym_name);
+                    $crate::report_error(
+                        "dlsym_missing",
+                        concat!("no ", $sym_name, " after the shim; using the raw syscall"),
+                        None,
+                    );
+                    return None;
                 }
-                std::mem::transmute::<*mut c_void, $ty>(sym)
+                Some(std::mem::transmute::<*mut std::os::raw::c_void, $ty>(sym))
             })
         }
     };
 }
+#[cfg(target_os = "linux")]
+pub(crate) use declare_symbol;
 
 type WriteFn = unsafe extern "C" fn(c_int, *const c_void, libc::size_t) -> libc::ssize_t;
 type PwriteFn =
     unsafe extern "C" fn(c_int, *const c_void, libc::size_t, libc::off_t) -> libc::ssize_t;
 type WritevFn = unsafe extern "C" fn(c_int, *const libc::iovec, c_int) -> libc::ssize_t;
+#[cfg(target_os = "linux")]
+type PwritevFn =
+    unsafe extern "C" fn(c_int, *const libc::iovec, c_int, libc::off_t) -> libc::ssize_t;
 type CloseFn = unsafe extern "C" fn(c_int) -> c_int;
 type UnlinkFn = unsafe extern "C" fn(*const c_char) -> c_int;
+#[cfg(target_os = "linux")]
+type ShmOpenFn = unsafe extern "C" fn(*const c_char, c_int, libc::mode_t) -> c_int;
 type RenameFn = unsafe extern "C" fn(*const c_char, *const c_char) -> c_int;
 type ReadFn = unsafe extern "C" fn(c_int, *mut c_void, libc::size_t) -> libc::ssize_t;
 type FtruncateFn = unsafe extern "C" fn(c_int, libc::off_t) -> c_int;
 type TruncateFn = unsafe extern "C" fn(*const c_char, libc::off_t) -> c_int;
+type ChdirFn = unsafe extern "C" fn(*const c_char) -> c_int;
+type FchdirFn = unsafe extern "C" fn(c_int) -> c_int;
+type FflushFn = unsafe extern "C" fn(*mut libc::FILE) -> c_int;
+type MkfifoFn = unsafe extern "C" fn(*const c_char, libc::mode_t) -> c_int;
+type MkfifoatFn = unsafe extern "C" fn(c_int, *const c_char, libc::mode_t) -> c_int;
+type MknodFn = unsafe extern "C" fn(*const c_char, libc::mode_t, libc::dev_t) -> c_int;
+type ExitFn = unsafe extern "C" fn(c_int) -> !;
+type FchmodFn = unsafe extern "C" fn(c_int, libc::mode_t) -> c_int;
+type FutimensFn = unsafe extern "C" fn(c_int, *const libc::timespec) -> c_int;
+type FutimesFn = unsafe extern "C" fn(c_int, *const libc::timeval) -> c_int;
+type UtimensatFn =
+    unsafe extern "C" fn(c_int, *const c_char, *const libc::timespec, c_int) -> c_int;
+type UtimesFn = unsafe extern "C" fn(*const c_char, *const libc::timeval) -> c_int;
+type SetuidFn = unsafe extern "C" fn(libc::uid_t) -> c_int;
+type SetgidFn = unsafe extern "C" fn(libc::gid_t) -> c_int;
+type ExecveFn =
+    unsafe extern "C" fn(*const c_char, *const *const c_char, *const *const c_char) -> c_int;
+#[cfg(target_os = "linux")]
+type ExecvFn = unsafe extern "C" fn(*const c_char, *const *const c_char) -> c_int;
+type PosixSpawnFn = unsafe extern "C" fn(
+    *mut libc::pid_t,
+    *const c_char,
+    *const libc::posix_spawn_file_actions_t,
+    *const libc::posix_spawnattr_t,
+    *const *mut c_char,
+    *const *mut c_char,
+) -> c_int;
+#[cfg(target_os = "macos")]
+type FchflagsFn = unsafe extern "C" fn(c_int, libc::c_uint) -> c_int;
+type AclSetFileFn = unsafe extern "C" fn(*const c_char, c_int, *mut c_void) -> c_int;
+type AclSetFdFn = unsafe extern "C" fn(c_int, *mut c_void) -> c_int;
+type FlockFn = unsafe extern "C" fn(c_int, c_int) -> c_int;
+/// `fcntl(2)`; its third argument is variadic, see the platform glue.
+type FcntlFn = unsafe extern "C" fn(c_int, c_int, ...) -> c_int;
+#[cfg(target_os = "linux")]
+type LockfFn = unsafe extern "C" fn(c_int, c_int, libc::off_t) -> c_int;
+#[cfg(target_os = "macos")]
+type CopyfileFn = unsafe extern "C" fn(
+    *const c_char,
+    *const c_char,
+    *mut c_void,
+    libc::copyfile_flags_t,
+) -> c_int;
+#[cfg(target_os = "macos")]
+type RemovefileFn = unsafe extern "C" fn(*const c_char, *mut c_void, u32) -> c_int;
+#[cfg(target_os = "macos")]
+type RenamexFn = unsafe extern "C" fn(*const c_char, *const c_char, libc::c_uint) -> c_int;
+#[cfg(target_os = "macos")]
+type RenameatxFn =
+    unsafe extern "C" fn(c_int, *const c_char, c_int, *const c_char, libc::c_uint) -> c_int;
+/// `open(2)`/`openat(2)` as the C library declares them. The mode is
+/// variadic; see the platform export glue for how it is fetched.
+#[cfg(target_os = "linux")]
+type OpenFn = unsafe extern "C" fn(*const c_char, c_int, ...) -> c_int;
+#[cfg(target_os = "linux")]
+type OpenatFn = unsafe extern "C" fn(c_int, *const c_char, c_int, ...) -> c_int;
+#[cfg(target_os = "linux")]
+type UnlinkatFn = unsafe extern "C" fn(c_int, *const c_char, c_int) -> c_int;
+#[cfg(target_os = "linux")]
+type RenameatFn = unsafe extern "C" fn(c_int, *const c_char, c_int, *const c_char) -> c_int;
+#[cfg(target_os = "linux")]
+type Renameat2Fn =
+    unsafe extern "C" fn(c_int, *const c_char, c_int, *const c_char, libc::c_uint) -> c_int;
 
 declare_symbol!(real_write, "write", WriteFn);
 declare_symbol!(real_read, "read", ReadFn);
 
 //
-// -------- dyld interpose glue --------
+// -------- Hook entry points --------
 //
-
-#[repr(C)]
-struct InterposePair<T> {
-    replacement: T,
-    original: T,
-}
-macro_rules! register_interpose {
-    ($name:ident, $replacement:expr, $original:expr, $ty:ty) => {
-        #[used]
-        #[link_section = "__DATA,__interpose"]
-        static $name: InterposePair<$ty> = InterposePair {
-            replacement: $replacement,
-            original: $original,
-        };
-    };
-}
-
-extern "C" {
-    fn write(fd: c_int, buf: *const c_void, count: libc::size_t) -> libc::ssize_t;
-    #[link_name = "write$NOCANCEL"]
-    fn write_nocancel_symbol(fd: c_int, buf: *const c_void, count: libc::size_t) -> libc::ssize_t;
-    fn pwrite(
-        fd: c_int,
-        buf: *const c_void,
-        count: libc::size_t,
-        offset: libc::off_t,
-    ) -> libc::ssize_t;
-    #[link_name = "pwrite$NOCANCEL"]
-    fn pwrite_nocancel_symbol(
-        fd: c_int,
-        buf: *const c_void,
-        count: libc::size_t,
-        offset: libc::off_t,
-    ) -> libc::ssize_t;
-    fn writev(fd: c_int, iov: *const libc::iovec, iovcnt: c_int) -> libc::ssize_t;
-    #[link_name = "writev$NOCANCEL"]
-    fn writev_nocancel_symbol(fd: c_int, iov: *const libc::iovec, iovcnt: c_int) -> libc::ssize_t;
-
-    fn close(fd: c_int) -> c_int;
-    #[link_name = "close$NOCANCEL"]
-    fn close_nocancel_symbol(fd: c_int) -> c_int;
-
-    fn unlink(path: *const c_char) -> c_int;
-
-    fn rename(old: *const c_char, new: *const c_char) -> c_int;
-    #[cfg(not(target_arch = "aarch64"))]
-    #[link_name = "rename$UNIX2003"]
-    fn rename_unix2003_symbol(old: *const c_char, new: *const c_char) -> c_int;
-
-    #[cfg(not(target_arch = "aarch64"))]
-    #[link_name = "unlink$NOCANCEL"]
-    fn unlink_nocancel_symbol(path: *const c_char) -> c_int;
-
-    fn ftruncate(fd: c_int, length: libc::off_t) -> c_int;
-    fn truncate(path: *const c_char, length: libc::off_t) -> c_int;
-}
-
-//
-// -------- Handlers --------
-//
-
-fn maybe_pre_on_first_write(fd: c_int) -> bool {
-    if !is_regular_file(fd) {
-        return true;
-    }
-    let (path_opt, send_pre) = {
-        let mut t = FD_TABLE.lock();
-        let e = t.entry(fd).or_insert_with(|| FdState {
-            path: fd_path(fd),
-            dev: 0,
-            ino: 0,
-            dirty: false,
-            pre_sent: false,
-        });
-        if e.path.is_none() {
-            e.path = fd_path(fd);
-        }
-        if (e.dev, e.ino) == (0, 0) {
-            if let Some((d, i)) = fd_dev_ino(fd) {
-                e.dev = d;
-                e.ino = i;
+// What the platform glue calls. Each runs its `tracked_*` body (see
+// `handlers`) on `Live` under `contain::hook`, with the bare call to fall
+// back on.
+
+unsafe fn handle_open(
+    dirfd: Option<c_int>,
+    path: *const c_char,
+    flags: c_int,
+    mode: Option<libc::mode_t>,
+) -> c_int {
+    contain::hook(
+        Hook::Open,
+        || unsafe { tracked_open(&Live, dirfd, path, flags, mode) },
+        || unsafe {
+            let raw_mode = mode.unwrap_or(0);
+            match dirfd {
+                Some(dirfd) => platform::sys_openat(dirfd, path, flags, raw_mode),
+                None => platform::sys_open(path, flags, raw_mode),
             }
-        }
-        if !e.pre_sent {
-            e.pre_sent = true;
-            (e.path.clone(), true)
-        } else {
-            (e.path.clone(), false)
-        }
-    };
-
-    if send_pre {
-        if let Some(ref p) = path_opt {
-            return preflight_block("pre_modify", p);
-        }
-    }
-    true
+        },
+    )
 }
 
 unsafe fn handle_write(fd: c_int, buf: *const c_void, count: libc::size_t) -> libc::ssize_t {
-    let guard = Guard::enter();
-
-    if !guard.enabled {
-        return unsafe { syscall_write(fd, buf, count) };
-    }
-
-    if guard.is_primary() && count > 0 {
-        if !maybe_pre_on_first_write(fd) {
-            set_errno(libc::EPERM);
-            return -1;
-        }
-    }
-
-    let res = unsafe { syscall_write(fd, buf, count) };
-
-    if guard.is_primary() && res > 0 && count > 0 {
-        mark_fd_dirty(fd);
-        debug_event(
-            "shim/write_call",
-            json!({ "fd": fd, "count": count, "res": res, "tracked_path": tracked_path(fd)}),
-        );
-    }
-    res
+    contain::hook(
+        Hook::Write,
+        || unsafe { tracked_write(&Live, fd, buf, count) },
+        || unsafe { platform::sys_write(fd, buf, count) },
+    )
 }
 
 unsafe fn handle_pwrite(
@@ -715,367 +1415,532 @@ unsafe fn handle_pwrite(
     count: libc::size_t,
     offset: libc::off_t,
 ) -> libc::ssize_t {
-    let guard = Guard::enter();
-
-    if !guard.enabled {
-        return unsafe { syscall_pwrite(fd, buf, count, offset) };
-    }
-
-    if guard.is_primary() && count > 0 {
-        if !maybe_pre_on_first_write(fd) {
-            set_errno(libc::EPERM);
-            return -1;
-        }
-    }
-
-    let res = unsafe { syscall_pwrite(fd, buf, count, offset) };
+    contain::hook(
+        Hook::Pwrite,
+        || unsafe { tracked_pwrite(&Live, fd, buf, count, offset) },
+        || unsafe { platform::sys_pwrite(fd, buf, count, offset) },
+    )
+}
 
-    if guard.is_primary() && res > 0 && count > 0 {
-        mark_fd_dirty(fd);
-        debug_event(
-            "shim/pwrite_call",
-            json!({ "fd": fd, "count": count, "res": res, "tracked_path": tracked_path(fd)}),
-        );
-    }
-    res
+unsafe fn handle_writev(fd: c_int, iov: *const libc::iovec, iovcnt: c_int) -> libc::ssize_t {
+    contain::hook(
+        Hook::Writev,
+        || unsafe { tracked_writev(&Live, fd, iov, iovcnt, None) },
+        || unsafe { platform::sys_writev(fd, iov, iovcnt) },
+    )
 }
 
-unsafe fn handle_writev(
+#[cfg(target_os = "linux")]
+unsafe fn handle_pwritev(
     fd: c_int,
     iov: *const libc::iovec,
     iovcnt: c_int,
+    offset: libc::off_t,
 ) -> libc::ssize_t {
-    let guard = Guard::enter();
+    contain::hook(
+        Hook::Pwritev,
+        || unsafe { tracked_writev(&Live, fd, iov, iovcnt, Some(offset)) },
+        || unsafe { platform::sys_pwritev(fd, iov, iovcnt, offset) },
+    )
+}
 
-    if !guard.enabled {
-        return unsafe { syscall_writev(fd, iov, iovcnt) };
-    }
+unsafe fn handle_close(fd: c_int) -> c_int {
+    contain::hook(
+        Hook::Close,
+        || unsafe { tracked_close(&Live, fd) },
+        || unsafe { platform::sys_close(fd) },
+    )
+}
 
-    if guard.is_primary() && iovcnt > 0 {
-        if !maybe_pre_on_first_write(fd) {
-            set_errno(libc::EPERM);
-            return -1;
-        }
-    }
+unsafe fn handle_unlink(path: *const c_char) -> c_int {
+    contain::hook(
+        Hook::Unlink,
+        || unsafe { tracked_unlink(&Live, path) },
+        || unsafe { platform::sys_unlink(path) },
+    )
+}
 
-    let res = unsafe { syscall_writev(fd, iov, iovcnt) };
+unsafe fn handle_rename(old: *const c_char, new: *const c_char) -> c_int {
+    contain::hook(
+        Hook::Rename,
+        || unsafe { tracked_rename(&Live, old, new) },
+        || unsafe { platform::sys_rename(old, new) },
+    )
+}
 
-    if guard.is_primary() && res >= 0 {
-        mark_fd_dirty(fd);
-        debug_event(
-            "shim/writev_call",
-            json!({ "fd": fd, "iovcnt": iovcnt, "res": res, "tracked_path": tracked_path(fd)}),
-        );
-    }
-    res
+#[cfg(target_os = "linux")]
+unsafe fn handle_unlinkat(dirfd: c_int, path: *const c_char, flags: c_int) -> c_int {
+    contain::hook(
+        Hook::Unlinkat,
+        || unsafe { tracked_unlinkat(&Live, dirfd, path, flags) },
+        || unsafe { platform::sys_unlinkat(dirfd, path, flags) },
+    )
 }
 
-unsafe fn handle_close(fd: c_int) -> c_int {
-    let guard = Guard::enter();
+#[cfg(target_os = "linux")]
+unsafe fn handle_renameat(
+    olddirfd: c_int,
+    old: *const c_char,
+    newdirfd: c_int,
+    new: *const c_char,
+    flags: Option<libc::c_uint>,
+) -> c_int {
+    contain::hook(
+        Hook::Renameat,
+        || unsafe { tracked_renameat(&Live, olddirfd, old, newdirfd, new, flags) },
+        || unsafe {
+            match flags {
+                Some(f) => platform::sys_renameat2(olddirfd, old, newdirfd, new, f),
+                None => platform::sys_renameat(olddirfd, old, newdirfd, new),
+            }
+        },
+    )
+}
 
-    if !guard.enabled {
-        return unsafe { syscall_close(fd) };
-    }
+unsafe fn handle_ftruncate(fd: c_int, len: libc::off_t) -> c_int {
+    contain::hook(
+        Hook::Ftruncate,
+        || unsafe { tracked_ftruncate(&Live, fd, len) },
+        || unsafe { platform::sys_ftruncate(fd, len) },
+    )
+}
 
-    let state = if guard.is_primary() {
-        // Peek state before close; we remove after.
-        FD_TABLE.lock().get(&fd).cloned()
-    } else {
-        None
-    };
+unsafe fn handle_truncate(path: *const c_char, len: libc::off_t) -> c_int {
+    contain::hook(
+        Hook::Truncate,
+        || unsafe { tracked_truncate(&Live, path, len) },
+        || unsafe { platform::sys_truncate(path, len) },
+    )
+}
 
-    let rc = unsafe { syscall_close(fd) };
+unsafe fn handle_mkfifo(path: *const c_char, mode: libc::mode_t) -> c_int {
+    contain::hook(
+        Hook::Mkfifo,
+        || unsafe {
+            tracked_mknod(&Live, None, path, mode | libc::S_IFIFO, || {
+                platform::sys_mkfifo(path, mode)
+            })
+        },
+        || unsafe { platform::sys_mkfifo(path, mode) },
+    )
+}
 
-    if guard.is_primary() {
-        let info = take_fd(fd).or(state);
-        if rc == 0 {
-            if let Some(info) = info {
-                if let Some(p) = info.path {
-                    if info.dirty {
-                        post_notify("post_modify", json!({ "path": p.to_string_lossy() }));
-                    }
-                }
-            }
-        }
-        debug_event(
-            "shim/close_call",
-            json!({ "fd": fd, "rc": rc, "tracked_path": tracked_path(fd)}),
-        );
-    }
+unsafe fn handle_mkfifoat(dirfd: c_int, path: *const c_char, mode: libc::mode_t) -> c_int {
+    contain::hook(
+        Hook::Mkfifoat,
+        || unsafe {
+            tracked_mknod(&Live, Some(dirfd), path, mode | libc::S_IFIFO, || {
+                platform::sys_mkfifoat(dirfd, path, mode)
+            })
+        },
+        || unsafe { platform::sys_mkfifoat(dirfd, path, mode) },
+    )
+}
 
-    rc
+unsafe fn handle_mknod(path: *const c_char, mode: libc::mode_t, dev: libc::dev_t) -> c_int {
+    contain::hook(
+        Hook::Mknod,
+        || unsafe {
+            tracked_mknod(&Live, None, path, mode, || {
+                platform::sys_mknod(path, mode, dev)
+            })
+        },
+        || unsafe { platform::sys_mknod(path, mode, dev) },
+    )
 }
 
-unsafe fn handle_unlink(path: *const c_char) -> c_int {
-    let guard = Guard::enter();
+unsafe fn handle_shm_open(name: *const c_char, oflag: c_int, mode: libc::mode_t) -> c_int {
+    contain::hook(
+        Hook::ShmOpen,
+        || unsafe { tracked_shm_open(&Live, name, oflag, mode) },
+        || unsafe { platform::sys_shm_open(name, oflag, mode) },
+    )
+}
 
-    if !guard.enabled {
-        return unsafe { syscall_unlink(path) };
-    }
+unsafe fn handle_shm_unlink(name: *const c_char) -> c_int {
+    contain::hook(
+        Hook::ShmUnlink,
+        || unsafe { tracked_shm_unlink(&Live, name) },
+        || unsafe { platform::sys_shm_unlink(name) },
+    )
+}
 
-    let pbuf = c_path(path);
-    if guard.is_primary() {
-        if let Some(ref p) = pbuf {
-            if !preflight_block("pre_delete", p) {
-                set_errno(libc::EPERM);
-                return -1;
-            }
-        }
-    }
+fn handle_exit(status: c_int) -> ! {
+    contain::hook(Hook::Exit, || tracked_exit(&Live, false), || 0);
+    unsafe { platform::sys_exit(status) }
+}
 
-    let rc = unsafe { syscall_unlink(path) };
+fn handle_uexit(status: c_int) -> ! {
+    contain::hook(Hook::UExit, || tracked_exit(&Live, true), || 0);
+    unsafe { platform::sys_uexit(status) }
+}
 
-    if guard.is_primary() && rc == 0 {
-        if let Some(p) = pbuf {
-            post_notify("post_delete", json!({ "path": p.to_string_lossy() }));
-        }
-        debug_event(
-            "shim/unlink_call",
-            json!({ "rc": rc, "path": c_path(path).map(|p| p.to_string_lossy().to_string()) }),
-        );
-    }
+unsafe fn handle_fflush(stream: *mut libc::FILE) -> c_int {
+    contain::hook(
+        Hook::Fflush,
+        || unsafe { tracked_fflush(&Live, stream) },
+        || unsafe { platform::sys_fflush(stream) },
+    )
+}
 
-    rc
+unsafe fn handle_fchmod(fd: c_int, mode: libc::mode_t) -> c_int {
+    contain::hook(
+        Hook::Fchmod,
+        || unsafe {
+            tracked_fd_meta(
+                &Live,
+                "post_chmod",
+                fd,
+                json!({ "mode": format!("{:04o}", mode & 0o7777) }),
+                || platform::sys_fchmod(fd, mode),
+            )
+        },
+        || unsafe { platform::sys_fchmod(fd, mode) },
+    )
 }
 
-unsafe fn handle_rename(old: *const c_char, new: *const c_char) -> c_int {
-    let guard = Guard::enter();
+unsafe fn handle_flock(fd: c_int, op: c_int) -> c_int {
+    contain::hook(
+        Hook::Flock,
+        || unsafe {
+            tracked_lock(&Live, fd, "flock", locks::Request::of_flock(op), || {
+                platform::sys_flock(fd, op)
+            })
+        },
+        || unsafe { platform::sys_flock(fd, op) },
+    )
+}
 
-    if !guard.enabled {
-        return unsafe { syscall_rename(old, new) };
-    }
+/// Every `fcntl` comes through here, whatever its command; each command
+/// the shim has a use for gets its own arm. `arg` is only read by those.
+unsafe fn handle_fcntl(fd: c_int, cmd: c_int, arg: usize) -> c_int {
+    contain::hook(
+        Hook::Fcntl,
+        || unsafe {
+            let lock = locks::Request::of_fcntl(cmd, || match arg as *const libc::flock {
+                l if l.is_null() => -1,
+                l => c_int::from((*l).l_type),
+            });
+            match lock {
+                Some(req) => tracked_lock(&Live, fd, "fcntl", Some(req), || {
+                    platform::sys_fcntl(fd, cmd, arg)
+                }),
+                None => platform::sys_fcntl(fd, cmd, arg),
+            }
+        },
+        || unsafe { platform::sys_fcntl(fd, cmd, arg) },
+    )
+}
 
-    let oldp = c_path(old);
-    let newp = c_path(new);
+#[cfg(target_os = "linux")]
+unsafe fn handle_lockf(fd: c_int, cmd: c_int, len: libc::off_t) -> c_int {
+    contain::hook(
+        Hook::Lockf,
+        || unsafe {
+            tracked_lock(&Live, fd, "lockf", locks::Request::of_lockf(cmd), || {
+                platform::sys_lockf(fd, cmd, len)
+            })
+        },
+        || unsafe { platform::sys_lockf(fd, cmd, len) },
+    )
+}
 
-    if guard.is_primary() {
-        if let Some(ref to) = newp {
-            if !preflight_block("pre_rename", to) {
-                set_errno(libc::EPERM);
-                return -1;
-            }
-        }
-    }
+unsafe fn handle_futimens(fd: c_int, times: *const libc::timespec) -> c_int {
+    contain::hook(
+        Hook::Futimens,
+        || unsafe {
+            tracked_touch(
+                &Live,
+                || inodes::params(fd, tracked_path(fd).map(PathBuf::from)),
+                || platform::sys_futimens(fd, times),
+            )
+        },
+        || unsafe { platform::sys_futimens(fd, times) },
+    )
+}
 
-    let rc = unsafe { syscall_rename(old, new) };
+unsafe fn handle_futimes(fd: c_int, times: *const libc::timeval) -> c_int {
+    contain::hook(
+        Hook::Futimes,
+        || unsafe {
+            tracked_touch(
+                &Live,
+                || inodes::params(fd, tracked_path(fd).map(PathBuf::from)),
+                || platform::sys_futimes(fd, times),
+            )
+        },
+        || unsafe { platform::sys_futimes(fd, times) },
+    )
+}
 
-    if guard.is_primary() && rc == 0 {
-        if let Some(ref to) = newp {
-            post_notify("post_modify", json!({ "path": to.to_string_lossy() }));
-        }
-        debug_event(
-            "shim/rename_call",
-            json!({
-                "rc": rc,
-                "oldPath": oldp.as_ref().map(|p| p.to_string_lossy().to_string()),
-                "newPath": newp.as_ref().map(|p| p.to_string_lossy().to_string())
-            }),
-        );
-    }
+/// A null `path` sets `dirfd`'s own timestamps, as `futimens` does.
+unsafe fn handle_utimensat(
+    dirfd: c_int,
+    path: *const c_char,
+    times: *const libc::timespec,
+    flags: c_int,
+) -> c_int {
+    contain::hook(
+        Hook::Utimensat,
+        || unsafe {
+            tracked_touch(
+                &Live,
+                || match path.is_null() {
+                    true => inodes::params(dirfd, tracked_path(dirfd).map(PathBuf::from)),
+                    false => c_path_at(dirfd, path).map(|p| json!({ "path": p.to_string_lossy() })),
+                },
+                || platform::sys_utimensat(dirfd, path, times, flags),
+            )
+        },
+        || unsafe { platform::sys_utimensat(dirfd, path, times, flags) },
+    )
+}
 
-    rc
+unsafe fn handle_utimes(path: *const c_char, times: *const libc::timeval) -> c_int {
+    contain::hook(
+        Hook::Utimes,
+        || unsafe {
+            tracked_touch(
+                &Live,
+                || c_path(path).map(|p| json!({ "path": absolute(p).to_string_lossy() })),
+                || platform::sys_utimes(path, times),
+            )
+        },
+        || unsafe { platform::sys_utimes(path, times) },
+    )
 }
 
-unsafe fn handle_ftruncate(fd: c_int, len: libc::off_t) -> c_int {
-    let guard = Guard::enter();
+unsafe fn handle_setuid(uid: libc::uid_t) -> c_int {
+    contain::hook(
+        Hook::Setuid,
+        || unsafe { tracked_setid("setuid", || platform::sys_setuid(uid)) },
+        || unsafe { platform::sys_setuid(uid) },
+    )
+}
 
-    if !guard.enabled {
-        return unsafe { syscall_ftruncate_fd(fd, len) };
-    }
+unsafe fn handle_seteuid(uid: libc::uid_t) -> c_int {
+    contain::hook(
+        Hook::Seteuid,
+        || unsafe { tracked_setid("seteuid", || platform::sys_seteuid(uid)) },
+        || unsafe { platform::sys_seteuid(uid) },
+    )
+}
 
-    if guard.is_primary() {
-        if let Some(p) = tracked_path(fd).map(PathBuf::from) {
-            if !preflight_block("pre_truncate", &p) {
-                set_errno(libc::EPERM);
-                return -1;
-            }
-        }
-    }
+unsafe fn handle_setgid(gid: libc::gid_t) -> c_int {
+    contain::hook(
+        Hook::Setgid,
+        || unsafe { tracked_setid("setgid", || platform::sys_setgid(gid)) },
+        || unsafe { platform::sys_setgid(gid) },
+    )
+}
+
+unsafe fn handle_setegid(gid: libc::gid_t) -> c_int {
+    contain::hook(
+        Hook::Setegid,
+        || unsafe { tracked_setid("setegid", || platform::sys_setegid(gid)) },
+        || unsafe { platform::sys_setegid(gid) },
+    )
+}
 
-    let rc = unsafe { syscall_ftruncate_fd(fd, len) };
+#[cfg(target_os = "macos")]
+unsafe fn handle_fchflags(fd: c_int, flags: libc::c_uint) -> c_int {
+    contain::hook(
+        Hook::Fchflags,
+        || unsafe {
+            tracked_fd_meta(&Live, "post_chflags", fd, json!({ "flags": flags }), || {
+                platform::sys_fchflags(fd, flags)
+            })
+        },
+        || unsafe { platform::sys_fchflags(fd, flags) },
+    )
+}
 
-    if guard.is_primary() && rc == 0 {
-        mark_fd_dirty(fd);
-        debug_event(
-            "shim/ftruncate_call",
-            json!({ "fd": fd, "len": len, "rc": rc, "tracked_path": tracked_path(fd)}),
-        );
-    }
-    rc
+unsafe fn handle_acl_set_file(path: *const c_char, ty: c_int, acl: *mut c_void) -> c_int {
+    contain::hook(
+        Hook::AclSetFile,
+        || unsafe {
+            tracked_acl(
+                &Live,
+                "acl_set_file",
+                || c_path(path).map(|p| json!({ "path": absolute(p).to_string_lossy() })),
+                acl,
+                || platform::sys_acl_set_file(path, ty, acl),
+            )
+        },
+        || unsafe { platform::sys_acl_set_file(path, ty, acl) },
+    )
 }
 
-unsafe fn handle_truncate(path: *const c_char, len: libc::off_t) -> c_int {
-    let guard = Guard::enter();
+#[cfg(target_os = "macos")]
+unsafe fn handle_acl_set_link(path: *const c_char, ty: c_int, acl: *mut c_void) -> c_int {
+    contain::hook(
+        Hook::AclSetLink,
+        || unsafe {
+            tracked_acl(
+                &Live,
+                "acl_set_link_np",
+                || c_path(path).map(|p| json!({ "path": absolute(p).to_string_lossy() })),
+                acl,
+                || platform::sys_acl_set_link(path, ty, acl),
+            )
+        },
+        || unsafe { platform::sys_acl_set_link(path, ty, acl) },
+    )
+}
 
-    if !guard.enabled {
-        return unsafe { syscall_truncate_path(path, len) };
-    }
+unsafe fn handle_acl_set_fd(fd: c_int, acl: *mut c_void) -> c_int {
+    contain::hook(
+        Hook::AclSetFd,
+        || unsafe {
+            tracked_acl(
+                &Live,
+                "acl_set_fd",
+                || inodes::params(fd, tracked_path(fd).map(PathBuf::from)),
+                acl,
+                || platform::sys_acl_set_fd(fd, acl),
+            )
+        },
+        || unsafe { platform::sys_acl_set_fd(fd, acl) },
+    )
+}
 
-    let pbuf = c_path(path);
-    if guard.is_primary() {
-        if let Some(ref p) = pbuf {
-            if !preflight_block("pre_truncate", p) {
-                set_errno(libc::EPERM);
-                return -1;
-            }
-        }
-    }
+#[cfg(target_os = "macos")]
+unsafe fn handle_copyfile(
+    from: *const c_char,
+    to: *const c_char,
+    state: *mut c_void,
+    flags: libc::copyfile_flags_t,
+) -> c_int {
+    contain::hook(
+        Hook::Copyfile,
+        || unsafe { tracked_copyfile(&Live, from, to, state, flags) },
+        || unsafe { platform::sys_copyfile(from, to, state, flags) },
+    )
+}
 
-    let rc = unsafe { syscall_truncate_path(path, len) };
+#[cfg(target_os = "macos")]
+unsafe fn handle_removefile(path: *const c_char, state: *mut c_void, flags: u32) -> c_int {
+    contain::hook(
+        Hook::Removefile,
+        || unsafe { tracked_removefile(&Live, path, state, flags) },
+        || unsafe { platform::sys_removefile(path, state, flags) },
+    )
+}
 
-    if guard.is_primary() && rc == 0 {
-        if let Some(p) = pbuf {
-            post_notify("post_modify", json!({ "path": p.to_string_lossy() }));
-        }
-        debug_event(
-            "shim/truncate_call",
-            json!({ "len": len, "rc": rc, "path": c_path(path).map(|p| p.to_string_lossy().to_string()) }),
-        );
-    }
+#[cfg(target_os = "macos")]
+unsafe fn handle_renameatx(
+    olddirfd: c_int,
+    old: *const c_char,
+    newdirfd: c_int,
+    new: *const c_char,
+    flags: libc::c_uint,
+) -> c_int {
+    contain::hook(
+        Hook::Renamex,
+        || unsafe { tracked_renameatx(&Live, olddirfd, old, newdirfd, new, flags) },
+        || unsafe { platform::sys_renameatx(olddirfd, old, newdirfd, new, flags) },
+    )
+}
 
-    rc
+unsafe fn handle_chdir(path: *const c_char) -> c_int {
+    contain::hook(
+        Hook::Chdir,
+        || unsafe { tracked_chdir(&Live, path) },
+        || unsafe { platform::sys_chdir(path) },
+    )
 }
 
-//
-// -------- Shims + interpose registration --------
-//
+unsafe fn handle_fchdir(fd: c_int) -> c_int {
+    contain::hook(
+        Hook::Fchdir,
+        || unsafe { tracked_fchdir(&Live, fd) },
+        || unsafe { platform::sys_fchdir(fd) },
+    )
+}
 
-unsafe extern "C" fn shim_write(
-    fd: c_int,
-    buf: *const c_void,
-    count: libc::size_t,
-) -> libc::ssize_t {
-    unsafe { handle_write(fd, buf, count) }
+unsafe fn handle_execve(
+    path: *const c_char,
+    argv: *const *const c_char,
+    envp: *const *const c_char,
+) -> c_int {
+    contain::hook(
+        Hook::Exec,
+        || unsafe { tracked_exec(&Live, envp, |envp| platform::sys_execve(path, argv, envp)) },
+        || unsafe { platform::sys_execve(path, argv, envp) },
+    )
 }
-register_interpose!(INTERPOSE_WRITE, shim_write, write as WriteFn, WriteFn);
 
-unsafe extern "C" fn shim_write_nocancel(
-    fd: c_int,
-    buf: *const c_void,
-    count: libc::size_t,
-) -> libc::ssize_t {
-    unsafe { handle_write(fd, buf, count) }
+/// `execv`, which is `execve` with this process's environment.
+#[cfg(target_os = "linux")]
+unsafe fn handle_execv(path: *const c_char, argv: *const *const c_char) -> c_int {
+    contain::hook(
+        Hook::Exec,
+        || unsafe {
+            tracked_exec(&Live, platform::env_block(), |envp| {
+                platform::sys_execve(path, argv, envp)
+            })
+        },
+        || unsafe { platform::sys_execv(path, argv) },
+    )
 }
-register_interpose!(
-    INTERPOSE_WRITE_NC,
-    shim_write_nocancel,
-    write_nocancel_symbol as WriteFn,
-    WriteFn
-);
 
-unsafe extern "C" fn shim_pwrite(
-    fd: c_int,
-    buf: *const c_void,
-    count: libc::size_t,
-    offset: libc::off_t,
-) -> libc::ssize_t {
-    unsafe { handle_pwrite(fd, buf, count, offset) }
+/// `execvp`, searching `PATH` like it but through `execvpe`, so the
+/// environment can carry `exec_fds`'s variable.
+#[cfg(target_os = "linux")]
+unsafe fn handle_execvp(file: *const c_char, argv: *const *const c_char) -> c_int {
+    contain::hook(
+        Hook::Exec,
+        || unsafe {
+            tracked_exec(&Live, platform::env_block(), |envp| {
+                platform::sys_execvpe(file, argv, envp)
+            })
+        },
+        || unsafe { platform::sys_execvp(file, argv) },
+    )
 }
-register_interpose!(INTERPOSE_PWRITE, shim_pwrite, pwrite as PwriteFn, PwriteFn);
 
-unsafe extern "C" fn shim_pwrite_nocancel(
-    fd: c_int,
-    buf: *const c_void,
-    count: libc::size_t,
-    offset: libc::off_t,
-) -> libc::ssize_t {
-    unsafe { handle_pwrite(fd, buf, count, offset) }
+unsafe fn handle_posix_spawn(
+    pid: *mut libc::pid_t,
+    path: *const c_char,
+    actions: *const libc::posix_spawn_file_actions_t,
+    attr: *const libc::posix_spawnattr_t,
+    argv: *const *mut c_char,
+    envp: *const *mut c_char,
+) -> c_int {
+    let spawn = |envp| unsafe { platform::sys_posix_spawn(pid, path, actions, attr, argv, envp) };
+    contain::hook(
+        Hook::Spawn,
+        || unsafe { tracked_spawn(envp, spawn) },
+        || spawn(envp),
+    )
 }
-register_interpose!(
-    INTERPOSE_PWRITE_NC,
-    shim_pwrite_nocancel,
-    pwrite_nocancel_symbol as PwriteFn,
-    PwriteFn
-);
 
-unsafe extern "C" fn shim_writev(
-    fd: c_int,
-    iov: *const libc::iovec,
-    iovcnt: c_int,
-) -> libc::ssize_t {
-    unsafe { handle_writev(fd, iov, iovcnt) }
+unsafe fn handle_posix_spawnp(
+    pid: *mut libc::pid_t,
+    file: *const c_char,
+    actions: *const libc::posix_spawn_file_actions_t,
+    attr: *const libc::posix_spawnattr_t,
+    argv: *const *mut c_char,
+    envp: *const *mut c_char,
+) -> c_int {
+    let spawn = |envp| unsafe { platform::sys_posix_spawnp(pid, file, actions, attr, argv, envp) };
+    contain::hook(
+        Hook::Spawn,
+        || unsafe { tracked_spawn(envp, spawn) },
+        || spawn(envp),
+    )
 }
-register_interpose!(INTERPOSE_WRITEV, shim_writev, writev as WritevFn, WritevFn);
 
-unsafe extern "C" fn shim_writev_nocancel(
-    fd: c_int,
-    iov: *const libc::iovec,
-    iovcnt: c_int,
-) -> libc::ssize_t {
-    unsafe { handle_writev(fd, iov, iovcnt) }
-}
-register_interpose!(
-    INTERPOSE_WRITEV_NC,
-    shim_writev_nocancel,
-    writev_nocancel_symbol as WritevFn,
-    WritevFn
-);
-
-unsafe extern "C" fn shim_close(fd: c_int) -> c_int {
-    unsafe { handle_close(fd) }
-}
-register_interpose!(INTERPOSE_CLOSE, shim_close, close as CloseFn, CloseFn);
-
-unsafe extern "C" fn shim_close_nocancel(fd: c_int) -> c_int {
-    unsafe { handle_close(fd) }
-}
-register_interpose!(
-    INTERPOSE_CLOSE_NC,
-    shim_close_nocancel,
-    close_nocancel_symbol as CloseFn,
-    CloseFn
-);
-
-unsafe extern "C" fn shim_unlink(path: *const c_char) -> c_int {
-    unsafe { handle_unlink(path) }
-}
-register_interpose!(INTERPOSE_UNLINK, shim_unlink, unlink as UnlinkFn, UnlinkFn);
-
-#[cfg(not(target_arch = "aarch64"))]
-unsafe extern "C" fn shim_unlink_nocancel(path: *const c_char) -> c_int {
-    unsafe { handle_unlink(path) }
-}
-#[cfg(not(target_arch = "aarch64"))]
-register_interpose!(
-    INTERPOSE_UNLINK_NC,
-    shim_unlink_nocancel,
-    unlink_nocancel_symbol as UnlinkFn,
-    UnlinkFn
-);
-
-unsafe extern "C" fn shim_rename(old: *const c_char, new: *const c_char) -> c_int {
-    unsafe { handle_rename(old, new) }
-}
-register_interpose!(INTERPOSE_RENAME, shim_rename, rename as RenameFn, RenameFn);
-
-#[cfg(not(target_arch = "aarch64"))]
-unsafe extern "C" fn shim_rename_unix2003(old: *const c_char, new: *const c_char) -> c_int {
-    unsafe { handle_rename(old, new) }
-}
-#[cfg(not(target_arch = "aarch64"))]
-register_interpose!(
-    INTERPOSE_RENAME_U2003,
-    shim_rename_unix2003,
-    rename_unix2003_symbol as RenameFn,
-    RenameFn
-);
-
-unsafe extern "C" fn shim_ftruncate(fd: c_int, length: libc::off_t) -> c_int {
-    unsafe { handle_ftruncate(fd, length) }
-}
-register_interpose!(
-    INTERPOSE_FTRUNCATE,
-    shim_ftruncate,
-    ftruncate as FtruncateFn,
-    FtruncateFn
-);
-
-unsafe extern "C" fn shim_truncate(path: *const c_char, length: libc::off_t) -> c_int {
-    unsafe { handle_truncate(path, length) }
-}
-register_interpose!(
-    INTERPOSE_TRUNCATE,
-    shim_truncate,
-    truncate as TruncateFn,
-    TruncateFn
-);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    declare_symbol!(real_nothing, "nvim_claude_shim_no_such_symbol", CloseFn);
+
+    #[test]
+    fn missing_originals_are_none_not_a_panic() {
+        assert!(real_nothing().is_none());
+        assert!(real_nothing().is_none());
+        assert!(real_write().is_some());
+    }
+}
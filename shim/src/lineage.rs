@@ -0,0 +1,94 @@
+//! Which command started the chain a process belongs to.
+//!
+//! The agent runs `bash -c 'sed ... | sort > out'`, and the writes come
+//! from `sed` and `sort`, pids nobody asked for. The server attributes
+//! work to the command it launched instead, so every preflight and post
+//! carries, next to the writer's own `pid`, the first shimmed process in
+//! its ancestry:
+//!
+//! ```json
+//! "pid": 4243, "root_pid": 4242, "root_argv0": "bash"
+//! ```
+//!
+//! The first shimmed process (no `ENV` in its environment, or one that
+//! doesn't parse) is its own root, and puts itself in `ENV` from the
+//! library constructor; children inherit it with the rest of the
+//! environment. A child started with an environment of its own making
+//! loses it, and becomes a root in turn. `root_argv0` is cut to
+//! `MAX_ARGV0` bytes.
+
+use std::ffi::CString;
+
+use once_cell::sync::OnceCell;
+use serde_json::{json, Value};
+
+use crate::platform;
+
+pub(crate) const ENV: &str = "NVIM_CLAUDE_SHIM_ROOT";
+const MAX_ARGV0: usize = 256;
+
+struct Root {
+    pid: i64,
+    argv0: String,
+}
+
+static ROOT: OnceCell<Root> = OnceCell::new();
+
+/// `<pid>:<argv0>`, as `ENV` holds it.
+fn parse(v: &str) -> Option<Root> {
+    let (pid, argv0) = v.split_once(':')?;
+    Some(Root {
+        pid: pid.parse().ok().filter(|&p| p > 0)?,
+        argv0: argv0.to_string(),
+    })
+}
+
+fn clip(s: &str) -> String {
+    let mut end = s.len().min(MAX_ARGV0);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    s[..end].to_string()
+}
+
+/// Take the root from the environment, or become it. From the library
+/// constructor, before the program has threads that could race `setenv`.
+pub(crate) fn capture() {
+    let inherited = std::env::var(ENV).ok().as_deref().and_then(parse);
+    let root = inherited.unwrap_or_else(|| {
+        let root = Root {
+            pid: unsafe { libc::getpid() } as i64,
+            argv0: clip(&platform::argv0().unwrap_or_default()),
+        };
+        let value = format!("{}:{}", root.pid, root.argv0);
+        if let (Ok(k), Ok(v)) = (CString::new(ENV), CString::new(value)) {
+            unsafe { libc::setenv(k.as_ptr(), v.as_ptr(), 1) };
+        }
+        root
+    });
+    let _ = ROOT.set(root);
+}
+
+/// Add `pid`, `root_pid` and `root_argv0` to an event's params.
+pub(crate) fn tag(params: &mut Value) {
+    params["pid"] = json!(unsafe { libc::getpid() });
+    if let Some(root) = ROOT.get() {
+        params["root_pid"] = json!(root.pid);
+        params["root_argv0"] = json!(root.argv0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_root_travels_as_pid_and_argv0() {
+        let root = parse("4242:bash -c").unwrap();
+        assert_eq!((root.pid, root.argv0.as_str()), (4242, "bash -c"));
+        assert!(parse("4242").is_none());
+        assert!(parse("0:bash").is_none());
+        assert!(parse("x:bash").is_none());
+        assert_eq!(clip(&"é".repeat(200)).len(), 256);
+    }
+}
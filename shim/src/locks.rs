@@ -0,0 +1,205 @@
+//! Exclusive locks on tracked files.
+//!
+//! A process that takes an exclusive advisory lock on a file (a sqlite
+//! database, a package manager's lockfile) is about to write it, and
+//! whoever else has it open is about to wait. So a successful exclusive
+//! `flock`, `fcntl(F_SETLK/F_SETLKW)` (and the OFD forms) or `lockf` on a
+//! regular file that isn't ignored is reported, notification only:
+//!
+//! ```json
+//! { "method": "post_lock",
+//!   "params": { "path": "/p/app.db", "lock": "exclusive", "via": "fcntl",
+//!               "blocking": true } }
+//! { "method": "post_unlock",
+//!   "params": { "path": "/p/app.db", "via": "fcntl", "reason": "close" } }
+//! ```
+//!
+//! `blocking` says the caller asked to wait (`F_SETLKW`, `F_LOCK`, an
+//! `flock` without `LOCK_NB`). The lock is remembered by fd until it is
+//! released or downgraded to shared (`"reason": "unlock"`) or that fd is
+//! closed (`"reason": "close"`). Locking again through an fd that already
+//! holds one says nothing, whatever the byte range; shared locks are never
+//! reported. At most `CAP` fds are remembered, within the `heap` cap; a
+//! forked child starts with none, since its locks are its parent's (or,
+//! for `fcntl`, gone).
+
+use std::collections::HashMap;
+use std::os::raw::c_int;
+use std::os::unix::prelude::RawFd;
+use std::path::PathBuf;
+
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+
+use crate::{heap, platform};
+
+const CAP: usize = 64;
+
+/// What a lock call asked for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Request {
+    Exclusive { blocking: bool },
+    Shared,
+    Unlock,
+}
+
+impl Request {
+    /// An `flock` operation.
+    pub(crate) fn of_flock(op: c_int) -> Option<Request> {
+        let blocking = op & libc::LOCK_NB == 0;
+        match op & !libc::LOCK_NB {
+            libc::LOCK_EX => Some(Request::Exclusive { blocking }),
+            libc::LOCK_SH => Some(Request::Shared),
+            libc::LOCK_UN => Some(Request::Unlock),
+            _ => None,
+        }
+    }
+
+    /// An `fcntl` command with its `struct flock`'s `l_type`, which is
+    /// only read for the locking commands.
+    pub(crate) fn of_fcntl(cmd: c_int, l_type: impl FnOnce() -> c_int) -> Option<Request> {
+        let blocking = match cmd {
+            libc::F_SETLK => false,
+            libc::F_SETLKW => true,
+            #[cfg(target_os = "linux")]
+            libc::F_OFD_SETLK => false,
+            #[cfg(target_os = "linux")]
+            libc::F_OFD_SETLKW => true,
+            _ => return None,
+        };
+        match l_type() {
+            t if t == c_int::from(libc::F_WRLCK) => Some(Request::Exclusive { blocking }),
+            t if t == c_int::from(libc::F_RDLCK) => Some(Request::Shared),
+            t if t == c_int::from(libc::F_UNLCK) => Some(Request::Unlock),
+            _ => None,
+        }
+    }
+
+    /// A `lockf` command; its locks are always exclusive.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn of_lockf(cmd: c_int) -> Option<Request> {
+        match cmd {
+            libc::F_LOCK => Some(Request::Exclusive { blocking: true }),
+            libc::F_TLOCK => Some(Request::Exclusive { blocking: false }),
+            libc::F_ULOCK => Some(Request::Unlock),
+            _ => None,
+        }
+    }
+}
+
+struct Held {
+    path: PathBuf,
+    via: &'static str,
+}
+
+#[derive(Default)]
+struct Table {
+    pid: i32,
+    held: HashMap<RawFd, Held>,
+}
+
+static TABLE: Mutex<Option<Table>> = parking_lot::const_mutex(None);
+
+fn cost(path: &std::path::Path) -> usize {
+    path.as_os_str().len() + heap::ENTRY
+}
+
+fn with_table<T>(f: impl FnOnce(&mut HashMap<RawFd, Held>) -> T) -> T {
+    let pid = unsafe { libc::getpid() };
+    let mut table = TABLE.lock();
+    let t = table.get_or_insert_with(Table::default);
+    if t.pid != pid {
+        // The child's heap count started from the parent's.
+        t.held.values().for_each(|h| heap::release(cost(&h.path)));
+        *t = Table {
+            pid,
+            ..Table::default()
+        };
+    }
+    f(&mut t.held)
+}
+
+fn regular_path(fd: RawFd, known: Option<PathBuf>) -> Option<PathBuf> {
+    let mut st: libc::stat = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstat(fd, &mut st) } != 0 || st.st_mode & libc::S_IFMT != libc::S_IFREG {
+        return None;
+    }
+    known.or_else(|| platform::fd_path(fd))
+}
+
+/// `req` through `via` just succeeded on `fd`, whose path the fd table
+/// has as `known`. The post it calls for, if any.
+pub(crate) fn settled(
+    fd: RawFd,
+    via: &'static str,
+    req: Request,
+    known: Option<PathBuf>,
+) -> Option<(&'static str, Value)> {
+    let Request::Exclusive { blocking } = req else {
+        let held = with_table(|t| t.remove(&fd))?;
+        heap::release(cost(&held.path));
+        return Some(("post_unlock", unlock_params(&held, "unlock")));
+    };
+    if with_table(|t| t.contains_key(&fd)) {
+        return None;
+    }
+    // Outside the lock: the path may take a readlink.
+    let path = regular_path(fd, known)?;
+    let params = json!({
+        "path": path.to_string_lossy(),
+        "lock": "exclusive",
+        "via": via,
+        "blocking": blocking,
+    });
+    with_table(|t| {
+        if t.len() < CAP && heap::charge(cost(&path)) {
+            t.insert(fd, Held { path, via });
+        }
+    });
+    Some(("post_lock", params))
+}
+
+/// `fd` was just closed. The `post_unlock` params if it held a lock.
+pub(crate) fn closed(fd: RawFd) -> Option<Value> {
+    let held = with_table(|t| {
+        if t.is_empty() {
+            return None;
+        }
+        t.remove(&fd)
+    })?;
+    heap::release(cost(&held.path));
+    Some(unlock_params(&held, "close"))
+}
+
+fn unlock_params(held: &Held, reason: &str) -> Value {
+    json!({ "path": held.path.to_string_lossy(), "via": held.via, "reason": reason })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lock_calls_are_read_as_exclusive_shared_or_unlock() {
+        let exclusive = |blocking| Some(Request::Exclusive { blocking });
+        assert_eq!(Request::of_flock(libc::LOCK_EX), exclusive(true));
+        assert_eq!(
+            Request::of_flock(libc::LOCK_EX | libc::LOCK_NB),
+            exclusive(false)
+        );
+        assert_eq!(Request::of_flock(libc::LOCK_UN), Some(Request::Unlock));
+        let wrlck = || c_int::from(libc::F_WRLCK);
+        assert_eq!(Request::of_fcntl(libc::F_SETLKW, wrlck), exclusive(true));
+        assert_eq!(
+            Request::of_fcntl(libc::F_SETLK, || c_int::from(libc::F_RDLCK)),
+            Some(Request::Shared)
+        );
+        // Not a locking command: no struct to read.
+        assert_eq!(Request::of_fcntl(libc::F_GETFL, || unreachable!()), None);
+        #[cfg(target_os = "linux")]
+        {
+            assert_eq!(Request::of_lockf(libc::F_TLOCK), exclusive(false));
+            assert_eq!(Request::of_lockf(libc::F_TEST), None);
+        }
+    }
+}
@@ -0,0 +1,552 @@
+//! Just enough MessagePack for Neovim's msgpack-RPC, without pulling a
+//! serialization framework into every traced process.
+//!
+//! Encoding goes straight from `serde_json::Value` (what the rest of the
+//! shim builds). Decoding works on a connection's receive buffer:
+//! `decode_prefix` takes one value off the front once all of it is there.
+
+use serde_json::Value as Json;
+use std::io;
+
+use crate::demux::Incoming;
+
+/// Longest string/binary/array we accept from the peer, so a corrupt
+/// length can't make us allocate gigabytes inside someone else's process.
+const MAX_LEN: usize = 16 << 20;
+const MAX_DEPTH: usize = 32;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Value {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Bin(Vec<u8>),
+    Array(Vec<Value>),
+    Map(Vec<(Value, Value)>),
+    Ext(i8, Vec<u8>),
+}
+
+impl Value {
+    pub(crate) fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Int(i) => Some(*i),
+            _ => None,
+        }
+    }
+}
+
+//
+// -------- Encoding --------
+//
+
+pub(crate) fn write_nil(out: &mut Vec<u8>) {
+    out.push(0xc0);
+}
+
+pub(crate) fn write_bool(out: &mut Vec<u8>, b: bool) {
+    out.push(if b { 0xc3 } else { 0xc2 });
+}
+
+pub(crate) fn write_uint(out: &mut Vec<u8>, n: u64) {
+    match n {
+        0..=0x7f => out.push(n as u8),
+        0x80..=0xff => out.extend_from_slice(&[0xcc, n as u8]),
+        0x100..=0xffff => {
+            out.push(0xcd);
+            out.extend_from_slice(&(n as u16).to_be_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            out.push(0xce);
+            out.extend_from_slice(&(n as u32).to_be_bytes());
+        }
+        _ => {
+            out.push(0xcf);
+            out.extend_from_slice(&n.to_be_bytes());
+        }
+    }
+}
+
+pub(crate) fn write_int(out: &mut Vec<u8>, n: i64) {
+    if n >= 0 {
+        return write_uint(out, n as u64);
+    }
+    match n {
+        -32..=-1 => out.push(n as i8 as u8),
+        -128..=-33 => out.extend_from_slice(&[0xd0, n as i8 as u8]),
+        -32768..=-129 => {
+            out.push(0xd1);
+            out.extend_from_slice(&(n as i16).to_be_bytes());
+        }
+        -2147483648..=-32769 => {
+            out.push(0xd2);
+            out.extend_from_slice(&(n as i32).to_be_bytes());
+        }
+        _ => {
+            out.push(0xd3);
+            out.extend_from_slice(&n.to_be_bytes());
+        }
+    }
+}
+
+pub(crate) fn write_str(out: &mut Vec<u8>, s: &str) {
+    let n = s.len();
+    match n {
+        0..=31 => out.push(0xa0 | n as u8),
+        32..=0xff => out.extend_from_slice(&[0xd9, n as u8]),
+        0x100..=0xffff => {
+            out.push(0xda);
+            out.extend_from_slice(&(n as u16).to_be_bytes());
+        }
+        _ => {
+            out.push(0xdb);
+            out.extend_from_slice(&(n as u32).to_be_bytes());
+        }
+    }
+    out.extend_from_slice(s.as_bytes());
+}
+
+pub(crate) fn write_array_len(out: &mut Vec<u8>, n: usize) {
+    match n {
+        0..=15 => out.push(0x90 | n as u8),
+        16..=0xffff => {
+            out.push(0xdc);
+            out.extend_from_slice(&(n as u16).to_be_bytes());
+        }
+        _ => {
+            out.push(0xdd);
+            out.extend_from_slice(&(n as u32).to_be_bytes());
+        }
+    }
+}
+
+fn write_map_len(out: &mut Vec<u8>, n: usize) {
+    match n {
+        0..=15 => out.push(0x80 | n as u8),
+        16..=0xffff => {
+            out.push(0xde);
+            out.extend_from_slice(&(n as u16).to_be_bytes());
+        }
+        _ => {
+            out.push(0xdf);
+            out.extend_from_slice(&(n as u32).to_be_bytes());
+        }
+    }
+}
+
+pub(crate) fn write_json(out: &mut Vec<u8>, v: &Json) {
+    match v {
+        Json::Null => write_nil(out),
+        Json::Bool(b) => write_bool(out, *b),
+        Json::Number(n) => {
+            if let Some(u) = n.as_u64() {
+                write_uint(out, u);
+            } else if let Some(i) = n.as_i64() {
+                write_int(out, i);
+            } else {
+                out.push(0xcb);
+                out.extend_from_slice(&n.as_f64().unwrap_or(0.0).to_be_bytes());
+            }
+        }
+        Json::String(s) => write_str(out, s),
+        Json::Array(a) => {
+            write_array_len(out, a.len());
+            for item in a {
+                write_json(out, item);
+            }
+        }
+        Json::Object(m) => {
+            write_map_len(out, m.len());
+            for (k, item) in m {
+                write_str(out, k);
+                write_json(out, item);
+            }
+        }
+    }
+}
+
+//
+// -------- Decoding --------
+//
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Decode one value, pulling bytes through `read_exact`.
+pub(crate) fn read_value(
+    read_exact: &mut impl FnMut(&mut [u8]) -> io::Result<()>,
+) -> io::Result<Value> {
+    read_at(read_exact, 0)
+}
+
+fn read_at(r: &mut impl FnMut(&mut [u8]) -> io::Result<()>, depth: usize) -> io::Result<Value> {
+    if depth > MAX_DEPTH {
+        return Err(invalid("msgpack nesting too deep"));
+    }
+    let tag = be::<1>(r)?[0];
+    let v = match tag {
+        0x00..=0x7f => Value::Int(tag as i64),
+        0x80..=0x8f => read_map(r, Len::Fixed((tag & 0x0f) as usize), depth)?,
+        0x90..=0x9f => read_array(r, Len::Fixed((tag & 0x0f) as usize), depth)?,
+        0xa0..=0xbf => Value::Str(read_string(r, Len::Fixed((tag & 0x1f) as usize))?),
+        0xc0 => Value::Nil,
+        0xc2 => Value::Bool(false),
+        0xc3 => Value::Bool(true),
+        0xc4 => Value::Bin(read_bytes(r, Len::Prefixed(1))?),
+        0xc5 => Value::Bin(read_bytes(r, Len::Prefixed(2))?),
+        0xc6 => Value::Bin(read_bytes(r, Len::Prefixed(4))?),
+        0xc7 => read_ext(r, Len::Prefixed(1))?,
+        0xc8 => read_ext(r, Len::Prefixed(2))?,
+        0xc9 => read_ext(r, Len::Prefixed(4))?,
+        0xca => Value::Float(f32::from_be_bytes(be(r)?) as f64),
+        0xcb => Value::Float(f64::from_be_bytes(be(r)?)),
+        0xcc => Value::Int(u8::from_be_bytes(be(r)?) as i64),
+        0xcd => Value::Int(u16::from_be_bytes(be(r)?) as i64),
+        0xce => Value::Int(u32::from_be_bytes(be(r)?) as i64),
+        0xcf => {
+            let n = u64::from_be_bytes(be(r)?);
+            Value::Int(i64::try_from(n).map_err(|_| invalid("msgpack uint64 out of range"))?)
+        }
+        0xd0 => Value::Int(i8::from_be_bytes(be(r)?) as i64),
+        0xd1 => Value::Int(i16::from_be_bytes(be(r)?) as i64),
+        0xd2 => Value::Int(i32::from_be_bytes(be(r)?) as i64),
+        0xd3 => Value::Int(i64::from_be_bytes(be(r)?)),
+        0xd4 => read_ext(r, Len::Fixed(1))?,
+        0xd5 => read_ext(r, Len::Fixed(2))?,
+        0xd6 => read_ext(r, Len::Fixed(4))?,
+        0xd7 => read_ext(r, Len::Fixed(8))?,
+        0xd8 => read_ext(r, Len::Fixed(16))?,
+        0xd9 => Value::Str(read_string(r, Len::Prefixed(1))?),
+        0xda => Value::Str(read_string(r, Len::Prefixed(2))?),
+        0xdb => Value::Str(read_string(r, Len::Prefixed(4))?),
+        0xdc => read_array(r, Len::Prefixed(2), depth)?,
+        0xdd => read_array(r, Len::Prefixed(4), depth)?,
+        0xde => read_map(r, Len::Prefixed(2), depth)?,
+        0xdf => read_map(r, Len::Prefixed(4), depth)?,
+        0xe0..=0xff => Value::Int(tag as i8 as i64),
+        0xc1 => return Err(invalid("msgpack tag 0xc1 is reserved")),
+    };
+    Ok(v)
+}
+
+fn be<const N: usize>(r: &mut impl FnMut(&mut [u8]) -> io::Result<()>) -> io::Result<[u8; N]> {
+    let mut b = [0u8; N];
+    r(&mut b)?;
+    Ok(b)
+}
+
+/// A length encoded in the tag itself, or in the `N` big-endian bytes
+/// that follow it.
+#[derive(Clone, Copy)]
+enum Len {
+    Fixed(usize),
+    Prefixed(usize),
+}
+
+fn length(r: &mut impl FnMut(&mut [u8]) -> io::Result<()>, len: Len) -> io::Result<usize> {
+    let n = match len {
+        Len::Fixed(n) => n,
+        Len::Prefixed(width) => {
+            let mut b = [0u8; 4];
+            r(&mut b[4 - width..])?;
+            u32::from_be_bytes(b) as usize
+        }
+    };
+    if n > MAX_LEN {
+        return Err(invalid("msgpack value too large"));
+    }
+    Ok(n)
+}
+
+fn read_bytes(r: &mut impl FnMut(&mut [u8]) -> io::Result<()>, len: Len) -> io::Result<Vec<u8>> {
+    let n = length(r, len)?;
+    let mut b = vec![0u8; n];
+    r(&mut b)?;
+    Ok(b)
+}
+
+fn read_string(r: &mut impl FnMut(&mut [u8]) -> io::Result<()>, len: Len) -> io::Result<String> {
+    String::from_utf8(read_bytes(r, len)?).map_err(|_| invalid("msgpack string is not UTF-8"))
+}
+
+fn read_ext(r: &mut impl FnMut(&mut [u8]) -> io::Result<()>, len: Len) -> io::Result<Value> {
+    let n = length(r, len)?;
+    let ty = i8::from_be_bytes(be(r)?);
+    Ok(Value::Ext(ty, read_bytes(r, Len::Fixed(n))?))
+}
+
+fn read_array(
+    r: &mut impl FnMut(&mut [u8]) -> io::Result<()>,
+    len: Len,
+    depth: usize,
+) -> io::Result<Value> {
+    let n = length(r, len)?;
+    let mut items = Vec::with_capacity(n.min(64));
+    for _ in 0..n {
+        items.push(read_at(r, depth + 1)?);
+    }
+    Ok(Value::Array(items))
+}
+
+fn read_map(
+    r: &mut impl FnMut(&mut [u8]) -> io::Result<()>,
+    len: Len,
+    depth: usize,
+) -> io::Result<Value> {
+    let n = length(r, len)?;
+    let mut entries = Vec::with_capacity(n.min(64));
+    for _ in 0..n {
+        let k = read_at(r, depth + 1)?;
+        let v = read_at(r, depth + 1)?;
+        entries.push((k, v));
+    }
+    Ok(Value::Map(entries))
+}
+
+//
+// -------- msgpack-RPC --------
+//
+
+/// What Neovim runs for every shim call: `(method, params)` go to the
+/// plugin's handler, which answers a preflight with `{ allow = ... }`.
+const LUA_ENTRY: &str = "return require('nvim-claude.shim').rpc(...)";
+
+/// `[0, msgid, "nvim_exec_lua", [LUA_ENTRY, [method, params]]]`
+pub(crate) fn request(msgid: u32, method: &str, params: &Json) -> Vec<u8> {
+    let mut out = Vec::with_capacity(128);
+    write_array_len(&mut out, 4);
+    write_uint(&mut out, 0);
+    write_uint(&mut out, msgid as u64);
+    write_exec_lua(&mut out, method, params);
+    out
+}
+
+/// `[2, "nvim_exec_lua", [LUA_ENTRY, [method, params]]]`
+pub(crate) fn notification(method: &str, params: &Json) -> Vec<u8> {
+    let mut out = Vec::with_capacity(128);
+    write_array_len(&mut out, 3);
+    write_uint(&mut out, 2);
+    write_exec_lua(&mut out, method, params);
+    out
+}
+
+fn write_exec_lua(out: &mut Vec<u8>, method: &str, params: &Json) {
+    write_str(out, "nvim_exec_lua");
+    write_array_len(out, 2);
+    write_str(out, LUA_ENTRY);
+    write_array_len(out, 2);
+    write_str(out, method);
+    write_json(out, params);
+}
+
+/// `[1, msgid, error, result]`, answering a request from the peer.
+pub(crate) fn response(msgid: &Json, result: Result<Json, Json>) -> Vec<u8> {
+    let mut out = Vec::with_capacity(64);
+    write_array_len(&mut out, 4);
+    write_uint(&mut out, 1);
+    write_json(&mut out, msgid);
+    let (error, result) = match result {
+        Ok(r) => (Json::Null, r),
+        Err(e) => (e, Json::Null),
+    };
+    write_json(&mut out, &error);
+    write_json(&mut out, &result);
+    out
+}
+
+/// The value at the front of `buf` and its length, or `None` while it is
+/// incomplete.
+pub(crate) fn decode_prefix(buf: &[u8]) -> io::Result<Option<(Value, usize)>> {
+    let mut rest = buf;
+    let decoded = read_value(&mut |out: &mut [u8]| {
+        if rest.len() < out.len() {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let (head, tail) = rest.split_at(out.len());
+        out.copy_from_slice(head);
+        rest = tail;
+        Ok(())
+    });
+    match decoded {
+        Ok(v) => Ok(Some((v, buf.len() - rest.len()))),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Sort one msgpack-RPC message into request, notification or response.
+pub(crate) fn incoming(frame: Value) -> Option<Incoming> {
+    let Value::Array(items) = frame else {
+        return None;
+    };
+    let mut items = items.into_iter();
+    let kind = items.next()?.as_int()?;
+    let mut next = || items.next().map_or(Json::Null, Value::into_json);
+    match kind {
+        0 => {
+            let id = next();
+            let Json::String(method) = next() else {
+                return None;
+            };
+            Some(Incoming::Request {
+                id,
+                method,
+                params: next(),
+            })
+        }
+        1 => {
+            let id = next();
+            let (error, result) = (next(), next());
+            Some(Incoming::Response {
+                id,
+                result: match error {
+                    Json::Null => Ok(result),
+                    e => Err(e),
+                },
+            })
+        }
+        2 => {
+            let Json::String(method) = next() else {
+                return None;
+            };
+            Some(Incoming::Notification {
+                method,
+                params: next(),
+            })
+        }
+        _ => None,
+    }
+}
+
+impl Value {
+    /// For handlers, which work on JSON. Binary becomes an array of bytes,
+    /// non-string map keys their debug form, extensions null.
+    pub(crate) fn into_json(self) -> Json {
+        match self {
+            Value::Nil | Value::Ext(..) => Json::Null,
+            Value::Bool(b) => Json::Bool(b),
+            Value::Int(i) => Json::from(i),
+            Value::Float(f) => serde_json::Number::from_f64(f).map_or(Json::Null, Json::Number),
+            Value::Str(s) => Json::String(s),
+            Value::Bin(b) => Json::from(b),
+            Value::Array(a) => Json::Array(a.into_iter().map(Value::into_json).collect()),
+            Value::Map(m) => Json::Object(
+                m.into_iter()
+                    .map(|(k, v)| {
+                        let k = match k {
+                            Value::Str(s) => s,
+                            other => format!("{other:?}"),
+                        };
+                        (k, v.into_json())
+                    })
+                    .collect(),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn decode(bytes: &[u8]) -> io::Result<Value> {
+        match decode_prefix(bytes)? {
+            Some((v, _)) => Ok(v),
+            None => Err(io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+
+    fn roundtrip(v: &Json) -> Value {
+        let mut out = Vec::new();
+        write_json(&mut out, v);
+        decode(&out).unwrap()
+    }
+
+    #[test]
+    fn encodes_known_bytes() {
+        let mut out = Vec::new();
+        write_json(&mut out, &json!([0, 1, "a", true, null, -1, 300]));
+        assert_eq!(
+            out,
+            [0x97, 0x00, 0x01, 0xa1, b'a', 0xc3, 0xc0, 0xff, 0xcd, 0x01, 0x2c]
+        );
+    }
+
+    #[test]
+    fn json_roundtrips() {
+        let v = roundtrip(
+            &json!({ "allow": false, "path": "/w/a.txt", "n": -70000, "big": 1u64 << 40 }),
+        );
+        assert_eq!(
+            v.into_json(),
+            json!({ "allow": false, "path": "/w/a.txt", "n": -70000, "big": 1u64 << 40 })
+        );
+        let long = "x".repeat(70_000);
+        assert_eq!(roundtrip(&json!(long)), Value::Str(long));
+    }
+
+    #[test]
+    fn truncated_input_is_an_error() {
+        let mut out = Vec::new();
+        write_json(&mut out, &json!(["abc", 1]));
+        out.truncate(out.len() - 2);
+        assert!(decode(&out).is_err());
+    }
+
+    #[test]
+    fn absurd_lengths_are_refused() {
+        let frame = [0xdb, 0xff, 0xff, 0xff, 0xff];
+        let err = decode(&frame).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn messages_are_sorted_by_kind() {
+        let frame = |v: Json| {
+            let mut out = Vec::new();
+            write_json(&mut out, &v);
+            incoming(decode_prefix(&out).unwrap().unwrap().0)
+        };
+        assert!(matches!(
+            frame(json!([0, 4, "shim/flush", []])),
+            Some(Incoming::Request { id, method, .. }) if id == 4 && method == "shim/flush"
+        ));
+        assert!(matches!(
+            frame(json!([2, "nvim_buf_lines_event", [1, 2]])),
+            Some(Incoming::Notification { method, .. }) if method == "nvim_buf_lines_event"
+        ));
+        assert!(matches!(
+            frame(json!([1, 7, null, { "allow": false }])),
+            Some(Incoming::Response { id, result: Ok(r) }) if id == 7 && r["allow"] == false
+        ));
+        assert!(matches!(
+            frame(json!([1, 8, [0, "boom"], null])),
+            Some(Incoming::Response { result: Err(_), .. })
+        ));
+        assert!(frame(json!({ "not": "rpc" })).is_none());
+    }
+
+    #[test]
+    fn partial_values_wait_for_more_bytes() {
+        let mut out = Vec::new();
+        write_json(&mut out, &json!([1, 2, null, "abcdef"]));
+        let whole = out.len();
+        out.push(0xc0);
+        assert!(decode_prefix(&out[..whole - 3]).unwrap().is_none());
+        let (_, used) = decode_prefix(&out).unwrap().unwrap();
+        assert_eq!(used, whole);
+    }
+
+    #[test]
+    fn requests_wrap_the_call_in_exec_lua() {
+        let frame = request(3, "pre_delete", &json!({ "path": "/w/a" }));
+        let v = decode(&frame).unwrap().into_json();
+        assert_eq!(
+            v,
+            json!([0, 3, "nvim_exec_lua", [LUA_ENTRY, ["pre_delete", { "path": "/w/a" }]]])
+        );
+    }
+}
@@ -0,0 +1,210 @@
+//! The operation a thread is handling, and how deep in it the shim is.
+//!
+//! Every handler opens an `OpContext` before anything else and hands it
+//! down to the helpers that talk to the server: `preflight`,
+//! `preflight_with`, `preflight_async` and `post_notify` take one, and
+//! nothing else reaches the destination. The rules:
+//!
+//! - Only the outermost context on a thread (`is_outermost`) sends
+//!   anything. One operation is one protocol-visible preflight and one post
+//!   per path it changes, however many handlers its work goes through. A
+//!   handler that does another's work (a composite hook like `copyfile`,
+//!   `dup2` closing what it replaces, an `open` whose writes follow) passes
+//!   its own context down, and holds it across the real call.
+//! - The shim's own work on a thread that already has a context (its
+//!   socket I/O, a DNS lookup, an exit handler) opens a nested one, which
+//!   sends nothing; helpers below the outermost context find things out,
+//!   they don't ask. A hook entered beneath another hook calls the
+//!   original straight away (see `contain`), so nested real calls are
+//!   always raw.
+//! - Before the library constructor has run every context is disabled,
+//!   and handlers only pass calls through.
+//!
+//! Debug builds check the first rule: a second preflight, or a second
+//! post, with one method and path inside one outermost context panics
+//! (and `contain` catches it). Summaries (`"synthesized": true`) and the
+//! shim's own `shim/` notifications aren't an operation's events and
+//! aren't counted, nor are posts for fds a flush or exit reports.
+
+use std::cell::Cell;
+use std::sync::atomic::Ordering;
+
+use crate::SHIM_READY;
+
+thread_local! {
+    static DEPTH: Cell<u32> = const { Cell::new(0) };
+}
+
+#[cfg(debug_assertions)]
+thread_local! {
+    /// The outermost context's events so far, as (method, path).
+    static EMITTED: std::cell::RefCell<Vec<(String, String)>> =
+        const { std::cell::RefCell::new(Vec::new()) };
+}
+
+#[derive(Debug)]
+pub(crate) struct OpContext {
+    outermost: bool,
+    enabled: bool,
+}
+
+impl OpContext {
+    pub(crate) fn enter() -> OpContext {
+        OpContext::begin(SHIM_READY.load(Ordering::Relaxed))
+    }
+
+    fn begin(ready: bool) -> OpContext {
+        if !ready {
+            return OpContext {
+                outermost: false,
+                enabled: false,
+            };
+        }
+        let depth = DEPTH.with(|d| d.replace(d.get().saturating_add(1)));
+        #[cfg(debug_assertions)]
+        if depth == 0 {
+            let _ = EMITTED.try_with(|e| e.borrow_mut().clear());
+        }
+        OpContext {
+            outermost: depth == 0,
+            enabled: true,
+        }
+    }
+
+    /// False before the library constructor has run; such a context only
+    /// passes calls through.
+    pub(crate) fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Whether this context is the one that may talk to the server.
+    pub(crate) fn is_outermost(&self) -> bool {
+        self.outermost
+    }
+
+    /// `method` with `params` is about to go out for this operation.
+    #[cfg(debug_assertions)]
+    pub(crate) fn emitting(&self, method: &str, params: &serde_json::Value) {
+        if method.starts_with("shim/") || params["synthesized"] == true {
+            return;
+        }
+        let Some(path) = params["path"].as_str() else {
+            return;
+        };
+        debug_assert!(
+            self.outermost,
+            "shim: {method} {path} from a nested context"
+        );
+        // Gone once the thread's locals are torn down, as in exit handlers.
+        let first = EMITTED
+            .try_with(|e| {
+                let mut e = e.borrow_mut();
+                let key = (method.to_string(), path.to_string());
+                let first = !e.contains(&key);
+                e.push(key);
+                first
+            })
+            .unwrap_or(true);
+        debug_assert!(first, "shim: a second {method} for {path} in one operation");
+    }
+
+    #[cfg(not(debug_assertions))]
+    #[inline]
+    pub(crate) fn emitting(&self, _method: &str, _params: &serde_json::Value) {}
+}
+
+impl Drop for OpContext {
+    fn drop(&mut self) {
+        if self.enabled {
+            DEPTH.with(|d| d.set(d.get().saturating_sub(1)));
+        }
+    }
+}
+
+/// True beneath a context that is itself nested, so two below the
+/// outermost: the shim's own work inside its own work. Notifications go
+/// out from the outermost context, so only deeper nesting suppresses them.
+#[inline]
+pub(crate) fn nested() -> bool {
+    SHIM_READY.load(Ordering::Relaxed) && DEPTH.with(|d| d.get() > 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn only_the_outermost_of_two_levels_may_send() {
+        let outer = OpContext::begin(true);
+        {
+            let inner = OpContext::begin(true);
+            assert!(outer.is_outermost() && !inner.is_outermost());
+            assert!(inner.enabled());
+        }
+        assert!(outer.is_outermost());
+        drop(outer);
+        assert!(OpContext::begin(true).is_outermost());
+    }
+
+    #[test]
+    fn three_levels_nest_and_unwind_in_order() {
+        let outer = OpContext::begin(true);
+        let middle = OpContext::begin(true);
+        let inner = OpContext::begin(true);
+        assert_eq!(
+            [&outer, &middle, &inner].map(OpContext::is_outermost),
+            [true, false, false]
+        );
+        assert_eq!(DEPTH.with(Cell::get), 3);
+        drop(inner);
+        drop(middle);
+        assert_eq!(DEPTH.with(Cell::get), 1);
+        drop(outer);
+        assert_eq!(DEPTH.with(Cell::get), 0);
+    }
+
+    #[test]
+    fn contexts_before_init_are_disabled_and_leave_the_depth_alone() {
+        let early = OpContext::begin(false);
+        assert!(!early.enabled() && !early.is_outermost());
+        drop(early);
+        assert_eq!(DEPTH.with(Cell::get), 0);
+    }
+
+    #[test]
+    fn one_operation_may_send_one_event_per_method_and_path() {
+        let ctx = OpContext::begin(true);
+        ctx.emitting("pre_rename", &json!({ "path": "/p/b" }));
+        ctx.emitting("pre_rename", &json!({ "path": "/p/a" }));
+        ctx.emitting("post_rename", &json!({ "path": "/p/b" }));
+        ctx.emitting(
+            "post_dir_changed",
+            &json!({ "path": "/p", "synthesized": true }),
+        );
+        ctx.emitting(
+            "post_dir_changed",
+            &json!({ "path": "/p", "synthesized": true }),
+        );
+        ctx.emitting("shim/cwd_changed", &json!({ "cwd": "/p" }));
+        ctx.emitting("shim/cwd_changed", &json!({ "cwd": "/p" }));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "a second post_create for /p/a in one operation")]
+    fn a_second_post_for_one_path_panics_in_debug_builds() {
+        let ctx = OpContext::begin(true);
+        ctx.emitting("post_create", &json!({ "path": "/p/a" }));
+        ctx.emitting("post_create", &json!({ "path": "/p/a" }));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "from a nested context")]
+    fn events_from_a_nested_context_panic_in_debug_builds() {
+        let _outer = OpContext::begin(true);
+        let inner = OpContext::begin(true);
+        inner.emitting("post_delete", &json!({ "path": "/p/a" }));
+    }
+}
@@ -0,0 +1,129 @@
+//! The descriptors the shim holds for itself.
+//!
+//! Each thread keeps its own control connection (and one per route it
+//! has used), so a process with many threads and a raised fd limit would
+//! otherwise hand the shim a share of its descriptors that grows without
+//! bound. So every control connection and every file `internal_io` has
+//! open is counted here while it is open, and a new connection is only
+//! made while the count is under `cap`: a quarter of the soft
+//! `RLIMIT_NOFILE`, read at each connect. Past that a connect is refused
+//! as if nothing answered, and counted.
+//!
+//! A connect or an open of ours that fails with `EMFILE` or `ENFILE`
+//! means the process (or the system) is out of descriptors, and trying
+//! again at every operation would only take more of them from the
+//! program. So from then on the primary destination is treated as
+//! disabled (unless it is an inherited fd, which needs none), no connect
+//! is tried anywhere, and it is said once on stderr and to the fan-out
+//! sinks as `shim/error` with `"error": "fd_exhausted"`. `shim/stats`
+//! reports it all as `fds`.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use serde_json::{json, Value};
+
+/// Below this soft limit the shim may still hold one fd.
+const MIN_CAP: usize = 1;
+
+static OPEN: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+static REFUSED: AtomicU64 = AtomicU64::new(0);
+static EXHAUSTED: AtomicBool = AtomicBool::new(false);
+
+/// One fd of ours, counted until dropped.
+#[derive(Debug)]
+pub(crate) struct Held(());
+
+impl Held {
+    pub(crate) fn new() -> Held {
+        let open = OPEN.fetch_add(1, Ordering::Relaxed) + 1;
+        PEAK.fetch_max(open, Ordering::Relaxed);
+        Held(())
+    }
+}
+
+impl Drop for Held {
+    fn drop(&mut self) {
+        OPEN.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// How many fds the shim may hold at once.
+fn cap() -> Option<usize> {
+    let mut lim = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut lim) } != 0
+        || lim.rlim_cur == libc::RLIM_INFINITY
+    {
+        return None;
+    }
+    Some(usize::try_from(lim.rlim_cur / 4).map_or(usize::MAX, |c| c.max(MIN_CAP)))
+}
+
+/// Whether a new connection may be made now; `false` is counted.
+pub(crate) fn admit() -> bool {
+    if EXHAUSTED.load(Ordering::Relaxed) {
+        return false;
+    }
+    let ok = cap().is_none_or(|cap| OPEN.load(Ordering::Relaxed) < cap);
+    if !ok {
+        REFUSED.fetch_add(1, Ordering::Relaxed);
+    }
+    ok
+}
+
+/// Whether the process has run out of fds under one of our calls.
+pub(crate) fn exhausted() -> bool {
+    EXHAUSTED.load(Ordering::Relaxed)
+}
+
+/// Look at the error a connect or open of ours failed with; `true`, and
+/// from then on `exhausted`, when it says there are no fds left.
+pub(crate) fn note(e: &io::Error) -> bool {
+    if !matches!(e.raw_os_error(), Some(libc::EMFILE | libc::ENFILE)) {
+        return false;
+    }
+    if !EXHAUSTED.swap(true, Ordering::Relaxed) {
+        let detail = format!(
+            "{e} with {} of the shim's own open; destination disabled",
+            OPEN.load(Ordering::Relaxed)
+        );
+        crate::report_error("fd_exhausted", &detail, None);
+        let params = json!({
+            "error": "fd_exhausted",
+            "detail": detail,
+            "pid": unsafe { libc::getpid() },
+        });
+        crate::fan_out("shim/error", &params);
+    }
+    true
+}
+
+/// For `shim/stats`.
+pub(crate) fn snapshot() -> Value {
+    json!({
+        "open": OPEN.load(Ordering::Relaxed),
+        "peak": PEAK.load(Ordering::Relaxed),
+        "cap": cap(),
+        "refused": REFUSED.load(Ordering::Relaxed),
+        "exhausted": exhausted(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_running_out_of_fds_counts_as_exhaustion() {
+        assert!(!note(&io::Error::from_raw_os_error(libc::ECONNREFUSED)));
+        assert!(!note(&io::Error::from_raw_os_error(libc::ENOENT)));
+        assert!(!exhausted());
+        let held = Held::new();
+        assert!(OPEN.load(Ordering::Relaxed) >= 1 && PEAK.load(Ordering::Relaxed) >= 1);
+        drop(held);
+    }
+}
@@ -0,0 +1,271 @@
+//! What kind of file a path is, for policy.
+//!
+//! Writes under `.git/` (the index, refs, objects) happen all the time
+//! and are never worth a prompt, while a write to `.git/hooks/pre-commit`
+//! or `~/.zshrc` is exactly what a user wants to see first. So every path
+//! falls in one class:
+//!
+//! - `vcs_internal`: inside a `.git`, `.hg`, `.svn` or `.jj` directory,
+//!   or the `.git` file of a worktree;
+//! - `vcs_hooks`: inside `.git/hooks`;
+//! - `dotfile`: a dot-named file in the home directory (`~/.zshrc`,
+//!   `~/.gitconfig`), or anything under `~/.config`, `~/.ssh` or
+//!   `~/.gnupg`; the home directory's other dot directories are mostly
+//!   caches (`~/.cache`, `~/.cargo`, `~/.npm`) and stay `normal`;
+//! - `normal`: everything else, dot-named files in a project included.
+//!
+//! `[[classify]]` globs come first, so a class can be given or taken
+//! away. Each class has a policy (`class_vcs_internal` and so on): `off`
+//! is ignored like an `ignore` glob, `notify` gets posts but never a
+//! preflight, `block` is asked about every time (no cached allow, and
+//! appends and truncates that would only be reported are asked about
+//! too), and `default` is the rest of the config as it stands. The class
+//! goes out as `"class"` on every preflight and post.
+//!
+//! Classes are matched on the path as reported (`paths::for_matching`),
+//! and everything but the file name is decided once per directory: at
+//! most `CAP` are kept (past that the table starts again). The same
+//! table says which of `watch_dirs` a directory is under.
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::Deserialize;
+
+use crate::config::{self, ClassPolicy, ShimConfig};
+use crate::{glob, heap, paths};
+
+const CAP: usize = 256;
+
+pub(crate) const VCS_DIRS: [&str; 4] = [".git", ".hg", ".svn", ".jj"];
+/// Directories in the home directory whose every file is a dotfile.
+const DOT_DIRS: [&str; 3] = [".config", ".ssh", ".gnupg"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum PathClass {
+    VcsInternal,
+    VcsHooks,
+    Dotfile,
+    Normal,
+}
+
+impl PathClass {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            PathClass::VcsInternal => "vcs_internal",
+            PathClass::VcsHooks => "vcs_hooks",
+            PathClass::Dotfile => "dotfile",
+            PathClass::Normal => "normal",
+        }
+    }
+}
+
+impl std::str::FromStr for PathClass {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "vcs_internal" => Ok(PathClass::VcsInternal),
+            "vcs_hooks" => Ok(PathClass::VcsHooks),
+            "dotfile" => Ok(PathClass::Dotfile),
+            "normal" => Ok(PathClass::Normal),
+            other => Err(format!("unknown class {other:?}")),
+        }
+    }
+}
+
+/// What a directory makes of the entries in it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Dir {
+    Vcs,
+    Hooks,
+    /// The home directory: its dot-named entries are dotfiles.
+    Home,
+    /// Under one of `DOT_DIRS`.
+    UnderDotfile,
+    Plain,
+}
+
+static HOME: Lazy<Option<PathBuf>> = Lazy::new(|| {
+    std::env::var_os("HOME")
+        .filter(|h| !h.is_empty())
+        .map(|h| paths::for_matching(Path::new(&h), config::get().normalize_unicode).into_owned())
+});
+
+/// A directory's `Dir`, and the nearest of `watch_dirs` it is under.
+type Cached = (Dir, Option<usize>);
+
+static DIRS: Mutex<Option<HashMap<PathBuf, Cached>>> = parking_lot::const_mutex(None);
+
+fn same(a: &OsStr, b: &str, fold: bool) -> bool {
+    if fold {
+        a.as_bytes().eq_ignore_ascii_case(b.as_bytes())
+    } else {
+        a.as_bytes() == b.as_bytes()
+    }
+}
+
+fn is_vcs(name: &OsStr, fold: bool) -> bool {
+    VCS_DIRS.iter().any(|v| same(name, v, fold))
+}
+
+fn is_dot(name: &OsStr) -> bool {
+    name.as_bytes().first() == Some(&b'.')
+}
+
+fn dir_of(dir: &Path, home: Option<&Path>, fold: bool) -> Dir {
+    let names: Vec<&OsStr> = dir.iter().collect();
+    if let Some(i) = names.iter().position(|n| is_vcs(n, fold)) {
+        let hooks = same(names[i], ".git", fold)
+            && names.get(i + 1).is_some_and(|n| same(n, "hooks", fold));
+        return if hooks { Dir::Hooks } else { Dir::Vcs };
+    }
+    let Some(home) = home.filter(|h| glob::has_prefix(dir, h, fold)) else {
+        return Dir::Plain;
+    };
+    match dir.iter().nth(home.iter().count()) {
+        None => Dir::Home,
+        Some(first) if DOT_DIRS.iter().any(|d| same(first, d, fold)) => Dir::UnderDotfile,
+        Some(_) => Dir::Plain,
+    }
+}
+
+/// The nearest of `watched` that `dir` is, or is under.
+fn watch_of(dir: &Path, watched: &[PathBuf], fold: bool) -> Option<usize> {
+    watched
+        .iter()
+        .enumerate()
+        .filter(|(_, w)| glob::has_prefix(dir, w, fold))
+        .max_by_key(|(_, w)| w.components().count())
+        .map(|(i, _)| i)
+}
+
+fn cached_dir(cfg: &ShimConfig, dir: &Path, home: Option<&Path>) -> Cached {
+    let fold = cfg.case_insensitive;
+    let mut dirs = DIRS.lock();
+    let dirs = dirs.get_or_insert_with(HashMap::new);
+    if let Some(&d) = dirs.get(dir) {
+        return d;
+    }
+    let d = (
+        dir_of(dir, home, fold),
+        watch_of(dir, &cfg.watch_dirs, fold),
+    );
+    if dirs.len() >= CAP {
+        for dir in dirs.keys() {
+            heap::release(dir.as_os_str().len() + heap::ENTRY);
+        }
+        dirs.clear();
+    }
+    if heap::charge(dir.as_os_str().len() + heap::ENTRY) {
+        dirs.insert(dir.to_path_buf(), d);
+    }
+    d
+}
+
+fn classify_in(cfg: &ShimConfig, path: &Path, home: Option<&Path>) -> PathClass {
+    let fold = cfg.case_insensitive;
+    if let Some(rule) = cfg
+        .classify
+        .iter()
+        .find(|r| glob::matches(&r.glob, path, fold))
+    {
+        return rule.class;
+    }
+    let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+        return PathClass::Normal;
+    };
+    match cached_dir(cfg, dir, home).0 {
+        Dir::Vcs => PathClass::VcsInternal,
+        Dir::Hooks => PathClass::VcsHooks,
+        _ if same(name, ".git", fold) => PathClass::VcsInternal,
+        Dir::UnderDotfile => PathClass::Dotfile,
+        Dir::Home if is_dot(name) => PathClass::Dotfile,
+        _ => PathClass::Normal,
+    }
+}
+
+/// `path`'s class.
+pub(crate) fn classify(path: &Path) -> PathClass {
+    let cfg = config::get();
+    let path = paths::for_matching(path, cfg.normalize_unicode);
+    classify_in(cfg, &path, HOME.as_deref())
+}
+
+/// `path`'s class and the policy for it.
+pub(crate) fn policy(path: &Path) -> (PathClass, ClassPolicy) {
+    policy_with(config::get(), path)
+}
+
+/// `path`'s class and the policy for it under `cfg`.
+pub(crate) fn policy_with(cfg: &ShimConfig, path: &Path) -> (PathClass, ClassPolicy) {
+    let matched = paths::for_matching(path, cfg.normalize_unicode);
+    let class = classify_in(cfg, &matched, HOME.as_deref());
+    (class, cfg.class_policy(class))
+}
+
+/// Which of `watch_dirs` `path` is under, the nearest if several.
+pub(crate) fn watched(path: &Path) -> Option<usize> {
+    watched_with(config::get(), path)
+}
+
+/// Which of `cfg`'s `watch_dirs` `path` is under.
+pub(crate) fn watched_with(cfg: &ShimConfig, path: &Path) -> Option<usize> {
+    if cfg.watch_dirs.is_empty() {
+        return None;
+    }
+    let path = paths::for_matching(path, cfg.normalize_unicode);
+    cached_dir(cfg, path.parent()?, HOME.as_deref()).1
+}
+
+/// Whether `path` is asked about every time.
+pub(crate) fn strict(path: &Path) -> bool {
+    policy(path).1 == ClassPolicy::Block
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vcs_metadata_hooks_and_home_dotfiles_are_told_apart() {
+        let mut cfg = ShimConfig {
+            case_insensitive: false,
+            ..ShimConfig::default()
+        };
+        let home = Some(Path::new("/home/u"));
+        let class = |p: &str| classify_in(&cfg, Path::new(p), home);
+        assert_eq!(class("/home/u/p/.git/index"), PathClass::VcsInternal);
+        assert_eq!(
+            class("/home/u/p/.git/refs/heads/main"),
+            PathClass::VcsInternal
+        );
+        assert_eq!(class("/home/u/p/.git"), PathClass::VcsInternal);
+        assert_eq!(
+            class("/home/u/p/.git/hooks/pre-commit"),
+            PathClass::VcsHooks
+        );
+        assert_eq!(class("/home/u/.zshrc"), PathClass::Dotfile);
+        assert_eq!(
+            class("/home/u/.config/fish/config.fish"),
+            PathClass::Dotfile
+        );
+        assert_eq!(class("/home/u/p/.gitignore"), PathClass::Normal);
+        assert_eq!(class("/home/u/p/src/a.rs"), PathClass::Normal);
+        assert_eq!(class("/home/u"), PathClass::Normal);
+
+        cfg.classify = vec![config::ClassifyConfig {
+            glob: "/home/u/.config/nvim/**".into(),
+            class: PathClass::Normal,
+        }];
+        let class = |p: &str| classify_in(&cfg, Path::new(p), home);
+        assert_eq!(class("/home/u/.config/nvim/x/init.lua"), PathClass::Normal);
+        assert_eq!(class("/home/u/.ssh/authorized_keys"), PathClass::Dotfile);
+        assert_eq!(class("/home/u/.cargo/env"), PathClass::Normal);
+    }
+}
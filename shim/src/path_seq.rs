@@ -0,0 +1,90 @@
+//! Per-path event order.
+//!
+//! Every preflight and post names one path, and each carries
+//! `params.path_seq`: a number that grows with every event for that path
+//! within this process. Arrival order alone can't be trusted for that:
+//! each thread has its own control connection, so a `post_modify` written
+//! on one socket can land after a later `pre_delete` written on another,
+//! and notifications replayed after a reconnect reach the server after
+//! newer ones. The server orders a path's events by `path_seq` instead.
+//!
+//! Counters are kept for `CAP` paths at a time. When the table fills it is
+//! emptied, and every path starts again above the highest number handed
+//! out so far, so a path's numbers only ever increase. The same happens
+//! when the `heap` cap has no room for another path; with none even then,
+//! the event takes the next number above the floor, and the floor moves
+//! up to it, without the path being kept.
+
+use std::collections::HashMap;
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+
+use crate::heap;
+
+const CAP: usize = 4096;
+
+#[derive(Default)]
+struct Counters {
+    by_path: HashMap<String, u64>,
+    /// Every path not in `by_path` continues from here.
+    floor: u64,
+}
+
+static COUNTERS: Lazy<Mutex<Counters>> = Lazy::new(|| Mutex::new(Counters::default()));
+
+impl Counters {
+    fn next(&mut self, path: &str) -> u64 {
+        if let Some(n) = self.by_path.get_mut(path) {
+            *n += 1;
+            return *n;
+        }
+        if self.by_path.len() >= CAP {
+            self.empty();
+        }
+        let cost = path.len() + heap::ENTRY;
+        if !heap::charge(cost) {
+            self.empty();
+            if !heap::charge(cost) {
+                self.floor += 1;
+                return self.floor;
+            }
+        }
+        let n = self.floor + 1;
+        self.by_path.insert(path.to_string(), n);
+        n
+    }
+
+    /// Forget every path, raising the floor above all their numbers.
+    fn empty(&mut self) {
+        for (path, n) in self.by_path.drain() {
+            heap::release(path.len() + heap::ENTRY);
+            self.floor = self.floor.max(n);
+        }
+    }
+}
+
+/// The `path_seq` for the next event on `path`, as reported.
+pub(crate) fn next(path: &str) -> u64 {
+    COUNTERS.lock().next(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers_only_grow_across_a_full_table() {
+        let mut c = Counters::default();
+        assert_eq!(c.next("/a"), 1);
+        assert_eq!(c.next("/a"), 2);
+        assert_eq!(c.next("/b"), 1);
+        for i in 0..CAP {
+            c.next(&format!("/fill/{i}"));
+        }
+        // The table emptied on the way; /a picks up above its old 2.
+        assert!(!c.by_path.contains_key("/a"));
+        assert_eq!(c.next("/a"), 3);
+        assert_eq!(c.next("/a"), 4);
+    }
+}
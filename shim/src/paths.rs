@@ -0,0 +1,65 @@
+//! Path forms used for matching and reporting.
+//!
+//! macOS hands back NFD-decomposed names (`cafe\u{301}`) while buffers and
+//! user globs are usually NFC (`caf\u{e9}`), so by default every path is
+//! composed to NFC before it's matched or sent. ASCII paths, the common
+//! case, are detected with one byte scan and never copied.
+
+use std::borrow::Cow;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use unicode_normalization::{is_nfc_quick, IsNormalized, UnicodeNormalization};
+
+/// `path` composed to NFC. Borrowed when it's already NFC or isn't UTF-8
+/// (those bytes are passed through untouched).
+pub(crate) fn nfc(path: &Path) -> Cow<'_, Path> {
+    if path.as_os_str().as_bytes().is_ascii() {
+        return Cow::Borrowed(path);
+    }
+    let Some(s) = path.to_str() else {
+        return Cow::Borrowed(path);
+    };
+    if is_nfc_quick(s.chars()) == IsNormalized::Yes {
+        return Cow::Borrowed(path);
+    }
+    let composed: String = s.nfc().collect();
+    if composed == s {
+        Cow::Borrowed(path)
+    } else {
+        Cow::Owned(PathBuf::from(composed))
+    }
+}
+
+/// NFC when `normalize` is set, the raw path otherwise.
+pub(crate) fn for_matching(path: &Path, normalize: bool) -> Cow<'_, Path> {
+    if normalize {
+        nfc(path)
+    } else {
+        Cow::Borrowed(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn composes_decomposed_names() {
+        let nfd = Path::new("/w/cafe\u{301}.txt");
+        assert_eq!(nfc(nfd), Path::new("/w/caf\u{e9}.txt"));
+        assert!(matches!(nfc(nfd), Cow::Owned(_)));
+    }
+
+    #[test]
+    fn leaves_ascii_and_nfc_borrowed() {
+        assert!(matches!(nfc(Path::new("/w/plain.txt")), Cow::Borrowed(_)));
+        assert!(matches!(nfc(Path::new("/w/caf\u{e9}")), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn passes_non_utf8_through() {
+        use std::ffi::OsStr;
+        let raw = Path::new(OsStr::from_bytes(b"/w/\xff\xfe"));
+        assert_eq!(nfc(raw), raw);
+    }
+}
@@ -0,0 +1,1318 @@
+//! macOS: raw Darwin syscalls, `F_GETPATH`, and dyld `__interpose` glue.
+
+use std::ffi::{CStr, OsStr};
+use std::os::raw::{c_char, c_int, c_uint, c_void};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::prelude::RawFd;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+
+const F_GETPATH: c_int = libc::F_GETPATH;
+
+mod darwin_sys {
+    use libc::c_int;
+
+    pub const SYS_OPEN: c_int = 5;
+    pub const SYS_OPENAT: c_int = 463;
+    pub const SYS_WRITE: c_int = 4;
+    pub const SYS_PWRITE: c_int = 154;
+    pub const SYS_WRITEV: c_int = 121;
+    pub const SYS_CLOSE: c_int = 6;
+    pub const SYS_UNLINK: c_int = 10;
+    pub const SYS_CHDIR: c_int = 12;
+    pub const SYS_FCHDIR: c_int = 13;
+    pub const SYS_RENAME: c_int = 128;
+    pub const SYS_TRUNCATE: c_int = 200;
+    pub const SYS_FTRUNCATE: c_int = 201;
+}
+
+//
+// -------- Library init --------
+//
+
+unsafe extern "C" fn shim_library_init() {
+    crate::handshake::announce();
+    if crate::bypass::decide() {
+        return;
+    }
+    crate::lineage::capture();
+    crate::adopt_inherited_fd_from_env();
+    crate::exec_fds::adopt();
+    crate::procinfo::capture_cwd();
+    crate::procinfo::capture_umask();
+    crate::contain::install_panic_hook();
+    crate::contain::disable_configured();
+    crate::shutdown::register();
+    crate::SHIM_READY.store(true, Ordering::SeqCst);
+    crate::verify::run();
+}
+
+#[cfg(not(test))]
+#[link_section = "__DATA,__mod_init_func"]
+#[used]
+static SHIM_INIT_HOOK: unsafe extern "C" fn() = shim_library_init;
+
+//
+// -------- Raw syscalls --------
+//
+
+/// Whether `open`'s variadic mode argument is present for these flags.
+#[inline]
+pub(crate) fn open_needs_mode(flags: c_int) -> bool {
+    flags & libc::O_CREAT != 0
+}
+
+#[inline]
+pub(crate) unsafe fn sys_open(path: *const c_char, flags: c_int, mode: libc::mode_t) -> c_int {
+    unsafe {
+        libc::syscall(
+            darwin_sys::SYS_OPEN,
+            path as libc::intptr_t,
+            flags as libc::intptr_t,
+            mode as libc::intptr_t,
+        ) as c_int
+    }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_openat(
+    dirfd: c_int,
+    path: *const c_char,
+    flags: c_int,
+    mode: libc::mode_t,
+) -> c_int {
+    unsafe {
+        libc::syscall(
+            darwin_sys::SYS_OPENAT,
+            dirfd as libc::intptr_t,
+            path as libc::intptr_t,
+            flags as libc::intptr_t,
+            mode as libc::intptr_t,
+        ) as c_int
+    }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_write(
+    fd: c_int,
+    buf: *const c_void,
+    count: libc::size_t,
+) -> libc::ssize_t {
+    unsafe {
+        libc::syscall(
+            darwin_sys::SYS_WRITE,
+            fd as libc::intptr_t,
+            buf as libc::intptr_t,
+            count as libc::intptr_t,
+        ) as libc::ssize_t
+    }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_pwrite(
+    fd: c_int,
+    buf: *const c_void,
+    count: libc::size_t,
+    offset: libc::off_t,
+) -> libc::ssize_t {
+    unsafe {
+        libc::syscall(
+            darwin_sys::SYS_PWRITE,
+            fd as libc::intptr_t,
+            buf as libc::intptr_t,
+            count as libc::intptr_t,
+            offset as libc::intptr_t,
+        ) as libc::ssize_t
+    }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_writev(
+    fd: c_int,
+    iov: *const libc::iovec,
+    iovcnt: c_int,
+) -> libc::ssize_t {
+    unsafe {
+        libc::syscall(
+            darwin_sys::SYS_WRITEV,
+            fd as libc::intptr_t,
+            iov as libc::intptr_t,
+            iovcnt as libc::intptr_t,
+        ) as libc::ssize_t
+    }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_close(fd: c_int) -> c_int {
+    unsafe { libc::syscall(darwin_sys::SYS_CLOSE, fd as libc::intptr_t) as c_int }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_unlink(path: *const c_char) -> c_int {
+    unsafe { libc::syscall(darwin_sys::SYS_UNLINK, path as libc::intptr_t) as c_int }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_rename(old: *const c_char, new: *const c_char) -> c_int {
+    unsafe {
+        libc::syscall(
+            darwin_sys::SYS_RENAME,
+            old as libc::intptr_t,
+            new as libc::intptr_t,
+        ) as c_int
+    }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_truncate(path: *const c_char, len: libc::off_t) -> c_int {
+    unsafe {
+        libc::syscall(
+            darwin_sys::SYS_TRUNCATE,
+            path as libc::intptr_t,
+            len as libc::intptr_t,
+        ) as c_int
+    }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_ftruncate(fd: c_int, len: libc::off_t) -> c_int {
+    unsafe {
+        libc::syscall(
+            darwin_sys::SYS_FTRUNCATE,
+            fd as libc::intptr_t,
+            len as libc::intptr_t,
+        ) as c_int
+    }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_chdir(path: *const c_char) -> c_int {
+    unsafe { libc::syscall(darwin_sys::SYS_CHDIR, path as libc::intptr_t) as c_int }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_fchdir(fd: c_int) -> c_int {
+    unsafe { libc::syscall(darwin_sys::SYS_FCHDIR, fd as libc::intptr_t) as c_int }
+}
+
+/// stdio's own flush; calls from the shim's image aren't interposed.
+#[inline]
+pub(crate) unsafe fn sys_fflush(stream: *mut libc::FILE) -> c_int {
+    unsafe { libc::fflush(stream) }
+}
+
+// Calls from the shim's image aren't interposed, so libc's own will do.
+#[inline]
+pub(crate) unsafe fn sys_mkfifo(path: *const c_char, mode: libc::mode_t) -> c_int {
+    unsafe { libc::mkfifo(path, mode) }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_mkfifoat(dirfd: c_int, path: *const c_char, mode: libc::mode_t) -> c_int {
+    unsafe { libc::mkfifoat(dirfd, path, mode) }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_mknod(path: *const c_char, mode: libc::mode_t, dev: libc::dev_t) -> c_int {
+    unsafe { libc::mknod(path, mode, dev) }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_shm_open(name: *const c_char, oflag: c_int, mode: libc::mode_t) -> c_int {
+    unsafe { libc::shm_open(name, oflag, mode as c_uint) }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_shm_unlink(name: *const c_char) -> c_int {
+    unsafe { libc::shm_unlink(name) }
+}
+
+/// Shared memory has no path on macOS.
+pub(crate) fn shm_path(_name: *const c_char) -> Option<PathBuf> {
+    None
+}
+
+#[inline]
+pub(crate) unsafe fn sys_flock(fd: c_int, op: c_int) -> c_int {
+    unsafe { libc::flock(fd, op) }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_fcntl(fd: c_int, cmd: c_int, arg: usize) -> c_int {
+    unsafe { libc::fcntl(fd, cmd, arg) }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_fchmod(fd: c_int, mode: libc::mode_t) -> c_int {
+    unsafe { libc::fchmod(fd, mode) }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_futimens(fd: c_int, times: *const libc::timespec) -> c_int {
+    unsafe { libc::futimens(fd, times) }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_futimes(fd: c_int, times: *const libc::timeval) -> c_int {
+    unsafe { libc::futimes(fd, times) }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_utimensat(
+    dirfd: c_int,
+    path: *const c_char,
+    times: *const libc::timespec,
+    flags: c_int,
+) -> c_int {
+    unsafe { libc::utimensat(dirfd, path, times, flags) }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_utimes(path: *const c_char, times: *const libc::timeval) -> c_int {
+    unsafe { libc::utimes(path, times) }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_setuid(uid: libc::uid_t) -> c_int {
+    unsafe { libc::setuid(uid) }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_seteuid(uid: libc::uid_t) -> c_int {
+    unsafe { libc::seteuid(uid) }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_setgid(gid: libc::gid_t) -> c_int {
+    unsafe { libc::setgid(gid) }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_setegid(gid: libc::gid_t) -> c_int {
+    unsafe { libc::setegid(gid) }
+}
+
+// `execv` and `execvp` come through `execve`, so only it is interposed.
+#[inline]
+pub(crate) unsafe fn sys_execve(
+    path: *const c_char,
+    argv: *const *const c_char,
+    envp: *const *const c_char,
+) -> c_int {
+    unsafe { libc::execve(path, argv, envp) }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_posix_spawn(
+    pid: *mut libc::pid_t,
+    path: *const c_char,
+    actions: *const libc::posix_spawn_file_actions_t,
+    attr: *const libc::posix_spawnattr_t,
+    argv: *const *mut c_char,
+    envp: *const *mut c_char,
+) -> c_int {
+    unsafe { libc::posix_spawn(pid, path, actions, attr, argv, envp) }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_posix_spawnp(
+    pid: *mut libc::pid_t,
+    file: *const c_char,
+    actions: *const libc::posix_spawn_file_actions_t,
+    attr: *const libc::posix_spawnattr_t,
+    argv: *const *mut c_char,
+    envp: *const *mut c_char,
+) -> c_int {
+    unsafe { libc::posix_spawnp(pid, file, actions, attr, argv, envp) }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_fchflags(fd: c_int, flags: libc::c_uint) -> c_int {
+    unsafe { libc::fchflags(fd, flags) }
+}
+
+pub(crate) unsafe fn sys_exit(status: c_int) -> ! {
+    unsafe { libc::exit(status) }
+}
+
+pub(crate) unsafe fn sys_uexit(status: c_int) -> ! {
+    unsafe { libc::_exit(status) }
+}
+
+/// `removefile(3)` flag: take the whole tree.
+pub(crate) const REMOVEFILE_RECURSIVE: u32 = 1 << 0;
+
+extern "C" {
+    // libremovefile, part of libSystem; not in the libc crate.
+    fn removefile(path: *const c_char, state: *mut c_void, flags: u32) -> c_int;
+
+    // libSystem's POSIX.1e ACL calls; not in the libc crate either.
+    fn acl_set_file(path: *const c_char, ty: c_int, acl: *mut c_void) -> c_int;
+    fn acl_set_link_np(path: *const c_char, ty: c_int, acl: *mut c_void) -> c_int;
+    fn acl_set_fd(fd: c_int, acl: *mut c_void) -> c_int;
+    fn acl_to_text(acl: *mut c_void, len: *mut libc::ssize_t) -> *mut c_char;
+    fn acl_free(obj: *mut c_void) -> c_int;
+}
+
+/// The library routines, not a syscall: they are what make the calls the
+/// other hooks see. Calls from the shim's image aren't interposed.
+#[inline]
+pub(crate) unsafe fn sys_copyfile(
+    from: *const c_char,
+    to: *const c_char,
+    state: *mut c_void,
+    flags: libc::copyfile_flags_t,
+) -> c_int {
+    unsafe { libc::copyfile(from, to, state, flags) }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_removefile(path: *const c_char, state: *mut c_void, flags: u32) -> c_int {
+    unsafe { removefile(path, state, flags) }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_acl_set_file(path: *const c_char, ty: c_int, acl: *mut c_void) -> c_int {
+    unsafe { acl_set_file(path, ty, acl) }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_acl_set_link(path: *const c_char, ty: c_int, acl: *mut c_void) -> c_int {
+    unsafe { acl_set_link_np(path, ty, acl) }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_acl_set_fd(fd: c_int, acl: *mut c_void) -> c_int {
+    unsafe { acl_set_fd(fd, acl) }
+}
+
+/// `acl` as `acl_to_text` renders it.
+pub(crate) unsafe fn acl_text(acl: *mut c_void) -> Option<String> {
+    let text = unsafe { acl_to_text(acl, std::ptr::null_mut()) };
+    if text.is_null() {
+        return None;
+    }
+    let out = unsafe { std::ffi::CStr::from_ptr(text) }
+        .to_string_lossy()
+        .into_owned();
+    unsafe { acl_free(text as *mut c_void) };
+    Some(out)
+}
+
+#[inline]
+pub(crate) unsafe fn sys_renameatx(
+    olddirfd: c_int,
+    old: *const c_char,
+    newdirfd: c_int,
+    new: *const c_char,
+    flags: libc::c_uint,
+) -> c_int {
+    unsafe { libc::renameatx_np(olddirfd, old, newdirfd, new, flags) }
+}
+
+/// Write straight to stderr without passing through our own `write` hook.
+pub(crate) fn stderr_write(msg: &[u8]) {
+    unsafe {
+        let _ = sys_write(
+            libc::STDERR_FILENO,
+            msg.as_ptr() as *const c_void,
+            msg.len(),
+        );
+    }
+}
+
+//
+// -------- Paths + errno --------
+//
+
+pub(crate) fn fd_path(fd: RawFd) -> Option<PathBuf> {
+    unsafe {
+        let mut buf = [0u8; libc::PATH_MAX as usize];
+        let rc = libc::fcntl(fd, F_GETPATH, buf.as_mut_ptr() as *mut c_void);
+        if rc == -1 {
+            return None;
+        }
+        let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+        let slice = &buf[..len];
+        Some(PathBuf::from(OsStr::from_bytes(slice)))
+    }
+}
+
+/// The volume's `f_fstypename` (`apfs`, `hfs`, `smbfs`, `nfs`, `msdos`, ...).
+pub(crate) fn fd_fs_type(fd: RawFd) -> Option<String> {
+    unsafe {
+        let mut sfs: libc::statfs = std::mem::zeroed();
+        if libc::fstatfs(fd, &mut sfs) != 0 {
+            return None;
+        }
+        let name = CStr::from_ptr(sfs.f_fstypename.as_ptr());
+        Some(name.to_string_lossy().into_owned())
+    }
+}
+
+/// Effective uid of the process on the other end of a Unix socket
+/// (`LOCAL_PEERCRED` underneath).
+pub(crate) fn peer_uid(fd: RawFd) -> Option<libc::uid_t> {
+    let (mut uid, mut gid) = (0, 0);
+    (unsafe { libc::getpeereid(fd, &mut uid, &mut gid) } == 0).then_some(uid)
+}
+
+/// `argv[0]`, from `_NSGetArgv`.
+pub(crate) fn argv0() -> Option<String> {
+    extern "C" {
+        fn _NSGetArgv() -> *mut *mut *mut c_char;
+    }
+    unsafe {
+        let argv = *_NSGetArgv();
+        if argv.is_null() || (*argv).is_null() {
+            return None;
+        }
+        Some(CStr::from_ptr(*argv).to_string_lossy().into_owned())
+    }
+}
+
+/// The system-wide id of the calling thread, as Instruments and `lldb`
+/// show it.
+pub(crate) fn thread_id() -> u64 {
+    let mut id = 0u64;
+    unsafe { libc::pthread_threadid_np(libc::pthread_self(), &mut id) };
+    id
+}
+
+/// Monotonic milliseconds by the clock as of the last context switch,
+/// which the commpage keeps without a syscall.
+pub(crate) fn coarse_ms() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC_RAW_APPROX, &mut ts) };
+    ts.tv_sec as u64 * 1000 + ts.tv_nsec as u64 / 1_000_000
+}
+
+/// The label of the dispatch queue the calling thread is serving, if any.
+pub(crate) fn queue_label() -> Option<String> {
+    extern "C" {
+        fn dispatch_queue_get_label(queue: *const c_void) -> *const c_char;
+    }
+    // A null queue is `DISPATCH_CURRENT_QUEUE_LABEL`.
+    let label = unsafe { dispatch_queue_get_label(std::ptr::null()) };
+    if label.is_null() {
+        return None;
+    }
+    let label = unsafe { CStr::from_ptr(label) };
+    Some(label.to_string_lossy().into_owned())
+}
+
+#[inline]
+pub(crate) fn set_errno(e: c_int) {
+    // macOS: __error() -> *mut c_int
+    unsafe {
+        *libc::__error() = e;
+    }
+}
+
+//
+// -------- dyld interpose glue --------
+//
+
+#[cfg(not(test))]
+mod interpose {
+    use super::*;
+    use crate::{
+        handle_acl_set_fd, handle_acl_set_file, handle_acl_set_link, handle_chdir, handle_close,
+        handle_copyfile, handle_execve, handle_exit, handle_fchdir, handle_fchflags, handle_fchmod,
+        handle_fcntl, handle_fflush, handle_flock, handle_ftruncate, handle_futimens,
+        handle_futimes, handle_mkfifo, handle_mkfifoat, handle_mknod, handle_open,
+        handle_posix_spawn, handle_posix_spawnp, handle_pwrite, handle_removefile, handle_rename,
+        handle_renameatx, handle_setegid, handle_seteuid, handle_setgid, handle_setuid,
+        handle_shm_open, handle_shm_unlink, handle_truncate, handle_uexit, handle_unlink,
+        handle_utimensat, handle_utimes, handle_write, handle_writev, AclSetFdFn, AclSetFileFn,
+        ChdirFn, CloseFn, CopyfileFn, ExecveFn, ExitFn, FchdirFn, FchflagsFn, FchmodFn, FcntlFn,
+        FflushFn, FlockFn, FtruncateFn, FutimensFn, FutimesFn, MkfifoFn, MkfifoatFn, MknodFn,
+        PosixSpawnFn, PwriteFn, RemovefileFn, RenameFn, RenameatxFn, RenamexFn, SetgidFn, SetuidFn,
+        TruncateFn, UnlinkFn, UtimensatFn, UtimesFn, WriteFn, WritevFn,
+    };
+    use std::os::raw::c_uint;
+
+    #[repr(C)]
+    struct InterposePair<T, O = T> {
+        replacement: T,
+        original: O,
+    }
+    macro_rules! register_interpose {
+        ($name:ident, $replacement:expr, $original:expr, $ty:ty) => {
+            register_interpose!($name, $replacement, $original, $ty, $ty);
+        };
+        ($name:ident, $replacement:expr, $original:expr, $rty:ty, $oty:ty) => {
+            #[used]
+            #[link_section = "__DATA,__interpose"]
+            static $name: InterposePair<$rty, $oty> = InterposePair {
+                replacement: $replacement,
+                original: $original,
+            };
+        };
+    }
+
+    type OpenFn = unsafe extern "C" fn(*const c_char, c_int, ...) -> c_int;
+    type OpenatFn = unsafe extern "C" fn(c_int, *const c_char, c_int, ...) -> c_int;
+
+    // Stable Rust can't define C-variadic functions, so each open replacement
+    // declares the mode as a fixed parameter where the ABI puts the first
+    // variadic int, and only reads it when `O_CREAT` says the caller passed
+    // one (otherwise that slot holds garbage):
+    //  - x86_64 passes variadic ints in the same registers as named ones, so
+    //    the mode is just the next argument.
+    //  - arm64 passes every variadic argument on the stack, so the mode sits
+    //    behind padding for the unused argument registers, in the first
+    //    stack slot.
+    #[cfg(target_arch = "x86_64")]
+    type OpenShimFn = unsafe extern "C" fn(*const c_char, c_int, c_uint) -> c_int;
+    #[cfg(target_arch = "x86_64")]
+    type OpenatShimFn = unsafe extern "C" fn(c_int, *const c_char, c_int, c_uint) -> c_int;
+    #[cfg(target_arch = "aarch64")]
+    type OpenShimFn = unsafe extern "C" fn(
+        *const c_char,
+        c_int,
+        usize,
+        usize,
+        usize,
+        usize,
+        usize,
+        usize,
+        c_uint,
+    ) -> c_int;
+    #[cfg(target_arch = "aarch64")]
+    type OpenatShimFn = unsafe extern "C" fn(
+        c_int,
+        *const c_char,
+        c_int,
+        usize,
+        usize,
+        usize,
+        usize,
+        usize,
+        c_uint,
+    ) -> c_int;
+
+    macro_rules! open_shim {
+        ($name:ident) => {
+            #[cfg(target_arch = "x86_64")]
+            unsafe extern "C" fn $name(path: *const c_char, flags: c_int, mode: c_uint) -> c_int {
+                let mode = open_needs_mode(flags).then_some(mode as libc::mode_t);
+                unsafe { handle_open(None, path, flags, mode) }
+            }
+            #[cfg(target_arch = "aarch64")]
+            #[allow(clippy::too_many_arguments)]
+            unsafe extern "C" fn $name(
+                path: *const c_char,
+                flags: c_int,
+                _x2: usize,
+                _x3: usize,
+                _x4: usize,
+                _x5: usize,
+                _x6: usize,
+                _x7: usize,
+                mode: c_uint,
+            ) -> c_int {
+                let mode = open_needs_mode(flags).then_some(mode as libc::mode_t);
+                unsafe { handle_open(None, path, flags, mode) }
+            }
+        };
+    }
+
+    macro_rules! openat_shim {
+        ($name:ident) => {
+            #[cfg(target_arch = "x86_64")]
+            unsafe extern "C" fn $name(
+                dirfd: c_int,
+                path: *const c_char,
+                flags: c_int,
+                mode: c_uint,
+            ) -> c_int {
+                let mode = open_needs_mode(flags).then_some(mode as libc::mode_t);
+                unsafe { handle_open(Some(dirfd), path, flags, mode) }
+            }
+            #[cfg(target_arch = "aarch64")]
+            #[allow(clippy::too_many_arguments)]
+            unsafe extern "C" fn $name(
+                dirfd: c_int,
+                path: *const c_char,
+                flags: c_int,
+                _x3: usize,
+                _x4: usize,
+                _x5: usize,
+                _x6: usize,
+                _x7: usize,
+                mode: c_uint,
+            ) -> c_int {
+                let mode = open_needs_mode(flags).then_some(mode as libc::mode_t);
+                unsafe { handle_open(Some(dirfd), path, flags, mode) }
+            }
+        };
+    }
+
+    // dyld binds the interpose table when the library loads, and stable
+    // Rust can't weak-import, so a variant libSystem doesn't export would
+    // fail the load outright. Each one is listed only for the architectures
+    // whose libSystem has it (the `$UNIX2003` aliases are x86_64-only).
+    extern "C" {
+        fn open(path: *const c_char, flags: c_int, ...) -> c_int;
+        #[link_name = "open$NOCANCEL"]
+        fn open_nocancel_symbol(path: *const c_char, flags: c_int, ...) -> c_int;
+        #[cfg(target_arch = "x86_64")]
+        #[link_name = "open$UNIX2003"]
+        fn open_unix2003_symbol(path: *const c_char, flags: c_int, ...) -> c_int;
+        fn openat(dirfd: c_int, path: *const c_char, flags: c_int, ...) -> c_int;
+        #[link_name = "openat$NOCANCEL"]
+        fn openat_nocancel_symbol(dirfd: c_int, path: *const c_char, flags: c_int, ...) -> c_int;
+
+        fn write(fd: c_int, buf: *const c_void, count: libc::size_t) -> libc::ssize_t;
+        #[link_name = "write$NOCANCEL"]
+        fn write_nocancel_symbol(
+            fd: c_int,
+            buf: *const c_void,
+            count: libc::size_t,
+        ) -> libc::ssize_t;
+        fn pwrite(
+            fd: c_int,
+            buf: *const c_void,
+            count: libc::size_t,
+            offset: libc::off_t,
+        ) -> libc::ssize_t;
+        #[link_name = "pwrite$NOCANCEL"]
+        fn pwrite_nocancel_symbol(
+            fd: c_int,
+            buf: *const c_void,
+            count: libc::size_t,
+            offset: libc::off_t,
+        ) -> libc::ssize_t;
+        fn writev(fd: c_int, iov: *const libc::iovec, iovcnt: c_int) -> libc::ssize_t;
+        #[link_name = "writev$NOCANCEL"]
+        fn writev_nocancel_symbol(
+            fd: c_int,
+            iov: *const libc::iovec,
+            iovcnt: c_int,
+        ) -> libc::ssize_t;
+
+        fn close(fd: c_int) -> c_int;
+        #[link_name = "close$NOCANCEL"]
+        fn close_nocancel_symbol(fd: c_int) -> c_int;
+
+        fn unlink(path: *const c_char) -> c_int;
+
+        fn rename(old: *const c_char, new: *const c_char) -> c_int;
+        #[cfg(not(target_arch = "aarch64"))]
+        #[link_name = "rename$UNIX2003"]
+        fn rename_unix2003_symbol(old: *const c_char, new: *const c_char) -> c_int;
+
+        #[cfg(not(target_arch = "aarch64"))]
+        #[link_name = "unlink$NOCANCEL"]
+        fn unlink_nocancel_symbol(path: *const c_char) -> c_int;
+
+        fn ftruncate(fd: c_int, length: libc::off_t) -> c_int;
+        fn truncate(path: *const c_char, length: libc::off_t) -> c_int;
+
+        fn chdir(path: *const c_char) -> c_int;
+        fn fchdir(fd: c_int) -> c_int;
+
+        fn fflush(stream: *mut libc::FILE) -> c_int;
+
+        fn mkfifo(path: *const c_char, mode: libc::mode_t) -> c_int;
+        fn mkfifoat(dirfd: c_int, path: *const c_char, mode: libc::mode_t) -> c_int;
+        fn mknod(path: *const c_char, mode: libc::mode_t, dev: libc::dev_t) -> c_int;
+
+        fn copyfile(
+            from: *const c_char,
+            to: *const c_char,
+            state: *mut c_void,
+            flags: libc::copyfile_flags_t,
+        ) -> c_int;
+        fn removefile(path: *const c_char, state: *mut c_void, flags: u32) -> c_int;
+        fn renamex_np(old: *const c_char, new: *const c_char, flags: c_uint) -> c_int;
+        fn renameatx_np(
+            olddirfd: c_int,
+            old: *const c_char,
+            newdirfd: c_int,
+            new: *const c_char,
+            flags: c_uint,
+        ) -> c_int;
+    }
+
+    open_shim!(shim_open);
+    register_interpose!(
+        INTERPOSE_OPEN,
+        shim_open,
+        open as OpenFn,
+        OpenShimFn,
+        OpenFn
+    );
+
+    open_shim!(shim_open_nocancel);
+    register_interpose!(
+        INTERPOSE_OPEN_NC,
+        shim_open_nocancel,
+        open_nocancel_symbol as OpenFn,
+        OpenShimFn,
+        OpenFn
+    );
+
+    #[cfg(target_arch = "x86_64")]
+    open_shim!(shim_open_unix2003);
+    #[cfg(target_arch = "x86_64")]
+    register_interpose!(
+        INTERPOSE_OPEN_U2003,
+        shim_open_unix2003,
+        open_unix2003_symbol as OpenFn,
+        OpenShimFn,
+        OpenFn
+    );
+
+    openat_shim!(shim_openat);
+    register_interpose!(
+        INTERPOSE_OPENAT,
+        shim_openat,
+        openat as OpenatFn,
+        OpenatShimFn,
+        OpenatFn
+    );
+
+    openat_shim!(shim_openat_nocancel);
+    register_interpose!(
+        INTERPOSE_OPENAT_NC,
+        shim_openat_nocancel,
+        openat_nocancel_symbol as OpenatFn,
+        OpenatShimFn,
+        OpenatFn
+    );
+
+    unsafe extern "C" fn shim_write(
+        fd: c_int,
+        buf: *const c_void,
+        count: libc::size_t,
+    ) -> libc::ssize_t {
+        unsafe { handle_write(fd, buf, count) }
+    }
+    register_interpose!(INTERPOSE_WRITE, shim_write, write as WriteFn, WriteFn);
+
+    unsafe extern "C" fn shim_write_nocancel(
+        fd: c_int,
+        buf: *const c_void,
+        count: libc::size_t,
+    ) -> libc::ssize_t {
+        unsafe { handle_write(fd, buf, count) }
+    }
+    register_interpose!(
+        INTERPOSE_WRITE_NC,
+        shim_write_nocancel,
+        write_nocancel_symbol as WriteFn,
+        WriteFn
+    );
+
+    unsafe extern "C" fn shim_pwrite(
+        fd: c_int,
+        buf: *const c_void,
+        count: libc::size_t,
+        offset: libc::off_t,
+    ) -> libc::ssize_t {
+        unsafe { handle_pwrite(fd, buf, count, offset) }
+    }
+    register_interpose!(INTERPOSE_PWRITE, shim_pwrite, pwrite as PwriteFn, PwriteFn);
+
+    unsafe extern "C" fn shim_pwrite_nocancel(
+        fd: c_int,
+        buf: *const c_void,
+        count: libc::size_t,
+        offset: libc::off_t,
+    ) -> libc::ssize_t {
+        unsafe { handle_pwrite(fd, buf, count, offset) }
+    }
+    register_interpose!(
+        INTERPOSE_PWRITE_NC,
+        shim_pwrite_nocancel,
+        pwrite_nocancel_symbol as PwriteFn,
+        PwriteFn
+    );
+
+    unsafe extern "C" fn shim_writev(
+        fd: c_int,
+        iov: *const libc::iovec,
+        iovcnt: c_int,
+    ) -> libc::ssize_t {
+        unsafe { handle_writev(fd, iov, iovcnt) }
+    }
+    register_interpose!(INTERPOSE_WRITEV, shim_writev, writev as WritevFn, WritevFn);
+
+    unsafe extern "C" fn shim_writev_nocancel(
+        fd: c_int,
+        iov: *const libc::iovec,
+        iovcnt: c_int,
+    ) -> libc::ssize_t {
+        unsafe { handle_writev(fd, iov, iovcnt) }
+    }
+    register_interpose!(
+        INTERPOSE_WRITEV_NC,
+        shim_writev_nocancel,
+        writev_nocancel_symbol as WritevFn,
+        WritevFn
+    );
+
+    unsafe extern "C" fn shim_close(fd: c_int) -> c_int {
+        unsafe { handle_close(fd) }
+    }
+    register_interpose!(INTERPOSE_CLOSE, shim_close, close as CloseFn, CloseFn);
+
+    unsafe extern "C" fn shim_close_nocancel(fd: c_int) -> c_int {
+        unsafe { handle_close(fd) }
+    }
+    register_interpose!(
+        INTERPOSE_CLOSE_NC,
+        shim_close_nocancel,
+        close_nocancel_symbol as CloseFn,
+        CloseFn
+    );
+
+    unsafe extern "C" fn shim_unlink(path: *const c_char) -> c_int {
+        unsafe { handle_unlink(path) }
+    }
+    register_interpose!(INTERPOSE_UNLINK, shim_unlink, unlink as UnlinkFn, UnlinkFn);
+
+    #[cfg(not(target_arch = "aarch64"))]
+    unsafe extern "C" fn shim_unlink_nocancel(path: *const c_char) -> c_int {
+        unsafe { handle_unlink(path) }
+    }
+    #[cfg(not(target_arch = "aarch64"))]
+    register_interpose!(
+        INTERPOSE_UNLINK_NC,
+        shim_unlink_nocancel,
+        unlink_nocancel_symbol as UnlinkFn,
+        UnlinkFn
+    );
+
+    unsafe extern "C" fn shim_rename(old: *const c_char, new: *const c_char) -> c_int {
+        unsafe { handle_rename(old, new) }
+    }
+    register_interpose!(INTERPOSE_RENAME, shim_rename, rename as RenameFn, RenameFn);
+
+    #[cfg(not(target_arch = "aarch64"))]
+    unsafe extern "C" fn shim_rename_unix2003(old: *const c_char, new: *const c_char) -> c_int {
+        unsafe { handle_rename(old, new) }
+    }
+    #[cfg(not(target_arch = "aarch64"))]
+    register_interpose!(
+        INTERPOSE_RENAME_U2003,
+        shim_rename_unix2003,
+        rename_unix2003_symbol as RenameFn,
+        RenameFn
+    );
+
+    unsafe extern "C" fn shim_ftruncate(fd: c_int, length: libc::off_t) -> c_int {
+        unsafe { handle_ftruncate(fd, length) }
+    }
+    register_interpose!(
+        INTERPOSE_FTRUNCATE,
+        shim_ftruncate,
+        ftruncate as FtruncateFn,
+        FtruncateFn
+    );
+
+    unsafe extern "C" fn shim_truncate(path: *const c_char, length: libc::off_t) -> c_int {
+        unsafe { handle_truncate(path, length) }
+    }
+    register_interpose!(
+        INTERPOSE_TRUNCATE,
+        shim_truncate,
+        truncate as TruncateFn,
+        TruncateFn
+    );
+
+    unsafe extern "C" fn shim_chdir(path: *const c_char) -> c_int {
+        unsafe { handle_chdir(path) }
+    }
+    register_interpose!(INTERPOSE_CHDIR, shim_chdir, chdir as ChdirFn, ChdirFn);
+
+    unsafe extern "C" fn shim_fchdir(fd: c_int) -> c_int {
+        unsafe { handle_fchdir(fd) }
+    }
+    register_interpose!(INTERPOSE_FCHDIR, shim_fchdir, fchdir as FchdirFn, FchdirFn);
+
+    unsafe extern "C" fn shim_fflush(stream: *mut libc::FILE) -> c_int {
+        unsafe { handle_fflush(stream) }
+    }
+    register_interpose!(INTERPOSE_FFLUSH, shim_fflush, fflush as FflushFn, FflushFn);
+
+    unsafe extern "C" fn shim_mkfifo(path: *const c_char, mode: libc::mode_t) -> c_int {
+        unsafe { handle_mkfifo(path, mode) }
+    }
+    register_interpose!(INTERPOSE_MKFIFO, shim_mkfifo, mkfifo as MkfifoFn, MkfifoFn);
+
+    unsafe extern "C" fn shim_mkfifoat(
+        dirfd: c_int,
+        path: *const c_char,
+        mode: libc::mode_t,
+    ) -> c_int {
+        unsafe { handle_mkfifoat(dirfd, path, mode) }
+    }
+    register_interpose!(
+        INTERPOSE_MKFIFOAT,
+        shim_mkfifoat,
+        mkfifoat as MkfifoatFn,
+        MkfifoatFn
+    );
+
+    unsafe extern "C" fn shim_mknod(
+        path: *const c_char,
+        mode: libc::mode_t,
+        dev: libc::dev_t,
+    ) -> c_int {
+        unsafe { handle_mknod(path, mode, dev) }
+    }
+    register_interpose!(INTERPOSE_MKNOD, shim_mknod, mknod as MknodFn, MknodFn);
+
+    unsafe extern "C" fn shim_acl_set_file(
+        path: *const c_char,
+        ty: c_int,
+        acl: *mut c_void,
+    ) -> c_int {
+        unsafe { handle_acl_set_file(path, ty, acl) }
+    }
+    register_interpose!(
+        INTERPOSE_ACL_SET_FILE,
+        shim_acl_set_file,
+        acl_set_file as AclSetFileFn,
+        AclSetFileFn
+    );
+
+    unsafe extern "C" fn shim_acl_set_link(
+        path: *const c_char,
+        ty: c_int,
+        acl: *mut c_void,
+    ) -> c_int {
+        unsafe { handle_acl_set_link(path, ty, acl) }
+    }
+    register_interpose!(
+        INTERPOSE_ACL_SET_LINK,
+        shim_acl_set_link,
+        acl_set_link_np as AclSetFileFn,
+        AclSetFileFn
+    );
+
+    unsafe extern "C" fn shim_acl_set_fd(fd: c_int, acl: *mut c_void) -> c_int {
+        unsafe { handle_acl_set_fd(fd, acl) }
+    }
+    register_interpose!(
+        INTERPOSE_ACL_SET_FD,
+        shim_acl_set_fd,
+        acl_set_fd as AclSetFdFn,
+        AclSetFdFn
+    );
+
+    #[cfg(target_arch = "x86_64")]
+    unsafe extern "C" fn shim_shm_open(name: *const c_char, oflag: c_int, mode: c_uint) -> c_int {
+        let mode = if open_needs_mode(oflag) { mode } else { 0 };
+        unsafe { handle_shm_open(name, oflag, mode as libc::mode_t) }
+    }
+    #[cfg(target_arch = "aarch64")]
+    #[allow(clippy::too_many_arguments)]
+    unsafe extern "C" fn shim_shm_open(
+        name: *const c_char,
+        oflag: c_int,
+        _x2: usize,
+        _x3: usize,
+        _x4: usize,
+        _x5: usize,
+        _x6: usize,
+        _x7: usize,
+        mode: c_uint,
+    ) -> c_int {
+        let mode = if open_needs_mode(oflag) { mode } else { 0 };
+        unsafe { handle_shm_open(name, oflag, mode as libc::mode_t) }
+    }
+    register_interpose!(
+        INTERPOSE_SHM_OPEN,
+        shim_shm_open,
+        libc::shm_open as OpenFn,
+        OpenShimFn,
+        OpenFn
+    );
+
+    unsafe extern "C" fn shim_shm_unlink(name: *const c_char) -> c_int {
+        unsafe { handle_shm_unlink(name) }
+    }
+    register_interpose!(
+        INTERPOSE_SHM_UNLINK,
+        shim_shm_unlink,
+        libc::shm_unlink as UnlinkFn,
+        UnlinkFn
+    );
+
+    unsafe extern "C" fn shim_fchmod(fd: c_int, mode: libc::mode_t) -> c_int {
+        unsafe { handle_fchmod(fd, mode) }
+    }
+    register_interpose!(
+        INTERPOSE_FCHMOD,
+        shim_fchmod,
+        libc::fchmod as FchmodFn,
+        FchmodFn
+    );
+
+    unsafe extern "C" fn shim_futimens(fd: c_int, times: *const libc::timespec) -> c_int {
+        unsafe { handle_futimens(fd, times) }
+    }
+    register_interpose!(
+        INTERPOSE_FUTIMENS,
+        shim_futimens,
+        libc::futimens as FutimensFn,
+        FutimensFn
+    );
+
+    unsafe extern "C" fn shim_futimes(fd: c_int, times: *const libc::timeval) -> c_int {
+        unsafe { handle_futimes(fd, times) }
+    }
+    register_interpose!(
+        INTERPOSE_FUTIMES,
+        shim_futimes,
+        libc::futimes as FutimesFn,
+        FutimesFn
+    );
+
+    unsafe extern "C" fn shim_utimensat(
+        dirfd: c_int,
+        path: *const c_char,
+        times: *const libc::timespec,
+        flags: c_int,
+    ) -> c_int {
+        unsafe { handle_utimensat(dirfd, path, times, flags) }
+    }
+    register_interpose!(
+        INTERPOSE_UTIMENSAT,
+        shim_utimensat,
+        libc::utimensat as UtimensatFn,
+        UtimensatFn
+    );
+
+    unsafe extern "C" fn shim_utimes(path: *const c_char, times: *const libc::timeval) -> c_int {
+        unsafe { handle_utimes(path, times) }
+    }
+    register_interpose!(
+        INTERPOSE_UTIMES,
+        shim_utimes,
+        libc::utimes as UtimesFn,
+        UtimesFn
+    );
+
+    unsafe extern "C" fn shim_fchflags(fd: c_int, flags: c_uint) -> c_int {
+        unsafe { handle_fchflags(fd, flags) }
+    }
+    register_interpose!(
+        INTERPOSE_FCHFLAGS,
+        shim_fchflags,
+        libc::fchflags as FchflagsFn,
+        FchflagsFn
+    );
+
+    unsafe extern "C" fn shim_setuid(uid: libc::uid_t) -> c_int {
+        unsafe { handle_setuid(uid) }
+    }
+    register_interpose!(
+        INTERPOSE_SETUID,
+        shim_setuid,
+        libc::setuid as SetuidFn,
+        SetuidFn
+    );
+
+    unsafe extern "C" fn shim_seteuid(uid: libc::uid_t) -> c_int {
+        unsafe { handle_seteuid(uid) }
+    }
+    register_interpose!(
+        INTERPOSE_SETEUID,
+        shim_seteuid,
+        libc::seteuid as SetuidFn,
+        SetuidFn
+    );
+
+    unsafe extern "C" fn shim_setgid(gid: libc::gid_t) -> c_int {
+        unsafe { handle_setgid(gid) }
+    }
+    register_interpose!(
+        INTERPOSE_SETGID,
+        shim_setgid,
+        libc::setgid as SetgidFn,
+        SetgidFn
+    );
+
+    unsafe extern "C" fn shim_setegid(gid: libc::gid_t) -> c_int {
+        unsafe { handle_setegid(gid) }
+    }
+    register_interpose!(
+        INTERPOSE_SETEGID,
+        shim_setegid,
+        libc::setegid as SetgidFn,
+        SetgidFn
+    );
+
+    unsafe extern "C" fn shim_execve(
+        path: *const c_char,
+        argv: *const *const c_char,
+        envp: *const *const c_char,
+    ) -> c_int {
+        unsafe { handle_execve(path, argv, envp) }
+    }
+    register_interpose!(
+        INTERPOSE_EXECVE,
+        shim_execve,
+        libc::execve as ExecveFn,
+        ExecveFn
+    );
+
+    unsafe extern "C" fn shim_posix_spawn(
+        pid: *mut libc::pid_t,
+        path: *const c_char,
+        actions: *const libc::posix_spawn_file_actions_t,
+        attr: *const libc::posix_spawnattr_t,
+        argv: *const *mut c_char,
+        envp: *const *mut c_char,
+    ) -> c_int {
+        unsafe { handle_posix_spawn(pid, path, actions, attr, argv, envp) }
+    }
+    register_interpose!(
+        INTERPOSE_POSIX_SPAWN,
+        shim_posix_spawn,
+        libc::posix_spawn as PosixSpawnFn,
+        PosixSpawnFn
+    );
+
+    unsafe extern "C" fn shim_posix_spawnp(
+        pid: *mut libc::pid_t,
+        file: *const c_char,
+        actions: *const libc::posix_spawn_file_actions_t,
+        attr: *const libc::posix_spawnattr_t,
+        argv: *const *mut c_char,
+        envp: *const *mut c_char,
+    ) -> c_int {
+        unsafe { handle_posix_spawnp(pid, file, actions, attr, argv, envp) }
+    }
+    register_interpose!(
+        INTERPOSE_POSIX_SPAWNP,
+        shim_posix_spawnp,
+        libc::posix_spawnp as PosixSpawnFn,
+        PosixSpawnFn
+    );
+
+    unsafe extern "C" fn shim_flock(fd: c_int, op: c_int) -> c_int {
+        unsafe { handle_flock(fd, op) }
+    }
+    register_interpose!(INTERPOSE_FLOCK, shim_flock, libc::flock as FlockFn, FlockFn);
+
+    // `fcntl`'s third argument is variadic, placed as `open`'s mode is.
+    #[cfg(target_arch = "x86_64")]
+    type FcntlShimFn = unsafe extern "C" fn(c_int, c_int, usize) -> c_int;
+    #[cfg(target_arch = "aarch64")]
+    type FcntlShimFn = unsafe extern "C" fn(
+        c_int,
+        c_int,
+        usize,
+        usize,
+        usize,
+        usize,
+        usize,
+        usize,
+        usize,
+    ) -> c_int;
+
+    #[cfg(target_arch = "x86_64")]
+    unsafe extern "C" fn shim_fcntl(fd: c_int, cmd: c_int, arg: usize) -> c_int {
+        unsafe { handle_fcntl(fd, cmd, arg) }
+    }
+    #[cfg(target_arch = "aarch64")]
+    #[allow(clippy::too_many_arguments)]
+    unsafe extern "C" fn shim_fcntl(
+        fd: c_int,
+        cmd: c_int,
+        _x2: usize,
+        _x3: usize,
+        _x4: usize,
+        _x5: usize,
+        _x6: usize,
+        _x7: usize,
+        arg: usize,
+    ) -> c_int {
+        unsafe { handle_fcntl(fd, cmd, arg) }
+    }
+    register_interpose!(
+        INTERPOSE_FCNTL,
+        shim_fcntl,
+        libc::fcntl as FcntlFn,
+        FcntlShimFn,
+        FcntlFn
+    );
+
+    extern "C" fn shim_exit(status: c_int) -> ! {
+        handle_exit(status)
+    }
+    register_interpose!(INTERPOSE_EXIT, shim_exit, libc::exit as ExitFn, ExitFn);
+
+    extern "C" fn shim_uexit(status: c_int) -> ! {
+        handle_uexit(status)
+    }
+    register_interpose!(INTERPOSE_UEXIT, shim_uexit, libc::_exit as ExitFn, ExitFn);
+
+    unsafe extern "C" fn shim_copyfile(
+        from: *const c_char,
+        to: *const c_char,
+        state: *mut c_void,
+        flags: libc::copyfile_flags_t,
+    ) -> c_int {
+        unsafe { handle_copyfile(from, to, state, flags) }
+    }
+    register_interpose!(
+        INTERPOSE_COPYFILE,
+        shim_copyfile,
+        copyfile as CopyfileFn,
+        CopyfileFn
+    );
+
+    unsafe extern "C" fn shim_removefile(
+        path: *const c_char,
+        state: *mut c_void,
+        flags: u32,
+    ) -> c_int {
+        unsafe { handle_removefile(path, state, flags) }
+    }
+    register_interpose!(
+        INTERPOSE_REMOVEFILE,
+        shim_removefile,
+        removefile as RemovefileFn,
+        RemovefileFn
+    );
+
+    unsafe extern "C" fn shim_renamex_np(
+        old: *const c_char,
+        new: *const c_char,
+        flags: c_uint,
+    ) -> c_int {
+        unsafe { handle_renameatx(libc::AT_FDCWD, old, libc::AT_FDCWD, new, flags) }
+    }
+    register_interpose!(
+        INTERPOSE_RENAMEX,
+        shim_renamex_np,
+        renamex_np as RenamexFn,
+        RenamexFn
+    );
+
+    unsafe extern "C" fn shim_renameatx_np(
+        olddirfd: c_int,
+        old: *const c_char,
+        newdirfd: c_int,
+        new: *const c_char,
+        flags: c_uint,
+    ) -> c_int {
+        unsafe { handle_renameatx(olddirfd, old, newdirfd, new, flags) }
+    }
+    register_interpose!(
+        INTERPOSE_RENAMEATX,
+        shim_renameatx_np,
+        renameatx_np as RenameatxFn,
+        RenameatxFn
+    );
+}
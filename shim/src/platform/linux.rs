@@ -0,0 +1,1237 @@
+//! Linux: `LD_PRELOAD` symbol overriding. We export strong definitions of the
+//! libc entry points and forward to the originals resolved via
+//! `dlsym(RTLD_NEXT)`; paths come from `/proc/self/fd/N`.
+
+use std::ffi::{CStr, CString, OsStr};
+use std::os::raw::{c_char, c_int, c_uint, c_void};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::prelude::RawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+
+use crate::{
+    declare_symbol, AclSetFdFn, AclSetFileFn, ChdirFn, CloseFn, ExecvFn, ExecveFn, ExitFn,
+    FchdirFn, FchmodFn, FcntlFn, FflushFn, FlockFn, FtruncateFn, FutimensFn, FutimesFn, LockfFn,
+    MkfifoFn, MkfifoatFn, MknodFn, OpenFn, OpenatFn, PosixSpawnFn, PwriteFn, PwritevFn, RenameFn,
+    Renameat2Fn, RenameatFn, SetgidFn, SetuidFn, ShmOpenFn, TruncateFn, UnlinkFn, UnlinkatFn,
+    UtimensatFn, UtimesFn, WritevFn,
+};
+
+//
+// -------- Library init --------
+//
+
+unsafe extern "C" fn shim_library_init() {
+    crate::handshake::announce();
+    if crate::bypass::decide() {
+        return;
+    }
+    crate::lineage::capture();
+    crate::adopt_inherited_fd_from_env();
+    crate::exec_fds::adopt();
+    crate::procinfo::capture_cwd();
+    crate::procinfo::capture_umask();
+    crate::contain::install_panic_hook();
+    crate::contain::disable_configured();
+    crate::shutdown::register();
+    crate::SHIM_READY.store(true, Ordering::SeqCst);
+    crate::verify::run();
+}
+
+#[cfg(not(test))]
+#[link_section = ".init_array"]
+#[used]
+static SHIM_INIT_HOOK: unsafe extern "C" fn() = shim_library_init;
+
+//
+// -------- Originals (RTLD_NEXT) --------
+//
+// A libc without one of these (renameat2 before glibc 2.28, a static or
+// unusual libc) gets the bare syscall in its place.
+
+declare_symbol!(real_open64, "open64", OpenFn);
+declare_symbol!(real_openat64, "openat64", OpenatFn);
+declare_symbol!(real_pwrite64, "pwrite64", PwriteFn);
+declare_symbol!(real_writev, "writev", WritevFn);
+declare_symbol!(real_pwritev64, "pwritev64", PwritevFn);
+declare_symbol!(real_close, "close", CloseFn);
+declare_symbol!(real_unlink, "unlink", UnlinkFn);
+declare_symbol!(real_unlinkat, "unlinkat", UnlinkatFn);
+declare_symbol!(real_rename, "rename", RenameFn);
+declare_symbol!(real_renameat, "renameat", RenameatFn);
+declare_symbol!(real_renameat2, "renameat2", Renameat2Fn);
+declare_symbol!(real_truncate, "truncate", TruncateFn);
+declare_symbol!(real_ftruncate, "ftruncate", FtruncateFn);
+declare_symbol!(real_chdir, "chdir", ChdirFn);
+declare_symbol!(real_fchdir, "fchdir", FchdirFn);
+declare_symbol!(real_fflush, "fflush", FflushFn);
+declare_symbol!(real_mkfifo, "mkfifo", MkfifoFn);
+declare_symbol!(real_mkfifoat, "mkfifoat", MkfifoatFn);
+declare_symbol!(real_mknod, "mknod", MknodFn);
+declare_symbol!(real_shm_open, "shm_open", ShmOpenFn);
+declare_symbol!(real_shm_unlink, "shm_unlink", UnlinkFn);
+declare_symbol!(real_fchmod, "fchmod", FchmodFn);
+declare_symbol!(real_futimens, "futimens", FutimensFn);
+declare_symbol!(real_futimes, "futimes", FutimesFn);
+declare_symbol!(real_utimensat, "utimensat", UtimensatFn);
+declare_symbol!(real_utimes, "utimes", UtimesFn);
+declare_symbol!(real_flock, "flock", FlockFn);
+// `fcntl64` is the same call on the 64-bit ABIs the shim builds for, and
+// older libcs lack it.
+declare_symbol!(real_fcntl, "fcntl", FcntlFn);
+declare_symbol!(real_lockf, "lockf", LockfFn);
+declare_symbol!(real_setuid, "setuid", SetuidFn);
+declare_symbol!(real_seteuid, "seteuid", SetuidFn);
+declare_symbol!(real_setgid, "setgid", SetgidFn);
+declare_symbol!(real_setegid, "setegid", SetgidFn);
+declare_symbol!(real_execve, "execve", ExecveFn);
+declare_symbol!(real_execv, "execv", ExecvFn);
+declare_symbol!(real_execvp, "execvp", ExecvFn);
+declare_symbol!(real_execvpe, "execvpe", ExecveFn);
+declare_symbol!(real_posix_spawn, "posix_spawn", PosixSpawnFn);
+declare_symbol!(real_posix_spawnp, "posix_spawnp", PosixSpawnFn);
+declare_symbol!(real_exit, "exit", ExitFn);
+declare_symbol!(real_uexit, "_exit", ExitFn);
+
+// libacl's, when the host has loaded it; no syscall stands in for these.
+type AclToTextFn = unsafe extern "C" fn(*mut c_void, *mut libc::ssize_t) -> *mut c_char;
+type AclFreeFn = unsafe extern "C" fn(*mut c_void) -> c_int;
+declare_symbol!(real_acl_set_file, "acl_set_file", AclSetFileFn);
+declare_symbol!(real_acl_set_fd, "acl_set_fd", AclSetFdFn);
+declare_symbol!(real_acl_to_text, "acl_to_text", AclToTextFn);
+declare_symbol!(real_acl_free, "acl_free", AclFreeFn);
+
+/// Whether `open`'s variadic mode argument is present for these flags.
+#[inline]
+pub(crate) fn open_needs_mode(flags: c_int) -> bool {
+    flags & libc::O_CREAT != 0 || flags & libc::O_TMPFILE == libc::O_TMPFILE
+}
+
+#[inline]
+pub(crate) unsafe fn sys_open(path: *const c_char, flags: c_int, mode: libc::mode_t) -> c_int {
+    match real_open64() {
+        Some(real) => unsafe { real(path, flags, mode as c_uint) },
+        None => unsafe { sys_openat(libc::AT_FDCWD, path, flags, mode) },
+    }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_openat(
+    dirfd: c_int,
+    path: *const c_char,
+    flags: c_int,
+    mode: libc::mode_t,
+) -> c_int {
+    match real_openat64() {
+        Some(real) => unsafe { real(dirfd, path, flags, mode as c_uint) },
+        None => unsafe {
+            libc::syscall(
+                libc::SYS_openat,
+                dirfd as libc::c_long,
+                path as libc::c_long,
+                flags as libc::c_long,
+                mode as libc::c_long,
+            ) as c_int
+        },
+    }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_write(
+    fd: c_int,
+    buf: *const c_void,
+    count: libc::size_t,
+) -> libc::ssize_t {
+    match crate::real_write() {
+        Some(real) => unsafe { real(fd, buf, count) },
+        None => unsafe {
+            libc::syscall(
+                libc::SYS_write,
+                fd as libc::c_long,
+                buf as libc::c_long,
+                count as libc::c_long,
+            ) as libc::ssize_t
+        },
+    }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_pwrite(
+    fd: c_int,
+    buf: *const c_void,
+    count: libc::size_t,
+    offset: libc::off_t,
+) -> libc::ssize_t {
+    match real_pwrite64() {
+        Some(real) => unsafe { real(fd, buf, count, offset) },
+        None => unsafe {
+            libc::syscall(
+                libc::SYS_pwrite64,
+                fd as libc::c_long,
+                buf as libc::c_long,
+                count as libc::c_long,
+                offset as libc::c_long,
+            ) as libc::ssize_t
+        },
+    }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_pwritev(
+    fd: c_int,
+    iov: *const libc::iovec,
+    iovcnt: c_int,
+    offset: libc::off_t,
+) -> libc::ssize_t {
+    match real_pwritev64() {
+        Some(real) => unsafe { real(fd, iov, iovcnt, offset) },
+        // The kernel takes the offset as two longs, low half first.
+        None => unsafe {
+            libc::syscall(
+                libc::SYS_pwritev,
+                fd as libc::c_long,
+                iov as libc::c_long,
+                iovcnt as libc::c_long,
+                offset as libc::c_long,
+                ((offset as u64) >> 32) as libc::c_long,
+            ) as libc::ssize_t
+        },
+    }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_writev(
+    fd: c_int,
+    iov: *const libc::iovec,
+    iovcnt: c_int,
+) -> libc::ssize_t {
+    match real_writev() {
+        Some(real) => unsafe { real(fd, iov, iovcnt) },
+        None => unsafe {
+            libc::syscall(
+                libc::SYS_writev,
+                fd as libc::c_long,
+                iov as libc::c_long,
+                iovcnt as libc::c_long,
+            ) as libc::ssize_t
+        },
+    }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_close(fd: c_int) -> c_int {
+    match real_close() {
+        Some(real) => unsafe { real(fd) },
+        None => unsafe { libc::syscall(libc::SYS_close, fd as libc::c_long) as c_int },
+    }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_unlink(path: *const c_char) -> c_int {
+    match real_unlink() {
+        Some(real) => unsafe { real(path) },
+        None => unsafe { sys_unlinkat(libc::AT_FDCWD, path, 0) },
+    }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_unlinkat(dirfd: c_int, path: *const c_char, flags: c_int) -> c_int {
+    match real_unlinkat() {
+        Some(real) => unsafe { real(dirfd, path, flags) },
+        None => unsafe {
+            libc::syscall(
+                libc::SYS_unlinkat,
+                dirfd as libc::c_long,
+                path as libc::c_long,
+                flags as libc::c_long,
+            ) as c_int
+        },
+    }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_rename(old: *const c_char, new: *const c_char) -> c_int {
+    match real_rename() {
+        Some(real) => unsafe { real(old, new) },
+        None => unsafe { sys_renameat(libc::AT_FDCWD, old, libc::AT_FDCWD, new) },
+    }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_renameat(
+    olddirfd: c_int,
+    old: *const c_char,
+    newdirfd: c_int,
+    new: *const c_char,
+) -> c_int {
+    match real_renameat() {
+        Some(real) => unsafe { real(olddirfd, old, newdirfd, new) },
+        // aarch64 has no plain renameat syscall.
+        None => unsafe { raw_renameat2(olddirfd, old, newdirfd, new, 0) },
+    }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_renameat2(
+    olddirfd: c_int,
+    old: *const c_char,
+    newdirfd: c_int,
+    new: *const c_char,
+    flags: c_uint,
+) -> c_int {
+    match real_renameat2() {
+        Some(real) => unsafe { real(olddirfd, old, newdirfd, new, flags) },
+        // glibc before 2.28 has no wrapper; the kernel has had it since 3.15.
+        None => unsafe { raw_renameat2(olddirfd, old, newdirfd, new, flags) },
+    }
+}
+
+unsafe fn raw_renameat2(
+    olddirfd: c_int,
+    old: *const c_char,
+    newdirfd: c_int,
+    new: *const c_char,
+    flags: c_uint,
+) -> c_int {
+    unsafe {
+        libc::syscall(
+            libc::SYS_renameat2,
+            olddirfd as libc::c_long,
+            old as libc::c_long,
+            newdirfd as libc::c_long,
+            new as libc::c_long,
+            flags as libc::c_long,
+        ) as c_int
+    }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_truncate(path: *const c_char, len: libc::off_t) -> c_int {
+    match real_truncate() {
+        Some(real) => unsafe { real(path, len) },
+        None => unsafe {
+            libc::syscall(
+                libc::SYS_truncate,
+                path as libc::c_long,
+                len as libc::c_long,
+            ) as c_int
+        },
+    }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_ftruncate(fd: c_int, len: libc::off_t) -> c_int {
+    match real_ftruncate() {
+        Some(real) => unsafe { real(fd, len) },
+        None => unsafe {
+            libc::syscall(libc::SYS_ftruncate, fd as libc::c_long, len as libc::c_long) as c_int
+        },
+    }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_chdir(path: *const c_char) -> c_int {
+    match real_chdir() {
+        Some(real) => unsafe { real(path) },
+        None => unsafe { libc::syscall(libc::SYS_chdir, path as libc::c_long) as c_int },
+    }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_fchdir(fd: c_int) -> c_int {
+    match real_fchdir() {
+        Some(real) => unsafe { real(fd) },
+        None => unsafe { libc::syscall(libc::SYS_fchdir, fd as libc::c_long) as c_int },
+    }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_mkfifo(path: *const c_char, mode: libc::mode_t) -> c_int {
+    match real_mkfifo() {
+        Some(real) => unsafe { real(path, mode) },
+        None => unsafe { raw_mknodat(libc::AT_FDCWD, path, mode | libc::S_IFIFO, 0) },
+    }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_mkfifoat(dirfd: c_int, path: *const c_char, mode: libc::mode_t) -> c_int {
+    match real_mkfifoat() {
+        Some(real) => unsafe { real(dirfd, path, mode) },
+        None => unsafe { raw_mknodat(dirfd, path, mode | libc::S_IFIFO, 0) },
+    }
+}
+
+/// glibc before 2.33 has no `mknod` symbol, only `__xmknod` behind an
+/// inline wrapper, so binaries built against it never reach this hook.
+#[inline]
+pub(crate) unsafe fn sys_mknod(path: *const c_char, mode: libc::mode_t, dev: libc::dev_t) -> c_int {
+    match real_mknod() {
+        Some(real) => unsafe { real(path, mode, dev) },
+        None => unsafe { raw_mknodat(libc::AT_FDCWD, path, mode, dev) },
+    }
+}
+
+/// Before glibc 2.34 these live in librt, which the host may not have
+/// loaded; then no one can be calling them.
+pub(crate) unsafe fn sys_shm_open(name: *const c_char, oflag: c_int, mode: libc::mode_t) -> c_int {
+    match real_shm_open() {
+        Some(real) => unsafe { real(name, oflag, mode) },
+        None => {
+            set_errno(libc::ENOSYS);
+            -1
+        }
+    }
+}
+
+pub(crate) unsafe fn sys_shm_unlink(name: *const c_char) -> c_int {
+    match real_shm_unlink() {
+        Some(real) => unsafe { real(name) },
+        None => {
+            set_errno(libc::ENOSYS);
+            -1
+        }
+    }
+}
+
+/// Where glibc keeps the shared memory object `name`.
+pub(crate) fn shm_path(name: *const c_char) -> Option<PathBuf> {
+    if name.is_null() {
+        return None;
+    }
+    let name = unsafe { CStr::from_ptr(name) }.to_bytes();
+    let start = name.iter().position(|&b| b != b'/')?;
+    let name = &name[start..];
+    if name.contains(&b'/') {
+        return None;
+    }
+    Some(Path::new("/dev/shm").join(OsStr::from_bytes(name)))
+}
+
+#[inline]
+pub(crate) unsafe fn sys_flock(fd: c_int, op: c_int) -> c_int {
+    match real_flock() {
+        Some(real) => unsafe { real(fd, op) },
+        None => unsafe {
+            libc::syscall(libc::SYS_flock, fd as libc::c_long, op as libc::c_long) as c_int
+        },
+    }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_fcntl(fd: c_int, cmd: c_int, arg: usize) -> c_int {
+    match real_fcntl() {
+        Some(real) => unsafe { real(fd, cmd, arg) },
+        None => unsafe {
+            libc::syscall(
+                libc::SYS_fcntl,
+                fd as libc::c_long,
+                cmd as libc::c_long,
+                arg as libc::c_long,
+            ) as c_int
+        },
+    }
+}
+
+/// `lockf` is libc's own, built on `fcntl`; there is no syscall for it.
+pub(crate) unsafe fn sys_lockf(fd: c_int, cmd: c_int, len: libc::off_t) -> c_int {
+    match real_lockf() {
+        Some(real) => unsafe { real(fd, cmd, len) },
+        None => {
+            set_errno(libc::ENOSYS);
+            -1
+        }
+    }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_fchmod(fd: c_int, mode: libc::mode_t) -> c_int {
+    match real_fchmod() {
+        Some(real) => unsafe { real(fd, mode) },
+        None => unsafe {
+            libc::syscall(libc::SYS_fchmod, fd as libc::c_long, mode as libc::c_long) as c_int
+        },
+    }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_futimens(fd: c_int, times: *const libc::timespec) -> c_int {
+    match real_futimens() {
+        Some(real) => unsafe { real(fd, times) },
+        None => unsafe { raw_futimens(fd, times) },
+    }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_futimes(fd: c_int, times: *const libc::timeval) -> c_int {
+    if let Some(real) = real_futimes() {
+        return unsafe { real(fd, times) };
+    }
+    match unsafe { timespecs(times) } {
+        Some(ts) => unsafe { raw_futimens(fd, ts.as_ptr()) },
+        None => unsafe { raw_futimens(fd, std::ptr::null()) },
+    }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_utimensat(
+    dirfd: c_int,
+    path: *const c_char,
+    times: *const libc::timespec,
+    flags: c_int,
+) -> c_int {
+    match real_utimensat() {
+        Some(real) => unsafe { real(dirfd, path, times, flags) },
+        None => unsafe { raw_utimensat(dirfd, path, times, flags) },
+    }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_utimes(path: *const c_char, times: *const libc::timeval) -> c_int {
+    if let Some(real) = real_utimes() {
+        return unsafe { real(path, times) };
+    }
+    match unsafe { timespecs(times) } {
+        Some(ts) => unsafe { raw_utimensat(libc::AT_FDCWD, path, ts.as_ptr(), 0) },
+        None => unsafe { raw_utimensat(libc::AT_FDCWD, path, std::ptr::null(), 0) },
+    }
+}
+
+// The raw syscalls change only the calling thread's ids, where libc's
+// calls change every thread's. They are only for a libc without these
+// symbols, which no threaded program links against.
+
+#[inline]
+pub(crate) unsafe fn sys_setuid(uid: libc::uid_t) -> c_int {
+    match real_setuid() {
+        Some(real) => unsafe { real(uid) },
+        None => unsafe { libc::syscall(libc::SYS_setuid, uid as libc::c_long) as c_int },
+    }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_seteuid(uid: libc::uid_t) -> c_int {
+    match real_seteuid() {
+        Some(real) => unsafe { real(uid) },
+        None => unsafe {
+            libc::syscall(
+                libc::SYS_setresuid,
+                -1 as libc::c_long,
+                uid as libc::c_long,
+                -1 as libc::c_long,
+            ) as c_int
+        },
+    }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_setgid(gid: libc::gid_t) -> c_int {
+    match real_setgid() {
+        Some(real) => unsafe { real(gid) },
+        None => unsafe { libc::syscall(libc::SYS_setgid, gid as libc::c_long) as c_int },
+    }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_setegid(gid: libc::gid_t) -> c_int {
+    match real_setegid() {
+        Some(real) => unsafe { real(gid) },
+        None => unsafe {
+            libc::syscall(
+                libc::SYS_setresgid,
+                -1 as libc::c_long,
+                gid as libc::c_long,
+                -1 as libc::c_long,
+            ) as c_int
+        },
+    }
+}
+
+extern "C" {
+    static environ: *const *const c_char;
+}
+
+/// This process's environment, as `execv` and `execvp` pass it on.
+pub(crate) fn env_block() -> *const *const c_char {
+    unsafe { environ }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_execve(
+    path: *const c_char,
+    argv: *const *const c_char,
+    envp: *const *const c_char,
+) -> c_int {
+    match real_execve() {
+        Some(real) => unsafe { real(path, argv, envp) },
+        None => unsafe { libc::syscall(libc::SYS_execve, path, argv, envp) as c_int },
+    }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_execv(path: *const c_char, argv: *const *const c_char) -> c_int {
+    match real_execv() {
+        Some(real) => unsafe { real(path, argv) },
+        None => unsafe { sys_execve(path, argv, env_block()) },
+    }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_execvp(file: *const c_char, argv: *const *const c_char) -> c_int {
+    match real_execvp() {
+        Some(real) => unsafe { real(file, argv) },
+        None => unsafe { sys_execvpe(file, argv, env_block()) },
+    }
+}
+
+/// Without the libc's `execvpe`, `file` is taken as a path: there is no
+/// `PATH` search.
+#[inline]
+pub(crate) unsafe fn sys_execvpe(
+    file: *const c_char,
+    argv: *const *const c_char,
+    envp: *const *const c_char,
+) -> c_int {
+    match real_execvpe() {
+        Some(real) => unsafe { real(file, argv, envp) },
+        None => unsafe { sys_execve(file, argv, envp) },
+    }
+}
+
+// These return the error rather than setting `errno`, and there is no
+// syscall to fall back to.
+#[inline]
+pub(crate) unsafe fn sys_posix_spawn(
+    pid: *mut libc::pid_t,
+    path: *const c_char,
+    actions: *const libc::posix_spawn_file_actions_t,
+    attr: *const libc::posix_spawnattr_t,
+    argv: *const *mut c_char,
+    envp: *const *mut c_char,
+) -> c_int {
+    match real_posix_spawn() {
+        Some(real) => unsafe { real(pid, path, actions, attr, argv, envp) },
+        None => libc::ENOSYS,
+    }
+}
+
+#[inline]
+pub(crate) unsafe fn sys_posix_spawnp(
+    pid: *mut libc::pid_t,
+    file: *const c_char,
+    actions: *const libc::posix_spawn_file_actions_t,
+    attr: *const libc::posix_spawnattr_t,
+    argv: *const *mut c_char,
+    envp: *const *mut c_char,
+) -> c_int {
+    match real_posix_spawnp() {
+        Some(real) => unsafe { real(pid, file, actions, attr, argv, envp) },
+        None => libc::ENOSYS,
+    }
+}
+
+/// The `utimensat` form of a `futimes`/`utimes` argument; `None` for
+/// null, "now".
+unsafe fn timespecs(times: *const libc::timeval) -> Option<[libc::timespec; 2]> {
+    if times.is_null() {
+        return None;
+    }
+    let tv = unsafe { *(times as *const [libc::timeval; 2]) };
+    Some(tv.map(|t| libc::timespec {
+        tv_sec: t.tv_sec,
+        tv_nsec: t.tv_usec * 1000,
+    }))
+}
+
+// `futimens` is `utimensat` on the fd itself, with no path.
+unsafe fn raw_futimens(fd: c_int, times: *const libc::timespec) -> c_int {
+    unsafe { raw_utimensat(fd, std::ptr::null(), times, 0) }
+}
+
+unsafe fn raw_utimensat(
+    dirfd: c_int,
+    path: *const c_char,
+    times: *const libc::timespec,
+    flags: c_int,
+) -> c_int {
+    unsafe {
+        libc::syscall(
+            libc::SYS_utimensat,
+            dirfd as libc::c_long,
+            path as libc::c_long,
+            times as libc::c_long,
+            flags as libc::c_long,
+        ) as c_int
+    }
+}
+
+/// Without libc's `exit` the handlers don't run; there is nothing else
+/// to end the process with.
+pub(crate) unsafe fn sys_exit(status: c_int) -> ! {
+    match real_exit() {
+        Some(real) => unsafe { real(status) },
+        None => unsafe { raw_exit_group(status) },
+    }
+}
+
+pub(crate) unsafe fn sys_uexit(status: c_int) -> ! {
+    match real_uexit() {
+        Some(real) => unsafe { real(status) },
+        None => unsafe { raw_exit_group(status) },
+    }
+}
+
+/// Without libacl there is no ACL to set; `ENOSYS`.
+pub(crate) unsafe fn sys_acl_set_file(path: *const c_char, ty: c_int, acl: *mut c_void) -> c_int {
+    match real_acl_set_file() {
+        Some(real) => unsafe { real(path, ty, acl) },
+        None => {
+            set_errno(libc::ENOSYS);
+            -1
+        }
+    }
+}
+
+pub(crate) unsafe fn sys_acl_set_fd(fd: c_int, acl: *mut c_void) -> c_int {
+    match real_acl_set_fd() {
+        Some(real) => unsafe { real(fd, acl) },
+        None => {
+            set_errno(libc::ENOSYS);
+            -1
+        }
+    }
+}
+
+/// `acl` as `acl_to_text` renders it.
+pub(crate) unsafe fn acl_text(acl: *mut c_void) -> Option<String> {
+    let (to_text, free) = (real_acl_to_text()?, real_acl_free()?);
+    let text = unsafe { to_text(acl, std::ptr::null_mut()) };
+    if text.is_null() {
+        return None;
+    }
+    let out = unsafe { std::ffi::CStr::from_ptr(text) }
+        .to_string_lossy()
+        .into_owned();
+    unsafe { free(text as *mut c_void) };
+    Some(out)
+}
+
+unsafe fn raw_exit_group(status: c_int) -> ! {
+    loop {
+        unsafe { libc::syscall(libc::SYS_exit_group, status as libc::c_long) };
+    }
+}
+
+// aarch64 has no plain `mknod` syscall, only `mknodat`.
+unsafe fn raw_mknodat(
+    dirfd: c_int,
+    path: *const c_char,
+    mode: libc::mode_t,
+    dev: libc::dev_t,
+) -> c_int {
+    unsafe {
+        libc::syscall(
+            libc::SYS_mknodat,
+            dirfd as libc::c_long,
+            path as libc::c_long,
+            mode as libc::c_long,
+            dev as libc::c_long,
+        ) as c_int
+    }
+}
+
+/// stdio's own flush. There is no syscall to stand in for it: without one
+/// the buffer stays put and the caller sees `EOF`.
+#[inline]
+pub(crate) unsafe fn sys_fflush(stream: *mut libc::FILE) -> c_int {
+    match real_fflush() {
+        Some(real) => unsafe { real(stream) },
+        None => {
+            set_errno(libc::ENOSYS);
+            libc::EOF
+        }
+    }
+}
+
+/// Write straight to stderr without passing through our own `write` export.
+pub(crate) fn stderr_write(msg: &[u8]) {
+    unsafe {
+        let _ = libc::syscall(
+            libc::SYS_write,
+            libc::STDERR_FILENO as libc::c_long,
+            msg.as_ptr() as libc::c_long,
+            msg.len() as libc::c_long,
+        );
+    }
+}
+
+//
+// -------- Paths + errno --------
+//
+
+/// Resolve an fd through `/proc/self/fd/N`. Pipes, sockets and anonymous
+/// inodes resolve to `pipe:[…]`-style names; those are reported as `None`,
+/// matching `F_GETPATH` failing for non-vnode fds on macOS.
+pub(crate) fn fd_path(fd: RawFd) -> Option<PathBuf> {
+    let link = CString::new(format!("/proc/self/fd/{fd}")).ok()?;
+    let mut buf = [0u8; libc::PATH_MAX as usize];
+    let n = unsafe { libc::readlink(link.as_ptr(), buf.as_mut_ptr() as *mut c_char, buf.len()) };
+    if n <= 0 || buf[0] != b'/' {
+        return None;
+    }
+    let slice = &buf[..n as usize];
+    Some(PathBuf::from(OsStr::from_bytes(slice)))
+}
+
+/// `fstatfs` magic mapped to the names `mount` uses; unknown magics come
+/// back as hex so they can still be listed in `track_filesystems`.
+pub(crate) fn fd_fs_type(fd: RawFd) -> Option<String> {
+    let mut sfs: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstatfs(fd, &mut sfs) } != 0 {
+        return None;
+    }
+    let name = match sfs.f_type as u32 {
+        0xEF53 => "ext4",
+        0x5846_5342 => "xfs",
+        0x9123_683E => "btrfs",
+        0x2FC1_2FC1 => "zfs",
+        0xF2F5_2010 => "f2fs",
+        0x0102_1994 => "tmpfs",
+        0x794C_7630 => "overlay",
+        0x6969 => "nfs",
+        0x517B => "smb",
+        0xFF53_4D42 => "cifs",
+        0xFE53_4D42 => "smb2",
+        0x6573_5546 => "fuse",
+        0x4D44 => "vfat",
+        0x2011_BAB0 => "exfat",
+        0x5346_544E => "ntfs",
+        0x0102_1997 => "9p",
+        other => return Some(format!("{other:#x}")),
+    };
+    Some(name.to_string())
+}
+
+/// Effective uid of the process on the other end of a Unix socket.
+pub(crate) fn peer_uid(fd: RawFd) -> Option<libc::uid_t> {
+    let mut cred: libc::ucred = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let rc = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut c_void,
+            &mut len,
+        )
+    };
+    (rc == 0).then_some(cred.uid)
+}
+
+/// `argv[0]`, from `/proc/self/cmdline`.
+pub(crate) fn argv0() -> Option<String> {
+    let cmdline = crate::internal_io::read(std::path::Path::new("/proc/self/cmdline")).ok()?;
+    let first = cmdline.split(|&b| b == 0).next()?;
+    Some(String::from_utf8_lossy(first).into_owned())
+}
+
+/// The kernel's id for the calling thread, as `ps -L` shows it.
+pub(crate) fn thread_id() -> u64 {
+    unsafe { libc::gettid() as u64 }
+}
+
+/// Monotonic milliseconds by the coarse clock, read from the vDSO
+/// without a syscall.
+pub(crate) fn coarse_ms() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC_COARSE, &mut ts) };
+    ts.tv_sec as u64 * 1000 + ts.tv_nsec as u64 / 1_000_000
+}
+
+/// No libdispatch here.
+pub(crate) fn queue_label() -> Option<String> {
+    None
+}
+
+#[inline]
+pub(crate) fn set_errno(e: c_int) {
+    unsafe {
+        *libc::__errno_location() = e;
+    }
+}
+
+//
+// -------- Exported overrides --------
+//
+
+#[cfg(not(test))]
+mod exports {
+    use super::*;
+    use crate::{
+        handle_acl_set_fd, handle_acl_set_file, handle_chdir, handle_close, handle_execv,
+        handle_execve, handle_execvp, handle_exit, handle_fchdir, handle_fchmod, handle_fcntl,
+        handle_fflush, handle_flock, handle_ftruncate, handle_futimens, handle_futimes,
+        handle_lockf, handle_mkfifo, handle_mkfifoat, handle_mknod, handle_open,
+        handle_posix_spawn, handle_posix_spawnp, handle_pwrite, handle_pwritev, handle_rename,
+        handle_renameat, handle_setegid, handle_seteuid, handle_setgid, handle_setuid,
+        handle_shm_open, handle_shm_unlink, handle_truncate, handle_uexit, handle_unlink,
+        handle_unlinkat, handle_utimensat, handle_utimes, handle_write, handle_writev,
+    };
+
+    // Stable Rust can't define C-variadic functions, so the mode is declared
+    // as a trailing fixed argument. On the x86_64 and aarch64 Linux ABIs a
+    // variadic int travels in the same register as the equivalent named one;
+    // when the caller didn't pass it the register is garbage, so it is only
+    // read when the flags say it is there.
+    #[no_mangle]
+    pub unsafe extern "C" fn open(path: *const c_char, flags: c_int, mode: c_uint) -> c_int {
+        let mode = open_needs_mode(flags).then_some(mode as libc::mode_t);
+        unsafe { handle_open(None, path, flags, mode) }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn open64(path: *const c_char, flags: c_int, mode: c_uint) -> c_int {
+        let mode = open_needs_mode(flags).then_some(mode as libc::mode_t);
+        unsafe { handle_open(None, path, flags, mode) }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn openat(
+        dirfd: c_int,
+        path: *const c_char,
+        flags: c_int,
+        mode: c_uint,
+    ) -> c_int {
+        let mode = open_needs_mode(flags).then_some(mode as libc::mode_t);
+        unsafe { handle_open(Some(dirfd), path, flags, mode) }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn openat64(
+        dirfd: c_int,
+        path: *const c_char,
+        flags: c_int,
+        mode: c_uint,
+    ) -> c_int {
+        let mode = open_needs_mode(flags).then_some(mode as libc::mode_t);
+        unsafe { handle_open(Some(dirfd), path, flags, mode) }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn write(
+        fd: c_int,
+        buf: *const c_void,
+        count: libc::size_t,
+    ) -> libc::ssize_t {
+        unsafe { handle_write(fd, buf, count) }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn pwrite(
+        fd: c_int,
+        buf: *const c_void,
+        count: libc::size_t,
+        offset: libc::off_t,
+    ) -> libc::ssize_t {
+        unsafe { handle_pwrite(fd, buf, count, offset) }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn pwrite64(
+        fd: c_int,
+        buf: *const c_void,
+        count: libc::size_t,
+        offset: libc::off64_t,
+    ) -> libc::ssize_t {
+        unsafe { handle_pwrite(fd, buf, count, offset as libc::off_t) }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn writev(
+        fd: c_int,
+        iov: *const libc::iovec,
+        iovcnt: c_int,
+    ) -> libc::ssize_t {
+        unsafe { handle_writev(fd, iov, iovcnt) }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn pwritev(
+        fd: c_int,
+        iov: *const libc::iovec,
+        iovcnt: c_int,
+        offset: libc::off_t,
+    ) -> libc::ssize_t {
+        unsafe { handle_pwritev(fd, iov, iovcnt, offset) }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn pwritev64(
+        fd: c_int,
+        iov: *const libc::iovec,
+        iovcnt: c_int,
+        offset: libc::off64_t,
+    ) -> libc::ssize_t {
+        unsafe { handle_pwritev(fd, iov, iovcnt, offset as libc::off_t) }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn close(fd: c_int) -> c_int {
+        unsafe { handle_close(fd) }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn unlink(path: *const c_char) -> c_int {
+        unsafe { handle_unlink(path) }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn unlinkat(dirfd: c_int, path: *const c_char, flags: c_int) -> c_int {
+        unsafe { handle_unlinkat(dirfd, path, flags) }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn rename(old: *const c_char, new: *const c_char) -> c_int {
+        unsafe { handle_rename(old, new) }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn renameat(
+        olddirfd: c_int,
+        old: *const c_char,
+        newdirfd: c_int,
+        new: *const c_char,
+    ) -> c_int {
+        unsafe { handle_renameat(olddirfd, old, newdirfd, new, None) }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn renameat2(
+        olddirfd: c_int,
+        old: *const c_char,
+        newdirfd: c_int,
+        new: *const c_char,
+        flags: c_uint,
+    ) -> c_int {
+        unsafe { handle_renameat(olddirfd, old, newdirfd, new, Some(flags)) }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn truncate(path: *const c_char, length: libc::off_t) -> c_int {
+        unsafe { handle_truncate(path, length) }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn truncate64(path: *const c_char, length: libc::off64_t) -> c_int {
+        unsafe { handle_truncate(path, length as libc::off_t) }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn ftruncate(fd: c_int, length: libc::off_t) -> c_int {
+        unsafe { handle_ftruncate(fd, length) }
+    }
+
+    // What LFS builds (Rust's std among them) call.
+    #[no_mangle]
+    pub unsafe extern "C" fn ftruncate64(fd: c_int, length: libc::off64_t) -> c_int {
+        unsafe { handle_ftruncate(fd, length as libc::off_t) }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn fflush(stream: *mut libc::FILE) -> c_int {
+        unsafe { handle_fflush(stream) }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn chdir(path: *const c_char) -> c_int {
+        unsafe { handle_chdir(path) }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn fchdir(fd: c_int) -> c_int {
+        unsafe { handle_fchdir(fd) }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn mkfifo(path: *const c_char, mode: libc::mode_t) -> c_int {
+        unsafe { handle_mkfifo(path, mode) }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn mkfifoat(
+        dirfd: c_int,
+        path: *const c_char,
+        mode: libc::mode_t,
+    ) -> c_int {
+        unsafe { handle_mkfifoat(dirfd, path, mode) }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn mknod(
+        path: *const c_char,
+        mode: libc::mode_t,
+        dev: libc::dev_t,
+    ) -> c_int {
+        unsafe { handle_mknod(path, mode, dev) }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn acl_set_file(
+        path: *const c_char,
+        ty: c_int,
+        acl: *mut c_void,
+    ) -> c_int {
+        unsafe { handle_acl_set_file(path, ty, acl) }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn acl_set_fd(fd: c_int, acl: *mut c_void) -> c_int {
+        unsafe { handle_acl_set_fd(fd, acl) }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn shm_open(
+        name: *const c_char,
+        oflag: c_int,
+        mode: libc::mode_t,
+    ) -> c_int {
+        unsafe { handle_shm_open(name, oflag, mode) }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn shm_unlink(name: *const c_char) -> c_int {
+        unsafe { handle_shm_unlink(name) }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn fchmod(fd: c_int, mode: libc::mode_t) -> c_int {
+        unsafe { handle_fchmod(fd, mode) }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn futimens(fd: c_int, times: *const libc::timespec) -> c_int {
+        unsafe { handle_futimens(fd, times) }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn futimes(fd: c_int, times: *const libc::timeval) -> c_int {
+        unsafe { handle_futimes(fd, times) }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn utimensat(
+        dirfd: c_int,
+        path: *const c_char,
+        times: *const libc::timespec,
+        flags: c_int,
+    ) -> c_int {
+        unsafe { handle_utimensat(dirfd, path, times, flags) }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn utimes(path: *const c_char, times: *const libc::timeval) -> c_int {
+        unsafe { handle_utimes(path, times) }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn setuid(uid: libc::uid_t) -> c_int {
+        unsafe { handle_setuid(uid) }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn seteuid(uid: libc::uid_t) -> c_int {
+        unsafe { handle_seteuid(uid) }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn setgid(gid: libc::gid_t) -> c_int {
+        unsafe { handle_setgid(gid) }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn setegid(gid: libc::gid_t) -> c_int {
+        unsafe { handle_setegid(gid) }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn execve(
+        path: *const c_char,
+        argv: *const *const c_char,
+        envp: *const *const c_char,
+    ) -> c_int {
+        unsafe { handle_execve(path, argv, envp) }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn execv(path: *const c_char, argv: *const *const c_char) -> c_int {
+        unsafe { handle_execv(path, argv) }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn execvp(file: *const c_char, argv: *const *const c_char) -> c_int {
+        unsafe { handle_execvp(file, argv) }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn posix_spawn(
+        pid: *mut libc::pid_t,
+        path: *const c_char,
+        actions: *const libc::posix_spawn_file_actions_t,
+        attr: *const libc::posix_spawnattr_t,
+        argv: *const *mut c_char,
+        envp: *const *mut c_char,
+    ) -> c_int {
+        unsafe { handle_posix_spawn(pid, path, actions, attr, argv, envp) }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn posix_spawnp(
+        pid: *mut libc::pid_t,
+        file: *const c_char,
+        actions: *const libc::posix_spawn_file_actions_t,
+        attr: *const libc::posix_spawnattr_t,
+        argv: *const *mut c_char,
+        envp: *const *mut c_char,
+    ) -> c_int {
+        unsafe { handle_posix_spawnp(pid, file, actions, attr, argv, envp) }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn flock(fd: c_int, op: c_int) -> c_int {
+        unsafe { handle_flock(fd, op) }
+    }
+
+    // The argument is variadic, as `open`'s mode is: an int or a pointer,
+    // in the register a named third argument would take.
+    #[no_mangle]
+    pub unsafe extern "C" fn fcntl(fd: c_int, cmd: c_int, arg: usize) -> c_int {
+        unsafe { handle_fcntl(fd, cmd, arg) }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn fcntl64(fd: c_int, cmd: c_int, arg: usize) -> c_int {
+        unsafe { handle_fcntl(fd, cmd, arg) }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn lockf(fd: c_int, cmd: c_int, len: libc::off_t) -> c_int {
+        unsafe { handle_lockf(fd, cmd, len) }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn lockf64(fd: c_int, cmd: c_int, len: libc::off64_t) -> c_int {
+        unsafe { handle_lockf(fd, cmd, len as libc::off_t) }
+    }
+
+    #[no_mangle]
+    pub extern "C" fn exit(status: c_int) -> ! {
+        handle_exit(status)
+    }
+
+    #[no_mangle]
+    pub extern "C" fn _exit(status: c_int) -> ! {
+        handle_uexit(status)
+    }
+}
@@ -0,0 +1,18 @@
+//! Platform layer: raw syscall plumbing, fd → path resolution, errno, the
+//! library init hook and symbol interposition glue.
+//!
+//! Everything above this module (FD_TABLE, protocol, policy) is shared; each
+//! platform module exposes the same `sys_*` / `fd_path` / `set_errno` surface.
+
+#[cfg(target_os = "macos")]
+mod darwin;
+#[cfg(target_os = "macos")]
+pub(crate) use darwin::*;
+
+#[cfg(target_os = "linux")]
+mod linux;
+#[cfg(target_os = "linux")]
+pub(crate) use linux::*;
+
+#[cfg(not(any(target_os = "macos", target_os = "linux")))]
+compile_error!("This shim targets macOS (dyld __interpose) and Linux (LD_PRELOAD).");
@@ -0,0 +1,120 @@
+//! Exclusive creates that found the path taken.
+//!
+//! An `open` with `O_CREAT | O_EXCL` that fails with `EEXIST` is how some
+//! agents ask whether a path is there. One such failure is nothing, but a
+//! run of them against protected paths is worth a look. So a failure on a
+//! path matching a `sensitive` glob is reported, without waiting for an
+//! answer, as
+//!
+//! ```json
+//! { "method": "shim/probe",
+//!   "params": { "pid": 4242, "path": "/p/.env", "count": 7 } }
+//! ```
+//!
+//! `count` is the path's failures in this process so far. The first is
+//! sent at once, and after that one at most every `probe_debounce_ms`
+//! carries the count on; failures in between are only counted. Paths
+//! outside `sensitive` are never counted. At most `PATHS` paths are
+//! counted at a time, within the `heap` cap, and one past that starts
+//! them all afresh. A forked child starts with no counts.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+
+use crate::{config, heap};
+
+const PATHS: usize = 256;
+
+struct Probe {
+    count: u64,
+    sent: Instant,
+}
+
+#[derive(Default)]
+struct Table {
+    pid: i32,
+    paths: HashMap<PathBuf, Probe>,
+}
+
+static TABLE: Mutex<Option<Table>> = parking_lot::const_mutex(None);
+
+fn cost(path: &Path) -> usize {
+    path.as_os_str().len() + heap::ENTRY
+}
+
+fn clear(paths: &mut HashMap<PathBuf, Probe>) {
+    paths.drain().for_each(|(p, _)| heap::release(cost(&p)));
+}
+
+fn failed_at(path: &Path, debounce: Duration, now: Instant) -> Option<Value> {
+    let pid = unsafe { libc::getpid() };
+    let mut table = TABLE.lock();
+    let t = table.get_or_insert_with(Table::default);
+    if t.pid != pid {
+        clear(&mut t.paths);
+        t.pid = pid;
+    }
+    let count = match t.paths.get_mut(path) {
+        Some(p) => {
+            p.count += 1;
+            if now.saturating_duration_since(p.sent) < debounce {
+                return None;
+            }
+            p.sent = now;
+            p.count
+        }
+        None => {
+            if t.paths.len() >= PATHS || !heap::charge(cost(path)) {
+                clear(&mut t.paths);
+                if !heap::charge(cost(path)) {
+                    return None;
+                }
+            }
+            t.paths.insert(
+                path.to_path_buf(),
+                Probe {
+                    count: 1,
+                    sent: now,
+                },
+            );
+            1
+        }
+    };
+    Some(json!({ "pid": pid, "path": path.to_string_lossy(), "count": count }))
+}
+
+/// An exclusive create of `path` failed with `EEXIST`. The `shim/probe`
+/// params, when one is due.
+pub(crate) fn failed(path: &Path) -> Option<Value> {
+    let cfg = config::get();
+    if !cfg.sensitive(path) {
+        return None;
+    }
+    failed_at(
+        path,
+        Duration::from_millis(cfg.probe_debounce_ms),
+        Instant::now(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn probes_are_counted_and_sent_once_per_debounce() {
+        let window = Duration::from_millis(100);
+        let (path, t0) = (Path::new("/t/probes/.env"), Instant::now());
+        assert_eq!(failed_at(path, window, t0).unwrap()["count"], 1);
+        for _ in 0..5 {
+            assert_eq!(failed_at(path, window, t0), None);
+        }
+        let later = t0 + Duration::from_millis(150);
+        assert_eq!(failed_at(path, window, later).unwrap()["count"], 7);
+        assert_eq!(failed_at(path, window, later), None);
+    }
+}
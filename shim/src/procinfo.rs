@@ -0,0 +1,170 @@
+//! Where the process is working, for `shim/hello`.
+//!
+//! The same `node` or `python` runs in many projects per session, so the
+//! hello carries the working directory and the values of the environment
+//! variables named by `hello_env` (unset ones are left out). Values are
+//! cut to `MAX_VALUE` bytes.
+//!
+//! The cwd is also what relative paths are made absolute against, so it is
+//! cached rather than asked for on every event. It is taken when the
+//! library loads and again after each successful `chdir`/`fchdir`; the
+//! hooks hold the lock across the call, so concurrent changes in other
+//! threads land in order. Until the first capture, or when `getcwd` fails,
+//! the cache is unreliable and each lookup asks the kernel. The first
+//! lookup after a change reports it, for the caller to announce with
+//! `shim/cwd_changed`.
+//!
+//! The umask, for `post_create`, is read once at load. Linux shows it in
+//! `/proc/self/status`. Elsewhere, or on kernels before 4.7, the only way
+//! is to set it and put it back, and a file another thread created in
+//! between would get no umask at all; so that is only done from the
+//! library constructor, before the program has threads of its own, and
+//! never again. A later `umask()` by the program isn't seen.
+
+use std::os::raw::c_int;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use parking_lot::Mutex;
+use serde_json::{json, Map, Value};
+
+const MAX_VALUE: usize = 1024;
+
+struct Cwd {
+    /// Valid only while `reliable`.
+    now: Option<PathBuf>,
+    reliable: bool,
+    /// The cwd last handed to a caller.
+    seen: Option<PathBuf>,
+}
+
+static CWD: Mutex<Cwd> = parking_lot::const_mutex(Cwd {
+    now: None,
+    reliable: false,
+    seen: None,
+});
+
+impl Cwd {
+    fn refresh(&mut self) {
+        self.now = std::env::current_dir().ok();
+        self.reliable = self.now.is_some();
+    }
+}
+
+/// Remember the cwd as of library load.
+pub(crate) fn capture_cwd() {
+    let mut cwd = CWD.lock();
+    cwd.refresh();
+    cwd.seen = cwd.now.clone();
+}
+
+/// The umask as of load; `NO_UMASK` until then.
+static UMASK: AtomicU32 = AtomicU32::new(NO_UMASK);
+const NO_UMASK: u32 = u32::MAX;
+
+/// Remember the umask. Only for the library constructor: see the module
+/// doc.
+pub(crate) fn capture_umask() {
+    let mask = umask_from_proc().unwrap_or_else(|| unsafe {
+        let mask = libc::umask(0);
+        libc::umask(mask);
+        mask as u32
+    });
+    UMASK.store(mask & 0o777, Ordering::Relaxed);
+}
+
+#[cfg(target_os = "linux")]
+fn umask_from_proc() -> Option<u32> {
+    let status = crate::internal_io::read_to_string(Path::new("/proc/self/status")).ok()?;
+    umask_field(&status)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn umask_from_proc() -> Option<u32> {
+    None
+}
+
+/// The `Umask:` line of a `/proc/<pid>/status`.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn umask_field(status: &str) -> Option<u32> {
+    let v = status.lines().find_map(|l| l.strip_prefix("Umask:"))?;
+    u32::from_str_radix(v.trim(), 8).ok()
+}
+
+/// The umask as of load, once it has been read.
+pub(crate) fn umask() -> Option<u32> {
+    let mask = UMASK.load(Ordering::Relaxed);
+    (mask != NO_UMASK).then_some(mask)
+}
+
+/// Run a `chdir`-like call and, when it succeeds, cache where it went.
+pub(crate) fn change_dir(call: impl FnOnce() -> c_int) -> c_int {
+    let mut cwd = CWD.lock();
+    let rc = call();
+    if rc == 0 {
+        cwd.refresh();
+    }
+    rc
+}
+
+/// The cwd now, and whether it differs from the one last seen.
+pub(crate) fn current_cwd() -> Option<(PathBuf, bool)> {
+    let mut cwd = CWD.lock();
+    if !cwd.reliable {
+        cwd.refresh();
+    }
+    let now = cwd.now.clone()?;
+    let moved = cwd.seen.as_ref() != Some(&now);
+    if moved {
+        cwd.seen = Some(now.clone());
+    }
+    Some((now, moved))
+}
+
+/// `cwd` and `env` for `shim/hello`.
+pub(crate) fn hello_params(env: &[String]) -> Value {
+    let vars: Map<String, Value> = env
+        .iter()
+        .filter_map(|name| {
+            let v = std::env::var_os(name)?;
+            Some((name.clone(), json!(clip(&v.to_string_lossy()))))
+        })
+        .collect();
+    json!({
+        "cwd": CWD.lock().seen.as_deref().map(cwd_value),
+        "env": vars,
+    })
+}
+
+/// A cwd as sent.
+pub(crate) fn cwd_value(cwd: &Path) -> String {
+    clip(&cwd.to_string_lossy())
+}
+
+/// `s` cut to at most `MAX_VALUE` bytes, on a character boundary.
+fn clip(s: &str) -> String {
+    let mut end = s.len().min(MAX_VALUE);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    s[..end].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_values_are_clipped_on_a_char_boundary() {
+        assert_eq!(clip("short"), "short");
+        let long = format!("{}\u{e9}", "a".repeat(MAX_VALUE - 1));
+        assert_eq!(clip(&long), "a".repeat(MAX_VALUE - 1));
+    }
+
+    #[test]
+    fn the_umask_is_read_from_proc_status() {
+        let status = "Name:\tcargo\nUmask:\t0027\nState:\tR (running)\n";
+        assert_eq!(umask_field(status), Some(0o27));
+        assert_eq!(umask_field("Name:\tcargo\n"), None);
+    }
+}
@@ -0,0 +1,209 @@
+//! Policy committed with the repo, in `.nvim-claude/shim.toml`.
+//!
+//! The project root is the nearest directory, from the working directory
+//! the process starts in upward, that holds a `.git`, `.hg`, `.svn` or
+//! `.jj`. A `.nvim-claude/shim.toml` there is read with the schema of the
+//! `NVIM_CLAUDE_SHIM_CONFIG` file and sits between the built-in defaults
+//! and that file: a key the user's file sets replaces the project's, and
+//! `FS_SHIM_*` variables win over both. Paths in it are relative to the
+//! root: `roots`, `watch_dirs`, `denial_log` and `cache_token_file` are
+//! joined to it, and a glob not starting with `/` matches below it only
+//! (`gen/**` is `<root>/gen/**`, `*.snap` is `<root>/**/*.snap`). `[[destination]]`
+//! and `[[route]]` tables are skipped, since they decide where events go:
+//! a cloned repo doesn't get to send them elsewhere.
+//!
+//! A file that can't be read or doesn't parse, either one, is left out
+//! (the rest still applies), and is reported with the next post as
+//!
+//! ```json
+//! { "method": "shim/config_error",
+//!   "params": { "pid": 4242, "path": "/p/.nvim-claude/shim.toml",
+//!               "line": 3, "message": "unknown variant `blok`, ..." } }
+//! ```
+//!
+//! `line` is left out when the parser doesn't give one. Other settings
+//! found bad later, like a TCP address (see `tcp_addr`), are reported
+//! the same way, with `variable` instead of `path` when they came from
+//! the environment. The file is read
+//! once per process; `shim/reload_config` reads both files again, for
+//! long-lived processes (see `config::reload`).
+
+use std::path::{Path, PathBuf};
+
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+
+use crate::config::ShimConfig;
+use crate::{internal_io, path_class};
+
+const FILE: &str = ".nvim-claude/shim.toml";
+
+/// Keys the project file may not set.
+const SKIPPED: [&str; 2] = ["destination", "route"];
+
+/// Parse failures not yet sent.
+static ERRORS: Mutex<Vec<Value>> = Mutex::new(Vec::new());
+
+/// The project root for the working directory now, if there is one.
+pub(crate) fn root() -> Option<PathBuf> {
+    let cwd = std::env::current_dir().ok()?;
+    let root = cwd.ancestors().find(|d| {
+        path_class::VCS_DIRS
+            .iter()
+            .any(|v| std::fs::symlink_metadata(d.join(v)).is_ok())
+    })?;
+    Some(root.to_path_buf())
+}
+
+/// The project file that applies to this process, if there is one.
+pub(crate) fn file() -> Option<PathBuf> {
+    Some(root()?.join(FILE)).filter(|f| f.is_file())
+}
+
+/// `path`'s top-level table, when it parses as a config. `None`, and
+/// recorded, for one that can't be read or doesn't parse.
+pub(crate) fn read(path: &Path) -> Option<toml::Table> {
+    let text = match internal_io::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) => {
+            record(path, None, &e.to_string());
+            return None;
+        }
+    };
+    // Typed first, so a bad value is caught with its line.
+    if let Err(e) = toml::from_str::<ShimConfig>(&text) {
+        let line = e.span().map(|s| text[..s.start].matches('\n').count() + 1);
+        record(path, line, e.message());
+        return None;
+    }
+    toml::from_str(&text).ok()
+}
+
+/// The project file's table, made relative to `root` and without the
+/// keys it may not set.
+pub(crate) fn rebase(mut table: toml::Table, root: &Path) -> toml::Table {
+    for key in SKIPPED {
+        if table.remove(key).is_some() {
+            crate::log_debug(&format!("[shim] config: [[{key}]] ignored in {FILE}\n"));
+        }
+    }
+    let root = root.to_string_lossy();
+    let join = |s: &str| match Path::new(s).is_absolute() {
+        true => s.to_string(),
+        false => format!("{root}/{s}"),
+    };
+    let anchor = |g: &str| match (g.starts_with('/'), g.contains('/')) {
+        (true, _) => g.to_string(),
+        (false, true) => format!("{root}/{g}"),
+        (false, false) => format!("{root}/**/{g}"),
+    };
+    for (key, f) in [
+        ("roots", &join as &dyn Fn(&str) -> String),
+        ("watch_dirs", &join),
+        ("denial_log", &join),
+        ("cache_token_file", &join),
+        ("ignore", &anchor),
+        ("capture_backtrace", &anchor),
+        ("touch_verbose", &anchor),
+        ("sensitive", &anchor),
+    ] {
+        if let Some(v) = table.get_mut(key) {
+            map_strings(v, f);
+        }
+    }
+    for key in ["classify", "force"] {
+        if let Some(toml::Value::Array(tables)) = table.get_mut(key) {
+            for glob in tables.iter_mut().filter_map(|t| t.get_mut("glob")) {
+                map_strings(glob, &anchor);
+            }
+        }
+    }
+    table
+}
+
+/// Apply `f` to a string, or to each string in an array.
+fn map_strings(v: &mut toml::Value, f: &dyn Fn(&str) -> String) {
+    match v {
+        toml::Value::String(s) => *s = f(s),
+        toml::Value::Array(items) => items.iter_mut().for_each(|i| map_strings(i, f)),
+        _ => {}
+    }
+}
+
+pub(crate) fn record(path: &Path, line: Option<usize>, message: &str) {
+    crate::log_debug(&format!(
+        "[shim] config: {}:{}: {message}\n",
+        path.display(),
+        line.map_or(String::from("?"), |l| l.to_string())
+    ));
+    let mut params = json!({
+        "pid": unsafe { libc::getpid() },
+        "path": path.to_string_lossy(),
+        "message": message.trim(),
+    });
+    if let Some(line) = line {
+        params["line"] = json!(line);
+    }
+    ERRORS.lock().push(params);
+}
+
+/// A bad value in the environment variable `var`, reported like one in
+/// a file, with `variable` in place of `path`.
+pub(crate) fn record_env(var: &str, message: &str) {
+    crate::log_debug(&format!("[shim] config: {var}: {message}\n"));
+    ERRORS.lock().push(json!({
+        "pid": unsafe { libc::getpid() },
+        "variable": var,
+        "message": message.trim(),
+    }));
+}
+
+/// The `shim/config_error` params recorded since the last call.
+pub(crate) fn take_errors() -> Vec<Value> {
+    std::mem::take(&mut *ERRORS.lock())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn project_paths_are_relative_to_the_root() {
+        let table: toml::Table = toml::from_str(
+            r#"
+            roots = ["src", "/abs"]
+            denial_log = "denials.log"
+            ignore = ["gen/**", "*.snap", "/tmp/**"]
+            [[force]]
+            glob = "locked.rs"
+            outcome = "deny"
+            [[destination]]
+            kind = "tcp"
+            address = "198.51.100.1:9"
+            "#,
+        )
+        .unwrap();
+        let cfg: ShimConfig = rebase(table, Path::new("/repo")).try_into().unwrap();
+        assert_eq!(cfg.roots, [Path::new("/repo/src"), Path::new("/abs")]);
+        assert_eq!(cfg.denial_log, Path::new("/repo/denials.log"));
+        assert_eq!(cfg.ignore, ["/repo/gen/**", "/repo/**/*.snap", "/tmp/**"]);
+        assert_eq!(cfg.force[0].glob, "/repo/**/locked.rs");
+        assert!(cfg.destinations.is_empty());
+    }
+
+    #[test]
+    fn malformed_files_are_recorded_with_their_line() {
+        let dir = std::env::temp_dir().join(format!("shim-project-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("shim.toml");
+        std::fs::write(&path, "ignore = []\nappend_mode = \"blok\"\n").unwrap();
+        assert!(read(&path).is_none());
+        assert!(read(&dir.join("missing.toml")).is_none());
+        let errors = take_errors();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0]["line"], 2);
+        assert_eq!(errors[0]["path"], path.to_str().unwrap());
+        assert_eq!(errors[1].get("line"), None);
+    }
+}
@@ -0,0 +1,140 @@
+//! A cap on how often one path is asked about.
+//!
+//! A watcher or test runner that opens and closes one file hundreds of
+//! times a second asks about it that often, cache or not, when the server
+//! answers allow-once. So each path that gets a blocking preflight has a
+//! token bucket: `preflight_burst` tokens, refilled at `preflight_rate`
+//! a second (0, the default, turns this off). A preflight that finds the
+//! bucket empty isn't sent. It gets the last answer the server gave for
+//! that path, or the fail policy when there was none, and its post says
+//! `"rate_limited": true`. Conflicts are always asked.
+//!
+//! Buckets are kept for at most `CAP` paths, within the `heap` cap; past
+//! that, the one used longest ago goes. Each counts the preflights it
+//! held back, for `shim/stats`.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde_json::{json, Map, Value};
+
+use crate::{config, heap, Decision};
+
+const CAP: usize = 256;
+
+struct Bucket {
+    tokens: f64,
+    at: Instant,
+    /// The server's last answer for the path; `None` inside is a denial.
+    last: Option<Option<Decision>>,
+    hits: u64,
+}
+
+static BUCKETS: Lazy<Mutex<HashMap<PathBuf, Bucket>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+static HITS: AtomicU64 = AtomicU64::new(0);
+
+fn cost(path: &Path) -> usize {
+    path.as_os_str().len() + heap::ENTRY
+}
+
+/// Take a token for asking about `path`. `Err` when there is none, with
+/// the last answer to stand in for the server's, if there was one.
+pub(crate) fn take(path: &Path) -> Result<(), Option<Option<Decision>>> {
+    let cfg = config::get();
+    if cfg.preflight_rate <= 0.0 {
+        return Ok(());
+    }
+    take_at(
+        path,
+        cfg.preflight_rate,
+        cfg.preflight_burst,
+        Instant::now(),
+    )
+}
+
+fn take_at(
+    path: &Path,
+    rate: f64,
+    burst: u32,
+    now: Instant,
+) -> Result<(), Option<Option<Decision>>> {
+    let burst = f64::from(burst.max(1));
+    let mut buckets = BUCKETS.lock();
+    if !buckets.contains_key(path) {
+        if buckets.len() >= CAP {
+            let oldest = buckets
+                .iter()
+                .min_by_key(|(_, b)| b.at)
+                .map(|(p, _)| p.clone());
+            if let Some(p) = oldest {
+                buckets.remove(&p);
+                heap::release(cost(&p));
+            }
+        }
+        if !heap::charge(cost(path)) {
+            return Ok(());
+        }
+        let bucket = Bucket {
+            tokens: burst,
+            at: now,
+            last: None,
+            hits: 0,
+        };
+        buckets.insert(path.to_path_buf(), bucket);
+    }
+    let b = buckets.get_mut(path).expect("inserted above");
+    let refill = now.saturating_duration_since(b.at).as_secs_f64() * rate;
+    b.tokens = (b.tokens + refill).min(burst);
+    b.at = now;
+    if b.tokens >= 1.0 {
+        b.tokens -= 1.0;
+        return Ok(());
+    }
+    b.hits += 1;
+    HITS.fetch_add(1, Ordering::Relaxed);
+    Err(b.last)
+}
+
+/// The server answered a preflight about `path` with `answer`.
+pub(crate) fn answered(path: &Path, answer: Option<Decision>) {
+    if let Some(b) = BUCKETS.lock().get_mut(path) {
+        b.last = Some(answer);
+    }
+}
+
+/// Preflights held back, in all and per path still kept, for
+/// `shim/stats`.
+pub(crate) fn snapshot() -> Value {
+    let paths: Map<String, Value> = BUCKETS
+        .lock()
+        .iter()
+        .filter(|(_, b)| b.hits > 0)
+        .map(|(p, b)| (p.to_string_lossy().into_owned(), json!(b.hits)))
+        .collect();
+    json!({ "hits": HITS.load(Ordering::Relaxed), "paths": paths })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn an_empty_bucket_stands_in_the_last_answer_until_it_refills() {
+        let path = Path::new("/s/rate_limit.rs");
+        let t0 = Instant::now();
+        assert_eq!(take_at(path, 10.0, 2, t0), Ok(()));
+        assert_eq!(take_at(path, 10.0, 2, t0), Ok(()));
+        assert_eq!(take_at(path, 10.0, 2, t0), Err(None));
+        answered(path, None);
+        assert_eq!(take_at(path, 10.0, 2, t0), Err(Some(None)));
+        let later = t0 + Duration::from_millis(100);
+        assert_eq!(take_at(path, 10.0, 2, later), Ok(()));
+        assert_eq!(take_at(path, 10.0, 2, later), Err(Some(None)));
+        assert_eq!(snapshot()["paths"]["/s/rate_limit.rs"], 3);
+    }
+}
@@ -0,0 +1,110 @@
+//! At-least-once notifications, for servers that ask for them.
+//!
+//! The shim offers `"reliable": true` in `shim/hello`; a server that
+//! answers the same gets every notification on that connection with a
+//! process-wide `params.seq`, and acknowledges with
+//! `{"method": "shim/ack", "params": {"upto": N}}` every so often. Until
+//! then each frame stays in a window here. When a control connection
+//! breaks, the next one to the server replays everything still in the
+//! window, in order; the server discards sequence numbers it has seen.
+//!
+//! The window holds `CAP` frames, within the `heap` cap. Once it is full,
+//! further notifications go out unprotected, as if reliability had not
+//! been negotiated, and are counted; the count is reported as
+//! `shim/overflow` on the next reconnect.
+
+use std::collections::VecDeque;
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+
+use crate::heap;
+
+const CAP: usize = 256;
+
+struct Window {
+    next: u64,
+    frames: VecDeque<(u64, Vec<u8>)>,
+    overflowed: u64,
+}
+
+static WINDOW: Lazy<Mutex<Window>> = Lazy::new(|| {
+    Mutex::new(Window {
+        next: 1,
+        frames: VecDeque::new(),
+        overflowed: 0,
+    })
+});
+
+/// The next sequence number; numbers are never reused within a process.
+pub(crate) fn next_seq() -> u64 {
+    let mut w = WINDOW.lock();
+    let seq = w.next;
+    w.next += 1;
+    seq
+}
+
+/// Hold `payload` (numbered `seq`) until the server acks it. A full window
+/// only counts it.
+pub(crate) fn keep(seq: u64, payload: &[u8]) {
+    let mut w = WINDOW.lock();
+    if w.frames.len() >= CAP || !heap::charge(payload.len()) {
+        w.overflowed += 1;
+        return;
+    }
+    w.frames.push_back((seq, payload.to_vec()));
+}
+
+/// Forget every frame up to and including `upto`; returns how many are
+/// still unacked.
+pub(crate) fn ack(upto: u64) -> usize {
+    let mut w = WINDOW.lock();
+    w.frames.retain(|(seq, f)| {
+        let keep = *seq > upto;
+        if !keep {
+            heap::release(f.len());
+        }
+        keep
+    });
+    w.frames.len()
+}
+
+/// Everything not acked yet, oldest first, for replay on a new connection.
+pub(crate) fn unacked() -> Vec<Vec<u8>> {
+    WINDOW
+        .lock()
+        .frames
+        .iter()
+        .map(|(_, f)| f.clone())
+        .collect()
+}
+
+/// How many notifications went out unprotected since the last call.
+pub(crate) fn take_overflowed() -> u64 {
+    std::mem::take(&mut WINDOW.lock().overflowed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One test owns the process-wide window, so nothing runs alongside it.
+    #[test]
+    fn window_keeps_unacked_frames_in_order() {
+        let seqs: Vec<u64> = (0..CAP).map(|_| next_seq()).collect();
+        let first = seqs[0];
+        for &seq in &seqs {
+            keep(seq, &seq.to_le_bytes());
+        }
+        keep(next_seq(), b"over");
+        assert_eq!(take_overflowed(), 1);
+        assert_eq!(take_overflowed(), 0);
+
+        assert_eq!(ack(first + 1), CAP - 2);
+        let replay = unacked();
+        assert_eq!(replay[0], (first + 2).to_le_bytes());
+        assert_eq!(replay.len(), CAP - 2);
+        assert_eq!(ack(u64::MAX), 0);
+        assert!(unacked().is_empty());
+    }
+}
@@ -0,0 +1,156 @@
+//! Renames that add up to one change.
+//!
+//! Refactoring tools move a file aside as a backup, write a new one in
+//! its place and delete the backup (`a.rs` to `a.rs.bak`, a new `a.rs`,
+//! then `a.rs.bak` unlinked), or move a file in two hops. The plugin sees
+//! unrelated events, and its baseline for `a.rs` gets lost among them. So
+//! each successful rename is remembered by the inode it moved, for
+//! `WINDOW`, and a chain that completes within that time is also reported
+//! as one `post_refactor`, after the raw events it sums up:
+//!
+//! ```json
+//! { "method": "post_refactor",
+//!   "params": { "kind": "rewrite", "path": "/p/a.rs", "backup": "/p/a.rs.bak",
+//!               "synthesized": true } }
+//! { "method": "post_refactor",
+//!   "params": { "kind": "move", "path": "/p/c.rs", "old_path": "/p/a.rs",
+//!               "via": ["/p/b.rs"], "synthesized": true } }
+//! ```
+//!
+//! A `rewrite` is a file renamed away and then unlinked while another file
+//! stands at its first name. A `move` is a file renamed again from where
+//! the last rename left it; each further hop reports the whole chain
+//! again. `"synthesized": true` marks the notification as a summary, so a
+//! server can follow either it or the raw events. At most `CAP` chains
+//! are kept; the oldest goes first.
+
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+
+const WINDOW: Duration = Duration::from_secs(10);
+const CAP: usize = 32;
+
+struct Chain {
+    /// The moved file's (dev, ino).
+    id: (u64, u64),
+    /// Where it started.
+    origin: PathBuf,
+    /// Where it went, in order; the last is where it is.
+    hops: Vec<PathBuf>,
+    at: Instant,
+}
+
+static CHAINS: Mutex<Vec<Chain>> = parking_lot::const_mutex(Vec::new());
+
+fn id(path: &Path) -> Option<(u64, u64)> {
+    std::fs::symlink_metadata(path)
+        .ok()
+        .map(|m| (m.dev(), m.ino()))
+}
+
+/// `old` was just renamed to `new`. The `post_refactor` params when that
+/// moves a file a second time or more.
+pub(crate) fn renamed(old: &Path, new: &Path) -> Option<Value> {
+    let moved = id(new)?;
+    let mut chains = CHAINS.lock();
+    chains.retain(|c| c.at.elapsed() < WINDOW);
+    let i = chains
+        .iter()
+        .position(|c| c.id == moved && c.hops.last().is_some_and(|h| h == old));
+    let Some(i) = i else {
+        if chains.len() >= CAP {
+            chains.remove(0);
+        }
+        chains.push(Chain {
+            id: moved,
+            origin: old.to_path_buf(),
+            hops: vec![new.to_path_buf()],
+            at: Instant::now(),
+        });
+        return None;
+    };
+    let c = &mut chains[i];
+    c.hops.push(new.to_path_buf());
+    c.at = Instant::now();
+    if c.hops.contains(&c.origin) {
+        // Back where it started: nothing moved.
+        chains.remove(i);
+        return None;
+    }
+    let via: Vec<_> = c.hops[..c.hops.len() - 1]
+        .iter()
+        .map(|h| h.to_string_lossy())
+        .collect();
+    Some(json!({
+        "kind": "move",
+        "path": new.to_string_lossy(),
+        "old_path": c.origin.to_string_lossy(),
+        "via": via,
+        "synthesized": true,
+    }))
+}
+
+/// `path` was just unlinked. The `post_refactor` params when it was a
+/// backup, renamed away from a name that now holds another file.
+pub(crate) fn unlinked(path: &Path) -> Option<Value> {
+    let mut chains = CHAINS.lock();
+    if chains.is_empty() {
+        return None;
+    }
+    chains.retain(|c| c.at.elapsed() < WINDOW);
+    let i = chains
+        .iter()
+        .position(|c| c.hops.last().is_some_and(|h| h == path))?;
+    let c = chains.remove(i);
+    let replaced = id(&c.origin).is_some_and(|now| now != c.id);
+    replaced.then(|| {
+        json!({
+            "kind": "rewrite",
+            "path": c.origin.to_string_lossy(),
+            "backup": path.to_string_lossy(),
+            "synthesized": true,
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backups_and_second_hops_complete_a_chain() {
+        let dir = std::env::temp_dir().join(format!("shim-chain-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (a, bak, b, c) = (
+            dir.join("a"),
+            dir.join("a.bak"),
+            dir.join("b"),
+            dir.join("c"),
+        );
+
+        std::fs::write(&a, "old").unwrap();
+        std::fs::rename(&a, &bak).unwrap();
+        assert_eq!(renamed(&a, &bak), None);
+        std::fs::write(&a, "new").unwrap();
+        std::fs::remove_file(&bak).unwrap();
+        let rewrite = unlinked(&bak).unwrap();
+        assert_eq!(rewrite["kind"], "rewrite");
+        assert_eq!(rewrite["path"], a.to_string_lossy().as_ref());
+        assert_eq!(unlinked(&bak), None);
+
+        std::fs::rename(&a, &b).unwrap();
+        assert_eq!(renamed(&a, &b), None);
+        std::fs::rename(&b, &c).unwrap();
+        let moved = renamed(&b, &c).unwrap();
+        assert_eq!(moved["old_path"], a.to_string_lossy().as_ref());
+        assert_eq!(moved["via"], json!([b.to_string_lossy()]));
+        // Deleted with nothing at its first name: no rewrite.
+        std::fs::remove_file(&c).unwrap();
+        assert_eq!(unlinked(&c), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,162 @@
+//! Subtrees answered by a destination of their own.
+//!
+//! In a monorepo there may be one Neovim per package and one agent at the
+//! root. `[[route]]` tables send the preflights and posts for a subtree to
+//! the `[[destination]]` of that `name`; everything else goes to the
+//! primary as before:
+//!
+//! ```toml
+//! [[destination]]
+//! name = "a"
+//! kind = "unix"
+//! address = "/tmp/nvim-a.sock"
+//!
+//! [[route]]
+//! glob = "packages/a/**"
+//! destination = "a"
+//! ```
+//!
+//! Routes name directories: a path takes the first rule whose glob
+//! matches its directory or one above it, and a trailing `/**` is
+//! implied. That makes the answer a function of the directory alone, so
+//! it is worked out once per directory and cached. Each thread keeps its
+//! own connection to each routed destination, as it does to the primary;
+//! only the primary's get notifications replayed after a reconnect.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+
+use crate::breaker::Breaker;
+use crate::{config, glob, log_debug, on_slot, paths, Conn, Destination};
+
+/// Directories remembered before the cache is dropped.
+const CACHED_DIRS: usize = 4096;
+
+struct Target {
+    dest: Destination,
+    /// The peer failed the uid check; nothing more goes to it.
+    untrusted: AtomicBool,
+    breaker: Breaker,
+}
+
+struct Rules {
+    /// Globs without their trailing `/**`, and the target each sends to.
+    rules: Vec<(String, usize)>,
+    targets: Vec<Target>,
+}
+
+static RULES: Lazy<Rules> = Lazy::new(|| {
+    let cfg = config::get();
+    let mut targets: Vec<(&str, Target)> = Vec::new();
+    let mut rules = Vec::new();
+    for r in &cfg.routes {
+        let Some(d) = cfg
+            .destinations
+            .iter()
+            .find(|d| d.name.as_deref() == Some(r.destination.as_str()))
+        else {
+            log_debug(&format!(
+                "shim: route {:?} names no destination {:?}\n",
+                r.glob, r.destination
+            ));
+            continue;
+        };
+        let i = match targets.iter().position(|(n, _)| *n == r.destination) {
+            Some(i) => i,
+            None => {
+                let dest = Destination::from_config(d);
+                let untrusted = AtomicBool::new(false);
+                let breaker = Breaker::new(cfg.send_timeout(Some(d)));
+                targets.push((
+                    &r.destination,
+                    Target {
+                        dest,
+                        untrusted,
+                        breaker,
+                    },
+                ));
+                targets.len() - 1
+            }
+        };
+        let g = r.glob.strip_suffix("/**").unwrap_or(&r.glob);
+        rules.push((g.to_string(), i));
+    }
+    Rules {
+        rules,
+        targets: targets.into_iter().map(|(_, t)| t).collect(),
+    }
+});
+
+static BY_DIR: Lazy<Mutex<HashMap<PathBuf, Option<usize>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+thread_local! {
+    static CONNS: RefCell<Vec<Option<Conn>>> = const { RefCell::new(Vec::new()) };
+}
+
+pub(crate) fn is_empty() -> bool {
+    RULES.rules.is_empty()
+}
+
+/// The target `path` is routed to, if any.
+pub(crate) fn target(path: &Path) -> Option<usize> {
+    if is_empty() {
+        return None;
+    }
+    let cfg = config::get();
+    let path = paths::for_matching(path, cfg.normalize_unicode);
+    let dir = path.parent()?;
+    if let Some(&t) = BY_DIR.lock().get(dir) {
+        return t;
+    }
+    let t = dir.ancestors().find_map(|d| {
+        RULES
+            .rules
+            .iter()
+            .find(|(g, _)| glob::matches(g, d, cfg.case_insensitive))
+            .map(|&(_, i)| i)
+    });
+    let mut cache = BY_DIR.lock();
+    if cache.len() >= CACHED_DIRS {
+        cache.clear();
+    }
+    cache.insert(dir.to_path_buf(), t);
+    t
+}
+
+/// Target `i`'s send timeout and breaker.
+pub(crate) fn breaker(i: usize) -> Option<&'static Breaker> {
+    RULES.targets.get(i).map(|t| &t.breaker)
+}
+
+/// Run `f` on this thread's connection to target `i`. `None` when it is
+/// unreachable, untrusted, or already in use further up this thread's
+/// stack.
+pub(crate) fn with_stream<T>(i: usize, f: impl FnOnce(&mut Conn) -> T) -> Option<T> {
+    let t = RULES.targets.get(i)?;
+    if t.untrusted.load(Ordering::Relaxed) {
+        return None;
+    }
+    CONNS.with(|cell| {
+        let mut conns = cell.try_borrow_mut().ok()?;
+        if conns.len() <= i {
+            conns.resize_with(i + 1, || None);
+        }
+        on_slot(
+            &mut conns[i],
+            &t.dest,
+            &t.breaker,
+            &t.untrusted,
+            false,
+            |conn| {
+                conn.route = Some(i);
+                f(conn)
+            },
+        )
+    })
+}
@@ -0,0 +1,133 @@
+//! Files this process made.
+//!
+//! A process that creates a file (its `pre_create` allowed) and later
+//! opens it again to add more would be asked about a file that didn't
+//! exist before it ran. So each allowed create remembers the file's
+//! (dev, ino), with its path and owner, and a later fd's first write to
+//! that inode, at that path and with that owner, asks nothing. It is
+//! tracked as a notify-only fd would be, and its post says
+//!
+//! ```json
+//! { "method": "post_modify",
+//!   "params": { "path": "/p/out.log", "bytes": 120, "allowed_by": null,
+//!               "self_created": true } }
+//! ```
+//!
+//! A file deleted or renamed away by this process is forgotten, and one
+//! whose path or owner no longer matches is forgotten when next looked at,
+//! so a `chown` by anyone, or another process's rename, is caught too.
+//! At most `CAP` files are kept, within the `heap` cap; past that the set
+//! starts again. A forked child starts with none. `trust_self_created =
+//! false` asks about every file as before.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use parking_lot::Mutex;
+
+use crate::heap;
+
+const CAP: usize = 1024;
+
+struct Made {
+    path: PathBuf,
+    uid: u32,
+}
+
+#[derive(Default)]
+struct Set {
+    pid: i32,
+    files: HashMap<(u64, u64), Made>,
+}
+
+static SET: Mutex<Option<Set>> = parking_lot::const_mutex(None);
+
+fn cost(path: &Path) -> usize {
+    path.as_os_str().len() + heap::ENTRY
+}
+
+fn with<T>(f: impl FnOnce(&mut HashMap<(u64, u64), Made>) -> T) -> T {
+    let pid = unsafe { libc::getpid() };
+    let mut set = SET.lock();
+    let s = set.get_or_insert_with(Set::default);
+    if s.pid != pid {
+        clear(&mut s.files);
+        s.pid = pid;
+    }
+    f(&mut s.files)
+}
+
+fn clear(files: &mut HashMap<(u64, u64), Made>) {
+    files
+        .drain()
+        .for_each(|(_, m)| heap::release(cost(&m.path)));
+}
+
+/// This process's allowed create made `path`, the file at (`dev`, `ino`)
+/// owned by `uid`.
+pub(crate) fn created(dev: u64, ino: u64, path: &Path, uid: u32) {
+    with(|files| {
+        if let Some(old) = files.remove(&(dev, ino)) {
+            heap::release(cost(&old.path));
+        }
+        if files.len() >= CAP {
+            clear(files);
+        }
+        if heap::charge(cost(path)) {
+            let path = path.to_path_buf();
+            files.insert((dev, ino), Made { path, uid });
+        }
+    });
+}
+
+/// Whether the file at (`dev`, `ino`), now `path` owned by `uid`, is one
+/// this process made.
+pub(crate) fn holds(dev: u64, ino: u64, path: &Path, uid: u32) -> bool {
+    with(|files| {
+        let Some(m) = files.get(&(dev, ino)) else {
+            return false;
+        };
+        if m.path == path && m.uid == uid {
+            return true;
+        }
+        if let Some(old) = files.remove(&(dev, ino)) {
+            heap::release(cost(&old.path));
+        }
+        false
+    })
+}
+
+/// `path` was deleted or renamed away.
+pub(crate) fn forget(path: &Path) {
+    with(|files| {
+        files.retain(|_, m| {
+            let keep = m.path != path;
+            if !keep {
+                heap::release(cost(&m.path));
+            }
+            keep
+        })
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_made_file_is_held_until_it_moves_or_changes_hands() {
+        let a = Path::new("/t/self_created/a.log");
+        created(7, 42, a, 1000);
+        assert!(holds(7, 42, a, 1000));
+        assert!(!holds(7, 43, a, 1000));
+        // Chowned: forgotten, even once the owner is back.
+        assert!(!holds(7, 42, a, 0));
+        assert!(!holds(7, 42, a, 1000));
+
+        created(7, 42, a, 1000);
+        forget(a);
+        assert!(!holds(7, 42, a, 1000));
+        created(7, 42, a, 1000);
+        assert!(!holds(7, 42, Path::new("/t/self_created/b.log"), 1000));
+    }
+}
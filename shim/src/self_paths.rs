@@ -0,0 +1,116 @@
+//! Files the server writes itself.
+//!
+//! nvim-claude keeps baselines and session logs on disk, sometimes through
+//! processes that are themselves traced; reporting those writes back to
+//! it makes it react to its own output. So the server names them, as
+//! exact `paths` and `globs`, either in `shim/self_paths` or under a
+//! `"self_paths"` key in the result of any call it answers:
+//!
+//! ```json
+//! { "paths": ["/p/.nvim-claude/baseline.json"], "globs": ["**/.nvim-claude/logs/**"] }
+//! ```
+//!
+//! Both are added to what is already registered unless `"replace": true`.
+//! A match is treated like an ignore glob (see `ShimConfig::ignored_by`,
+//! which consults this list last): no preflights, no posts, counted in
+//! `shim/stats` as `ignored.self_paths` so a loop that still happens can
+//! be seen.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use parking_lot::RwLock;
+use serde_json::{json, Value};
+
+use crate::{config, glob, paths};
+
+/// Entries of each kind kept; past this, further ones are dropped.
+const CAP: usize = 4096;
+
+struct SelfPaths {
+    paths: Vec<PathBuf>,
+    globs: Vec<String>,
+}
+
+static SELF: RwLock<SelfPaths> = parking_lot::const_rwlock(SelfPaths {
+    paths: Vec::new(),
+    globs: Vec::new(),
+});
+/// Whether anything is registered, so the common case takes no lock.
+static ANY: AtomicBool = AtomicBool::new(false);
+
+/// Register what `spec` names; returns how many of each are now held.
+pub(crate) fn apply(spec: &Value) -> Value {
+    let normalize = config::get().normalize_unicode;
+    let mut s = SELF.write();
+    if spec["replace"] == true {
+        s.paths.clear();
+        s.globs.clear();
+    }
+    let strings = |k: &str| -> Vec<String> {
+        spec[k]
+            .as_array()
+            .map(|a| {
+                a.iter()
+                    .filter_map(Value::as_str)
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+    for p in strings("paths") {
+        let p = paths::for_matching(Path::new(&p), normalize).into_owned();
+        if s.paths.len() < CAP && !s.paths.contains(&p) {
+            s.paths.push(p);
+        }
+    }
+    for g in strings("globs") {
+        if s.globs.len() < CAP && !s.globs.contains(&g) {
+            s.globs.push(g);
+        }
+    }
+    ANY.store(
+        !s.paths.is_empty() || !s.globs.is_empty(),
+        Ordering::Relaxed,
+    );
+    json!({ "paths": s.paths.len(), "globs": s.globs.len() })
+}
+
+/// Pick up `"self_paths"` from a call's result, if the server sent one.
+pub(crate) fn from_result(result: &Value) {
+    if let Some(spec) = result.get("self_paths").filter(|v| v.is_object()) {
+        apply(spec);
+    }
+}
+
+/// Whether `path`, already in matching form, is one of the server's.
+pub(crate) fn matches(path: &Path, fold: bool) -> bool {
+    if !ANY.load(Ordering::Relaxed) {
+        return false;
+    }
+    let s = SELF.read();
+    s.paths.iter().any(|p| glob::same_path(path, p, fold))
+        || s.globs.iter().any(|g| glob::matches(g, path, fold))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registrations_add_up_until_replaced() {
+        apply(&json!({ "paths": ["/srv/baseline.json"], "globs": ["/srv/logs/**"] }));
+        let n = apply(&json!({ "paths": ["/srv/baseline.json", "/srv/state"] }));
+        assert_eq!(n, json!({ "paths": 2, "globs": 1 }));
+        assert!(matches(Path::new("/srv/logs/today.log"), false));
+        assert!(matches(Path::new("/srv/state"), false));
+        assert!(!matches(Path::new("/srv/main.rs"), false));
+        from_result(
+            &json!({ "allow": true, "self_paths": { "replace": true, "paths": ["/srv/a"] } }),
+        );
+        assert!(!matches(Path::new("/srv/state"), false));
+        assert!(matches(Path::new("/srv/a"), false));
+        apply(&json!({ "replace": true }));
+        assert!(!matches(Path::new("/srv/a"), false));
+    }
+}
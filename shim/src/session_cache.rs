@@ -0,0 +1,138 @@
+//! Allows that outlive the process they were given to.
+//!
+//! A shell-driven agent runs one short-lived `sed` after another against
+//! the same files, and each starts with an empty allow cache and asks
+//! again. So an allow may carry a token for the server's own memory of
+//! the session:
+//!
+//! ```json
+//! { "allow": true, "cache_token": "s-81f2" }
+//! ```
+//!
+//! The shim keeps the latest token in `cache_token_file` (nowhere when
+//! that is empty, the default), and a process that finds one there
+//! presents it in its `shim/hello` as `cache_token`. The server may then
+//! answer the hello with the allows it has already given:
+//!
+//! ```json
+//! { "framing": "length-prefixed",
+//!   "allow": [{ "op": "pre_modify", "path": "/p/a.rs", "ttl_ms": 30000 }] }
+//! ```
+//!
+//! and each goes into the allow cache for its `ttl_ms`, so the first
+//! write of a command run again is not asked about (`"allowed_by":
+//! "cache"`). `path` is as preflights name it. A process connects before
+//! its first preflight looks at the cache, so the hello's allows are in
+//! by then. Nothing is seeded while the allow cache is off
+//! (`allow_cache_ms = 0`). Entries without a string `op` and `path` and
+//! an integer `ttl_ms` are skipped, and past `SEEDS` the rest are. A
+//! token that isn't a line of at most `MAX_TOKEN` bytes is ignored. The
+//! file is written beside itself and renamed into place, so a process
+//! starting meanwhile reads the old token or the new one, never half.
+
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+
+use crate::{allow_cache, config, internal_io};
+
+const MAX_TOKEN: usize = 256;
+const SEEDS: usize = 1024;
+
+/// The token this process last read or was given.
+static TOKEN: Mutex<Option<String>> = parking_lot::const_mutex(None);
+
+/// Whether this process has sent a `shim/hello` yet.
+static HELLOED: AtomicBool = AtomicBool::new(false);
+
+fn file() -> Option<&'static Path> {
+    let f = config::get().cache_token_file.as_path();
+    (!f.as_os_str().is_empty()).then_some(f)
+}
+
+fn valid(token: &str) -> bool {
+    !token.is_empty() && token.len() <= MAX_TOKEN && !token.contains('\n')
+}
+
+/// Whether a preflight should connect before it looks at the cache, for
+/// the allows the hello may bring.
+pub(crate) fn pending() -> bool {
+    !HELLOED.load(Ordering::Relaxed) && allow_cache::enabled() && file().is_some()
+}
+
+/// Add the session's token, if there is one, to a `shim/hello`.
+pub(crate) fn hello(params: &mut Value) {
+    HELLOED.store(true, Ordering::Relaxed);
+    let Some(file) = file() else {
+        return;
+    };
+    let mut token = TOKEN.lock();
+    if token.is_none() {
+        *token = internal_io::read_to_string(file)
+            .ok()
+            .map(|t| t.trim_end().to_string())
+            .filter(|t| valid(t));
+    }
+    if let Some(t) = token.as_deref() {
+        params["cache_token"] = json!(t);
+    }
+}
+
+/// Take the allows a hello's `reply` hands out; returns how many.
+pub(crate) fn seed(reply: &Value) -> usize {
+    let Some(allows) = reply.get("allow").and_then(Value::as_array) else {
+        return 0;
+    };
+    let mut seeded = 0;
+    for a in allows.iter().take(SEEDS) {
+        let (Some(op), Some(path), Some(ttl)) =
+            (a["op"].as_str(), a["path"].as_str(), a["ttl_ms"].as_u64())
+        else {
+            continue;
+        };
+        allow_cache::seed(op, Path::new(path), Duration::from_millis(ttl));
+        seeded += 1;
+    }
+    seeded
+}
+
+/// Keep the token an allow's `result` carries, when it is a new one.
+pub(crate) fn offered(result: &Value) {
+    let Some(t) = result.get("cache_token").and_then(Value::as_str) else {
+        return;
+    };
+    let Some(file) = file().filter(|_| valid(t)) else {
+        return;
+    };
+    let mut token = TOKEN.lock();
+    if token.as_deref() == Some(t) {
+        return;
+    }
+    *token = Some(t.to_string());
+    if let Some(dir) = file.parent() {
+        let _ = std::fs::create_dir_all(dir);
+    }
+    let _ = internal_io::replace(file, format!("{t}\n").as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hello_replies_seed_only_well_formed_allows() {
+        let reply = json!({ "allow": [
+            { "op": "pre_modify", "path": "/t/session/a", "ttl_ms": 30_000 },
+            { "op": "pre_modify", "path": "/t/session/b" },
+            { "op": 1, "path": "/t/session/c", "ttl_ms": 30_000 },
+            "pre_modify /t/session/d",
+        ] });
+        assert_eq!(seed(&reply), 1);
+        assert_eq!(seed(&json!({ "framing": "newline" })), 0);
+        assert!(valid("s-81f2"));
+        assert!(!valid("") && !valid("a\nb") && !valid(&"x".repeat(MAX_TOKEN + 1)));
+    }
+}
@@ -0,0 +1,63 @@
+//! Preflights once the process is on its way out.
+//!
+//! A tool's `atexit` handlers and static destructors still write files
+//! (caches, lock files, history), and asking about each one holds up the
+//! command's exit for up to `pre_timeout_ms` apiece, for a veto nobody
+//! can act on by then. So once exit has begun (`begin`), a preflight is
+//! sent as a notification, marked
+//!
+//! ```json
+//! { "method": "pre_modify",
+//!   "params": { "path": "/p/.cache", "path_seq": 3, "phase": "shutdown" } }
+//! ```
+//!
+//! and the operation goes ahead, failing closed or not; its post says
+//! `"allowed_by": "shutdown"`. Posts and the summary go out as before, on
+//! a connection of their own once this thread's is torn down.
+//!
+//! Exit begins in the `exit` and `_exit` hooks, which also send the dirty
+//! fds' posts (and, for `_exit`, which runs no handlers, the summary)
+//! before calling through. Returning from `main` doesn't come through
+//! them (libc calls `exit` from inside itself), so a handler registered
+//! at load begins it too, though only for what runs after it: handlers
+//! are run last-registered first. The phase belongs to the pid that
+//! began it, so a `vfork` child's `_exit` leaves its parent asking, and
+//! `_exit` in a child that hasn't `exec`ed (as after a failed `exec`)
+//! sends nothing: it may be sharing its parent's memory.
+
+use std::sync::atomic::{AtomicI32, Ordering};
+
+/// The pid that began exiting; 0 before any has.
+static EXITING: AtomicI32 = AtomicI32::new(0);
+/// The pid the library was loaded in.
+static LOADED: AtomicI32 = AtomicI32::new(0);
+
+/// This process has begun to exit.
+pub(crate) fn begin() {
+    EXITING.store(unsafe { libc::getpid() }, Ordering::Relaxed);
+}
+
+/// Whether this process is exiting.
+#[inline]
+pub(crate) fn active() -> bool {
+    let pid = EXITING.load(Ordering::Relaxed);
+    pid != 0 && pid == unsafe { libc::getpid() }
+}
+
+/// Whether this is the process the library was loaded in, not a child
+/// forked from it.
+pub(crate) fn loaded_here() -> bool {
+    LOADED.load(Ordering::Relaxed) == unsafe { libc::getpid() }
+}
+
+/// Begin exit from an `atexit` handler too. From the library constructor.
+pub(crate) fn register() {
+    LOADED.store(unsafe { libc::getpid() }, Ordering::Relaxed);
+    unsafe {
+        libc::atexit(begin_at_exit);
+    }
+}
+
+extern "C" fn begin_at_exit() {
+    begin();
+}
@@ -0,0 +1,216 @@
+//! One preflight per path at a time.
+//!
+//! Eight threads opening the same file and writing to it at once each
+//! reach their first write together, and each used to ask: eight
+//! `pre_modify` prompts for one path. So a preflight about to go out
+//! first `join`s the flight for its op and path. The first thread to
+//! arrive leads, asks as before and `land`s with its answer; the rest park
+//! until it does (or until their own deadline, when they get what their
+//! timeout would have given them) and take that answer as theirs. An
+//! allow reaches them as `"allowed_by": "coalesced"` and a denial denies
+//! them too, while the leader's post says how many it answered for,
+//! `"coalesced_waiters": 7`. The server's allow also went into the allow
+//! cache, so a thread arriving after it landed doesn't ask at all.
+//!
+//! Only blocking preflights take part: one from the reactor thread goes
+//! out without waiting, and has nothing to wait for here either. A forked
+//! child starts with no flights, since their leaders stayed behind.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
+
+use parking_lot::{Condvar, Mutex};
+
+use crate::Decision;
+
+type Key = (String, PathBuf);
+
+#[derive(Default)]
+struct Flight {
+    /// The leader's answer, once it has landed.
+    landed: Option<Option<Decision>>,
+    /// Threads parked on it.
+    waiters: usize,
+    /// The leader went away without an answer.
+    abandoned: bool,
+}
+
+type Shared = Arc<(Mutex<Flight>, Condvar)>;
+
+#[derive(Default)]
+struct Flights {
+    pid: i32,
+    by_key: HashMap<Key, Shared>,
+}
+
+static FLIGHTS: Mutex<Option<Flights>> = parking_lot::const_mutex(None);
+
+/// What `join` makes of this thread.
+pub(crate) enum Turn {
+    /// Ask, then `land` with the answer.
+    Lead(Leader),
+    /// The leader's answer; `None` (as the answer itself) is a denial.
+    Follow(Option<Decision>),
+    /// The deadline came first.
+    TimedOut,
+}
+
+/// The thread asking on behalf of everyone who joins after it. Dropped
+/// without landing (the ask panicked), its waiters get what a timeout
+/// would give them.
+pub(crate) struct Leader {
+    key: Option<Key>,
+    flight: Shared,
+}
+
+/// Lead the flight for `op` on `path`, or wait until `deadline` for the
+/// one already in the air.
+pub(crate) fn join(op: &str, path: &Path, deadline: Instant) -> Turn {
+    let key = (op.to_string(), path.to_path_buf());
+    let pid = unsafe { libc::getpid() };
+    let flight = {
+        let mut flights = FLIGHTS.lock();
+        let f = flights.get_or_insert_with(Flights::default);
+        if f.pid != pid {
+            *f = Flights {
+                pid,
+                ..Flights::default()
+            };
+        }
+        match f.by_key.get(&key) {
+            Some(flight) => {
+                let flight = flight.clone();
+                // Counted before the table is let go, so the leader can't
+                // land uncounting it.
+                flight.0.lock().waiters += 1;
+                flight
+            }
+            None => {
+                let flight = Shared::default();
+                f.by_key.insert(key.clone(), flight.clone());
+                return Turn::Lead(Leader {
+                    key: Some(key),
+                    flight,
+                });
+            }
+        }
+    };
+    let (lock, landed) = &*flight;
+    let mut state = lock.lock();
+    loop {
+        if let Some(answer) = state.landed {
+            return Turn::Follow(answer);
+        }
+        if state.abandoned
+            || landed.wait_until(&mut state, deadline).timed_out() && state.landed.is_none()
+        {
+            break;
+        }
+    }
+    state.waiters -= 1;
+    Turn::TimedOut
+}
+
+impl Leader {
+    /// Hand `answer` to everyone waiting; returns how many were.
+    pub(crate) fn land(mut self, answer: Option<Decision>) -> usize {
+        self.release(Some(answer))
+    }
+
+    fn release(&mut self, answer: Option<Option<Decision>>) -> usize {
+        let Some(key) = self.key.take() else {
+            return 0;
+        };
+        if let Some(f) = FLIGHTS.lock().as_mut() {
+            if f.by_key
+                .get(&key)
+                .is_some_and(|g| Arc::ptr_eq(g, &self.flight))
+            {
+                f.by_key.remove(&key);
+            }
+        }
+        let (lock, landed) = &*self.flight;
+        let mut state = lock.lock();
+        match answer {
+            Some(answer) => state.landed = Some(answer),
+            // Nobody to take over: each waiter gives up as if timed out.
+            None => state.abandoned = true,
+        }
+        landed.notify_all();
+        state.waiters
+    }
+}
+
+impl Drop for Leader {
+    fn drop(&mut self) {
+        self.release(None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AllowedBy;
+    use std::sync::Barrier;
+    use std::time::Duration;
+
+    fn key_waiters(op: &str, path: &Path) -> usize {
+        let flights = FLIGHTS.lock();
+        flights
+            .as_ref()
+            .and_then(|f| f.by_key.get(&(op.to_string(), path.to_path_buf())))
+            .map_or(0, |flight| flight.0.lock().waiters)
+    }
+
+    #[test]
+    fn threads_asking_at_once_share_one_answer() {
+        let path = Path::new("/s/single_flight.rs");
+        let far = Instant::now() + Duration::from_secs(10);
+        let Turn::Lead(leader) = join("pre_modify", path, far) else {
+            panic!("the first thread leads");
+        };
+        let start = Arc::new(Barrier::new(8));
+        let followers: Vec<_> = (0..7)
+            .map(|_| {
+                let start = start.clone();
+                std::thread::spawn(move || {
+                    start.wait();
+                    match join("pre_modify", path, far) {
+                        Turn::Follow(answer) => answer,
+                        _ => panic!("a later thread follows"),
+                    }
+                })
+            })
+            .collect();
+        start.wait();
+        while key_waiters("pre_modify", path) < 7 {
+            std::thread::yield_now();
+        }
+        let allow = Decision {
+            by: Some(AllowedBy::Server),
+            ..Decision::default()
+        };
+        assert_eq!(leader.land(Some(allow)), 7);
+        for f in followers {
+            assert_eq!(f.join().unwrap(), Some(allow));
+        }
+
+        // Landed, so the next thread leads a flight of its own; its
+        // waiters give up at their deadline, and a denial reaches the
+        // ones still there.
+        let Turn::Lead(leader) = join("pre_modify", path, far) else {
+            panic!("a landed flight is over");
+        };
+        let soon = Instant::now() + Duration::from_millis(20);
+        assert!(matches!(join("pre_modify", path, soon), Turn::TimedOut));
+        assert!(matches!(join("pre_delete", path, soon), Turn::Lead(_)));
+        let denied = std::thread::spawn(move || join("pre_modify", path, far));
+        while key_waiters("pre_modify", path) < 1 {
+            std::thread::yield_now();
+        }
+        assert_eq!(leader.land(None), 1);
+        assert!(matches!(denied.join().unwrap(), Turn::Follow(None)));
+    }
+}
@@ -0,0 +1,200 @@
+//! Socket path templates for `NVIM_CLAUDE_SHIM_SOCK`.
+//!
+//! The value is a colon-separated list of candidates, tried in order at
+//! connect time, so a per-project socket can fall back to a global one:
+//!
+//! ```text
+//! NVIM_CLAUDE_SHIM_SOCK='${PROJECT_ROOT}/.nvim-claude/%p.sock:/tmp/nvim-claude-%u.sock'
+//! ```
+//!
+//! Each candidate may contain:
+//!
+//! - `%p`: pid of the traced process's session leader (`getsid(0)`),
+//! - `%u`: real uid,
+//! - `%%`: a literal `%`,
+//! - `${NAME}`: the value of environment variable `NAME`.
+//!
+//! A candidate that names an unset variable, or has a malformed
+//! placeholder, is skipped rather than connected to half-expanded.
+//!
+//! With neither `NVIM_CLAUDE_SHIM_SOCK` nor `NVIM_CLAUDE_SHIM_TCP` set, the
+//! conventional locations from `conventional` are probed instead (unless
+//! `NVIM_CLAUDE_SHIM_NO_DISCOVERY=1`).
+//!
+//! Shared with `shim-run` via `#[path]`, so this file must not reach into
+//! the rest of the dylib.
+
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+
+/// What the placeholders expand to; `from_process` fills it for real use.
+pub(crate) struct Vars<E: Fn(&str) -> Option<String>> {
+    pub sid: u32,
+    pub uid: u32,
+    pub env: E,
+}
+
+pub(crate) fn from_process() -> Vars<impl Fn(&str) -> Option<String>> {
+    Vars {
+        sid: unsafe { libc::getsid(0) }.max(0) as u32,
+        uid: unsafe { libc::getuid() },
+        env: |k: &str| std::env::var(k).ok(),
+    }
+}
+
+/// Expand one candidate.
+pub(crate) fn expand<E: Fn(&str) -> Option<String>>(
+    template: &str,
+    vars: &Vars<E>,
+) -> Result<PathBuf, String> {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(i) = rest.find(['%', '$']) {
+        out.push_str(&rest[..i]);
+        let tail = &rest[i..];
+        if let Some(after) = tail.strip_prefix("${") {
+            let end = after
+                .find('}')
+                .ok_or_else(|| format!("unterminated ${{ in {template:?}"))?;
+            let name = &after[..end];
+            if name.is_empty() {
+                return Err(format!("empty ${{}} in {template:?}"));
+            }
+            out.push_str(&(vars.env)(name).ok_or_else(|| format!("{name} is not set"))?);
+            rest = &after[end + 1..];
+            continue;
+        }
+        if let Some(after) = tail.strip_prefix('$') {
+            out.push('$');
+            rest = after;
+            continue;
+        }
+        match tail.as_bytes().get(1) {
+            Some(b'p') => out.push_str(&vars.sid.to_string()),
+            Some(b'u') => out.push_str(&vars.uid.to_string()),
+            Some(b'%') => out.push('%'),
+            _ => return Err(format!("unknown placeholder in {template:?}")),
+        }
+        rest = &tail[2..];
+    }
+    out.push_str(rest);
+    Ok(PathBuf::from(out))
+}
+
+/// Split `value` into candidates and expand each, dropping (and reporting
+/// through `skip`) the ones that fail.
+pub(crate) fn candidates<E: Fn(&str) -> Option<String>>(
+    value: &str,
+    vars: &Vars<E>,
+    mut skip: impl FnMut(&str),
+) -> Vec<PathBuf> {
+    value
+        .split(':')
+        .filter(|c| !c.is_empty())
+        .filter_map(|c| expand(c, vars).map_err(|e| skip(&e)).ok())
+        .collect()
+}
+
+/// Where a plugin-managed socket lives when nobody told us: the XDG state
+/// dir, its `~/.local/state` default, then a per-uid dir under `$TMPDIR`.
+pub(crate) fn conventional<E: Fn(&str) -> Option<String>>(vars: &Vars<E>) -> Vec<PathBuf> {
+    let nonempty = |k: &str| (vars.env)(k).filter(|v| !v.is_empty());
+    let mut out = Vec::new();
+    if let Some(state) = nonempty("XDG_STATE_HOME") {
+        out.push(PathBuf::from(state).join("nvim-claude/shim.sock"));
+    }
+    if let Some(home) = nonempty("HOME") {
+        out.push(PathBuf::from(home).join(".local/state/nvim-claude/shim.sock"));
+    }
+    let tmp = nonempty("TMPDIR").unwrap_or_else(|| "/tmp".into());
+    out.push(PathBuf::from(tmp).join(format!("nvim-claude-{}/shim.sock", vars.uid)));
+    out.dedup();
+    out
+}
+
+/// The first of `paths` that exists and accepts a connection. The probe
+/// connection is dropped; callers reconnect per thread as usual.
+pub(crate) fn discover(paths: &[PathBuf]) -> Option<PathBuf> {
+    paths
+        .iter()
+        .find(|p| p.exists() && UnixStream::connect(p).is_ok())
+        .cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars() -> Vars<impl Fn(&str) -> Option<String>> {
+        Vars {
+            sid: 42,
+            uid: 501,
+            env: |k: &str| (k == "PROJECT_ROOT").then(|| "/work/app".to_string()),
+        }
+    }
+
+    fn x(t: &str) -> Result<String, String> {
+        expand(t, &vars()).map(|p| p.to_string_lossy().into_owned())
+    }
+
+    #[test]
+    fn expands_placeholders() {
+        assert_eq!(x("/tmp/claude-%p.sock").unwrap(), "/tmp/claude-42.sock");
+        assert_eq!(x("/tmp/%u/%p%%.sock").unwrap(), "/tmp/501/42%.sock");
+        assert_eq!(x("${PROJECT_ROOT}/.sock").unwrap(), "/work/app/.sock");
+        assert_eq!(x("/plain/$dollar").unwrap(), "/plain/$dollar");
+    }
+
+    #[test]
+    fn rejects_bad_placeholders() {
+        assert!(x("${MISSING}/s").is_err());
+        assert!(x("${PROJECT_ROOT/s").is_err());
+        assert!(x("${}/s").is_err());
+        assert!(x("/tmp/%q").is_err());
+        assert!(x("/tmp/trailing%").is_err());
+    }
+
+    #[test]
+    fn fallback_list_skips_failures() {
+        let mut skipped = Vec::new();
+        let got = candidates(
+            "${MISSING}/a.sock:${PROJECT_ROOT}/b.sock::/tmp/%u.sock",
+            &vars(),
+            |e| skipped.push(e.to_string()),
+        );
+        assert_eq!(
+            got,
+            [
+                PathBuf::from("/work/app/b.sock"),
+                PathBuf::from("/tmp/501.sock")
+            ]
+        );
+        assert_eq!(skipped, ["MISSING is not set"]);
+    }
+
+    #[test]
+    fn conventional_locations_in_order() {
+        let v = Vars {
+            sid: 1,
+            uid: 501,
+            env: |k: &str| match k {
+                "XDG_STATE_HOME" => Some("/x/state".to_string()),
+                "HOME" => Some("/home/me".to_string()),
+                _ => None,
+            },
+        };
+        assert_eq!(
+            conventional(&v),
+            [
+                PathBuf::from("/x/state/nvim-claude/shim.sock"),
+                PathBuf::from("/home/me/.local/state/nvim-claude/shim.sock"),
+                PathBuf::from("/tmp/nvim-claude-501/shim.sock"),
+            ]
+        );
+    }
+
+    #[test]
+    fn discovery_skips_missing_sockets() {
+        assert_eq!(discover(&[PathBuf::from("/nonexistent/shim.sock")]), None);
+    }
+}
@@ -0,0 +1,148 @@
+//! FIFOs, devices and sockets made in the tree.
+//!
+//! Build tools make FIFOs (and the odd device node) next to the sources,
+//! and every writable fd on one used to cost an `fstat` at open and
+//! another at first write just to learn it isn't a regular file. So
+//! `mkfifo`, `mkfifoat` and `mknod` are hooked: a node they make is
+//! reported once as
+//!
+//! ```json
+//! { "method": "post_create_special",
+//!   "params": { "path": "/p/build.fifo", "node": "fifo", "mode": "0644" } }
+//! ```
+//!
+//! and its path is remembered, so later opens of it skip the `fstat` and
+//! its fds are never tracked. At most `CAP` paths are kept (past that the
+//! table starts again); one is forgotten when this process unlinks or
+//! renames it. A node another process replaces with a regular file is
+//! only noticed once the path is forgotten. `mknod` of a regular file is
+//! nothing special and passes through untouched.
+//!
+//! POSIX shared memory looks enough like a file (a regular file on a
+//! tmpfs, written through an fd) that its writes used to turn into
+//! `post_modify`s for `/dev/shm/name`. So `shm_open` and `shm_unlink`
+//! are hooked too: the fd `shm_open` returns is known here from the
+//! start, like a node's, as is its path on Linux, and a name
+//! `shm_unlink` removes is forgotten. Neither sends anything.
+
+use std::collections::HashSet;
+use std::os::unix::prelude::RawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use parking_lot::Mutex;
+
+const CAP: usize = 256;
+
+struct Known {
+    paths: HashSet<PathBuf>,
+    fds: HashSet<RawFd>,
+}
+
+static KNOWN: Mutex<Option<Known>> = parking_lot::const_mutex(None);
+/// Whether any node is known, so the common case takes no lock.
+static ANY: AtomicBool = AtomicBool::new(false);
+
+/// The node type `mode` asks `mknod` for; `None` for a regular file.
+pub(crate) fn kind(mode: libc::mode_t) -> Option<&'static str> {
+    match mode & libc::S_IFMT {
+        libc::S_IFIFO => Some("fifo"),
+        libc::S_IFCHR => Some("char"),
+        libc::S_IFBLK => Some("block"),
+        libc::S_IFSOCK => Some("socket"),
+        _ => None,
+    }
+}
+
+fn with<T>(f: impl FnOnce(&mut Known) -> T) -> T {
+    let mut known = KNOWN.lock();
+    let k = known.get_or_insert_with(|| Known {
+        paths: HashSet::new(),
+        fds: HashSet::new(),
+    });
+    let out = f(k);
+    ANY.store(!k.paths.is_empty() || !k.fds.is_empty(), Ordering::Relaxed);
+    out
+}
+
+/// This process made a special node at `path`.
+pub(crate) fn created(path: &Path) {
+    with(|k| {
+        if k.paths.len() >= CAP {
+            k.paths.clear();
+        }
+        k.paths.insert(path.to_path_buf());
+    });
+}
+
+/// `path` was unlinked or renamed away.
+pub(crate) fn forget(path: &Path) {
+    if ANY.load(Ordering::Relaxed) {
+        with(|k| k.paths.remove(path));
+    }
+}
+
+/// Whether anything is known; callers check before building a path.
+#[inline]
+pub(crate) fn any() -> bool {
+    ANY.load(Ordering::Relaxed)
+}
+
+/// Whether `path` is a node this process made.
+pub(crate) fn is_path(path: &Path) -> bool {
+    ANY.load(Ordering::Relaxed) && with(|k| k.paths.contains(path))
+}
+
+/// `fd` was just opened on a shared memory object, at `path` where the
+/// platform gives it one.
+pub(crate) fn shm_opened(fd: RawFd, path: Option<&Path>) {
+    with(|k| {
+        k.fds.insert(fd);
+        if let Some(p) = path {
+            if k.paths.len() >= CAP {
+                k.paths.clear();
+            }
+            k.paths.insert(p.to_path_buf());
+        }
+    });
+}
+
+/// `fd` was just opened on a known node.
+pub(crate) fn opened(fd: RawFd) {
+    with(|k| k.fds.insert(fd));
+}
+
+/// Whether `fd` is open on a known node, so not worth an `fstat`.
+#[inline]
+pub(crate) fn is_fd(fd: RawFd) -> bool {
+    ANY.load(Ordering::Relaxed) && with(|k| k.fds.contains(&fd))
+}
+
+/// `fd` was closed, or now names something else.
+pub(crate) fn forget_fd(fd: RawFd) {
+    if ANY.load(Ordering::Relaxed) {
+        with(|k| k.fds.remove(&fd));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_nodes_and_their_fds_are_forgotten_with_them() {
+        assert_eq!(kind(libc::S_IFIFO | 0o644), Some("fifo"));
+        assert_eq!(kind(libc::S_IFREG | 0o644), None);
+        assert_eq!(kind(0o644), None);
+
+        let p = Path::new("/s/build.fifo");
+        created(p);
+        assert!(is_path(p));
+        opened(41);
+        assert!(is_fd(41));
+        forget_fd(41);
+        assert!(!is_fd(41));
+        forget(p);
+        assert!(!is_path(p));
+    }
+}
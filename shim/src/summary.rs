@@ -0,0 +1,276 @@
+//! One record of everything a process touched.
+//!
+//! Neovim keeps an audit entry per process, and rebuilding it from the
+//! event stream means replaying every post. So the shim keeps the tally
+//! itself: each post it sends, and each preflight denied, is noted here
+//! against its reported path, on the way out (`post_params`, and wherever
+//! `after_denied` learns of a denial), so the record can't disagree with
+//! the events. It goes out as
+//!
+//! ```json
+//! { "method": "shim/summary",
+//!   "params": { "pid": 123, "overflow": 0, "paths": [
+//!     { "path": "/p/a.rs", "ops": ["create", "modify"], "bytes": 120 },
+//!     { "path": "/p/b.rs", "ops": [], "bytes": 0, "denied": ["delete"] } ] } }
+//! ```
+//!
+//! at exit (`_exit` included), and after the posts on each `shim/flush`.
+//! `ops` names the posts (`post_create` is `create`), plus `rename_source`
+//! for the `old_path` of a `post_rename`; `bytes` adds up their `bytes`;
+//! `denied` names the denied preflights. At most `CAP` paths are kept,
+//! within the `heap` cap; events on any others are counted in `overflow`.
+//! A forked child starts afresh.
+//!
+//! With `path_history` on, each entry also keeps how many creates and
+//! modifies were posted for it and the unix times in ms of the first and
+//! the last (`"modified": 312, "first_ms": ..., "last_ms": ...`), so a
+//! server that only cares about those for a file a dev server rewrites
+//! all day can skip the events in between and ask. `shim/query_path`
+//! with `{"path": ...}` answers with the path's entry, or `null`. Off by
+//! default, since it costs every entry another allocation.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+
+use crate::op_context::OpContext;
+use crate::{config, heap, with_exit_stream};
+
+const CAP: usize = 1024;
+
+/// Every op a summary can name, in the order it lists them.
+const OPS: [&str; 7] = [
+    "create",
+    "modify",
+    "chmod",
+    "acl",
+    "delete",
+    "rename",
+    "rename_source",
+];
+
+#[derive(Default)]
+struct Entry {
+    /// Bit `i` for `OPS[i]`.
+    ops: u8,
+    denied: u8,
+    bytes: u64,
+    /// Only with `path_history`.
+    history: Option<Box<History>>,
+}
+
+struct History {
+    modified: u64,
+    first_ms: u64,
+    last_ms: u64,
+}
+
+impl Entry {
+    fn record(&self, path: &str) -> Value {
+        let mut v = json!({ "path": path, "ops": names(self.ops), "bytes": self.bytes });
+        if self.denied != 0 {
+            v["denied"] = json!(names(self.denied));
+        }
+        if let Some(h) = &self.history {
+            v["modified"] = json!(h.modified);
+            v["first_ms"] = json!(h.first_ms);
+            v["last_ms"] = json!(h.last_ms);
+        }
+        v
+    }
+
+    /// What `heap` was charged for it.
+    fn cost(&self, path: &str) -> usize {
+        let history = self.history.as_ref().map_or(0, |_| heap::ENTRY);
+        path.len() + heap::ENTRY + history
+    }
+}
+
+#[derive(Default)]
+struct Summary {
+    pid: i32,
+    by_path: HashMap<String, Entry>,
+    overflow: u64,
+}
+
+static SUMMARY: Mutex<Option<Summary>> = parking_lot::const_mutex(None);
+/// Whether anything has been noted, so an idle process sends nothing.
+static ANY: AtomicBool = AtomicBool::new(false);
+
+fn bit(op: &str) -> Option<u8> {
+    let op = op
+        .strip_prefix("post_")
+        .or_else(|| op.strip_prefix("pre_"))
+        .unwrap_or(op);
+    OPS.iter().position(|&o| o == op).map(|i| 1 << i)
+}
+
+fn names(bits: u8) -> Vec<&'static str> {
+    OPS.iter()
+        .enumerate()
+        .filter(|&(i, _)| bits & 1 << i != 0)
+        .map(|(_, &o)| o)
+        .collect()
+}
+
+/// Apply `f` to `path`'s entry, made if there is room.
+fn note(path: &str, f: impl FnOnce(&mut Entry)) {
+    static AT_EXIT: std::sync::Once = std::sync::Once::new();
+    AT_EXIT.call_once(|| unsafe {
+        libc::atexit(summary_at_exit);
+    });
+    let pid = unsafe { libc::getpid() };
+    let mut guard = SUMMARY.lock();
+    let s = guard.get_or_insert_with(Summary::default);
+    if s.pid != pid {
+        for (path, e) in &s.by_path {
+            heap::release(e.cost(path));
+        }
+        *s = Summary {
+            pid,
+            ..Summary::default()
+        };
+    }
+    ANY.store(true, Ordering::Relaxed);
+    if let Some(e) = s.by_path.get_mut(path) {
+        return f(e);
+    }
+    if s.by_path.len() >= CAP || !heap::charge(path.len() + heap::ENTRY) {
+        s.overflow += 1;
+        return;
+    }
+    f(s.by_path.entry(path.to_string()).or_default());
+}
+
+/// A post about to go out, with its params as sent.
+pub(crate) fn posted(method: &str, params: &Value) {
+    let Some(op) = bit(method) else {
+        return;
+    };
+    let modified = config::get().path_history && matches!(method, "post_create" | "post_modify");
+    if let Some(path) = params["path"].as_str() {
+        let bytes = params["bytes"].as_u64().unwrap_or(0);
+        note(path, |e| {
+            e.ops |= op;
+            e.bytes += bytes;
+            if modified {
+                touch(e);
+            }
+        });
+    }
+    if let (Some(old), Some(source)) = (params["old_path"].as_str(), bit("rename_source")) {
+        note(old, |e| e.ops |= source);
+    }
+}
+
+/// Count a modification in `e`'s history, started if there is room.
+fn touch(e: &mut Entry) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64);
+    if e.history.is_none() && heap::charge(heap::ENTRY) {
+        e.history = Some(Box::new(History {
+            modified: 0,
+            first_ms: now,
+            last_ms: now,
+        }));
+    }
+    if let Some(h) = &mut e.history {
+        h.modified += 1;
+        h.last_ms = now;
+    }
+}
+
+/// `op` (a preflight method) on the reported `path` was denied.
+pub(crate) fn denied(op: &str, path: &str) {
+    let Some(op) = bit(op) else {
+        return;
+    };
+    note(path, |e| e.denied |= op);
+}
+
+/// The `shim/summary` params; `None` while nothing has been noted.
+pub(crate) fn params() -> Option<Value> {
+    if !ANY.load(Ordering::Relaxed) {
+        return None;
+    }
+    let guard = SUMMARY.lock();
+    let s = guard.as_ref()?;
+    if s.pid != unsafe { libc::getpid() } {
+        return None;
+    }
+    let mut paths: Vec<(&String, &Entry)> = s.by_path.iter().collect();
+    paths.sort_by_key(|&(p, _)| p);
+    let paths: Vec<Value> = paths.into_iter().map(|(path, e)| e.record(path)).collect();
+    Some(json!({ "pid": s.pid, "overflow": s.overflow, "paths": paths }))
+}
+
+/// `path`'s entry as the summary lists it, for `shim/query_path`.
+pub(crate) fn query(path: &str) -> Option<Value> {
+    let guard = SUMMARY.lock();
+    let s = guard.as_ref()?;
+    if s.pid != unsafe { libc::getpid() } {
+        return None;
+    }
+    s.by_path.get(path).map(|e| e.record(path))
+}
+
+/// Send the summary on the way out, from a hook or handler.
+pub(crate) fn send() {
+    if let Some(params) = params() {
+        let _ = with_exit_stream(|conn| conn.notify("shim/summary", params));
+    }
+}
+
+extern "C" fn summary_at_exit() {
+    // Depth 1, as in a hook, so our own socket I/O passes through.
+    let ctx = OpContext::enter();
+    if !ctx.enabled() || !ctx.is_outermost() {
+        return;
+    }
+    send();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn posts_and_denials_fold_into_one_entry_per_path() {
+        posted("post_create", &json!({ "path": "/s/a.rs", "bytes": 3 }));
+        posted("post_modify", &json!({ "path": "/s/a.rs", "bytes": 4 }));
+        posted(
+            "post_rename",
+            &json!({ "path": "/s/b.rs", "old_path": "/s/a.rs" }),
+        );
+        denied("pre_delete", "/s/b.rs");
+        posted("shim/cwd_changed", &json!({ "path": "/s/c.rs" }));
+
+        let params = params().unwrap();
+        let paths = params["paths"].as_array().unwrap();
+        let a = paths.iter().find(|p| p["path"] == "/s/a.rs").unwrap();
+        assert_eq!(a["ops"], json!(["create", "modify", "rename_source"]));
+        assert_eq!(a["bytes"], 7);
+        assert!(a.get("denied").is_none());
+        let b = paths.iter().find(|p| p["path"] == "/s/b.rs").unwrap();
+        assert_eq!(b["ops"], json!(["rename"]));
+        assert_eq!(b["denied"], json!(["delete"]));
+        assert!(paths.iter().all(|p| p["path"] != "/s/c.rs"));
+    }
+
+    #[test]
+    fn history_counts_modifications_between_the_first_and_last() {
+        let mut e = Entry::default();
+        touch(&mut e);
+        touch(&mut e);
+        let v = e.record("/s/bundle.js");
+        assert_eq!(v["modified"], 2);
+        assert!(v["first_ms"].as_u64().unwrap() <= v["last_ms"].as_u64().unwrap());
+        assert!(Entry::default().record("/s/x").get("modified").is_none());
+        // What `touch` charged.
+        heap::release(heap::ENTRY);
+    }
+}
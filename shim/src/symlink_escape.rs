@@ -0,0 +1,105 @@
+//! Writes a symlink takes out of the project.
+//!
+//! `ln -s ~/.ssh/config ./innocent.txt` and a write to `innocent.txt`
+//! clobbers a file the server would never have allowed by name. So every
+//! open for writing below the project root (see `project`) is compared
+//! with where it landed: the fd's path as the platform resolves it
+//! (`/proc/self/fd` on Linux, `F_GETPATH` on macOS). A path that names a
+//! file inside the root, taken as written (`..` applied, nothing
+//! followed), but lands outside the real root went through a symlink, and
+//! and with `symlink_escape = "block"`, the default, its first write is
+//! refused with `EPERM` without asking anyone (no answer would change
+//! it), as are the fd's later ones. A post says so instead:
+//!
+//! ```json
+//! { "method": "post_modify",
+//!   "params": { "path": "/home/me/.ssh/config", "bytes": 0,
+//!               "allowed_by": null,
+//!               "symlink_escape": { "from": "/p/innocent.txt",
+//!                                   "to": "/home/me/.ssh/config",
+//!                                   "policy": "block" } } }
+//! ```
+//!
+//! With `"ask"` the fd's preflight carries `symlink_escape` instead, and
+//! it is asked about even where it otherwise wouldn't be (an append, a
+//! file this process made), always blocking; the answer stands. `"off"`
+//! doesn't look. What the kernel didn't follow isn't an escape:
+//! an `O_NOFOLLOW` open of a symlink fails, and stays unreported. Raw
+//! `openat2` calls (there is no libc wrapper to interpose) are not seen.
+//! The root is the one found for the working directory the first time a
+//! file is opened for writing; without one nothing is checked.
+
+use std::path::{Component, Path, PathBuf};
+
+use once_cell::sync::Lazy;
+use serde_json::{json, Value};
+
+use crate::config::SymlinkEscape;
+use crate::project;
+
+/// The project root as found and as resolved.
+static ROOT: Lazy<Option<(PathBuf, PathBuf)>> = Lazy::new(|| {
+    let root = project::root()?;
+    let real = std::fs::canonicalize(&root).ok()?;
+    Some((root, real))
+});
+
+/// Whether there is a root to check against.
+pub(crate) fn checking() -> bool {
+    ROOT.is_some()
+}
+
+/// `path` with `.` and `..` applied as written.
+fn lexical(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for c in path.components() {
+        match c {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                out.pop();
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn escapes(raw: &Path, landed: &Path, root: &Path, real: &Path) -> bool {
+    let raw = lexical(raw);
+    (raw.starts_with(root) || raw.starts_with(real)) && !landed.starts_with(real)
+}
+
+/// `raw`, the path an open was given (made absolute), when it named a
+/// file inside the root but `landed` outside it.
+pub(crate) fn check(raw: &Path, landed: &Path) -> Option<PathBuf> {
+    let (root, real) = ROOT.as_ref()?;
+    escapes(raw, landed, root, real).then(|| lexical(raw))
+}
+
+/// The preflight's `symlink_escape`.
+pub(crate) fn params(from: &Path, to: &Path, policy: SymlinkEscape) -> Value {
+    json!({
+        "from": from.to_string_lossy(),
+        "to": to.to_string_lossy(),
+        "policy": policy.name(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_paths_inside_that_land_outside_escape() {
+        let (root, real) = (Path::new("/p"), Path::new("/private/p"));
+        let escape =
+            |raw: &str, landed: &str| escapes(Path::new(raw), Path::new(landed), root, real);
+        assert!(escape("/p/innocent.txt", "/home/me/.ssh/config"));
+        assert!(escape("/p/./src/../a.txt", "/etc/hosts"));
+        assert!(!escape("/p/a.txt", "/private/p/a.txt"));
+        assert!(!escape("/p/link", "/private/p/src/real"));
+        // Outside as written is no escape, however it got there.
+        assert!(!escape("/p/../etc/hosts", "/etc/hosts"));
+        assert!(!escape("/tmp/a", "/private/tmp/a"));
+    }
+}
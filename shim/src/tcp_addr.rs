@@ -0,0 +1,215 @@
+//! TCP destination addresses, checked once.
+//!
+//! `NVIM_CLAUDE_SHIM_TCP` and a `kind = "tcp"` `[[destination]]`'s
+//! `address` are `host:port`, with an IPv6 host in brackets
+//! (`[::1]:7777`). One that doesn't parse, or whose port isn't a number
+//! from 1 to 65535, disables its destination and is reported as a
+//! `shim/config_error` (see `project`), instead of failing inside every
+//! connect. An IP address is used as it is. Any other host is resolved
+//! when the address is parsed, which is the destination's first use, and
+//! the addresses are kept. Once they are `tcp_dns_ttl_ms` old (0: never),
+//! the next connect has them resolved again on a thread of its own and
+//! goes on with the old ones until that is done, so no hook waits on DNS
+//! after the first. A failed lookup keeps the old addresses. A connect
+//! tries each address in turn, starting from the last one that answered.
+
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use crate::config;
+
+#[derive(Debug)]
+struct Resolved {
+    addrs: Vec<SocketAddr>,
+    at: Instant,
+    /// Where the next connect starts.
+    next: usize,
+    /// The process with a lookup running, if any.
+    resolving: Option<libc::pid_t>,
+}
+
+#[derive(Debug)]
+pub(crate) struct TcpAddr {
+    /// Without brackets; TLS's default server name.
+    pub(crate) host: String,
+    port: u16,
+    /// Whether `host` is an IP address, so never looked up.
+    fixed: bool,
+    resolved: Arc<Mutex<Resolved>>,
+}
+
+/// Split `s` into host and port.
+fn split(s: &str) -> Result<(&str, u16), String> {
+    let s = s.trim();
+    let (host, port) = match s.strip_prefix('[') {
+        Some(rest) => {
+            let (host, after) = rest
+                .split_once(']')
+                .ok_or_else(|| format!("{s:?}: unclosed `[`"))?;
+            let port = after
+                .strip_prefix(':')
+                .ok_or_else(|| format!("{s:?}: no port after `]`"))?;
+            if host.parse::<std::net::Ipv6Addr>().is_err() {
+                return Err(format!("{s:?}: {host:?} is not an IPv6 address"));
+            }
+            (host, port)
+        }
+        None => {
+            let (host, port) = s
+                .rsplit_once(':')
+                .ok_or_else(|| format!("{s:?}: no port (host:port)"))?;
+            if host.contains(':') {
+                return Err(format!(
+                    "{s:?}: an IPv6 host goes in brackets, [{host}]:{port}"
+                ));
+            }
+            (host, port)
+        }
+    };
+    if host.is_empty() || host.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return Err(format!("{s:?}: no host"));
+    }
+    match port.parse::<u16>() {
+        Ok(p) if p > 0 => Ok((host, p)),
+        _ => Err(format!("{s:?}: port {port:?} is not 1-65535")),
+    }
+}
+
+/// `host`'s addresses; empty when the lookup fails.
+fn resolve(host: &str, port: u16) -> Vec<SocketAddr> {
+    match (host, port).to_socket_addrs() {
+        Ok(addrs) => addrs.collect(),
+        Err(e) => {
+            crate::log_debug(&format!("shim: resolving {host}: {e}\n"));
+            Vec::new()
+        }
+    }
+}
+
+/// Parse `s`, and look up its host unless it is an IP address.
+pub(crate) fn parse(s: &str) -> Result<TcpAddr, String> {
+    let (host, port) = split(s)?;
+    let ip = host.parse::<IpAddr>().ok();
+    let addrs = match ip {
+        Some(ip) => vec![SocketAddr::new(ip, port)],
+        None => resolve(host, port),
+    };
+    Ok(TcpAddr {
+        host: host.to_string(),
+        port,
+        fixed: ip.is_some(),
+        resolved: Arc::new(Mutex::new(Resolved {
+            addrs,
+            at: Instant::now(),
+            next: 0,
+            resolving: None,
+        })),
+    })
+}
+
+impl TcpAddr {
+    /// The addresses to try, in order, having started a lookup when they
+    /// are stale.
+    fn candidates(&self, now: Instant) -> Vec<SocketAddr> {
+        let mut r = self.resolved.lock();
+        let ttl = Duration::from_millis(config::get().tcp_dns_ttl_ms);
+        let pid = unsafe { libc::getpid() };
+        let stale = r.addrs.is_empty() || (!ttl.is_zero() && now.duration_since(r.at) >= ttl);
+        if !self.fixed && stale && r.resolving != Some(pid) {
+            r.resolving = Some(pid);
+            if !self.refresh() {
+                // Tried again after another `tcp_dns_ttl_ms`.
+                r.at = now;
+                r.resolving = None;
+            }
+        }
+        let n = r.addrs.len();
+        (0..n).map(|i| r.addrs[(r.next + i) % n]).collect()
+    }
+
+    /// Look the host up again on a thread of its own. False when there
+    /// is no thread to do it.
+    fn refresh(&self) -> bool {
+        let (host, port, resolved) = (self.host.clone(), self.port, self.resolved.clone());
+        std::thread::Builder::new()
+            .name("nvim-claude-dns".into())
+            .spawn(move || {
+                // Depth 1: the resolver's own file and socket I/O passes
+                // through the hooks.
+                let _ctx = crate::op_context::OpContext::enter();
+                let addrs = resolve(&host, port);
+                let mut r = resolved.lock();
+                if !addrs.is_empty() {
+                    r.next = r.next.min(addrs.len() - 1);
+                    r.addrs = addrs;
+                }
+                r.at = Instant::now();
+                r.resolving = None;
+            })
+            .is_ok()
+    }
+
+    /// A connection to the first address that takes one.
+    pub(crate) fn connect(&self) -> Option<TcpStream> {
+        let candidates = self.candidates(Instant::now());
+        for addr in &candidates {
+            match TcpStream::connect(addr) {
+                Ok(stream) => {
+                    let mut r = self.resolved.lock();
+                    if let Some(i) = r.addrs.iter().position(|a| a == addr) {
+                        r.next = i;
+                    }
+                    return Some(stream);
+                }
+                // No fds left: the next address won't get one either.
+                Err(e) if crate::own_fds::note(&e) => return None,
+                Err(_) => {}
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses_are_split_and_checked() {
+        assert_eq!(split("dev.example:7777"), Ok(("dev.example", 7777)));
+        assert_eq!(split("[::1]:7777"), Ok(("::1", 7777)));
+        assert_eq!(split(" 127.0.0.1:9 "), Ok(("127.0.0.1", 9)));
+        for bad in [
+            "localhost",
+            "localhost:",
+            "localhost:0",
+            "localhost:70000",
+            ":7777",
+            "::1:7777",
+            "[::1]7777",
+            "[nope]:7777",
+        ] {
+            assert!(split(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn connects_rotate_past_addresses_that_refuse() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let open = listener.local_addr().unwrap();
+        // Bound and then dropped, so nothing listens there.
+        let closed = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let addr = parse(&format!("127.0.0.1:{}", open.port())).unwrap();
+        assert!(addr.fixed);
+        addr.resolved.lock().addrs = vec![closed, open];
+        assert!(addr.connect().is_some());
+        assert_eq!(addr.resolved.lock().next, 1);
+        assert_eq!(addr.candidates(Instant::now()), [open, closed]);
+    }
+}
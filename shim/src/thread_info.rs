@@ -0,0 +1,138 @@
+//! Which thread an event came from.
+//!
+//! A language server and its formatter thread can touch files in the same
+//! process, and a pid alone can't tell their events apart. When the server
+//! takes `threads` in `shim/hello`, every preflight and post carries `tid`
+//! (the OS thread id), `thread_name` when the thread has one, and `conn`,
+//! the id of the control connection that carried it. Each thread has its
+//! own connection, except for an inherited fd, which all threads share. A
+//! post sent by `shim/flush` names the flushing thread, not the writer.
+//!
+//! Both are looked up on a thread's first event and cached; a name set
+//! after that isn't seen. The cache is keyed by pid so a forked child
+//! doesn't report its parent's thread.
+//!
+//! The name also picks out runtime reactor threads (tokio workers,
+//! `NSURLSession`'s), where a thread blocked on a preflight stalls every
+//! task it runs. Their preflights are all sent the way `async_pre` sends
+//! a nonblocking fd's. Which threads count is `reactor_threads`, a list of
+//! globs on the name; the libdispatch manager thread, which is unnamed,
+//! always does.
+
+use std::cell::{Cell, RefCell};
+use std::os::raw::c_char;
+use std::path::Path;
+
+use serde_json::{json, Value};
+
+use crate::{config, glob, platform};
+
+/// The queue the libdispatch manager thread serves.
+const DISPATCH_MANAGER: &str = "com.apple.libdispatch-manager";
+
+/// Longer than either platform allows (16 on Linux, 64 on macOS).
+const NAME_MAX: usize = 64;
+
+struct Info {
+    pid: libc::pid_t,
+    tid: u64,
+    name: Option<String>,
+}
+
+thread_local! {
+    static INFO: RefCell<Option<Info>> = const { RefCell::new(None) };
+    /// A fork keeps the calling thread, name and all, so this needs no pid.
+    static REACTOR: Cell<Option<bool>> = const { Cell::new(None) };
+}
+
+fn name() -> Option<String> {
+    let mut buf = [0u8; NAME_MAX];
+    let rc = unsafe {
+        libc::pthread_getname_np(
+            libc::pthread_self(),
+            buf.as_mut_ptr() as *mut c_char,
+            buf.len(),
+        )
+    };
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    (rc == 0 && len > 0).then(|| String::from_utf8_lossy(&buf[..len]).into_owned())
+}
+
+/// Whether the calling thread runs a reactor, so must never wait for a
+/// preflight. Decided on the thread's first preflight.
+pub(crate) fn on_reactor() -> bool {
+    if let Some(known) = REACTOR.get() {
+        return known;
+    }
+    let named = name().is_some_and(|n| {
+        let n = Path::new(&n);
+        config::get()
+            .reactor_threads
+            .iter()
+            .any(|g| glob::matches(g, n, false))
+    });
+    let reactor = named || platform::queue_label().as_deref() == Some(DISPATCH_MANAGER);
+    REACTOR.set(Some(reactor));
+    reactor
+}
+
+/// Add the calling thread, and `conn` as the connection carrying it.
+pub(crate) fn tag(params: &mut Value, conn: u64) {
+    let pid = unsafe { libc::getpid() };
+    INFO.with(|cell| {
+        let mut info = cell.borrow_mut();
+        if info.as_ref().is_none_or(|i| i.pid != pid) {
+            *info = Some(Info {
+                pid,
+                tid: platform::thread_id(),
+                name: name(),
+            });
+        }
+        let Some(info) = info.as_ref() else { return };
+        params["tid"] = json!(info.tid);
+        if let Some(name) = &info.name {
+            params["thread_name"] = json!(name);
+        }
+        params["conn"] = json!(conn);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn threads_are_told_apart_and_named() {
+        let tag_here = || {
+            let mut params = json!({});
+            tag(&mut params, 7);
+            params
+        };
+        let main = tag_here();
+        let other = std::thread::Builder::new()
+            .name("formatter".into())
+            .spawn(tag_here)
+            .unwrap()
+            .join()
+            .unwrap();
+        assert_ne!(main["tid"], other["tid"]);
+        assert_eq!(other["thread_name"], "formatter");
+        assert_eq!(other["conn"], 7);
+        // Cached: the same answer each time.
+        assert_eq!(tag_here(), main);
+    }
+
+    #[test]
+    fn tokio_workers_are_reactors() {
+        let on = |name: &str| {
+            std::thread::Builder::new()
+                .name(name.into())
+                .spawn(on_reactor)
+                .unwrap()
+                .join()
+                .unwrap()
+        };
+        assert!(on("tokio-runtime-worker"));
+        assert!(!on("formatter"));
+    }
+}
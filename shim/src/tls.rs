@@ -0,0 +1,231 @@
+//! TLS for the TCP transport (`tls` feature, `NVIM_CLAUDE_SHIM_TCP_TLS=1`).
+//!
+//! The server is authenticated by one or both of:
+//!
+//! - `NVIM_CLAUDE_SHIM_TLS_CA`: a PEM bundle of roots its chain must lead to,
+//!   checked against `NVIM_CLAUDE_SHIM_TLS_SERVER_NAME` (default: the host
+//!   part of `NVIM_CLAUDE_SHIM_TCP`);
+//! - `NVIM_CLAUDE_SHIM_TLS_PIN`: the SHA-256 of its certificate, in hex
+//!   (`:` separators and a `sha256:` prefix are accepted). A pin alone
+//!   accepts a self-signed certificate.
+//!
+//! There is no fallback to system roots. TLS records still move through
+//! the unhooked read/write helpers, exactly like a plain socket.
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::os::unix::prelude::AsRawFd;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{
+    CertificateError, ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore,
+    SignatureScheme,
+};
+
+use crate::internal_io;
+use crate::transport::{Channel, UnhookedIo};
+
+pub(crate) struct TlsChannel {
+    conn: ClientConnection,
+    sock: TcpStream,
+}
+
+impl Channel for TlsChannel {
+    fn send(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        let mut io = UnhookedIo(self.sock.as_raw_fd());
+        let mut tls = rustls::Stream::new(&mut self.conn, &mut io);
+        tls.write_all(buf)?;
+        tls.flush()
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut io = UnhookedIo(self.sock.as_raw_fd());
+        rustls::Stream::new(&mut self.conn, &mut io).read(buf)
+    }
+
+    fn fd(&self) -> std::os::unix::prelude::RawFd {
+        self.sock.as_raw_fd()
+    }
+}
+
+/// Built once per process; an error here fails every connect the same way.
+static CONFIG: Lazy<Result<Arc<ClientConfig>, String>> = Lazy::new(client_config);
+
+/// Handshake over a connected `sock`, bounded by `timeout`.
+pub(crate) fn connect(
+    sock: TcpStream,
+    host: &str,
+    timeout: Duration,
+) -> Result<TlsChannel, String> {
+    let config = CONFIG.as_ref().map_err(Clone::clone)?;
+    let name = match std::env::var("NVIM_CLAUDE_SHIM_TLS_SERVER_NAME") {
+        Ok(n) => n,
+        Err(_) => host.to_string(),
+    };
+    let name = ServerName::try_from(name).map_err(|e| format!("server name: {e}"))?;
+    let mut conn = ClientConnection::new(config.clone(), name).map_err(|e| e.to_string())?;
+
+    sock.set_read_timeout(Some(timeout)).ok();
+    sock.set_write_timeout(Some(timeout)).ok();
+    let mut io = UnhookedIo(sock.as_raw_fd());
+    while conn.is_handshaking() {
+        conn.complete_io(&mut io).map_err(|e| e.to_string())?;
+    }
+    sock.set_read_timeout(None).ok();
+    sock.set_write_timeout(None).ok();
+    Ok(TlsChannel { conn, sock })
+}
+
+fn client_config() -> Result<Arc<ClientConfig>, String> {
+    let provider = Arc::new(ring::default_provider());
+    let ca = std::env::var_os("NVIM_CLAUDE_SHIM_TLS_CA");
+    let pin = std::env::var("NVIM_CLAUDE_SHIM_TLS_PIN")
+        .ok()
+        .map(|p| parse_pin(&p))
+        .transpose()?;
+
+    let chain = match ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            let pem = internal_io::read(Path::new(&path)).map_err(|e| format!("CA: {e}"))?;
+            for cert in CertificateDer::pem_slice_iter(&pem) {
+                roots
+                    .add(cert.map_err(|e| format!("CA: {e}"))?)
+                    .map_err(|e| format!("CA: {e}"))?;
+            }
+            let verifier =
+                WebPkiServerVerifier::builder_with_provider(roots.into(), provider.clone())
+                    .build()
+                    .map_err(|e| format!("CA: {e}"))?;
+            Some(verifier)
+        }
+        None => None,
+    };
+
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?;
+    let config = match (pin, chain) {
+        (Some(pin), chain) => builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(Pinned {
+                pin,
+                chain,
+                provider,
+            }))
+            .with_no_client_auth(),
+        (None, Some(chain)) => builder.with_webpki_verifier(chain).with_no_client_auth(),
+        (None, None) => {
+            return Err("TLS needs NVIM_CLAUDE_SHIM_TLS_CA or NVIM_CLAUDE_SHIM_TLS_PIN".into())
+        }
+    };
+    Ok(Arc::new(config))
+}
+
+/// `host` out of `host:port` or `[v6]:port`.
+fn parse_pin(s: &str) -> Result<[u8; 32], String> {
+    let hex: String = s
+        .trim()
+        .trim_start_matches("sha256:")
+        .chars()
+        .filter(|&c| c != ':')
+        .collect();
+    let bad = || format!("NVIM_CLAUDE_SHIM_TLS_PIN is not a SHA-256 hex digest: {s:?}");
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err(bad());
+    }
+    let mut out = [0u8; 32];
+    for (i, b) in out.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| bad())?;
+    }
+    Ok(out)
+}
+
+/// Accepts exactly the pinned certificate (after the CA chain, if one was
+/// configured). Handshake signatures are still verified.
+#[derive(Debug)]
+struct Pinned {
+    pin: [u8; 32],
+    chain: Option<Arc<WebPkiServerVerifier>>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for Pinned {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if let Some(chain) = &self.chain {
+            chain.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+        }
+        let got = ::ring::digest::digest(&::ring::digest::SHA256, end_entity.as_ref());
+        if got.as_ref() == self.pin {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pins_parse_in_common_spellings() {
+        let hex = "ab".repeat(32);
+        let colons = vec!["AB"; 32].join(":");
+        assert_eq!(parse_pin(&hex).unwrap(), [0xab; 32]);
+        assert_eq!(parse_pin(&format!("sha256:{colons}")).unwrap(), [0xab; 32]);
+        assert!(parse_pin("abcd").is_err());
+        assert!(parse_pin(&"zz".repeat(32)).is_err());
+    }
+}
@@ -0,0 +1,176 @@
+//! Timestamp changes, summed per directory.
+//!
+//! Build systems set timestamps on thousands of files a run (`make`'s
+//! `touch` rules, bazel resetting mtimes), and a post for each would
+//! dwarf everything else the server hears. So a `utimensat`, `utimes`,
+//! `futimens` or `futimes` that succeeds on a path that isn't ignored
+//! sends nothing of its own; it counts toward its directory's
+//!
+//! ```json
+//! { "method": "post_touch",
+//!   "params": { "path": "/p/out", "touches": 250, "files": 250,
+//!               "sample": ["a.o", "b.o"], "synthesized": true } }
+//! ```
+//!
+//! `touches` counts the calls, `files` the distinct paths, and `sample`
+//! names up to `SAMPLE` of them, sorted. The touches in the
+//! `touch_debounce_ms` after the first are summed into one (see `batch`),
+//! sent after a touch once that has passed, or on `shim/flush`, or at
+//! exit. Paths matching a `touch_verbose` glob get their own
+//! `post_utimes` as before, and so does an fd whose file is gone, having
+//! no directory to count toward. At most `DIRS` directories are pending
+//! at a time, within the `heap` cap; a touch in a directory past that has
+//! every pending one sent early. A forked child starts with nothing
+//! pending.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+
+use crate::batch::Batch;
+use crate::op_context::OpContext;
+use crate::{config, heap, post_notify, shutdown};
+
+const DIRS: usize = 256;
+const SAMPLE: usize = 16;
+
+#[derive(Default)]
+struct Dir {
+    batch: Batch,
+    touches: u64,
+}
+
+#[derive(Default)]
+struct Table {
+    pid: i32,
+    dirs: HashMap<PathBuf, Dir>,
+}
+
+static TABLE: Mutex<Option<Table>> = parking_lot::const_mutex(None);
+
+fn cost(dir: &Path) -> usize {
+    dir.as_os_str().len() + heap::ENTRY
+}
+
+fn with_table<T>(f: impl FnOnce(&mut HashMap<PathBuf, Dir>) -> T) -> T {
+    let pid = unsafe { libc::getpid() };
+    let mut table = TABLE.lock();
+    let t = table.get_or_insert_with(Table::default);
+    if t.pid != pid {
+        t.dirs.keys().for_each(|d| heap::release(cost(d)));
+        *t = Table {
+            pid,
+            ..Table::default()
+        };
+    }
+    f(&mut t.dirs)
+}
+
+/// The `post_touch` params for `dir`, which is forgotten.
+fn take(dirs: &mut HashMap<PathBuf, Dir>, dir: &Path) -> Option<Value> {
+    let (dir, mut d) = dirs.remove_entry(dir)?;
+    heap::release(cost(&dir));
+    let (files, kept) = d.batch.take();
+    let sample: Vec<_> = kept
+        .iter()
+        .take(SAMPLE)
+        .filter_map(|f| f.file_name())
+        .map(|n| n.to_string_lossy())
+        .collect();
+    Some(json!({
+        "path": dir.to_string_lossy(),
+        "touches": d.touches,
+        "files": files,
+        "sample": sample,
+        "synthesized": true,
+    }))
+}
+
+fn touched_at(path: &Path, debounce: Duration, now: Instant) -> Vec<Value> {
+    let Some(dir) = path.parent() else {
+        return Vec::new();
+    };
+    with_table(|dirs| {
+        let mut due = Vec::new();
+        if !dirs.contains_key(dir) {
+            if dirs.len() >= DIRS || !heap::charge(cost(dir)) {
+                let all: Vec<PathBuf> = dirs.keys().cloned().collect();
+                due.extend(all.iter().filter_map(|d| take(dirs, d)));
+                if !heap::charge(cost(dir)) {
+                    return due;
+                }
+            }
+            dirs.insert(dir.to_path_buf(), Dir::default());
+        }
+        let Some(d) = dirs.get_mut(dir) else {
+            return due;
+        };
+        d.touches += 1;
+        if d.batch.add(path, debounce, now) {
+            due.extend(take(dirs, dir));
+        }
+        due
+    })
+}
+
+/// A timestamp change to `path` that no post reported. The `post_touch`
+/// params that are due.
+pub(crate) fn touched(path: &Path) -> Vec<Value> {
+    static AT_EXIT: std::sync::Once = std::sync::Once::new();
+    AT_EXIT.call_once(|| unsafe {
+        libc::atexit(touched_at_exit);
+    });
+    let debounce = Duration::from_millis(config::get().touch_debounce_ms);
+    touched_at(path, debounce, Instant::now())
+}
+
+/// The `post_touch` params for every directory with touches not yet
+/// reported, for `shim/flush` and exit.
+pub(crate) fn take_pending() -> Vec<Value> {
+    with_table(|dirs| {
+        let all: Vec<PathBuf> = dirs.keys().cloned().collect();
+        all.iter().filter_map(|d| take(dirs, d)).collect()
+    })
+}
+
+/// Returning from `main` doesn't come through the `exit` hook's flush;
+/// as in `watch_dirs`.
+extern "C" fn touched_at_exit() {
+    shutdown::begin();
+    // Depth 1, as in a hook, so our own socket I/O passes through.
+    let ctx = OpContext::enter();
+    if !ctx.enabled() || !ctx.is_outermost() {
+        return;
+    }
+    for params in take_pending() {
+        post_notify(&ctx, "post_touch", params);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn touches_within_the_debounce_are_summed_per_directory() {
+        let window = Duration::from_millis(100);
+        let t0 = Instant::now();
+        for name in ["/t/out/a.o", "/t/out/b.o", "/t/out/a.o", "/t/gen/c.h"] {
+            assert_eq!(touched_at(Path::new(name), window, t0), Vec::<Value>::new());
+        }
+        let later = t0 + Duration::from_millis(150);
+        let due = touched_at(Path::new("/t/out/d.o"), window, later);
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0]["path"], "/t/out");
+        assert_eq!(due[0]["touches"], 4);
+        assert_eq!(due[0]["files"], 3);
+        assert_eq!(due[0]["sample"], json!(["a.o", "b.o", "d.o"]));
+        let left = take_pending();
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0]["path"], "/t/gen");
+        assert_eq!(left[0]["touches"], 1);
+    }
+}
@@ -0,0 +1,631 @@
+//! The control connections: how a thread reaches its destination.
+//!
+//! A `Channel` is one socket, plain or TLS, read and written past the
+//! shim's own hooks. A `Conn` is a channel plus what its `shim/hello`
+//! negotiated (framing, reliable notifications, thread ids), and sends
+//! notifications, makes calls and serves what the server sends while a
+//! call waits (see `demux`). Each thread keeps its own connection to the
+//! primary destination (`with_thread_stream`) and, through `routes`, to
+//! each routed one; `on_slot` replaces one that broke, replaying what the
+//! server hasn't acked. Which destination that is, and whether to trust
+//! its peer, is decided in `lib.rs`.
+
+use std::cell::RefCell;
+use std::os::raw::c_void;
+use std::os::unix::net::UnixStream;
+use std::os::unix::prelude::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+use serde_json::json;
+
+use crate::breaker::Breaker;
+use crate::demux::Incoming;
+use crate::framing::{self, FrameReader, Framing};
+#[cfg(feature = "tls")]
+use crate::tls;
+use crate::{
+    config, encode_notification, env_flag, heap, log_debug, msgpack, op_context, own_fds,
+    peer_trusted, platform, real_read, real_write, reliable, report_error, routes, shutdown,
+    tcp_addr, thread_info, Destination, Format, DESTINATION, FORMAT, INHERITED, PEER_UNTRUSTED,
+    PRIMARY_BREAKER,
+};
+#[cfg(not(feature = "notify-only"))]
+use crate::{
+    contain, demux, procinfo, self_paths, session_cache, RpcCall, BUILD_ID, NEXT_ID,
+    PRE_TIMEOUT_MS, PROTOCOL_VERSION,
+};
+
+/// One control connection as the RPC helpers see it. Plain sockets go
+/// straight through the unhooked syscalls; a TLS session (`tls` feature)
+/// encrypts on top of the same syscalls.
+pub(crate) trait Channel: Send {
+    fn send(&mut self, buf: &[u8]) -> std::io::Result<()>;
+    fn recv(&mut self, buf: &mut [u8]) -> std::io::Result<usize>;
+    /// The socket underneath, for receive timeouts.
+    fn fd(&self) -> RawFd;
+}
+
+/// A plain socket: owned (the stream keeps it open) or a borrowed `RawFd`.
+pub(crate) struct PlainChannel<S: AsRawFd + Send>(pub(crate) S);
+
+impl<S: AsRawFd + Send> Channel for PlainChannel<S> {
+    fn send(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        write_unhooked(self.0.as_raw_fd(), buf)
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        read_unhooked(self.0.as_raw_fd(), buf)
+    }
+
+    fn fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+/// One `recv` that gives up at `deadline` even if nothing arrives: the
+/// socket's receive timeout is set to what is left first. Buffered TLS
+/// plaintext is returned without touching the socket.
+fn recv_until(ch: &mut dyn Channel, deadline: Instant, buf: &mut [u8]) -> std::io::Result<usize> {
+    let left = deadline.saturating_duration_since(Instant::now());
+    if left.is_zero() {
+        return Err(std::io::ErrorKind::TimedOut.into());
+    }
+    let tv = libc::timeval {
+        tv_sec: left.as_secs() as libc::time_t,
+        tv_usec: left.subsec_micros().max(1) as libc::suseconds_t,
+    };
+    unsafe {
+        libc::setsockopt(
+            ch.fd(),
+            libc::SOL_SOCKET,
+            libc::SO_RCVTIMEO,
+            &tv as *const libc::timeval as *const c_void,
+            std::mem::size_of::<libc::timeval>() as libc::socklen_t,
+        );
+    }
+    match ch.recv(buf) {
+        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+            Err(std::io::ErrorKind::TimedOut.into())
+        }
+        r => r,
+    }
+}
+
+/// A channel plus what was negotiated on it.
+pub(crate) struct Conn {
+    ch: Box<dyn Channel>,
+    framing: Framing,
+    /// Notifications are numbered and kept until acked (`reliable`).
+    reliable: bool,
+    /// Preflights and posts name their thread and this connection.
+    threads: bool,
+    /// Names the connection in events, when `threads` is on.
+    id: u64,
+    /// A send failed, or a receive for a reason other than a timeout;
+    /// the connection is replaced before its next use.
+    broken: bool,
+    /// Its destination's send timeout and breaker.
+    breaker: &'static Breaker,
+    /// The `routes` target it goes to; `None` for the primary.
+    pub(crate) route: Option<usize>,
+    reader: FrameReader,
+    max_frame: usize,
+    /// Counts the fd in `own_fds`, when the connection opened it.
+    held: Option<own_fds::Held>,
+}
+
+impl Conn {
+    /// Newline framing until `hello` says otherwise.
+    pub(crate) fn new(ch: Box<dyn Channel>, breaker: &'static Breaker) -> Conn {
+        let max_frame = config::get().max_frame_bytes;
+        breaker.arm(ch.fd());
+        Conn {
+            ch,
+            framing: Framing::Newline,
+            reliable: false,
+            threads: false,
+            id: NEXT_CONN_ID.fetch_add(1, Ordering::Relaxed),
+            broken: false,
+            breaker,
+            route: None,
+            reader: FrameReader::new(max_frame),
+            max_frame,
+            held: None,
+        }
+    }
+
+    /// `params` naming the calling thread and this connection, if the
+    /// server asked for that.
+    pub(crate) fn with_thread(&self, mut params: serde_json::Value) -> serde_json::Value {
+        if self.threads {
+            thread_info::tag(&mut params, self.id);
+        }
+        params
+    }
+
+    /// Send one payload from `encode_notification` or a request.
+    pub(crate) fn send(&mut self, payload: &[u8]) -> std::io::Result<()> {
+        let res = match *FORMAT {
+            Format::Json => {
+                let (framing, max) = (self.framing, self.max_frame);
+                let ch = &mut self.ch;
+                heap::with_scratch(|frame| {
+                    framing.encode_into(payload, max, frame)?;
+                    ch.send(frame)
+                })
+            }
+            Format::MsgpackRpc => self.ch.send(payload),
+        };
+        res.inspect_err(|e| {
+            // The send timeout (see `breaker`); part of the frame may be
+            // out, so `note_error` takes the connection down too.
+            if e.kind() == std::io::ErrorKind::WouldBlock && self.breaker.timed_out(Instant::now())
+            {
+                log_debug("shim: destination stopped reading; dropping posts for a while\n");
+            }
+            self.note_error(e)
+        })
+    }
+
+    /// Send a notification; on a reliable connection it is numbered and
+    /// kept for replay until the server acks it.
+    pub(crate) fn notify(&mut self, method: &str, mut params: serde_json::Value) {
+        if !self.reliable {
+            if let Some(payload) = encode_notification(method, params) {
+                let _ = self.send(&payload);
+            }
+            return;
+        }
+        let seq = reliable::next_seq();
+        params["seq"] = json!(seq);
+        if let Some(payload) = encode_notification(method, params) {
+            reliable::keep(seq, &payload);
+            let _ = self.send(&payload);
+        }
+    }
+
+    /// After reconnecting: everything the server hasn't acked, in order,
+    /// and how many notifications went out unprotected meanwhile.
+    fn replay(&mut self) {
+        if !self.reliable {
+            return;
+        }
+        let overflowed = reliable::take_overflowed();
+        if overflowed > 0 {
+            let params = json!({ "pid": unsafe { libc::getpid() }, "count": overflowed });
+            if let Some(payload) = encode_notification("shim/overflow", params) {
+                let _ = self.send(&payload);
+            }
+        }
+        let frames = reliable::unacked();
+        log_debug(&format!("shim: replaying {} notifications\n", frames.len()));
+        for frame in frames {
+            if self.send(&frame).is_err() {
+                return;
+            }
+        }
+    }
+
+    fn note_error(&mut self, e: &std::io::Error) {
+        // Oversized frames are refused before anything is written.
+        use std::io::ErrorKind::{InvalidData, TimedOut};
+        if !matches!(e.kind(), TimedOut | InvalidData) {
+            self.broken = true;
+        }
+    }
+
+    /// Send a request and wait for its response, serving whatever else
+    /// the server sends meanwhile. `None` when it couldn't be sent or
+    /// nothing answered by `deadline`; `Err` carries the server's error.
+    #[cfg(not(feature = "notify-only"))]
+    pub(crate) fn call(
+        &mut self,
+        method: &str,
+        params: serde_json::Value,
+        deadline: Instant,
+    ) -> Option<Result<serde_json::Value, serde_json::Value>> {
+        let id = self.request(method, params)?;
+        loop {
+            let msg = match self.recv_incoming(deadline) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    self.note_error(&e);
+                    return None;
+                }
+            };
+            match msg {
+                Incoming::Response { id: got, result } if got.as_u64() == Some(id as u64) => {
+                    if let Ok(r) = &result {
+                        self_paths::from_result(r);
+                    }
+                    return Some(result);
+                }
+                other => demux::dispatch(self, other),
+            }
+        }
+    }
+
+    /// Send a request without waiting; its id, if it went out.
+    #[cfg(not(feature = "notify-only"))]
+    pub(crate) fn request(&mut self, method: &str, params: serde_json::Value) -> Option<u32> {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let payload = match *FORMAT {
+            Format::Json => serde_json::to_vec(&RpcCall {
+                jsonrpc: "2.0",
+                id: Some(id as u64),
+                method,
+                params: Some(params),
+            })
+            .ok()?,
+            Format::MsgpackRpc => msgpack::request(id, method, &params),
+        };
+        self.send(&payload).ok()?;
+        Some(id)
+    }
+
+    /// One message from the buffer or, if none is complete, the socket.
+    #[cfg(not(feature = "notify-only"))]
+    fn recv_incoming(&mut self, deadline: Instant) -> std::io::Result<Incoming> {
+        loop {
+            if let Some(frame) = self.take_incoming()? {
+                return Ok(frame);
+            }
+            let ch = self.ch.as_mut();
+            self.reader
+                .fill(deadline, &mut |buf| recv_until(ch, deadline, buf))?;
+        }
+    }
+
+    /// The next complete message already buffered. Frames that aren't
+    /// RPC at all are skipped.
+    fn take_incoming(&mut self) -> std::io::Result<Option<Incoming>> {
+        let (framing, max) = (self.framing, self.max_frame);
+        loop {
+            let parsed = self.reader.take_by(|buf| match *FORMAT {
+                Format::Json => Ok(framing::split(framing, buf, max)?.map(|(frame, used)| {
+                    let msg = serde_json::from_slice(&frame).ok();
+                    (msg.and_then(Incoming::from_json), used)
+                })),
+                Format::MsgpackRpc => Ok(msgpack::decode_prefix(buf)?
+                    .map(|(value, used)| (msgpack::incoming(value), used))),
+            })?;
+            match parsed {
+                Some(Some(frame)) => return Ok(Some(frame)),
+                Some(None) => continue,
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Serve whatever the server sent since we last looked, without
+    /// blocking on an idle socket.
+    #[cfg(not(feature = "notify-only"))]
+    fn drain_incoming(&mut self) {
+        loop {
+            match self.take_incoming() {
+                Ok(Some(frame)) => {
+                    demux::dispatch(self, frame);
+                    continue;
+                }
+                Ok(None) => {}
+                Err(e) => return self.note_error(&e),
+            }
+            if !fd_readable(self.ch.fd()) {
+                return;
+            }
+            let deadline = Instant::now() + DRAIN_WAIT;
+            let ch = self.ch.as_mut();
+            if let Err(e) = self
+                .reader
+                .fill(deadline, &mut |buf| recv_until(ch, deadline, buf))
+            {
+                return self.note_error(&e);
+            }
+        }
+    }
+
+    /// Offer framing v2, reliable notifications and thread ids, and switch to what the
+    /// server takes. A server that predates `shim/hello` answers like a
+    /// preflight (or not at all, within the preflight timeout) and keeps
+    /// newlines and fire-and-forget notifications.
+    #[cfg(not(feature = "notify-only"))]
+    fn hello(&mut self) {
+        if *FORMAT != Format::Json {
+            return;
+        }
+        let mut params = procinfo::hello_params(&config::get().hello_env);
+        params["pid"] = json!(unsafe { libc::getpid() });
+        params["version"] = json!(BUILD_ID);
+        params["protocol"] = json!(PROTOCOL_VERSION);
+        params["framing"] = json!(framing::OFFERED);
+        params["max_frame_bytes"] = json!(self.max_frame);
+        params["reliable"] = json!(true);
+        params["threads"] = json!(true);
+        params["hooks"] = contain::hello_params();
+        session_cache::hello(&mut params);
+        let deadline = Instant::now() + Duration::from_millis(*PRE_TIMEOUT_MS);
+        let reply = self
+            .call("shim/hello", params, deadline)
+            .and_then(Result::ok);
+        self.framing = Framing::from_hello(reply.as_ref());
+        self.reliable = reply.as_ref().is_some_and(|r| r["reliable"] == true);
+        self.threads = reply.as_ref().is_some_and(|r| r["threads"] == true);
+        let seeded = reply.as_ref().map_or(0, session_cache::seed);
+        log_debug(&format!(
+            "shim: framing {:?}, reliable {}, threads {}, {seeded} allows seeded\n",
+            self.framing, self.reliable, self.threads
+        ));
+    }
+
+    /// `notify-only` never reads: no hello, so newline framing and
+    /// fire-and-forget notifications, and nothing the server sends is served.
+    #[cfg(feature = "notify-only")]
+    fn hello(&mut self) {}
+
+    #[cfg(feature = "notify-only")]
+    fn drain_incoming(&mut self) {}
+}
+
+static NEXT_CONN_ID: AtomicU64 = AtomicU64::new(1);
+
+/// How long `drain_incoming` waits for the rest of a frame the socket has
+/// started delivering.
+const DRAIN_WAIT: Duration = Duration::from_millis(5);
+
+fn fd_readable(fd: RawFd) -> bool {
+    let mut p = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    unsafe { libc::poll(&mut p, 1, 0) > 0 }
+}
+
+thread_local! {
+    static CTRL: RefCell<Option<Conn>> = const { RefCell::new(None) };
+}
+
+// Use the real write/read on socket fds so we never recurse.
+fn write_unhooked(fd: RawFd, mut buf: &[u8]) -> std::io::Result<()> {
+    unsafe {
+        while !buf.is_empty() {
+            let n = raw_write(fd, buf);
+            if n < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            if n == 0 {
+                break;
+            }
+            buf = &buf[n as usize..];
+        }
+    }
+    Ok(())
+}
+
+/// One `write` past our hook: the next definition, or the raw syscall.
+unsafe fn raw_write(fd: RawFd, buf: &[u8]) -> libc::ssize_t {
+    let ptr = buf.as_ptr() as *const c_void;
+    match real_write() {
+        Some(real) => unsafe { real(fd, ptr, buf.len()) },
+        None => unsafe { platform::sys_write(fd, ptr, buf.len()) },
+    }
+}
+
+fn read_unhooked(fd: RawFd, buf: &mut [u8]) -> std::io::Result<usize> {
+    let ptr = buf.as_mut_ptr() as *mut c_void;
+    // `read` isn't hooked, so libc's own serves when dlsym can't.
+    let n = match real_read() {
+        Some(real) => unsafe { real(fd, ptr, buf.len()) },
+        None => unsafe { libc::read(fd, ptr, buf.len()) },
+    };
+    if n < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(n as usize)
+    }
+}
+
+/// `Read`/`Write` over the unhooked helpers, for transports layered on a
+/// socket fd.
+#[cfg_attr(not(feature = "tls"), allow(dead_code))]
+pub(crate) struct UnhookedIo(pub RawFd);
+
+impl std::io::Read for UnhookedIo {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        read_unhooked(self.0, buf)
+    }
+}
+
+impl std::io::Write for UnhookedIo {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = unsafe { raw_write(self.0, buf) };
+        if n < 0 {
+            Err(std::io::Error::last_os_error())
+        } else {
+            Ok(n as usize)
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+static TCP_TLS: Lazy<bool> = Lazy::new(|| env_flag("NVIM_CLAUDE_SHIM_TCP_TLS"));
+
+/// Why `connect` produced no channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ConnectError {
+    /// Nothing answered; worth retrying later.
+    Unreachable,
+    /// A Unix peer failed the uid check; the destination must be dropped.
+    Untrusted,
+}
+
+/// Open a fresh connection to a path- or address-based destination and
+/// negotiate its framing.
+pub(crate) fn connect(dest: &Destination, breaker: &'static Breaker) -> Result<Conn, ConnectError> {
+    if matches!(dest, Destination::Unix(_) | Destination::Tcp(_)) && !own_fds::admit() {
+        return Err(ConnectError::Unreachable);
+    }
+    let ch = match dest {
+        Destination::Unix(paths) => connect_unix(paths)?,
+        Destination::Tcp(addr) => connect_tcp(addr).ok_or(ConnectError::Unreachable)?,
+        Destination::Fd(_) | Destination::Disabled => return Err(ConnectError::Unreachable),
+    };
+    let mut conn = Conn::new(ch, breaker);
+    conn.held = Some(own_fds::Held::new());
+    conn.hello();
+    Ok(conn)
+}
+
+fn connect_unix(paths: &[PathBuf]) -> Result<Box<dyn Channel>, ConnectError> {
+    let attempt = |p: &PathBuf| UnixStream::connect(p).map_err(|e| own_fds::note(&e)).ok();
+    let Some(stream) = paths.iter().find_map(attempt) else {
+        log_debug("shim: unix connect failed\n");
+        return Err(ConnectError::Unreachable);
+    };
+    if !peer_trusted(stream.as_raw_fd()) {
+        return Err(ConnectError::Untrusted);
+    }
+    log_debug("shim: connected unix socket\n");
+    stream.set_nonblocking(false).ok();
+    Ok(Box::new(PlainChannel(stream)))
+}
+
+fn connect_tcp(addr: &tcp_addr::TcpAddr) -> Option<Box<dyn Channel>> {
+    let stream = addr.connect()?;
+    stream.set_nonblocking(false).ok();
+    if !*TCP_TLS {
+        return Some(Box::new(PlainChannel(stream)));
+    }
+    // Every op retries the connect, so only the first failure is reported.
+    static REPORTED: AtomicBool = AtomicBool::new(false);
+    #[cfg(feature = "tls")]
+    match tls::connect(
+        stream,
+        &addr.host,
+        Duration::from_millis(*crate::PRE_TIMEOUT_MS),
+    ) {
+        Ok(ch) => Some(Box::new(ch)),
+        Err(e) => {
+            if !REPORTED.swap(true, Ordering::Relaxed) {
+                report_error("tls_handshake", &e, None);
+            }
+            None
+        }
+    }
+    #[cfg(not(feature = "tls"))]
+    {
+        drop(stream);
+        if !REPORTED.swap(true, Ordering::Relaxed) {
+            report_error(
+                "tls_unavailable",
+                "shim built without the `tls` feature",
+                None,
+            );
+        }
+        None
+    }
+}
+
+pub(crate) fn with_thread_stream<T>(f: impl FnOnce(&mut Conn) -> T) -> Option<T> {
+    debug_assert!(
+        !op_context::nested(),
+        "shim: the destination reached from a nested context"
+    );
+    if PEER_UNTRUSTED.load(Ordering::Relaxed) {
+        return None;
+    }
+    match &*DESTINATION {
+        Destination::Fd(fd) => {
+            let mut shared = INHERITED.lock();
+            let conn = shared.get_or_insert_with(|| {
+                let mut conn = Conn::new(Box::new(PlainChannel(*fd)), &PRIMARY_BREAKER);
+                conn.hello();
+                conn
+            });
+            conn.drain_incoming();
+            return Some(f(conn));
+        }
+        Destination::Disabled => return None,
+        Destination::Unix(_) | Destination::Tcp(_) => {}
+    }
+    // Busy only when a server request on a routed connection needs the
+    // primary while this thread is already using it.
+    CTRL.with(|cell| {
+        let mut slot = cell.try_borrow_mut().ok()?;
+        on_slot(
+            &mut slot,
+            &DESTINATION,
+            &PRIMARY_BREAKER,
+            &PEER_UNTRUSTED,
+            true,
+            f,
+        )
+    })
+}
+
+/// `with_thread_stream` for an exit handler. By then this thread's own
+/// connection may be torn down (thread-locals go before `atexit`
+/// handlers); if so, a connection just for `f`.
+pub(crate) fn with_exit_stream<T>(f: impl FnOnce(&mut Conn) -> T) -> Option<T> {
+    let own = CTRL.try_with(|_| ()).is_ok();
+    if own || !matches!(&*DESTINATION, Destination::Unix(_) | Destination::Tcp(_)) {
+        return with_thread_stream(f);
+    }
+    if PEER_UNTRUSTED.load(Ordering::Relaxed) {
+        return None;
+    }
+    connect(&DESTINATION, &PRIMARY_BREAKER)
+        .ok()
+        .map(|mut conn| f(&mut conn))
+}
+
+/// Run `f` on the connection in `slot`, first reading what has arrived
+/// on it, or on a fresh one to `dest` if it is missing or broken. A peer
+/// failing the uid check sets `untrusted`. With `replay`, a replacement
+/// for a lost connection resends the unacked notifications.
+pub(crate) fn on_slot<T>(
+    slot: &mut Option<Conn>,
+    dest: &Destination,
+    breaker: &'static Breaker,
+    untrusted: &AtomicBool,
+    replay: bool,
+    f: impl FnOnce(&mut Conn) -> T,
+) -> Option<T> {
+    if let Some(conn) = slot.as_mut() {
+        conn.drain_incoming();
+    }
+    let lost = slot.as_ref().is_some_and(|c| c.broken);
+    if lost {
+        log_debug("shim: control connection lost, reconnecting\n");
+        *slot = None;
+    }
+    if slot.is_none() {
+        match connect(dest, breaker) {
+            Ok(mut conn) => {
+                if lost && replay {
+                    conn.replay();
+                }
+                *slot = Some(conn);
+            }
+            Err(ConnectError::Untrusted) => untrusted.store(true, Ordering::Relaxed),
+            Err(ConnectError::Unreachable) => {}
+        }
+    }
+    slot.as_mut().map(f)
+}
+
+/// `with_thread_stream`, but to the destination a `[[route]]` names for
+/// `path` if there is one.
+pub(crate) fn with_stream_for<T>(path: &Path, f: impl FnOnce(&mut Conn) -> T) -> Option<T> {
+    match routes::target(path) {
+        Some(i) => routes::with_stream(i, f),
+        None if shutdown::active() => with_exit_stream(f),
+        None => with_thread_stream(f),
+    }
+}
@@ -0,0 +1,77 @@
+//! Files a path `truncate` was allowed to cut.
+//!
+//! Clearing a file by path and then opening it to write it back is one
+//! edit, but the open's fd has never seen the `pre_truncate`. So the
+//! server's allowal of a path truncate remembers the file's (dev, ino)
+//! with its path, and the next fd's first write to that inode, still at
+//! that path, asks nothing. Its posts carry the truncate's
+//! `"allowed_by": "server"`. Each allowal covers one fd, however long the
+//! write-back takes; it is forgotten once taken, or when the inode turns
+//! up at another path. A fallback, a cached allow or a failed truncate
+//! remembers nothing. At most `CAP` files are kept, within the `heap` cap;
+//! past that the set starts again.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use parking_lot::Mutex;
+
+use crate::heap;
+
+const CAP: usize = 256;
+
+static CUT: Mutex<Option<HashMap<(u64, u64), PathBuf>>> = parking_lot::const_mutex(None);
+
+fn cost(path: &Path) -> usize {
+    path.as_os_str().len() + heap::ENTRY
+}
+
+fn clear(files: &mut HashMap<(u64, u64), PathBuf>) {
+    files.drain().for_each(|(_, p)| heap::release(cost(&p)));
+}
+
+/// The server allowed truncating `path`, the file at (`dev`, `ino`), and
+/// it was truncated.
+pub(crate) fn allowed(dev: u64, ino: u64, path: &Path) {
+    let mut cut = CUT.lock();
+    let files = cut.get_or_insert_with(HashMap::new);
+    if let Some(old) = files.remove(&(dev, ino)) {
+        heap::release(cost(&old));
+    }
+    if files.len() >= CAP {
+        clear(files);
+    }
+    if heap::charge(cost(path)) {
+        files.insert((dev, ino), path.to_path_buf());
+    }
+}
+
+/// Whether an fd's first write to (`dev`, `ino`), at `path`, is covered by
+/// an allowed truncate. Either way the file is forgotten.
+pub(crate) fn take(dev: u64, ino: u64, path: &Path) -> bool {
+    let mut cut = CUT.lock();
+    let Some(p) = cut.as_mut().and_then(|files| files.remove(&(dev, ino))) else {
+        return false;
+    };
+    heap::release(cost(&p));
+    p == path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn an_allowed_truncate_covers_one_write_back_at_its_path() {
+        let a = Path::new("/t/truncated/a.rs");
+        allowed(9, 42, a);
+        assert!(!take(9, 43, a));
+        assert!(take(9, 42, a));
+        assert!(!take(9, 42, a));
+
+        // Renamed meanwhile: forgotten.
+        allowed(9, 42, a);
+        assert!(!take(9, 42, Path::new("/t/truncated/b.rs")));
+        assert!(!take(9, 42, a));
+    }
+}
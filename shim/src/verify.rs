@@ -0,0 +1,160 @@
+//! A canary for calls that go around the hooks.
+//!
+//! Some runtimes (Go with cgo, custom allocators) look up `write` and
+//! `close` once at startup and keep the pointer. Looked up before
+//! interposition applies, or straight to the syscall stub, their writes
+//! never reach a hook, and one tool's edits go missing without a word.
+//! The shim can't fix that, but it can say so: with `FS_SHIM_VERIFY=1`
+//! the library constructor writes a byte to `/dev/null` and closes it
+//! along each route a host might use, and checks that both calls came
+//! through the hooks. A route that went around them is reported on
+//! stderr and as
+//!
+//! ```json
+//! { "method": "shim/coverage_warning",
+//!   "params": { "pid": 123, "route": "dlsym", "missed": ["write", "close"] } }
+//! ```
+//!
+//! The routes are `libc`, a direct call as the host's own code makes
+//! one, and `dlsym`, a pointer from `dlsym(RTLD_DEFAULT, ...)`. On macOS
+//! only `dlsym` is tried: dyld never interposes calls from the image
+//! that does the interposing, so a direct call from here proves nothing.
+//!
+//! While a probe runs on a thread, every hook there notes itself and
+//! calls straight through, so the canary sends no events of its own.
+
+use std::cell::Cell;
+use std::ffi::CStr;
+use std::os::raw::c_void;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use serde_json::json;
+
+use crate::contain::Hook;
+use crate::op_context::OpContext;
+use crate::{env_flag, log_debug, platform, post_notify, report_error, CloseFn, WriteFn};
+
+/// Whether a probe may be running, so other threads pay one load.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    /// The hooks seen by this thread's running probe; `None` when none runs.
+    static SEEN: Cell<Option<u32>> = const { Cell::new(None) };
+}
+
+/// A way of calling `write` and `close`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Route {
+    #[cfg_attr(target_os = "macos", allow(dead_code))]
+    Libc,
+    Dlsym,
+}
+
+impl Route {
+    fn name(self) -> &'static str {
+        match self {
+            Route::Libc => "libc",
+            Route::Dlsym => "dlsym",
+        }
+    }
+
+    fn calls(self) -> Option<(WriteFn, CloseFn)> {
+        match self {
+            Route::Libc => Some((libc::write, libc::close)),
+            Route::Dlsym => unsafe {
+                let write = lookup(c"write")?;
+                let close = lookup(c"close")?;
+                Some((
+                    std::mem::transmute::<*mut c_void, WriteFn>(write),
+                    std::mem::transmute::<*mut c_void, CloseFn>(close),
+                ))
+            },
+        }
+    }
+}
+
+const ROUTES: &[Route] = if cfg!(target_os = "macos") {
+    &[Route::Dlsym]
+} else {
+    &[Route::Libc, Route::Dlsym]
+};
+
+unsafe fn lookup(name: &CStr) -> Option<*mut c_void> {
+    let sym = unsafe { libc::dlsym(libc::RTLD_DEFAULT, name.as_ptr()) };
+    (!sym.is_null()).then_some(sym)
+}
+
+/// Called by every hook: whether this thread is probing, in which case
+/// `hook` is noted and must pass the call straight through.
+#[inline]
+pub(crate) fn canary(hook: Hook) -> bool {
+    if !ACTIVE.load(Ordering::Relaxed) {
+        return false;
+    }
+    SEEN.try_with(|s| match s.get() {
+        Some(seen) => {
+            s.set(Some(seen | 1 << hook as u32));
+            true
+        }
+        None => false,
+    })
+    .unwrap_or(false)
+}
+
+/// Write and close through `route`; the hooks it went around. `None`
+/// when the route can't be tried.
+pub(crate) fn probe(route: Route) -> Option<Vec<Hook>> {
+    let (write, close) = route.calls()?;
+    let flags = libc::O_WRONLY | libc::O_CLOEXEC;
+    let fd = unsafe { platform::sys_open(c"/dev/null".as_ptr(), flags, 0) };
+    if fd < 0 {
+        return None;
+    }
+    ACTIVE.store(true, Ordering::SeqCst);
+    SEEN.set(Some(0));
+    unsafe {
+        write(fd, b"x".as_ptr() as *const c_void, 1);
+        close(fd);
+    }
+    let seen = SEEN.replace(None).unwrap_or(0);
+    ACTIVE.store(false, Ordering::SeqCst);
+    let missed = [Hook::Write, Hook::Close]
+        .into_iter()
+        .filter(|&h| seen & 1 << h as u32 == 0)
+        .collect();
+    Some(missed)
+}
+
+/// Under `FS_SHIM_VERIFY=1`, probe every route and report the ones that
+/// went around the hooks. From the library constructor.
+pub(crate) fn run() {
+    if !env_flag("FS_SHIM_VERIFY") {
+        return;
+    }
+    for &route in ROUTES {
+        let Some(missed) = probe(route) else {
+            log_debug(&format!("shim: verify: {} not tried\n", route.name()));
+            continue;
+        };
+        if missed.is_empty() {
+            log_debug(&format!("shim: verify: {} hooked\n", route.name()));
+            continue;
+        }
+        let missed: Vec<&str> = missed.iter().map(|h| h.name()).collect();
+        report_error(
+            "coverage_warning",
+            &format!(
+                "{} calls to {} bypass the shim",
+                route.name(),
+                missed.join(", ")
+            ),
+            None,
+        );
+        let params = json!({
+            "pid": unsafe { libc::getpid() },
+            "route": route.name(),
+            "missed": missed,
+        });
+        post_notify(&OpContext::enter(), "shim/coverage_warning", params);
+    }
+}
@@ -0,0 +1,136 @@
+//! Directories reported as a whole.
+//!
+//! Some users want to hear whenever anything under `src/` changes, but
+//! not to be asked about each file. Paths under one of `watch_dirs` get
+//! no blocking preflight; their posts go out as before, and each also
+//! counts toward a `post_dir_changed` for the nearest watched directory
+//! above it:
+//!
+//! ```json
+//! { "method": "post_dir_changed",
+//!   "params": { "path": "/p/src", "files": 12, "synthesized": true } }
+//! ```
+//!
+//! `files` is how many paths beneath it were posted about since the last
+//! one. The changes in the `watch_debounce_ms` after the first are summed
+//! into one (see `batch`), sent after the first post once that has
+//! passed, or on `shim/flush`, or at exit. Which watched directory a path
+//! is under is decided along with its class (`path_class::watched`). A
+//! forked child starts with nothing pending, its parent's changes being
+//! its parent's to report.
+
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+
+use crate::batch::Batch;
+use crate::op_context::OpContext;
+use crate::{config, path_class, post_notify, shutdown};
+
+#[derive(Default)]
+struct Table {
+    pid: i32,
+    /// By index into `watch_dirs`.
+    dirs: Vec<Batch>,
+}
+
+static TABLE: Mutex<Option<Table>> = parking_lot::const_mutex(None);
+
+fn with_dir<T>(i: usize, f: impl FnOnce(&mut Batch) -> T) -> T {
+    let pid = unsafe { libc::getpid() };
+    let mut table = TABLE.lock();
+    let t = table.get_or_insert_with(Table::default);
+    if t.pid != pid {
+        // Dropping the parent's batches gives back their heap charges,
+        // which the child's count started from.
+        *t = Table {
+            pid,
+            ..Table::default()
+        };
+    }
+    if t.dirs.len() <= i {
+        t.dirs.resize_with(i + 1, Batch::default);
+    }
+    f(&mut t.dirs[i])
+}
+
+/// The `post_dir_changed` params for watched directory `dir`, with what
+/// `b` has pending, which starts afresh.
+fn take(dir: &Path, b: &mut Batch) -> Value {
+    let (files, _) = b.take();
+    json!({ "path": dir.to_string_lossy(), "files": files, "synthesized": true })
+}
+
+fn changed_at(
+    i: usize,
+    dir: &Path,
+    path: &Path,
+    debounce: Duration,
+    now: Instant,
+) -> Option<Value> {
+    with_dir(i, |b| b.add(path, debounce, now).then(|| take(dir, b)))
+}
+
+/// A post about `path` just went out. The `post_dir_changed` params for
+/// the watched directory it is under, when one is due.
+pub(crate) fn changed(path: &Path) -> Option<Value> {
+    let i = path_class::watched(path)?;
+    static AT_EXIT: std::sync::Once = std::sync::Once::new();
+    AT_EXIT.call_once(|| unsafe {
+        libc::atexit(changed_at_exit);
+    });
+    let cfg = config::get();
+    let debounce = Duration::from_millis(cfg.watch_debounce_ms);
+    changed_at(i, &cfg.watch_dirs[i], path, debounce, Instant::now())
+}
+
+/// The `post_dir_changed` params for every watched directory with
+/// changes not yet reported, for `shim/flush` and exit.
+pub(crate) fn take_pending() -> Vec<Value> {
+    let cfg = config::get();
+    (0..cfg.watch_dirs.len())
+        .filter_map(|i| with_dir(i, |b| b.pending().then(|| take(&cfg.watch_dirs[i], b))))
+        .collect()
+}
+
+/// Returning from `main` doesn't come through the `exit` hook's flush.
+/// Run before the handler that begins the exit phase, so begins it too:
+/// this thread's connection may already be gone.
+extern "C" fn changed_at_exit() {
+    shutdown::begin();
+    // Depth 1, as in a hook, so our own socket I/O passes through.
+    let ctx = OpContext::enter();
+    if !ctx.enabled() || !ctx.is_outermost() {
+        return;
+    }
+    for params in take_pending() {
+        post_notify(&ctx, "post_dir_changed", params);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changes_within_the_debounce_are_summed_into_one() {
+        let (dir, window) = (Path::new("/w/src"), Duration::from_millis(100));
+        let t0 = Instant::now();
+        for name in ["a.rs", "b.rs", "c.rs", "b.rs"] {
+            assert_eq!(changed_at(7, dir, &dir.join(name), window, t0), None);
+        }
+        let later = t0 + Duration::from_millis(150);
+        let changed = changed_at(7, dir, &dir.join("d.rs"), window, later).unwrap();
+        // b.rs twice is one file.
+        assert_eq!(changed["files"], 4);
+        assert_eq!(changed["path"], "/w/src");
+        // The window starts again at the next change.
+        let next = later + Duration::from_millis(50);
+        assert_eq!(changed_at(7, dir, &dir.join("e.rs"), window, later), None);
+        assert_eq!(changed_at(7, dir, &dir.join("f.rs"), window, next), None);
+        let left = with_dir(7, |p| take(dir, p));
+        assert_eq!(left["files"], 2);
+    }
+}
@@ -0,0 +1,97 @@
+//! Who else is writing a file an fd writes.
+//!
+//! Every post for an fd's writes (`post_modify`, `post_create`) names the
+//! file's `dev` and `ino`, an `op_id` unique to the fd within its process,
+//! and, for an fd the shim saw opened, `opened_ms`, when that was (Unix
+//! milliseconds). That is enough for the server to see two processes
+//! writing one file at once. Within a process the shim sees it itself:
+//! when an fd's writes start while another fd on the same inode, last
+//! started from a different thread, has writes not yet posted, both posts
+//! carry `"concurrent_writers": true` and the other's id as
+//! `concurrent_op_id`. Writes through one fd from several threads, or
+//! through two fds from one thread, aren't flagged.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::json;
+
+use crate::platform;
+
+static NEXT: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Writer {
+    /// 0 until the fd's first write.
+    op_id: u64,
+    /// The thread that started its current run of writes.
+    thread: u64,
+    /// Another fd's `op_id`, while both have writes not yet posted.
+    with: Option<u64>,
+}
+
+/// Now, for `opened_ms`.
+pub(crate) fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+impl Writer {
+    /// This fd's writes start again, on this thread.
+    pub(crate) fn start(&mut self) {
+        if self.op_id == 0 {
+            self.op_id = NEXT.fetch_add(1, Ordering::Relaxed);
+        }
+        self.thread = platform::thread_id();
+        self.with = None;
+    }
+
+    /// Pair with `other`, another fd on the same inode with writes not yet
+    /// posted, unless one thread started both.
+    pub(crate) fn meet(&mut self, other: &mut Writer) -> bool {
+        if self.thread == other.thread {
+            return false;
+        }
+        self.with.get_or_insert(other.op_id);
+        other.with.get_or_insert(self.op_id);
+        true
+    }
+
+    /// The writes were posted.
+    pub(crate) fn posted(&mut self) {
+        self.with = None;
+    }
+
+    /// Add this fd's fields to its post.
+    pub(crate) fn annotate(&self, params: &mut serde_json::Value) {
+        params["op_id"] = json!(self.op_id);
+        if let Some(other) = self.with {
+            params["concurrent_writers"] = json!(true);
+            params["concurrent_op_id"] = json!(other);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_writers_on_different_threads_meet() {
+        let (mut a, mut b) = (Writer::default(), Writer::default());
+        a.start();
+        b.start();
+        assert!(!a.meet(&mut b));
+        b.thread += 1;
+        assert!(a.meet(&mut b));
+        let mut params = json!({});
+        b.annotate(&mut params);
+        assert_eq!(params["op_id"], b.op_id);
+        assert_eq!(params["concurrent_op_id"], a.op_id);
+        b.posted();
+        let mut params = json!({});
+        b.annotate(&mut params);
+        assert_eq!(params.get("concurrent_writers"), None);
+    }
+}
@@ -0,0 +1,86 @@
+//! Moves across filesystems.
+//!
+//! `rename` fails with `EXDEV` when source and destination are on
+//! different filesystems, and tools like `mv` then copy the file and unlink
+//! the source. The shim would see an unrelated write to the destination
+//! and a delete of the source, and the plugin would lose the move. So each
+//! `EXDEV` failure is remembered for `WINDOW`. If the source is unlinked
+//! within that time, and the destination has been written since the
+//! failure, the unlink is also reported as a `post_rename` with
+//! `"via": "copy"`. The copy itself is not tracked, so a copy made with
+//! `copy_file_range` or `sendfile` counts as well.
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use parking_lot::Mutex;
+
+const WINDOW: Duration = Duration::from_secs(10);
+/// Failures remembered at once; the oldest goes first.
+const CAP: usize = 16;
+
+struct Failed {
+    old: PathBuf,
+    new: PathBuf,
+    at: Instant,
+    /// Wall clock at the failure, to compare with the destination's mtime.
+    since: SystemTime,
+}
+
+static FAILED: Mutex<Vec<Failed>> = parking_lot::const_mutex(Vec::new());
+
+/// `rename(old, new)` just failed with `EXDEV`.
+pub(crate) fn failed(old: &Path, new: &Path) {
+    let mut f = FAILED.lock();
+    f.retain(|e| e.at.elapsed() < WINDOW && e.old != old);
+    if f.len() >= CAP {
+        f.remove(0);
+    }
+    f.push(Failed {
+        old: old.to_path_buf(),
+        new: new.to_path_buf(),
+        at: Instant::now(),
+        since: SystemTime::now(),
+    });
+}
+
+/// `old` was just unlinked. The destination, when that finishes a move
+/// that `rename` refused.
+pub(crate) fn unlinked(old: &Path) -> Option<PathBuf> {
+    let mut f = FAILED.lock();
+    if f.is_empty() {
+        return None;
+    }
+    f.retain(|e| e.at.elapsed() < WINDOW);
+    let i = f.iter().position(|e| e.old == old)?;
+    let e = f.remove(i);
+    // Truncated to whole seconds, as coarse mtimes would be.
+    let written = std::fs::symlink_metadata(&e.new)
+        .and_then(|m| m.modified())
+        .is_ok_and(|m| m >= e.since - Duration::from_secs(1));
+    written.then_some(e.new)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_a_written_destination_completes_a_move() {
+        let dir = std::env::temp_dir().join(format!("shim-xdev-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (old, new) = (dir.join("old"), dir.join("new"));
+
+        failed(&old, &new);
+        assert_eq!(unlinked(&dir.join("other")), None);
+        // Never copied: forgotten without a move.
+        assert_eq!(unlinked(&old), None);
+        assert_eq!(unlinked(&old), None);
+
+        failed(&old, &new);
+        std::fs::write(&new, "x").unwrap();
+        assert_eq!(unlinked(&old), Some(new.clone()));
+        assert_eq!(unlinked(&old), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
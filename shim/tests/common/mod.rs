@@ -0,0 +1,1433 @@
+//! Integration harness: a mock JSON-RPC server on a Unix socket plus a fixture
+//! that re-executes the current test binary with the shim preloaded.
+//!
+//! Each test file declares `#[test] fn fixture() { common::fixture_entry() }`;
+//! it is a no-op unless `SHIM_FIXTURE_OPS` is set, in which case it performs
+//! the scripted filesystem operations and prints one `fixture:` line per op.
+
+#![allow(dead_code)]
+
+/// The shim's protocol table, compiled in rather than linked: the shim
+/// crate exports its hooks, and the fixture re-executes this binary.
+#[path = "../../src/conformance.rs"]
+mod conformance;
+
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const OPS_ENV: &str = "SHIM_FIXTURE_OPS";
+/// Between ops: ASCII record separator, so paths may hold newlines.
+const OP_SEP: &str = "\x1e";
+
+//
+// -------- Temp dirs --------
+//
+
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(tag: &str) -> TempDir {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let n = COUNTER.fetch_add(1, Ordering::SeqCst);
+        let dir = std::env::temp_dir().join(format!("ncshim-{tag}-{}-{n}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).expect("create temp dir");
+        // macOS reports /private/var/... from F_GETPATH; compare canonical forms.
+        TempDir(dir.canonicalize().expect("canonicalize temp dir"))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn join(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+//
+// -------- Mock server --------
+//
+
+pub struct MockServer {
+    pub dir: TempDir,
+    pub sock: PathBuf,
+    events: Arc<Mutex<Vec<Value>>>,
+    /// Frames the protocol table refuses, with why; see `checked`.
+    refused: Arc<Mutex<Vec<String>>>,
+    live: Arc<AtomicUsize>,
+}
+
+impl MockServer {
+    /// Allow every preflight.
+    pub fn start() -> MockServer {
+        MockServer::with_denied(&[])
+    }
+
+    /// Answer `allow: false` for the listed `pre_*` methods.
+    pub fn with_denied(deny: &[&str]) -> MockServer {
+        MockServer::spawn_checked(deny, serve_connection)
+    }
+
+    fn spawn(
+        deny: &[&str],
+        serve: impl Fn(UnixStream, &Mutex<Vec<Value>>, &[String]) + Send + Sync + 'static,
+    ) -> MockServer {
+        MockServer::spawn_checked(deny, move |conn, events, deny, _| serve(conn, events, deny))
+    }
+
+    /// `spawn`, for a server that hands each JSON frame it reads to
+    /// `checked` along with the list it records refusals in.
+    fn spawn_checked(
+        deny: &[&str],
+        serve: impl Fn(UnixStream, &Mutex<Vec<Value>>, &[String], &Mutex<Vec<String>>)
+            + Send
+            + Sync
+            + 'static,
+    ) -> MockServer {
+        let dir = TempDir::new("srv");
+        let sock = dir.join("shim.sock");
+        let listener = UnixListener::bind(&sock).expect("bind mock socket");
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let refused = Arc::new(Mutex::new(Vec::new()));
+        let live = Arc::new(AtomicUsize::new(0));
+        let deny: Vec<String> = deny.iter().map(|s| s.to_string()).collect();
+        let serve = Arc::new(serve);
+
+        let (ev, rf, lv) = (events.clone(), refused.clone(), live.clone());
+        std::thread::spawn(move || {
+            for conn in listener.incoming() {
+                let Ok(conn) = conn else { break };
+                lv.fetch_add(1, Ordering::SeqCst);
+                let (ev, rf, lv, deny, serve) = (
+                    ev.clone(),
+                    rf.clone(),
+                    lv.clone(),
+                    deny.clone(),
+                    serve.clone(),
+                );
+                std::thread::spawn(move || {
+                    serve(conn, &ev, &deny, &rf);
+                    lv.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+
+        MockServer {
+            dir,
+            sock,
+            events,
+            refused,
+            live,
+        }
+    }
+
+    /// Like `with_denied`, but takes framing v2 (length-prefixed) when the
+    /// shim offers it in `shim/hello`.
+    pub fn framed(deny: &[&str]) -> MockServer {
+        MockServer::spawn_checked(deny, serve_framed_connection)
+    }
+
+    /// Allow everything, but first send `frame` (a server request or
+    /// notification) when a preflight for `path` comes in, and only then
+    /// answer it.
+    pub fn pushing(path: &str, frame: Value) -> MockServer {
+        let script = Script {
+            push: Some((path.to_string(), frame)),
+            ..Script::default()
+        };
+        MockServer::spawn_checked(&[], move |conn, events, deny, refused| {
+            serve_json(conn, events, deny, refused, &script)
+        })
+    }
+
+    /// Allow everything, with `extra` merged into each answer.
+    pub fn answering(extra: Value) -> MockServer {
+        let script = Script {
+            answer: Some(extra),
+            ..Script::default()
+        };
+        MockServer::spawn_checked(&[], move |conn, events, deny, refused| {
+            serve_json(conn, events, deny, refused, &script)
+        })
+    }
+
+    /// Negotiate reliable notifications and ack each one, but drop the
+    /// connection instead of recording the first notification.
+    pub fn lossy() -> MockServer {
+        let script = Script {
+            reliable: Some(Arc::new(AtomicBool::new(false))),
+            ..Script::default()
+        };
+        MockServer::spawn_checked(&[], move |conn, events, deny, refused| {
+            serve_json(conn, events, deny, refused, &script)
+        })
+    }
+
+    /// Record every frame but leave preflights unanswered.
+    pub fn silent() -> MockServer {
+        let script = Script {
+            silent: true,
+            ..Script::default()
+        };
+        MockServer::spawn_checked(&[], move |conn, events, deny, refused| {
+            serve_json(conn, events, deny, refused, &script)
+        })
+    }
+
+    /// Accept connections but never read from them, as a wedged server
+    /// would, dropping each after a few seconds.
+    pub fn deaf() -> MockServer {
+        MockServer::spawn(&[], |conn, _, _| {
+            std::thread::sleep(Duration::from_secs(5));
+            drop(conn);
+        })
+    }
+
+    /// Allow everything, and take thread ids when `shim/hello` offers them.
+    pub fn threaded() -> MockServer {
+        let script = Script {
+            threads: true,
+            ..Script::default()
+        };
+        MockServer::spawn_checked(&[], move |conn, events, deny, refused| {
+            serve_json(conn, events, deny, refused, &script)
+        })
+    }
+
+    /// Allow everything with a `cache_token`, and answer a `shim/hello`
+    /// that presents it with every allow given so far.
+    pub fn remembering() -> MockServer {
+        let script = Script {
+            session: Some(Arc::new(Mutex::new(Vec::new()))),
+            ..Script::default()
+        };
+        MockServer::spawn_checked(&[], move |conn, events, deny, refused| {
+            serve_json(conn, events, deny, refused, &script)
+        })
+    }
+
+    /// Speak Neovim's msgpack-RPC instead (`NVIM_CLAUDE_SHIM_FORMAT=msgpack-rpc`),
+    /// interleaving the unrelated frames a real Neovim would send. Calls
+    /// are recorded as `{ id?, method, params }` like the JSON server's.
+    pub fn msgpack(deny: &[&str]) -> MockServer {
+        MockServer::spawn(deny, serve_msgpack_connection)
+    }
+
+    /// Every frame received so far, after waiting for open connections to
+    /// drain (the fixture has exited by the time tests call this). Fails
+    /// the test if any of them broke the protocol table.
+    pub fn events(&self) -> Vec<Value> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while self.live.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        self.assert_conforming();
+        self.events.lock().unwrap().clone()
+    }
+
+    fn assert_conforming(&self) {
+        let refused = self.refused.lock().unwrap();
+        assert!(
+            refused.is_empty(),
+            "the shim sent frames its protocol table refuses:\n{}",
+            refused.join("\n")
+        );
+    }
+
+    /// `(method, path)` for every frame carrying a path, in arrival order.
+    pub fn ops(&self) -> Vec<(String, String)> {
+        self.events()
+            .iter()
+            .filter_map(|e| {
+                let method = e.get("method")?.as_str()?.to_string();
+                let path = e.get("params")?.get("path")?.as_str()?.to_string();
+                Some((method, path))
+            })
+            .filter(|(m, _)| !m.starts_with("shim/"))
+            .collect()
+    }
+
+    /// `params` of every frame for `method`, in arrival order.
+    pub fn params(&self, method: &str) -> Vec<Value> {
+        self.events()
+            .into_iter()
+            .filter(|e| e.get("method").and_then(Value::as_str) == Some(method))
+            .filter_map(|mut e| e.get_mut("params").map(Value::take))
+            .collect()
+    }
+}
+
+impl Drop for MockServer {
+    /// The same check for a test that never looked at what came in.
+    fn drop(&mut self) {
+        if !std::thread::panicking() {
+            self.assert_conforming();
+        }
+    }
+}
+
+/// How `serve_json` departs from allowing everything it is asked.
+#[derive(Default)]
+struct Script {
+    /// Take framing v2 (length-prefixed) when `shim/hello` offers it.
+    v2: bool,
+    /// Send this frame when the preflight for this path comes in, then
+    /// answer the preflight.
+    push: Option<(String, Value)>,
+    /// Take reliable notifications and ack each one, except that the
+    /// first notification is lost along with its connection. Shared by
+    /// every connection, so the loss happens once.
+    reliable: Option<Arc<AtomicBool>>,
+    /// Answer `shim/hello` and nothing else, like a server that has
+    /// stopped responding.
+    silent: bool,
+    /// Take thread ids when `shim/hello` offers them.
+    threads: bool,
+    /// Merged into every preflight's answer.
+    answer: Option<Value>,
+    /// The `(op, path)` allowed under `SESSION_TOKEN` so far, shared by
+    /// every connection.
+    session: Option<Arc<Mutex<Vec<Allowed>>>>,
+}
+
+/// An `(op, path)` the mock server allowed.
+type Allowed = (String, String);
+
+const SESSION_TOKEN: &str = "session-1";
+
+fn serve_connection(
+    conn: UnixStream,
+    events: &Mutex<Vec<Value>>,
+    deny: &[String],
+    refused: &Mutex<Vec<String>>,
+) {
+    serve_json(conn, events, deny, refused, &Script::default())
+}
+
+fn serve_framed_connection(
+    conn: UnixStream,
+    events: &Mutex<Vec<Value>>,
+    deny: &[String],
+    refused: &Mutex<Vec<String>>,
+) {
+    let script = Script {
+        v2: true,
+        ..Script::default()
+    };
+    serve_json(conn, events, deny, refused, &script)
+}
+
+/// Whether `frame` (a payload, without its newline or length) is one the
+/// protocol table admits, recording it in `refused` when it isn't.
+fn checked(frame: &[u8], refused: &Mutex<Vec<String>>) {
+    let frame = frame.strip_suffix(b"\n").unwrap_or(frame);
+    if let Err(e) = conformance::validate_frame(frame) {
+        let text = String::from_utf8_lossy(frame);
+        refused.lock().unwrap().push(format!("{e}: {text}"));
+    }
+}
+
+/// Answers every request like a preflight, `shim/hello` included, unless
+/// `script` says otherwise. Frames without a `method` are the shim's
+/// answers to `push` and go unanswered. Every frame is `checked`.
+fn serve_json(
+    conn: UnixStream,
+    events: &Mutex<Vec<Value>>,
+    deny: &[String],
+    refused: &Mutex<Vec<String>>,
+    script: &Script,
+) {
+    let mut writer = conn.try_clone().expect("clone mock conn");
+    let mut reader = BufReader::new(conn);
+    let mut prefixed = false;
+    let mut push = script.push.as_ref();
+    let send = |w: &mut UnixStream, prefixed: bool, frame: Value| {
+        let frame = frame.to_string();
+        let _ = if prefixed {
+            w.write_all(&(frame.len() as u32).to_le_bytes())
+                .and_then(|_| w.write_all(frame.as_bytes()))
+        } else {
+            writeln!(w, "{frame}")
+        };
+    };
+    loop {
+        let frame = if prefixed {
+            let mut len = [0u8; 4];
+            if reader.read_exact(&mut len).is_err() {
+                break;
+            }
+            let mut buf = vec![0u8; u32::from_le_bytes(len) as usize];
+            if reader.read_exact(&mut buf).is_err() {
+                break;
+            }
+            buf
+        } else {
+            let mut buf = Vec::new();
+            if reader.read_until(b'\n', &mut buf).unwrap_or(0) == 0 {
+                break;
+            }
+            buf
+        };
+        checked(&frame, refused);
+        let Ok(msg) = serde_json::from_slice::<Value>(&frame) else {
+            continue;
+        };
+        let id = msg.get("id").filter(|id| !id.is_null());
+        if let (None, Some(lost)) = (id, &script.reliable) {
+            if !lost.swap(true, Ordering::SeqCst) {
+                break;
+            }
+        }
+        events.lock().unwrap().push(msg.clone());
+        let Some(method) = msg.get("method").and_then(Value::as_str) else {
+            continue;
+        };
+        let Some(id) = id else {
+            if let (Some(seq), Some(_)) = (msg["params"]["seq"].as_u64(), &script.reliable) {
+                let ack =
+                    json!({ "jsonrpc": "2.0", "method": "shim/ack", "params": { "upto": seq } });
+                send(&mut writer, prefixed, ack);
+            }
+            continue;
+        };
+        if let Some((_, frame)) = push.filter(|(path, _)| msg["params"]["path"] == **path) {
+            send(&mut writer, prefixed, frame.clone());
+            push = None;
+        }
+        if script.silent && method != "shim/hello" {
+            continue;
+        }
+        let result = if method == "shim/hello" {
+            let mut r = json!({});
+            if script.v2 {
+                r["framing"] = json!("length-prefixed");
+            }
+            if script.reliable.is_some() {
+                r["reliable"] = json!(true);
+            }
+            if script.threads {
+                r["threads"] = json!(true);
+            }
+            if let Some(session) = &script.session {
+                if msg["params"]["cache_token"] == SESSION_TOKEN {
+                    let session = session.lock().unwrap();
+                    let allows = session
+                        .iter()
+                        .map(|(op, path)| json!({ "op": op, "path": path, "ttl_ms": 60_000 }));
+                    r["allow"] = allows.collect();
+                }
+            }
+            r
+        } else {
+            let mut r = json!({ "allow": !deny.iter().any(|d| d == method) });
+            if let (Some(r), Some(Value::Object(extra))) = (r.as_object_mut(), &script.answer) {
+                r.extend(extra.clone());
+            }
+            if let (Some(session), Some(path)) = (&script.session, msg["params"]["path"].as_str()) {
+                let mut session = session.lock().unwrap();
+                session.push((method.to_string(), path.to_string()));
+                r["cache_token"] = json!(SESSION_TOKEN);
+            }
+            r
+        };
+        send(
+            &mut writer,
+            prefixed,
+            json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        );
+        prefixed |= script.v2 && method == "shim/hello";
+    }
+}
+
+fn serve_msgpack_connection(conn: UnixStream, events: &Mutex<Vec<Value>>, deny: &[String]) {
+    use rmpv::Value as Mp;
+
+    let mut writer = conn.try_clone().expect("clone mock conn");
+    let send = |w: &mut UnixStream, v: Mp| {
+        let mut buf = Vec::new();
+        rmpv::encode::write_value(&mut buf, &v).unwrap();
+        let _ = w.write_all(&buf);
+    };
+    send(
+        &mut writer,
+        Mp::Array(vec![
+            2.into(),
+            "nvim_buf_changedtick_event".into(),
+            Mp::Array(vec![1.into(), 7.into()]),
+        ]),
+    );
+    let mut reader = BufReader::new(conn);
+    while let Ok(frame) = rmpv::decode::read_value(&mut reader) {
+        let Some(frame) = frame.as_array() else {
+            continue;
+        };
+        // [0, id, "nvim_exec_lua", [code, [method, params]]] or
+        // [2, "nvim_exec_lua", [code, [method, params]]]
+        let (id, args) = match frame.first().and_then(Mp::as_u64) {
+            Some(0) => (frame.get(1).and_then(Mp::as_u64), frame.get(3)),
+            Some(2) => (None, frame.get(2)),
+            _ => continue,
+        };
+        let Some([_code, call]) = args.and_then(Mp::as_array).map(Vec::as_slice) else {
+            continue;
+        };
+        let Some([method, params]) = call.as_array().map(Vec::as_slice) else {
+            continue;
+        };
+        let method = method.as_str().unwrap_or("").to_string();
+        let mut msg = json!({ "method": method, "params": serde_json::to_value(params).unwrap() });
+        if let Some(id) = id {
+            msg["id"] = json!(id);
+            let allow = !deny.contains(&method);
+            // A stale answer first: the shim must match on msgid.
+            send(
+                &mut writer,
+                Mp::Array(vec![1.into(), (id + 1000).into(), Mp::Nil, Mp::Nil]),
+            );
+            let result = Mp::Map(vec![("allow".into(), allow.into())]);
+            send(
+                &mut writer,
+                Mp::Array(vec![1.into(), id.into(), Mp::Nil, result]),
+            );
+        }
+        events.lock().unwrap().push(msg);
+    }
+}
+
+//
+// -------- Fixture --------
+//
+
+/// The shim library cargo built alongside this test binary. `cargo test`
+/// leaves it in `target/<profile>/deps/` without uplifting it.
+pub fn shim_library() -> PathBuf {
+    let exe = std::env::current_exe().expect("current exe");
+    let deps = exe.parent().expect("deps dir");
+    let name = if cfg!(target_os = "macos") {
+        "libnvimclaude_shim.dylib"
+    } else {
+        "libnvimclaude_shim.so"
+    };
+    let lib = deps.join(name);
+    assert!(lib.exists(), "shim library not built at {}", lib.display());
+    lib
+}
+
+pub struct FixtureRun {
+    pub status: std::process::ExitStatus,
+    /// One entry per op: `"ok"` or `"err <errno>"`.
+    pub results: Vec<String>,
+    pub stderr: String,
+}
+
+/// Run `ops` (one op per line, tab-separated fields) in a shimmed child.
+pub fn run_fixture(server: &MockServer, ops: &[&str]) -> FixtureRun {
+    run_fixture_with_env(server, ops, &[])
+}
+
+pub fn run_fixture_with_env(server: &MockServer, ops: &[&str], env: &[(&str, &str)]) -> FixtureRun {
+    run_fixture_without(server, ops, env, &[])
+}
+
+/// Like `run_fixture_with_env`, then removes `unset` (e.g. the socket var,
+/// to exercise discovery).
+pub fn run_fixture_without(
+    server: &MockServer,
+    ops: &[&str],
+    env: &[(&str, &str)],
+    unset: &[&str],
+) -> FixtureRun {
+    run_fixture_at(server, None, ops, env, unset)
+}
+
+/// Like `run_fixture_with_env`, started in `cwd`.
+pub fn run_fixture_in(
+    server: &MockServer,
+    cwd: &Path,
+    ops: &[&str],
+    env: &[(&str, &str)],
+) -> FixtureRun {
+    run_fixture_at(server, Some(cwd), ops, env, &[])
+}
+
+fn run_fixture_at(
+    server: &MockServer,
+    cwd: Option<&Path>,
+    ops: &[&str],
+    env: &[(&str, &str)],
+    unset: &[&str],
+) -> FixtureRun {
+    let preload = if cfg!(target_os = "macos") {
+        "DYLD_INSERT_LIBRARIES"
+    } else {
+        "LD_PRELOAD"
+    };
+    let mut cmd = Command::new(std::env::current_exe().unwrap());
+    cmd.args(["fixture", "--exact", "--nocapture", "--test-threads=1"]);
+    for (k, _) in std::env::vars_os() {
+        let k = k.to_string_lossy().to_string();
+        if k.starts_with("NVIM_CLAUDE_SHIM") || k.starts_with("FS_SHIM") {
+            cmd.env_remove(k);
+        }
+    }
+    cmd.env(preload, shim_library())
+        .env("NVIM_CLAUDE_SHIM_SOCK", &server.sock)
+        // Fail-closed tests mustn't write to the user's real denial log.
+        .env("FS_SHIM_DENIAL_LOG", "")
+        .env(OPS_ENV, ops.join(OP_SEP));
+    for (k, v) in env {
+        cmd.env(k, v);
+    }
+    for k in unset {
+        cmd.env_remove(k);
+    }
+    if let Some(cwd) = cwd {
+        cmd.current_dir(cwd);
+    }
+    let out = cmd.output().expect("spawn fixture");
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    let results = stdout
+        .lines()
+        // libtest prints "test fixture ... " on the same line as the first op.
+        .filter_map(|l| l.rsplit_once("fixture: ").map(|(_, r)| r.to_string()))
+        .collect();
+    FixtureRun {
+        status: out.status,
+        results,
+        stderr: String::from_utf8_lossy(&out.stderr).to_string(),
+    }
+}
+
+static SIG_FD: std::sync::atomic::AtomicI32 = std::sync::atomic::AtomicI32::new(-1);
+/// `nvim_claude_shim_enter_critical` and `_exit_critical`, or 0.
+static SIG_ENTER: AtomicUsize = AtomicUsize::new(0);
+static SIG_EXIT: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn on_alarm(_: libc::c_int) {
+    let saved = unsafe { *errno_location() };
+    let (enter, exit) = (
+        SIG_ENTER.load(Ordering::Relaxed),
+        SIG_EXIT.load(Ordering::Relaxed),
+    );
+    if enter != 0 {
+        let enter: extern "C" fn() = unsafe { std::mem::transmute(enter) };
+        enter();
+    }
+    unsafe { libc::write(SIG_FD.load(Ordering::Relaxed), b"s".as_ptr().cast(), 1) };
+    if exit != 0 {
+        let exit: extern "C" fn() = unsafe { std::mem::transmute(exit) };
+        exit();
+    }
+    unsafe { *errno_location() = saved };
+}
+
+// Not in the `libc` crate for every target.
+extern "C" {
+    fn setitimer(
+        which: libc::c_int,
+        new: *const libc::itimerval,
+        old: *mut libc::itimerval,
+    ) -> libc::c_int;
+}
+
+unsafe fn errno_location() -> *mut libc::c_int {
+    #[cfg(target_os = "macos")]
+    return unsafe { libc::__error() };
+    #[cfg(not(target_os = "macos"))]
+    return unsafe { libc::__errno_location() };
+}
+
+/// `sigwrite`: nothing on the writing side allocates, so a handler that
+/// interrupts it outside the shim may safely go through the hooks.
+fn signal_writes(path: &str, count: usize, critical: bool) -> std::io::Result<()> {
+    use std::ffi::CString;
+
+    let open = |p: String| {
+        let c = CString::new(p).unwrap();
+        let flags = libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC;
+        match unsafe { libc::open(c.as_ptr(), flags, 0o644) } {
+            -1 => Err(std::io::Error::last_os_error()),
+            fd => Ok(fd),
+        }
+    };
+    let fd = open(path.to_string())?;
+    SIG_FD.store(open(format!("{path}.sig"))?, Ordering::Relaxed);
+    if critical {
+        for (name, slot) in [
+            (c"nvim_claude_shim_enter_critical", &SIG_ENTER),
+            (c"nvim_claude_shim_exit_critical", &SIG_EXIT),
+        ] {
+            let sym = unsafe { libc::dlsym(libc::RTLD_DEFAULT, name.as_ptr()) };
+            assert!(!sym.is_null(), "shim exports no {name:?}");
+            slot.store(sym as usize, Ordering::Relaxed);
+        }
+    }
+    let tick = libc::timeval {
+        tv_sec: 0,
+        tv_usec: 100,
+    };
+    let zero = libc::timeval {
+        tv_sec: 0,
+        tv_usec: 0,
+    };
+    unsafe {
+        let mut sa: libc::sigaction = std::mem::zeroed();
+        sa.sa_sigaction = on_alarm as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        sa.sa_flags = libc::SA_RESTART;
+        libc::sigaction(libc::SIGALRM, &sa, std::ptr::null_mut());
+        let on = libc::itimerval {
+            it_interval: tick,
+            it_value: tick,
+        };
+        setitimer(libc::ITIMER_REAL, &on, std::ptr::null_mut());
+    }
+    let mut res = Ok(());
+    for _ in 0..count {
+        if unsafe { libc::write(fd, b"x".as_ptr().cast(), 1) } != 1 {
+            res = Err(std::io::Error::last_os_error());
+            break;
+        }
+    }
+    unsafe {
+        let off = libc::itimerval {
+            it_interval: zero,
+            it_value: zero,
+        };
+        setitimer(libc::ITIMER_REAL, &off, std::ptr::null_mut());
+        libc::signal(libc::SIGALRM, libc::SIG_IGN);
+        libc::close(SIG_FD.load(Ordering::Relaxed));
+        libc::close(fd);
+    }
+    res
+}
+
+/// Entry point for the shimmed child. Ops:
+/// `write <path> <text>`, `rename <from> <to>`, `unlink <path>`,
+/// `truncate <path> <len>`, `ftruncate <path> <len>`,
+/// `truncwrite <path> <text>` (opens without `O_TRUNC`, `ftruncate`s to
+/// 0, then writes through the same fd), `writetrunc <path> <text> <len>`
+/// (writes, then `ftruncate`s to `len` through the same fd),
+/// `writeheld <first> <second> <text>` (writes `first`, then `second`,
+/// then `first` again through the same fd), `sleep <ms>`,
+/// `agedwrite <path> <text> <ms>` (writes, sleeps `ms`, then writes again
+/// through the same fd),
+/// `dupwrite <path> <text>` (writes through a `dup` of the opened fd),
+/// `writes <path> <text> <count>` (writes `count` times through one fd),
+/// `bigwrite <path> <mib>` (creates `path` and writes `mib` MiB to it,
+/// 8 MiB a call),
+/// `lock <path> <flock|fcntl|lockf> <unlock|close>` (opens `path`
+/// read-write, takes a waiting exclusive lock the given way, then unlocks
+/// it or closes the fd),
+/// `seekwrites <path> <text> <pos>...` and `pwrites <path> <text> <pos>...`
+/// (write `text` through one fd at each position, a byte offset or `end`,
+/// seeking first or with `pwrite`),
+/// `chdir <dir>`, `fchdir <dir>` (through an fd on the directory),
+/// `flushheld <path> <text> <count>` (writes, calls
+/// `nvim_claude_shim_flush` expecting `count`, writes again),
+/// `nbwrite <path> <text> <ms>` (opens with `O_NONBLOCK`, writes, waits,
+/// writes again; the result is the second write's),
+/// `threadwrite <path> <text> <name>` (writes from a thread so named),
+/// `stdio <path> <other> <text> <null|stream>` (writes `path`, wraps the
+/// fd in a stream, `fflush(NULL)`s or flushes it, writes `other`, then
+/// `fclose`s),
+/// `memflush <text>` (`fflush` of an `fmemopen` stream),
+/// `mkfifo <path>`, `mknod <path>` (an empty regular file),
+/// `spawn <op>...` (runs the rest of the fields as one op in a child
+/// fixture, with this one's environment; `spawn spawn ...` nests),
+/// `untar <archive> <dir>` (runs `tar -xf` with this environment, so
+/// shimmed where `tar` isn't SIP-protected),
+/// `fifowrite <path> <text>` (opens a FIFO read-write, so without waiting
+/// for a reader, and writes),
+/// `aclset <path> <text>` (on Linux, `acl_set_file` of the access ACL
+/// `acl_from_text` makes of `text`, through libacl; elsewhere unsupported),
+/// `fdmeta <path> <mode>` (opens `path` read-only, `fchmod`s it to the
+/// octal `mode` and `futimens` it, then unlinks it and does both again
+/// through the same fd),
+/// `shm <name> <text>` (creates the shared memory object `name`, writes
+/// through its fd and, on Linux, through its `/dev/shm` path, then
+/// unlinks it),
+/// `exitwrite <held> <late> <text>` (writes `held` through an fd left open,
+/// then `exit`s, with an `atexit` handler that writes `late`; must be the
+/// last op),
+/// `churn <path> <cycles> <threads>` (each thread creates, rewrites and
+/// deletes `<path>.<thread>` over and over),
+/// `closes <dir> <count> <max_ms>` (creates and writes `count` files in
+/// `dir`, failing with `ETIMEDOUT` if any one's `close` took over
+/// `max_ms`), `sigwrite <path> <count> <auto|critical>` (writes one byte
+/// `count` times to `path` while a fast `SIGALRM` timer's handler writes
+/// to `<path>.sig`, with `critical` inside the shim's critical section),
+/// `racewrite <path> <text>` (two threads each open the existing `path`
+/// and write `text`, and close once both have written),
+/// `setid <setuid|seteuid|setgid|setegid> <id>` (that call),
+/// `execwrite <path> <first> <second>` (writes `first` through an fd left
+/// open across an `execve` of this fixture, whose only op is then
+/// `fdwrite <fd> <second>`; must be the last op), `fdwrite <fd> <text>`
+/// (writes through the inherited `fd`, then closes it),
+/// `touches <dir> <count> <utimensat|utimes>` (sets the
+/// timestamps of `count` existing files `<dir>/<i>` to now through that
+/// call), `writes <dir> <count>` (creates, writes and closes
+/// `<dir>/<i>` one after another), `nofile <n>` (sets the soft fd limit
+/// to `n`, as `ulimit -n` would), `exhaustwrite <path> <text>` (opens
+/// `path`, fills the fd table with `dup`s of stdout, writes `text` from a
+/// new thread, then closes them all), `limit <bytes>` (on Linux,
+/// caps the address space at `bytes` past what is mapped now; elsewhere a
+/// no-op), `rawunlink <addr>` (on Linux, `unlinkat` straight to the
+/// kernel with `addr` as the path pointer, so a supervisor is handed a
+/// path it can't read), `mv <from> <to>` (renames,
+/// or copies and unlinks across filesystems, as `mv` does), and on macOS
+/// `copyfile <from> <to>` (a cloning `copyfile`, as `cp -c` makes) and
+/// `removefile <path>` (recursive).
+pub fn fixture_entry() {
+    let Ok(ops) = std::env::var(OPS_ENV) else {
+        return;
+    };
+    for op in ops.split(OP_SEP).filter(|op| !op.is_empty()) {
+        let fields: Vec<&str> = op.split('\t').collect();
+        let res = run_op(&fields);
+        let line = match res {
+            Ok(()) => "fixture: ok\n".to_string(),
+            Err(e) => format!("fixture: err {}\n", e.raw_os_error().unwrap_or(-1)),
+        };
+        std::io::stdout().write_all(line.as_bytes()).unwrap();
+    }
+}
+
+fn run_op(fields: &[&str]) -> std::io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    match fields {
+        ["write", path, text] => std::fs::write(path, text),
+        // Write at offset 0 without O_TRUNC, so the old size is still there.
+        ["overwrite", path, text] => std::fs::OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .write_all(text.as_bytes()),
+        ["append", path, text] => std::fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)?
+            .write_all(text.as_bytes()),
+        ["writeheld", first, second, text] => {
+            let mut held = std::fs::File::create(first)?;
+            held.write_all(text.as_bytes())?;
+            std::fs::write(second, text)?;
+            held.write_all(text.as_bytes())
+        }
+        ["agedwrite", path, text, ms] => {
+            let mut f = std::fs::OpenOptions::new().write(true).open(path)?;
+            f.write_all(text.as_bytes())?;
+            std::thread::sleep(Duration::from_millis(ms.parse().unwrap()));
+            f.write_all(text.as_bytes())
+        }
+        ["writes", path, text, count] => {
+            let mut f = std::fs::OpenOptions::new().write(true).open(path)?;
+            for _ in 0..count.parse::<usize>().unwrap() {
+                f.write_all(text.as_bytes())?;
+            }
+            Ok(())
+        }
+        ["bigwrite", path, mib] => {
+            let chunk = vec![0x5a_u8; 8 << 20];
+            let mut f = std::fs::File::create(path)?;
+            let mut left = mib.parse::<usize>().unwrap() << 20;
+            while left > 0 {
+                let n = left.min(chunk.len());
+                f.write_all(&chunk[..n])?;
+                left -= n;
+            }
+            Ok(())
+        }
+        ["lock", path, how, release] => {
+            let f = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(path)?;
+            let fd = f.as_raw_fd();
+            let fcntl = |l_type| {
+                let mut l: libc::flock = unsafe { std::mem::zeroed() };
+                l.l_type = l_type as _;
+                l.l_whence = libc::SEEK_SET as _;
+                unsafe { libc::fcntl(fd, libc::F_SETLKW, &l) }
+            };
+            let rc = match *how {
+                "flock" => unsafe { libc::flock(fd, libc::LOCK_EX) },
+                "fcntl" => fcntl(libc::F_WRLCK),
+                "lockf" => unsafe { libc::lockf(fd, libc::F_LOCK, 0) },
+                other => panic!("unknown lock {other}"),
+            };
+            if rc != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            let rc = match (*release, *how) {
+                ("close", _) => 0,
+                (_, "flock") => unsafe { libc::flock(fd, libc::LOCK_UN) },
+                (_, "fcntl") => fcntl(libc::F_UNLCK),
+                _ => unsafe { libc::lockf(fd, libc::F_ULOCK, 0) },
+            };
+            if rc != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            drop(f);
+            Ok(())
+        }
+        ["seekwrites" | "pwrites", path, text, at @ ..] => {
+            use std::io::{Seek, SeekFrom};
+            use std::os::unix::fs::FileExt;
+
+            let mut f = std::fs::OpenOptions::new().write(true).open(path)?;
+            for pos in at {
+                let pos = match *pos {
+                    "end" => f.metadata()?.len(),
+                    n => n.parse().unwrap(),
+                };
+                if fields[0] == "pwrites" {
+                    f.write_all_at(text.as_bytes(), pos)?;
+                } else {
+                    f.seek(SeekFrom::Start(pos))?;
+                    f.write_all(text.as_bytes())?;
+                }
+            }
+            Ok(())
+        }
+        ["flushheld", path, text, count] => {
+            let mut f = std::fs::OpenOptions::new().write(true).open(path)?;
+            f.write_all(text.as_bytes())?;
+            let name = c"nvim_claude_shim_flush";
+            let sym = unsafe { libc::dlsym(libc::RTLD_DEFAULT, name.as_ptr()) };
+            assert!(!sym.is_null(), "shim exports no flush");
+            let flush: extern "C" fn() -> libc::c_int = unsafe { std::mem::transmute(sym) };
+            let flushed = flush();
+            if flushed.to_string() != *count {
+                return Err(std::io::Error::other(format!("flushed {flushed}")));
+            }
+            f.write_all(text.as_bytes())
+        }
+        ["churn", path, cycles, threads] => {
+            let cycles: usize = cycles.parse().unwrap();
+            let workers: Vec<_> = (0..threads.parse::<usize>().unwrap())
+                .map(|t| {
+                    let path = format!("{path}.{t}");
+                    std::thread::spawn(move || -> std::io::Result<()> {
+                        for _ in 0..cycles {
+                            std::fs::write(&path, "new")?;
+                            std::fs::write(&path, "rewritten")?;
+                            std::fs::remove_file(&path)?;
+                        }
+                        Ok(())
+                    })
+                })
+                .collect();
+            workers.into_iter().try_for_each(|w| w.join().unwrap())
+        }
+        ["closes", dir, count, max_ms] => {
+            let max = std::time::Duration::from_millis(max_ms.parse().unwrap());
+            let mut slowest = std::time::Duration::ZERO;
+            for i in 0..count.parse::<usize>().unwrap() {
+                let mut f = std::fs::File::create(format!("{dir}/{i}"))?;
+                f.write_all(b"closing")?;
+                let started = std::time::Instant::now();
+                drop(f);
+                slowest = slowest.max(started.elapsed());
+            }
+            if slowest > max {
+                return Err(std::io::Error::from_raw_os_error(libc::ETIMEDOUT));
+            }
+            Ok(())
+        }
+        ["sigwrite", path, count, mode] => {
+            signal_writes(path, count.parse().unwrap(), *mode == "critical")
+        }
+        ["racewrite", path, text] => {
+            // Both fds are dirty before either closes.
+            let both = std::sync::Arc::new(std::sync::Barrier::new(2));
+            let workers: Vec<_> = (0..2)
+                .map(|_| {
+                    let (path, text, both) = (path.to_string(), text.to_string(), both.clone());
+                    std::thread::spawn(move || -> std::io::Result<()> {
+                        let mut f = std::fs::OpenOptions::new().write(true).open(&path)?;
+                        f.write_all(text.as_bytes())?;
+                        both.wait();
+                        Ok(())
+                    })
+                })
+                .collect();
+            workers.into_iter().try_for_each(|w| w.join().unwrap())
+        }
+        ["setid", call, id] => {
+            let id = id.parse().unwrap();
+            let rc = unsafe {
+                match *call {
+                    "setuid" => libc::setuid(id),
+                    "seteuid" => libc::seteuid(id),
+                    "setgid" => libc::setgid(id),
+                    "setegid" => libc::setegid(id),
+                    _ => unreachable!("setid {call}"),
+                }
+            };
+            if rc != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        }
+        ["execwrite", path, first, second] => {
+            use std::os::unix::io::IntoRawFd;
+            let mut f = std::fs::OpenOptions::new().write(true).open(path)?;
+            f.write_all(first.as_bytes())?;
+            let fd = f.into_raw_fd();
+            if unsafe { libc::fcntl(fd, libc::F_SETFD, 0) } != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            let exe = CString::new(std::env::current_exe()?.as_os_str().as_bytes()).unwrap();
+            let args: Vec<CString> = std::iter::once(exe.clone())
+                .chain(
+                    ["fixture", "--exact", "--nocapture", "--test-threads=1"]
+                        .map(|a| CString::new(a).unwrap()),
+                )
+                .collect();
+            let env: Vec<CString> = std::env::vars_os()
+                .filter(|(k, _)| k != OPS_ENV)
+                .map(|(k, v)| [k.as_bytes(), b"=", v.as_bytes()].concat())
+                .chain(std::iter::once(
+                    format!("{OPS_ENV}=fdwrite\t{fd}\t{second}").into_bytes(),
+                ))
+                .map(|kv| CString::new(kv).unwrap())
+                .collect();
+            let ptrs = |v: &[CString]| {
+                let mut p: Vec<*const libc::c_char> = v.iter().map(|c| c.as_ptr()).collect();
+                p.push(std::ptr::null());
+                p
+            };
+            let (argv, envp) = (ptrs(&args), ptrs(&env));
+            unsafe { libc::execve(exe.as_ptr(), argv.as_ptr(), envp.as_ptr()) };
+            Err(std::io::Error::last_os_error())
+        }
+        ["fdwrite", fd, text] => {
+            use std::os::unix::io::FromRawFd;
+            let mut f = unsafe { std::fs::File::from_raw_fd(fd.parse().unwrap()) };
+            f.write_all(text.as_bytes())
+        }
+        ["touches", dir, count, via] => {
+            for i in 0..count.parse::<usize>().unwrap() {
+                let c = CString::new(format!("{dir}/{i}")).unwrap();
+                let rc = match *via {
+                    "utimensat" => unsafe {
+                        libc::utimensat(libc::AT_FDCWD, c.as_ptr(), std::ptr::null(), 0)
+                    },
+                    _ => unsafe { libc::utimes(c.as_ptr(), std::ptr::null()) },
+                };
+                if rc != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        }
+        ["writes", dir, count] => {
+            for i in 0..count.parse::<usize>().unwrap() {
+                std::fs::write(format!("{dir}/{i}"), "x")?;
+            }
+            Ok(())
+        }
+        ["nofile", n] => {
+            let mut lim = libc::rlimit {
+                rlim_cur: 0,
+                rlim_max: 0,
+            };
+            unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut lim) };
+            lim.rlim_cur = n.parse().unwrap();
+            if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &lim) } != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        }
+        #[cfg(target_os = "linux")]
+        ["rawunlink", addr] => {
+            let addr: usize = addr.parse().unwrap();
+            let rc = unsafe { libc::syscall(libc::SYS_unlinkat, libc::AT_FDCWD, addr, 0) };
+            if rc != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        }
+        ["exhaustwrite", path, text] => {
+            let mut f = std::fs::File::create(path)?;
+            let mut held = Vec::new();
+            loop {
+                let fd = unsafe { libc::dup(1) };
+                if fd < 0 {
+                    break;
+                }
+                held.push(fd);
+            }
+            // A thread with no connection yet, and no fd to make one with.
+            let text = text.to_string();
+            let res = std::thread::spawn(move || f.write_all(text.as_bytes()))
+                .join()
+                .unwrap();
+            for fd in held {
+                unsafe { libc::close(fd) };
+            }
+            res
+        }
+        ["mkfifo", path] => {
+            let c = CString::new(path.as_bytes()).unwrap();
+            if unsafe { libc::mkfifo(c.as_ptr(), 0o644) } != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        }
+        ["mknod", path] => {
+            let c = CString::new(path.as_bytes()).unwrap();
+            if unsafe { libc::mknod(c.as_ptr(), libc::S_IFREG | 0o644, 0) } != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        }
+        ["fdmeta", path, mode] => {
+            let file = std::fs::File::open(path)?;
+            let mode = libc::mode_t::from_str_radix(mode, 8).unwrap();
+            let meta = |fd| unsafe {
+                if libc::fchmod(fd, mode) != 0 || libc::futimens(fd, std::ptr::null()) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            };
+            meta(file.as_raw_fd())?;
+            std::fs::remove_file(path)?;
+            meta(file.as_raw_fd())
+        }
+        ["shm", name, text] => {
+            let c = CString::new(name.as_bytes()).unwrap();
+            let fd = unsafe { libc::shm_open(c.as_ptr(), libc::O_CREAT | libc::O_RDWR, 0o600) };
+            if fd < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            let mut file =
+                unsafe { <std::fs::File as std::os::unix::io::FromRawFd>::from_raw_fd(fd) };
+            file.write_all(text.as_bytes())?;
+            drop(file);
+            if cfg!(target_os = "linux") {
+                std::fs::OpenOptions::new()
+                    .write(true)
+                    .open(format!("/dev/shm/{}", name.trim_start_matches('/')))?
+                    .write_all(text.as_bytes())?;
+            }
+            if unsafe { libc::shm_unlink(c.as_ptr()) } != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        }
+        ["fifowrite", path, text] => std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)?
+            .write_all(text.as_bytes()),
+        ["spawn", op @ ..] => {
+            let out = Command::new(std::env::current_exe()?)
+                .args(["fixture", "--exact", "--nocapture", "--test-threads=1"])
+                .env(OPS_ENV, op.join("\t"))
+                .output()?;
+            let stdout = String::from_utf8_lossy(&out.stdout);
+            match stdout.lines().find_map(|l| l.rsplit_once("fixture: ")) {
+                Some((_, "ok")) => Ok(()),
+                _ => Err(std::io::Error::other(format!("child: {stdout}"))),
+            }
+        }
+        ["untar", archive, dir] => {
+            let status = Command::new("tar")
+                .args(["-xf", archive, "-C", dir])
+                .status()?;
+            match status.success() {
+                true => Ok(()),
+                false => Err(std::io::Error::other(format!("tar: {status}"))),
+            }
+        }
+        ["exitwrite", held, late, text] => {
+            static LATE: std::sync::OnceLock<(String, String)> = std::sync::OnceLock::new();
+            extern "C" fn write_late() {
+                let (path, text) = LATE.get().unwrap();
+                let _ = std::fs::write(path, text);
+            }
+            let mut f = std::fs::File::create(held)?;
+            f.write_all(text.as_bytes())?;
+            std::mem::forget(f);
+            LATE.set((late.to_string(), text.to_string())).unwrap();
+            unsafe { libc::atexit(write_late) };
+            std::io::stdout().write_all(b"fixture: ok\n")?;
+            std::io::stdout().flush()?;
+            unsafe { libc::exit(0) }
+        }
+        ["aclset", path, text] => {
+            if !cfg!(target_os = "linux") {
+                return Err(std::io::ErrorKind::Unsupported.into());
+            }
+            type FromText = unsafe extern "C" fn(*const libc::c_char) -> *mut libc::c_void;
+            type SetFile =
+                unsafe extern "C" fn(*const libc::c_char, libc::c_uint, *mut libc::c_void) -> i32;
+            const ACL_TYPE_ACCESS: libc::c_uint = 0x8000;
+            let (from_text, set_file) = unsafe {
+                let lib = libc::dlopen(c"libacl.so.1".as_ptr(), libc::RTLD_NOW | libc::RTLD_GLOBAL);
+                if lib.is_null() {
+                    return Err(std::io::ErrorKind::Unsupported.into());
+                }
+                // The default scope, as a linked caller would see it: the
+                // shim's export first.
+                let set_file = libc::dlsym(libc::RTLD_DEFAULT, c"acl_set_file".as_ptr());
+                (
+                    std::mem::transmute::<*mut libc::c_void, FromText>(libc::dlsym(
+                        lib,
+                        c"acl_from_text".as_ptr(),
+                    )),
+                    std::mem::transmute::<*mut libc::c_void, SetFile>(set_file),
+                )
+            };
+            let (path, text) = (CString::new(*path).unwrap(), CString::new(*text).unwrap());
+            let acl = unsafe { from_text(text.as_ptr()) };
+            if acl.is_null() || unsafe { set_file(path.as_ptr(), ACL_TYPE_ACCESS, acl) } != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        }
+        ["limit", extra] => {
+            if cfg!(target_os = "linux") {
+                let statm = std::fs::read_to_string("/proc/self/statm")?;
+                let pages: u64 = statm.split(' ').next().unwrap().parse().unwrap();
+                let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+                let cap = pages * page + extra.parse::<u64>().unwrap();
+                let lim = libc::rlimit {
+                    rlim_cur: cap as libc::rlim_t,
+                    rlim_max: cap as libc::rlim_t,
+                };
+                if unsafe { libc::setrlimit(libc::RLIMIT_AS, &lim) } != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        }
+        ["nbwrite", path, text, ms] => {
+            use std::os::unix::fs::OpenOptionsExt;
+            let mut f = std::fs::OpenOptions::new()
+                .write(true)
+                .custom_flags(libc::O_NONBLOCK)
+                .open(path)?;
+            f.write_all(text.as_bytes())
+                .map_err(|e| std::io::Error::other(format!("first write: {e}")))?;
+            std::thread::sleep(Duration::from_millis(ms.parse().unwrap()));
+            f.write_all(text.as_bytes())
+        }
+        ["threadwrite", path, text, name] => {
+            let (path, text) = (path.to_string(), text.to_string());
+            std::thread::Builder::new()
+                .name(name.to_string())
+                .spawn(move || std::fs::write(path, text))?
+                .join()
+                .unwrap()
+        }
+        ["stdio", path, other, text, which] => {
+            use std::os::fd::IntoRawFd;
+            let mut file = std::fs::OpenOptions::new().write(true).open(path)?;
+            file.write_all(text.as_bytes())?;
+            let f = unsafe { libc::fdopen(file.into_raw_fd(), c"w".as_ptr()) };
+            if f.is_null() {
+                return Err(std::io::Error::last_os_error());
+            }
+            let flushed = match *which {
+                "null" => unsafe { libc::fflush(std::ptr::null_mut()) },
+                _ => unsafe { libc::fflush(f) },
+            };
+            std::fs::write(other, text.as_bytes())?;
+            unsafe { libc::fclose(f) };
+            if flushed == 0 {
+                Ok(())
+            } else {
+                Err(std::io::Error::last_os_error())
+            }
+        }
+        ["memflush", text] => {
+            let mut buf = [0u8; 64];
+            let text = CString::new(*text).unwrap();
+            let f = unsafe { libc::fmemopen(buf.as_mut_ptr().cast(), buf.len(), c"w".as_ptr()) };
+            if f.is_null() {
+                return Err(std::io::Error::last_os_error());
+            }
+            unsafe { libc::fputs(text.as_ptr(), f) };
+            let flushed = unsafe { libc::fflush(f) };
+            unsafe { libc::fclose(f) };
+            if flushed == 0 {
+                Ok(())
+            } else {
+                Err(std::io::Error::last_os_error())
+            }
+        }
+        ["dupwrite", path, text] => {
+            use std::os::fd::{AsRawFd, FromRawFd};
+            let f = std::fs::OpenOptions::new().write(true).open(path)?;
+            let dup = unsafe { libc::dup(f.as_raw_fd()) };
+            if dup < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            unsafe { std::fs::File::from_raw_fd(dup) }.write_all(text.as_bytes())
+        }
+        ["sleep", ms] => {
+            std::thread::sleep(Duration::from_millis(ms.parse().unwrap()));
+            Ok(())
+        }
+        ["chdir", dir] => std::env::set_current_dir(dir),
+        ["fchdir", dir] => {
+            use std::os::fd::AsRawFd;
+            let d = std::fs::File::open(dir)?;
+            match unsafe { libc::fchdir(d.as_raw_fd()) } {
+                0 => Ok(()),
+                _ => Err(std::io::Error::last_os_error()),
+            }
+        }
+        ["rename", from, to] => std::fs::rename(from, to),
+        ["mv", from, to] => match std::fs::rename(from, to) {
+            Err(e) if e.raw_os_error() == Some(libc::EXDEV) => {
+                std::fs::copy(from, to)?;
+                std::fs::remove_file(from)
+            }
+            res => res,
+        },
+        // What `cp -c` and `rm -r`-style tree removal call. SIP strips
+        // `DYLD_INSERT_LIBRARIES` from /bin's tools, so the fixture calls
+        // them itself.
+        #[cfg(target_os = "macos")]
+        ["copyfile", from, to] => {
+            let (from, to) = (CString::new(*from).unwrap(), CString::new(*to).unwrap());
+            let flags = libc::COPYFILE_METADATA | libc::COPYFILE_DATA | libc::COPYFILE_CLONE;
+            let rc =
+                unsafe { libc::copyfile(from.as_ptr(), to.as_ptr(), std::ptr::null_mut(), flags) };
+            if rc == 0 {
+                Ok(())
+            } else {
+                Err(std::io::Error::last_os_error())
+            }
+        }
+        #[cfg(target_os = "macos")]
+        ["removefile", path] => {
+            extern "C" {
+                fn removefile(
+                    path: *const libc::c_char,
+                    state: *mut libc::c_void,
+                    flags: u32,
+                ) -> i32;
+            }
+            let path = CString::new(*path).unwrap();
+            // REMOVEFILE_RECURSIVE
+            if unsafe { removefile(path.as_ptr(), std::ptr::null_mut(), 1) } == 0 {
+                Ok(())
+            } else {
+                Err(std::io::Error::last_os_error())
+            }
+        }
+        ["unlink", path] => std::fs::remove_file(path),
+        ["ftruncate", path, len] => std::fs::OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .set_len(len.parse().unwrap()),
+        ["truncwrite", path, text] => {
+            let mut f = std::fs::OpenOptions::new().write(true).open(path)?;
+            f.set_len(0)?;
+            f.write_all(text.as_bytes())
+        }
+        ["writetrunc", path, text, len] => {
+            let mut f = std::fs::OpenOptions::new().write(true).open(path)?;
+            f.write_all(text.as_bytes())?;
+            f.set_len(len.parse().unwrap())
+        }
+        ["truncate", path, len] => {
+            let c = CString::new(Path::new(path).as_os_str().as_bytes()).unwrap();
+            let rc = unsafe { libc::truncate(c.as_ptr(), len.parse().unwrap()) };
+            if rc == 0 {
+                Ok(())
+            } else {
+                Err(std::io::Error::last_os_error())
+            }
+        }
+        // writev with a single empty iovec: nothing to ask about or report.
+        ["writev0", path] => {
+            use std::os::fd::AsRawFd;
+            let f = std::fs::OpenOptions::new().write(true).open(path)?;
+            let iov = libc::iovec {
+                iov_base: std::ptr::null_mut(),
+                iov_len: 0,
+            };
+            match unsafe { libc::writev(f.as_raw_fd(), &iov, 1) } {
+                0 => Ok(()),
+                n if n < 0 => Err(std::io::Error::last_os_error()),
+                n => panic!("writev of nothing wrote {n} bytes"),
+            }
+        }
+        // writev of "abc", nothing, then "de".
+        ["writev3", path] => {
+            use std::os::fd::AsRawFd;
+            let f = std::fs::OpenOptions::new().write(true).open(path)?;
+            let iov = |s: &'static [u8]| libc::iovec {
+                iov_base: s.as_ptr() as *mut libc::c_void,
+                iov_len: s.len(),
+            };
+            let iovs = [iov(b"abc"), iov(b""), iov(b"de")];
+            match unsafe { libc::writev(f.as_raw_fd(), iovs.as_ptr(), 3) } {
+                5 => Ok(()),
+                n if n < 0 => Err(std::io::Error::last_os_error()),
+                n => panic!("writev of 5 bytes wrote {n}"),
+            }
+        }
+        // Every write fails (the fd is read-only); ok once they all have.
+        ["failwrites", path, n] => {
+            let mut f = std::fs::File::open(path)?;
+            for _ in 0..n.parse::<u32>().unwrap() {
+                if f.write(b"x").is_ok() {
+                    panic!("write to a read-only fd succeeded");
+                }
+            }
+            Ok(())
+        }
+        ["create", path, mode] => {
+            use std::os::unix::fs::OpenOptionsExt;
+            std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(u32::from_str_radix(mode, 8).unwrap())
+                .open(path)
+                .map(drop)
+        }
+        ["openat", path, mode] => {
+            let c = CString::new(Path::new(path).as_os_str().as_bytes()).unwrap();
+            let mode: libc::c_uint = u32::from_str_radix(mode, 8).unwrap() as _;
+            let fd = unsafe {
+                libc::openat(
+                    libc::AT_FDCWD,
+                    c.as_ptr(),
+                    libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL,
+                    mode,
+                )
+            };
+            if fd < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            unsafe { libc::close(fd) };
+            Ok(())
+        }
+        ["closefd", fd] => match unsafe { libc::close(fd.parse().unwrap()) } {
+            0 => Ok(()),
+            _ => Err(std::io::Error::last_os_error()),
+        },
+        _ => panic!("unknown fixture op: {fields:?}"),
+    }
+}
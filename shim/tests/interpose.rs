@@ -0,0 +1,3116 @@
+// Preflights, the hello and server requests; `notify.rs` covers a
+// `notify-only` build.
+#![cfg(not(feature = "notify-only"))]
+
+mod common;
+
+use common::{
+    run_fixture, run_fixture_in, run_fixture_with_env, run_fixture_without, FixtureRun, MockServer,
+};
+
+#[test]
+fn fixture() {
+    common::fixture_entry();
+}
+
+fn p(server: &MockServer, name: &str) -> String {
+    server.dir.join(name).to_string_lossy().to_string()
+}
+
+#[test]
+fn write_is_preflighted_and_reported_on_close() {
+    let server = MockServer::start();
+    let file = p(&server, "a.txt");
+    std::fs::write(&file, "old").unwrap();
+    let run = run_fixture(&server, &[&format!("write\t{file}\thello")]);
+    assert!(run.status.success(), "{}", run.stderr);
+    assert_eq!(run.results, ["ok"]);
+    assert_eq!(
+        server.ops(),
+        [
+            ("pre_modify".to_string(), file.clone()),
+            ("post_modify".to_string(), file.clone())
+        ]
+    );
+    assert_eq!(std::fs::read_to_string(&file).unwrap(), "hello");
+}
+
+#[test]
+fn denied_write_fails_with_eperm() {
+    let server = MockServer::with_denied(&["pre_modify"]);
+    let file = p(&server, "denied.txt");
+    std::fs::write(&file, "").unwrap();
+    let run = run_fixture(&server, &[&format!("write\t{file}\tnope")]);
+    assert_eq!(run.results, [format!("err {}", libc::EPERM)]);
+    assert_eq!(std::fs::read_to_string(&file).unwrap(), "");
+    assert!(!server.ops().iter().any(|(m, _)| m == "post_modify"));
+}
+
+#[test]
+fn rename_and_unlink_are_reported() {
+    let server = MockServer::start();
+    let (a, b) = (p(&server, "a.txt"), p(&server, "b.txt"));
+    std::fs::write(&a, "x").unwrap();
+    let run = run_fixture(
+        &server,
+        &[&format!("rename\t{a}\t{b}"), &format!("unlink\t{b}")],
+    );
+    assert_eq!(run.results, ["ok", "ok"]);
+    assert_eq!(
+        server.ops(),
+        [
+            ("pre_rename".to_string(), b.clone()),
+            ("post_create".to_string(), b.clone()),
+            ("pre_delete".to_string(), b.clone()),
+            ("post_delete".to_string(), b.clone())
+        ]
+    );
+}
+
+#[test]
+fn renames_onto_existing_files_say_they_clobber() {
+    let server = MockServer::start();
+    let (a, b, c) = (
+        p(&server, "a.txt"),
+        p(&server, "b.txt"),
+        p(&server, "c.txt"),
+    );
+    std::fs::write(&a, "x").unwrap();
+    std::fs::write(&c, "old").unwrap();
+    let run = run_fixture(
+        &server,
+        &[&format!("rename\t{a}\t{b}"), &format!("rename\t{b}\t{c}")],
+    );
+    assert_eq!(run.results, ["ok", "ok"]);
+    let pre = server.params("pre_rename");
+    assert_eq!(pre[0]["old_path"], a.as_str());
+    assert_eq!(pre[0]["destination_exists"], false);
+    assert!(pre[0].get("destination_size").is_none());
+    assert_eq!(pre[1]["old_path"], b.as_str());
+    assert_eq!(pre[1]["destination_exists"], true);
+    assert_eq!(pre[1]["destination_size"], 3);
+    assert!(pre[1]["destination_mtime"].as_i64().unwrap() > 0);
+    assert_eq!(server.params("post_create")[0]["clobbered"], false);
+    assert_eq!(server.params("post_modify")[0]["clobbered"], true);
+}
+
+#[test]
+fn denied_unlink_leaves_file_in_place() {
+    let server = MockServer::with_denied(&["pre_delete"]);
+    let a = p(&server, "keep.txt");
+    std::fs::write(&a, "x").unwrap();
+    let run = run_fixture(&server, &[&format!("unlink\t{a}")]);
+    assert_eq!(run.results, [format!("err {}", libc::EPERM)]);
+    assert!(std::path::Path::new(&a).exists());
+}
+
+#[test]
+fn events_soon_after_a_denial_carry_it() {
+    let server = MockServer::with_denied(&["pre_delete"]);
+    let a = p(&server, "keep.txt");
+    let trash = p(&server, "trash.txt");
+    std::fs::write(&a, "x").unwrap();
+    let run = run_fixture(
+        &server,
+        &[&format!("unlink\t{a}"), &format!("rename\t{a}\t{trash}")],
+    );
+    assert_eq!(run.results, [format!("err {}", libc::EPERM), "ok".into()]);
+    let denied = &server.params("pre_delete")[0];
+    let rename = &server.params("pre_rename")[0];
+    assert_eq!(rename["old_path"], a.as_str());
+    assert_eq!(rename["after_denied"]["op"], "pre_delete");
+    assert_eq!(rename["after_denied"]["op_id"], denied["path_seq"]);
+    assert!(denied.get("after_denied").is_none());
+}
+
+#[test]
+fn bypassed_processes_are_left_alone() {
+    let server = MockServer::with_denied(&["pre_delete"]);
+    let a = p(&server, "a.txt");
+    std::fs::write(&a, "x").unwrap();
+    let exe = std::env::current_exe().unwrap();
+    let name = exe.file_name().unwrap().to_str().unwrap();
+    let stem = format!("{}*", name.split('-').next().unwrap());
+    for pattern in [format!("ld,{stem}"), exe.display().to_string()] {
+        let run = run_fixture_with_env(
+            &server,
+            &[&format!("write\t{a}\ty")],
+            &[("FS_SHIM_BYPASS_PROCESSES", &pattern)],
+        );
+        assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    }
+    let run = run_fixture_with_env(
+        &server,
+        &[&format!("unlink\t{a}")],
+        &[("FS_SHIM_BYPASS_PROCESSES", &stem)],
+    );
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    assert!(server.events().is_empty());
+    assert!(!std::path::Path::new(&a).exists());
+
+    // Anything else is tracked as usual.
+    let run = run_fixture_with_env(
+        &server,
+        &[&format!("write\t{a}\tz")],
+        &[("FS_SHIM_BYPASS_PROCESSES", "ld")],
+    );
+    assert_eq!(run.results, ["ok"]);
+    assert_eq!(
+        server.ops(),
+        [
+            ("pre_create".to_string(), a.clone()),
+            ("post_create".to_string(), a)
+        ]
+    );
+}
+
+#[test]
+fn a_session_token_lets_the_next_process_start_with_its_allows() {
+    let server = MockServer::remembering();
+    let (a, b, token) = (
+        p(&server, "a.txt"),
+        p(&server, "b.txt"),
+        p(&server, "token"),
+    );
+    std::fs::write(&a, "").unwrap();
+    std::fs::write(&b, "").unwrap();
+    let env = [
+        ("FS_SHIM_ALLOW_CACHE_MS", "60000"),
+        ("FS_SHIM_CACHE_TOKEN_FILE", token.as_str()),
+    ];
+    for text in ["one", "two"] {
+        let run = run_fixture_with_env(&server, &[&format!("write\t{a}\t{text}")], &env);
+        assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    }
+    let run = run_fixture_with_env(&server, &[&format!("write\t{b}\tx")], &env);
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    assert_eq!(std::fs::read_to_string(&token).unwrap(), "session-1\n");
+
+    // a.txt was asked about by the first process only; b.txt, never
+    // allowed before, still is.
+    let asked: Vec<(String, String)> = server
+        .ops()
+        .into_iter()
+        .filter(|(m, _)| m.starts_with("pre_"))
+        .collect();
+    assert_eq!(
+        asked,
+        [
+            ("pre_modify".to_string(), a.clone()),
+            ("pre_modify".to_string(), b.clone())
+        ]
+    );
+    let by: Vec<serde_json::Value> = server
+        .params("post_modify")
+        .iter()
+        .map(|p| p["allowed_by"].clone())
+        .collect();
+    assert_eq!(by, ["server", "cache", "server"]);
+    let tokens: Vec<serde_json::Value> = server
+        .params("shim/hello")
+        .iter()
+        .map(|p| p["cache_token"].clone())
+        .collect();
+    assert_eq!(tokens[0], serde_json::Value::Null);
+    assert!(tokens[1..].iter().all(|t| t == "session-1"), "{tokens:?}");
+}
+
+#[test]
+fn an_fd_written_on_both_sides_of_an_exec_is_asked_about_and_posted_once() {
+    let server = MockServer::start();
+    let a = p(&server, "a.txt");
+    std::fs::write(&a, "old").unwrap();
+    let run = run_fixture(&server, &[&format!("execwrite\t{a}\tone\ttwo")]);
+    // Only the new image prints a result; the old one's is the exec.
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    assert_eq!(std::fs::read_to_string(&a).unwrap(), "onetwo");
+    assert_eq!(
+        server.ops(),
+        [
+            ("pre_modify".to_string(), a.clone()),
+            ("post_modify".to_string(), a.clone())
+        ]
+    );
+    let post = &server.params("post_modify")[0];
+    assert_eq!(post["bytes"], 6, "{post}");
+    assert_eq!(post["allowed_by"], "server", "{post}");
+}
+
+#[test]
+fn a_file_the_process_made_is_not_asked_about_again_until_it_moves() {
+    let server = MockServer::start();
+    let (a, c) = (p(&server, "a.log"), p(&server, "c.log"));
+    let ops = [
+        format!("write\t{a}\tx"),
+        format!("overwrite\t{a}\ty"),
+        format!("rename\t{a}\t{c}"),
+        format!("overwrite\t{c}\tz"),
+    ];
+    let ops: Vec<&str> = ops.iter().map(String::as_str).collect();
+    let run = run_fixture(&server, &ops);
+    assert_eq!(run.results, ["ok"; 4], "{}", run.stderr);
+    let asked: Vec<(String, String)> = server
+        .ops()
+        .into_iter()
+        .filter(|(m, _)| m.starts_with("pre_"))
+        .collect();
+    // Renamed away, it is a file like any other.
+    assert_eq!(
+        asked,
+        [
+            ("pre_create".to_string(), a.clone()),
+            ("pre_rename".to_string(), c.clone()),
+            ("pre_modify".to_string(), c.clone())
+        ]
+    );
+    let posts = server.params("post_modify");
+    assert_eq!(posts[0]["path"], a.as_str());
+    assert_eq!(posts[0]["self_created"], true, "{}", posts[0]);
+    assert_eq!(posts[0]["allowed_by"], serde_json::Value::Null);
+    assert_eq!(
+        posts.last().unwrap()["self_created"],
+        serde_json::Value::Null
+    );
+
+    let server = MockServer::start();
+    let a = p(&server, "a.log");
+    let (create, reopen) = (format!("write\t{a}\tx"), format!("overwrite\t{a}\ty"));
+    let env = [("FS_SHIM_TRUST_SELF_CREATED", "0")];
+    let run = run_fixture_with_env(&server, &[&create, &reopen], &env);
+    assert_eq!(run.results, ["ok", "ok"], "{}", run.stderr);
+    assert_eq!(server.params("pre_modify").len(), 1);
+}
+
+#[test]
+fn dir_scoped_allows_cover_the_directory_until_it_moves() {
+    let server = MockServer::answering(serde_json::json!({ "scope": "dir", "ttl_ms": 60_000 }));
+    let (d, d2) = (p(&server, "feature"), p(&server, "moved"));
+    std::fs::create_dir(&d).unwrap();
+    let [a, b, c] = ["a.rs", "b.rs", "c.rs"].map(|f| format!("{d}/{f}"));
+    let run = run_fixture(
+        &server,
+        &[
+            &format!("write\t{a}\tx"),
+            &format!("write\t{b}\tx"),
+            // Renamed away and back: the same directory, but no longer
+            // covered.
+            &format!("rename\t{d}\t{d2}"),
+            &format!("rename\t{d2}\t{d}"),
+            &format!("write\t{c}\tx"),
+        ],
+    );
+    assert_eq!(run.results, ["ok"; 5], "{}", run.stderr);
+    let asked: Vec<String> = server
+        .ops()
+        .into_iter()
+        .filter(|(m, _)| m.starts_with("pre_"))
+        .map(|(_, p)| p)
+        .collect();
+    assert_eq!(asked, [a.clone(), d2, d, c.clone()]);
+    let by: Vec<(serde_json::Value, serde_json::Value)> = server
+        .params("post_create")
+        .iter()
+        .filter(|p| p["path"].as_str().is_some_and(|p| p.ends_with(".rs")))
+        .map(|p| (p["path"].clone(), p["allowed_by"].clone()))
+        .collect();
+    assert_eq!(
+        by,
+        [
+            (serde_json::json!(a), serde_json::json!("server")),
+            (serde_json::json!(b), serde_json::json!("dir_cache")),
+            (serde_json::json!(c), serde_json::json!("server")),
+        ]
+    );
+}
+
+#[test]
+fn post_modify_says_where_the_writes_went() {
+    let server = MockServer::start();
+    let files = ["trunc", "over", "tail", "log", "mixed", "late"].map(|f| p(&server, f));
+    for f in &files {
+        std::fs::write(f, "0123456789").unwrap();
+    }
+    let [trunc, over, tail, log, mixed, late] = &files;
+    let late_ops = std::iter::once("end".to_string())
+        .chain((0..64).map(|i| i.to_string()))
+        .collect::<Vec<_>>()
+        .join("\t");
+    let run = run_fixture(
+        &server,
+        &[
+            &format!("write\t{trunc}\tx"),
+            &format!("overwrite\t{over}\tx"),
+            &format!("seekwrites\t{tail}\tx\tend\tend"),
+            &format!("append\t{log}\tx"),
+            &format!("pwrites\t{mixed}\tx\tend\t0"),
+            // Every 64th plain write is sampled; the 65th is at 63.
+            &format!("seekwrites\t{late}\tx\t{late_ops}"),
+        ],
+    );
+    assert!(run.results.iter().all(|r| r == "ok"), "{:?}", run.results);
+    let shape = |f: &String| {
+        server
+            .params("post_modify")
+            .into_iter()
+            .find(|p| p["path"] == f.as_str())
+            .map(|p| p["write_shape"].clone())
+            .unwrap()
+    };
+    assert_eq!(shape(trunc), "overwrite");
+    assert_eq!(shape(over), "overwrite");
+    assert_eq!(shape(tail), "append");
+    assert_eq!(shape(log), "append");
+    assert_eq!(shape(mixed), "mixed");
+    assert_eq!(shape(late), "mixed");
+}
+
+#[test]
+fn verify_finds_every_route_hooked() {
+    let server = MockServer::start();
+    let a = p(&server, "a.txt");
+    let run = run_fixture_with_env(
+        &server,
+        &[&format!("write\t{a}\tx")],
+        &[("FS_SHIM_VERIFY", "1"), ("NVIM_CLAUDE_SHIM_DEBUG", "1")],
+    );
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    assert!(!run.stderr.contains("coverage_warning"), "{}", run.stderr);
+    assert!(server.params("shim/coverage_warning").is_empty());
+    let routes = if cfg!(target_os = "macos") {
+        &["dlsym"][..]
+    } else {
+        &["libc", "dlsym"]
+    };
+    for route in routes {
+        let line = format!("shim: verify: {route} hooked");
+        assert!(run.stderr.contains(&line), "{}", run.stderr);
+    }
+    // The canary's own calls go untracked.
+    let written: Vec<serde_json::Value> = server
+        .params("shim/write_call")
+        .iter()
+        .map(|p| p["tracked_path"].clone())
+        .collect();
+    assert_eq!(written, [serde_json::json!(a)]);
+}
+
+#[test]
+fn a_host_short_of_memory_survives_the_shim() {
+    let server = MockServer::start();
+    let a = p(&server, "a.txt");
+    std::fs::write(&a, "").unwrap();
+    let base = p(&server, "churn");
+    let run = run_fixture_with_env(
+        &server,
+        &[
+            "limit\t67108864",
+            &format!("writes\t{a}\tline of output\n\t2000"),
+            &format!("churn\t{base}\t100\t4"),
+        ],
+        &[
+            ("FS_SHIM_MAX_HEAP_BYTES", "4096"),
+            ("FS_SHIM_ALLOW_CACHE_MS", "60000"),
+        ],
+    );
+    assert!(run.status.success(), "{}", run.stderr);
+    assert_eq!(run.results, ["ok", "ok", "ok"], "{}", run.stderr);
+    // Every path's numbers still distinct, with most of the table refused.
+    let mut seen = std::collections::HashSet::new();
+    for e in server.events() {
+        if let (Some(path), Some(seq)) = (
+            e["params"]["path"].as_str(),
+            e["params"]["path_seq"].as_u64(),
+        ) {
+            assert!(seen.insert((path.to_string(), seq)), "{path} #{seq} twice");
+        }
+    }
+    assert!(server.ops().iter().any(|(op, _)| op == "post_delete"));
+}
+
+#[test]
+fn the_exit_summary_matches_the_events() {
+    let server = MockServer::with_denied(&["pre_delete"]);
+    let (a, b, c) = (p(&server, "a.rs"), p(&server, "b.rs"), p(&server, "c.rs"));
+    let run = run_fixture(
+        &server,
+        &[
+            &format!("write\t{a}\tfirst"),
+            &format!("append\t{a}\tsecond"),
+            &format!("write\t{b}\tb"),
+            &format!("rename\t{b}\t{c}"),
+            &format!("unlink\t{a}"),
+        ],
+    );
+    assert_eq!(
+        run.results,
+        ["ok", "ok", "ok", "ok", "err 1"],
+        "{}",
+        run.stderr
+    );
+
+    // What the stream says, path by path.
+    let mut expected: std::collections::BTreeMap<String, (Vec<String>, u64, Vec<String>)> =
+        Default::default();
+    for e in server.events() {
+        let (Some(method), Some(path)) = (e["method"].as_str(), e["params"]["path"].as_str())
+        else {
+            continue;
+        };
+        let entry = expected.entry(path.to_string()).or_default();
+        if let Some(op) = method.strip_prefix("post_") {
+            if !entry.0.iter().any(|o| o == op) {
+                entry.0.push(op.to_string());
+            }
+            entry.1 += e["params"]["bytes"].as_u64().unwrap_or(0);
+        } else if method == "pre_delete" {
+            entry.2.push("delete".to_string());
+        }
+    }
+    expected.retain(|_, (ops, _, denied)| !ops.is_empty() || !denied.is_empty());
+
+    let summaries = server.params("shim/summary");
+    assert_eq!(summaries.len(), 1);
+    assert_eq!(summaries[0]["overflow"], 0);
+    let summary: std::collections::BTreeMap<String, (Vec<String>, u64, Vec<String>)> = summaries[0]
+        ["paths"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|p| {
+            let names = |v: &serde_json::Value| -> Vec<String> {
+                v.as_array()
+                    .map(|a| a.iter().map(|o| o.as_str().unwrap().to_string()).collect())
+                    .unwrap_or_default()
+            };
+            let path = p["path"].as_str().unwrap().to_string();
+            let bytes = p["bytes"].as_u64().unwrap();
+            (path, (names(&p["ops"]), bytes, names(&p["denied"])))
+        })
+        .collect();
+    assert_eq!(summary, expected);
+    assert_eq!(summary[&a].1, 11);
+    assert_eq!(summary[&a].2, ["delete"]);
+    assert_eq!(summary[&c].0, ["create"]);
+}
+
+#[test]
+fn fifos_are_reported_once_and_never_tracked() {
+    let server = MockServer::start();
+    let fifo = p(&server, "build.fifo");
+    let node = p(&server, "plain.txt");
+    let run = run_fixture(
+        &server,
+        &[
+            &format!("mkfifo\t{fifo}"),
+            &format!("fifowrite\t{fifo}\tping"),
+            &format!("mknod\t{node}"),
+            &format!("unlink\t{fifo}"),
+        ],
+    );
+    assert_eq!(run.results, ["ok"; 4], "{}", run.stderr);
+    assert_eq!(
+        server.ops(),
+        [
+            ("post_create_special".to_string(), fifo.clone()),
+            ("pre_delete".to_string(), fifo.clone()),
+            ("post_delete".to_string(), fifo),
+        ]
+    );
+    let made = &server.params("post_create_special")[0];
+    assert_eq!(made["node"], "fifo");
+    assert_eq!(made["mode"], "0644");
+}
+
+#[test]
+fn every_event_names_the_command_that_started_the_chain() {
+    let server = MockServer::start();
+    let (a, b, c) = (
+        p(&server, "a.txt"),
+        p(&server, "b.txt"),
+        p(&server, "c.txt"),
+    );
+    let run = run_fixture(
+        &server,
+        &[
+            &format!("write\t{a}\tx"),
+            &format!("spawn\twrite\t{b}\tx"),
+            &format!("spawn\tspawn\twrite\t{c}\tx"),
+        ],
+    );
+    assert_eq!(run.results, ["ok"; 3], "{}", run.stderr);
+
+    let events: Vec<serde_json::Value> = server
+        .events()
+        .into_iter()
+        .filter(|e| {
+            let m = e["method"].as_str().unwrap_or("");
+            m.starts_with("pre_") || m.starts_with("post_")
+        })
+        .collect();
+    assert_eq!(events.len(), 6);
+    let pid_of = |path: &str| {
+        let pids: Vec<_> = events
+            .iter()
+            .filter(|e| e["params"]["path"] == path)
+            .map(|e| e["params"]["pid"].as_u64().unwrap())
+            .collect();
+        assert_eq!(pids.len(), 2);
+        assert_eq!(pids[0], pids[1]);
+        pids[0]
+    };
+    let root = pid_of(&a);
+    assert_ne!(pid_of(&b), root);
+    assert_ne!(pid_of(&c), root);
+    assert_ne!(pid_of(&b), pid_of(&c));
+    let argv0 = &events[0]["params"]["root_argv0"];
+    assert!(argv0.as_str().unwrap().contains("interpose"), "{argv0}");
+    for e in &events {
+        assert_eq!(e["params"]["root_pid"], root, "{e}");
+        assert_eq!(&e["params"]["root_argv0"], argv0, "{e}");
+    }
+}
+
+#[test]
+fn writes_during_exit_are_told_not_asked() {
+    let server = MockServer::with_denied(&["pre_create"]);
+    let (held, late) = (p(&server, "held.txt"), p(&server, "late.txt"));
+    std::fs::write(&held, "").unwrap();
+
+    let run = run_fixture(&server, &[&format!("exitwrite\t{held}\t{late}\tbye")]);
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    assert!(run.status.success(), "{}", run.stderr);
+    // Denied had it been asked.
+    assert_eq!(std::fs::read_to_string(&late).unwrap(), "bye");
+
+    let events = server.events();
+    let of = |method: &str, path: &str| {
+        events
+            .iter()
+            .find(|e| e["method"] == method && e["params"]["path"] == path)
+            .unwrap_or_else(|| panic!("no {method} for {path} in {events:?}"))
+            .clone()
+    };
+    // The open fd's post went out from the exit hook, before the handlers.
+    assert_eq!(of("post_modify", &held)["params"]["bytes"], 3);
+    let pre = of("pre_create", &late);
+    assert!(pre.get("id").is_none(), "{pre}");
+    assert_eq!(pre["params"]["phase"], "shutdown");
+    let post = of("post_create", &late);
+    assert_eq!(post["params"]["allowed_by"], "shutdown");
+    assert!(of("pre_modify", &held).get("id").is_some());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn acl_changes_are_reported_and_can_be_refused() {
+    let server = MockServer::with_denied(&["pre_acl"]);
+    let (a, b) = (p(&server, "a.rs"), p(&server, "b.rs"));
+    std::fs::write(&a, "").unwrap();
+    std::fs::write(&b, "").unwrap();
+    let acl = "u::rw-,g::r--,o::r--";
+
+    let run = run_fixture(&server, &[&format!("aclset\t{a}\t{acl}")]);
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    let run = run_fixture_with_env(
+        &server,
+        &[&format!("aclset\t{b}\t{acl}")],
+        &[("FS_SHIM_ACL_MODE", "block")],
+    );
+    assert_eq!(
+        run.results,
+        [format!("err {}", libc::EPERM)],
+        "{}",
+        run.stderr
+    );
+
+    assert_eq!(
+        server.ops(),
+        [
+            ("post_acl".to_string(), a.clone()),
+            ("pre_acl".to_string(), b.clone()),
+        ]
+    );
+    let post = &server.params("post_acl")[0];
+    assert_eq!(post["acl"], "user::rw-\ngroup::r--\nother::r--\n");
+    assert_eq!(server.params("pre_acl")[0]["source"], "acl_set_file");
+}
+
+#[test]
+fn metadata_changes_through_an_unlinked_fd_name_the_inode() {
+    use std::os::unix::fs::MetadataExt;
+
+    let server = MockServer::start();
+    let a = p(&server, "a.rs");
+    std::fs::write(&a, "x").unwrap();
+    let meta = std::fs::metadata(&a).unwrap();
+
+    let run = run_fixture_with_env(
+        &server,
+        &[&format!("fdmeta\t{a}\t600")],
+        &[("FS_SHIM_TOUCH_VERBOSE", &a)],
+    );
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+
+    assert_eq!(
+        server.ops(),
+        [
+            ("post_chmod".to_string(), a.clone()),
+            ("post_utimes".to_string(), a.clone()),
+            ("pre_delete".to_string(), a.clone()),
+            ("post_delete".to_string(), a.clone()),
+        ]
+    );
+    let chmods = server.params("post_chmod");
+    assert_eq!(chmods.len(), 2);
+    assert_eq!(chmods[0]["mode"], "0600");
+    let unlinked = &chmods[1];
+    assert!(unlinked["path"].is_null());
+    assert_eq!(unlinked["dev"], meta.dev());
+    assert_eq!(unlinked["ino"], meta.ino());
+    assert_eq!(unlinked["last_path"], a.as_str());
+    assert_eq!(unlinked["mode"], "0600");
+    assert_eq!(server.params("post_utimes")[1]["ino"], meta.ino());
+}
+
+#[test]
+fn touch_storms_are_summed_into_one_post_touch_per_directory() {
+    let server = MockServer::start();
+    let dirs: Vec<String> = (0..4).map(|d| p(&server, &format!("out{d}"))).collect();
+    for dir in &dirs {
+        std::fs::create_dir_all(dir).unwrap();
+        for i in 0..250 {
+            std::fs::write(format!("{dir}/{i}"), "").unwrap();
+        }
+    }
+    let verbose = format!("{}/7", dirs[0]);
+    let ops: Vec<String> = dirs
+        .iter()
+        .zip(["utimensat", "utimes", "utimensat", "utimes"])
+        .map(|(dir, via)| format!("touches\t{dir}\t250\t{via}"))
+        .collect();
+    let ops: Vec<&str> = ops.iter().map(String::as_str).collect();
+    let run = run_fixture_with_env(&server, &ops, &[("FS_SHIM_TOUCH_VERBOSE", &verbose)]);
+    assert_eq!(run.results, ["ok"; 4], "{}", run.stderr);
+
+    let utimes = server.params("post_utimes");
+    assert_eq!(utimes.len(), 1);
+    assert_eq!(utimes[0]["path"], verbose.as_str());
+    let mut touches = server.params("post_touch");
+    touches.sort_by_key(|t| t["path"].as_str().unwrap().to_string());
+    assert_eq!(touches.len(), 4);
+    for (t, dir) in touches.iter().zip(&dirs) {
+        let n = if *dir == dirs[0] { 249 } else { 250 };
+        assert_eq!(t["path"], dir.as_str());
+        assert_eq!(
+            (t["touches"].as_u64(), t["files"].as_u64()),
+            (Some(n), Some(n))
+        );
+        assert_eq!(t["sample"].as_array().unwrap().len(), 16);
+        assert_eq!(t["synthesized"], true);
+    }
+}
+
+#[test]
+fn two_threads_writing_one_file_are_flagged_as_concurrent_writers() {
+    use std::os::unix::fs::MetadataExt;
+
+    let server = MockServer::start();
+    let (race, solo) = (p(&server, "race.txt"), p(&server, "solo.txt"));
+    std::fs::write(&race, "").unwrap();
+    let run = run_fixture(
+        &server,
+        &[
+            &format!("racewrite\t{race}\tmine"),
+            &format!("write\t{solo}\tsolo"),
+        ],
+    );
+    assert_eq!(run.results, ["ok", "ok"], "{}", run.stderr);
+
+    let posts = server.params("post_modify");
+    let raced: Vec<_> = posts
+        .iter()
+        .filter(|p| p["path"] == race.as_str())
+        .collect();
+    assert_eq!(raced.len(), 2, "{posts:?}");
+    let meta = std::fs::metadata(&race).unwrap();
+    for (post, other) in raced.iter().zip(raced.iter().rev()) {
+        assert_eq!(post["concurrent_writers"], true, "{post}");
+        assert_eq!(post["concurrent_op_id"], other["op_id"]);
+        assert_ne!(post["op_id"], other["op_id"]);
+        assert_eq!(post["dev"], meta.dev());
+        assert_eq!(post["ino"], meta.ino());
+        assert!(post["opened_ms"].as_u64().is_some_and(|t| t > 0), "{post}");
+    }
+    let created = server.params("post_create");
+    let solo = created.iter().find(|p| p["path"] == solo.as_str()).unwrap();
+    assert_eq!(solo.get("concurrent_writers"), None);
+    assert!(solo["op_id"].is_u64() && solo["ino"].is_u64());
+}
+
+#[test]
+fn id_changes_are_reported_and_later_preflights_carry_the_effective_ids() {
+    use std::os::unix::fs::PermissionsExt;
+
+    // Only root can change its ids to anything else.
+    if unsafe { libc::geteuid() } != 0 {
+        return;
+    }
+    let server = MockServer::start();
+    let shared = p(&server, "shared");
+    std::fs::create_dir(&shared).unwrap();
+    std::fs::set_permissions(&shared, std::fs::Permissions::from_mode(0o777)).unwrap();
+    let (before, during, after) = (
+        p(&server, "a.txt"),
+        format!("{shared}/b.txt"),
+        p(&server, "c.txt"),
+    );
+    let ops = [
+        format!("write\t{before}\ta"),
+        "setid\tsetegid\t65534".to_string(),
+        "setid\tseteuid\t65534".to_string(),
+        format!("write\t{during}\tb"),
+        "setid\tseteuid\t0".to_string(),
+        "setid\tsetegid\t0".to_string(),
+        "setid\tsetgid\t4294967295".to_string(),
+        format!("write\t{after}\tc"),
+    ];
+    let ops: Vec<&str> = ops.iter().map(String::as_str).collect();
+    let run = run_fixture(&server, &ops);
+    let mut expected = ["ok"; 8].map(String::from);
+    expected[6] = format!("err {}", libc::EINVAL);
+    assert_eq!(run.results, expected, "{}", run.stderr);
+
+    let changes = server.params("shim/credentials_changed");
+    let calls: Vec<_> = changes
+        .iter()
+        .map(|c| c["call"].as_str().unwrap())
+        .collect();
+    assert_eq!(calls, ["setegid", "seteuid", "seteuid", "setegid"]);
+    assert_eq!(changes[1]["old"]["euid"], 0);
+    assert_eq!(changes[1]["new"]["euid"], 65534);
+    assert_eq!(changes[1]["new"]["egid"], 65534);
+    assert_eq!(changes[1]["new"]["uid"], 0);
+
+    let pre = server.params("pre_create");
+    let of = |path: &str| pre.iter().find(|p| p["path"] == path).unwrap().clone();
+    assert_eq!(of(&before).get("euid"), None);
+    assert_eq!(
+        (&of(&during)["euid"], &of(&during)["egid"]),
+        (&serde_json::json!(65534), &serde_json::json!(65534))
+    );
+    assert_eq!(
+        (&of(&after)["euid"], &of(&after)["egid"]),
+        (&serde_json::json!(0), &serde_json::json!(0))
+    );
+}
+
+#[test]
+fn failed_exclusive_creates_of_sensitive_paths_are_reported_as_probes() {
+    let eexist = format!("err {}", libc::EEXIST);
+    for (debounce, counts) in [("60000", vec![1]), ("0", vec![1, 2, 3])] {
+        let server = MockServer::start();
+        std::fs::create_dir(p(&server, "secrets")).unwrap();
+        let (key, other) = (p(&server, "secrets/key"), p(&server, "other.txt"));
+        std::fs::write(&key, "k").unwrap();
+        std::fs::write(&other, "o").unwrap();
+        let ops = [&key, &other, &key, &key].map(|path| format!("create\t{path}\t600"));
+        let ops: Vec<&str> = ops.iter().map(String::as_str).collect();
+        let env = [
+            ("FS_SHIM_SENSITIVE", "**/secrets/**"),
+            ("FS_SHIM_PROBE_DEBOUNCE_MS", debounce),
+        ];
+        let run = run_fixture_with_env(&server, &ops, &env);
+        assert_eq!(run.results, [eexist.as_str(); 4], "{}", run.stderr);
+        let probes = server.params("shim/probe");
+        assert!(
+            probes.iter().all(|p| p["path"] == key.as_str()),
+            "{probes:?}"
+        );
+        let seen: Vec<_> = probes
+            .iter()
+            .map(|p| p["count"].as_u64().unwrap())
+            .collect();
+        assert_eq!(seen, counts);
+    }
+}
+
+#[test]
+fn writes_from_a_signal_handler_pass_through_without_deadlock() {
+    let server = MockServer::start();
+    let (auto, critical) = (p(&server, "auto.txt"), p(&server, "critical.txt"));
+    let run = run_fixture(
+        &server,
+        &[
+            &format!("sigwrite\t{auto}\t200000\tauto"),
+            &format!("sigwrite\t{critical}\t200000\tcritical"),
+        ],
+    );
+    assert_eq!(run.results, ["ok", "ok"], "{}", run.stderr);
+    for f in [&auto, &critical] {
+        assert_eq!(std::fs::metadata(f).unwrap().len(), 200000);
+        assert!(std::fs::metadata(format!("{f}.sig")).unwrap().len() > 0);
+    }
+    let created: Vec<String> = server
+        .ops()
+        .into_iter()
+        .filter(|(op, _)| op == "post_create")
+        .map(|(_, p)| p)
+        .collect();
+    assert!(created.contains(&auto) && created.contains(&critical));
+    // Inside the section the handler's own file was never seen.
+    assert!(!created.contains(&format!("{critical}.sig")));
+}
+
+#[test]
+fn vcs_metadata_is_left_alone_while_hooks_and_dotfiles_are_always_asked() {
+    let server = MockServer::start();
+    let (git, home) = (p(&server, ".git"), p(&server, "home"));
+    std::fs::create_dir_all(format!("{git}/hooks")).unwrap();
+    std::fs::create_dir_all(&home).unwrap();
+    let (index, hook, a) = (
+        format!("{git}/index"),
+        format!("{git}/hooks/pre-commit"),
+        p(&server, "a.rs"),
+    );
+    let zshrc = format!("{home}/.zshrc");
+    std::fs::write(&zshrc, "").unwrap();
+
+    let run = run_fixture_with_env(
+        &server,
+        &[
+            &format!("write\t{index}\tx"),
+            &format!("write\t{hook}\tx"),
+            &format!("write\t{hook}\tx"),
+            &format!("write\t{hook}\tx"),
+            &format!("write\t{a}\tx"),
+            &format!("write\t{a}\tx"),
+            &format!("write\t{a}\tx"),
+            &format!("append\t{zshrc}\tx"),
+        ],
+        &[
+            ("HOME", &home),
+            ("FS_SHIM_ALLOW_CACHE_MS", "60000"),
+            ("FS_SHIM_TRUST_SELF_CREATED", "0"),
+        ],
+    );
+    assert!(run.results.iter().all(|r| r == "ok"), "{}", run.stderr);
+
+    let ops = server.ops();
+    assert!(ops.iter().all(|(_, path)| *path != index), "{ops:?}");
+    let asked = |path: &str| {
+        ops.iter()
+            .filter(|(m, p)| m == "pre_modify" && p == path)
+            .count()
+    };
+    assert_eq!(asked(&hook), 2);
+    assert_eq!(asked(&a), 1);
+    assert_eq!(asked(&zshrc), 1);
+    let class = |method: &str, path: &str| {
+        server
+            .params(method)
+            .iter()
+            .find(|p| p["path"] == path)
+            .map(|p| p["class"].clone())
+    };
+    assert_eq!(class("pre_create", &hook), Some("vcs_hooks".into()));
+    assert_eq!(class("post_modify", &a), Some("normal".into()));
+    assert_eq!(class("post_modify", &zshrc), Some("dotfile".into()));
+}
+
+#[test]
+fn shared_memory_is_never_reported() {
+    let server = MockServer::start();
+    let name = format!("/nvim-claude-shim-test-{}", std::process::id());
+    let run = run_fixture(&server, &[&format!("shm\t{name}\tx")]);
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    assert_eq!(server.ops(), []);
+}
+
+#[test]
+fn an_fd_dirty_past_max_dirty_age_is_reported_before_its_close() {
+    let server = MockServer::start();
+    let path = p(&server, "wal");
+    std::fs::write(&path, "").unwrap();
+    let run = run_fixture_with_env(
+        &server,
+        &[&format!("agedwrite\t{path}\tpage\t300")],
+        &[("FS_SHIM_MAX_DIRTY_AGE_MS", "100")],
+    );
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    assert_eq!(
+        server.ops(),
+        [
+            ("pre_modify".to_string(), path.clone()),
+            ("post_modify".to_string(), path.clone()),
+            ("post_modify".to_string(), path.clone()),
+        ]
+    );
+    let posts = server.params("post_modify");
+    assert_eq!(posts[0]["reason"], "age_flush");
+    assert_eq!(posts[0]["bytes"], 4);
+    assert!(posts[1].get("reason").is_none());
+    assert_eq!(posts[1]["bytes"], 4);
+}
+
+#[test]
+fn a_disabled_hook_calls_through_while_the_rest_track() {
+    let server = MockServer::start();
+    let (a, b) = (p(&server, "a.txt"), p(&server, "b.txt"));
+    std::fs::write(&b, "x").unwrap();
+    let run = run_fixture_with_env(
+        &server,
+        &[&format!("write\t{a}\tx"), &format!("unlink\t{b}")],
+        &[("FS_SHIM_DISABLE_HOOKS", "unlinkat, unlink")],
+    );
+    assert_eq!(run.results, ["ok", "ok"], "{}", run.stderr);
+    assert!(!std::path::Path::new(&b).exists());
+    assert_eq!(
+        server.ops(),
+        [
+            ("pre_create".to_string(), a.clone()),
+            ("post_create".to_string(), a.clone()),
+        ]
+    );
+    let hooks = &server.params("shim/hello")[0]["hooks"];
+    let names = |k: &str| -> Vec<String> { serde_json::from_value(hooks[k].clone()).unwrap() };
+    assert!(names("disabled").contains(&"unlink".to_string()));
+    assert!(!names("active").contains(&"unlink".to_string()));
+    assert!(names("active").contains(&"write".to_string()));
+}
+
+#[test]
+fn a_path_over_its_preflight_rate_gets_its_last_answer_unasked() {
+    let env = [
+        ("FS_SHIM_PREFLIGHT_RATE", "0.001"),
+        ("FS_SHIM_PREFLIGHT_BURST", "1"),
+    ];
+    let server = MockServer::start();
+    let a = p(&server, "hot.txt");
+    std::fs::write(&a, "").unwrap();
+    let writes = [format!("write\t{a}\tx"), format!("write\t{a}\ty")];
+    let ops: Vec<&str> = writes.iter().map(String::as_str).collect();
+    let run = run_fixture_with_env(&server, &ops, &env);
+    assert_eq!(run.results, ["ok", "ok"], "{}", run.stderr);
+    assert_eq!(server.params("pre_modify").len(), 1);
+    let posts = server.params("post_modify");
+    assert!(posts[0].get("rate_limited").is_none());
+    assert_eq!(posts[1]["rate_limited"], true);
+    assert_eq!(posts[1]["allowed_by"], "server");
+
+    let server = MockServer::with_denied(&["pre_modify"]);
+    let a = p(&server, "hot.txt");
+    std::fs::write(&a, "").unwrap();
+    let writes = [format!("write\t{a}\tx"), format!("write\t{a}\ty")];
+    let ops: Vec<&str> = writes.iter().map(String::as_str).collect();
+    let run = run_fixture_with_env(&server, &ops, &env);
+    let eperm = format!("err {}", libc::EPERM);
+    assert_eq!(run.results, [eperm.clone(), eperm], "{}", run.stderr);
+    assert_eq!(server.params("pre_modify").len(), 1);
+}
+
+#[test]
+fn a_backup_and_rewrite_is_reported_as_one_refactor() {
+    let server = MockServer::start();
+    let (a, bak) = (p(&server, "a.rs"), p(&server, "a.rs.bak"));
+    std::fs::write(&a, "old").unwrap();
+    let run = run_fixture(
+        &server,
+        &[
+            &format!("rename\t{a}\t{bak}"),
+            &format!("write\t{a}\tnew"),
+            &format!("unlink\t{bak}"),
+        ],
+    );
+    assert_eq!(run.results, ["ok", "ok", "ok"]);
+    let refactor = server.params("post_refactor");
+    assert_eq!(refactor.len(), 1);
+    assert_eq!(refactor[0]["kind"], "rewrite");
+    assert_eq!(refactor[0]["path"], a.as_str());
+    assert_eq!(refactor[0]["backup"], bak.as_str());
+    assert_eq!(refactor[0]["synthesized"], true);
+    // The raw events still go out, the summary after them.
+    let ops = server.ops();
+    let delete = ops.iter().position(|(m, _)| m == "post_delete").unwrap();
+    assert!(ops.iter().position(|(m, _)| m == "post_refactor").unwrap() > delete);
+}
+
+#[test]
+fn a_file_moved_in_two_hops_is_reported_as_one_move() {
+    let server = MockServer::start();
+    let (a, b, c) = (p(&server, "a.rs"), p(&server, "b.rs"), p(&server, "c.rs"));
+    std::fs::write(&a, "x").unwrap();
+    let run = run_fixture(
+        &server,
+        &[&format!("rename\t{a}\t{b}"), &format!("rename\t{b}\t{c}")],
+    );
+    assert_eq!(run.results, ["ok", "ok"]);
+    let refactor = server.params("post_refactor");
+    assert_eq!(refactor.len(), 1);
+    assert_eq!(refactor[0]["kind"], "move");
+    assert_eq!(refactor[0]["path"], c.as_str());
+    assert_eq!(refactor[0]["old_path"], a.as_str());
+    assert_eq!(refactor[0]["via"], serde_json::json!([b]));
+}
+
+#[test]
+fn a_forced_deny_refuses_without_asking() {
+    let server = MockServer::start();
+    let (locked, free) = (p(&server, "locked.txt"), p(&server, "free.txt"));
+    std::fs::write(&locked, "").unwrap();
+    std::fs::write(&free, "").unwrap();
+    let run = run_fixture_with_env(
+        &server,
+        &[
+            &format!("overwrite\t{locked}\tx"),
+            &format!("overwrite\t{free}\tx"),
+        ],
+        &[("FS_SHIM_FORCE", "deny:**/locked.txt")],
+    );
+    assert_eq!(run.results, [format!("err {}", libc::EPERM), "ok".into()]);
+    assert_eq!(std::fs::read_to_string(&locked).unwrap(), "");
+    let asked = server.params("pre_modify");
+    assert_eq!(asked.len(), 1);
+    assert_eq!(asked[0]["path"], free.as_str());
+    let forced = server.params("shim/forced");
+    assert_eq!(forced.len(), 1);
+    assert_eq!(forced[0]["op"], "pre_modify");
+    assert_eq!(forced[0]["path"], locked.as_str());
+    assert_eq!(forced[0]["forced"], "deny");
+    assert_eq!(forced[0]["glob"], "**/locked.txt");
+    assert_eq!(forced[0]["allowed"], false);
+}
+
+#[test]
+fn a_forced_allow_overrides_a_denying_server() {
+    let server = MockServer::with_denied(&["pre_modify"]);
+    let a = p(&server, "a.txt");
+    std::fs::write(&a, "").unwrap();
+    let run = run_fixture_with_env(
+        &server,
+        &[&format!("overwrite\t{a}\tx")],
+        &[("FS_SHIM_FORCE", "allow:*.txt")],
+    );
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    assert!(server.params("pre_modify").is_empty());
+    assert_eq!(server.params("post_modify")[0]["allowed_by"], "forced");
+    assert_eq!(server.params("shim/forced")[0]["allowed"], true);
+}
+
+#[test]
+fn a_forced_timeout_waits_out_the_deadline_then_takes_the_fail_policy() {
+    let server = MockServer::start();
+    let a = p(&server, "slow.txt");
+    std::fs::write(&a, "").unwrap();
+    let ops = [format!("overwrite\t{a}\tx")];
+    let ops: Vec<&str> = ops.iter().map(String::as_str).collect();
+    let env = [
+        ("FS_SHIM_FORCE", "timeout:**/slow.txt"),
+        ("FS_SHIM_PRE_TIMEOUT_MS", "200"),
+    ];
+    let run = run_fixture_with_env(&server, &ops, &env);
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    assert!(server.params("pre_modify").is_empty());
+    let post = &server.params("post_modify")[0];
+    assert_eq!(post["allowed_by"], "fallback_open");
+    assert!(post["blocked_ms"].as_u64().unwrap() >= 200, "{post}");
+    assert_eq!(server.params("shim/forced")[0]["forced"], "timeout");
+
+    let server = MockServer::start();
+    let a = p(&server, "slow.txt");
+    std::fs::write(&a, "").unwrap();
+    let ops = [format!("overwrite\t{a}\tx")];
+    let ops: Vec<&str> = ops.iter().map(String::as_str).collect();
+    let run = run_fixture_with_env(
+        &server,
+        &ops,
+        &[env[0], env[1], ("FS_SHIM_FAIL_CLOSED", "1")],
+    );
+    assert_eq!(run.results, [format!("err {}", libc::EPERM)]);
+    assert_eq!(server.params("shim/forced")[0]["allowed"], false);
+}
+
+#[test]
+fn huge_fds_are_counted_without_content_features() {
+    let server = MockServer::start();
+    let (small, big, large) = (
+        p(&server, "small.txt"),
+        p(&server, "big.bin"),
+        p(&server, "large.bin"),
+    );
+    std::fs::write(&small, "0123456789").unwrap();
+    std::fs::write(&big, "").unwrap();
+    std::fs::write(&large, "x".repeat(64)).unwrap();
+    let run = run_fixture_with_env(
+        &server,
+        &[
+            &format!("seekwrites\t{small}\tab\t0"),
+            // The first write decides, for the fd's life.
+            &format!("writes\t{big}\t{}\t3", "y".repeat(40)),
+            &format!("seekwrites\t{large}\tab\t0"),
+        ],
+        &[("FS_SHIM_CONTENT_FEATURES_MAX_BYTES", "32")],
+    );
+    assert_eq!(run.results, ["ok", "ok", "ok"], "{}", run.stderr);
+    let posts = server.params("post_modify");
+    let post = |path: &str| posts.iter().find(|p| p["path"] == path).unwrap().clone();
+    assert_eq!(post(&small)["write_shape"], "overwrite");
+    assert!(post(&small).get("content_features_skipped").is_none());
+    assert_eq!(post(&big)["content_features_skipped"], true);
+    assert_eq!(post(&big)["bytes"], 120);
+    assert!(post(&big).get("write_shape").is_none());
+    assert_eq!(post(&large)["content_features_skipped"], true);
+}
+
+/// A benchmark more than a test: `cargo test --release -- --ignored`.
+#[test]
+#[ignore]
+fn a_gigabyte_written_through_the_shim_costs_little_more_than_without() {
+    let server = MockServer::start();
+    let file = p(&server, "huge.bin");
+    let op = format!("bigwrite\t{file}\t1024");
+    let preload = if cfg!(target_os = "macos") {
+        "DYLD_INSERT_LIBRARIES"
+    } else {
+        "LD_PRELOAD"
+    };
+    // The best of a few runs each, against the disk's own noise.
+    let best = |unset: &[&str]| {
+        (0..3)
+            .map(|_| {
+                // Truncating a gigabyte costs more than writing one.
+                let _ = std::fs::remove_file(&file);
+                let started = std::time::Instant::now();
+                let run = run_fixture_without(&server, &[&op], &[], unset);
+                assert_eq!(run.results, ["ok"], "{}", run.stderr);
+                started.elapsed()
+            })
+            .min()
+            .unwrap()
+    };
+    let plain = best(&[preload]);
+    let shimmed = best(&[]);
+    std::fs::remove_file(&file).unwrap();
+    let post = &server.params("post_create")[0];
+    assert_eq!(post["bytes"], 1u64 << 30);
+    assert_eq!(post["content_features_skipped"], true);
+    assert!(
+        shimmed.saturating_sub(plain) < std::time::Duration::from_millis(250),
+        "shimmed {shimmed:?}, plain {plain:?}"
+    );
+}
+
+#[test]
+fn exclusive_locks_are_reported_until_released() {
+    let server = MockServer::start();
+    let db = p(&server, "app.db");
+    std::fs::write(&db, "").unwrap();
+    let mut ways = vec![("flock", "unlock"), ("fcntl", "close")];
+    // macOS's lockf is an fcntl, and reported as one.
+    if cfg!(target_os = "linux") {
+        ways.push(("lockf", "unlock"));
+    }
+    let ops: Vec<_> = ways
+        .iter()
+        .map(|(how, release)| format!("lock\t{db}\t{how}\t{release}"))
+        .collect();
+    let ops: Vec<_> = ops.iter().map(String::as_str).collect();
+    let run = run_fixture(&server, &ops);
+    assert!(run.results.iter().all(|r| r == "ok"), "{}", run.stderr);
+    let locks = server.params("post_lock");
+    let unlocks = server.params("post_unlock");
+    assert_eq!(locks.len(), ways.len());
+    assert_eq!(unlocks.len(), ways.len());
+    for ((how, release), (lock, unlock)) in ways.iter().zip(locks.iter().zip(&unlocks)) {
+        assert_eq!(lock["path"], db.as_str());
+        assert_eq!(lock["lock"], "exclusive");
+        assert_eq!(lock["via"], *how);
+        assert_eq!(lock["blocking"], true);
+        assert_eq!(unlock["via"], *how);
+        assert_eq!(unlock["reason"], *release);
+    }
+}
+
+#[test]
+fn a_server_that_stops_reading_does_not_stall_close() {
+    let server = MockServer::deaf();
+    let dir = p(&server, "out");
+    std::fs::create_dir(&dir).unwrap();
+    let started = std::time::Instant::now();
+    // Enough posts to fill the socket buffer a few times over.
+    let run = run_fixture_with_env(
+        &server,
+        &[&format!("closes\t{dir}\t2000\t1000")],
+        &[
+            ("FS_SHIM_SEND_TIMEOUT_MS", "100"),
+            ("FS_SHIM_PRE_TIMEOUT_MS", "100"),
+            ("FS_SHIM_BLOCK_BUDGET_MS", "1"),
+        ],
+    );
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    assert!(started.elapsed() < std::time::Duration::from_secs(10));
+}
+
+#[test]
+fn a_symlink_out_of_the_project_is_named_and_refused_by_default() {
+    let server = MockServer::start();
+    let root = server.dir.join("repo");
+    std::fs::create_dir_all(root.join(".git")).unwrap();
+    let outside = p(&server, "config");
+    std::fs::write(&outside, "secret").unwrap();
+    let link = root.join("innocent.txt");
+    std::os::unix::fs::symlink(&outside, &link).unwrap();
+    let link = link.to_string_lossy().to_string();
+    let op = format!("overwrite\t{link}\tpwned");
+
+    let run = run_fixture_in(&server, &root, &[&op], &[]);
+    assert_eq!(
+        run.results,
+        [format!("err {}", libc::EPERM)],
+        "{}",
+        run.stderr
+    );
+    assert_eq!(std::fs::read_to_string(&outside).unwrap(), "secret");
+    // Refused without asking, and told.
+    assert!(server.params("pre_modify").is_empty());
+    let post = &server.params("post_modify")[0];
+    assert_eq!(post["path"], outside.as_str());
+    assert_eq!(
+        (&post["bytes"], &post["allowed_by"]),
+        (&serde_json::json!(0), &serde_json::Value::Null)
+    );
+    assert_eq!(
+        post["symlink_escape"],
+        serde_json::json!({ "from": link, "to": outside, "policy": "block" })
+    );
+
+    // Asked, the answer stands; and the posts name the escape too.
+    let server = MockServer::start();
+    let env = [("FS_SHIM_SYMLINK_ESCAPE", "ask")];
+    let run = run_fixture_in(&server, &root, &[&op], &env);
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    assert_eq!(std::fs::read_to_string(&outside).unwrap(), "pwnedt");
+    let pre = &server.params("pre_modify")[0];
+    assert_eq!(pre["path"], outside.as_str());
+    assert_eq!(pre["symlink_escape"]["from"], link.as_str());
+    let post = &server.params("post_modify")[0];
+    assert_eq!(post["symlink_escape"]["policy"], "ask", "{post}");
+}
+
+#[test]
+fn project_config_applies_below_its_repo_root() {
+    let server = MockServer::start();
+    let root = server.dir.join("repo");
+    std::fs::create_dir_all(root.join(".git")).unwrap();
+    std::fs::create_dir_all(root.join(".nvim-claude")).unwrap();
+    std::fs::create_dir_all(root.join("gen")).unwrap();
+    let config = root.join(".nvim-claude/shim.toml");
+    std::fs::write(&config, "ignore = [\"gen/**\"]\n").unwrap();
+    let generated = root.join("gen/out.rs").to_string_lossy().to_string();
+    let kept = root.join("main.rs").to_string_lossy().to_string();
+    let ops = [
+        format!("write\t{generated}\tx"),
+        format!("write\t{kept}\tx"),
+    ];
+    let ops: Vec<_> = ops.iter().map(String::as_str).collect();
+    let run = run_fixture_in(&server, &root.join("gen"), &ops, &[]);
+    assert_eq!(run.results, ["ok", "ok"], "{}", run.stderr);
+    let paths: Vec<_> = server.ops().into_iter().map(|(_, p)| p).collect();
+    assert!(!paths.contains(&generated), "{paths:?}");
+    assert!(paths.contains(&kept), "{paths:?}");
+
+    std::fs::write(&config, "ignore = []\nappend_mode = \"blok\"\n").unwrap();
+    let run = run_fixture_in(&server, &root, &ops, &[]);
+    assert_eq!(run.results, ["ok", "ok"], "{}", run.stderr);
+    let errors = server.params("shim/config_error");
+    assert_eq!(errors.len(), 1, "{errors:?}");
+    assert_eq!(errors[0]["path"], config.to_str().unwrap());
+    assert_eq!(errors[0]["line"], 2);
+    // The broken file is left out as a whole.
+    assert!(server.ops().iter().any(|(_, p)| *p == generated));
+
+    // A long-lived process picks up the fixed file when told to.
+    let server = MockServer::pushing(&kept, serde_json::json!({ "method": "shim/reload_config" }));
+    let ops = [
+        format!("write\t{}\tignore = [\"gen/**\"]", config.display()),
+        format!("write\t{kept}\tx"),
+        format!("write\t{generated}\tx"),
+    ];
+    let ops: Vec<_> = ops.iter().map(String::as_str).collect();
+    let run = run_fixture_in(&server, &root, &ops, &[]);
+    assert_eq!(run.results, ["ok", "ok", "ok"], "{}", run.stderr);
+    assert_eq!(server.params("shim/config_error").len(), 1);
+    assert!(!server.ops().iter().any(|(_, p)| *p == generated));
+}
+
+#[test]
+fn truncate_is_preflighted() {
+    let server = MockServer::start();
+    let a = p(&server, "t.txt");
+    std::fs::write(&a, "0123456789").unwrap();
+    let run = run_fixture(&server, &[&format!("truncate\t{a}\t4")]);
+    assert_eq!(run.results, ["ok"]);
+    assert_eq!(
+        server.ops(),
+        [
+            ("pre_truncate".to_string(), a.clone()),
+            ("post_modify".to_string(), a.clone())
+        ]
+    );
+    assert_eq!(std::fs::metadata(&a).unwrap().len(), 4);
+}
+
+#[test]
+fn a_truncate_and_the_writes_through_one_fd_are_asked_about_once() {
+    let server = MockServer::start();
+    let (a, b) = (p(&server, "a.txt"), p(&server, "b.txt"));
+    std::fs::write(&a, "0123456789").unwrap();
+    std::fs::write(&b, "0123456789").unwrap();
+    let run = run_fixture(
+        &server,
+        &[
+            &format!("truncwrite\t{a}\tnew"),
+            &format!("writetrunc\t{b}\tnew\t2"),
+        ],
+    );
+    assert_eq!(run.results, ["ok", "ok"], "{}", run.stderr);
+    assert_eq!(std::fs::read_to_string(&a).unwrap(), "new");
+    assert_eq!(std::fs::read_to_string(&b).unwrap(), "ne");
+    assert_eq!(
+        server.ops(),
+        [
+            ("pre_truncate".to_string(), a.clone()),
+            ("post_modify".to_string(), a.clone()),
+            ("pre_modify".to_string(), b.clone()),
+            ("post_modify".to_string(), b.clone())
+        ]
+    );
+    let posts = server.params("post_modify");
+    assert!(
+        posts.iter().all(|p| p["allowed_by"] == "server"),
+        "{posts:?}"
+    );
+    assert_eq!(posts[1]["truncate"]["length"], 2, "{}", posts[1]);
+
+    // A path's allowed truncate covers an open that writes it back, with
+    // the allow cache off.
+    let server = MockServer::start();
+    let a = p(&server, "a.txt");
+    std::fs::write(&a, "0123456789").unwrap();
+    let run = run_fixture(
+        &server,
+        &[
+            &format!("truncate\t{a}\t0"),
+            &format!("overwrite\t{a}\tx"),
+            &format!("overwrite\t{a}\ty"),
+        ],
+    );
+    assert_eq!(run.results, ["ok", "ok", "ok"], "{}", run.stderr);
+    let asked: Vec<String> = server
+        .ops()
+        .into_iter()
+        .filter(|(m, _)| m.starts_with("pre_"))
+        .map(|(m, _)| m)
+        .collect();
+    // The next open is asked again.
+    assert_eq!(asked, ["pre_truncate", "pre_modify"]);
+    assert_eq!(server.params("post_modify")[1]["allowed_by"], "server");
+}
+
+#[test]
+fn open_passes_create_mode_through() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let server = MockServer::start();
+    let (a, b) = (p(&server, "a.txt"), p(&server, "b.txt"));
+    let run = run_fixture(
+        &server,
+        &[&format!("create\t{a}\t640"), &format!("openat\t{b}\t604")],
+    );
+    assert!(run.status.success(), "{}", run.stderr);
+    assert_eq!(run.results, ["ok", "ok"]);
+    // The fixture inherits the test's umask; pick modes it can't mask.
+    let umask = unsafe {
+        let m = libc::umask(0);
+        libc::umask(m);
+        m as u32
+    };
+    for (file, mode) in [(&a, 0o640), (&b, 0o604)] {
+        let got = std::fs::metadata(file).unwrap().permissions().mode() & 0o777;
+        assert_eq!(got, mode & !umask, "{file}");
+    }
+}
+
+#[test]
+fn pre_modify_describes_the_open() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let server = MockServer::start();
+    let (new, old, dup) = (
+        p(&server, "new.txt"),
+        p(&server, "old.txt"),
+        p(&server, "dup.txt"),
+    );
+    std::fs::write(&old, "abc").unwrap();
+    std::fs::write(&dup, "tiny").unwrap();
+    std::fs::set_permissions(&dup, std::fs::Permissions::from_mode(0o640)).unwrap();
+    let run = run_fixture(
+        &server,
+        &[
+            &format!("write\t{new}\tx"),
+            &format!("write\t{old}\tx"),
+            &format!("dupwrite\t{dup}\tx"),
+        ],
+    );
+    assert_eq!(run.results, ["ok"; 3], "{}", run.stderr);
+    let flags = |v: &serde_json::Value| -> Vec<String> {
+        serde_json::from_value(v["flags"].clone()).unwrap()
+    };
+    let created = server.params("pre_create");
+    assert_eq!(created.len(), 1);
+    assert_eq!(created[0]["source"], "open");
+    assert_eq!(created[0]["existing"], false);
+    assert_eq!(created[0]["mode"], "0666");
+    assert!(created[0].get("size").is_none());
+    assert_eq!(flags(&created[0])[..3], ["O_WRONLY", "O_CREAT", "O_TRUNC"]);
+
+    let pre = server.params("pre_modify");
+    assert_eq!(pre.len(), 2);
+    // Sized before O_TRUNC emptied it.
+    assert_eq!(pre[0]["existing"], true);
+    assert_eq!(pre[0]["size"], 3);
+    // A dup'd fd: no open to go by, only fstat and F_GETFL.
+    assert_eq!(pre[1]["source"], "first_write");
+    assert_eq!(pre[1]["mode"], "0640");
+    assert_eq!(pre[1]["size"], 4);
+    assert_eq!(flags(&pre[1])[0], "O_WRONLY");
+}
+
+#[test]
+fn hello_carries_cwd_and_env_and_cwd_changes_are_announced() {
+    let server = MockServer::start();
+    let sub = server.dir.join("sub");
+    std::fs::create_dir(&sub).unwrap();
+    std::fs::write(sub.join("rel.txt"), "x").unwrap();
+    let first = p(&server, "first.txt");
+    std::fs::write(&first, "").unwrap();
+    let run = run_fixture_with_env(
+        &server,
+        &[
+            &format!("write\t{first}\tx"),
+            &format!("chdir\t{}", sub.display()),
+            "unlink\trel.txt",
+        ],
+        &[
+            ("VIRTUAL_ENV", "/venvs/proj"),
+            ("FS_SHIM_HELLO_ENV", "VIRTUAL_ENV,NO_SUCH_VAR"),
+        ],
+    );
+    assert_eq!(run.results, ["ok"; 3], "{}", run.stderr);
+
+    let hello = &server.params("shim/hello")[0];
+    let load_cwd = std::env::current_dir().unwrap();
+    assert_eq!(hello["cwd"], load_cwd.to_string_lossy().as_ref());
+    assert_eq!(
+        hello["env"],
+        serde_json::json!({ "VIRTUAL_ENV": "/venvs/proj" })
+    );
+
+    // getcwd reports the directory with symlinks (macOS's /var) resolved.
+    let sub = std::fs::canonicalize(&sub).unwrap();
+    let changed = server.params("shim/cwd_changed");
+    assert_eq!(changed.len(), 1);
+    assert_eq!(changed[0]["cwd"], sub.to_string_lossy().as_ref());
+    let rel = sub.join("rel.txt").to_string_lossy().to_string();
+    assert!(server
+        .ops()
+        .contains(&("pre_delete".to_string(), rel.clone())));
+    assert!(server.ops().contains(&("post_delete".to_string(), rel)));
+}
+
+#[test]
+fn relative_paths_follow_each_chdir_and_fchdir() {
+    let server = MockServer::start();
+    let (a, b) = (server.dir.join("a"), server.dir.join("b"));
+    for d in [&a, &b] {
+        std::fs::create_dir(d).unwrap();
+        std::fs::write(d.join("x"), "").unwrap();
+    }
+    std::fs::write(a.join("y"), "").unwrap();
+    let run = run_fixture(
+        &server,
+        &[
+            &format!("chdir\t{}", a.display()),
+            "unlink\tx",
+            &format!("fchdir\t{}", b.display()),
+            "unlink\tx",
+            "chdir\t../a",
+            "unlink\ty",
+        ],
+    );
+    assert_eq!(run.results, ["ok"; 6], "{}", run.stderr);
+
+    let (a, b) = (
+        std::fs::canonicalize(&a).unwrap(),
+        std::fs::canonicalize(&b).unwrap(),
+    );
+    let deleted: Vec<String> = server
+        .ops()
+        .into_iter()
+        .filter(|(m, _)| m == "post_delete")
+        .map(|(_, p)| p)
+        .collect();
+    let s = |p: std::path::PathBuf| p.to_string_lossy().to_string();
+    assert_eq!(deleted, [s(a.join("x")), s(b.join("x")), s(a.join("y"))]);
+    let changed: Vec<_> = server
+        .params("shim/cwd_changed")
+        .iter()
+        .map(|c| c["cwd"].clone())
+        .collect();
+    assert_eq!(changed, [s(a.clone()), s(b), s(a)]);
+}
+
+#[test]
+fn events_name_their_thread_once_the_server_asks() {
+    let server = MockServer::threaded();
+    let base = p(&server, "t");
+    let run = run_fixture(&server, &[&format!("churn\t{base}\t2\t2")]);
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+
+    let all = server.params("pre_delete");
+    let by_path = |n: usize| -> Vec<serde_json::Value> {
+        let path = format!("{base}.{n}");
+        all.iter().filter(|e| e["path"] == *path).cloned().collect()
+    };
+    let (zero, one) = (by_path(0), by_path(1));
+    assert_eq!(zero.len(), 2);
+    for events in [&zero, &one] {
+        assert!(events[0]["tid"].as_u64().is_some_and(|t| t > 0));
+        assert_eq!(events[0]["tid"], events[1]["tid"]);
+        assert_eq!(events[0]["conn"], events[1]["conn"]);
+    }
+    // One connection per thread.
+    assert_ne!(zero[0]["tid"], one[0]["tid"]);
+    assert_ne!(zero[0]["conn"], one[0]["conn"]);
+    let posts = server.params("post_delete");
+    assert!(posts
+        .iter()
+        .all(|e| e["tid"].is_u64() && e["conn"].is_u64()));
+
+    // Not offered back: the fields stay out.
+    let plain = MockServer::start();
+    let f = p(&plain, "f.txt");
+    std::fs::write(&f, "").unwrap();
+    let run = run_fixture(&plain, &[&format!("write\t{f}\tx")]);
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    let post = &plain.params("post_modify")[0];
+    assert!(post.get("tid").is_none() && post.get("conn").is_none());
+}
+
+#[test]
+fn a_panicking_hook_still_completes_the_call_once() {
+    let server = MockServer::start();
+    let f = p(&server, "log.txt");
+    std::fs::write(&f, "").unwrap();
+    let ops: Vec<String> = ["a", "b", "c", "d"]
+        .iter()
+        .map(|t| format!("append\t{f}\t{t}"))
+        .collect();
+    let ops: Vec<&str> = ops.iter().map(String::as_str).collect();
+    let run = run_fixture_with_env(&server, &ops, &[("NVIM_CLAUDE_SHIM_PANIC_IN", "write")]);
+    // The fixture's own stdout goes through the same hook.
+    assert!(run.status.success(), "{}", run.stderr);
+    assert_eq!(run.results, ["ok"; 4], "{}", run.stderr);
+    // Each write happened exactly once, panic or not.
+    assert_eq!(std::fs::read_to_string(&f).unwrap(), "abcd");
+
+    let reports: Vec<&str> = run
+        .stderr
+        .lines()
+        .filter(|l| l.contains("handler_panic"))
+        .collect();
+    assert_eq!(reports.len(), 3, "{}", run.stderr);
+    assert!(reports[0].contains("write hook, panic 1: injected"));
+    assert!(reports[2].ends_with("hook disabled"));
+    assert!(!run.stderr.contains("panicked at"), "{}", run.stderr);
+}
+
+#[test]
+fn preflights_stop_blocking_once_the_budget_is_spent() {
+    let server = MockServer::silent();
+    let files: Vec<String> = ["a", "b", "c", "d"]
+        .iter()
+        .map(|n| p(&server, &format!("{n}.txt")))
+        .collect();
+    for f in &files {
+        std::fs::write(f, "").unwrap();
+    }
+    let ops: Vec<String> = files.iter().map(|f| format!("write\t{f}\tx")).collect();
+    let ops: Vec<&str> = ops.iter().map(String::as_str).collect();
+    let run = run_fixture_with_env(
+        &server,
+        &ops,
+        &[
+            ("FS_SHIM_PRE_TIMEOUT_MS", "200"),
+            ("FS_SHIM_BLOCK_BUDGET_MS", "300"),
+        ],
+    );
+    assert_eq!(run.results, ["ok"; 4], "{}", run.stderr);
+    // Timing out on a and b spends the budget; c and d aren't asked.
+    let asked: Vec<String> = server
+        .params("pre_modify")
+        .iter()
+        .map(|p| p["path"].as_str().unwrap().to_string())
+        .collect();
+    assert_eq!(asked, files[..2]);
+    let exceeded = server.params("shim/budget_exceeded");
+    assert_eq!(exceeded.len(), 1);
+    assert_eq!(exceeded[0]["budget_ms"], 300);
+    assert!(exceeded[0]["blocked_ms"].as_u64().unwrap() >= 400);
+}
+
+#[test]
+fn each_paths_events_arrive_in_path_seq_order() {
+    let server = MockServer::start();
+    let base = p(&server, "churn");
+    let (cycles, threads) = (25, 4);
+    // Each rewrite of a file it just made asks, as with `trust_self_created` off.
+    let run = run_fixture_with_env(
+        &server,
+        &[&format!("churn\t{base}\t{cycles}\t{threads}")],
+        &[("FS_SHIM_TRUST_SELF_CREATED", "0")],
+    );
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+
+    let cycle = [
+        "pre_create",
+        "post_create",
+        "pre_modify",
+        "post_modify",
+        "pre_delete",
+        "post_delete",
+    ];
+    let mut by_path: std::collections::BTreeMap<String, Vec<(u64, String)>> = Default::default();
+    for e in server.events() {
+        let (Some(method), Some(path)) = (e["method"].as_str(), e["params"]["path"].as_str())
+        else {
+            continue;
+        };
+        let seq = e["params"]["path_seq"].as_u64().expect("path_seq");
+        by_path
+            .entry(path.to_string())
+            .or_default()
+            .push((seq, method.to_string()));
+    }
+    assert_eq!(by_path.len(), threads);
+    for (path, events) in by_path {
+        // One thread per path, so arrival order is event order.
+        let seqs: Vec<u64> = events.iter().map(|(s, _)| *s).collect();
+        let expected: Vec<u64> = (1..=seqs.len() as u64).collect();
+        assert_eq!(seqs, expected, "{path}");
+        let methods: Vec<&str> = events.iter().map(|(_, m)| m.as_str()).collect();
+        assert_eq!(methods, cycle.repeat(cycles), "{path}");
+    }
+}
+
+#[test]
+fn posts_say_how_their_preflight_was_decided() {
+    let server = MockServer::start();
+    let (a, log) = (p(&server, "a.txt"), p(&server, "build.log"));
+    std::fs::write(&a, "").unwrap();
+    std::fs::write(&log, "").unwrap();
+    let run = run_fixture_with_env(
+        &server,
+        &[
+            &format!("write\t{a}\tx"),
+            &format!("write\t{a}\ty"),
+            &format!("append\t{log}\tline"),
+            &format!("unlink\t{a}"),
+        ],
+        &[("FS_SHIM_ALLOW_CACHE_MS", "60000")],
+    );
+    assert_eq!(run.results, ["ok"; 4], "{}", run.stderr);
+    let decided: Vec<_> = server
+        .events()
+        .iter()
+        .filter(|e| e["method"].as_str().is_some_and(|m| m.starts_with("post_")))
+        .map(|e| {
+            (
+                e["params"]["path"].clone(),
+                e["params"]["allowed_by"].clone(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        decided,
+        [
+            (a.clone().into(), "server".into()),
+            (a.clone().into(), "cache".into()),
+            // Appends aren't preflighted by default.
+            (log.into(), serde_json::Value::Null),
+            (a.into(), "server".into()),
+        ]
+    );
+    for post in server.params("post_modify") {
+        assert!(post["blocked_ms"].is_u64(), "{post}");
+    }
+}
+
+#[test]
+fn unanswered_preflights_are_reported_as_fallbacks() {
+    let server = MockServer::silent();
+    let a = p(&server, "a.txt");
+    std::fs::write(&a, "").unwrap();
+    let run = run_fixture_with_env(
+        &server,
+        &[&format!("write\t{a}\tx")],
+        &[("FS_SHIM_PRE_TIMEOUT_MS", "100")],
+    );
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    let post = &server.params("post_modify")[0];
+    assert_eq!(post["allowed_by"], "fallback_open");
+    assert!(post["blocked_ms"].as_u64().unwrap() >= 100);
+}
+
+#[test]
+fn unanswered_denials_leave_a_trail() {
+    let server = MockServer::silent();
+    let (a, log) = (p(&server, "a.txt"), p(&server, "logs/denials.log"));
+    std::fs::write(&a, "").unwrap();
+    let run = run_fixture_with_env(
+        &server,
+        &[&format!("overwrite\t{a}\tx")],
+        &[
+            ("FS_SHIM_PRE_TIMEOUT_MS", "100"),
+            ("FS_SHIM_FAIL_CLOSED", "1"),
+            ("FS_SHIM_DENIAL_LOG", &log),
+        ],
+    );
+    assert_eq!(run.results, [format!("err {}", libc::EPERM)]);
+    let trail = std::fs::read_to_string(&log).unwrap();
+    assert!(
+        trail.ends_with(&format!("op=pre_modify path={a} reason=transport_down\n")),
+        "{trail}"
+    );
+    assert_eq!(trail.lines().count(), 1);
+    assert!(run.stderr.contains(&format!(
+        "denied 1 operation with no answer from the server; see {log}"
+    )));
+}
+
+/// Runs, with no destination at all, a create, an overwrite, truncates
+/// to zero and to less, renames over a file and onto a new name, and an
+/// unlink, each on fresh files named after `tag`.
+fn run_without_a_server(server: &MockServer, tag: &str, env: &[(&str, &str)]) -> FixtureRun {
+    let f = |n: &str| p(server, &format!("{tag}-{n}"));
+    for n in ["old", "clear", "shrink", "from", "to", "new", "gone"] {
+        std::fs::write(f(n), "xx").unwrap();
+    }
+    let mut env = env.to_vec();
+    env.push(("NVIM_CLAUDE_SHIM_NO_DISCOVERY", "1"));
+    run_fixture_without(
+        server,
+        &[
+            &format!("write\t{}\tx", f("created")),
+            &format!("overwrite\t{}\tx", f("old")),
+            &format!("truncate\t{}\t0", f("clear")),
+            &format!("truncate\t{}\t1", f("shrink")),
+            &format!("rename\t{}\t{}", f("from"), f("to")),
+            &format!("rename\t{}\t{}", f("new"), f("renamed")),
+            &format!("unlink\t{}", f("gone")),
+        ],
+        &env,
+        &["NVIM_CLAUDE_SHIM_SOCK"],
+    )
+}
+
+#[test]
+fn without_a_server_failing_open_allows_everything_quietly() {
+    let server = MockServer::start();
+    let run = run_without_a_server(&server, "open", &[]);
+    assert_eq!(run.results, ["ok"; 7], "{}", run.stderr);
+    assert!(!run.stderr.contains("no_server"), "{}", run.stderr);
+    assert!(server.events().is_empty());
+}
+
+#[test]
+fn without_a_server_failing_closed_denies_what_cant_be_undone() {
+    let server = MockServer::start();
+    let log = p(&server, "denials.log");
+    let env = [("FS_SHIM_FAIL_CLOSED", "1"), ("FS_SHIM_DENIAL_LOG", &log)];
+    let run = run_without_a_server(&server, "closed", &env);
+    let denied = format!("err {}", libc::EPERM);
+    let (ok, denied) = ("ok", denied.as_str());
+    assert_eq!(
+        run.results,
+        [ok, ok, denied, ok, denied, ok, denied],
+        "{}",
+        run.stderr
+    );
+    assert_eq!(
+        run.stderr.matches("nvim-claude shim: no_server:").count(),
+        1
+    );
+    let trail = std::fs::read_to_string(&log).unwrap();
+    assert_eq!(trail.matches("reason=no_server").count(), 3, "{trail}");
+    assert!(trail.contains("op=pre_delete"));
+
+    let mut env = env.to_vec();
+    env.push(("FS_SHIM_NO_SERVER_WRITES", "deny"));
+    let run = run_without_a_server(&server, "strict", &env);
+    assert_eq!(run.results, [denied; 7], "{}", run.stderr);
+    assert!(server.events().is_empty());
+}
+
+#[test]
+fn control_characters_in_names_stay_inside_their_frames() {
+    let server = MockServer::start();
+    let names = [
+        p(&server, "two\nlines.txt"),
+        p(&server, "cr\r{\"id\":1,\"result\":{\"allow\":true}}\n.txt"),
+        p(&server, "\x1b[2Jclear.txt"),
+    ];
+    let mut ops = Vec::new();
+    for n in &names {
+        std::fs::write(n, "").unwrap();
+        ops.push(format!("overwrite\t{n}\tx"));
+    }
+    let ops: Vec<&str> = ops.iter().map(String::as_str).collect();
+    let run = run_fixture(&server, &ops);
+    assert_eq!(run.results, ["ok", "ok", "ok"], "{}", run.stderr);
+    let want: Vec<(String, String)> = names
+        .iter()
+        .flat_map(|n| {
+            [
+                ("pre_modify".into(), n.clone()),
+                ("post_modify".into(), n.clone()),
+            ]
+        })
+        .collect();
+    assert_eq!(server.ops(), want);
+}
+
+#[test]
+fn the_denial_log_escapes_control_characters() {
+    let server = MockServer::silent();
+    let (a, log) = (p(&server, "a\n\x1b[31mb.txt"), p(&server, "denials.log"));
+    std::fs::write(&a, "").unwrap();
+    let run = run_fixture_with_env(
+        &server,
+        &[&format!("overwrite\t{a}\tx")],
+        &[
+            ("FS_SHIM_PRE_TIMEOUT_MS", "100"),
+            ("FS_SHIM_FAIL_CLOSED", "1"),
+            ("FS_SHIM_DENIAL_LOG", &log),
+        ],
+    );
+    assert_eq!(run.results, [format!("err {}", libc::EPERM)]);
+    let trail = std::fs::read_to_string(&log).unwrap();
+    assert_eq!(trail.lines().count(), 1, "{trail:?}");
+    assert!(!trail.contains('\x1b'), "{trail:?}");
+    assert!(trail.contains("a\\n\\x1b[31mb.txt reason="), "{trail:?}");
+}
+
+#[test]
+fn nonblocking_first_writes_do_not_wait_for_an_answer() {
+    let server = MockServer::silent();
+    let a = p(&server, "a.txt");
+    std::fs::write(&a, "").unwrap();
+    let started = std::time::Instant::now();
+    let run = run_fixture_with_env(
+        &server,
+        &[&format!("nbwrite\t{a}\tx\t0")],
+        &[("FS_SHIM_PRE_TIMEOUT_MS", "5000")],
+    );
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    assert!(started.elapsed() < std::time::Duration::from_secs(4));
+    assert_eq!(server.params("pre_modify").len(), 1);
+    let post = &server.params("post_modify")[0];
+    assert_eq!(post["allowed_by"], "optimistic");
+    assert_eq!(post["preflight_mode"], "async");
+    assert_eq!(post["bytes"], 2);
+}
+
+#[test]
+fn tokio_workers_never_wait_for_a_preflight() {
+    let server = MockServer::silent();
+    let a = p(&server, "a.txt");
+    std::fs::write(&a, "").unwrap();
+    let started = std::time::Instant::now();
+    // Named as tokio names its workers; the 16-byte limit makes it
+    // `tokio-runtime-w` on Linux.
+    let run = run_fixture_with_env(
+        &server,
+        &[&format!("threadwrite\t{a}\tx\ttokio-runtime-worker")],
+        &[("FS_SHIM_PRE_TIMEOUT_MS", "5000")],
+    );
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    assert!(started.elapsed() < std::time::Duration::from_secs(1));
+    let post = &server.params("post_modify")[0];
+    assert_eq!(post["allowed_by"], "optimistic");
+    assert_eq!(post["preflight_mode"], "async");
+}
+
+#[test]
+fn audited_paths_carry_the_writers_frames() {
+    let server = MockServer::start();
+    let (env, other) = (p(&server, ".env"), p(&server, "notes.txt"));
+    std::fs::write(&env, "").unwrap();
+    std::fs::write(&other, "").unwrap();
+    let run = run_fixture_with_env(
+        &server,
+        &[
+            &format!("overwrite\t{env}\tSECRET=1"),
+            &format!("overwrite\t{other}\tx"),
+        ],
+        &[("FS_SHIM_CAPTURE_BACKTRACE", "**/.env")],
+    );
+    assert_eq!(run.results, ["ok", "ok"], "{}", run.stderr);
+    let pres = server.params("pre_modify");
+    let frames = pres[0]["backtrace"].as_array().unwrap();
+    assert!(frames.len() <= 16);
+    // The fixture is this test binary, which made the write.
+    let exe = std::env::current_exe().unwrap();
+    let exe = exe.file_name().unwrap().to_str().unwrap();
+    assert!(
+        frames
+            .iter()
+            .any(|f| f["module"].as_str().is_some_and(|m| m.ends_with(exe))),
+        "{frames:?}"
+    );
+    assert!(pres[1].get("backtrace").is_none());
+}
+
+#[test]
+fn a_late_denial_fails_the_writes_after_it() {
+    let server = MockServer::with_denied(&["pre_modify"]);
+    let a = p(&server, "a.txt");
+    std::fs::write(&a, "").unwrap();
+    let run = run_fixture(&server, &[&format!("nbwrite\t{a}\tx\t200")]);
+    assert_eq!(
+        run.results,
+        [format!("err {}", libc::EPERM)],
+        "{}",
+        run.stderr
+    );
+    assert_eq!(std::fs::read_to_string(&a).unwrap(), "x");
+}
+
+#[cfg(target_os = "macos")]
+#[test]
+fn a_copyfile_is_one_create_however_it_copies() {
+    let server = MockServer::start();
+    let (from, to) = (p(&server, "src.txt"), p(&server, "copy.txt"));
+    std::fs::write(&from, "hello").unwrap();
+    let run = run_fixture(&server, &[&format!("copyfile\t{from}\t{to}")]);
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    assert_eq!(
+        server.ops(),
+        [
+            ("pre_create".to_string(), to.clone()),
+            ("post_create".to_string(), to.clone())
+        ]
+    );
+    assert_eq!(server.params("post_create")[0]["via"], "copyfile");
+    assert_eq!(std::fs::read_to_string(&to).unwrap(), "hello");
+}
+
+#[cfg(target_os = "macos")]
+#[test]
+fn a_recursive_removefile_is_one_delete() {
+    let server = MockServer::start();
+    let tree = p(&server, "tree");
+    std::fs::create_dir_all(format!("{tree}/sub")).unwrap();
+    for f in ["a.txt", "sub/b.txt"] {
+        std::fs::write(format!("{tree}/{f}"), "x").unwrap();
+    }
+    let run = run_fixture(&server, &[&format!("removefile\t{tree}")]);
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    assert_eq!(
+        server.ops(),
+        [
+            ("pre_delete".to_string(), tree.clone()),
+            ("post_delete".to_string(), tree.clone())
+        ]
+    );
+    assert_eq!(server.params("pre_delete")[0]["recursive"], true);
+    assert!(!std::path::Path::new(&tree).exists());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn moves_across_filesystems_are_reported_as_renames() {
+    use std::os::unix::fs::MetadataExt;
+    let ram = std::path::Path::new("/dev/shm");
+    let repo = std::path::Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let dev = |p: &std::path::Path| p.metadata().map(|m| m.dev()).ok();
+    if dev(ram).is_none() || dev(ram) == dev(repo) {
+        eprintln!("skipping: no RAM disk on its own filesystem");
+        return;
+    }
+    let server = MockServer::start();
+    let name = format!("ncshim-mv-{}", std::process::id());
+    let (old, new) = (ram.join(&name), repo.join(&name));
+    std::fs::write(&old, "moved").unwrap();
+    let (old, new) = (old.to_string_lossy(), new.to_string_lossy());
+    let run = run_fixture(&server, &[&format!("mv\t{old}\t{new}")]);
+    let _ = std::fs::remove_file(&*new);
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+
+    let renames = server.params("post_rename");
+    assert_eq!(renames.len(), 1);
+    assert_eq!(renames[0]["path"], new.as_ref());
+    assert_eq!(renames[0]["old_path"], old.as_ref());
+    assert_eq!(renames[0]["via"], "copy");
+    // The delete is still reported on its own, just before.
+    let ops = server.ops();
+    let at = ops.iter().position(|(m, _)| m == "post_rename").unwrap();
+    assert_eq!(ops[at - 1], ("post_delete".to_string(), old.to_string()));
+}
+
+#[test]
+fn new_files_are_reported_as_created() {
+    let server = MockServer::with_denied(&["pre_modify"]);
+    let (new, moved, old) = (
+        p(&server, "new.txt"),
+        p(&server, "moved.txt"),
+        p(&server, "old.txt"),
+    );
+    std::fs::write(&old, "old").unwrap();
+    let run = run_fixture(
+        &server,
+        &[
+            &format!("create\t{new}\t640"),
+            &format!("rename\t{new}\t{moved}"),
+            &format!("rename\t{moved}\t{old}"),
+        ],
+    );
+    assert_eq!(run.results, ["ok"; 3], "{}", run.stderr);
+    // Onto a new name it's a creation, onto an existing one a modification.
+    assert_eq!(
+        server.ops(),
+        [
+            ("pre_rename".to_string(), moved.clone()),
+            ("post_create".to_string(), moved),
+            ("pre_rename".to_string(), old.clone()),
+            ("post_modify".to_string(), old.clone()),
+            // Two hops of one file, summed up.
+            ("post_refactor".to_string(), old),
+        ]
+    );
+}
+
+#[test]
+fn created_files_report_mode_and_final_size() {
+    // Denying pre_modify doesn't stop a creation.
+    let server = MockServer::with_denied(&["pre_modify"]);
+    let file = p(&server, "fresh.txt");
+    let run = run_fixture(&server, &[&format!("write\t{file}\thello")]);
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    assert_eq!(
+        server.ops(),
+        [
+            ("pre_create".to_string(), file.clone()),
+            ("post_create".to_string(), file.clone())
+        ]
+    );
+    let post = &server.params("post_create")[0];
+    assert_eq!(post["mode"], "0666");
+    let umask = unsafe {
+        let m = libc::umask(0);
+        libc::umask(m);
+        m as u32
+    };
+    assert_eq!(post["umask"], format!("{umask:04o}"));
+    assert_eq!(post["final_mode"], format!("{:04o}", 0o666 & !umask));
+    assert_eq!(post["size"], 5);
+    assert_eq!(post["bytes"], 5);
+}
+
+#[test]
+fn debug_write_calls_are_coalesced() {
+    let server = MockServer::start();
+    let file = p(&server, "log.txt");
+    std::fs::write(&file, "").unwrap();
+    let run = run_fixture_with_env(
+        &server,
+        &[&format!("writes\t{file}\tline\n\t500")],
+        &[("NVIM_CLAUDE_SHIM_DEBUG", "1")],
+    );
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    let writes: Vec<_> = server
+        .params("shim/write_call")
+        .into_iter()
+        .filter(|w| w["tracked_path"] == file.as_str())
+        .collect();
+    assert!(writes.len() < 50, "{} frames", writes.len());
+    let calls: u64 = writes.iter().map(|w| w["repeat"].as_u64().unwrap()).sum();
+    let bytes: u64 = writes.iter().map(|w| w["res"].as_u64().unwrap()).sum();
+    assert_eq!((calls, bytes), (500, 2500));
+    assert_eq!(server.params("post_modify")[0]["bytes"], 2500);
+}
+
+#[test]
+fn post_modify_carries_byte_count() {
+    let server = MockServer::start();
+    let file = p(&server, "n.txt");
+    std::fs::write(&file, "").unwrap();
+    let run = run_fixture(&server, &[&format!("write\t{file}\thello")]);
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    let posts = server.params("post_modify");
+    assert_eq!(posts.len(), 1);
+    assert_eq!(posts[0]["bytes"], 5);
+}
+
+#[test]
+fn zero_byte_writev_is_not_reported() {
+    let server = MockServer::start();
+    let file = p(&server, "empty.txt");
+    std::fs::write(&file, "keep").unwrap();
+    let run = run_fixture(&server, &[&format!("writev0\t{file}")]);
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    assert!(server.ops().is_empty(), "{:?}", server.ops());
+}
+
+#[test]
+fn writev_counts_the_bytes_its_iovecs_carry() {
+    let server = MockServer::start();
+    let file = p(&server, "v.txt");
+    std::fs::write(&file, "").unwrap();
+    let run = run_fixture_with_env(
+        &server,
+        &[&format!("writev3\t{file}")],
+        &[("NVIM_CLAUDE_SHIM_DEBUG", "1")],
+    );
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    let call = &server.params("shim/writev_call")[0];
+    assert_eq!(
+        (&call["requested"], &call["written"]),
+        (&serde_json::json!(5), &serde_json::json!(5))
+    );
+    let post = &server.params("post_modify")[0];
+    assert_eq!(post["bytes"], 5);
+    assert!(post.get("requested").is_none());
+    assert_eq!(std::fs::read_to_string(&file).unwrap(), "abcde");
+}
+
+#[test]
+fn failing_writes_are_not_reported() {
+    let server = MockServer::start();
+    let file = p(&server, "ro.txt");
+    std::fs::write(&file, "keep").unwrap();
+    let run = run_fixture(&server, &[&format!("failwrites\t{file}\t3")]);
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    assert!(server.params("post_modify").is_empty());
+    assert_eq!(std::fs::read_to_string(&file).unwrap(), "keep");
+}
+
+#[test]
+fn length_prefixed_framing_is_negotiated() {
+    let server = MockServer::framed(&["pre_delete"]);
+    let kept = p(&server, "kept.txt");
+    let written = p(&server, "written.txt");
+    std::fs::write(&kept, "x").unwrap();
+    std::fs::write(&written, "").unwrap();
+    let run = run_fixture(
+        &server,
+        &[&format!("unlink\t{kept}"), &format!("write\t{written}\thi")],
+    );
+    assert_eq!(
+        run.results,
+        [format!("err {}", libc::EPERM), "ok".to_string()],
+        "{}",
+        run.stderr
+    );
+    let hellos = server.params("shim/hello");
+    assert!(!hellos.is_empty());
+    assert_eq!(hellos[0]["framing"][0], "length-prefixed");
+    assert_eq!(
+        server.ops(),
+        [
+            ("pre_delete".into(), kept),
+            ("pre_modify".into(), written.clone()),
+            ("post_modify".into(), written),
+        ]
+    );
+}
+
+#[test]
+fn oversized_frames_are_not_sent() {
+    let server = MockServer::start();
+    let file = p(&server, "a.txt");
+    let run = run_fixture_with_env(
+        &server,
+        &[&format!("write\t{file}\thi")],
+        &[("FS_SHIM_MAX_FRAME_BYTES", "64")],
+    );
+    // The preflight never went out, so the write went ahead unasked.
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    assert!(server.ops().is_empty());
+}
+
+#[test]
+fn silent_server_times_out_instead_of_hanging() {
+    let server = MockServer::start();
+    let silent = server.dir.join("silent.sock");
+    let listener = std::os::unix::net::UnixListener::bind(&silent).unwrap();
+    std::thread::spawn(move || {
+        // Accept, read nothing, answer nothing.
+        let mut held = Vec::new();
+        for conn in listener.incoming() {
+            held.push(conn);
+        }
+    });
+    let file = p(&server, "a.txt");
+    let started = std::time::Instant::now();
+    let run = run_fixture_with_env(
+        &server,
+        &[&format!("write\t{file}\thi")],
+        &[
+            ("NVIM_CLAUDE_SHIM_SOCK", silent.to_str().unwrap()),
+            ("FS_SHIM_PRE_TIMEOUT_MS", "200"),
+        ],
+    );
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    assert!(started.elapsed() < std::time::Duration::from_secs(5));
+}
+
+#[test]
+fn server_flush_request_posts_dirty_fds_early() {
+    let dir = common::TempDir::new("flush");
+    let held = dir.join("held.txt").to_string_lossy().to_string();
+    let second = dir.join("second.txt").to_string_lossy().to_string();
+    std::fs::write(&held, "").unwrap();
+    std::fs::write(&second, "").unwrap();
+    let flush = serde_json::json!({ "jsonrpc": "2.0", "id": "srv-1", "method": "shim/flush" });
+    let server = MockServer::pushing(&second, flush);
+    let run = run_fixture(&server, &[&format!("writeheld\t{held}\t{second}\thi")]);
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    // `held` is posted while `second` waits on its preflight, and again at
+    // close for the write after that.
+    assert_eq!(
+        server.ops(),
+        [
+            ("pre_modify".into(), held.clone()),
+            ("pre_modify".into(), second.clone()),
+            ("post_modify".into(), held.clone()),
+            ("post_modify".into(), second),
+            ("post_modify".into(), held.clone()),
+        ]
+    );
+    let answer = server
+        .events()
+        .into_iter()
+        .find(|e| e["id"] == "srv-1")
+        .unwrap();
+    assert_eq!(answer["result"]["flushed"], 1);
+    // The flush also sends the summary so far, before the exit one.
+    let summaries = server.params("shim/summary");
+    assert_eq!(summaries.len(), 2);
+    let paths: Vec<_> = summaries[0]["paths"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|p| p["path"].as_str().unwrap())
+        .collect();
+    assert_eq!(paths, [held.as_str()]);
+}
+
+#[test]
+fn classify_answers_with_the_shims_view_and_touches_nothing() {
+    let dir = common::TempDir::new("classify");
+    let index = dir.join(".git/index").to_string_lossy().to_string();
+    let other = dir.join("other.rs").to_string_lossy().to_string();
+    let query = serde_json::json!({
+        "jsonrpc": "2.0",
+        "id": "srv-1",
+        "method": "shim/classify",
+        "params": { "path": index },
+    });
+    let server = MockServer::pushing(&other, query);
+    let run = run_fixture(&server, &[&format!("write\t{other}\tx")]);
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    let answer = server
+        .events()
+        .into_iter()
+        .find(|e| e["id"] == "srv-1")
+        .unwrap();
+    let view = &answer["result"];
+    assert_eq!(view["path"], index.as_str());
+    assert_eq!(view["op"], "pre_modify");
+    assert_eq!(view["class"], "vcs_internal");
+    assert_eq!(view["class_policy"], "off");
+    assert_eq!(view["outcome"], "ignore");
+    assert_eq!(view["ignored_by"]["class"], "vcs_internal");
+    assert!(!dir.join(".git").exists());
+    assert!(server.ops().iter().all(|(_, p)| *p != index));
+}
+
+#[test]
+fn thousands_of_writes_under_a_low_fd_limit_leave_the_shim_fds_to_spare() {
+    let server = MockServer::start();
+    let dir = common::TempDir::new("nofile");
+    let d = dir.path().to_string_lossy().to_string();
+    let ops = ["nofile\t64".to_string(), format!("writes\t{d}\t2000")];
+    let ops: Vec<_> = ops.iter().map(String::as_str).collect();
+    let run = run_fixture(&server, &ops);
+    assert_eq!(run.results, ["ok", "ok"], "{}", run.stderr);
+    assert!(!run.stderr.contains("fd_exhausted"), "{}", run.stderr);
+    let creates = server
+        .ops()
+        .iter()
+        .filter(|(m, _)| m == "pre_create")
+        .count();
+    assert_eq!(creates, 2000);
+}
+
+#[test]
+fn running_out_of_fds_disables_the_destination_once() {
+    let server = MockServer::start();
+    let dir = common::TempDir::new("exhaust");
+    let [before, a, b, after] =
+        ["before", "a", "b", "after"].map(|f| dir.join(f).to_string_lossy().to_string());
+    let ops = [
+        "nofile\t64".to_string(),
+        format!("write\t{before}\tv"),
+        format!("exhaustwrite\t{a}\tx"),
+        format!("exhaustwrite\t{b}\ty"),
+        format!("write\t{after}\tz"),
+    ];
+    let ops: Vec<_> = ops.iter().map(String::as_str).collect();
+    let run = run_fixture(&server, &ops);
+    assert_eq!(run.results, ["ok"; 5], "{}", run.stderr);
+    // Said once, and nothing more is sent or tried.
+    assert_eq!(
+        run.stderr.matches("fd_exhausted").count(),
+        1,
+        "{}",
+        run.stderr
+    );
+    assert!(
+        server.ops().iter().all(|(_, p)| *p == before),
+        "{:?}",
+        server.ops()
+    );
+    assert_eq!(std::fs::read_to_string(&a).unwrap(), "x");
+    assert_eq!(std::fs::read_to_string(&after).unwrap(), "z");
+}
+
+#[test]
+fn path_history_is_queried_and_summarized() {
+    let dir = common::TempDir::new("history");
+    let bundle = dir.join("bundle.js").to_string_lossy().to_string();
+    let other = dir.join("other.js").to_string_lossy().to_string();
+    let query = serde_json::json!({
+        "jsonrpc": "2.0",
+        "id": "srv-1",
+        "method": "shim/query_path",
+        "params": { "path": bundle },
+    });
+    let server = MockServer::pushing(&other, query);
+    let ops = [
+        format!("write\t{bundle}\tv1"),
+        format!("write\t{bundle}\tv2"),
+        format!("write\t{other}\tx"),
+    ];
+    let ops: Vec<_> = ops.iter().map(String::as_str).collect();
+    let run = run_fixture_with_env(&server, &ops, &[("FS_SHIM_PATH_HISTORY", "1")]);
+    assert_eq!(run.results, ["ok", "ok", "ok"], "{}", run.stderr);
+    let answer = server
+        .events()
+        .into_iter()
+        .find(|e| e["id"] == "srv-1")
+        .unwrap();
+    let record = &answer["result"];
+    assert_eq!(record["path"], bundle.as_str());
+    assert_eq!(record["modified"], 2);
+    assert!(record["first_ms"].as_u64().unwrap() <= record["last_ms"].as_u64().unwrap());
+    let summary = &server.params("shim/summary")[0];
+    let entry = summary["paths"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|p| p["path"] == bundle.as_str())
+        .unwrap();
+    assert_eq!(entry["modified"], 2);
+
+    // Off by default.
+    let server = MockServer::start();
+    let run = run_fixture(&server, &[&format!("write\t{bundle}\tv3")]);
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    let summary = &server.params("shim/summary")[0];
+    assert!(summary["paths"][0].get("modified").is_none());
+}
+
+#[test]
+fn exported_flush_posts_dirty_fds_without_closing() {
+    let server = MockServer::start();
+    let held = p(&server, "held.txt");
+    std::fs::write(&held, "").unwrap();
+    let run = run_fixture(&server, &[&format!("flushheld\t{held}\thi\t1")]);
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    assert_eq!(
+        server.ops(),
+        [
+            ("pre_modify".into(), held.clone()),
+            ("post_modify".into(), held.clone()),
+            ("post_modify".into(), held),
+        ]
+    );
+    let bytes: Vec<u64> = server
+        .params("post_modify")
+        .iter()
+        .map(|p| p["bytes"].as_u64().unwrap())
+        .collect();
+    assert_eq!(bytes, [2, 2]);
+}
+
+#[test]
+fn invalidated_paths_are_preflighted_again() {
+    let dir = common::TempDir::new("invalidate");
+    let held = dir.join("held.txt").to_string_lossy().to_string();
+    let second = dir.join("second.txt").to_string_lossy().to_string();
+    std::fs::write(&held, "").unwrap();
+    std::fs::write(&second, "").unwrap();
+    let revoke = serde_json::json!({
+        "jsonrpc": "2.0",
+        "method": "shim/invalidate",
+        "params": { "paths": [held] },
+    });
+    let server = MockServer::pushing(&second, revoke);
+    let run = run_fixture_with_env(
+        &server,
+        &[&format!("writeheld\t{held}\t{second}\thi")],
+        &[("FS_SHIM_ALLOW_CACHE_MS", "60000")],
+    );
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    // The open fd on `held` asks again on its next write.
+    assert_eq!(
+        server.ops(),
+        [
+            ("pre_modify".into(), held.clone()),
+            ("pre_modify".into(), second.clone()),
+            ("post_modify".into(), second),
+            ("pre_modify".into(), held.clone()),
+            ("post_modify".into(), held),
+        ]
+    );
+    let acks = server.params("shim/invalidated");
+    assert_eq!(acks, [serde_json::json!({ "evicted": 1, "rearmed": 1 })]);
+}
+
+#[test]
+fn fflush_posts_the_stream_before_it_closes() {
+    for which in ["stream", "null"] {
+        let server = MockServer::start();
+        let (a, b) = (p(&server, "a.txt"), p(&server, "b.txt"));
+        std::fs::write(&a, "").unwrap();
+        std::fs::write(&b, "").unwrap();
+        let run = run_fixture(
+            &server,
+            &[&format!("stdio\t{a}\t{b}\thello\t{which}"), "memflush\tx"],
+        );
+        assert_eq!(run.results, ["ok", "ok"], "{which}: {}", run.stderr);
+        // `a`'s post comes at the flush. (glibc's `fclose` closes with an
+        // internal call the shim never sees, so without it none would.)
+        assert_eq!(
+            server.ops(),
+            [
+                ("pre_modify".into(), a.clone()),
+                ("post_modify".into(), a.clone()),
+                ("pre_modify".into(), b.clone()),
+                ("post_modify".into(), b),
+            ],
+            "{which}"
+        );
+        assert_eq!(server.params("post_modify")[0]["bytes"], 5);
+        assert_eq!(std::fs::read_to_string(&a).unwrap(), "hello");
+    }
+}
+
+#[test]
+fn conflict_paths_are_always_asked_about() {
+    let dir = common::TempDir::new("conflicts");
+    let a = dir.join("a.rs").to_string_lossy().to_string();
+    let log = dir.join("notes.log").to_string_lossy().to_string();
+    std::fs::write(&a, "").unwrap();
+    std::fs::write(&log, "").unwrap();
+    let push = serde_json::json!({
+        "jsonrpc": "2.0",
+        "method": "shim/conflict_paths",
+        "params": { "paths": [a, log] },
+    });
+    let server = MockServer::pushing(&a, push);
+    let run = run_fixture_with_env(
+        &server,
+        &[
+            &format!("write\t{a}\tone"),
+            &format!("write\t{a}\ttwo"),
+            &format!("append\t{log}\tline"),
+        ],
+        &[("FS_SHIM_ALLOW_CACHE_MS", "60000")],
+    );
+    assert_eq!(run.results, ["ok", "ok", "ok"], "{}", run.stderr);
+    // The second write would be a cache hit and the append notify-only.
+    let pre = server.params("pre_modify");
+    let asked: Vec<_> = pre
+        .iter()
+        .map(|p| (p["path"].as_str().unwrap(), p["conflict"] == true))
+        .collect();
+    assert_eq!(
+        asked,
+        [
+            (a.as_str(), false),
+            (a.as_str(), true),
+            (log.as_str(), true)
+        ]
+    );
+}
+
+#[test]
+fn the_servers_own_files_are_kept_out_of_events() {
+    let dir = common::TempDir::new("selfpaths");
+    let held = dir.join("baseline.json").to_string_lossy().to_string();
+    let second = dir.join("second.txt").to_string_lossy().to_string();
+    std::fs::write(&held, "").unwrap();
+    std::fs::write(&second, "").unwrap();
+    let register = serde_json::json!({
+        "jsonrpc": "2.0",
+        "id": "srv-1",
+        "method": "shim/self_paths",
+        "params": { "globs": ["**/baseline.json"] },
+    });
+    let server = MockServer::pushing(&second, register);
+    let run = run_fixture(&server, &[&format!("writeheld\t{held}\t{second}\thi")]);
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    // Registered while `second` waits: `held`'s close sends nothing.
+    assert_eq!(
+        server.ops(),
+        [
+            ("pre_modify".into(), held),
+            ("pre_modify".into(), second.clone()),
+            ("post_modify".into(), second),
+        ]
+    );
+    let answer = server
+        .events()
+        .into_iter()
+        .find(|e| e["id"] == "srv-1")
+        .unwrap();
+    assert_eq!(
+        answer["result"],
+        serde_json::json!({ "paths": 0, "globs": 1 })
+    );
+}
+
+#[test]
+fn unacked_notifications_are_replayed_after_reconnect() {
+    let server = MockServer::lossy();
+    let a = p(&server, "a.txt");
+    let b = p(&server, "b.txt");
+    std::fs::write(&a, "").unwrap();
+    std::fs::write(&b, "").unwrap();
+    let run = run_fixture(
+        &server,
+        &[
+            &format!("write\t{a}\tone"),
+            "sleep\t100",
+            &format!("write\t{b}\ttwo"),
+        ],
+    );
+    assert_eq!(run.results, ["ok"; 3], "{}", run.stderr);
+    // The first connection died with `post_modify a`; the second replays it.
+    assert_eq!(
+        server.ops(),
+        [
+            ("pre_modify".into(), a.clone()),
+            ("post_modify".into(), a),
+            ("pre_modify".into(), b.clone()),
+            ("post_modify".into(), b),
+        ]
+    );
+    let seqs: Vec<_> = server
+        .params("post_modify")
+        .iter()
+        .map(|p| p["seq"].clone())
+        .collect();
+    assert_eq!(seqs, [1, 2]);
+    // The third carries the exit summary.
+    assert_eq!(server.params("shim/hello").len(), 3);
+}
+
+#[test]
+fn ignored_operations_are_counted_by_glob() {
+    let dir = common::TempDir::new("ignstats");
+    let log = dir.join("build.log").to_string_lossy().to_string();
+    let kept = dir.join("main.rs").to_string_lossy().to_string();
+    let stats = serde_json::json!({ "jsonrpc": "2.0", "id": "stats", "method": "shim/stats" });
+    let server = MockServer::pushing(&kept, stats);
+    let run = run_fixture_with_env(
+        &server,
+        &[&format!("write\t{log}\tx"), &format!("write\t{kept}\tx")],
+        &[("FS_SHIM_IGNORE", "*.tmp:*.log")],
+    );
+    assert_eq!(run.results, ["ok"; 2], "{}", run.stderr);
+    let answer = server
+        .events()
+        .into_iter()
+        .find(|e| e["id"] == "stats")
+        .unwrap();
+    let ignored = &answer["result"]["ignored"];
+    // The preflight and the post for build.log.
+    assert_eq!(
+        ignored["globs"],
+        serde_json::json!({ "*.tmp": 0, "*.log": 2 })
+    );
+    assert_eq!(ignored["recent"][0]["path"], log);
+    assert_eq!(ignored["recent"].as_array().unwrap().len(), 2);
+}
+
+#[test]
+fn ignore_audit_reports_each_path_once() {
+    let dir = common::TempDir::new("ignaudit");
+    let kept = dir.join("main.rs").to_string_lossy().to_string();
+    let log = dir.join("build.log").to_string_lossy().to_string();
+    let audit = serde_json::json!({
+        "jsonrpc": "2.0",
+        "method": "shim/ignore_audit",
+        "params": { "enabled": true },
+    });
+    let server = MockServer::pushing(&kept, audit);
+    let run = run_fixture_with_env(
+        &server,
+        &[
+            &format!("write\t{kept}\tx"),
+            &format!("write\t{log}\tx"),
+            &format!("write\t{log}\ty"),
+        ],
+        &[("FS_SHIM_IGNORE", "*.log")],
+    );
+    assert_eq!(run.results, ["ok"; 3], "{}", run.stderr);
+    assert_eq!(
+        server.params("shim/ignored"),
+        [serde_json::json!({ "op": "pre_create", "path": log, "by": { "glob": "*.log" } })]
+    );
+}
+
+#[test]
+fn allowed_preflights_are_cached_until_invalidated() {
+    let dir = common::TempDir::new("cache");
+    let a = dir.join("a.txt").to_string_lossy().to_string();
+    let b = dir.join("b.txt").to_string_lossy().to_string();
+    std::fs::write(&a, "").unwrap();
+    std::fs::write(&b, "").unwrap();
+    let drop_all = serde_json::json!({ "jsonrpc": "2.0", "method": "shim/invalidate_cache" });
+    let server = MockServer::pushing(&b, drop_all);
+    let run = run_fixture_with_env(
+        &server,
+        &[
+            &format!("write\t{a}\tone"),
+            &format!("write\t{a}\ttwo"),
+            &format!("write\t{b}\tx"),
+            &format!("write\t{a}\tthree"),
+        ],
+        &[("FS_SHIM_ALLOW_CACHE_MS", "60000")],
+    );
+    assert_eq!(run.results, ["ok"; 4], "{}", run.stderr);
+    let pre: Vec<String> = server
+        .ops()
+        .into_iter()
+        .filter(|(m, _)| m == "pre_modify")
+        .map(|(_, path)| path)
+        .collect();
+    assert_eq!(pre, [a.clone(), b, a]);
+}
+
+#[test]
+fn msgpack_rpc_transport_carries_preflights_and_posts() {
+    let server = MockServer::msgpack(&["pre_delete"]);
+    let kept = p(&server, "kept.txt");
+    let written = p(&server, "written.txt");
+    std::fs::write(&kept, "x").unwrap();
+    let run = run_fixture_with_env(
+        &server,
+        &[&format!("unlink\t{kept}"), &format!("write\t{written}\thi")],
+        &[("NVIM_CLAUDE_SHIM_FORMAT", "msgpack-rpc")],
+    );
+    assert_eq!(
+        run.results,
+        [format!("err {}", libc::EPERM), "ok".to_string()],
+        "{}",
+        run.stderr
+    );
+    assert!(std::path::Path::new(&kept).exists());
+    assert_eq!(
+        server.ops(),
+        [
+            ("pre_delete".into(), kept),
+            ("pre_create".into(), written.clone()),
+            ("post_create".into(), written),
+        ]
+    );
+}
+
+#[test]
+fn socket_path_falls_back_through_expanded_candidates() {
+    let server = MockServer::start();
+    let f = p(&server, "a.txt");
+    let dir = server.sock.parent().unwrap().to_string_lossy().to_string();
+    let name = server
+        .sock
+        .file_name()
+        .unwrap()
+        .to_string_lossy()
+        .to_string();
+    let sock =
+        format!("${{NVIM_CLAUDE_TEST_UNSET}}/x.sock:{dir}/missing-%u-%p.sock:${{SOCK_DIR}}/{name}");
+    let run = run_fixture_with_env(
+        &server,
+        &[&format!("write\t{f}\tx")],
+        &[("NVIM_CLAUDE_SHIM_SOCK", &sock), ("SOCK_DIR", &dir)],
+    );
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    assert_eq!(
+        server.ops(),
+        [("pre_create".into(), f.clone()), ("post_create".into(), f)]
+    );
+}
+
+#[test]
+fn socket_is_discovered_without_env() {
+    let server = MockServer::start();
+    let state = server.dir.join("state");
+    std::fs::create_dir_all(state.join("nvim-claude")).unwrap();
+    std::os::unix::fs::symlink(&server.sock, state.join("nvim-claude/shim.sock")).unwrap();
+    let state = state.to_string_lossy().to_string();
+    let f = p(&server, "a.txt");
+    let op = format!("write\t{f}\tx");
+    let run = run_fixture_without(
+        &server,
+        &[&op],
+        &[("XDG_STATE_HOME", &state)],
+        &["NVIM_CLAUDE_SHIM_SOCK"],
+    );
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    assert_eq!(server.ops().len(), 2);
+
+    let run = run_fixture_without(
+        &server,
+        &[&op],
+        &[
+            ("XDG_STATE_HOME", &state),
+            ("NVIM_CLAUDE_SHIM_NO_DISCOVERY", "1"),
+        ],
+        &["NVIM_CLAUDE_SHIM_SOCK"],
+    );
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    assert_eq!(server.ops().len(), 2);
+}
+
+#[test]
+fn inherited_socket_fd_is_used_and_kept_open() {
+    use std::os::fd::AsRawFd;
+
+    let server = MockServer::start();
+    let stream = std::os::unix::net::UnixStream::connect(&server.sock).unwrap();
+    let fd = stream.as_raw_fd();
+    unsafe { libc::fcntl(fd, libc::F_SETFD, 0) };
+    let fd = fd.to_string();
+    let f = p(&server, "a.txt");
+    let run = run_fixture_without(
+        &server,
+        &[&format!("closefd\t{fd}"), &format!("write\t{f}\tx")],
+        &[("NVIM_CLAUDE_SHIM_SOCK_FD", &fd)],
+        &["NVIM_CLAUDE_SHIM_SOCK"],
+    );
+    assert_eq!(run.results, ["ok", "ok"], "{}", run.stderr);
+    drop(stream);
+    assert_eq!(
+        server.ops(),
+        [("pre_create".into(), f.clone()), ("post_create".into(), f)]
+    );
+}
+
+#[test]
+fn non_socket_fd_is_not_adopted() {
+    let server = MockServer::start();
+    let f = p(&server, "a.txt");
+    let run = run_fixture_without(
+        &server,
+        &[&format!("write\t{f}\tx")],
+        &[
+            ("NVIM_CLAUDE_SHIM_SOCK_FD", "0"),
+            ("NVIM_CLAUDE_SHIM_NO_DISCOVERY", "1"),
+        ],
+        &["NVIM_CLAUDE_SHIM_SOCK"],
+    );
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    assert!(server.ops().is_empty());
+}
+
+#[test]
+fn sockets_in_world_writable_dirs_are_refused() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let server = MockServer::start();
+    let dir = server.sock.parent().unwrap();
+    std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o777)).unwrap();
+    let f = p(&server, "a.txt");
+    let op = format!("write\t{f}\tx");
+    let run = run_fixture(&server, &[&op]);
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    assert!(server.ops().is_empty());
+
+    let run = run_fixture_with_env(
+        &server,
+        &[&op],
+        &[("NVIM_CLAUDE_SHIM_ALLOW_INSECURE_DIR", "1")],
+    );
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    assert_eq!(server.ops().len(), 2);
+
+    std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o1777)).unwrap();
+    let run = run_fixture(&server, &[&op]);
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    assert_eq!(server.ops().len(), 4);
+}
+
+fn destinations_config(server: &MockServer, body: &str) -> String {
+    let path = server.dir.join("shim.toml");
+    std::fs::write(&path, body).unwrap();
+    path.to_string_lossy().to_string()
+}
+
+fn sink_config(server: &MockServer, sink: &MockServer) -> String {
+    destinations_config(
+        server,
+        &format!(
+            "[[destination]]\nkind = \"unix\"\naddress = \"{}\"\nrole = \"sink\"\n",
+            sink.sock.display()
+        ),
+    )
+}
+
+#[test]
+fn notifications_fan_out_to_sinks() {
+    let server = MockServer::start();
+    let sink = MockServer::start();
+    let cfg = sink_config(&server, &sink);
+    let f = p(&server, "a.txt");
+    let run = run_fixture_with_env(
+        &server,
+        &[&format!("write\t{f}\tx")],
+        &[("NVIM_CLAUDE_SHIM_CONFIG", &cfg)],
+    );
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    assert_eq!(
+        server.ops(),
+        [
+            ("pre_create".into(), f.clone()),
+            ("post_create".into(), f.clone())
+        ]
+    );
+    assert_eq!(sink.ops(), [("post_create".into(), f)]);
+}
+
+#[test]
+fn a_tcp_address_without_a_port_disables_the_destination_once() {
+    let server = MockServer::start();
+    let sink = MockServer::start();
+    let cfg = sink_config(&server, &sink);
+    let (a, b) = (p(&server, "a.txt"), p(&server, "b.txt"));
+    let run = run_fixture_without(
+        &server,
+        &[&format!("write\t{a}\tx"), &format!("write\t{b}\tx")],
+        &[
+            ("NVIM_CLAUDE_SHIM_CONFIG", &cfg),
+            ("NVIM_CLAUDE_SHIM_TCP", "localhost"),
+        ],
+        &["NVIM_CLAUDE_SHIM_SOCK"],
+    );
+    assert_eq!(run.results, ["ok", "ok"], "{}", run.stderr);
+    assert_eq!(
+        run.stderr.matches("config_error").count(),
+        1,
+        "{}",
+        run.stderr
+    );
+    assert!(server.events().is_empty());
+    let errors = sink.params("shim/config_error");
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0]["variable"], "NVIM_CLAUDE_SHIM_TCP");
+    assert!(errors[0]["message"].as_str().unwrap().contains("no port"));
+    assert_eq!(
+        sink.ops(),
+        [("post_create".into(), a), ("post_create".into(), b)]
+    );
+}
+
+#[test]
+fn sinks_are_served_inline_when_no_sender_thread_starts() {
+    let server = MockServer::start();
+    let sink = MockServer::start();
+    let cfg = sink_config(&server, &sink);
+    let files: Vec<String> = (0..20).map(|i| p(&server, &format!("{i}.txt"))).collect();
+    let ops: Vec<String> = files.iter().map(|f| format!("write\t{f}\tx")).collect();
+    let ops: Vec<&str> = ops.iter().map(String::as_str).collect();
+    let run = run_fixture_with_env(
+        &server,
+        &ops,
+        &[
+            ("NVIM_CLAUDE_SHIM_CONFIG", &cfg),
+            ("NVIM_CLAUDE_SHIM_FAIL_SPAWN", "1000"),
+        ],
+    );
+    assert_eq!(run.results.len(), 20, "{}", run.stderr);
+    let posted: Vec<_> = files
+        .into_iter()
+        .map(|f| ("post_create".to_string(), f))
+        .collect();
+    assert_eq!(sink.ops(), posted);
+    let errors = sink.params("shim/error");
+    assert_eq!(errors.len(), 1, "{errors:?}");
+    assert_eq!(errors[0]["error"], "sink_fallback");
+    assert!(run.stderr.contains("sink_fallback"), "{}", run.stderr);
+}
+
+#[test]
+fn a_sink_loses_nothing_while_its_sender_thread_is_retried() {
+    let server = MockServer::start();
+    let sink = MockServer::start();
+    let cfg = sink_config(&server, &sink);
+    let (a, b, c) = (
+        p(&server, "a.txt"),
+        p(&server, "b.txt"),
+        p(&server, "c.txt"),
+    );
+    let run = run_fixture_with_env(
+        &server,
+        &[
+            &format!("write\t{a}\tx"),
+            // Past the retry, so the next post starts a worker.
+            "sleep\t1100",
+            &format!("write\t{b}\tx"),
+            &format!("write\t{c}\tx"),
+        ],
+        &[
+            ("NVIM_CLAUDE_SHIM_CONFIG", &cfg),
+            ("NVIM_CLAUDE_SHIM_FAIL_SPAWN", "1"),
+        ],
+    );
+    assert_eq!(run.results, ["ok", "ok", "ok", "ok"], "{}", run.stderr);
+    assert_eq!(
+        sink.ops(),
+        [
+            ("post_create".into(), a),
+            ("post_create".into(), b),
+            ("post_create".into(), c)
+        ]
+    );
+    assert_eq!(sink.params("shim/error").len(), 1);
+}
+
+#[test]
+fn config_authoritative_destination_answers_preflights() {
+    let server = MockServer::with_denied(&["pre_delete"]);
+    let sink = MockServer::start();
+    let cfg = destinations_config(
+        &server,
+        &format!(
+            "[[destination]]\nkind = \"unix\"\naddress = \"{}\"\n\n\
+             [[destination]]\nkind = \"unix\"\naddress = \"{}\"\nrole = \"authoritative\"\n",
+            sink.sock.display(),
+            server.sock.display()
+        ),
+    );
+    let f = p(&server, "a.txt");
+    std::fs::write(&f, "x").unwrap();
+    let run = run_fixture_without(
+        &server,
+        &[&format!("unlink\t{f}")],
+        &[("NVIM_CLAUDE_SHIM_CONFIG", &cfg)],
+        &["NVIM_CLAUDE_SHIM_SOCK"],
+    );
+    assert_eq!(
+        run.results,
+        [format!("err {}", libc::EPERM)],
+        "{}",
+        run.stderr
+    );
+    assert_eq!(server.ops(), [("pre_delete".into(), f)]);
+    assert!(sink.ops().is_empty());
+}
+
+#[test]
+fn routed_subtrees_are_answered_by_their_own_destination() {
+    let server = MockServer::start();
+    let pkg = MockServer::with_denied(&["pre_delete"]);
+    let cfg = destinations_config(
+        &server,
+        &format!(
+            "[[destination]]\nname = \"a\"\nkind = \"unix\"\naddress = \"{}\"\n\n\
+             [[route]]\nglob = \"packages/a/**\"\ndestination = \"a\"\n",
+            pkg.sock.display()
+        ),
+    );
+    let src = server.dir.join("packages/a/src");
+    std::fs::create_dir_all(&src).unwrap();
+    let (inside, deeper, outside) = (
+        p(&server, "packages/a/lib.rs"),
+        src.join("main.rs").to_string_lossy().to_string(),
+        p(&server, "top.rs"),
+    );
+    for f in [&inside, &deeper, &outside] {
+        std::fs::write(f, "x").unwrap();
+    }
+    let run = run_fixture_with_env(
+        &server,
+        &[
+            &format!("unlink\t{inside}"),
+            &format!("write\t{deeper}\ty"),
+            &format!("unlink\t{outside}"),
+        ],
+        &[("NVIM_CLAUDE_SHIM_CONFIG", &cfg)],
+    );
+    assert_eq!(
+        run.results,
+        [format!("err {}", libc::EPERM), "ok".into(), "ok".into()],
+        "{}",
+        run.stderr
+    );
+    assert_eq!(
+        pkg.ops(),
+        [
+            ("pre_delete".into(), inside),
+            ("pre_modify".into(), deeper.clone()),
+            ("post_modify".into(), deeper)
+        ]
+    );
+    assert_eq!(
+        server.ops(),
+        [
+            ("pre_delete".into(), outside.clone()),
+            ("post_delete".into(), outside)
+        ]
+    );
+}
+
+#[test]
+fn dead_sink_does_not_hold_up_the_process() {
+    let server = MockServer::start();
+    let cfg = destinations_config(
+        &server,
+        "[[destination]]\nkind = \"unix\"\naddress = \"/nonexistent/audit.sock\"\n",
+    );
+    let f = p(&server, "a.txt");
+    let ops: Vec<String> = (0..20).map(|_| format!("write\t{f}\tx")).collect();
+    let ops: Vec<&str> = ops.iter().map(String::as_str).collect();
+    let start = std::time::Instant::now();
+    let env = [
+        ("NVIM_CLAUDE_SHIM_CONFIG", cfg.as_str()),
+        ("FS_SHIM_TRUST_SELF_CREATED", "0"),
+    ];
+    let run = run_fixture_with_env(&server, &ops, &env);
+    assert_eq!(run.results.len(), 20, "{}", run.stderr);
+    assert!(start.elapsed() < std::time::Duration::from_secs(3));
+    assert_eq!(server.ops().len(), 40);
+}
@@ -0,0 +1,68 @@
+//! A `notify-only` build: posts go out, and nothing the server says can
+//! block or deny a call.
+
+#![cfg(feature = "notify-only")]
+
+mod common;
+
+use common::{run_fixture, MockServer};
+
+#[test]
+fn fixture() {
+    common::fixture_entry();
+}
+
+fn p(server: &MockServer, name: &str) -> String {
+    server.dir.join(name).to_string_lossy().to_string()
+}
+
+#[test]
+fn denials_have_no_effect_and_posts_still_arrive() {
+    let server = MockServer::with_denied(&[
+        "pre_create",
+        "pre_modify",
+        "pre_delete",
+        "pre_rename",
+        "pre_truncate",
+    ]);
+    let (old, made, new, gone) = (
+        p(&server, "old.txt"),
+        p(&server, "made.txt"),
+        p(&server, "new.txt"),
+        p(&server, "gone.txt"),
+    );
+    std::fs::write(&old, "abc").unwrap();
+    std::fs::write(&gone, "").unwrap();
+    let run = run_fixture(
+        &server,
+        &[
+            &format!("write\t{old}\tx"),
+            &format!("write\t{made}\tx"),
+            &format!("truncate\t{old}\t0"),
+            &format!("rename\t{old}\t{new}"),
+            &format!("unlink\t{gone}"),
+        ],
+    );
+    assert_eq!(run.results, ["ok"; 5], "{}", run.stderr);
+    assert!(std::fs::metadata(&new).is_ok());
+    assert!(std::fs::metadata(&gone).is_err());
+
+    // Nothing was asked, not even the hello: every frame is a notification.
+    let events = server.events();
+    assert!(events.iter().all(|e| e.get("id").is_none()), "{events:?}");
+    let post = |m: &str, p: &str| (m.to_string(), p.to_string());
+    assert_eq!(
+        server.ops(),
+        [
+            post("post_modify", &old),
+            post("post_create", &made),
+            post("post_modify", &old),
+            post("post_create", &new),
+            post("post_delete", &gone),
+        ]
+    );
+    assert_eq!(
+        server.params("post_delete")[0]["allowed_by"],
+        serde_json::Value::Null
+    );
+}
@@ -0,0 +1,421 @@
+//! Config-driven policy: append handling, truncate kinds, ignore globs
+//! and watched directories.
+
+// Policy is about preflights, which a `notify-only` build never sends.
+#![cfg(not(feature = "notify-only"))]
+
+mod common;
+
+use common::{run_fixture, run_fixture_with_env, MockServer};
+
+#[test]
+fn fixture() {
+    common::fixture_entry();
+}
+
+fn p(server: &MockServer, name: &str) -> String {
+    server.dir.join(name).to_string_lossy().to_string()
+}
+
+#[test]
+fn appends_are_notify_only_by_default() {
+    let server = MockServer::start();
+    let log = p(&server, "build.log");
+    std::fs::write(&log, "").unwrap();
+    let run = run_fixture(&server, &[&format!("append\t{log}\tline")]);
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    assert!(server.params("pre_modify").is_empty());
+    let posts = server.params("post_modify");
+    assert_eq!(posts.len(), 1);
+    assert_eq!(posts[0]["path"], log.as_str());
+    assert_eq!(posts[0]["append"], true);
+}
+
+#[test]
+fn append_mode_block_preflights() {
+    let server = MockServer::with_denied(&["pre_modify"]);
+    let log = p(&server, "build.log");
+    std::fs::write(&log, "").unwrap();
+    let run = run_fixture_with_env(
+        &server,
+        &[&format!("append\t{log}\tline")],
+        &[("FS_SHIM_APPEND_MODE", "block")],
+    );
+    assert_eq!(run.results, [format!("err {}", libc::EPERM)]);
+}
+
+#[test]
+fn append_mode_off_sends_nothing() {
+    let server = MockServer::start();
+    let log = p(&server, "build.log");
+    let run = run_fixture_with_env(
+        &server,
+        &[&format!("append\t{log}\tline")],
+        &[("FS_SHIM_APPEND_MODE", "off")],
+    );
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    assert!(server.ops().is_empty());
+}
+
+#[test]
+fn truncates_say_what_they_do_and_follow_their_kinds_policy() {
+    let server = MockServer::start();
+    let (a, b, c) = (p(&server, "a"), p(&server, "b"), p(&server, "c"));
+    for f in [&a, &b, &c] {
+        std::fs::write(f, "0123456789").unwrap();
+    }
+    let run = run_fixture_with_env(
+        &server,
+        &[
+            &format!("ftruncate\t{a}\t0"),
+            &format!("truncate\t{b}\t4"),
+            &format!("ftruncate\t{c}\t64"),
+        ],
+        &[("FS_SHIM_TRUNCATE_EXTEND", "notify")],
+    );
+    assert_eq!(run.results, ["ok", "ok", "ok"], "{}", run.stderr);
+    let pre = server.params("pre_truncate");
+    assert_eq!(
+        pre.len(),
+        2,
+        "the extension isn't asked about: {:?}",
+        server.ops()
+    );
+    assert_eq!(
+        (
+            &pre[0]["path"],
+            &pre[0]["kind"],
+            &pre[0]["length"],
+            &pre[0]["size"]
+        ),
+        (&a.as_str().into(), &"clear".into(), &0.into(), &10.into())
+    );
+    assert_eq!(
+        (&pre[1]["path"], &pre[1]["kind"]),
+        (&b.as_str().into(), &"shrink".into())
+    );
+    let posts = server.params("post_modify");
+    assert_eq!(posts.len(), 3);
+    assert_eq!(posts[0]["truncate"]["kind"], "clear");
+    assert_eq!(
+        (&posts[1]["kind"], &posts[1]["length"]),
+        (&"shrink".into(), &4.into())
+    );
+    assert_eq!(posts[2]["truncate"]["kind"], "extend");
+    assert_eq!(posts[2]["truncate"]["size"], 10);
+    assert_eq!(std::fs::metadata(&c).unwrap().len(), 64);
+}
+
+#[test]
+fn truncate_policy_off_sends_nothing() {
+    let server = MockServer::start();
+    let a = p(&server, "a");
+    std::fs::write(&a, "0123456789").unwrap();
+    let run = run_fixture_with_env(
+        &server,
+        &[&format!("ftruncate\t{a}\t0")],
+        &[("FS_SHIM_TRUNCATE_CLEAR", "off")],
+    );
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    assert!(server.ops().is_empty(), "{:?}", server.ops());
+    assert_eq!(std::fs::metadata(&a).unwrap().len(), 0);
+}
+
+#[test]
+fn append_posts_are_debounced() {
+    let server = MockServer::start();
+    let log = p(&server, "build.log");
+    let op = format!("append\t{log}\tline");
+    std::fs::write(&log, "").unwrap();
+    let run = run_fixture_with_env(
+        &server,
+        &[&op, &op, &op],
+        &[("FS_SHIM_APPEND_DEBOUNCE_MS", "60000")],
+    );
+    assert_eq!(run.results, ["ok", "ok", "ok"], "{}", run.stderr);
+    assert_eq!(server.params("post_modify").len(), 1);
+}
+
+#[test]
+fn ignored_paths_get_no_preflight_or_events() {
+    let server = MockServer::with_denied(&["pre_modify", "pre_delete"]);
+    let (junk, kept) = (p(&server, "x.tmp"), p(&server, "y.txt"));
+    std::fs::write(&kept, "keep").unwrap();
+    let run = run_fixture_with_env(
+        &server,
+        &[
+            &format!("write\t{junk}\tdata"),
+            &format!("unlink\t{junk}"),
+            &format!("write\t{kept}\tdata"),
+        ],
+        &[("FS_SHIM_IGNORE", "*.tmp")],
+    );
+    assert_eq!(
+        run.results,
+        [
+            "ok".to_string(),
+            "ok".to_string(),
+            format!("err {}", libc::EPERM)
+        ]
+    );
+    assert_eq!(server.ops(), [("pre_modify".to_string(), kept)]);
+}
+
+#[test]
+fn ignore_globs_beat_append_notifications() {
+    let server = MockServer::start();
+    let log = p(&server, "build.log");
+    let run = run_fixture_with_env(
+        &server,
+        &[&format!("append\t{log}\tline")],
+        &[("FS_SHIM_IGNORE", "*.log")],
+    );
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    assert!(server.ops().is_empty());
+}
+
+#[test]
+fn config_file_is_read() {
+    let server = MockServer::start();
+    let cfg = server.dir.join("shim.toml");
+    std::fs::write(&cfg, "append_mode = \"off\"\n").unwrap();
+    let log = p(&server, "build.log");
+    let run = run_fixture_with_env(
+        &server,
+        &[&format!("append\t{log}\tline")],
+        &[("NVIM_CLAUDE_SHIM_CONFIG", cfg.to_str().unwrap())],
+    );
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    assert!(server.ops().is_empty());
+}
+
+#[test]
+fn large_files_are_reported_with_sizes_only() {
+    let server = MockServer::start();
+    let big = p(&server, "weights.bin");
+    std::fs::write(&big, vec![0u8; 4096]).unwrap();
+    let run = run_fixture_with_env(
+        &server,
+        &[&format!("overwrite\t{big}\thead")],
+        &[("FS_SHIM_MAX_FILE_SIZE", "1024")],
+    );
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    let pre = server.params("pre_modify");
+    assert_eq!(pre.len(), 1);
+    assert_eq!(pre[0]["large_file"], true);
+    assert_eq!(pre[0]["size"], 4096);
+    let post = server.params("post_modify");
+    assert_eq!(post.len(), 1);
+    assert_eq!(post[0]["large_file"], true);
+    assert_eq!(post[0]["size_before"], 4096);
+    assert_eq!(post[0]["bytes"], 4);
+}
+
+#[test]
+fn small_files_are_not_flagged_large() {
+    let server = MockServer::start();
+    let small = p(&server, "small.txt");
+    std::fs::write(&small, "tiny").unwrap();
+    let run = run_fixture_with_env(
+        &server,
+        &[&format!("overwrite\t{small}\tTI")],
+        &[("FS_SHIM_MAX_FILE_SIZE", "1024")],
+    );
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    assert!(server.params("pre_modify")[0].get("large_file").is_none());
+    assert!(server.params("post_modify")[0].get("large_file").is_none());
+}
+
+#[test]
+fn files_above_ignore_size_are_ignored() {
+    let server = MockServer::with_denied(&["pre_modify"]);
+    let big = p(&server, "db.sqlite");
+    std::fs::write(&big, vec![0u8; 4096]).unwrap();
+    let run = run_fixture_with_env(
+        &server,
+        &[&format!("overwrite\t{big}\thead")],
+        &[("FS_SHIM_IGNORE_FILE_SIZE", "1024")],
+    );
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    assert!(server.ops().is_empty());
+}
+
+#[test]
+fn untracked_filesystems_are_notify_only_by_default() {
+    let server = MockServer::with_denied(&["pre_modify"]);
+    let file = p(&server, "remote.txt");
+    std::fs::write(&file, "").unwrap();
+    let run = run_fixture_with_env(
+        &server,
+        &[&format!("write\t{file}\tdata")],
+        &[("FS_SHIM_TRACK_FILESYSTEMS", "no-such-fs")],
+    );
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    assert!(server.params("pre_modify").is_empty());
+    assert_eq!(server.params("post_modify").len(), 1);
+}
+
+#[test]
+fn untracked_filesystems_can_be_ignored() {
+    let server = MockServer::start();
+    let file = p(&server, "remote.txt");
+    let run = run_fixture_with_env(
+        &server,
+        &[&format!("write\t{file}\tdata")],
+        &[
+            ("FS_SHIM_TRACK_FILESYSTEMS", "no-such-fs"),
+            ("FS_SHIM_OTHER_FILESYSTEMS", "ignore"),
+        ],
+    );
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    assert!(server.ops().is_empty());
+}
+
+const CAFE_NFC: &str = "caf\u{e9}";
+const CAFE_NFD: &str = "cafe\u{301}";
+
+#[test]
+fn decomposed_names_are_reported_composed() {
+    let server = MockServer::start();
+    let nfd = p(&server, &format!("{CAFE_NFD}.txt"));
+    std::fs::write(&nfd, "").unwrap();
+    let run = run_fixture(&server, &[&format!("write\t{nfd}\tx")]);
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    let nfc = p(&server, &format!("{CAFE_NFC}.txt"));
+    for method in ["pre_modify", "post_modify"] {
+        let params = server.params(method);
+        assert_eq!(params[0]["path"], nfc.as_str(), "{method}");
+        assert_eq!(params[0]["raw_path"], nfd.as_str(), "{method}");
+    }
+}
+
+#[test]
+fn ignore_globs_match_both_unicode_forms() {
+    let server = MockServer::with_denied(&["pre_modify"]);
+    let ops = [
+        format!("write\t{}\tx", p(&server, &format!("{CAFE_NFC}.txt"))),
+        format!("write\t{}\tx", p(&server, &format!("{CAFE_NFD}-2.txt"))),
+    ];
+    let glob = format!("{CAFE_NFC}*");
+    let run = run_fixture_with_env(&server, &[&ops[0], &ops[1]], &[("FS_SHIM_IGNORE", &glob)]);
+    assert_eq!(run.results, ["ok", "ok"], "{}", run.stderr);
+    assert!(server.ops().is_empty());
+}
+
+#[test]
+fn roots_match_both_unicode_forms() {
+    let server = MockServer::start();
+    let nfd_dir = server.dir.join("date\u{301}");
+    std::fs::create_dir(&nfd_dir).unwrap();
+    let inside = nfd_dir.join("in.txt").to_string_lossy().to_string();
+    let outside = p(&server, "out.txt");
+    let root = server.dir.join("dat\u{e9}").to_string_lossy().to_string();
+    let run = run_fixture_with_env(
+        &server,
+        &[
+            &format!("write\t{inside}\tx"),
+            &format!("write\t{outside}\tx"),
+        ],
+        &[("FS_SHIM_ROOTS", &root)],
+    );
+    assert_eq!(run.results, ["ok", "ok"], "{}", run.stderr);
+    let paths: Vec<String> = server.ops().into_iter().map(|(_, p)| p).collect();
+    let inside_nfc = format!("{root}/in.txt");
+    assert_eq!(paths, [inside_nfc.clone(), inside_nfc]);
+}
+
+#[test]
+fn normalization_can_be_disabled() {
+    let server = MockServer::start();
+    let nfd = p(&server, &format!("{CAFE_NFD}.txt"));
+    let glob = format!("{CAFE_NFC}*");
+    std::fs::write(&nfd, "").unwrap();
+    let run = run_fixture_with_env(
+        &server,
+        &[&format!("write\t{nfd}\tx")],
+        &[
+            ("FS_SHIM_IGNORE", &glob),
+            ("FS_SHIM_NORMALIZE_UNICODE", "0"),
+        ],
+    );
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    let pre = server.params("pre_modify");
+    assert_eq!(pre[0]["path"], nfd.as_str());
+    assert!(pre[0].get("raw_path").is_none());
+}
+
+#[test]
+fn case_insensitive_matching_is_configurable() {
+    let server = MockServer::start();
+    let log = p(&server, "build.log");
+    let op = format!("write\t{log}\tx");
+    let run = run_fixture_with_env(
+        &server,
+        &[&op],
+        &[
+            ("FS_SHIM_IGNORE", "*.LOG"),
+            ("FS_SHIM_CASE_INSENSITIVE", "1"),
+        ],
+    );
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    assert!(server.ops().is_empty());
+
+    let run = run_fixture_with_env(
+        &server,
+        &[&op],
+        &[
+            ("FS_SHIM_IGNORE", "*.LOG"),
+            ("FS_SHIM_CASE_INSENSITIVE", "0"),
+        ],
+    );
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    assert!(!server.ops().is_empty());
+}
+
+// `/usr/bin/tar` is SIP-protected on macOS, so would run unshimmed.
+#[cfg(target_os = "linux")]
+#[test]
+fn an_archive_unpacked_into_a_watched_dir_is_summed_up_without_prompts() {
+    let server = MockServer::start();
+    let (staging, src) = (server.dir.join("staging"), server.dir.join("src"));
+    std::fs::create_dir_all(staging.join("lib")).unwrap();
+    std::fs::create_dir(&src).unwrap();
+    let names = ["a.rs", "b.rs", "c.rs", "lib/d.rs", "lib/e.rs"];
+    for name in names {
+        std::fs::write(staging.join(name), name).unwrap();
+    }
+    let archive = p(&server, "src.tar");
+    let made = std::process::Command::new("tar")
+        .args(["-cf", &archive, "-C"])
+        .arg(&staging)
+        .args(["a.rs", "b.rs", "c.rs", "lib"])
+        .status()
+        .unwrap();
+    assert!(made.success());
+    let src = src.to_string_lossy().to_string();
+    let run = run_fixture_with_env(
+        &server,
+        &[&format!("untar\t{archive}\t{src}")],
+        &[("FS_SHIM_WATCH_DIRS", &src)],
+    );
+    assert_eq!(run.results, ["ok"], "{}", run.stderr);
+    let methods: Vec<String> = server.ops().into_iter().map(|(m, _)| m).collect();
+    assert!(
+        !methods.iter().any(|m| m.starts_with("pre_")),
+        "{methods:?}"
+    );
+    let mut created: Vec<String> = server
+        .params("post_create")
+        .iter()
+        .map(|p| p["path"].as_str().unwrap().to_string())
+        .collect();
+    created.sort();
+    let want: Vec<String> = names.iter().map(|n| format!("{src}/{n}")).collect();
+    assert_eq!(created, want);
+    let changed = server.params("post_dir_changed");
+    // Unpacked well within the debounce, and summed up at exit.
+    assert_eq!(changed.len(), 1, "{changed:?}");
+    assert_eq!(changed[0]["path"], src.as_str());
+    assert_eq!(changed[0]["files"], names.len());
+    assert_eq!(changed[0]["synthesized"], true);
+}
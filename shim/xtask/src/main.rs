@@ -0,0 +1,289 @@
+//! Release tasks for the fs shim.
+//!
+//! ```text
+//! cargo xtask dist [--arm64e] [--out DIR]
+//! ```
+//!
+//! On macOS `dist` builds the arm64 and x86_64 slices (plus arm64e with
+//! `--arm64e`, which needs a nightly toolchain), fuses them with `lipo`,
+//! ad-hoc signs the result, and checks that every runnable slice loads and
+//! reports the same build id. On Linux it builds the host `.so` and runs the
+//! same load check. The Neovim plugin's install step calls this.
+
+use std::ffi::{CStr, CString, OsStr};
+use std::path::{Path, PathBuf};
+use std::process::{exit, Command};
+
+const USAGE: &str = "usage: cargo xtask dist [--arm64e] [--out DIR]\n";
+
+const SHIM_PACKAGE: &str = "nvim-claude-shim";
+const BUILD_ID_ENV: &str = "NVIM_CLAUDE_SHIM_BUILD_ID";
+
+#[cfg(target_os = "macos")]
+const LIB_NAME: &str = "libnvimclaude_shim.dylib";
+#[cfg(not(target_os = "macos"))]
+const LIB_NAME: &str = "libnvimclaude_shim.so";
+
+struct DistArgs {
+    arm64e: bool,
+    out: Option<PathBuf>,
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let res = match args.first().map(String::as_str) {
+        Some("dist") => parse_dist(&args[1..]).and_then(|a| dist(&a)),
+        // Internal: run by `dist` under each slice's architecture.
+        Some("smoke") if args.len() == 3 => smoke(Path::new(&args[1]), &args[2]),
+        Some("-h") | Some("--help") => {
+            print!("{USAGE}");
+            return;
+        }
+        _ => Err(format!("unknown task\n{USAGE}")),
+    };
+    if let Err(e) = res {
+        eprintln!("xtask: {e}");
+        exit(1);
+    }
+}
+
+fn parse_dist(args: &[String]) -> Result<DistArgs, String> {
+    let mut out = DistArgs {
+        arm64e: false,
+        out: None,
+    };
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "--arm64e" => out.arm64e = true,
+            "--out" => out.out = Some(it.next().ok_or("--out needs a directory")?.into()),
+            other => return Err(format!("unknown flag: {other}\n{USAGE}")),
+        }
+    }
+    Ok(out)
+}
+
+/// The `shim/` workspace root.
+fn root() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .parent()
+        .expect("xtask lives inside the workspace")
+        .to_path_buf()
+}
+
+fn run(cmd: &mut Command) -> Result<(), String> {
+    let status = cmd.status().map_err(|e| format!("{cmd:?}: {e}"))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("{cmd:?} failed with {status}"))
+    }
+}
+
+fn output(cmd: &mut Command) -> Option<String> {
+    let out = cmd.output().ok()?;
+    out.status
+        .success()
+        .then(|| String::from_utf8_lossy(&out.stdout).trim().to_string())
+}
+
+fn cargo() -> Command {
+    let mut cmd = Command::new(std::env::var_os("CARGO").unwrap_or_else(|| "cargo".into()));
+    cmd.current_dir(root());
+    cmd
+}
+
+/// `<shim version>+<short hash>[-dirty]`, computed once so every slice
+/// embeds the same string.
+fn build_id() -> Result<String, String> {
+    let manifest = std::fs::read_to_string(root().join("Cargo.toml"))
+        .map_err(|e| format!("read Cargo.toml: {e}"))?;
+    let version = manifest
+        .lines()
+        .find_map(|l| l.strip_prefix("version = \""))
+        .and_then(|v| v.strip_suffix('"'))
+        .ok_or("no package version in Cargo.toml")?;
+    let git = |args: &[&str]| output(Command::new("git").args(args).current_dir(root()));
+    let Some(hash) = git(&["rev-parse", "--short=12", "HEAD"]) else {
+        return Ok(version.to_string());
+    };
+    let dirty = git(&["status", "--porcelain", "--", "."]).is_some_and(|s| !s.is_empty());
+    Ok(format!(
+        "{version}+{hash}{}",
+        if dirty { "-dirty" } else { "" }
+    ))
+}
+
+fn build_slice(target: Option<&str>, id: &str, nightly: bool) -> Result<PathBuf, String> {
+    let mut cmd = if nightly {
+        let mut c = Command::new("rustup");
+        c.args(["run", "nightly", "cargo", "build", "-Z", "build-std"])
+            .current_dir(root());
+        c
+    } else {
+        let mut c = cargo();
+        c.arg("build");
+        c
+    };
+    cmd.args(["--release", "--lib", "--package", SHIM_PACKAGE])
+        .env(BUILD_ID_ENV, id);
+    if let Some(t) = target {
+        cmd.args(["--target", t]);
+    }
+    run(&mut cmd)?;
+    let mut dir = root().join("target");
+    if let Some(t) = target {
+        dir.push(t);
+    }
+    Ok(dir.join("release").join(LIB_NAME))
+}
+
+fn dist(args: &DistArgs) -> Result<(), String> {
+    let id = build_id()?;
+    eprintln!("xtask: build id {id}");
+
+    #[cfg(target_os = "macos")]
+    let lib = dist_universal(args, &id)?;
+    #[cfg(not(target_os = "macos"))]
+    let lib = {
+        if args.arm64e {
+            return Err("--arm64e only applies to macOS".into());
+        }
+        let built = build_slice(None, &id, false)?;
+        run_smoke(
+            Command::new(std::env::current_exe().map_err(|e| e.to_string())?),
+            &built,
+            &id,
+        )?;
+        built
+    };
+
+    let lib = match &args.out {
+        Some(dir) => {
+            std::fs::create_dir_all(dir).map_err(|e| format!("{}: {e}", dir.display()))?;
+            let dest = dir.join(LIB_NAME);
+            std::fs::copy(&lib, &dest).map_err(|e| format!("{}: {e}", dest.display()))?;
+            dest
+        }
+        None => lib,
+    };
+    println!("{}", lib.display());
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+fn dist_universal(args: &DistArgs, id: &str) -> Result<PathBuf, String> {
+    // (rust target, lipo arch name)
+    let mut slices = vec![
+        ("aarch64-apple-darwin", "arm64"),
+        ("x86_64-apple-darwin", "x86_64"),
+    ];
+    if args.arm64e {
+        slices.push(("arm64e-apple-darwin", "arm64e"));
+    }
+
+    let mut built = Vec::new();
+    for (target, _) in &slices {
+        eprintln!("xtask: building {target}");
+        built.push(build_slice(
+            Some(target),
+            id,
+            *target == "arm64e-apple-darwin",
+        )?);
+    }
+
+    let dir = root().join("target/universal/release");
+    std::fs::create_dir_all(&dir).map_err(|e| format!("{}: {e}", dir.display()))?;
+    let fat = dir.join(LIB_NAME);
+    run(Command::new("lipo")
+        .arg("-create")
+        .args(&built)
+        .arg("-output")
+        .arg(&fat))?;
+    run(Command::new("codesign").args(["-s", "-", "-f"]).arg(&fat))?;
+
+    let info = output(Command::new("lipo").arg("-info").arg(&fat)).ok_or("lipo -info failed")?;
+    eprintln!("xtask: {info}");
+    let archs: Vec<&str> = info
+        .rsplit(':')
+        .next()
+        .unwrap_or("")
+        .split_whitespace()
+        .collect();
+    for (_, arch) in &slices {
+        if !archs.contains(arch) {
+            return Err(format!("universal dylib is missing the {arch} slice"));
+        }
+    }
+
+    // arm64e slices only load into arm64e processes; a plain arm64 process
+    // exercises the arm64 slice, so those are the two we can smoke-test.
+    for (target, arch) in slices.iter().filter(|(_, a)| *a != "arm64e") {
+        if !arch_runnable(arch) {
+            eprintln!(
+                "xtask: cannot run {arch} code here (Rosetta missing?); skipping its smoke test"
+            );
+            continue;
+        }
+        let mut build = cargo();
+        build.args(["build", "--package", "xtask", "--target", target]);
+        run(&mut build)?;
+        let exe = root().join("target").join(target).join("debug/xtask");
+        let mut cmd = Command::new("arch");
+        cmd.arg(format!("-{arch}")).arg(exe);
+        run_smoke(cmd, &fat, id)?;
+    }
+    Ok(fat)
+}
+
+#[cfg(target_os = "macos")]
+fn arch_runnable(arch: &str) -> bool {
+    Command::new("arch")
+        .arg(format!("-{arch}"))
+        .arg("/usr/bin/true")
+        .status()
+        .is_ok_and(|s| s.success())
+}
+
+/// Run `smoke` through `runner` (this binary, possibly under `arch -x`)
+/// against `lib`, with the shim's own env cleared so it stays inert.
+fn run_smoke(mut runner: Command, lib: &Path, id: &str) -> Result<(), String> {
+    for (key, _) in std::env::vars_os() {
+        let k = key.to_string_lossy();
+        if k.starts_with("NVIM_CLAUDE_SHIM") || k.starts_with("FS_SHIM") {
+            runner.env_remove(&key);
+        }
+    }
+    runner
+        .env_remove("DYLD_INSERT_LIBRARIES")
+        .env_remove("LD_PRELOAD")
+        .arg("smoke")
+        .arg(lib)
+        .arg(id);
+    run(&mut runner)
+}
+
+fn smoke(lib: &Path, expected: &str) -> Result<(), String> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let arch = std::env::consts::ARCH;
+    let c_lib = CString::new(OsStr::as_bytes(lib.as_os_str())).map_err(|e| e.to_string())?;
+    unsafe {
+        let handle = libc::dlopen(c_lib.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL);
+        if handle.is_null() {
+            let err = CStr::from_ptr(libc::dlerror()).to_string_lossy();
+            return Err(format!("{arch}: dlopen {}: {err}", lib.display()));
+        }
+        let sym = libc::dlsym(handle, c"nvim_claude_shim_version".as_ptr());
+        if sym.is_null() {
+            return Err(format!("{arch}: nvim_claude_shim_version not exported"));
+        }
+        let version: extern "C" fn() -> *const libc::c_char = std::mem::transmute(sym);
+        let got = CStr::from_ptr(version()).to_string_lossy();
+        if got != expected {
+            return Err(format!("{arch}: slice reports {got}, expected {expected}"));
+        }
+    }
+    eprintln!("xtask: {arch} slice loads ({expected})");
+    Ok(())
+}